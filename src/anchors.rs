@@ -0,0 +1,225 @@
+use std::path::{Path, PathBuf};
+use std::hash::Hasher;
+use std::io::{BufRead, Read};
+
+use std::cmp;
+use std::fs;
+use std::io;
+
+use rustc_serialize::json;
+
+use fileops::*;
+use hashers::*;
+use plan::*;
+use repo::*;
+
+// lines between the anchors a diff leaves behind
+pub const ANCHOR_SPACING: usize = 1024;
+
+// a point between two lines where the last diff of a file found everything
+// before it still matched the index: how many lines and bytes came before,
+// a hash of those bytes and where the diff's offsets stood. a file whose
+// start hashes the same can be diffed from there on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Anchor {
+    pub line: usize,
+    pub position: u64,
+    pub prefix_hash: u64,
+    pub offset: isize,
+    pub new_offset: isize
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct AnchorList {
+    // the content hash of the index they were found against, they mean
+    // nothing against any other
+    index_hash: u64,
+    anchors: Vec<Anchor>
+}
+
+// the anchors of every diffed file, kept by path id. they only make diffs
+// faster, so ones that are missing, unreadable or from another index are
+// the same as none
+#[derive(Debug)]
+pub struct Anchors {
+    path: PathBuf,
+    plan: Plan
+}
+
+impl Default for Anchors {
+    fn default() -> Anchors {
+        Anchors::new(repo_path("anchors"))
+    }
+}
+
+impl Anchors {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Anchors {
+        Anchors {
+            path: path.into(),
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Anchors {
+        self.plan = plan;
+        self
+    }
+
+    pub fn load(&self, id: &Path, index_hash: u64) -> Vec<Anchor> {
+        let mut data = String::new();
+        if let Err(e) = fs::File::open(self.path.join(id)).and_then(|mut f| f.read_to_string(&mut data)) {
+            trace!("No anchors for {:?}: {}", id, e);
+            return vec![];
+        }
+        match json::decode::<AnchorList>(&data) {
+            Ok(ref list) if list.index_hash == index_hash => {
+                trace!("{} anchors for {:?}", list.anchors.len(), id);
+                list.anchors.clone()
+            },
+            Ok(_) => {
+                debug!("Anchors for {:?} were found against another index", id);
+                vec![]
+            },
+            Err(e) => {
+                debug!("Anchors for {:?} are unreadable: {}", id, e);
+                vec![]
+            }
+        }
+    }
+
+    pub fn save(&self, id: &Path, index_hash: u64, anchors: &[Anchor]) -> io::Result<()> {
+        if anchors.is_empty() {
+            return self.forget(id);
+        }
+        let data = match json::encode(&AnchorList {index_hash: index_hash, anchors: anchors.to_vec()}) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to encode to json: {}", e)));
+            },
+            Ok(d) => d
+        };
+        let anchor_path = self.path.join(id);
+        if let Some(parent) = anchor_path.parent() {
+            if self.plan.allow(Op::CreateDir(parent)) {
+                try!(fs::create_dir_all(parent));
+            }
+        }
+        if self.plan.allow(Op::WriteFile(&anchor_path)) {
+            debug!("Saving {} anchors for {:?}", anchors.len(), id);
+            try!(atomic_write(&anchor_path, data.as_bytes()));
+        }
+        Ok(())
+    }
+
+    pub fn forget(&self, id: &Path) -> io::Result<()> {
+        // the anchors of a file, or of everything under a directory
+        let anchor_path = self.path.join(id);
+        let is_dir = match fs::metadata(&anchor_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(data) => data.is_dir()
+        };
+        if !self.plan.allow(Op::Remove(&anchor_path)) {
+            return Ok(());
+        }
+        debug!("Dropping anchors under {:?}", id);
+        if is_dir {
+            fs::remove_dir_all(&anchor_path)
+        } else {
+            fs::remove_file(&anchor_path)
+        }
+    }
+}
+
+pub fn find_anchor<R: BufRead>(reader: &mut R, anchors: &[Anchor]) -> io::Result<Option<Anchor>> {
+    // the furthest anchor the start of reader still matches, reading until
+    // the first one it doesn't. anchors are in order
+    let mut hasher = FnvHasher::default();
+    let mut position = 0;
+    let mut found = None;
+    for anchor in anchors.iter() {
+        while position < anchor.position {
+            let used = {
+                let available = try!(reader.fill_buf());
+                if available.is_empty() {
+                    debug!("File ends before line {}", anchor.line);
+                    return Ok(found);
+                }
+                let wanted = cmp::min(available.len() as u64, anchor.position - position) as usize;
+                hasher.write(&available[..wanted]);
+                wanted
+            };
+            reader.consume(used);
+            position += used as u64;
+        }
+        if position != anchor.position || hasher.finish() != anchor.prefix_hash {
+            debug!("File no longer matches up to line {}", anchor.line);
+            return Ok(found);
+        }
+        found = Some(*anchor);
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hasher;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::fs;
+    use std::env;
+
+    use hashers::*;
+
+    fn anchor_at(data: &[u8], line: usize) -> Anchor {
+        let position = data.split(|&c| c == b'\n').take(line).fold(0, |sum, text| sum + text.len() + 1);
+        let mut hasher = FnvHasher::default();
+        hasher.write(&data[..position]);
+        Anchor {
+            line: line,
+            position: position as u64,
+            prefix_hash: hasher.finish(),
+            offset: 0,
+            new_offset: 0
+        }
+    }
+
+    #[test]
+    fn test_find_anchor() {
+        let data = b"one\ntwo\nthree\nfour\n";
+        let anchors = vec![anchor_at(data, 1), anchor_at(data, 2), anchor_at(data, 3)];
+        assert_eq!(find_anchor(&mut Cursor::new(&data[..]), &anchors).unwrap(), Some(anchors[2]));
+        assert_eq!(find_anchor(&mut Cursor::new(&data[..]), &[]).unwrap(), None);
+
+        // the last anchor before a change is as far as it gets
+        let changed = b"one\ntwo\nTHREE\nfour\n";
+        assert_eq!(find_anchor(&mut Cursor::new(&changed[..]), &anchors).unwrap(), Some(anchors[1]));
+        let changed = b"ONE\ntwo\nthree\nfour\n";
+        assert_eq!(find_anchor(&mut Cursor::new(&changed[..]), &anchors).unwrap(), None);
+        assert_eq!(find_anchor(&mut Cursor::new(&b"one\ntw"[..]), &anchors).unwrap(), Some(anchors[0]));
+    }
+
+    #[test]
+    fn test_anchors_saved() {
+        let path = env::temp_dir().join("h2-test-anchors");
+        let _ = fs::remove_dir_all(&path);
+        let anchors = Anchors::new(&path);
+        let id = Path::new("dir/a.txt");
+        assert!(anchors.load(id, 1).is_empty());
+
+        let list = vec![anchor_at(b"one\ntwo\n", 1)];
+        anchors.save(id, 1, &list).unwrap();
+        assert_eq!(anchors.load(id, 1), list);
+        // only trusted against the index they were found with
+        assert!(anchors.load(id, 2).is_empty());
+
+        anchors.forget(Path::new("dir")).unwrap();
+        assert!(anchors.load(id, 1).is_empty());
+        anchors.forget(id).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
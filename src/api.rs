@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use std::fs;
+use std::io;
+
+use repo::*;
+use revs::*;
+use ignore::*;
+use oplog::*;
+use pathid::*;
+use manifest::*;
+use config::*;
+use crypt::*;
+
+use {Checkout, Logs, Stage, stage_dir_all};
+
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    // paths relative to the source directory that are never snapshotted
+    pub ignore: Vec<PathBuf>
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> SnapshotOptions {
+        SnapshotOptions {
+            ignore: vec![PathBuf::from(".h2")]
+        }
+    }
+}
+
+pub fn snapshot<T: Into<PathBuf>, V: Into<PathBuf>>(src_dir: T, repo_dir: V, options: &SnapshotOptions)
+                                                    -> io::Result<RevisionId> {
+    let repo = Repo {
+        root: src_dir.into(),
+        path: repo_dir.into()
+    };
+    info!("Snapshotting {:?} into {:?}", &repo.root, &repo.path);
+
+    match fs::metadata(&repo.path) {
+        Ok(ref data) if data.is_dir() => {
+            trace!("Repository already exists");
+            try!(repo.probe());
+            try!(repo.probe_trees());
+            try!(repo.check_writable());
+        },
+        _ => {
+            debug!("Creating repository");
+            try!(fs::create_dir_all(&repo.path));
+            try!(repo.write_header());
+        }
+    }
+
+    let checkout = Checkout::new(repo.root.clone());
+    // an encrypted repository's key comes from its own config
+    let key = try!(load_key(&try!(Config::load(repo.path.join("config")))));
+    let mut stage = Stage::new(repo.path.join("stage")).with_key(key.clone());
+    let mut logs = Logs::new(repo.path.join("logs")).with_manifest(try!(Manifest::open(repo.path.join("manifest"))));
+    let mut revs = Revisions::new(repo.path.join("revs")).with_key(key);
+    try!(stage.init());
+    try!(logs.init());
+    try!(revs.init());
+
+    debug!("Staging source directory");
+    try!(stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &IgnoreRules::new(options.ignore.clone())));
+
+    let id = try!(revs.commit(&stage, try!(logs.tree_hash())));
+    try!(OpLog::new(repo.path.join("oplog")).append(&OpRecord::new("snapshot", Some(id), vec![])));
+    Ok(id)
+}
+
+pub fn restore<T: Into<PathBuf>, V: Into<PathBuf>>(repo_dir: T, rev: RevisionId, dst_dir: V) -> io::Result<()> {
+    let repo = Repo {
+        root: PathBuf::new(),
+        path: repo_dir.into()
+    };
+    let dst_dir = dst_dir.into();
+    info!("Restoring revision {} from {:?} into {:?}", rev, &repo.path, &dst_dir);
+
+    match repo.read_header() {
+        Ok(version) if version == FORMAT_VERSION => {
+            trace!("Repository version is supported");
+        },
+        Ok(version) => {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("Repository format version {} does not match supported version {}",
+                                              version, FORMAT_VERSION)));
+        },
+        Err(e) => {
+            return Err(e);
+        }
+    }
+
+    let key = try!(load_key(&try!(Config::load(repo.path.join("config")))));
+    try!(Revisions::new(repo.path.join("revs")).with_key(key).restore(rev, &dst_dir));
+    OpLog::new(repo.path.join("oplog")).append(&OpRecord::new("restore", Some(rev),
+                                                              vec![escape_id(&dst_dir)]))
+}
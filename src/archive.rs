@@ -0,0 +1,480 @@
+use std::path::{Path, PathBuf, Component};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ffi::OsStr;
+
+use std::fs;
+use std::io;
+
+use tree::*;
+use map::*;
+use repo::*;
+use fileops::*;
+use portable::*;
+use linestore::*;
+use manifest::*;
+use pack::*;
+use migrate::*;
+use pathid::*;
+
+use LineIndex;
+
+// layout of an archive:
+// magic, archive version, repository format version
+// every entry's data, back to back
+// the index: entry count, then kind, path, offset and length of each entry
+// offset of the index, as the last eight bytes
+const ARCHIVE_MAGIC: &'static [u8] = b"H2ARCHV\n";
+// 2 stores the manifest and packs entry by entry, 1 copied them as they were
+const ARCHIVE_VERSION: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    Dir,
+    // copied byte for byte
+    File,
+    // a file's line index along with its places file, stored entry by entry
+    // so it's portable
+    IndexMap,
+    // the shared line store's index, stored the same way
+    LineTree,
+    // the manifest's map, along with its data file
+    ManifestMap,
+    // every index a pack holds that the manifest points at, each with its
+    // meta and its line index stored entry by entry
+    Pack
+}
+
+#[derive(Debug)]
+struct ArchiveEntry {
+    kind: EntryKind,
+    // relative to the .h2 directory
+    path: PathBuf,
+    offset: u64,
+    len: u64
+}
+
+impl EntryKind {
+    fn classify(id: &Path, is_dir: bool) -> Option<EntryKind> {
+        // none for things that aren't archived on their own
+        if id == Path::new("lock") || is_temp_path(id) {
+            // only mean something on this machine
+            None
+        } else if is_dir {
+            Some(EntryKind::Dir)
+        } else if id.starts_with("logs") && id.file_name() == Some(OsStr::new("content")) {
+            Some(EntryKind::IndexMap)
+        } else if id.starts_with("logs") && id.file_name() == Some(OsStr::new("places")) {
+            // exported along with its index
+            None
+        } else if id == Path::new("lines/index") {
+            Some(EntryKind::LineTree)
+        } else if id == Path::new("manifest/index") {
+            Some(EntryKind::ManifestMap)
+        } else if id == Path::new("manifest/data") {
+            // exported along with its index
+            None
+        } else if id.starts_with("packs") {
+            Some(EntryKind::Pack)
+        } else {
+            Some(EntryKind::File)
+        }
+    }
+
+    fn to_code(self) -> u64 {
+        match self {
+            EntryKind::Dir => 0,
+            EntryKind::File => 1,
+            EntryKind::IndexMap => 2,
+            EntryKind::LineTree => 3,
+            EntryKind::ManifestMap => 4,
+            EntryKind::Pack => 5
+        }
+    }
+
+    fn is_current_layout(self) -> bool {
+        // whether import writes this in the current tree layout, whatever
+        // the archived format version
+        match self {
+            EntryKind::IndexMap | EntryKind::LineTree | EntryKind::ManifestMap | EntryKind::Pack => true,
+            EntryKind::Dir | EntryKind::File => false
+        }
+    }
+
+    fn from_code(code: u64) -> io::Result<EntryKind> {
+        match code {
+            0 => Ok(EntryKind::Dir),
+            1 => Ok(EntryKind::File),
+            2 => Ok(EntryKind::IndexMap),
+            3 => Ok(EntryKind::LineTree),
+            4 => Ok(EntryKind::ManifestMap),
+            5 => Ok(EntryKind::Pack),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("Unknown archive entry kind {}", code)))
+        }
+    }
+}
+
+fn export_entry<W: Write>(out: &mut W, path: &Path, kind: EntryKind) -> io::Result<()> {
+    match kind {
+        EntryKind::Dir => Ok(()),
+        EntryKind::File => {
+            let mut file = try!(fs::File::open(path));
+            try!(io::copy(&mut file, out));
+            Ok(())
+        },
+        EntryKind::IndexMap => {
+            let file = try!(fs::OpenOptions::new().read(true).write(true).open(path));
+            let places = try!(fs::OpenOptions::new().read(true).write(true).open(path.with_file_name("places")));
+            let mut index: LineIndex<fs::File> = try!(unsafe {BufMap::from_buffers(file, places)});
+            try!(index.export(out));
+            Ok(())
+        },
+        EntryKind::LineTree => {
+            let file = try!(fs::OpenOptions::new().read(true).write(true).open(path));
+            let mut tree: BufTree<fs::File, LineRef> = try!(unsafe {BufTree::from_buffer(file)});
+            try!(tree.export(out));
+            Ok(())
+        },
+        EntryKind::ManifestMap => {
+            let mut manifest = match path.parent() {
+                Some(dir) => try!(Manifest::open(dir)),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{} is not in a manifest directory", path.display())));
+                }
+            };
+            try!(manifest.export(out));
+            Ok(())
+        },
+        EntryKind::Pack => export_pack(out, path)
+    }
+}
+
+fn pack_parts(path: &Path) -> io::Result<(Packs, u64, PathBuf)> {
+    // the packs a pack file is one of, its number and the repository's
+    // manifest, which says what's in it
+    let number = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse().ok());
+    match (path.parent(), number) {
+        (Some(dir), Some(number)) => match dir.parent() {
+            Some(repo_path) => Ok((Packs::new(dir), number, repo_path.join("manifest"))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       format!("{} is not in a repository", path.display())))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                format!("{} is not a pack", path.display())))
+    }
+}
+
+fn export_pack<W: Write>(out: &mut W, path: &Path) -> io::Result<()> {
+    // the count, then where each index was, its meta and whether it has a
+    // tree, followed by the tree. anything the manifest doesn't point at
+    // is left behind
+    let (packs, pack, manifest_path) = try!(pack_parts(path));
+    let mut manifest = try!(Manifest::open(manifest_path));
+    let mut locations: Vec<PackLocation> = vec![];
+    for id in try!(manifest.ids()) {
+        match try!(manifest.get(&id)).and_then(|entry| entry.pack) {
+            Some(location) if location.pack == pack && !locations.contains(&location) => {
+                locations.push(location);
+            },
+            _ => {}
+        }
+    }
+    locations.sort_by(|a, b| a.meta.offset.cmp(&b.meta.offset));
+
+    try!(write_u64(out, locations.len() as u64));
+    for location in locations.iter() {
+        try!(location.write_portable(out));
+        try!(write_bytes(out, &try!(packs.read_span(pack, location.meta))));
+        // an inline index packs empty trees
+        if location.content.len == 0 {
+            try!(write_u64(out, 0));
+            continue;
+        }
+        try!(write_u64(out, 1));
+        let content = try!(packs.window(pack, location.content));
+        let places = try!(packs.window(pack, location.places));
+        let mut index: LineIndex<_> = try!(unsafe {BufMap::from_buffers(content, places)});
+        try!(index.export(out));
+    }
+    Ok(())
+}
+
+fn import_pack<R: Read>(input: &mut R, path: &Path) -> io::Result<Vec<(PackLocation, PackLocation)>> {
+    // write a pack back, returning where each index was and where it is now
+    let (packs, pack, _) = try!(pack_parts(path));
+    let mut writer = try!(packs.writer(pack));
+    let mut moved = vec![];
+    for _ in 0..try!(read_u64(input)) {
+        let old = try!(PackLocation::read_portable(input));
+        let meta = try!(read_bytes(input));
+        let (content, places) = match try!(read_u64(input)) {
+            0 => (vec![], vec![]),
+            1 => {
+                let mut index: LineIndex<Cursor<Vec<u8>>> =
+                    try!(BufMap::import(Cursor::new(vec![]), Cursor::new(vec![]), input));
+                try!(index.tree_mut().trim());
+                let (content, places) = index.into_buffers();
+                (content.into_inner(), places.into_inner())
+            },
+            other => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Bad tree marker {} in pack {}", other, pack)));
+            }
+        };
+        moved.push((old, try!(writer.add(&meta, &content, &places))));
+    }
+    try!(writer.finish());
+    Ok(moved)
+}
+
+fn repoint_packs(repo: &Repo, moved: &[(PackLocation, PackLocation)]) -> io::Result<()> {
+    // the manifest still says where each packed index was in the archived
+    // packs
+    if moved.is_empty() {
+        return Ok(());
+    }
+    let mut manifest = try!(Manifest::open(repo.path.join("manifest")));
+    for id in try!(manifest.ids()) {
+        let mut entry = match try!(manifest.get(&id)) {
+            Some(entry) => entry,
+            None => {
+                continue;
+            }
+        };
+        let location = match entry.pack {
+            Some(location) => location,
+            None => {
+                continue;
+            }
+        };
+        match moved.iter().find(|&&(old, _)| old == location) {
+            Some(&(_, new)) => {
+                trace!("Index of {:?} moved to {:?}", &id, new);
+                entry.pack = Some(new);
+                try!(manifest.insert(entry));
+            },
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("The archive has no packed index for {}", escape_id(&id))));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn export_repo<T: AsRef<Path>>(repo: &Repo, to: T) -> io::Result<usize> {
+    let to = to.as_ref();
+    info!("Exporting {:?} to {:?}", &repo.path, to);
+    let version = try!(repo.read_header());
+
+    let mut out = match fs::File::create(try!(temp_path(to))) {
+        Err(e) => {
+            error!("Failed to create archive: {}", e);
+            return Err(e);
+        },
+        Ok(f) => f
+    };
+    try!(out.write_all(ARCHIVE_MAGIC));
+    try!(write_u64(&mut out, ARCHIVE_VERSION));
+    try!(write_u64(&mut out, version as u64));
+
+    let mut entries = vec![];
+    let mut to_visit = vec![repo.path.clone()];
+    while let Some(dir) = to_visit.pop() {
+        trace!("Reading directory {:?}", &dir);
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            let id = match entry.path().relative_from(&repo.path) {
+                Some(id) => PathBuf::from(id),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{} is outside of the repository", entry.path().display())));
+                }
+            };
+            let is_dir = try!(entry.metadata()).is_dir();
+            let kind = match EntryKind::classify(&id, is_dir) {
+                Some(kind) => kind,
+                None => {
+                    trace!("Skipping {:?}", &id);
+                    continue;
+                }
+            };
+            if is_dir {
+                to_visit.push(entry.path());
+            }
+            let offset = try!(out.seek(SeekFrom::Current(0)));
+            match export_entry(&mut out, &entry.path(), kind) {
+                Err(e) => {
+                    error!("Failed to export {}: {}", id.display(), e);
+                    return Err(e);
+                },
+                Ok(()) => {
+                    trace!("Exported {:?} as {:?}", &id, kind);
+                }
+            }
+            let end = try!(out.seek(SeekFrom::Current(0)));
+            entries.push(ArchiveEntry {
+                kind: kind,
+                path: id,
+                offset: offset,
+                len: end - offset
+            });
+        }
+    }
+
+    debug!("Writing archive index of {} entries", entries.len());
+    let index_offset = try!(out.seek(SeekFrom::Current(0)));
+    try!(write_u64(&mut out, entries.len() as u64));
+    for entry in entries.iter() {
+        try!(write_u64(&mut out, entry.kind.to_code()));
+        try!(write_bytes(&mut out, &id_bytes(&entry.path)));
+        try!(write_u64(&mut out, entry.offset));
+        try!(write_u64(&mut out, entry.len));
+    }
+    try!(write_u64(&mut out, index_offset));
+    try!(out.sync_all());
+    try!(commit_temp(to));
+
+    Ok(entries.len())
+}
+
+fn read_index(archive: &mut fs::File) -> io::Result<(u32, Vec<ArchiveEntry>)> {
+    // the archived repository's format version and every entry
+    let mut magic = vec![];
+    try!(Read::by_ref(archive).take(ARCHIVE_MAGIC.len() as u64).read_to_end(&mut magic));
+    if magic != ARCHIVE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a half2 archive"));
+    }
+
+    let archive_version = try!(read_u64(archive));
+    if archive_version == 0 || archive_version > ARCHIVE_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Archive version {} is not supported", archive_version)));
+    }
+    // an older repository is migrated once it's imported, so only ones
+    // migrations can't reach are refused here
+    let format_version = try!(read_u64(archive));
+    if format_version > FORMAT_VERSION as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Archived repository format version {} is newer than supported version {}",
+                                          format_version, FORMAT_VERSION)));
+    }
+    let format_version = format_version as u32;
+    try!(pending_migrations(format_version));
+
+    try!(archive.seek(SeekFrom::End(-8)));
+    let index_offset = try!(read_u64(archive));
+    try!(archive.seek(SeekFrom::Start(index_offset)));
+    let count = try!(read_u64(archive));
+    let mut entries = vec![];
+    for _ in 0..count {
+        let kind = try!(EntryKind::from_code(try!(read_u64(archive))));
+        let path = id_from_bytes(&try!(read_bytes(archive)));
+        // refuse anything that would land outside the repository
+        if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Archive entry {} is outside the repository", escape_id(&path))));
+        }
+        entries.push(ArchiveEntry {
+            kind: kind,
+            path: path,
+            offset: try!(read_u64(archive)),
+            len: try!(read_u64(archive))
+        });
+    }
+    Ok((format_version, entries))
+}
+
+fn import_entry(archive: &mut fs::File, entry: &ArchiveEntry, path: &Path)
+                -> io::Result<Vec<(PackLocation, PackLocation)>> {
+    // a pack gives back where its indexes moved to
+    if entry.kind == EntryKind::Dir {
+        try!(fs::create_dir_all(path));
+        return Ok(vec![]);
+    }
+
+    if let Some(parent) = path.parent() {
+        try!(fs::create_dir_all(parent));
+    }
+    try!(archive.seek(SeekFrom::Start(entry.offset)));
+    let mut input = Read::by_ref(archive).take(entry.len);
+    match entry.kind {
+        EntryKind::ManifestMap => {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            try!(Manifest::import(dir, &mut input));
+            return Ok(vec![]);
+        },
+        EntryKind::Pack => {
+            return import_pack(&mut input, path);
+        },
+        _ => {}
+    }
+    let file = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path));
+    match entry.kind {
+        EntryKind::IndexMap => {
+            let places = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                              .open(path.with_file_name("places")));
+            let _: LineIndex<fs::File> = try!(BufMap::import(file, places, &mut input));
+        },
+        EntryKind::LineTree => {
+            let _: BufTree<fs::File, LineRef> = try!(BufTree::import(file, &mut input));
+        },
+        _ => {
+            let mut file = file;
+            try!(io::copy(&mut input, &mut file));
+        }
+    }
+    Ok(vec![])
+}
+
+pub fn import_repo<T: AsRef<Path>>(from: T, repo: &Repo) -> io::Result<usize> {
+    let from = from.as_ref();
+    info!("Importing {:?} into {:?}", from, &repo.path);
+    let mut archive = match fs::File::open(from) {
+        Err(e) => {
+            error!("Failed to open archive: {}", e);
+            return Err(e);
+        },
+        Ok(f) => f
+    };
+    let (version, entries) = try!(read_index(&mut archive));
+
+    debug!("Creating {:?}", &repo.path);
+    match fs::create_dir(&repo.path) {
+        Err(e) => {
+            error!("Failed to create repository directory: {}", e);
+            return Err(e);
+        },
+        Ok(()) => {
+            trace!("Repository directory created");
+        }
+    }
+
+    let mut moved = vec![];
+    for entry in entries.iter() {
+        match import_entry(&mut archive, entry, &repo.path.join(&entry.path)) {
+            Err(e) => {
+                error!("Failed to import {}: {}", entry.path.display(), e);
+                return Err(e);
+            },
+            Ok(locations) => {
+                trace!("Imported {:?}", &entry.path);
+                moved.extend(locations);
+            }
+        }
+    }
+    try!(repoint_packs(repo, &moved));
+
+    if version < FORMAT_VERSION {
+        // trees that came entry by entry are already in the current layout,
+        // everything else goes through the usual migrations
+        let current: Vec<&Path> = entries.iter().filter(|entry| entry.kind.is_current_layout())
+            .map(|entry| entry.path.as_path()).collect();
+        for step in try!(migrate_imported(repo, &current)) {
+            info!("Migrated {} to {}: {}", step.from, step.from + 1, step.summary);
+        }
+    }
+
+    Ok(entries.len())
+}
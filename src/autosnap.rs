@@ -0,0 +1,92 @@
+// when `h2 autosnap` commits the changes it finds. a snapshot is due once
+// the newest revision is older than the interval, or sooner once enough files
+// have changed. snapshots it commits are marked in their meta, so prune can
+// drop them sooner than ones committed by hand
+
+/// How long `h2 autosnap` leaves changes before committing them, in seconds.
+pub const DEFAULT_AUTOSNAP_INTERVAL: u64 = 10 * 60;
+/// How often `h2 autosnap --daemon` looks for changes, in seconds.
+pub const AUTOSNAP_POLL_INTERVAL: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutosnapPolicy {
+    // seconds after the newest revision before changes are committed
+    pub interval: u64,
+    // commit before the interval is up once this many files have changed
+    pub max_changes: Option<usize>
+}
+
+impl Default for AutosnapPolicy {
+    fn default() -> AutosnapPolicy {
+        AutosnapPolicy {
+            interval: DEFAULT_AUTOSNAP_INTERVAL,
+            max_changes: None
+        }
+    }
+}
+
+impl AutosnapPolicy {
+    pub fn is_due(&self, changed: usize, last: Option<i64>, now: i64) -> bool {
+        // last is when the newest revision was committed, none if there
+        // isn't one or it wasn't recorded
+        if changed == 0 {
+            trace!("Nothing changed");
+            return false;
+        }
+        if self.max_changes.map_or(false, |count| changed >= count) {
+            debug!("{} files changed, a snapshot is due", changed);
+            return true;
+        }
+        match last {
+            Some(last) if now - last < self.interval as i64 => {
+                debug!("Only {} seconds since the last revision", now - last);
+                false
+            },
+            _ => true
+        }
+    }
+}
+
+pub fn parse_interval(text: &str) -> Option<u64> {
+    // a number of seconds, optionally with an s, m, h or d suffix
+    let text = text.trim();
+    let (digits, scale) = match text.chars().last() {
+        Some('s') => (&text[..text.len() - 1], 1),
+        Some('m') => (&text[..text.len() - 1], 60),
+        Some('h') => (&text[..text.len() - 1], 60 * 60),
+        Some('d') => (&text[..text.len() - 1], 24 * 60 * 60),
+        _ => (text, 1)
+    };
+    digits.parse::<u64>().ok().and_then(|count| count.checked_mul(scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("10m"), Some(600));
+        assert_eq!(parse_interval("2h"), Some(7200));
+        assert_eq!(parse_interval("1d"), Some(86400));
+        assert_eq!(parse_interval("45s"), Some(45));
+        assert_eq!(parse_interval("30"), Some(30));
+        assert_eq!(parse_interval("m"), None);
+        assert_eq!(parse_interval("soon"), None);
+    }
+
+    #[test]
+    fn test_is_due() {
+        let policy = AutosnapPolicy {interval: 600, max_changes: Some(5)};
+        // nothing to commit is never due
+        assert!(!policy.is_due(0, None, 1000));
+        assert!(!policy.is_due(0, Some(0), 1000));
+
+        assert!(policy.is_due(1, None, 1000));
+        assert!(policy.is_due(1, Some(400), 1000));
+        assert!(!policy.is_due(1, Some(401), 1000));
+        // enough changes don't wait for the interval
+        assert!(policy.is_due(5, Some(999), 1000));
+        assert!(!AutosnapPolicy {max_changes: None, ..policy}.is_due(5, Some(999), 1000));
+    }
+}
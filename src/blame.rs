@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use std::io;
+
+use revs::*;
+use diff::*;
+use hashers::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameLine {
+    // the revision that introduced the line, none if it isn't committed yet
+    pub rev: Option<RevisionId>,
+    pub time: Option<i64>,
+    pub line: Vec<u8>
+}
+
+fn line_hashes(hasher: LineHasher, data: &[u8]) -> Vec<u64> {
+    split_lines(data).iter().map(|line| hasher.hash_line(line)).collect()
+}
+
+pub fn blame<T: AsRef<Path>>(revs: &Revisions, id: T, current: &[u8]) -> io::Result<Vec<BlameLine>> {
+    // walk back from head diffing each revision against the one after it,
+    // following every current line for as long as the diffs keep it. a
+    // line stops at the revision whose diff inserted it
+    let id = id.as_ref();
+    let hasher = LineHasher::default();
+    let lines = split_lines(current);
+    let mut origins: Vec<Option<(RevisionId, Option<i64>)>> = vec![None; lines.len()];
+    // where each current line still being followed is in the newer side,
+    // starting with the checkout itself
+    let mut at: Vec<Option<usize>> = (0..lines.len()).map(Some).collect();
+    let mut newer: Option<(RevisionId, Option<i64>)> = None;
+    let mut newer_hashes = line_hashes(hasher, current);
+
+    let mut next = try!(revs.head());
+    while let Some(rev) = next {
+        if at.iter().all(|place| place.is_none()) {
+            break;
+        }
+        let data = match revs.read_path(rev, id) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("{:?} does not exist at revision {}", id, rev);
+                break;
+            },
+            Err(e) => {
+                error!("Failed to read {:?} at revision {}: {}", id, rev, e);
+                return Err(e);
+            },
+            Ok(data) => data
+        };
+        let meta = try!(revs.meta(rev));
+        trace!("Diffing revision {} against the one after it", rev);
+
+        // where each line of the newer side came from in this revision. a
+        // rewrite too big to diff counts as every line being new
+        let hashes = line_hashes(hasher, &data);
+        let mut from = vec![None; newer_hashes.len()];
+        if let Some(ops) = diff_within(&hashes, &newer_hashes, MAX_DIFF_EDITS) {
+            for op in ops {
+                if let DiffOp::Equal(old, new) = op {
+                    from[new] = Some(old);
+                }
+            }
+        }
+        for i in 0..lines.len() {
+            if let Some(place) = at[i] {
+                at[i] = from[place];
+                if at[i].is_none() {
+                    // the line was inserted by the newer side
+                    origins[i] = newer;
+                }
+            }
+        }
+
+        newer = Some((rev, meta.time));
+        newer_hashes = hashes;
+        next = meta.parent;
+    }
+
+    // whatever is left was there from the file's first revision
+    for i in 0..lines.len() {
+        if at[i].is_some() {
+            origins[i] = newer;
+        }
+    }
+
+    Ok(lines.into_iter().zip(origins.into_iter()).map(|(line, origin)| BlameLine {
+        rev: origin.map(|o| o.0),
+        time: origin.and_then(|o| o.1),
+        line: line
+    }).collect())
+}
+
+pub fn render_blame(lines: &[BlameLine]) -> Vec<Vec<u8>> {
+    // the revision and time a line came from, then the line as it is
+    lines.iter().map(|line| {
+        let rev = match line.rev {
+            Some(rev) => format!("{}", rev),
+            None => "working".to_string()
+        };
+        let time = match line.time {
+            Some(time) => format!("{}", time),
+            None => "-".to_string()
+        };
+        let mut out = format!("{:>7} {:>10} | ", rev, time).into_bytes();
+        out.extend(line.line.iter().cloned());
+        out
+    }).collect()
+}
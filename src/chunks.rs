@@ -0,0 +1,310 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::io::Read;
+use std::hash::Hasher;
+
+use std::fs;
+use std::io;
+
+use hashers::*;
+use fileops::*;
+
+// a stored file that starts with this is a list of chunks rather than the
+// content itself. the nul keeps it from colliding with any text file
+pub const CHUNK_MANIFEST_MAGIC: &'static [u8] = b"\0h2chunks\n";
+// files bigger than this are chunked when chunking is on
+pub const DEFAULT_CHUNK_THRESHOLD: u64 = 1 << 22;
+
+// cut points land where the low bits of the rolling hash are zero, so chunks
+// average around 64K, bounded so a run of odd data can't go to extremes
+const CHUNK_MIN: usize = 1 << 14;
+const CHUNK_MAX: usize = 1 << 18;
+const CHUNK_MASK: u64 = (1 << 16) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub hash: u64,
+    pub len: u64
+}
+
+// content addressed chunks shared between every stored file, so two
+// revisions of a big file only cost the chunks that changed
+#[derive(Debug)]
+pub struct ChunkStore {
+    path: PathBuf
+}
+
+fn gear_table() -> [u64; 256] {
+    // fixed pseudo random values per byte, from splitmix64. these decide
+    // where files are cut, so changing them orphans every stored chunk
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    for i in 0..table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+    }
+    table
+}
+
+pub fn cut_points(data: &[u8]) -> Vec<usize> {
+    // the end offset of every chunk. a cut only depends on the bytes just
+    // before it, so an insertion early in a file leaves later cuts in place
+    let table = gear_table();
+    let mut cuts = vec![];
+    let mut start = 0;
+    let mut hash: u64 = 0;
+    for i in 0..data.len() {
+        hash = (hash << 1).wrapping_add(table[data[i] as usize]);
+        let len = i + 1 - start;
+        if (len >= CHUNK_MIN && hash & CHUNK_MASK == 0) || len >= CHUNK_MAX {
+            cuts.push(i + 1);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        cuts.push(data.len());
+    }
+    cuts
+}
+
+fn hash_chunk(data: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+pub fn is_manifest(data: &[u8]) -> bool {
+    data.starts_with(CHUNK_MANIFEST_MAGIC)
+}
+
+pub fn write_manifest(chunks: &[ChunkRef]) -> Vec<u8> {
+    let mut data = CHUNK_MANIFEST_MAGIC.to_vec();
+    for chunk in chunks.iter() {
+        data.extend(format!("{:016x} {}\n", chunk.hash, chunk.len).bytes());
+    }
+    data
+}
+
+pub fn parse_manifest(data: &[u8]) -> io::Result<Vec<ChunkRef>> {
+    let text = match ::std::str::from_utf8(&data[CHUNK_MANIFEST_MAGIC.len()..]) {
+        Ok(text) => text,
+        Err(_) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk list is not valid text"));
+        }
+    };
+    let mut chunks = vec![];
+    for line in text.lines() {
+        let mut parts = line.split(' ');
+        let hash = parts.next().and_then(|hash| u64::from_str_radix(hash, 16).ok());
+        let len = parts.next().and_then(|len| len.parse().ok());
+        match (hash, len, parts.next()) {
+            (Some(hash), Some(len), None) => {
+                chunks.push(ChunkRef {
+                    hash: hash,
+                    len: len
+                });
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Bad chunk list entry: {:?}", line)));
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+fn read_file<T: AsRef<Path>>(path: T) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    try!(fs::File::open(path).and_then(|mut f| f.read_to_end(&mut data)));
+    Ok(data)
+}
+
+impl ChunkStore {
+    pub fn new<T: Into<PathBuf>>(path: T) -> ChunkStore {
+        ChunkStore {
+            path: path.into()
+        }
+    }
+
+    pub fn chunk_path(&self, hash: u64) -> PathBuf {
+        self.path.join(format!("{:016x}", hash))
+    }
+
+    pub fn write_chunk(&self, data: &[u8]) -> io::Result<ChunkRef> {
+        let chunk = ChunkRef {
+            hash: hash_chunk(data),
+            len: data.len() as u64
+        };
+        let path = self.chunk_path(chunk.hash);
+        match read_file(&path) {
+            Ok(ref existing) if &existing[..] == data => {
+                trace!("Chunk {:016x} is already stored", chunk.hash);
+                return Ok(chunk);
+            },
+            Ok(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Chunk {:016x} collides with a different stored chunk",
+                                                  chunk.hash)));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("Storing new chunk {:016x}", chunk.hash);
+            },
+            Err(e) => {
+                error!("Failed to read chunk {}: {}", path.display(), e);
+                return Err(e);
+            }
+        }
+        try!(fs::create_dir_all(&self.path));
+        try!(atomic_write(&path, data));
+        Ok(chunk)
+    }
+
+    pub fn read_chunk(&self, chunk: &ChunkRef) -> io::Result<Vec<u8>> {
+        let data = match read_file(self.chunk_path(chunk.hash)) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to read chunk {:016x}: {}", chunk.hash, e);
+                return Err(e);
+            }
+        };
+        if data.len() as u64 != chunk.len || hash_chunk(&data) != chunk.hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Chunk {:016x} is corrupt", chunk.hash)));
+        }
+        Ok(data)
+    }
+
+    pub fn read_hash(&self, hash: u64) -> io::Result<Vec<u8>> {
+        // a chunk known only by its hash, for copying it as it is
+        let data = try!(read_file(self.chunk_path(hash)));
+        if hash_chunk(&data) != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Chunk {:016x} is corrupt", hash)));
+        }
+        Ok(data)
+    }
+
+    pub fn store_file<T: AsRef<Path>>(&self, path: T) -> io::Result<Vec<u8>> {
+        // split a file into chunks and return the chunk list to keep in its place
+        let data = try!(read_file(path.as_ref()));
+        let mut chunks = vec![];
+        let mut start = 0;
+        for end in cut_points(&data) {
+            chunks.push(try!(self.write_chunk(&data[start..end])));
+            start = end;
+        }
+        debug!("Stored {:?} as {} chunks", path.as_ref(), chunks.len());
+        Ok(write_manifest(&chunks))
+    }
+
+    pub fn expand(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        // the original content of a stored file, which may be a chunk list
+        if !is_manifest(&data) {
+            return Ok(data);
+        }
+        let mut content = vec![];
+        for chunk in try!(parse_manifest(&data)) {
+            content.extend(try!(self.read_chunk(&chunk)));
+        }
+        Ok(content)
+    }
+
+    pub fn read_stored<T: AsRef<Path>>(&self, path: T) -> io::Result<Vec<u8>> {
+        let data = try!(read_file(path));
+        self.expand(data)
+    }
+
+    pub fn referenced_in<T: AsRef<Path>>(&self, dir: T, hashes: &mut HashSet<u64>) -> io::Result<()> {
+        // add every chunk used by a stored file under dir
+        let mut to_visit = vec![dir.as_ref().to_path_buf()];
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            let entries = match fs::read_dir(&dir) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+                Ok(entries) => entries
+            };
+            for item in entries {
+                let entry = try!(item);
+                let metadata = try!(entry.metadata());
+                if metadata.is_dir() {
+                    to_visit.push(entry.path());
+                    continue;
+                }
+                let data = try!(read_file(entry.path()));
+                if is_manifest(&data) {
+                    for chunk in try!(parse_manifest(&data)) {
+                        hashes.insert(chunk.hash);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> io::Result<Vec<u64>> {
+        let mut hashes = vec![];
+        let entries = match fs::read_dir(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(hashes),
+            Err(e) => return Err(e),
+            Ok(entries) => entries
+        };
+        for item in entries {
+            let entry = try!(item);
+            match entry.file_name().to_str().and_then(|name| u64::from_str_radix(name, 16).ok()) {
+                Some(hash) => {
+                    hashes.push(hash);
+                },
+                None => {
+                    trace!("Skipping {:?}", entry.path());
+                }
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_cut_points_stable() {
+        let data = noise(1 << 21, 1);
+        let cuts = cut_points(&data);
+        assert_eq!(*cuts.last().unwrap(), data.len());
+        let mut start = 0;
+        for &end in cuts.iter() {
+            assert!(end - start <= 1 << 18);
+            start = end;
+        }
+
+        // an insertion near the start only moves the cuts around it
+        let mut edited = b"inserted".to_vec();
+        edited.extend(data.iter().cloned());
+        let shifted: Vec<usize> = cut_points(&edited).iter().map(|cut| cut - 8).collect();
+        let shared = cuts.iter().filter(|cut| shifted.contains(cut)).count();
+        assert!(shared + 2 >= cuts.len());
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let chunks = vec![ChunkRef {hash: 0xdeadbeef, len: 100}, ChunkRef {hash: 1, len: 2}];
+        let data = write_manifest(&chunks);
+        assert!(is_manifest(&data));
+        assert_eq!(parse_manifest(&data).unwrap(), chunks);
+        assert!(!is_manifest(b"plain text\n"));
+    }
+}
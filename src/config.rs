@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::collections::HashMap;
+use std::io::Read;
+
+use std::fs;
+use std::io;
+
+// settings from .h2/config, one `key = value` per line with `#` comments,
+// the same format `h2 profile` prints its recommendations in
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    values: HashMap<String, String>
+}
+
+impl Config {
+    pub fn load<T: AsRef<Path>>(path: T) -> io::Result<Config> {
+        let path = path.as_ref();
+        debug!("Loading config from {:?}", path);
+        let mut data = String::new();
+        match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut data)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No config file, using defaults");
+                return Ok(Config::default());
+            },
+            Err(e) => {
+                error!("Failed to read config file: {}", e);
+                return Err(e);
+            },
+            Ok(_) => {
+                trace!("Read config file");
+            }
+        }
+        Config::parse(&data)
+    }
+
+    pub fn parse(data: &str) -> io::Result<Config> {
+        let mut config = Config::default();
+        for (number, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+            match line.find('=') {
+                Some(split) => {
+                    config.set(line[..split].trim(), line[split + 1..].trim());
+                },
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Config line {} is not `key = value`: {:?}",
+                                                      number + 1, line)));
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_ref())
+    }
+
+    pub fn get_bool(&self, key: &str, default: bool) -> io::Result<bool> {
+        match self.get(key) {
+            None => Ok(default),
+            Some("on") | Some("true") | Some("yes") => Ok(true),
+            Some("off") | Some("false") | Some("no") => Ok(false),
+            Some(value) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Config value {} = {:?} is not on or off", key, value)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_parse() {
+        let config = Config::parse("# recommended settings\ntree_width = 12\n\ngitignore=on\n").unwrap();
+        assert_eq!(config.get("tree_width"), Some("12"));
+        assert_eq!(config.get_bool("gitignore", false).unwrap(), true);
+        assert_eq!(config.get_bool("missing", true).unwrap(), true);
+        assert!(config.get_bool("tree_width", false).is_err());
+        assert!(Config::parse("just a line").is_err());
+    }
+}
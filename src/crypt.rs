@@ -0,0 +1,377 @@
+use std::path::{Path, PathBuf};
+use std::io::Read;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::env;
+
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use config::*;
+use fileops::*;
+use pathid::*;
+
+// a stored file that starts with this is sealed, and has to be opened with
+// the repository key before it means anything. the nul keeps it from
+// colliding with any text file, the same as chunk lists
+pub const SEALED_MAGIC: &'static [u8] = b"\0h2sealed\n";
+// where a key is taken from before the config's key_file
+pub const KEY_VAR: &'static str = "H2_KEY";
+
+pub const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+// the key check is an hmac of this under the key, so it can be compared
+// without saying anything about the key or what it sealed
+const CHECK_TEXT: &'static [u8] = b"h2 key check";
+// before format version 22 the key check was the start of the keystream
+// under this nonce. it's only read so migrate can replace it
+const LEGACY_CHECK_NONCE: &'static [u8; NONCE_BYTES] = b"h2 key check";
+const LEGACY_CHECK_BYTES: usize = 16;
+
+// where a key comes from. keys are 32 bytes written out as 64 hex digits,
+// in the variable or alone in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    Env,
+    File(PathBuf)
+}
+
+// the symmetric key that seals stored snapshots, with chacha20-poly1305
+#[derive(Clone)]
+pub struct StoreKey {
+    key: [u8; KEY_BYTES]
+}
+
+impl fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never the key itself, it ends up in logs
+        write!(f, "StoreKey {{ check: {} }}", self.check_value())
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let digits: Vec<String> = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    digits.join("")
+}
+
+fn random_nonce() -> io::Result<[u8; NONCE_BYTES]> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    match getrandom::fill(&mut nonce) {
+        Ok(()) => Ok(nonce),
+        Err(e) => {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("No secure source of random numbers to seal with on this system: {}", e)))
+        }
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+pub fn seal_place(area: &str, id: &Path) -> Vec<u8> {
+    // what a sealed file is bound to. it's authenticated along with the
+    // contents, so a file moved to another path or revision fails to open
+    let mut place = area.as_bytes().to_vec();
+    place.push(b'/');
+    place.extend(id_bytes(id));
+    place
+}
+
+pub fn open_stored(key: Option<&StoreKey>, place: &[u8], data: Vec<u8>, path: &Path) -> io::Result<Vec<u8>> {
+    // what was stored at path, once the key has been taken off. an encrypted
+    // repository never holds a file in the clear, and the reverse
+    match (key, is_sealed(&data)) {
+        (Some(key), true) => key.open(place, &data).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Failed to open {}: {}", path.display(), e))
+        }),
+        (Some(_), false) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("{} is stored in the clear in an encrypted repository", path.display())))
+        },
+        (None, true) => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               format!("{} is encrypted but the repository config doesn't say so", path.display())))
+        },
+        (None, false) => Ok(data)
+    }
+}
+
+pub fn write_stored(key: Option<&StoreKey>, place: &[u8], path: &Path, data: &[u8]) -> io::Result<()> {
+    // store data at path, sealed to its place if there's a key
+    match key {
+        Some(key) => atomic_write(path, &try!(key.seal(place, data))),
+        None => atomic_write(path, data)
+    }
+}
+
+impl StoreKey {
+    pub fn from_hex(text: &str) -> io::Result<StoreKey> {
+        let text = text.trim();
+        let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(16)).collect();
+        if text.len() != KEY_BYTES * 2 || digits.len() != KEY_BYTES * 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("A key is {} hex digits", KEY_BYTES * 2)));
+        }
+        let mut key = [0u8; KEY_BYTES];
+        for i in 0..KEY_BYTES {
+            key[i] = (digits[i * 2] << 4 | digits[i * 2 + 1]) as u8;
+        }
+        Ok(StoreKey {
+            key: key
+        })
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&Key::from(self.key))
+    }
+
+    pub fn check_value(&self) -> String {
+        // recorded in the config so a wrong key is caught before it's used
+        let mut mac = match <Hmac<Sha256> as KeyInit>::new_from_slice(&self.key) {
+            Ok(mac) => mac,
+            Err(_) => unreachable!("hmac takes keys of any length")
+        };
+        mac.update(CHECK_TEXT);
+        to_hex(&mac.finalize().into_bytes())
+    }
+
+    fn legacy_check_value(&self) -> String {
+        // what versions before 22 recorded, see LEGACY_CHECK_NONCE
+        let mut block = [0u8; LEGACY_CHECK_BYTES];
+        ChaCha20::new(&chacha20::Key::from(self.key), &chacha20::Nonce::from(*LEGACY_CHECK_NONCE))
+            .apply_keystream(&mut block);
+        to_hex(&block)
+    }
+
+    pub fn matches_check(&self, check: &str) -> bool {
+        // whether this is the key a repository's check was made with, as it
+        // is now or as versions before 22 wrote it
+        if check.len() == LEGACY_CHECK_BYTES * 2 {
+            return check == self.legacy_check_value();
+        }
+        check == self.check_value()
+    }
+
+    fn seal_with(&self, nonce: &[u8; NONCE_BYTES], place: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: data,
+            aad: place
+        };
+        let ciphertext = match self.cipher().encrypt(&Nonce::from(*nonce), payload) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too much data to seal at once"));
+            }
+        };
+
+        let mut sealed = SEALED_MAGIC.to_vec();
+        sealed.extend(nonce.iter().cloned());
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn seal(&self, place: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        // every seal gets a fresh nonce, so nothing needs to be remembered
+        // between them. it only opens again with the same place
+        let nonce = try!(random_nonce());
+        self.seal_with(&nonce, place, data)
+    }
+
+    pub fn open(&self, place: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        if !is_sealed(data) || data.len() < SEALED_MAGIC.len() + NONCE_BYTES + TAG_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not sealed data"));
+        }
+        let mut nonce = [0u8; NONCE_BYTES];
+        for (dest, src) in nonce.iter_mut().zip(data[SEALED_MAGIC.len()..].iter()) {
+            *dest = *src;
+        }
+        let payload = Payload {
+            msg: &data[SEALED_MAGIC.len() + NONCE_BYTES..],
+            aad: place
+        };
+        self.cipher().decrypt(&Nonce::from(nonce), payload).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Sealed data failed authentication")
+        })
+    }
+}
+
+impl KeySource {
+    pub fn load(&self) -> io::Result<StoreKey> {
+        match *self {
+            KeySource::Env => match env::var(KEY_VAR) {
+                Ok(text) => StoreKey::from_hex(&text),
+                Err(_) => {
+                    Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not set", KEY_VAR)))
+                }
+            },
+            KeySource::File(ref path) => {
+                debug!("Reading key from {:?}", path);
+                let mut text = String::new();
+                match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+                    Err(e) => {
+                        error!("Failed to read key file {}: {}", path.display(), e);
+                        Err(e)
+                    },
+                    Ok(_) => StoreKey::from_hex(&text)
+                }
+            }
+        }
+    }
+}
+
+pub fn load_key(config: &Config) -> io::Result<Option<StoreKey>> {
+    // the key for a repository's stored snapshots, none if it isn't
+    // encrypted. the variable wins over the config's key file
+    if !try!(config.get_bool("encryption", false)) {
+        return Ok(None);
+    }
+    let source = if env::var_os(KEY_VAR).is_some() {
+        KeySource::Env
+    } else {
+        match config.get("key_file") {
+            Some(path) => KeySource::File(PathBuf::from(path)),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("The repository is encrypted, set {} or key_file", KEY_VAR)));
+            }
+        }
+    };
+    let key = try!(source.load());
+    match config.get("key_check") {
+        Some(check) if key.matches_check(check) => {
+            trace!("Key matches the repository");
+            Ok(Some(key))
+        },
+        Some(_) => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               "The key does not match the one the repository was encrypted with"))
+        },
+        None => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               "Encryption is on but no key_check is recorded, it can only be turned on by init"))
+        }
+    }
+}
+
+pub fn encryption_config(key: &StoreKey, source: &KeySource) -> String {
+    // the config lines init writes for an encrypted repository
+    let mut config = format!("encryption = on\nkey_check = {}\n", key.check_value());
+    if let KeySource::File(ref path) = *source {
+        config.push_str(&format!("key_file = {}\n", absolute_path(path).display()));
+    }
+    config
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+    // commands can run from anywhere in the checkout, so a relative key
+    // file would only be found from where init ran
+    match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    use config::*;
+    use fileops::*;
+
+    fn from_hex(text: &str) -> Vec<u8> {
+        (0..text.len() / 2).map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_rfc_vectors() {
+        // from rfc 8439, section 2.8.2. files sealed before the cipher came
+        // from a crate have the same layout
+        let mut key = [0u8; 32];
+        for i in 0..32 {
+            key[i] = 0x80 + i as u8;
+        }
+        let nonce = b"\x07\0\0\0\x40\x41\x42\x43\x44\x45\x46\x47";
+        let aad = from_hex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for \
+                          the future, sunscreen would be it.";
+        let sealed = StoreKey {key: key}.seal_with(nonce, &aad, plaintext).unwrap();
+        let ciphertext = &sealed[SEALED_MAGIC.len() + 12..sealed.len() - 16];
+        assert_eq!(&ciphertext[..16], &from_hex("d31a8d34648e60db7b86afbc53ef7ec2")[..]);
+        assert_eq!(&sealed[sealed.len() - 16..], &from_hex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+        assert_eq!(StoreKey {key: key}.open(&aad, &sealed).unwrap(), plaintext.to_vec());
+    }
+
+    #[test]
+    fn test_check_value() {
+        // an hmac-sha256 of the check text, and the keystream block older
+        // versions wrote is still recognised
+        let key = StoreKey::from_hex(&vec!["ab"; 32].concat()).unwrap();
+        assert_eq!(key.check_value(), "eb53a4ffd0b34f07a74bce72e2c06928b836be67f68ab5d8202e73cfe20da6f4");
+        assert!(key.matches_check(&key.check_value()));
+        assert!(key.matches_check("c5e0908b39c067c4f43092b64c486ec4"));
+        let other = StoreKey::from_hex(&vec!["cd"; 32].concat()).unwrap();
+        assert!(!other.matches_check(&key.check_value()));
+        assert!(!other.matches_check("c5e0908b39c067c4f43092b64c486ec4"));
+    }
+
+    #[test]
+    fn test_seal() {
+        let key = StoreKey::from_hex(&vec!["ab"; 32].concat()).unwrap();
+        let place = seal_place("revs", Path::new("1/tree/a"));
+        let sealed = key.seal(&place, b"some secret\n").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(key.open(&place, &sealed).unwrap(), b"some secret\n".to_vec());
+        // a fresh nonce each time
+        assert!(key.seal(&place, b"some secret\n").unwrap() != sealed);
+        // moved to another path or revision, it doesn't open
+        assert!(key.open(&seal_place("revs", Path::new("1/tree/b")), &sealed).is_err());
+        assert!(key.open(&seal_place("revs", Path::new("2/tree/a")), &sealed).is_err());
+        assert!(key.open(&[], &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 20;
+        tampered[last] ^= 1;
+        assert!(key.open(&place, &tampered).is_err());
+        let other = StoreKey::from_hex(&vec!["cd"; 32].concat()).unwrap();
+        assert!(other.open(&place, &sealed).is_err());
+        assert!(StoreKey::from_hex("abcd").is_err());
+        assert!(StoreKey::from_hex(&vec!["zz"; 32].concat()).is_err());
+    }
+
+    #[test]
+    fn test_load_key() {
+        let key = StoreKey::from_hex(&vec!["ab"; 32].concat()).unwrap();
+        assert!(load_key(&Config::default()).unwrap().is_none());
+
+        if env::var_os(KEY_VAR).is_some() {
+            // the variable would be used instead of the key file
+            return;
+        }
+        let mut config = Config::parse(&encryption_config(&key, &KeySource::Env)).unwrap();
+        assert_eq!(load_key(&config).unwrap_err().kind(), io::ErrorKind::NotFound);
+        config.set("key_file", "/nonexistent/h2-key");
+        assert!(load_key(&config).is_err());
+
+        let path = env::temp_dir().join("h2-test-key");
+        atomic_write(&path, vec!["cd"; 32].concat().as_bytes()).unwrap();
+        config.set("key_file", &path.to_string_lossy());
+        assert_eq!(load_key(&config).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        atomic_write(&path, vec!["ab"; 32].concat().as_bytes()).unwrap();
+        assert_eq!(load_key(&config).unwrap().unwrap().check_value(), key.check_value());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::Cursor;
+
+use std::io;
+
+use diff::*;
+use portable::*;
+use revs::RevisionId;
+
+// a stored file that starts with this holds the changes from the same file
+// in an earlier revision rather than the content itself
+pub const DELTA_MAGIC: &'static [u8] = b"\0h2delta\n";
+// how many deltas can be stacked before a full copy is stored again, which
+// bounds how much reading one revision of a file takes
+pub const MAX_DELTA_DEPTH: u64 = 16;
+// the diff keeps a lot of state per line, bigger files are stored whole
+pub const DELTA_SIZE_LIMIT: u64 = 1 << 20;
+
+const OP_COPY: u64 = 0;
+const OP_INSERT: u64 = 1;
+
+enum DeltaOp {
+    // a run of lines from the base, by index and count
+    Copy(usize, usize),
+    Insert(Vec<u8>)
+}
+
+pub fn raw_lines(data: &[u8]) -> Vec<&[u8]> {
+    // lines with their terminators, so joining them gives back the exact bytes
+    let mut lines = vec![];
+    let mut start = 0;
+    for i in 0..data.len() {
+        if data[i] == b'\n' {
+            lines.push(&data[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+    lines
+}
+
+pub fn is_delta(data: &[u8]) -> bool {
+    data.starts_with(DELTA_MAGIC)
+}
+
+pub fn make_delta(base: &[u8], new: &[u8], base_rev: RevisionId, depth: u64) -> Vec<u8> {
+    // runs of lines kept from the base are copied by range, everything else
+    // is stored inline
+    let (old_lines, new_lines) = (raw_lines(base), raw_lines(new));
+    let mut ops: Vec<DeltaOp> = vec![];
+    for op in diff(&old_lines, &new_lines) {
+        match (op, ops.last_mut()) {
+            (DiffOp::Equal(old, _), Some(&mut DeltaOp::Copy(start, ref mut len))) if start + *len == old => {
+                *len += 1;
+                continue;
+            },
+            (DiffOp::Insert(new_index), Some(&mut DeltaOp::Insert(ref mut bytes))) => {
+                bytes.extend(new_lines[new_index].iter().cloned());
+                continue;
+            },
+            _ => {}
+        }
+        match op {
+            DiffOp::Equal(old, _) => ops.push(DeltaOp::Copy(old, 1)),
+            DiffOp::Insert(new_index) => ops.push(DeltaOp::Insert(new_lines[new_index].to_vec())),
+            DiffOp::Delete(_) => {}
+        }
+    }
+
+    let mut out = DELTA_MAGIC.to_vec();
+    // writing to memory can't fail
+    write_u64(&mut out, base_rev).unwrap();
+    write_u64(&mut out, depth).unwrap();
+    write_u64(&mut out, ops.len() as u64).unwrap();
+    for op in ops.iter() {
+        match *op {
+            DeltaOp::Copy(start, len) => {
+                write_u64(&mut out, OP_COPY).unwrap();
+                write_u64(&mut out, start as u64).unwrap();
+                write_u64(&mut out, len as u64).unwrap();
+            },
+            DeltaOp::Insert(ref data) => {
+                write_u64(&mut out, OP_INSERT).unwrap();
+                write_bytes(&mut out, data).unwrap();
+            }
+        }
+    }
+    out
+}
+
+pub fn delta_header(delta: &[u8]) -> io::Result<(RevisionId, u64)> {
+    // the revision a delta is against and how many deltas deep it is
+    let mut input = Cursor::new(&delta[DELTA_MAGIC.len()..]);
+    let base_rev = try!(read_u64(&mut input));
+    let depth = try!(read_u64(&mut input));
+    Ok((base_rev, depth))
+}
+
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let old_lines = raw_lines(base);
+    let mut input = Cursor::new(&delta[DELTA_MAGIC.len()..]);
+    try!(read_u64(&mut input));
+    try!(read_u64(&mut input));
+    let count = try!(read_u64(&mut input));
+    let mut out = vec![];
+    for _ in 0..count {
+        match try!(read_u64(&mut input)) {
+            OP_COPY => {
+                let start = try!(read_u64(&mut input)) as usize;
+                let len = try!(read_u64(&mut input)) as usize;
+                if start + len > old_lines.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Delta copies lines {}..{} of a {} line base",
+                                                      start, start + len, old_lines.len())));
+                }
+                for line in old_lines[start..start + len].iter() {
+                    out.extend(line.iter().cloned());
+                }
+            },
+            OP_INSERT => {
+                out.extend(try!(read_bytes(&mut input)));
+            },
+            code => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Unknown delta operation {}", code)));
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base = b"one\ntwo\nthree\nfour\nfive";
+        let new = b"one\ntwo\n2.5\nthree\nfive\nsix\n";
+        let delta = make_delta(base, new, 3, 1);
+        assert!(is_delta(&delta));
+        assert_eq!(delta_header(&delta).unwrap(), (3, 1));
+        assert_eq!(apply_delta(base, &delta).unwrap(), new.to_vec());
+        assert_eq!(apply_delta(b"", &make_delta(b"", new, 1, 1)).unwrap(), new.to_vec());
+        assert_eq!(apply_delta(base, &make_delta(base, b"", 1, 1)).unwrap(), b"".to_vec());
+    }
+}
@@ -0,0 +1,792 @@
+use std::io::{BufReader, Cursor};
+
+use std::cmp;
+
+use lines::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    // old index, new index
+    Equal(usize, usize),
+    // old index
+    Delete(usize),
+    // new index
+    Insert(usize)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub ops: Vec<DiffOp>
+}
+
+// changed byte ranges within a line
+pub type Spans = Vec<(usize, usize)>;
+
+// how many lines a change inserts and deletes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiffStat {
+    pub insertions: usize,
+    pub deletions: usize
+}
+
+impl DiffStat {
+    pub fn add(&mut self, other: DiffStat) {
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+    }
+
+    pub fn changes(&self) -> usize {
+        self.insertions + self.deletions
+    }
+}
+
+impl Hunk {
+    pub fn stat(&self) -> DiffStat {
+        let mut stat = DiffStat::default();
+        for op in self.ops.iter() {
+            match *op {
+                DiffOp::Equal(_, _) => {},
+                DiffOp::Delete(_) => {
+                    stat.deletions += 1;
+                },
+                DiffOp::Insert(_) => {
+                    stat.insertions += 1;
+                }
+            }
+        }
+        stat
+    }
+}
+
+pub fn hunks_stat(hunks: &[Hunk]) -> DiffStat {
+    let mut stat = DiffStat::default();
+    for hunk in hunks.iter() {
+        stat.add(hunk.stat());
+    }
+    stat
+}
+
+// edits a diff looks for before it gives up and just says the two differ.
+// finding a script takes time in proportion to its length, so a file that
+// was rewritten would otherwise hold up a status for minutes
+pub const MAX_DIFF_EDITS: usize = 20000;
+
+pub fn diff<T: PartialEq>(old: &[T], new: &[T]) -> Vec<DiffOp> {
+    // Myers' O(ND) algorithm in linear space: find the middle snake of the
+    // shortest edit script, then the scripts on either side of it the same
+    // way. both searches keep one row of furthest reaching paths, shared by
+    // every level
+    let max = (old.len() + new.len() + 1) / 2 + 1;
+    let mut forward = vec![0isize; 2 * max + 3];
+    let mut backward = vec![0isize; 2 * max + 3];
+    let mut ops = Vec::with_capacity(cmp::max(old.len(), new.len()));
+    diff_range(old, new, (0, old.len()), (0, new.len()), &mut forward, &mut backward, &mut ops);
+    ops
+}
+
+pub fn diff_within<T: PartialEq>(old: &[T], new: &[T], max_edits: usize) -> Option<Vec<DiffOp>> {
+    // the same as diff, or none if it takes more than max_edits edits
+    match edit_distance(old, new, max_edits) {
+        Some(_) => Some(diff(old, new)),
+        None => {
+            debug!("More than {} edits between {} and {} lines", max_edits, old.len(), new.len());
+            None
+        }
+    }
+}
+
+pub fn edit_distance<T: PartialEq>(old: &[T], new: &[T], limit: usize) -> Option<usize> {
+    // how many lines the shortest edit script inserts and deletes, if it's
+    // no more than limit. only the forward search, a row at a time
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = cmp::min(old.len() + new.len(), limit) as isize;
+    let off = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    for d in 0..max + 1 {
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + off) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                return Some(d as usize);
+            }
+            k += 2;
+        }
+    }
+    None
+}
+
+fn diff_range<T: PartialEq>(old: &[T], new: &[T], (mut a_lo, a_hi): (usize, usize), (mut b_lo, b_hi): (usize, usize),
+                            forward: &mut [isize], backward: &mut [isize], ops: &mut Vec<DiffOp>) {
+    // the ops turning old[a_lo..a_hi] into new[b_lo..b_hi], appended in order
+    while a_lo < a_hi && b_lo < b_hi && old[a_lo] == new[b_lo] {
+        ops.push(DiffOp::Equal(a_lo, b_lo));
+        a_lo += 1;
+        b_lo += 1;
+    }
+    let mut suffix = 0;
+    while a_lo + suffix < a_hi && b_lo + suffix < b_hi && old[a_hi - suffix - 1] == new[b_hi - suffix - 1] {
+        suffix += 1;
+    }
+    let (a_end, b_end) = (a_hi - suffix, b_hi - suffix);
+
+    if a_lo == a_end {
+        ops.extend((b_lo..b_end).map(DiffOp::Insert));
+    } else if b_lo == b_end {
+        ops.extend((a_lo..a_end).map(DiffOp::Delete));
+    } else {
+        // both sides start and end on a difference, so the script is at
+        // least two long and each side of the snake is shorter than it
+        let (x, y, u, v) = middle_snake(&old[a_lo..a_end], &new[b_lo..b_end], forward, backward);
+        diff_range(old, new, (a_lo, a_lo + x), (b_lo, b_lo + y), forward, backward, ops);
+        ops.extend((0..u - x).map(|i| DiffOp::Equal(a_lo + x + i, b_lo + y + i)));
+        diff_range(old, new, (a_lo + u, a_end), (b_lo + v, b_end), forward, backward, ops);
+    }
+
+    ops.extend((0..suffix).map(|i| DiffOp::Equal(a_end + i, b_end + i)));
+}
+
+fn middle_snake<T: PartialEq>(old: &[T], new: &[T], forward: &mut [isize], backward: &mut [isize])
+                              -> (usize, usize, usize, usize) {
+    // where the middle snake of the shortest edit script starts and ends.
+    // paths are searched from both ends at once until they overlap. the
+    // backward search works on the reversed sequences, where diagonal k is
+    // delta - k going forward
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let off = (forward.len() / 2) as isize;
+    forward[(off + 1) as usize] = 0;
+    backward[(off + 1) as usize] = 0;
+
+    for d in 0..(n + m + 1) / 2 + 1 {
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + off) as usize;
+            let mut x = if k == -d || (k != d && forward[idx - 1] < forward[idx + 1]) {
+                forward[idx + 1]
+            } else {
+                forward[idx - 1] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            let mut y = start_y;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[idx] = x;
+            if odd && k >= delta - (d - 1) && k <= delta + (d - 1) &&
+                x + backward[(delta - k + off) as usize] >= n {
+                return (start_x as usize, start_y as usize, x as usize, y as usize);
+            }
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + off) as usize;
+            let mut x = if k == -d || (k != d && backward[idx - 1] < backward[idx + 1]) {
+                backward[idx + 1]
+            } else {
+                backward[idx - 1] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            let mut y = start_y;
+            while x < n && y < m && old[(n - x - 1) as usize] == new[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[idx] = x;
+            if !odd && delta - k >= -d && delta - k <= d && x + forward[(delta - k + off) as usize] >= n {
+                return ((n - x) as usize, (m - y) as usize, (n - start_x) as usize, (m - start_y) as usize);
+            }
+            k += 2;
+        }
+    }
+    unreachable!()
+}
+
+fn is_change(op: &DiffOp) -> bool {
+    match *op {
+        DiffOp::Equal(_, _) => false,
+        _ => true
+    }
+}
+
+pub fn hunks(ops: &[DiffOp], context: usize) -> Vec<Hunk> {
+    // group changes into hunks with some equal lines around them
+    let changes: Vec<usize> = (0..ops.len()).filter(|&i| is_change(&ops[i])).collect();
+
+    let mut hunks = vec![];
+    let mut i = 0;
+    while i < changes.len() {
+        let start = if changes[i] > context {changes[i] - context} else {0};
+        let mut last = changes[i];
+        i += 1;
+        while i < changes.len() && changes[i] - last <= 2 * context + 1 {
+            last = changes[i];
+            i += 1;
+        }
+        let end = if last + context + 1 < ops.len() {last + context + 1} else {ops.len()};
+        hunks.push(make_hunk(ops, start, end));
+    }
+    hunks
+}
+
+fn make_hunk(ops: &[DiffOp], start: usize, end: usize) -> Hunk {
+    // figure out where in each file this hunk starts
+    let (mut old_start, mut new_start) = (0, 0);
+    for op in ops[..start].iter() {
+        match *op {
+            DiffOp::Equal(_, _) => {
+                old_start += 1;
+                new_start += 1;
+            },
+            DiffOp::Delete(_) => {
+                old_start += 1;
+            },
+            DiffOp::Insert(_) => {
+                new_start += 1;
+            }
+        }
+    }
+
+    let (mut old_len, mut new_len) = (0, 0);
+    for op in ops[start..end].iter() {
+        match *op {
+            DiffOp::Equal(_, _) => {
+                old_len += 1;
+                new_len += 1;
+            },
+            DiffOp::Delete(_) => {
+                old_len += 1;
+            },
+            DiffOp::Insert(_) => {
+                new_len += 1;
+            }
+        }
+    }
+
+    Hunk {
+        old_start: old_start,
+        old_len: old_len,
+        new_start: new_start,
+        new_len: new_len,
+        ops: ops[start..end].to_vec()
+    }
+}
+
+pub fn split_hunk(hunk: &Hunk) -> Vec<Hunk> {
+    // break a hunk up at the equal lines between its runs of changes. each
+    // piece keeps the equal lines on either side of it as context, so
+    // neighbouring pieces share some
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < hunk.ops.len() {
+        if is_change(&hunk.ops[i]) {
+            let start = i;
+            while i < hunk.ops.len() && is_change(&hunk.ops[i]) {
+                i += 1;
+            }
+            runs.push((start, i));
+        } else {
+            i += 1;
+        }
+    }
+    if runs.len() < 2 {
+        return vec![hunk.clone()];
+    }
+
+    let mut pieces = vec![];
+    for j in 0..runs.len() {
+        let start = if j == 0 {0} else {runs[j - 1].1};
+        let end = if j + 1 == runs.len() {hunk.ops.len()} else {runs[j + 1].0};
+        let mut piece = make_hunk(&hunk.ops, start, end);
+        piece.old_start += hunk.old_start;
+        piece.new_start += hunk.new_start;
+        pieces.push(piece);
+    }
+    pieces
+}
+
+pub fn apply_hunks(old: &[u8], new: &[u8], ops: &[DiffOp], accepted: &[Hunk]) -> Vec<u8> {
+    // rebuild the old content with only the changes in the accepted hunks.
+    // ops must be the diff of the two, and the hunks made from those ops
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let old_missing = !old.is_empty() && old[old.len() - 1] != b'\n';
+    let new_missing = !new.is_empty() && new[new.len() - 1] != b'\n';
+    let is_accepted = |op: &DiffOp| accepted.iter().any(|hunk| hunk.ops.contains(op));
+
+    let mut out = vec![];
+    // whether the last line written had no terminator where it came from
+    let mut missing = false;
+    for op in ops.iter() {
+        match *op {
+            DiffOp::Equal(o, _) => {
+                out.extend(old_lines[o].iter().cloned());
+                out.push(b'\n');
+                missing = old_missing && o + 1 == old_lines.len();
+            },
+            DiffOp::Delete(o) => {
+                if !is_accepted(op) {
+                    out.extend(old_lines[o].iter().cloned());
+                    out.push(b'\n');
+                    missing = old_missing && o + 1 == old_lines.len();
+                }
+            },
+            DiffOp::Insert(n) => {
+                if is_accepted(op) {
+                    out.extend(new_lines[n].iter().cloned());
+                    out.push(b'\n');
+                    missing = new_missing && n + 1 == new_lines.len();
+                }
+            }
+        }
+    }
+    if missing {
+        out.pop();
+    }
+    out
+}
+
+// two versions of a file merged against the one they both came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merged {
+    pub data: Vec<u8>,
+    // regions both sides changed differently, left between conflict markers
+    pub conflicts: usize
+}
+
+fn base_matches(ops: &[DiffOp], base_len: usize) -> Vec<Option<usize>> {
+    // where each base line ended up on one side, if it's still there
+    let mut matches = vec![None; base_len];
+    for op in ops.iter() {
+        if let DiffOp::Equal(o, n) = *op {
+            matches[o] = Some(n);
+        }
+    }
+    matches
+}
+
+fn push_lines(out: &mut Vec<u8>, lines: &[Vec<u8>], start: usize, end: usize, missing: bool,
+              last_missing: &mut bool) {
+    for i in start..end {
+        out.extend(lines[i].iter().cloned());
+        out.push(b'\n');
+        *last_missing = missing && i + 1 == lines.len();
+    }
+}
+
+pub fn merge3(base: &[u8], ours: &[u8], theirs: &[u8], labels: (&str, &str)) -> Merged {
+    // diff3: base lines both sides kept split the files into regions, and a
+    // region only one side changed takes that side. a region both changed
+    // the same way takes either, otherwise both go in between markers
+    let (base_lines, ours_lines, theirs_lines) = (split_lines(base), split_lines(ours), split_lines(theirs));
+    let missing = |data: &[u8]| !data.is_empty() && data[data.len() - 1] != b'\n';
+    let (base_missing, ours_missing, theirs_missing) = (missing(base), missing(ours), missing(theirs));
+    let ours_at = base_matches(&diff(&base_lines, &ours_lines), base_lines.len());
+    let theirs_at = base_matches(&diff(&base_lines, &theirs_lines), base_lines.len());
+
+    let mut out = vec![];
+    let mut conflicts = 0;
+    // whether the last line written had no terminator where it came from
+    let mut last_missing = false;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    while i < base_lines.len() || j < ours_lines.len() || k < theirs_lines.len() {
+        if i < base_lines.len() && ours_at[i] == Some(j) && theirs_at[i] == Some(k) {
+            out.extend(base_lines[i].iter().cloned());
+            out.push(b'\n');
+            // a final line both kept loses its terminator if either side dropped it
+            let last = i + 1 == base_lines.len() && j + 1 == ours_lines.len() && k + 1 == theirs_lines.len();
+            last_missing = last && if ours_missing == base_missing {theirs_missing} else {ours_missing};
+            i += 1;
+            j += 1;
+            k += 1;
+            continue;
+        }
+        // the region runs up to the next base line both sides kept
+        let mut next = i;
+        while next < base_lines.len() && (ours_at[next].is_none() || theirs_at[next].is_none()) {
+            next += 1;
+        }
+        let (ours_end, theirs_end) = match (ours_at.get(next), theirs_at.get(next)) {
+            (Some(&Some(ours_end)), Some(&Some(theirs_end))) => (ours_end, theirs_end),
+            _ => (ours_lines.len(), theirs_lines.len())
+        };
+
+        let base_region = &base_lines[i..next];
+        let ours_region = &ours_lines[j..ours_end];
+        let theirs_region = &theirs_lines[k..theirs_end];
+        if ours_region == base_region {
+            push_lines(&mut out, &theirs_lines, k, theirs_end, theirs_missing, &mut last_missing);
+        } else if theirs_region == base_region || ours_region == theirs_region {
+            push_lines(&mut out, &ours_lines, j, ours_end, ours_missing, &mut last_missing);
+        } else {
+            conflicts += 1;
+            out.extend(format!("<<<<<<< {}\n", labels.0).into_bytes());
+            push_lines(&mut out, &ours_lines, j, ours_end, ours_missing, &mut last_missing);
+            out.extend(b"=======\n".iter().cloned());
+            push_lines(&mut out, &theirs_lines, k, theirs_end, theirs_missing, &mut last_missing);
+            out.extend(format!(">>>>>>> {}\n", labels.1).into_bytes());
+            last_missing = false;
+        }
+        i = next;
+        j = ours_end;
+        k = theirs_end;
+    }
+    if last_missing {
+        out.pop();
+    }
+    Merged {
+        data: out,
+        conflicts: conflicts
+    }
+}
+
+fn token_class(byte: u8) -> u8 {
+    if (byte as char).is_alphanumeric() || byte == b'_' || byte >= 0x80 {
+        0
+    } else if (byte as char).is_whitespace() {
+        1
+    } else {
+        2
+    }
+}
+
+fn tokenize(line: &[u8]) -> Spans {
+    // words, runs of whitespace, and single punctuation characters
+    let mut tokens = vec![];
+    let mut start = 0;
+    while start < line.len() {
+        let class = token_class(line[start]);
+        let mut end = start + 1;
+        if class != 2 {
+            while end < line.len() && token_class(line[end]) == class {
+                end += 1;
+            }
+        }
+        tokens.push((start, end));
+        start = end;
+    }
+    tokens
+}
+
+fn push_span(spans: &mut Spans, span: (usize, usize)) {
+    // merge touching spans so highlights stay contiguous
+    if let Some(last) = spans.last_mut() {
+        if last.1 == span.0 {
+            last.1 = span.1;
+            return;
+        }
+    }
+    spans.push(span);
+}
+
+pub fn diff_words(old: &[u8], new: &[u8]) -> (Spans, Spans) {
+    // refine a changed line pair into the byte ranges that actually differ
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    let old_words: Vec<&[u8]> = old_tokens.iter().map(|&(s, e)| &old[s..e]).collect();
+    let new_words: Vec<&[u8]> = new_tokens.iter().map(|&(s, e)| &new[s..e]).collect();
+
+    let mut old_spans = vec![];
+    let mut new_spans = vec![];
+    for op in diff(&old_words, &new_words) {
+        match op {
+            DiffOp::Equal(_, _) => {},
+            DiffOp::Delete(i) => {
+                push_span(&mut old_spans, old_tokens[i]);
+            },
+            DiffOp::Insert(j) => {
+                push_span(&mut new_spans, new_tokens[j]);
+            }
+        }
+    }
+    (old_spans, new_spans)
+}
+
+pub fn split_lines(data: &[u8]) -> Vec<Vec<u8>> {
+    // the data is in memory already and callers join the lines back up, so
+    // none are cut
+    let mut reader = LineReader::new(BufReader::new(Cursor::new(data))).with_max_length(::std::usize::MAX);
+    let mut lines = vec![];
+    let mut line = vec![];
+    // reading from memory can't fail
+    while reader.read_line(&mut line).unwrap() {
+        lines.push(line.clone());
+    }
+    lines
+}
+
+fn render_line(prefix: u8, line: &[u8], spans: &Spans, highlight: bool) -> Vec<u8> {
+    // the line as it is, with changed spans in reverse video for a terminal
+    let mut out = vec![prefix];
+    if !highlight {
+        out.extend(line.iter().cloned());
+        return out;
+    }
+    let mut pos = 0;
+    for &(start, end) in spans.iter() {
+        out.extend(line[pos..start].iter().cloned());
+        out.extend(b"\x1b[7m".iter().cloned());
+        out.extend(line[start..end].iter().cloned());
+        out.extend(b"\x1b[27m".iter().cloned());
+        pos = end;
+    }
+    out.extend(line[pos..].iter().cloned());
+    out
+}
+
+pub fn render_hunk(hunk: &Hunk, old: &[Vec<u8>], new: &[Vec<u8>], highlight: bool) -> Vec<Vec<u8>> {
+    // an empty range names the line it comes after, like other diff tools.
+    // lines keep their own bytes, only highlighting adds any
+    let old_start = if hunk.old_len == 0 {hunk.old_start} else {hunk.old_start + 1};
+    let new_start = if hunk.new_len == 0 {hunk.new_start} else {hunk.new_start + 1};
+    let mut out = vec![format!("@@ -{},{} +{},{} @@", old_start, hunk.old_len, new_start, hunk.new_len).into_bytes()];
+    let mut i = 0;
+    while i < hunk.ops.len() {
+        match hunk.ops[i] {
+            DiffOp::Equal(o, _) => {
+                out.push(render_line(b' ', &old[o], &vec![], highlight));
+                i += 1;
+            },
+            _ => {
+                // collect a run of deletions followed by insertions and pair
+                // them up for intra-line refinement
+                let mut deleted = vec![];
+                let mut inserted = vec![];
+                while i < hunk.ops.len() {
+                    match hunk.ops[i] {
+                        DiffOp::Delete(o) if inserted.is_empty() => deleted.push(o),
+                        DiffOp::Insert(n) => inserted.push(n),
+                        _ => break
+                    }
+                    i += 1;
+                }
+                let mut refined = vec![];
+                for j in 0..deleted.len() {
+                    if highlight && j < inserted.len() {
+                        refined.push(diff_words(&old[deleted[j]], &new[inserted[j]]));
+                    }
+                }
+                for j in 0..deleted.len() {
+                    let spans = if j < refined.len() {refined[j].0.clone()} else {vec![]};
+                    out.push(render_line(b'-', &old[deleted[j]], &spans, highlight));
+                }
+                for j in 0..inserted.len() {
+                    let spans = if j < refined.len() {refined[j].1.clone()} else {vec![]};
+                    out.push(render_line(b'+', &new[inserted[j]], &spans, highlight));
+                }
+            }
+        }
+    }
+    out
+}
+
+pub fn render_stat(files: &[(String, DiffStat)], width: usize) -> Vec<String> {
+    // one line per file with its change count and a bar of +s and -s, then
+    // a summary. bars are scaled down to fit in width columns
+    let name_width = files.iter().map(|&(ref name, _)| name.chars().count()).max().unwrap_or(0);
+    let most = files.iter().map(|&(_, ref stat)| stat.changes()).max().unwrap_or(0);
+    let count_width = format!("{}", most).len();
+    let used = name_width + count_width + 5;
+    let bar_width = if width > used + 10 {width - used} else {10};
+
+    let mut out = vec![];
+    let mut total = DiffStat::default();
+    for &(ref name, stat) in files.iter() {
+        total.add(stat);
+        let (mut plus, mut minus) = (stat.insertions, stat.deletions);
+        if most > bar_width {
+            plus = plus * bar_width / most;
+            minus = minus * bar_width / most;
+            // anything that changed gets at least one mark
+            if plus == 0 && stat.insertions > 0 {
+                plus = 1;
+            }
+            if minus == 0 && stat.deletions > 0 {
+                minus = 1;
+            }
+        }
+        let padding = name_width - name.chars().count();
+        out.push(format!(" {}{} | {:>width$} {}{}", name, repeat(' ', padding), stat.changes(),
+                         repeat('+', plus), repeat('-', minus), width = count_width));
+    }
+    out.push(format!(" {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+                     files.len(), if files.len() == 1 {""} else {"s"},
+                     total.insertions, if total.insertions == 1 {""} else {"s"},
+                     total.deletions, if total.deletions == 1 {""} else {"s"}));
+    out
+}
+
+fn repeat(c: char, count: usize) -> String {
+    (0..count).map(|_| c).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(old: &[&str], new: &[&str], ops: &[DiffOp]) -> Vec<String> {
+        // rebuild the new sequence from the old one and the edit script
+        let mut out = vec![];
+        for op in ops.iter() {
+            match *op {
+                DiffOp::Equal(o, n) => {
+                    assert_eq!(old[o], new[n]);
+                    out.push(old[o].to_string());
+                },
+                DiffOp::Delete(_) => {},
+                DiffOp::Insert(n) => {
+                    out.push(new[n].to_string());
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_diff_empty() {
+        let empty: Vec<&str> = vec![];
+        assert_eq!(diff(&empty, &empty), vec![]);
+        assert_eq!(diff(&empty, &["a"]), vec![DiffOp::Insert(0)]);
+        assert_eq!(diff(&["a"], &empty), vec![DiffOp::Delete(0)]);
+    }
+
+    #[test]
+    fn test_diff_basic() {
+        let old = ["a", "b", "c", "a", "b", "b", "a"];
+        let new = ["c", "b", "a", "b", "a", "c"];
+        let ops = diff(&old, &new);
+        assert_eq!(apply(&old, &new, &ops), new.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        // the shortest edit script for this pair is five edits long
+        let edits = ops.iter().filter(|op| match **op {DiffOp::Equal(_, _) => false, _ => true}).count();
+        assert_eq!(edits, 5);
+    }
+
+    #[test]
+    fn test_diff_within() {
+        let old: Vec<usize> = (0..1000).collect();
+        let mut new = old.clone();
+        new[10] = 5000;
+        new.insert(500, 6000);
+        new.remove(900);
+        assert_eq!(edit_distance(&old, &new, 100), Some(4));
+        assert_eq!(edit_distance(&old, &new, 3), None);
+        let ops = diff_within(&old, &new, 4).unwrap();
+        assert_eq!(ops, diff(&old, &new));
+        assert_eq!(ops.iter().filter(|op| match **op {DiffOp::Equal(_, _) => false, _ => true}).count(), 4);
+        assert!(diff_within(&old, &new, 3).is_none());
+
+        // a rewrite gives up quickly instead of holding a row per edit
+        let rewritten: Vec<usize> = (100000..200000).collect();
+        let from: Vec<usize> = (0..100000).collect();
+        assert!(diff_within(&from, &rewritten, MAX_DIFF_EDITS).is_none());
+    }
+
+    #[test]
+    fn test_hunks() {
+        let old: Vec<String> = (0..20).map(|i| format!("{}", i)).collect();
+        let mut new = old.clone();
+        new[2] = "two".to_string();
+        new[17] = "seventeen".to_string();
+        let ops = diff(&old, &new);
+        let hunks = hunks(&ops, 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].old_start, hunks[0].old_len), (0, 6));
+        assert_eq!((hunks[1].new_start, hunks[1].new_len), (14, 6));
+    }
+
+    #[test]
+    fn test_split_and_apply_hunks() {
+        let old = b"a\nb\nc\nd\ne";
+        let new = b"a\nB\nc\nd\nE\nf";
+        let ops = diff(&split_lines(old), &split_lines(new));
+        let file_hunks = hunks(&ops, 1);
+        assert_eq!(file_hunks.len(), 1);
+        let pieces = split_hunk(&file_hunks[0]);
+        assert_eq!(pieces.len(), 2);
+        assert_eq!((pieces[1].old_start, pieces[1].new_start), (2, 2));
+        assert_eq!(split_hunk(&pieces[0]), vec![pieces[0].clone()]);
+
+        assert_eq!(apply_hunks(old, new, &ops, &[]), old.to_vec());
+        assert_eq!(apply_hunks(old, new, &ops, &file_hunks), new.to_vec());
+        assert_eq!(apply_hunks(old, new, &ops, &pieces[..1]), b"a\nB\nc\nd\ne".to_vec());
+        assert_eq!(apply_hunks(old, new, &ops, &pieces[1..]), b"a\nb\nc\nd\nE\nf".to_vec());
+    }
+
+    #[test]
+    fn test_diff_stat() {
+        let old = split_lines(b"a\nb\nc\n");
+        let new = split_lines(b"a\nB\nc\nd\n");
+        let stat = hunks_stat(&hunks(&diff(&old, &new), 3));
+        assert_eq!(stat, DiffStat {insertions: 2, deletions: 1});
+
+        let files = vec![("lib.rs".to_string(), stat),
+                         ("a".to_string(), DiffStat {insertions: 0, deletions: 100})];
+        let lines = render_stat(&files, 40);
+        assert_eq!(lines[0], " lib.rs |   3 +-");
+        assert_eq!(lines[1], format!(" a      | 100 {}", repeat('-', 26)));
+        assert_eq!(lines[2], " 2 files changed, 2 insertions(+), 101 deletions(-)");
+    }
+
+    #[test]
+    fn test_merge3() {
+        let base = b"a\nb\nc\nd\ne\n";
+        // changes on different lines both go in
+        let merged = merge3(base, b"a\nB\nc\nd\ne\n", b"a\nb\nc\nD\ne\nf\n", ("ours", "theirs"));
+        assert_eq!(merged, Merged {data: b"a\nB\nc\nD\ne\nf\n".to_vec(), conflicts: 0});
+        // the same change on both sides isn't a conflict
+        let merged = merge3(base, b"a\nx\nc\nd\ne\n", b"a\nx\nc\nd\ne\n", ("ours", "theirs"));
+        assert_eq!(merged.data, b"a\nx\nc\nd\ne\n".to_vec());
+        assert_eq!(merged.conflicts, 0);
+        // a deletion on one side and nothing on the other
+        assert_eq!(merge3(base, b"a\ne\n", base, ("ours", "theirs")).data, b"a\ne\n".to_vec());
+
+        let merged = merge3(base, b"a\nours\nc\nd\ne\n", b"a\ntheirs\nc\nd\ne", ("ours", "theirs"));
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(String::from_utf8(merged.data).unwrap(),
+                   "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\nd\ne");
+
+        // both adding to an empty file conflicts unless they add the same
+        let merged = merge3(b"", b"one\n", b"two\n", ("main", "topic"));
+        assert_eq!(merged.data, b"<<<<<<< main\none\n=======\ntwo\n>>>>>>> topic\n".to_vec());
+        assert_eq!(merge3(b"", b"one", b"one", ("main", "topic")).data, b"one".to_vec());
+    }
+
+    #[test]
+    fn test_diff_words() {
+        let (old, new) = diff_words(b"let x = foo(1);", b"let y = foo(2);");
+        assert_eq!(old, vec![(4, 5), (12, 13)]);
+        assert_eq!(new, vec![(4, 5), (12, 13)]);
+    }
+
+    #[test]
+    fn test_render_hunk() {
+        let old = vec![b"let x = 1;".to_vec(), b"caf\xe9".to_vec()];
+        let new = vec![b"let y = 1;".to_vec(), b"caf\xe9".to_vec()];
+        let ops = diff(&old, &new);
+        let found = hunks(&ops, 3);
+        assert_eq!(render_hunk(&found[0], &old, &new, false),
+                   vec![b"@@ -1,2 +1,2 @@".to_vec(), b"-let x = 1;".to_vec(), b"+let y = 1;".to_vec(),
+                        b" caf\xe9".to_vec()]);
+        assert_eq!(render_hunk(&found[0], &old, &new, true)[2], b"+let \x1b[7my\x1b[27m = 1;".to_vec());
+    }
+}
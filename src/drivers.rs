@@ -0,0 +1,217 @@
+use std::path::Path;
+use std::io::{BufRead, Read};
+
+use std::fmt;
+use std::io;
+
+use config::*;
+use ignore::*;
+use lines::*;
+use chunks::*;
+use pathid::*;
+
+// how a file is indexed and diffed. an index records the driver that built
+// it, and a file is only compared with the driver its index was built by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum DiffDriver {
+    // every line in the index, the default
+    Lines,
+    // just a hash of the whole file, for binary files where lines mean nothing
+    Whole,
+    // content defined blocks indexed the way lines are, for big files
+    Blocks,
+    // not indexed or diffed at all, only tracked
+    Skip
+}
+
+impl Default for DiffDriver {
+    fn default() -> DiffDriver {
+        DiffDriver::Lines
+    }
+}
+
+impl fmt::Display for DiffDriver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DiffDriver::Lines => write!(f, "lines"),
+            DiffDriver::Whole => write!(f, "whole"),
+            DiffDriver::Blocks => write!(f, "blocks"),
+            DiffDriver::Skip => write!(f, "skip")
+        }
+    }
+}
+
+impl DiffDriver {
+    pub fn from_name(name: &str) -> Option<DiffDriver> {
+        match name {
+            "lines" => Some(DiffDriver::Lines),
+            "whole" => Some(DiffDriver::Whole),
+            "blocks" => Some(DiffDriver::Blocks),
+            "skip" => Some(DiffDriver::Skip),
+            _ => None
+        }
+    }
+
+    pub fn has_lines(&self) -> bool {
+        // whether a diff of the file can be shown line by line
+        *self == DiffDriver::Lines
+    }
+}
+
+// which driver each path gets, from the diff_drivers config setting: space
+// separated `glob:driver` rules where the last one to match wins. a glob
+// without a `/` matches the file name at any level, like in an ignore file
+#[derive(Debug, Clone, Default)]
+pub struct DriverRules {
+    rules: Vec<(Vec<u8>, DiffDriver)>
+}
+
+impl DriverRules {
+    pub fn from_config(config: &Config) -> io::Result<DriverRules> {
+        let mut rules = DriverRules::default();
+        if let Some(value) = config.get("diff_drivers") {
+            for rule in value.split_whitespace() {
+                let driver = match rule.rfind(':') {
+                    Some(split) if split > 0 => DiffDriver::from_name(&rule[split + 1..]).map(|driver| (split, driver)),
+                    _ => None
+                };
+                match driver {
+                    Some((split, driver)) => {
+                        rules.add_rule(&rule[..split], driver);
+                    },
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Config value diff_drivers has {:?}, which is not \
+                                                           glob:lines, whole, blocks or skip", rule)));
+                    }
+                }
+            }
+            debug!("Read {} diff driver rules", rules.rules.len());
+        }
+        Ok(rules)
+    }
+
+    pub fn add_rule(&mut self, glob: &str, driver: DiffDriver) {
+        self.rules.push((glob.trim_left_matches('/').as_bytes().to_vec(), driver));
+    }
+
+    pub fn driver_for(&self, id: &Path) -> DiffDriver {
+        let id = id_bytes(id);
+        let name = match id.iter().rposition(|&c| c == b'/') {
+            Some(split) => &id[split + 1..],
+            None => &id[..]
+        };
+        for &(ref glob, driver) in self.rules.iter().rev() {
+            let text = if glob.contains(&b'/') {&id[..]} else {name};
+            if glob_match(glob, text) {
+                return driver;
+            }
+        }
+        DiffDriver::default()
+    }
+}
+
+// the units a driver splits a file into, which are indexed and diffed the
+// way lines are. blocks need the whole file to find where they're cut
+pub enum UnitReader<R: BufRead> {
+    Lines(LineReader<R>),
+    Blocks {
+        data: Vec<u8>,
+        cuts: Vec<usize>,
+        next: usize
+    }
+}
+
+impl<R: BufRead> UnitReader<R> {
+    pub fn new(driver: DiffDriver, mut inner: R, max_line_length: usize) -> io::Result<UnitReader<R>> {
+        match driver {
+            DiffDriver::Blocks => {
+                let mut data = vec![];
+                try!(inner.read_to_end(&mut data));
+                let cuts = cut_points(&data);
+                trace!("{} blocks in {} bytes", cuts.len(), data.len());
+                Ok(UnitReader::Blocks {
+                    data: data,
+                    cuts: cuts,
+                    next: 0
+                })
+            },
+            _ => Ok(UnitReader::Lines(LineReader::new(inner).with_max_length(max_line_length)))
+        }
+    }
+
+    pub fn read_unit(&mut self, unit: &mut Vec<u8>) -> io::Result<bool> {
+        match *self {
+            UnitReader::Lines(ref mut reader) => reader.read_line(unit),
+            UnitReader::Blocks {ref data, ref cuts, ref mut next} => {
+                unit.clear();
+                if *next >= cuts.len() {
+                    return Ok(false);
+                }
+                let start = if *next == 0 {0} else {cuts[*next - 1]};
+                unit.extend(data[start..cuts[*next]].iter().cloned());
+                *next += 1;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn missing_newline(&self) -> bool {
+        // blocks keep every byte, so they have no terminator to lose
+        match *self {
+            UnitReader::Lines(ref reader) => reader.missing_newline(),
+            UnitReader::Blocks {..} => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::*;
+    use lines::DEFAULT_MAX_LINE_LENGTH;
+    use std::path::Path;
+    use std::io;
+
+    #[test]
+    fn test_driver_rules() {
+        let config = Config::parse("diff_drivers = *.png:whole big/**:blocks big/notes.txt:lines *.log:skip").unwrap();
+        let rules = DriverRules::from_config(&config).unwrap();
+        assert_eq!(rules.driver_for(Path::new("a.txt")), DiffDriver::Lines);
+        assert_eq!(rules.driver_for(Path::new("img/logo.png")), DiffDriver::Whole);
+        assert_eq!(rules.driver_for(Path::new("big/data.bin")), DiffDriver::Blocks);
+        assert_eq!(rules.driver_for(Path::new("big/notes.txt")), DiffDriver::Lines);
+        assert_eq!(rules.driver_for(Path::new("big/run.log")), DiffDriver::Skip);
+
+        for bad in ["diff_drivers = *.png", "diff_drivers = *.png:binary", "diff_drivers = :whole"].iter() {
+            assert!(DriverRules::from_config(&Config::parse(bad).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_unit_reader() {
+        let mut units = vec![];
+        let mut unit = vec![];
+        let mut reader = UnitReader::new(DiffDriver::Lines, io::Cursor::new(b"one\ntwo".to_vec()),
+                                         DEFAULT_MAX_LINE_LENGTH).unwrap();
+        while reader.read_unit(&mut unit).unwrap() {
+            units.push(unit.clone());
+        }
+        assert_eq!(units, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert!(reader.missing_newline());
+
+        // blocks put back together are the file, terminators and all
+        let data: Vec<u8> = (0..300000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut joined = vec![];
+        let mut count = 0;
+        let mut reader = UnitReader::new(DiffDriver::Blocks, io::Cursor::new(data.clone()),
+                                         DEFAULT_MAX_LINE_LENGTH).unwrap();
+        while reader.read_unit(&mut unit).unwrap() {
+            joined.extend(unit.iter().cloned());
+            count += 1;
+        }
+        assert_eq!(joined, data);
+        assert!(count > 1);
+        assert!(!reader.missing_newline());
+    }
+}
@@ -0,0 +1,105 @@
+use std::error::Error;
+
+use std::fmt;
+use std::io;
+
+// what went wrong for the h2 binary, which decides how it exits. the library
+// still returns io::Error, which converts into a repository error
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // the command line didn't ask for anything h2 can do
+    Usage,
+    // the repository couldn't be read, written or trusted
+    Repository
+}
+
+#[derive(Debug)]
+pub struct H2Error {
+    kind: ErrorKind,
+    message: String,
+    cause: Option<Box<Error + Send + Sync>>
+}
+
+impl H2Error {
+    pub fn usage<T: Into<String>>(message: T) -> H2Error {
+        H2Error {
+            kind: ErrorKind::Usage,
+            message: message.into(),
+            cause: None
+        }
+    }
+
+    pub fn repository<T: Into<String>>(message: T) -> H2Error {
+        H2Error {
+            kind: ErrorKind::Repository,
+            message: message.into(),
+            cause: None
+        }
+    }
+
+    pub fn with_cause<E: Into<Box<Error + Send + Sync>>>(mut self, cause: E) -> Self {
+        self.cause = Some(cause.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for H2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // usage errors are read as they are, failures say what they failed on
+        match self.cause {
+            Some(ref cause) if self.kind == ErrorKind::Repository => write!(f, "{}: {}", self.message, cause),
+            _ => write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl Error for H2Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self.cause {
+            Some(ref cause) => Some(&**cause),
+            None => None
+        }
+    }
+}
+
+impl From<io::Error> for H2Error {
+    fn from(e: io::Error) -> H2Error {
+        H2Error::repository("Repository error").with_cause(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::error::Error;
+    use std::io;
+
+    #[test]
+    fn test_display() {
+        let e = H2Error::repository("Status failed").with_cause(io::Error::new(io::ErrorKind::NotFound, "no repository"));
+        assert_eq!(format!("{}", e), "Status failed: no repository");
+        assert_eq!(format!("{}", e.cause().unwrap()), "no repository");
+        assert_eq!(format!("{}", H2Error::usage("Usage: h2 add <path>...")), "Usage: h2 add <path>...");
+    }
+
+    #[test]
+    fn test_from_io() {
+        let e: H2Error = io::Error::new(io::ErrorKind::Other, "broken").into();
+        assert_eq!(e.kind(), ErrorKind::Repository);
+        assert!(e.cause().is_some());
+    }
+}
@@ -0,0 +1,527 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::hash::Hasher;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::thread;
+
+use instrument::*;
+use platform::*;
+use config::*;
+use hashers::*;
+
+// suffix of files that are still being written
+pub const TEMP_SUFFIX: &'static str = ".h2tmp";
+// buffer size for batched copies, big enough that large files aren't
+// copied a few kilobytes at a time
+const COPY_BUFFER_SIZE: usize = 1 << 18;
+// how many more times a file that changed while it was copied is copied again
+pub const DEFAULT_COPY_RETRIES: usize = 3;
+
+// how files are put into the stage, set with the stage_copy config setting.
+// cloning and linking fall back to the next way down when the filesystem
+// can't do them, ending with a plain copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyStrategy {
+    Copy,
+    // a copy-on-write clone, which shares blocks until either side changes
+    Clone,
+    // a clone, or else a hard link. a link is the checkout's own file, so
+    // an editor that writes in place changes the stage along with it
+    Link
+}
+
+impl Default for CopyStrategy {
+    fn default() -> CopyStrategy {
+        CopyStrategy::Copy
+    }
+}
+
+impl CopyStrategy {
+    pub fn from_config(config: &Config) -> io::Result<CopyStrategy> {
+        match config.get("stage_copy") {
+            None | Some("copy") => Ok(CopyStrategy::Copy),
+            Some("clone") => Ok(CopyStrategy::Clone),
+            Some("link") => Ok(CopyStrategy::Link),
+            Some(value) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Config value stage_copy = {:?} is not copy, clone or link", value)))
+            }
+        }
+    }
+}
+
+// copies queued up to run together: every directory is created once up
+// front, then the files are copied by a pool of threads
+#[derive(Debug, Default)]
+pub struct CopyBatch {
+    jobs: Vec<(PathBuf, PathBuf)>,
+    threads: usize,
+    retries: usize,
+    strategy: CopyStrategy,
+    // whether copies are read back and hashed
+    checked: bool,
+    // destinations of copies whose source never held still, since the
+    // last take_unsettled
+    unsettled: Vec<PathBuf>,
+    // destinations of checked copies with their hashes, since the last
+    // take_hashes
+    hashes: Vec<(PathBuf, u64)>
+}
+
+pub fn temp_path<T: AsRef<Path>>(path: T) -> io::Result<PathBuf> {
+    // a sibling of the path, so renaming over it stays on the same filesystem
+    let path = path.as_ref();
+    let mut name = match path.file_name() {
+        Some(name) => name.to_os_string(),
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{} has no file name to write next to", path.display())));
+        }
+    };
+    name.push(TEMP_SUFFIX);
+    Ok(path.with_file_name(name))
+}
+
+pub fn is_temp_path<T: AsRef<Path>>(path: T) -> bool {
+    match path.as_ref().file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.ends_with(TEMP_SUFFIX),
+        None => false
+    }
+}
+
+pub fn commit_temp<T: AsRef<Path>>(path: T) -> io::Result<()> {
+    // move a finished temporary file over its destination
+    let path = path.as_ref();
+    match fs::rename(try!(temp_path(path)), path) {
+        Err(e) => {
+            error!("Failed to move temporary file over {}: {}", path.display(), e);
+            Err(e)
+        },
+        Ok(()) => {
+            trace!("Moved temporary file over {:?}", path);
+            Ok(())
+        }
+    }
+}
+
+pub fn atomic_write<T: AsRef<Path>>(path: T, data: &[u8]) -> io::Result<()> {
+    // write to a temporary file first so a partial write is never mistaken
+    // for the real thing
+    let path = path.as_ref();
+    let temp = try!(temp_path(path));
+    trace!("Writing {} bytes to {:?}", data.len(), &temp);
+    match fs::File::create(&temp).and_then(|mut f| f.write_all(data)) {
+        Err(e) => {
+            error!("Failed to write {}: {}", temp.display(), e);
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        },
+        Ok(()) => {
+            trace!("Wrote temporary file");
+        }
+    }
+    commit_temp(path)
+}
+
+pub fn atomic_copy<T: AsRef<Path>, V: AsRef<Path>>(from: T, to: V) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let temp = try!(temp_path(to));
+    trace!("Copying {:?} to {:?}", from, &temp);
+    match fs::copy(from, &temp) {
+        Err(e) => {
+            error!("Failed to copy {} to {}: {}", from.display(), temp.display(), e);
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        },
+        Ok(copied) => {
+            trace!("Copied to temporary file");
+            count(Counter::BytesCopied, copied);
+        }
+    }
+    commit_temp(to)
+}
+
+pub fn share_file<T: AsRef<Path>, V: AsRef<Path>>(from: T, to: V, strategy: CopyStrategy) -> io::Result<bool> {
+    // put a file in place without copying its bytes, if the strategy and
+    // the filesystem allow it. false leaves the copy to the caller
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if strategy == CopyStrategy::Copy {
+        return Ok(false);
+    }
+    let temp = try!(temp_path(to));
+    let _ = fs::remove_file(&temp);
+    let shared = if try!(clone_file(from, &temp)) {
+        trace!("Cloned {:?} to {:?}", from, &temp);
+        true
+    } else if strategy == CopyStrategy::Link {
+        match fs::hard_link(from, &temp) {
+            Err(e) => {
+                debug!("Failed to link {:?}, copying it: {}", from, e);
+                false
+            },
+            Ok(()) => {
+                trace!("Linked {:?} to {:?}", from, &temp);
+                true
+            }
+        }
+    } else {
+        false
+    };
+    if !shared {
+        return Ok(false);
+    }
+    count(Counter::FilesShared, 1);
+    try!(commit_temp(to));
+    // renaming over another link to the same file leaves the temp behind
+    let _ = fs::remove_file(&temp);
+    Ok(true)
+}
+
+fn stamp(path: &Path) -> io::Result<(u64, i64, i64)> {
+    // what tells that a file changed while it was being read
+    let metadata = try!(fs::metadata(path));
+    let (secs, nsecs) = mtime(&metadata);
+    Ok((metadata.len(), secs, nsecs))
+}
+
+pub fn settled_read<T, F: FnMut() -> io::Result<T>>(path: &Path, retries: usize, mut read: F)
+                                                    -> io::Result<(T, bool)> {
+    // run something that reads a file until the file has the same size and
+    // mtime after as before, at most retries more times. the last result
+    // comes back either way, along with whether the file held still for it
+    let mut attempts = 0;
+    loop {
+        let before = try!(stamp(path));
+        let value = try!(read());
+        if try!(stamp(path)) == before {
+            return Ok((value, true));
+        }
+        if attempts >= retries {
+            warn!("{:?} kept changing while it was read", path);
+            return Ok((value, false));
+        }
+        attempts += 1;
+        debug!("{:?} changed while it was read, reading it again", path);
+    }
+}
+
+// a reader that hashes what's read through it, if it has a hasher
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Option<FnvHasher>
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.write(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    // the hash checked copies are recorded with
+    let mut hasher = FnvHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+pub fn hash_file<T: AsRef<Path>>(path: T) -> io::Result<u64> {
+    // like hash_bytes, without reading the whole file in at once
+    let input = try!(fs::File::open(path));
+    let mut reader = HashingReader {
+        inner: BufReader::with_capacity(COPY_BUFFER_SIZE, input),
+        hasher: Some(FnvHasher::default())
+    };
+    try!(io::copy(&mut reader, &mut io::sink()));
+    Ok(reader.hasher.map_or(0, |hasher| hasher.finish()))
+}
+
+fn copy_through(from: &Path, to: &Path, hash: bool) -> io::Result<(u64, Option<u64>)> {
+    // bytes copied, and their hash if it was asked for
+    let temp = try!(temp_path(to));
+    trace!("Copying {:?} to {:?}", from, &temp);
+    let result = fs::File::open(from).and_then(|input| {
+        let output = try!(fs::File::create(&temp));
+        let mut reader = HashingReader {
+            inner: BufReader::with_capacity(COPY_BUFFER_SIZE, input),
+            hasher: if hash {Some(FnvHasher::default())} else {None}
+        };
+        let mut writer = BufWriter::with_capacity(COPY_BUFFER_SIZE, output);
+        let copied = try!(io::copy(&mut reader, &mut writer));
+        try!(writer.flush());
+        Ok((copied, reader.hasher.map(|hasher| hasher.finish())))
+    });
+    match result {
+        Err(e) => {
+            error!("Failed to copy {} to {}: {}", from.display(), temp.display(), e);
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        },
+        Ok((copied, hash)) => {
+            count(Counter::BytesCopied, copied);
+            try!(commit_temp(to));
+            Ok((copied, hash))
+        }
+    }
+}
+
+pub fn buffered_copy<T: AsRef<Path>, V: AsRef<Path>>(from: T, to: V) -> io::Result<u64> {
+    // like atomic_copy, with large buffers on both ends
+    copy_through(from.as_ref(), to.as_ref(), false).map(|(copied, _)| copied)
+}
+
+pub fn checked_copy<T: AsRef<Path>, V: AsRef<Path>>(from: T, to: V, strategy: CopyStrategy) -> io::Result<u64> {
+    // copy, clone or link a file, then read back what was put in place and
+    // fail if it doesn't hash the same as what was read. returns the hash
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let hash = if try!(share_file(from, to, strategy)) {
+        try!(hash_file(from))
+    } else {
+        try!(copy_through(from, to, true)).1.unwrap_or(0)
+    };
+    let written = try!(hash_file(to));
+    if written != hash {
+        error!("Copy of {:?} hashes to {:016x}, not {:016x}", from, written, hash);
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Copy of {} at {} doesn't match what was read",
+                                          from.display(), to.display())));
+    }
+    Ok(hash)
+}
+
+impl CopyBatch {
+    pub fn new(threads: usize) -> CopyBatch {
+        CopyBatch {
+            jobs: vec![],
+            threads: if threads == 0 {1} else {threads},
+            retries: DEFAULT_COPY_RETRIES,
+            strategy: CopyStrategy::Copy,
+            checked: false,
+            unsettled: vec![],
+            hashes: vec![]
+        }
+    }
+
+    pub fn with_checked(mut self, checked: bool) -> CopyBatch {
+        self.checked = checked;
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: CopyStrategy) -> CopyBatch {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: usize) -> CopyBatch {
+        self.retries = retries;
+        self
+    }
+
+    pub fn take_unsettled(&mut self) -> Vec<PathBuf> {
+        mem::replace(&mut self.unsettled, vec![])
+    }
+
+    pub fn take_hashes(&mut self) -> Vec<(PathBuf, u64)> {
+        mem::replace(&mut self.hashes, vec![])
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn add<T: Into<PathBuf>, V: Into<PathBuf>>(&mut self, from: T, to: V) {
+        self.jobs.push((from.into(), to.into()));
+    }
+
+    pub fn run(&mut self) -> io::Result<usize> {
+        // copy everything queued, returning how many files were copied. on
+        // an error the remaining copies are abandoned
+        let jobs = mem::replace(&mut self.jobs, vec![]);
+        let count = jobs.len();
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let dirs: BTreeSet<PathBuf> = jobs.iter().filter_map(|job| job.1.parent().map(|p| p.to_path_buf())).collect();
+        debug!("Creating {} directories for {} copies", dirs.len(), count);
+        for dir in dirs.iter() {
+            match fs::create_dir_all(dir) {
+                Err(e) => {
+                    error!("Failed to create directory {}: {}", dir.display(), e);
+                    return Err(e);
+                },
+                Ok(()) => {
+                    trace!("Created {:?}", dir);
+                }
+            }
+        }
+
+        let queue = Arc::new(Mutex::new(jobs));
+        let failure: Arc<Mutex<Option<io::Error>>> = Arc::new(Mutex::new(None));
+        let unsettled: Arc<Mutex<Vec<PathBuf>>> = Arc::new(Mutex::new(vec![]));
+        let hashes: Arc<Mutex<Vec<(PathBuf, u64)>>> = Arc::new(Mutex::new(vec![]));
+        let (retries, strategy, checked) = (self.retries, self.strategy, self.checked);
+        let workers: Vec<_> = (0..self.threads).map(|_| {
+            let (queue, failure, unsettled, hashes) = (queue.clone(), failure.clone(), unsettled.clone(),
+                                                       hashes.clone());
+            thread::spawn(move || {
+                loop {
+                    if failure.lock().unwrap().is_some() {
+                        return;
+                    }
+                    let job = queue.lock().unwrap().pop();
+                    let (from, to) = match job {
+                        Some(job) => job,
+                        None => return
+                    };
+                    let copy = || {
+                        if checked {
+                            checked_copy(&from, &to, strategy).map(Some)
+                        } else if try!(share_file(&from, &to, strategy)) {
+                            Ok(None)
+                        } else {
+                            buffered_copy(&from, &to).map(|_| None)
+                        }
+                    };
+                    match settled_read(&from, retries, copy) {
+                        Ok((hash, settled)) => {
+                            if let Some(hash) = hash {
+                                hashes.lock().unwrap().push((to.clone(), hash));
+                            }
+                            if !settled {
+                                unsettled.lock().unwrap().push(to);
+                            }
+                        },
+                        Err(e) => {
+                            let mut failure = failure.lock().unwrap();
+                            if failure.is_none() {
+                                *failure = Some(e);
+                            }
+                        }
+                    }
+                }
+            })
+        }).collect();
+
+        for worker in workers {
+            if worker.join().is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other, "A copy thread panicked"));
+            }
+        }
+        self.unsettled.extend(mem::replace(&mut *unsettled.lock().unwrap(), vec![]));
+        self.hashes.extend(mem::replace(&mut *hashes.lock().unwrap(), vec![]));
+        let failure = failure.lock().unwrap().take();
+        match failure {
+            Some(e) => Err(e),
+            None => {
+                debug!("Copied {} files", count);
+                Ok(count)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::fs;
+    use std::env;
+
+    #[test]
+    fn test_copy_batch() {
+        let root = env::temp_dir().join("h2-test-copy-batch");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("from")).unwrap();
+
+        let mut batch = CopyBatch::new(3);
+        for i in 0..20 {
+            let from = root.join("from").join(format!("{}", i));
+            fs::File::create(&from).unwrap().write_all(format!("file {}\n", i).as_ref()).unwrap();
+            batch.add(from, root.join("to").join(format!("{}", i % 4)).join(format!("{}", i)));
+        }
+        assert_eq!(batch.run().unwrap(), 20);
+        assert_eq!(batch.len(), 0);
+
+        let mut data = String::new();
+        fs::File::open(root.join("to/3/7")).unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "file 7\n");
+        assert!(batch.take_unsettled().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_share_file() {
+        let root = env::temp_dir().join("h2-test-share-file");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let from = root.join("from");
+        fs::File::create(&from).unwrap().write_all(b"shared\n").unwrap();
+
+        assert!(!share_file(&from, root.join("copied"), CopyStrategy::Copy).unwrap());
+        assert!(fs::metadata(root.join("copied")).is_err());
+        // whether there's anything to clone onto depends on the filesystem
+        if share_file(&from, root.join("cloned"), CopyStrategy::Clone).unwrap() {
+            let mut data = String::new();
+            fs::File::open(root.join("cloned")).unwrap().read_to_string(&mut data).unwrap();
+            assert_eq!(data, "shared\n");
+        }
+
+        // a link is the same file, and linking it again leaves nothing behind
+        assert!(share_file(&from, root.join("linked"), CopyStrategy::Link).unwrap());
+        assert!(share_file(&from, root.join("linked"), CopyStrategy::Link).unwrap());
+        assert!(fs::metadata(temp_path(root.join("linked")).unwrap()).is_err());
+        fs::OpenOptions::new().append(true).open(&from).unwrap().write_all(b"more\n").unwrap();
+        let mut data = String::new();
+        fs::File::open(root.join("linked")).unwrap().read_to_string(&mut data).unwrap();
+        if data != "shared\nmore\n" {
+            // cloned rather than linked, so it kept the old content
+            assert_eq!(data, "shared\n");
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_settled_read() {
+        let path = env::temp_dir().join("h2-test-settled-read");
+        fs::File::create(&path).unwrap().write_all(b"steady\n").unwrap();
+
+        let mut reads = 0;
+        let (_, settled) = settled_read(&path, 2, || {
+            reads += 1;
+            Ok(())
+        }).unwrap();
+        assert!(settled);
+        assert_eq!(reads, 1);
+
+        // growing on every read, it never settles and is read once per retry
+        reads = 0;
+        let (last, settled) = settled_read(&path, 2, || {
+            reads += 1;
+            try!(fs::OpenOptions::new().append(true).open(&path)).write_all(b"more\n").map(|_| reads)
+        }).unwrap();
+        assert!(!settled);
+        assert_eq!((reads, last), (3, 3));
+
+        // settling down after the first read
+        reads = 0;
+        let (_, settled) = settled_read(&path, 2, || {
+            reads += 1;
+            if reads == 1 {
+                try!(fs::OpenOptions::new().append(true).open(&path).and_then(|mut f| f.write_all(b"once\n")));
+            }
+            Ok(())
+        }).unwrap();
+        assert!(settled);
+        assert_eq!(reads, 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+
+use std::fs;
+use std::io;
+
+use repo::*;
+use revs::*;
+use verify::*;
+use chunks::*;
+use manifest::*;
+use storage::*;
+
+use {Checkout, load_ignore};
+
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub kept: usize,
+    // the stage snapshots removed, and the indexes removed along with them
+    pub snapshots: Vec<PathBuf>,
+    pub logs: Vec<PathBuf>,
+    pub chunks: usize
+}
+
+fn in_any_revision(revs: &Revisions, ids: &[RevisionId], path: &Path) -> bool {
+    ids.iter().any(|&id| fs::metadata(revs.rev_path(id).join("tree").join(path)).is_ok())
+}
+
+pub fn collect_stage(repo: &Repo) -> io::Result<GcStats> {
+    info!("Collecting orphaned stage snapshots");
+    let stage_path = repo.path.join("stage");
+    let logs_path = repo.path.join("logs");
+    let revs = Revisions::new(repo.path.join("revs"));
+    let rev_ids = try!(revs.list());
+    let ignore = try!(load_ignore(&Checkout::new(repo.root.clone())));
+    let mut manifest = try!(Manifest::open_existing(repo.path.join("manifest")));
+    let mut store = open_store(try!(Backend::from_config(&try!(repo.config()))), &logs_path);
+    let mut stats = GcStats::default();
+
+    for id in try!(stage_files(&stage_path)) {
+        // still live if it's in the checkout and not ignored
+        let live = !ignore.is_ignored(&id, false) &&
+            fs::metadata(repo.root.join(&id)).map(|data| data.is_file()).unwrap_or(false);
+        if live || in_any_revision(&revs, &rev_ids, &id) {
+            trace!("Keeping {:?}", &id);
+            stats.kept += 1;
+            continue;
+        }
+
+        debug!("Removing snapshot of {:?}", &id);
+        match fs::remove_file(stage_path.join(&id)) {
+            Err(e) => {
+                error!("Failed to remove snapshot of {}: {}", id.display(), e);
+                return Err(e);
+            },
+            Ok(()) => {
+                stats.snapshots.push(id.clone());
+            }
+        }
+
+        // the index goes the way its backend keeps it, a packed one only
+        // needs the manifest to forget it
+        let loose = match store.remove(&id) {
+            Err(e) => {
+                error!("Failed to remove index of {}: {}", id.display(), e);
+                return Err(e);
+            },
+            Ok(loose) => loose
+        };
+        let listed = match manifest {
+            Some(ref mut manifest) => try!(manifest.remove(&id)),
+            None => false
+        };
+        if loose || listed {
+            stats.logs.push(id);
+        } else {
+            trace!("No index to remove");
+        }
+    }
+
+    stats.chunks = try!(collect_chunks(repo, &revs, &rev_ids));
+
+    Ok(stats)
+}
+
+fn collect_chunks(repo: &Repo, revs: &Revisions, rev_ids: &[RevisionId]) -> io::Result<usize> {
+    // chunks no snapshot in the stage or any revision lists anymore
+    info!("Collecting unused chunks");
+    let store = ChunkStore::new(repo.path.join("chunks"));
+    let mut used = HashSet::new();
+    try!(store.referenced_in(repo.path.join("stage"), &mut used));
+    for &id in rev_ids.iter() {
+        try!(store.referenced_in(revs.rev_path(id).join("tree"), &mut used));
+    }
+
+    let mut removed = 0;
+    for hash in try!(store.list()) {
+        if used.contains(&hash) {
+            continue;
+        }
+        debug!("Removing chunk {:016x}", hash);
+        match fs::remove_file(store.chunk_path(hash)) {
+            Err(e) => {
+                error!("Failed to remove chunk {:016x}: {}", hash, e);
+                return Err(e);
+            },
+            Ok(()) => {
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+use std::io::Write;
+
+use std::io;
+
+use revs::*;
+
+// revisions written out as a stream for git fast-import, so a half2 history
+// can be carried into git. marks are revision ids, so a stream can refer
+// back to any revision it has written
+
+#[derive(Debug, Clone)]
+pub struct FastExportOptions {
+    // the branch the revisions are committed to
+    pub branch: String,
+    // who the commits are by, as "Name <email>"
+    pub committer: String
+}
+
+impl Default for FastExportOptions {
+    fn default() -> FastExportOptions {
+        FastExportOptions {
+            branch: "master".to_string(),
+            committer: "half2 <half2@localhost>".to_string()
+        }
+    }
+}
+
+fn quote_path(path: &Path) -> io::Result<String> {
+    // paths are the rest of the line, quoted C style only when they'd be
+    // mistaken for a quoted path or run past it
+    let text = match path.to_str() {
+        Some(text) => text,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{} is not valid UTF-8", path.display())));
+        }
+    };
+    if !text.starts_with('"') && !text.contains('\n') {
+        return Ok(text.to_string());
+    }
+    let mut quoted = "\"".to_string();
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    Ok(quoted)
+}
+
+fn write_data<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    try!(write!(out, "data {}\n", data.len()));
+    try!(out.write_all(data));
+    out.write_all(b"\n")
+}
+
+fn write_file<W: Write>(out: &mut W, path: &Path, data: &[u8]) -> io::Result<()> {
+    try!(write!(out, "M 100644 inline {}\n", try!(quote_path(path))));
+    write_data(out, data)
+}
+
+pub fn write_fast_import<W: Write>(out: &mut W, revs: &Revisions, ids: &[RevisionId],
+                                   tags: &[(String, RevisionId)], options: &FastExportOptions)
+                                   -> io::Result<()> {
+    // the first revision is written whole, each after it as what changed
+    // since the one before. without a from, git continues the branch where
+    // it is, so a range picks up from an earlier export
+    let mut previous: Option<RevisionId> = None;
+    for &id in ids.iter() {
+        debug!("Exporting revision {}", id);
+        let meta = try!(revs.meta(id));
+        try!(write!(out, "commit refs/heads/{}\nmark :{}\n", options.branch, id));
+        // git wants an email, even an empty one
+        if let Some(ref author) = meta.author {
+            let author = if author.ends_with('>') {author.clone()} else {format!("{} <>", author)};
+            try!(write!(out, "author {} {} +0000\n", author, meta.time.unwrap_or(0)));
+        }
+        try!(write!(out, "committer {} {} +0000\n", options.committer, meta.time.unwrap_or(0)));
+        let message = match meta.message {
+            Some(ref message) => format!("{}\n", message),
+            None => format!("half2 revision {}\n", id)
+        };
+        try!(write_data(out, message.as_bytes()));
+
+        let files = try!(revs.files(id));
+        match previous {
+            Some(previous) => {
+                try!(write!(out, "from :{}\n", previous));
+                let before = try!(revs.files(previous));
+                for path in before.iter().filter(|path| files.binary_search(path).is_err()) {
+                    try!(write!(out, "D {}\n", try!(quote_path(path))));
+                }
+                for path in files.iter() {
+                    let data = try!(revs.read_path(id, path));
+                    if before.binary_search(path).is_ok() && try!(revs.read_path(previous, path)) == data {
+                        continue;
+                    }
+                    try!(write_file(out, path, &data));
+                }
+            },
+            None => {
+                try!(out.write_all(b"deleteall\n"));
+                for path in files.iter() {
+                    try!(write_file(out, path, &try!(revs.read_path(id, path))));
+                }
+            }
+        }
+        try!(out.write_all(b"\n"));
+        previous = Some(id);
+    }
+
+    // tags on what was written come along
+    for &(ref name, rev) in tags.iter().filter(|&&(_, rev)| ids.contains(&rev)) {
+        try!(write!(out, "reset refs/tags/{}\nfrom :{}\n\n", name, rev));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quote_path;
+    use std::path::Path;
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path(Path::new("dir/a b.txt")).unwrap(), "dir/a b.txt");
+        assert_eq!(quote_path(Path::new("\"odd\"")).unwrap(), "\"\\\"odd\\\"\"");
+        assert_eq!(quote_path(Path::new("a\nb\\c")).unwrap(), "\"a\\nb\\\\c\"");
+    }
+}
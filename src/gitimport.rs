@@ -0,0 +1,220 @@
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::io::Write;
+use std::thread;
+
+use std::io;
+
+// reading history out of a git repository by running git itself, so its
+// object database never has to be parsed here
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GitChange {
+    // a path added or changed, with the blob it now holds
+    Write(PathBuf, String),
+    Delete(PathBuf)
+}
+
+fn run_git(repo: &Path, args: &[&str], input: Option<Vec<u8>>) -> io::Result<Vec<u8>> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(repo).args(args)
+        .stdin(if input.is_some() {Stdio::piped()} else {Stdio::null()})
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!("Running git {}", args.join(" "));
+    let mut child = match command.spawn() {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "git is not installed"));
+        },
+        Err(e) => {
+            error!("Failed to run git: {}", e);
+            return Err(e);
+        },
+        Ok(child) => child
+    };
+
+    // fed from another thread, so git filling its output can't stall both
+    let writer = match (input, child.stdin.take()) {
+        (Some(input), Some(mut stdin)) => Some(thread::spawn(move || stdin.write_all(&input))),
+        _ => None
+    };
+    let output = try!(child.wait_with_output());
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("git {} failed: {}", args[0],
+                                          String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    if let Some(writer) = writer {
+        match writer.join() {
+            Ok(result) => try!(result),
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "The thread writing to git panicked"));
+            }
+        }
+    }
+    Ok(output.stdout)
+}
+
+pub fn git_commits(repo: &Path) -> io::Result<Vec<String>> {
+    // every commit on the first-parent line to HEAD, oldest first. history
+    // here is linear, so a merge comes in as the change it made to the line
+    // it was merged into
+    let output = try!(run_git(repo, &["rev-list", "--reverse", "--first-parent", "HEAD"], None));
+    Ok(String::from_utf8_lossy(&output).lines().map(|line| line.trim().to_string())
+       .filter(|line| !line.is_empty()).collect())
+}
+
+pub fn git_changes(repo: &Path, parent: Option<&str>, commit: &str) -> io::Result<Vec<GitChange>> {
+    // what a commit changed since its parent, or everything in it if it's the first
+    let mut args = vec!["diff-tree", "-r", "-z", "--no-renames", "--no-commit-id"];
+    match parent {
+        Some(parent) => args.push(parent),
+        None => args.push("--root")
+    }
+    args.push(commit);
+    parse_diff_tree(&try!(run_git(repo, &args, None)))
+}
+
+pub fn git_blobs(repo: &Path, blobs: &[String]) -> io::Result<Vec<Vec<u8>>> {
+    // the content of every blob, in order, from one git process. it's all
+    // held at once, which is fine for what one commit changes
+    if blobs.is_empty() {
+        return Ok(vec![]);
+    }
+    let input: String = blobs.iter().map(|blob| format!("{}\n", blob)).collect();
+    parse_batch(&try!(run_git(repo, &["cat-file", "--batch"], Some(input.into_bytes()))), blobs.len())
+}
+
+fn bad_output(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected output from git: {}", what))
+}
+
+fn check_path(path: &Path, header: &str) -> io::Result<()> {
+    // git never writes these itself, so a repository that holds them was
+    // made to land files outside the working tree or in the repository
+    if path.as_os_str().is_empty() {
+        return Err(bad_output(header));
+    }
+    if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("{:?} would be written outside the working tree", path)));
+    }
+    if path.components().any(|c| c.as_os_str() == ".h2") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("{:?} would be written inside the repository", path)));
+    }
+    Ok(())
+}
+
+fn parse_diff_tree(output: &[u8]) -> io::Result<Vec<GitChange>> {
+    // a header of ":oldmode newmode oldblob newblob status" and then the
+    // path, each ending in a nul. submodules are left out, and a symlink
+    // comes in as a file holding its target
+    let is_submodule = |mode: &str| mode == "160000";
+    let mut fields = output.split(|&b| b == 0);
+    let mut changes = vec![];
+    loop {
+        let header = match fields.next() {
+            Some(header) if !header.is_empty() => String::from_utf8_lossy(header).into_owned(),
+            _ => break
+        };
+        let path = match fields.next().map(|path| String::from_utf8(path.to_vec())) {
+            Some(Ok(path)) => PathBuf::from(path),
+            Some(Err(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("A path in {:?} is not valid UTF-8", header)));
+            },
+            None => {
+                return Err(bad_output(&header));
+            }
+        };
+        try!(check_path(&path, &header));
+        let parts: Vec<&str> = header.trim_left_matches(':').split(' ').collect();
+        if parts.len() != 5 {
+            return Err(bad_output(&header));
+        }
+        let (old_mode, new_mode, new_blob, status) = (parts[0], parts[1], parts[3], parts[4]);
+
+        let change = match status.chars().next() {
+            Some('D') if is_submodule(old_mode) => None,
+            Some('D') => Some(GitChange::Delete(path)),
+            Some('A') | Some('M') | Some('T') if is_submodule(new_mode) => {
+                // a file that became a submodule is gone as far as we're concerned
+                if is_submodule(old_mode) || old_mode == "000000" {None} else {Some(GitChange::Delete(path))}
+            },
+            Some('A') | Some('M') | Some('T') => Some(GitChange::Write(path, new_blob.to_string())),
+            _ => {
+                return Err(bad_output(&header));
+            }
+        };
+        match change {
+            Some(change) => {
+                changes.push(change);
+            },
+            None => {
+                warn!("Skipping submodule in {:?}", header);
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn parse_batch(output: &[u8], count: usize) -> io::Result<Vec<Vec<u8>>> {
+    // "<blob> blob <size>", a newline, the content and another newline, per object
+    let mut blobs = vec![];
+    let mut pos = 0;
+    while blobs.len() < count {
+        let end = match output[pos..].iter().position(|&b| b == b'\n') {
+            Some(i) => pos + i,
+            None => {
+                return Err(bad_output("object list was cut short"));
+            }
+        };
+        let header = String::from_utf8_lossy(&output[pos..end]).into_owned();
+        let parts: Vec<&str> = header.split(' ').collect();
+        let size: usize = match parts.get(2).and_then(|size| size.parse().ok()) {
+            Some(size) if parts.len() == 3 => size,
+            _ => {
+                return Err(bad_output(&header));
+            }
+        };
+        let start = end + 1;
+        if start + size > output.len() {
+            return Err(bad_output("object list was cut short"));
+        }
+        blobs.push(output[start..start + size].to_vec());
+        pos = start + size + 1;
+    }
+    Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{parse_diff_tree, parse_batch};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_diff_tree() {
+        let output = b":000000 100644 0000 aaaa A\0new.txt\0:100644 100644 bbbb cccc M\0dir/b.txt\0\
+                       :100644 000000 dddd 0000 D\0gone.txt\0:000000 160000 0000 eeee A\0sub\0";
+        assert_eq!(parse_diff_tree(output).unwrap(),
+                   vec![GitChange::Write(PathBuf::from("new.txt"), "aaaa".to_string()),
+                        GitChange::Write(PathBuf::from("dir/b.txt"), "cccc".to_string()),
+                        GitChange::Delete(PathBuf::from("gone.txt"))]);
+        assert_eq!(parse_diff_tree(b"").unwrap(), vec![]);
+        assert!(parse_diff_tree(b":100644 100644 bbbb M\0a\0").is_err());
+        assert!(parse_diff_tree(b":100644 100644 bbbb cccc M\0").is_err());
+        assert!(parse_diff_tree(b":000000 100644 0000 aaaa A\0../up.txt\0").is_err());
+        assert!(parse_diff_tree(b":000000 100644 0000 aaaa A\0/etc/passwd\0").is_err());
+        assert!(parse_diff_tree(b":000000 100644 0000 aaaa A\0dir/.h2/head\0").is_err());
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        let output = b"aaaa blob 4\none\n\nbbbb blob 0\n\n";
+        assert_eq!(parse_batch(output, 2).unwrap(), vec![b"one\n".to_vec(), vec![]]);
+        assert!(parse_batch(b"aaaa missing\n", 1).is_err());
+        assert!(parse_batch(b"aaaa blob 10\nshort\n", 1).is_err());
+    }
+}
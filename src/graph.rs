@@ -0,0 +1,213 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use std::io;
+
+use revs::*;
+
+// history drawn as lines of text the way `git log --graph` draws it: a
+// column for each line of descent with `*` on the revision itself, `/` where
+// a column joins the one of the revision it branched from and `\` where a
+// merge opens one. columns are two characters wide
+
+pub fn topo_order(parents: &HashMap<RevisionId, Vec<RevisionId>>) -> io::Result<Vec<RevisionId>> {
+    // every revision after everything committed on top of it, the newest
+    // first where that leaves a choice. parents that aren't in the map are
+    // left out, so their children start a history of their own
+    let mut children: HashMap<RevisionId, usize> = parents.keys().map(|&id| (id, 0)).collect();
+    for list in parents.values() {
+        for parent in list.iter() {
+            if let Some(count) = children.get_mut(parent) {
+                *count += 1;
+            }
+        }
+    }
+    let mut ready: BinaryHeap<RevisionId> = children.iter().filter(|&(_, &count)| count == 0)
+        .map(|(&id, _)| id).collect();
+    let mut order = vec![];
+    while let Some(id) = ready.pop() {
+        order.push(id);
+        for parent in parents[&id].iter() {
+            if let Some(count) = children.get_mut(parent) {
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(*parent);
+                }
+            }
+        }
+    }
+    if order.len() < parents.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Revision history has a cycle through {} revisions",
+                                          parents.len() - order.len())));
+    }
+    Ok(order)
+}
+
+fn ancestors(parents: &HashMap<RevisionId, Vec<RevisionId>>, id: RevisionId) -> HashSet<RevisionId> {
+    let mut seen = HashSet::new();
+    let mut to_visit = vec![id];
+    while let Some(id) = to_visit.pop() {
+        if seen.insert(id) {
+            if let Some(list) = parents.get(&id) {
+                to_visit.extend(list.iter().cloned());
+            }
+        }
+    }
+    seen
+}
+
+pub fn merge_base(parents: &HashMap<RevisionId, Vec<RevisionId>>, a: RevisionId, b: RevisionId)
+                  -> Option<RevisionId> {
+    // the newest revision in the history of both, either of them included.
+    // a revision's id is above its parents', so nothing else they share is
+    // committed on top of it
+    let ours = ancestors(parents, a);
+    ancestors(parents, b).into_iter().filter(|id| ours.contains(id)).max()
+}
+
+fn draw(marks: &[(usize, char)]) -> String {
+    let width = marks.iter().map(|&(at, _)| at + 1).max().unwrap_or(0);
+    let mut line = vec![' '; width];
+    for &(at, c) in marks.iter() {
+        line[at] = c;
+    }
+    line.into_iter().collect()
+}
+
+fn lanes_before(count: usize) -> Vec<(usize, char)> {
+    (0..count).map(|i| (2 * i, '|')).collect()
+}
+
+pub fn render_graph(order: &[RevisionId], parents: &HashMap<RevisionId, Vec<RevisionId>>)
+                    -> Vec<(String, Option<RevisionId>)> {
+    // the graph a line at a time, each with the revision it's drawn for or
+    // none for the lines that only move columns around. order comes from
+    // topo_order
+    let mut lanes: Vec<RevisionId> = vec![];
+    let mut out = vec![];
+    for &id in order.iter() {
+        let col = match lanes.iter().position(|&lane| lane == id) {
+            Some(col) => col,
+            None => {
+                lanes.push(id);
+                lanes.len() - 1
+            }
+        };
+
+        // every other column waiting on this revision joins its column,
+        // the ones right of it moving over
+        loop {
+            let join = match lanes.iter().rposition(|&lane| lane == id) {
+                Some(join) if join > col => join,
+                _ => break
+            };
+            let mut marks = lanes_before(join);
+            for at in (2 * col + 1)..(2 * join - 1) {
+                if at % 2 == 1 {
+                    marks.push((at, '_'));
+                }
+            }
+            for j in join..lanes.len() {
+                marks.push((2 * j - 1, '/'));
+            }
+            out.push((draw(&marks), None));
+            lanes.remove(join);
+        }
+
+        let mut marks = lanes_before(lanes.len());
+        marks[col] = (2 * col, '*');
+        out.push((draw(&marks), Some(id)));
+
+        let known: Vec<RevisionId> = parents.get(&id).map_or(vec![], |list| {
+            list.iter().cloned().filter(|parent| parents.contains_key(parent)).collect()
+        });
+        if known.is_empty() {
+            // the first revision of its history, its column closes
+            lanes.remove(col);
+            if col < lanes.len() {
+                let mut marks = lanes_before(col);
+                for j in col..lanes.len() {
+                    marks.push((2 * j + 1, '/'));
+                }
+                out.push((draw(&marks), None));
+            }
+            continue;
+        }
+        lanes[col] = known[0];
+        for (n, &parent) in known[1..].iter().enumerate() {
+            // each parent past the first opens a column next to this one
+            let at = col + 1 + n;
+            let mut marks = lanes_before(at);
+            marks.push((2 * at - 1, '\\'));
+            for j in at..lanes.len() {
+                marks.push((2 * j + 1, '\\'));
+            }
+            out.push((draw(&marks), None));
+            lanes.insert(at, parent);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use revs::RevisionId;
+
+    fn history(edges: Vec<(RevisionId, Vec<RevisionId>)>) -> HashMap<RevisionId, Vec<RevisionId>> {
+        edges.into_iter().collect()
+    }
+
+    fn drawn(parents: &HashMap<RevisionId, Vec<RevisionId>>) -> Vec<String> {
+        let order = topo_order(parents).unwrap();
+        render_graph(&order, parents).into_iter().map(|(graph, rev)| match rev {
+            Some(id) => format!("{} {}", graph, id),
+            None => graph
+        }).collect()
+    }
+
+    #[test]
+    fn test_linear() {
+        let parents = history(vec![(1, vec![]), (2, vec![1]), (3, vec![2])]);
+        assert_eq!(topo_order(&parents).unwrap(), vec![3, 2, 1]);
+        assert_eq!(drawn(&parents), vec!["* 3", "* 2", "* 1"]);
+    }
+
+    #[test]
+    fn test_branches() {
+        // 3 and 5 both follow 2, 6 has no history before it
+        let parents = history(vec![(1, vec![]), (2, vec![1]), (3, vec![2]), (4, vec![3]), (5, vec![2]),
+                                   (6, vec![])]);
+        assert_eq!(topo_order(&parents).unwrap(), vec![6, 5, 4, 3, 2, 1]);
+        assert_eq!(drawn(&parents), vec!["* 6", "* 5", "| * 4", "| * 3", "|/", "* 2", "* 1"]);
+
+        // a branch that ends out of the way of a column still open
+        let parents = history(vec![(1, vec![]), (2, vec![1]), (3, vec![1]), (4, vec![]), (5, vec![4]),
+                                   (6, vec![2]), (7, vec![5])]);
+        assert_eq!(drawn(&parents),
+                   vec!["* 7", "| * 6", "* | 5", "* | 4", " /", "| * 3", "* | 2", "|/", "* 1"]);
+    }
+
+    #[test]
+    fn test_merges() {
+        let parents = history(vec![(1, vec![]), (2, vec![1]), (3, vec![1]), (4, vec![2, 3])]);
+        assert_eq!(drawn(&parents), vec!["* 4", "|\\", "| * 3", "* | 2", "|/", "* 1"]);
+    }
+
+    #[test]
+    fn test_merge_base() {
+        let parents = history(vec![(1, vec![]), (2, vec![1]), (3, vec![1]), (4, vec![2, 3]), (5, vec![3]),
+                                   (6, vec![])]);
+        assert_eq!(merge_base(&parents, 2, 3), Some(1));
+        assert_eq!(merge_base(&parents, 4, 5), Some(3));
+        assert_eq!(merge_base(&parents, 4, 2), Some(2));
+        assert_eq!(merge_base(&parents, 5, 6), None);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let parents = history(vec![(1, vec![2]), (2, vec![1]), (3, vec![])]);
+        assert!(topo_order(&parents).is_err());
+    }
+}
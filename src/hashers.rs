@@ -0,0 +1,102 @@
+use std::hash::{Hash, Hasher, SipHasher};
+
+use std::fmt;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64-bit FNV-1a, much cheaper than SipHasher for short lines
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(FNV_OFFSET)
+    }
+}
+
+impl FnvHasher {
+    pub fn with_state(state: u64) -> FnvHasher {
+        // carry on from a hash finished earlier
+        FnvHasher(state)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum LineHasher {
+    Sip,
+    Fnv
+}
+
+impl Default for LineHasher {
+    fn default() -> LineHasher {
+        LineHasher::Fnv
+    }
+}
+
+impl fmt::Display for LineHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LineHasher::Sip => write!(f, "sip"),
+            LineHasher::Fnv => write!(f, "fnv")
+        }
+    }
+}
+
+impl LineHasher {
+    pub fn from_name(name: &str) -> Option<LineHasher> {
+        match name {
+            "sip" => Some(LineHasher::Sip),
+            "fnv" => Some(LineHasher::Fnv),
+            _ => None
+        }
+    }
+
+    pub fn hash_line(&self, line: &[u8]) -> u64 {
+        match *self {
+            LineHasher::Sip => hash_with::<SipHasher>(line),
+            LineHasher::Fnv => hash_with::<FnvHasher>(line)
+        }
+    }
+}
+
+fn hash_with<H: Hasher + Default>(line: &[u8]) -> u64 {
+    let mut hasher = H::default();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hasher;
+
+    #[test]
+    fn test_fnv_known() {
+        // reference values for 64-bit FNV-1a
+        let mut hasher = FnvHasher::default();
+        assert_eq!(hasher.finish(), 0xcbf29ce484222325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_hashers_differ() {
+        let line = b"fn main() {";
+        assert_eq!(LineHasher::Fnv.hash_line(line), LineHasher::Fnv.hash_line(line));
+        assert!(LineHasher::Fnv.hash_line(line) != LineHasher::Sip.hash_line(line));
+        assert!(LineHasher::Fnv.hash_line(b"a") != LineHasher::Fnv.hash_line(b"b"));
+    }
+}
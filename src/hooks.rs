@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use std::env;
+use std::fs;
+use std::io;
+
+use revs::*;
+use plan::*;
+use platform::*;
+use repo::*;
+
+// executables under .h2/hooks that commands run before and after they change
+// things. a hook learns what's going on from the environment:
+//   H2_HOOK   the name of the hook
+//   H2_REPO   the repository directory
+//   H2_REV    the revision, if there is one
+//   H2_PATHS  the affected paths, escaped, one per line
+// a pre hook exiting with anything but zero stops the command
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    path: PathBuf,
+    plan: Plan
+}
+
+impl Default for Hooks {
+    fn default() -> Hooks {
+        Hooks::new(repo_path("hooks"))
+    }
+}
+
+impl Hooks {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Hooks {
+        Hooks {
+            path: path.into(),
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Hooks {
+        self.plan = plan;
+        self
+    }
+
+    pub fn run(&self, name: &str, rev: Option<RevisionId>, paths: &[String]) -> io::Result<()> {
+        // run a hook if it's there, an error if it fails
+        let hook_path = self.path.join(name);
+        match fs::metadata(&hook_path) {
+            Ok(ref data) if is_executable(data) => {
+                trace!("Found {} hook", name);
+            },
+            Ok(_) => {
+                warn!("Ignoring {} hook, it isn't executable", name);
+                return Ok(());
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No {} hook", name);
+                return Ok(());
+            },
+            Err(e) => {
+                error!("Failed to get metadata for {} hook: {}", name, e);
+                return Err(e);
+            }
+        }
+        if !self.plan.allow(Op::RunHook(&hook_path)) {
+            return Ok(());
+        }
+
+        let repo_path = match self.path.parent() {
+            Some(parent) => try!(env::current_dir()).join(parent),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("{} has no repository around it", self.path.display())));
+            }
+        };
+        let mut command = Command::new(&hook_path);
+        command.env("H2_HOOK", name)
+            .env("H2_REPO", &repo_path)
+            .env("H2_PATHS", paths.join("\n"));
+        match rev {
+            Some(rev) => {
+                command.env("H2_REV", format!("{}", rev));
+            },
+            None => {
+                command.env_remove("H2_REV");
+            }
+        }
+
+        debug!("Running {} hook", name);
+        match command.status() {
+            Err(e) => {
+                error!("Failed to run {} hook: {}", name, e);
+                Err(e)
+            },
+            Ok(status) if status.success() => {
+                trace!("Hook succeeded");
+                Ok(())
+            },
+            Ok(status) => {
+                Err(io::Error::new(io::ErrorKind::Other,
+                                   format!("The {} hook failed: {}", name, status)))
+            }
+        }
+    }
+
+    pub fn run_post(&self, name: &str, rev: Option<RevisionId>, paths: &[String]) -> Option<String> {
+        // the command already happened, so a failing post hook is only a
+        // warning for the caller to pass on
+        match self.run(name, rev, paths) {
+            Ok(()) => None,
+            Err(e) => {
+                warn!("{}", e);
+                Some(e.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,581 @@
+use std::path::{Path, PathBuf, Component};
+use std::collections::HashSet;
+use std::cell::RefCell;
+use std::io::Read;
+use std::rc::Rc;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use config::*;
+use pathid::*;
+use platform::*;
+
+// rules read from a checkout's own ignore file
+pub const H2IGNORE_FILE: &'static str = ".h2ignore";
+pub const GITIGNORE_FILE: &'static str = ".gitignore";
+
+// how much of a file is looked at to decide it's binary, the same as git
+pub const BINARY_CHECK_SIZE: u64 = 8000;
+
+// a directory with one of these in it is a repository of its own
+pub const NESTED_REPO_DIR: &'static str = ".h2";
+
+// why a path no pattern matches is left out anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    // over the size limit, with the file's size
+    TooBig(u64),
+    Binary,
+    // a directory that's the checkout of another repository
+    NestedRepo,
+    // neither a file nor a directory, so there's nothing to stage
+    Unsupported(SpecialFile)
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkipReason::TooBig(size) => write!(f, "{} bytes, over the size limit", size),
+            SkipReason::Binary => write!(f, "binary"),
+            SkipReason::NestedRepo => write!(f, "nested repository"),
+            SkipReason::Unsupported(kind) => write!(f, "unsupported {}", kind)
+        }
+    }
+}
+
+// paths left out for what they are rather than what they're called, from
+// the max_file_size, exclude_binary and include_nested config settings or
+// the command line. nested repositories are left to track themselves unless
+// include_nested is set. sockets, fifos, devices and links are left out too,
+// or are an error for the walk if special_files = error
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileFilter {
+    pub max_file_size: Option<u64>,
+    pub exclude_binary: bool,
+    pub include_nested: bool,
+    pub reject_special: bool
+}
+
+pub fn parse_size(text: &str) -> Option<u64> {
+    // a number of bytes, optionally with a k, M or G suffix
+    let text = text.trim();
+    let (digits, scale) = match text.chars().last() {
+        Some('k') | Some('K') => (&text[..text.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&text[..text.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1)
+    };
+    digits.parse::<u64>().ok().and_then(|count| count.checked_mul(scale))
+}
+
+pub fn is_binary(data: &[u8]) -> bool {
+    // text doesn't have nul bytes in it, even most non-utf-8 text
+    data.iter().take(BINARY_CHECK_SIZE as usize).any(|&c| c == 0)
+}
+
+impl FileFilter {
+    pub fn from_config(config: &Config) -> io::Result<FileFilter> {
+        let max_file_size = match config.get("max_file_size") {
+            None => None,
+            Some(value) => match parse_size(value) {
+                Some(size) => Some(size),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Config value max_file_size = {:?} is not a size like 50M",
+                                                      value)));
+                }
+            }
+        };
+        let reject_special = match config.get("special_files") {
+            None | Some("skip") => false,
+            Some("error") => true,
+            Some(value) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value special_files = {:?} is not skip or error", value)));
+            }
+        };
+        Ok(FileFilter {
+            max_file_size: max_file_size,
+            exclude_binary: try!(config.get_bool("exclude_binary", false)),
+            include_nested: try!(config.get_bool("include_nested", false)),
+            reject_special: reject_special
+        })
+    }
+
+    pub fn or(self, other: FileFilter) -> FileFilter {
+        // these settings, falling back on other's where these have none
+        FileFilter {
+            max_file_size: self.max_file_size.or(other.max_file_size),
+            exclude_binary: self.exclude_binary || other.exclude_binary,
+            include_nested: self.include_nested || other.include_nested,
+            reject_special: self.reject_special || other.reject_special
+        }
+    }
+
+    pub fn check(&self, path: &Path, metadata: &fs::Metadata) -> io::Result<Option<SkipReason>> {
+        if let Some(kind) = special_file(metadata) {
+            if self.reject_special {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("{} is a {}, which can't be tracked", path.display(), kind)));
+            }
+            return Ok(Some(SkipReason::Unsupported(kind)));
+        }
+        if metadata.is_dir() {
+            let nested = fs::metadata(path.join(NESTED_REPO_DIR)).map(|data| data.is_dir()).unwrap_or(false);
+            return Ok(if nested && !self.include_nested {Some(SkipReason::NestedRepo)} else {None});
+        }
+        if let Some(max) = self.max_file_size {
+            if metadata.len() > max {
+                return Ok(Some(SkipReason::TooBig(metadata.len())));
+            }
+        }
+        if self.exclude_binary && metadata.is_file() {
+            let mut head = vec![];
+            try!(fs::File::open(path).and_then(|f| f.take(BINARY_CHECK_SIZE).read_to_end(&mut head)));
+            if is_binary(&head) {
+                return Ok(Some(SkipReason::Binary));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    glob: Vec<u8>,
+    // a leading `!` brings back a path an earlier rule ignored
+    negate: bool,
+    // a trailing `/` only matches directories
+    dir_only: bool,
+    // patterns with a `/` in them match from the root instead of any level
+    anchored: bool
+}
+
+// the common subset of gitignore syntax: comments, `!`, leading and
+// trailing `/`, `*`, `?`, `**` and character classes. only the ignore files
+// at the root of the checkout are read. files the filter leaves out are
+// remembered, and clones share the list, so they can be reported later
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    // exact ids that are always ignored, like our own directory
+    paths: HashSet<PathBuf>,
+    patterns: Vec<Pattern>,
+    // when set, only these subtrees are looked at and everything else counts
+    // as ignored. from the sparse config setting or the command line
+    include: Option<Vec<PathBuf>>,
+    filter: FileFilter,
+    skipped: Rc<RefCell<Vec<(PathBuf, SkipReason)>>>
+}
+
+fn match_class(class: &[u8], c: u8) -> Option<(bool, usize)> {
+    // match a `[...]` class at the start of the slice, returning whether it
+    // matched and how long the class was
+    let mut i = 1;
+    let negate = i < class.len() && (class[i] == b'!' || class[i] == b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() && (first || class[i] != b']') {
+        first = false;
+        if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            if class[i] <= c && c <= class[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    if i >= class.len() {
+        // no closing bracket, treat it as a literal
+        return None;
+    }
+    Some((matched != negate, i + 1))
+}
+
+pub fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    if glob.is_empty() {
+        return text.is_empty();
+    }
+    match glob[0] {
+        b'*' if glob.len() > 1 && glob[1] == b'*' => {
+            // `**` crosses directories, `**/` matches any number of whole ones
+            if glob.len() > 2 && glob[2] == b'/' {
+                (0..text.len() + 1).any(|i| (i == 0 || text[i - 1] == b'/') && glob_match(&glob[3..], &text[i..]))
+            } else {
+                (0..text.len() + 1).any(|i| glob_match(&glob[2..], &text[i..]))
+            }
+        },
+        b'*' => {
+            let mut i = 0;
+            loop {
+                if glob_match(&glob[1..], &text[i..]) {
+                    return true;
+                }
+                if i >= text.len() || text[i] == b'/' {
+                    return false;
+                }
+                i += 1;
+            }
+        },
+        b'?' => {
+            !text.is_empty() && text[0] != b'/' && glob_match(&glob[1..], &text[1..])
+        },
+        b'[' if !text.is_empty() => {
+            match match_class(glob, text[0]) {
+                Some((true, len)) => glob_match(&glob[len..], &text[1..]),
+                Some((false, _)) => false,
+                None => text[0] == b'[' && glob_match(&glob[1..], &text[1..])
+            }
+        },
+        b'\\' if glob.len() > 1 => {
+            !text.is_empty() && text[0] == glob[1] && glob_match(&glob[2..], &text[1..])
+        },
+        c => {
+            !text.is_empty() && text[0] == c && glob_match(&glob[1..], &text[1..])
+        }
+    }
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let mut line = line.trim_right();
+        if line.is_empty() || line.starts_with("#") {
+            return None;
+        }
+        let negate = line.starts_with("!");
+        if negate {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with("/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+        let anchored = line.contains('/');
+        if line.starts_with("/") {
+            line = &line[1..];
+        }
+        if line.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: line.as_bytes().to_vec(),
+            negate: negate,
+            dir_only: dir_only,
+            anchored: anchored
+        })
+    }
+
+    fn matches(&self, id: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.glob, id)
+        } else {
+            let name = match id.iter().rposition(|&c| c == b'/') {
+                Some(split) => &id[split + 1..],
+                None => id
+            };
+            glob_match(&self.glob, name)
+        }
+    }
+}
+
+fn sparse_id(part: &str) -> io::Result<PathBuf> {
+    // the sparse setting is written by hand, so take `src/` or `./docs` as
+    // well as plain ids but nothing that leaves the checkout
+    let mut id = PathBuf::new();
+    for component in Path::new(part).components() {
+        match component {
+            Component::CurDir => {},
+            Component::Normal(name) => {
+                id.push(name);
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value sparse has {:?}, which is not a path in the checkout",
+                                                  part)));
+            }
+        }
+    }
+    Ok(id)
+}
+
+impl IgnoreRules {
+    pub fn new<V: IntoIterator>(paths: V) -> IgnoreRules where V::Item: Into<PathBuf> {
+        IgnoreRules {
+            paths: paths.into_iter().map(|x| x.into()).collect(),
+            patterns: vec![],
+            include: None,
+            filter: FileFilter::default(),
+            skipped: Rc::new(RefCell::new(vec![]))
+        }
+    }
+
+    pub fn with_filter(mut self, filter: FileFilter) -> IgnoreRules {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_include<V: IntoIterator>(mut self, ids: V) -> IgnoreRules where V::Item: Into<PathBuf> {
+        self.include = Some(ids.into_iter().map(|x| x.into()).collect());
+        self
+    }
+
+    pub fn with_scope<V: IntoIterator>(mut self, ids: V) -> IgnoreRules where V::Item: Into<PathBuf> {
+        // narrow what's included down to these subtrees, keeping inside the
+        // sparse set if there is one. the root scopes nothing
+        let ids: Vec<PathBuf> = ids.into_iter().map(|x| x.into()).collect();
+        if ids.iter().any(|id| id.components().next().is_none()) {
+            return self;
+        }
+        let scope = match self.include {
+            None => ids,
+            Some(ref include) => {
+                let mut scope = vec![];
+                for id in ids.iter() {
+                    if include.iter().any(|path| id.starts_with(path)) {
+                        scope.push(id.clone());
+                    } else {
+                        scope.extend(include.iter().filter(|path| path.starts_with(id)).cloned());
+                    }
+                }
+                scope
+            }
+        };
+        self.include = Some(scope);
+        self
+    }
+
+    pub fn includes(&self, id: &Path, is_dir: bool) -> bool {
+        // whether a path is inside the included subtrees, or a directory on
+        // the way down to one
+        match self.include {
+            None => true,
+            Some(ref include) => include.iter().any(|path| id.starts_with(path) || (is_dir && path.starts_with(id)))
+        }
+    }
+
+    pub fn for_checkout<T: AsRef<Path>, V: IntoIterator>(root: T, paths: V, config: &Config) -> io::Result<IgnoreRules>
+        where V::Item: Into<PathBuf> {
+        // our own ignore file always applies, git's only when asked for.
+        // .h2ignore comes last so its rules win
+        let root = root.as_ref();
+        let mut rules = IgnoreRules::new(paths);
+        if try!(config.get_bool("gitignore", false)) {
+            let count = try!(rules.add_file(root.join(GITIGNORE_FILE)));
+            debug!("Read {} rules from {}", count, GITIGNORE_FILE);
+        }
+        let count = try!(rules.add_file(root.join(H2IGNORE_FILE)));
+        debug!("Read {} rules from {}", count, H2IGNORE_FILE);
+        if let Some(sparse) = config.get("sparse") {
+            // space separated subtrees, relative to the root of the checkout
+            let mut include = vec![];
+            for part in sparse.split_whitespace() {
+                include.push(try!(sparse_id(part)));
+            }
+            debug!("Only including {:?}", include);
+            rules = rules.with_include(include);
+        }
+        Ok(rules.with_filter(try!(FileFilter::from_config(config))))
+    }
+
+    pub fn add_pattern(&mut self, line: &str) -> bool {
+        match Pattern::parse(line) {
+            Some(pattern) => {
+                self.patterns.push(pattern);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn add_file<T: AsRef<Path>>(&mut self, path: T) -> io::Result<usize> {
+        // a missing ignore file just has no rules
+        let mut data = String::new();
+        match fs::File::open(path.as_ref()).and_then(|mut f| f.read_to_string(&mut data)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(0);
+            },
+            Err(e) => {
+                error!("Failed to read {}: {}", path.as_ref().display(), e);
+                return Err(e);
+            },
+            Ok(_) => {}
+        }
+        Ok(data.lines().filter(|line| self.add_pattern(line)).count())
+    }
+
+    pub fn matches(&self, id: &Path, is_dir: bool) -> bool {
+        // whether this path itself is ignored, the last matching rule wins
+        if self.paths.contains(id) {
+            return true;
+        }
+        if !self.includes(id, is_dir) {
+            return true;
+        }
+        // match on the raw bytes, names that aren't utf-8 can still match
+        let id = id_bytes(id);
+        let mut ignored = false;
+        for pattern in self.patterns.iter() {
+            if pattern.negate == ignored && pattern.matches(&id, is_dir) {
+                ignored = !pattern.negate;
+            }
+        }
+        ignored
+    }
+
+    pub fn skip(&self, id: &Path, path: &Path, metadata: &fs::Metadata) -> io::Result<bool> {
+        // whether the filter leaves out a path the patterns let through,
+        // noting it down if so. the root of the walk is never left out
+        if id == Path::new("") {
+            return Ok(false);
+        }
+        match try!(self.filter.check(path, metadata)) {
+            Some(reason) => {
+                // the rest were asked to be left out, these can't be staged
+                // at all
+                if let SkipReason::Unsupported(_) = reason {
+                    warn!("Skipping {:?}: {}", id, reason);
+                } else {
+                    info!("Skipping {:?}: {}", id, reason);
+                }
+                let mut skipped = self.skipped.borrow_mut();
+                if !skipped.iter().any(|&(ref skipped_id, _)| skipped_id == id) {
+                    skipped.push((id.to_path_buf(), reason));
+                }
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    pub fn skipped(&self) -> Vec<(PathBuf, SkipReason)> {
+        self.skipped.borrow().clone()
+    }
+
+    pub fn is_ignored(&self, id: &Path, is_dir: bool) -> bool {
+        // a path is ignored if it or any of its parents are
+        let mut current = id.parent();
+        while let Some(path) = current {
+            if path != Path::new("") && self.matches(path, true) {
+                return true;
+            }
+            current = path.parent();
+        }
+        self.matches(id, is_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::fs;
+    use std::env;
+
+    use config::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*.o", b"main.o"));
+        assert!(!glob_match(b"*.o", b"src/main.o"));
+        assert!(glob_match(b"**/*.o", b"src/main.o"));
+        assert!(glob_match(b"**/*.o", b"main.o"));
+        assert!(glob_match(b"doc/**", b"doc/a/b"));
+        assert!(glob_match(b"file?.[ch]", b"file1.c"));
+        assert!(!glob_match(b"file?.[!ch]", b"file1.c"));
+        assert!(glob_match(b"[a-c]x", b"bx"));
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let mut rules = IgnoreRules::new(vec![".h2"]);
+        for line in "# build output\ntarget/\n*.log\n!keep.log\n/build\n".lines() {
+            rules.add_pattern(line);
+        }
+        assert!(rules.is_ignored(Path::new(".h2"), true));
+        assert!(rules.is_ignored(Path::new("target"), true));
+        assert!(!rules.is_ignored(Path::new("target"), false));
+        assert!(rules.is_ignored(Path::new("target/debug/h2"), false));
+        assert!(rules.is_ignored(Path::new("src/out.log"), false));
+        assert!(!rules.is_ignored(Path::new("src/keep.log"), false));
+        assert!(rules.is_ignored(Path::new("build"), false));
+        assert!(!rules.is_ignored(Path::new("src/build"), false));
+    }
+
+    #[test]
+    fn test_sparse_include() {
+        let config = Config::parse("sparse = src/ ./docs/api\n").unwrap();
+        let rules = IgnoreRules::for_checkout(env::temp_dir().join("h2-test-sparse"), vec![".h2"], &config).unwrap();
+        assert!(!rules.is_ignored(Path::new("src/main.rs"), false));
+        assert!(!rules.is_ignored(Path::new("docs"), true));
+        assert!(rules.is_ignored(Path::new("docs"), false));
+        assert!(rules.is_ignored(Path::new("docs/guide.md"), false));
+        assert!(!rules.is_ignored(Path::new("docs/api/index.md"), false));
+        assert!(rules.is_ignored(Path::new("README"), false));
+        assert!(rules.is_ignored(Path::new(".h2"), true));
+
+        // scoping stays inside the sparse set
+        let scoped = rules.clone().with_scope(vec!["docs", "src/bin"]);
+        assert!(scoped.includes(Path::new("docs/api/index.md"), false));
+        assert!(!scoped.includes(Path::new("src/main.rs"), false));
+        assert!(scoped.includes(Path::new("src/bin/h2.rs"), false));
+        assert!(!rules.clone().with_scope(vec!["test"]).includes(Path::new("src"), true));
+        assert!(rules.clone().with_scope(vec![""]).includes(Path::new("src/main.rs"), false));
+        assert!(IgnoreRules::new(vec![".h2"]).with_scope(vec!["lib"]).includes(Path::new("lib/a"), false));
+
+        let outside = Config::parse("sparse = ../up").unwrap();
+        assert!(IgnoreRules::for_checkout(env::temp_dir(), vec![".h2"], &outside).is_err());
+    }
+
+    #[test]
+    fn test_file_filter() {
+        assert_eq!(parse_size("50M"), Some(50 << 20));
+        assert_eq!(parse_size("12k"), Some(12 << 10));
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("lots"), None);
+        assert!(is_binary(b"GIF89a\0\x01"));
+        assert!(!is_binary(b"plain text\n"));
+
+        let config = Config::parse("max_file_size = 1k\nexclude_binary = on\n").unwrap();
+        let filter = FileFilter::from_config(&config).unwrap();
+        assert_eq!(filter, FileFilter {max_file_size: Some(1024), exclude_binary: true, include_nested: false,
+                                       reject_special: false});
+        let cli = FileFilter {max_file_size: Some(10), exclude_binary: false, include_nested: true,
+                              reject_special: false};
+        assert_eq!(cli.or(filter), FileFilter {max_file_size: Some(10), exclude_binary: true, include_nested: true,
+                                               reject_special: false});
+        assert!(FileFilter::from_config(&Config::parse("max_file_size = big").unwrap()).is_err());
+        assert!(FileFilter::from_config(&Config::parse("special_files = error").unwrap()).unwrap().reject_special);
+        assert!(FileFilter::from_config(&Config::parse("special_files = maybe").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_nested_repo() {
+        let path = env::temp_dir().join("h2-test-nested");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("inner").join(NESTED_REPO_DIR)).unwrap();
+        fs::create_dir_all(path.join("plain")).unwrap();
+
+        let rules = IgnoreRules::new(vec![".h2"]);
+        let inner = path.join("inner");
+        assert!(rules.skip(Path::new("inner"), &inner, &fs::metadata(&inner).unwrap()).unwrap());
+        let plain = path.join("plain");
+        assert!(!rules.skip(Path::new("plain"), &plain, &fs::metadata(&plain).unwrap()).unwrap());
+        assert_eq!(rules.skipped(), vec![(PathBuf::from("inner"), SkipReason::NestedRepo)]);
+
+        let included = IgnoreRules::new(vec![".h2"]).with_filter(FileFilter {include_nested: true, ..FileFilter::default()});
+        assert!(!included.skip(Path::new("inner"), &inner, &fs::metadata(&inner).unwrap()).unwrap());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::io::Read;
+
+use std::fs;
+use std::io;
+
+use rustc_serialize::json;
+
+use repo::*;
+use tree::*;
+use drivers::*;
+
+use {FileIndex, FileMeta, IndexPlace, open_stage, open_logs, path_id, escape_id};
+
+// what the repository has stored for a path, read straight out of .h2 for
+// debugging the format or checking what was actually kept
+
+pub fn cat_stage<T: AsRef<Path>>(path: T, raw: bool) -> io::Result<Vec<u8>> {
+    // the staged copy of a file, as stored if raw, otherwise with any chunk
+    // references expanded the way a diff would see it
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let id = try!(path_id(path));
+    let stage = try!(open_stage());
+    let stored = stage.path.join(&id);
+    match fs::metadata(&stored) {
+        Ok(ref data) if data.is_file() => {
+            debug!("Reading staged {:?}", &id);
+        },
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not staged", id.display())));
+        }
+    }
+    if raw {
+        let mut data = vec![];
+        try!(fs::File::open(&stored).and_then(|mut f| f.read_to_end(&mut data)));
+        Ok(data)
+    } else {
+        stage.read_path(&id)
+    }
+}
+
+pub fn index_meta<T: AsRef<Path>>(path: T) -> io::Result<FileMeta> {
+    // the decoded meta of a file's index, from a pack or its own directory
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let id = try!(path_id(path));
+    try!(open_logs()).read_meta(&id)
+}
+
+pub fn render_meta(meta: &FileMeta) -> String {
+    format!("{}", json::as_pretty_json(meta))
+}
+
+fn render_places(places: &[IndexPlace]) -> String {
+    // each place as line:offset, the offset signed so it reads as a shift
+    let parts: Vec<String> = places.iter().map(|place| format!("{}:{:+}", place.node, place.offset)).collect();
+    parts.join(" ")
+}
+
+pub fn dump_index<T: AsRef<Path>>(path: T) -> io::Result<Vec<String>> {
+    // every node of a file's line index, parents before children, indented
+    // by depth. each item is a line hash and the places it appears
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let id = try!(path_id(path));
+    let logs = try!(open_logs());
+    let (meta, index) = try!(logs.open_index(&id));
+    let mut index = match index {
+        FileIndex::Tree(index) => index,
+        FileIndex::Inline(items) => {
+            // a small file's index has no nodes, just its items in order
+            let mut out = vec![format!("index of {}: {} lines, {} hasher, {} driver, inline, {} items",
+                                       escape_id(&id), meta.node_count, meta.hasher,
+                                       meta.driver.unwrap_or(DiffDriver::Lines), items.len())];
+            for &(hash, ref places) in items.iter() {
+                out.push(format!("  {:016x} {}", hash, render_places(places)));
+            }
+            return Ok(out);
+        }
+    };
+    let shape = try!(index.tree_mut().stats());
+    let nodes = try!(index.dump_nodes());
+
+    let mut out = vec![format!("index of {}: {} lines, {} hasher, {} driver, width {}, {} nodes, depth {}, \
+                                {:.2} full, {} free",
+                               escape_id(&id), meta.node_count, meta.hasher, meta.driver.unwrap_or(DiffDriver::Lines),
+                               shape.width, shape.nodes, shape.depth, shape.fill_factor(), shape.free_nodes)];
+    for node in nodes.iter() {
+        let indent: String = (0..node.depth).map(|_| "  ").collect();
+        if node.leaf {
+            out.push(format!("{}node {} leaf, {} items", indent, node.idx, node.items.len()));
+        } else {
+            let next: Vec<String> = node.next.iter().map(|idx| idx.to_string()).collect();
+            out.push(format!("{}node {} -> {}, {} items", indent, node.idx, next.join(" "), node.items.len()));
+        }
+        for &(hash, ref places) in node.items.iter() {
+            out.push(format!("{}  {:016x} {}", indent, hash, render_places(places)));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_places;
+    use IndexPlace;
+
+    #[test]
+    fn test_render_places() {
+        let places = vec![IndexPlace {node: 3, offset: 0}, IndexPlace {node: 10, offset: -2}];
+        assert_eq!(render_places(&places), "3:+0 10:-2");
+        assert_eq!(render_places(&[]), "");
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::io::Write;
+
+use std::io;
+
+use platform::*;
+use timing::*;
+
+// counts of what a command did, kept apart from logging so they can be read
+// back and compared between runs. counting is always on, it's one atomic add
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    // tree nodes read from and written to their buffers
+    TreeReads,
+    TreeWrites,
+    // files whose size and mtime showed they hadn't changed, and ones that
+    // had to be read to find out
+    CacheHits,
+    CacheMisses,
+    // bytes copied file to file, mostly from the checkout into the stage
+    BytesCopied,
+    // files diffed or indexed
+    FilesProcessed,
+    // lines in a diff too common to try every place, matched by position
+    ProbeLimitHits,
+    // files staged as clones or hard links instead of copies
+    FilesShared,
+    // lines a diff didn't have to look up, starting from where the last one
+    // found the file still matched
+    LinesSkipped,
+    // files diffed against their staged copy because their index was
+    // unusable
+    StageFallbacks
+}
+
+pub const COUNTERS: [Counter; 10] = [Counter::TreeReads, Counter::TreeWrites, Counter::CacheHits,
+                                     Counter::CacheMisses, Counter::BytesCopied, Counter::FilesProcessed,
+                                     Counter::ProbeLimitHits, Counter::FilesShared, Counter::LinesSkipped,
+                                     Counter::StageFallbacks];
+
+// what gets written out, summary at exit and spans as they finish
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileMode {
+    Off,
+    Summary,
+    Spans
+}
+
+static TREE_READS: AtomicUsize = ATOMIC_USIZE_INIT;
+static TREE_WRITES: AtomicUsize = ATOMIC_USIZE_INIT;
+static CACHE_HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+static CACHE_MISSES: AtomicUsize = ATOMIC_USIZE_INIT;
+static BYTES_COPIED: AtomicUsize = ATOMIC_USIZE_INIT;
+static FILES_PROCESSED: AtomicUsize = ATOMIC_USIZE_INIT;
+static PROBE_LIMIT_HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+static FILES_SHARED: AtomicUsize = ATOMIC_USIZE_INIT;
+static LINES_SKIPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+static STAGE_FALLBACKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static MODE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn value(counter: Counter) -> &'static AtomicUsize {
+    match counter {
+        Counter::TreeReads => &TREE_READS,
+        Counter::TreeWrites => &TREE_WRITES,
+        Counter::CacheHits => &CACHE_HITS,
+        Counter::CacheMisses => &CACHE_MISSES,
+        Counter::BytesCopied => &BYTES_COPIED,
+        Counter::FilesProcessed => &FILES_PROCESSED,
+        Counter::ProbeLimitHits => &PROBE_LIMIT_HITS,
+        Counter::FilesShared => &FILES_SHARED,
+        Counter::LinesSkipped => &LINES_SKIPPED,
+        Counter::StageFallbacks => &STAGE_FALLBACKS
+    }
+}
+
+fn counter_name(counter: Counter) -> &'static str {
+    match counter {
+        Counter::TreeReads => "tree_reads",
+        Counter::TreeWrites => "tree_writes",
+        Counter::CacheHits => "cache_hits",
+        Counter::CacheMisses => "cache_misses",
+        Counter::BytesCopied => "bytes_copied",
+        Counter::FilesProcessed => "files_processed",
+        Counter::ProbeLimitHits => "probe_limit_hits",
+        Counter::FilesShared => "files_shared",
+        Counter::LinesSkipped => "lines_skipped",
+        Counter::StageFallbacks => "stage_fallbacks"
+    }
+}
+
+pub fn count(counter: Counter, amount: u64) {
+    value(counter).fetch_add(amount as usize, Ordering::Relaxed);
+}
+
+pub fn counter_value(counter: Counter) -> u64 {
+    value(counter).load(Ordering::Relaxed) as u64
+}
+
+pub fn reset_counters() {
+    for counter in COUNTERS.iter() {
+        value(*counter).store(0, Ordering::Relaxed);
+    }
+}
+
+pub fn set_profile_mode(mode: ProfileMode) {
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+pub fn profile_mode() -> ProfileMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => ProfileMode::Summary,
+        2 => ProfileMode::Spans,
+        _ => ProfileMode::Off
+    }
+}
+
+fn snapshot() -> [u64; 10] {
+    let mut values = [0; 10];
+    for (i, counter) in COUNTERS.iter().enumerate() {
+        values[i] = counter_value(*counter);
+    }
+    values
+}
+
+fn format_counters(values: &[u64; 10]) -> String {
+    // key=value pairs, the same names in every line
+    let pairs: Vec<String> = COUNTERS.iter().zip(values.iter())
+        .map(|(counter, value)| format!("{}={}", counter_name(*counter), value)).collect();
+    pairs.join(" ")
+}
+
+// one operation, written to stderr with its time and what it counted when
+// it's dropped. does nothing unless spans were asked for. counters are
+// shared, so a span covering parallel work counts the other threads too
+pub struct Span {
+    name: &'static str,
+    detail: String,
+    started: u64,
+    counted: [u64; 10]
+}
+
+impl Span {
+    pub fn start<T: Into<String>>(name: &'static str, detail: T) -> Option<Span> {
+        if profile_mode() != ProfileMode::Spans {
+            return None;
+        }
+        Some(Span {
+            name: name,
+            detail: detail.into(),
+            started: monotonic_ns(),
+            counted: snapshot()
+        })
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = monotonic_ns() - self.started;
+        let now = snapshot();
+        let mut counted = [0; 10];
+        for i in 0..counted.len() {
+            counted[i] = now[i] - self.counted[i];
+        }
+        let _ = writeln!(io::stderr(), "span {} {:?} us={} {}", self.name, self.detail, elapsed / 1000,
+                         format_counters(&counted));
+    }
+}
+
+pub fn print_summary(elapsed_ns: u64) {
+    // every counter and phase on stderr, one per line, so it stays out of
+    // the command's own output
+    if profile_mode() == ProfileMode::Off {
+        return;
+    }
+    let mut stderr = io::stderr();
+    for counter in COUNTERS.iter() {
+        let _ = writeln!(stderr, "profile {} {}", counter_name(*counter), counter_value(*counter));
+    }
+    let mut rest = elapsed_ns;
+    for phase in PHASES.iter() {
+        let spent = phase_ns(*phase);
+        rest = rest.saturating_sub(spent);
+        let _ = writeln!(stderr, "profile {}_ms {}", format!("{:?}", phase).to_lowercase(), spent / 1000000);
+    }
+    let _ = writeln!(stderr, "profile walk_ms {}", rest / 1000000);
+    let _ = writeln!(stderr, "profile total_ms {}", elapsed_ns / 1000000);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        // other tests count too, so only look at the change
+        let before = counter_value(Counter::BytesCopied);
+        count(Counter::BytesCopied, 10);
+        count(Counter::BytesCopied, 5);
+        assert!(counter_value(Counter::BytesCopied) >= before + 15);
+
+        assert_eq!(profile_mode(), ProfileMode::Off);
+        assert!(Span::start("test", "nothing").is_none());
+        assert_eq!(format_counters(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]),
+                   "tree_reads=1 tree_writes=2 cache_hits=3 cache_misses=4 bytes_copied=5 files_processed=6 \
+                    probe_limit_hits=7 files_shared=8 lines_skipped=9 stage_fallbacks=10");
+    }
+}
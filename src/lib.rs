@@ -0,0 +1,4553 @@
+//! half2 keeps per-file line indexes of a checkout in a `.h2` directory,
+//! along with staged snapshots and committed revisions of every file.
+//! The `h2` binary is a thin command line wrapper around this crate.
+
+#![feature(core)]
+#![feature(hash)]
+#![feature(collections)]
+#![feature(dir_entry_ext)]
+#![feature(path_relative_from)]
+#![feature(associated_consts)]
+#![feature(test)]
+#[macro_use]
+extern crate log;
+extern crate test;
+extern crate rustc_serialize;
+extern crate chacha20;
+extern crate chacha20poly1305;
+extern crate hmac;
+extern crate sha2;
+extern crate getrandom;
+
+// general TODO:
+// - return the error type from the library too, not just the binary
+// - unify error handling to be more descriptive (replace try!, unwrap)
+// - move fileops into a separate module so we can mock it out for testing
+
+use std::path::{Path, PathBuf, Component};
+use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::hash::Hasher;
+
+use rustc_serialize::json;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::env;
+use std::mem;
+
+use tree::*;
+use map::*;
+use repo::*;
+use revs::*;
+use lines::*;
+use fileops::*;
+use hashers::*;
+use diff::*;
+use patch::*;
+use linestore::*;
+use manifest::*;
+use subbuf::*;
+use posbuf::*;
+use pack::*;
+use lock::*;
+use portable::*;
+use blame::*;
+use config::*;
+use ignore::*;
+use plan::*;
+use chunks::*;
+use oplog::*;
+use hooks::*;
+use refs::*;
+use undo::*;
+use pathid::*;
+use platform::*;
+use timing::*;
+use instrument::*;
+use walk::*;
+use verify::*;
+use gc::*;
+use crypt::*;
+use inspect::*;
+use sync::*;
+use gitimport::*;
+use gitexport::*;
+use drivers::*;
+use graph::*;
+use stash::*;
+use autosnap::*;
+use anchors::*;
+use storage::*;
+use linehash::*;
+use statuscache::*;
+use repository::*;
+
+pub mod tree;
+pub mod map;
+pub mod repo;
+pub mod verify;
+pub mod revs;
+pub mod api;
+pub mod profile;
+pub mod lines;
+pub mod fileops;
+pub mod hashers;
+pub mod diff;
+pub mod patch;
+pub mod gc;
+pub mod linestore;
+pub mod manifest;
+pub mod subbuf;
+pub mod posbuf;
+pub mod pack;
+pub mod lock;
+pub mod portable;
+pub mod archive;
+pub mod blame;
+pub mod plan;
+pub mod config;
+pub mod ignore;
+pub mod chunks;
+pub mod delta;
+pub mod oplog;
+pub mod hooks;
+pub mod refs;
+pub mod undo;
+pub mod pathid;
+pub mod platform;
+pub mod timing;
+pub mod instrument;
+pub mod walk;
+pub mod synth;
+pub mod stats;
+pub mod crypt;
+pub mod inspect;
+pub mod sync;
+pub mod gitimport;
+pub mod gitexport;
+pub mod repository;
+pub mod migrate;
+pub mod drivers;
+pub mod graph;
+pub mod stash;
+pub mod autosnap;
+pub mod anchors;
+pub mod storage;
+pub mod linehash;
+pub mod statuscache;
+pub mod error;
+
+pub use tree::BufTree;
+pub use map::BufMap;
+pub use repository::Repository;
+pub use error::H2Error;
+
+const FILE_BLOCK_LENGTH: usize = 1;
+/// How many places of a line a diff tries before matching it by position.
+pub const DEFAULT_PROBE_LIMIT: usize = 64;
+/// Files up to this many bytes keep their line index in their meta instead
+/// of in a tree of their own.
+pub const DEFAULT_INLINE_LIMIT: u64 = 4096;
+/// Paths that are never staged or diffed.
+pub const DEFAULT_IGNORE: [&'static str; 5] = [".h2", ".git", "target", "perf.data", "src"];
+
+/// Snapshots of every file as of the last time it was added.
+#[derive(Debug)]
+pub struct Stage {
+    path: PathBuf,
+    // where chunked snapshots keep their content
+    chunks: ChunkStore,
+    // files bigger than this are stored as chunk lists, none to copy everything whole
+    chunk_threshold: Option<u64>,
+    // where to save snapshots before they're replaced
+    undo: Option<Undo>,
+    // file copies waiting for flush, when copying in parallel
+    copies: Option<CopyBatch>,
+    // how many more times a file that changes while it's copied is copied
+    copy_retries: usize,
+    // whether files are copied, or cloned or linked where they can be
+    copy_strategy: CopyStrategy,
+    // whether copies are read back and checked against what was read
+    checked_copies: bool,
+    // ids of checked copies with their hashes, for the manifest
+    copy_hashes: Vec<(PathBuf, u64)>,
+    // ids of files that never held still while they were copied, staged as
+    // they were on the last try
+    unsettled: Vec<PathBuf>,
+    // seals every staged copy, if the repository is encrypted
+    key: Option<StoreKey>,
+    plan: Plan
+}
+
+/// The working tree the repository tracks.
+#[derive(Debug)]
+pub struct Checkout {
+    pub path: PathBuf,
+    plan: Plan,
+    // where walks put entries they couldn't read
+    errors: WalkErrors,
+    // the command line's file filter, which load_ignore puts over the
+    // config's
+    filter: FileFilter
+}
+
+/// A file or directory in the checkout, along with its id relative to it.
+pub struct PathInfo {
+    path: PathBuf,
+    pub id: PathBuf,
+    pub metadata: fs::Metadata
+}
+
+/// What a file is diffed against to tell whether it changed: its line
+/// index, or an index built in memory from its staged copy. The stage is
+/// also used whenever an index turns out to be damaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffSource {
+    Index,
+    Stage
+}
+
+impl Default for DiffSource {
+    fn default() -> DiffSource {
+        DiffSource::Index
+    }
+}
+
+impl fmt::Display for DiffSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DiffSource::Index => write!(f, "index"),
+            DiffSource::Stage => write!(f, "stage")
+        }
+    }
+}
+
+impl DiffSource {
+    pub fn from_config(config: &Config) -> io::Result<DiffSource> {
+        match config.get("diff_source") {
+            None | Some("index") => Ok(DiffSource::Index),
+            Some("stage") => Ok(DiffSource::Stage),
+            Some(value) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Config value diff_source = {:?} is not index or stage", value)))
+            }
+        }
+    }
+}
+
+/// The per-file line indexes used to find changes.
+#[derive(Debug)]
+pub struct Logs {
+    path: PathBuf,
+    // hasher used for new indexes, existing ones record their own
+    hasher: LineHasher,
+    // whether to trust matching size and mtime to mean a file is unchanged
+    stat_cache: bool,
+    // where to save indexes before they're replaced
+    undo: Option<Undo>,
+    // every tracked path with its stat info, kept up to date alongside the
+    // indexes. diffing only reads it, so it sits behind a RefCell
+    manifest: Option<RefCell<Manifest<fs::File>>>,
+    // packed indexes the manifest points into
+    packs: Packs,
+    // where new indexes go, their own directories or the active pack
+    store: Box<IndexStore + Send>,
+    // node width for new indexes, none to fill a page
+    tree_width: Option<usize>,
+    // how index files grow while they're built
+    tree_growth: TreeGrowth,
+    // line endings for new indexes, like the hasher existing ones record
+    // their own
+    line_endings: LineEndings,
+    // places a diff tries per line, a line in more than this many is
+    // matched against the ones nearest its position
+    probe_limit: usize,
+    // files diffed at once, each thread with its own handles
+    threads: usize,
+    // files up to this size get an inline index rather than a tree
+    inline_limit: u64,
+    // lines longer than this are indexed as blocks of this length, like the
+    // hasher existing indexes record their own
+    max_line_length: usize,
+    // which driver new indexes are built with, existing ones record their own
+    drivers: DriverRules,
+    // what diffs read, the index unless it's damaged or the config says
+    // the stage
+    diff_source: DiffSource,
+    // opens staged copies read for a diff, if the repository is encrypted
+    stage_key: Option<StoreKey>,
+    plan: Plan
+}
+
+#[derive(Debug, Clone, Copy, RustcDecodable, RustcEncodable)]
+struct IndexPlace {
+    node: usize,
+    offset: isize
+}
+
+// the line index of a file, from line hash to every place it appears
+type LineIndex<T> = BufMap<T, u64, Vec<IndexPlace>>;
+
+// a file's line index as it was kept: a tree of its own, or for a small file
+// a list sorted by line hash that lives in its meta. diffing and verifying
+// look lines up the same way in either
+enum FileIndex<T: Read + Write + Seek + fmt::Debug> {
+    Tree(LineIndex<T>),
+    Inline(Vec<(u64, Vec<IndexPlace>)>)
+}
+
+impl<T: Read + Write + Seek + fmt::Debug> FileIndex<T> {
+    fn get(&mut self, hash: u64) -> io::Result<Option<Vec<IndexPlace>>> {
+        match *self {
+            FileIndex::Tree(ref mut index) => index.get(hash),
+            FileIndex::Inline(ref items) => {
+                Ok(items.binary_search_by(|item| item.0.cmp(&hash)).ok().map(|i| items[i].1.clone()))
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            FileIndex::Tree(ref index) => index.len(),
+            FileIndex::Inline(ref items) => items.len()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn verify_each<F: FnMut(&u64, &Vec<IndexPlace>)>(&mut self, mut each: F) -> io::Result<usize> {
+        match *self {
+            FileIndex::Tree(ref mut index) => index.verify_each(each),
+            FileIndex::Inline(ref items) => {
+                // a binary search only finds what's in order
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 && items[i - 1].0 >= item.0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Inline index is out of order at line hash {}",
+                                                          item.0)));
+                    }
+                    each(&item.0, &item.1);
+                }
+                Ok(items.len())
+            }
+        }
+    }
+}
+
+/// What a file's index records about it: its line count, how it was hashed,
+/// and the stat info it had when it was indexed.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable)]
+pub struct FileMeta {
+    pub node_count: usize,
+    // whether the last line of the file had no terminator
+    pub no_trailing_newline: bool,
+    // the hasher the index was built with
+    pub hasher: LineHasher,
+    // stat info of the file when it was indexed
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    // hash over every line hash in the file
+    pub content_hash: u64,
+    // how line endings were treated when the lines were hashed, none for
+    // indexes from before it could be set, which kept them exact
+    pub line_endings: Option<LineEndings>,
+    // the driver that built the index, none for indexes from before there
+    // was a choice, which are all lines
+    pub driver: Option<DiffDriver>,
+    // how long a line could be before it was cut into blocks, none for
+    // indexes from before there was a cap, which are read with the one the
+    // config has now
+    pub max_line_length: Option<usize>,
+    // the whole line index of a small file, sorted by line hash. none when
+    // the index is a tree in the content and places files
+    inline_index: Option<Vec<(u64, Vec<IndexPlace>)>>
+}
+
+/// Every tracked path id with its meta, in path order, as returned by
+/// `Logs::tracked_paths`.
+pub struct TrackedPaths<'a> {
+    logs: &'a Logs,
+    ids: ::std::vec::IntoIter<PathBuf>
+}
+
+impl<'a> Iterator for TrackedPaths<'a> {
+    type Item = io::Result<(PathBuf, FileMeta)>;
+
+    fn next(&mut self) -> Option<io::Result<(PathBuf, FileMeta)>> {
+        self.ids.next().map(|id| {
+            let meta = try!(self.logs.read_meta(&id));
+            Ok((id, meta))
+        })
+    }
+}
+
+impl Portable for IndexPlace {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_u64(out, self.node as u64));
+        write_u64(out, self.offset as i64 as u64)
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<IndexPlace> {
+        Ok(IndexPlace {
+            node: try!(read_u64(input)) as usize,
+            offset: try!(read_u64(input)) as i64 as isize
+        })
+    }
+}
+
+impl fmt::Debug for PathInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PathInfo {{ path: {:?}, id: {:?}, metadata: {{...}} }}", self.path, self.id)
+    }
+}
+
+impl PathInfo {
+    pub fn new<T: Into<PathBuf>, V: Into<PathBuf>>(path: T, id: V, metadata: fs::Metadata) -> PathInfo {
+        PathInfo {
+            path: path.into(),
+            id: id.into(),
+            metadata: metadata
+        }
+    }
+
+    pub fn get_buffer(&self) -> Result<fs::File, io::Error> {
+        fs::File::open(&self.path)
+    }
+
+    pub fn copy<T: Into<PathBuf>>(&self, to: T) -> Result<(), io::Error> {
+        self.copy_with(to, CopyStrategy::Copy)
+    }
+
+    pub fn copy_with<T: Into<PathBuf>>(&self, to: T, strategy: CopyStrategy) -> Result<(), io::Error> {
+        if self.metadata.is_dir() {
+            trace!("Copying as directory");
+            self.copy_dir(to)
+        } else if self.metadata.is_file() {
+            trace!("Copying as file");
+            self.copy_file(to, strategy)
+        } else {
+            // walks leave these out, this is for a path named outright
+            error!("{} is neither a file nor a directory", self.path.display());
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("{} is neither a file nor a directory", self.path.display())))
+        }
+    }
+
+    fn copy_dir<T: Into<PathBuf>>(&self, to: T) -> Result<(), io::Error> {
+        let dest_path = to.into().join(&self.id);
+        debug!("Creating directory at {:?}", &dest_path);
+        match fs::create_dir_all(dest_path) {
+            Err(e) => {
+                error!("Failed to create directory: {}", e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Directory created successfully");
+                Ok(())
+            }
+        }
+    }
+
+    fn copy_file<T: Into<PathBuf>>(&self, to: T, strategy: CopyStrategy) -> Result<(), io::Error> {
+        let dest_path = to.into().join(&self.id);
+
+        debug!("Creating parent directory for path");
+        match fs::create_dir_all(dest_path.parent().unwrap()) {
+            Err(e) => {
+                error!("Failed to create parent directory: {}", e);
+                return Err(e);
+            },
+            Ok(_) => {
+                trace!("Directory created");
+            }
+        }
+
+        if try!(share_file(&self.path, &dest_path, strategy)) {
+            trace!("Shared {:?} with {:?}", &dest_path, &self.path);
+            return Ok(());
+        }
+
+        debug!("Copying {:?} to {:?}", &self.path, &dest_path);
+        match atomic_copy(&self.path, &dest_path) {
+            Err(e) => {
+                error!("Failed to copy {} to {}: {}", self.path.display(), dest_path.display(), e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Copy succeeded");
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for Stage {
+    fn default() -> Stage {
+        Stage::new(repo_path("stage"))
+    }
+}
+
+impl Stage {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Stage {
+        let path = path.into();
+        Stage {
+            chunks: ChunkStore::new(path.with_file_name("chunks")),
+            path: path,
+            chunk_threshold: None,
+            undo: None,
+            copies: None,
+            copy_retries: DEFAULT_COPY_RETRIES,
+            copy_strategy: CopyStrategy::Copy,
+            checked_copies: false,
+            copy_hashes: vec![],
+            unsettled: vec![],
+            key: None,
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Stage {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_key(mut self, key: Option<StoreKey>) -> Stage {
+        self.key = key;
+        self
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn with_chunking(mut self, threshold: Option<u64>) -> Stage {
+        self.chunk_threshold = threshold;
+        self
+    }
+
+    pub fn with_undo(mut self, undo: Undo) -> Stage {
+        self.undo = Some(undo);
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Stage {
+        // with more than one thread, file copies are queued until flush
+        self.copies = if threads > 1 {
+            Some(CopyBatch::new(threads).with_retries(self.copy_retries).with_strategy(self.copy_strategy)
+                 .with_checked(self.checked_copies))
+        } else {
+            None
+        };
+        self
+    }
+
+    pub fn with_copy_retries(mut self, retries: usize) -> Stage {
+        self.copy_retries = retries;
+        self.copies = self.copies.take().map(|copies| copies.with_retries(retries));
+        self
+    }
+
+    pub fn with_copy_strategy(mut self, strategy: CopyStrategy) -> Stage {
+        self.copy_strategy = strategy;
+        self.copies = self.copies.take().map(|copies| copies.with_strategy(strategy));
+        self
+    }
+
+    pub fn with_checked_copies(mut self, checked: bool) -> Stage {
+        self.checked_copies = checked;
+        self.copies = self.copies.take().map(|copies| copies.with_checked(checked));
+        self
+    }
+
+    pub fn take_copy_hashes(&mut self) -> Vec<(PathBuf, u64)> {
+        mem::replace(&mut self.copy_hashes, vec![])
+    }
+
+    pub fn unsettled(&self) -> &[PathBuf] {
+        &self.unsettled
+    }
+
+    pub fn init(&mut self) -> Result<(), io::Error> {
+        info!("Creating Stage");
+        if !self.plan.allow(Op::CreateDir(&self.path)) {
+            return Ok(());
+        }
+        match fs::create_dir_all(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                trace!("Directory already existed");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Directory created");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn add_path(&mut self, path: &PathInfo) -> Result<(), io::Error> {
+        // initial implementation. Overwrites anything.
+        info!("Adding path {:?}", path);
+        let dest_path = self.path.join(&path.id);
+        let op = if path.metadata.is_dir() {
+            Op::CreateDir(&dest_path)
+        } else {
+            Op::CopyFile(&path.path, &dest_path)
+        };
+        if !self.plan.allow(op) {
+            return Ok(());
+        }
+        if let Some(ref undo) = self.undo {
+            if path.metadata.is_file() {
+                try!(undo.save("stage", &self.path, &path.id));
+            }
+        }
+        let _timer = PhaseTimer::start(Phase::Copy);
+        let retries = self.copy_retries;
+        let settled = match self.chunk_threshold {
+            _ if path.metadata.is_file() && self.key.is_some() => {
+                // sealed as it's read, the clear copy never reaches the stage
+                debug!("Sealing {:?}", &path.id);
+                try!(fs::create_dir_all(dest_path.parent().unwrap()));
+                let (key, place) = (self.key.as_ref().unwrap(), seal_place("stage", &path.id));
+                let (sealed, settled) = try!(settled_read(&path.path, retries, || {
+                    let mut data = vec![];
+                    try!(path.get_buffer().and_then(|mut f| f.read_to_end(&mut data)));
+                    key.seal(&place, &data)
+                }));
+                try!(atomic_write(&dest_path, &sealed));
+                settled
+            },
+            Some(threshold) if path.metadata.is_file() && path.metadata.len() > threshold => {
+                debug!("Storing {:?} as chunks", &path.id);
+                try!(fs::create_dir_all(dest_path.parent().unwrap()));
+                let chunks = &self.chunks;
+                let (manifest, settled) = try!(settled_read(&path.path, retries, || chunks.store_file(&path.path)));
+                try!(atomic_write(&dest_path, &manifest));
+                settled
+            },
+            _ => match self.copies {
+                Some(ref mut copies) if path.metadata.is_file() => {
+                    trace!("Queueing copy of {:?}", &path.id);
+                    copies.add(path.path.clone(), dest_path.clone());
+                    true
+                },
+                _ if path.metadata.is_file() && self.checked_copies => {
+                    trace!("Copying {:?} with a check", &path.id);
+                    try!(fs::create_dir_all(dest_path.parent().unwrap()));
+                    let strategy = self.copy_strategy;
+                    let (hash, settled) = try!(settled_read(&path.path, retries,
+                                                            || checked_copy(&path.path, &dest_path, strategy)));
+                    self.copy_hashes.push((path.id.clone(), hash));
+                    settled
+                },
+                _ if path.metadata.is_file() => {
+                    // copy the file to the stage, again if it changes meanwhile
+                    let (stage_path, strategy) = (&self.path, self.copy_strategy);
+                    try!(settled_read(&path.path, retries, || path.copy_with(stage_path, strategy))).1
+                },
+                _ => {
+                    try!(path.copy(&self.path));
+                    true
+                }
+            }
+        };
+        if !settled {
+            self.unsettled.push(path.id.clone());
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<usize> {
+        // finish any queued copies
+        let _timer = PhaseTimer::start(Phase::Copy);
+        let copied = match self.copies {
+            Some(ref mut copies) => try!(copies.run()),
+            None => 0
+        };
+        let unsettled = match self.copies {
+            Some(ref mut copies) => copies.take_unsettled(),
+            None => vec![]
+        };
+        let hashes = match self.copies {
+            Some(ref mut copies) => copies.take_hashes(),
+            None => vec![]
+        };
+        for (dest, hash) in hashes {
+            match dest.relative_from(&self.path) {
+                Some(id) => {
+                    self.copy_hashes.push((PathBuf::from(id), hash));
+                },
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("Copied {} outside of the stage", dest.display())));
+                }
+            }
+        }
+        for dest in unsettled {
+            match dest.relative_from(&self.path) {
+                Some(id) => {
+                    self.unsettled.push(PathBuf::from(id));
+                },
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("Copied {} outside of the stage", dest.display())));
+                }
+            }
+        }
+        Ok(copied)
+    }
+
+    pub fn read_path<T: AsRef<Path>>(&self, id: T) -> io::Result<Vec<u8>> {
+        // the staged content of a file, empty if it was never staged
+        let data = try!(self.read_stored(id));
+        self.chunks.expand(data)
+    }
+
+    pub fn read_stored<T: AsRef<Path>>(&self, id: T) -> io::Result<Vec<u8>> {
+        // a staged copy as it was stored, opened if it was sealed but with
+        // any chunk list left as it is
+        let id = id.as_ref();
+        let path = self.path.join(id);
+        let data = try!(read_or_empty(&path));
+        if data.is_empty() {
+            return Ok(data);
+        }
+        open_stored(self.key.as_ref(), &seal_place("stage", id), data, &path)
+    }
+}
+
+impl Default for Checkout {
+    fn default() -> Checkout {
+        Checkout::new(".")
+    }
+}
+
+impl Checkout {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Checkout {
+        Checkout {
+            path: path.into(),
+            plan: Plan::default(),
+            errors: WalkErrors::default(),
+            filter: FileFilter::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Checkout {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_errors(mut self, errors: WalkErrors) -> Checkout {
+        self.errors = errors;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: FileFilter) -> Checkout {
+        self.filter = filter;
+        self
+    }
+
+    pub fn walk<F>(&self, path: &Path, ignore: &IgnoreRules, visit: F) -> io::Result<()>
+        where F: FnMut(PathBuf, PathBuf, fs::Metadata) -> io::Result<()> {
+        // everything under a directory of the checkout, see walk_dir
+        walk_dir(&self.path, &self.path.join(path), ignore, &self.errors, visit)
+    }
+
+    pub fn init(&mut self) -> Result<(), io::Error> {
+        info!("Creating checkout");
+        if fs::metadata(&self.path).is_ok() {
+            trace!("Checkout already exists");
+            return Ok(());
+        }
+        if !self.plan.allow(Op::CreateDir(&self.path)) {
+            return Ok(());
+        }
+        match fs::create_dir_all(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                trace!("Directory already existed");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Directory created");
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for Logs {
+    fn default() -> Logs {
+        Logs::new(repo_path("logs"))
+    }
+}
+
+impl Logs {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Logs {
+        Logs::with_hasher(path, LineHasher::default())
+    }
+
+    pub fn with_hasher<T: Into<PathBuf>>(path: T, hasher: LineHasher) -> Logs {
+        let path = path.into();
+        Logs {
+            packs: Packs::new(path.with_file_name("packs")),
+            store: open_store(Backend::default(), &path),
+            path: path,
+            hasher: hasher,
+            stat_cache: true,
+            undo: None,
+            manifest: None,
+            tree_width: None,
+            tree_growth: TreeGrowth::default(),
+            line_endings: LineEndings::default(),
+            probe_limit: DEFAULT_PROBE_LIMIT,
+            threads: 1,
+            inline_limit: DEFAULT_INLINE_LIMIT,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            drivers: DriverRules::default(),
+            diff_source: DiffSource::default(),
+            stage_key: None,
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Logs {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_stat_cache(mut self, stat_cache: bool) -> Logs {
+        self.stat_cache = stat_cache;
+        self
+    }
+
+    pub fn with_undo(mut self, undo: Undo) -> Logs {
+        self.undo = Some(undo);
+        self
+    }
+
+    pub fn with_tree_width(mut self, width: Option<usize>) -> Logs {
+        self.tree_width = width;
+        self
+    }
+
+    pub fn with_tree_growth(mut self, growth: TreeGrowth) -> Logs {
+        self.tree_growth = growth;
+        self
+    }
+
+    pub fn with_line_endings(mut self, line_endings: LineEndings) -> Logs {
+        self.line_endings = line_endings;
+        self
+    }
+
+    pub fn line_endings(&self) -> LineEndings {
+        self.line_endings
+    }
+
+    pub fn with_probe_limit(mut self, limit: usize) -> Logs {
+        self.probe_limit = limit;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Logs {
+        self.threads = if threads == 0 {1} else {threads};
+        self
+    }
+
+    pub fn with_inline_limit(mut self, limit: u64) -> Logs {
+        self.inline_limit = limit;
+        self
+    }
+
+    pub fn with_max_line_length(mut self, length: usize) -> Logs {
+        self.max_line_length = length;
+        self
+    }
+
+    pub fn with_drivers(mut self, drivers: DriverRules) -> Logs {
+        self.drivers = drivers;
+        self
+    }
+
+    pub fn drivers(&self) -> &DriverRules {
+        &self.drivers
+    }
+
+    pub fn driver_for(&self, id: &Path) -> DiffDriver {
+        self.drivers.driver_for(id)
+    }
+
+    pub fn with_diff_source(mut self, source: DiffSource) -> Logs {
+        self.diff_source = source;
+        self
+    }
+
+    pub fn with_stage_key(mut self, key: Option<StoreKey>) -> Logs {
+        self.stage_key = key;
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Logs {
+        self.store = open_store(backend, &self.path);
+        self
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.store.backend()
+    }
+
+    pub fn with_manifest(mut self, manifest: Manifest<fs::File>) -> Logs {
+        self.manifest = Some(RefCell::new(manifest));
+        self
+    }
+
+    pub fn fork(&self) -> io::Result<Logs> {
+        // the same logs with handles of their own, for diffing on another
+        // thread
+        let mut logs = Logs::with_hasher(self.path.clone(), self.hasher)
+            .with_stat_cache(self.stat_cache)
+            .with_tree_width(self.tree_width)
+            .with_tree_growth(self.tree_growth)
+            .with_line_endings(self.line_endings)
+            .with_probe_limit(self.probe_limit)
+            .with_inline_limit(self.inline_limit)
+            .with_max_line_length(self.max_line_length)
+            .with_drivers(self.drivers.clone())
+            .with_backend(self.store.backend())
+            .with_diff_source(self.diff_source)
+            .with_stage_key(self.stage_key.clone())
+            .with_plan(self.plan);
+        if let Some(ref manifest) = self.manifest {
+            logs = logs.with_manifest(try!(manifest.borrow().reopen()));
+        }
+        Ok(logs)
+    }
+
+    pub fn tracked_ids(&self) -> io::Result<Option<Vec<PathBuf>>> {
+        // every path in the manifest, none if there isn't one
+        match self.manifest {
+            Some(ref manifest) => manifest.borrow_mut().ids().map(Some),
+            None => Ok(None)
+        }
+    }
+
+    pub fn tracked_dirs(&self) -> io::Result<Option<Vec<PathBuf>>> {
+        // every directory in the manifest, none if there isn't one
+        match self.manifest {
+            Some(ref manifest) => manifest.borrow_mut().dir_ids().map(Some),
+            None => Ok(None)
+        }
+    }
+
+    pub fn tree_hash(&self) -> io::Result<Option<u64>> {
+        // the hash of every tracked path and its content, none without a manifest
+        match self.manifest {
+            Some(ref manifest) => manifest.borrow_mut().tree_hash().map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn indexed_ids(&self) -> io::Result<Vec<PathBuf>> {
+        // every path with an index, from the manifest when there is one and
+        // from the layout of the logs directory when there isn't
+        let mut ids = match try!(self.tracked_ids()) {
+            Some(ids) => ids,
+            None => try!(log_ids(&self.path))
+        };
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Iterate over every tracked path id along with the meta recorded in its
+    /// index, without the caller having to know how the logs are laid out.
+    pub fn tracked_paths(&self) -> io::Result<TrackedPaths> {
+        Ok(TrackedPaths {
+            logs: self,
+            ids: try!(self.indexed_ids()).into_iter()
+        })
+    }
+
+    pub fn forget(&self, id: &Path) -> io::Result<()> {
+        // drop a path, or everything under a directory, from the manifest
+        if self.plan.is_dry_run() {
+            return Ok(());
+        }
+        if let Some(ref manifest) = self.manifest {
+            let removed = try!(manifest.borrow_mut().remove_under(id));
+            debug!("Dropped {} paths under {:?} from the manifest", removed, id);
+        }
+        Anchors::new(self.path.with_file_name("anchors")).with_plan(self.plan).forget(id)
+    }
+
+    fn record_dirs(&self, path: &PathInfo) -> io::Result<()> {
+        // a directory being staged, and the ones a staged path is in up to
+        // the first that's already recorded, so emptying them later shows
+        let manifest = match self.manifest {
+            Some(ref manifest) if !self.plan.is_dry_run() => manifest,
+            _ => {
+                return Ok(());
+            }
+        };
+        let mut manifest = manifest.borrow_mut();
+        if path.metadata.is_dir() && !path.id.as_os_str().is_empty() && path.id.as_path() != Path::new(".") {
+            trace!("Recording directory {:?} in the manifest", &path.id);
+            try!(manifest.insert(ManifestEntry::dir(&path.id, mode_bits(&path.metadata))));
+        }
+        let mut id = path.id.parent();
+        let mut dir = path.path.parent();
+        while let (Some(parent_id), Some(parent)) = (id, dir) {
+            if parent_id.as_os_str().is_empty() || parent_id == Path::new(".") {
+                break;
+            }
+            if try!(manifest.get(parent_id)).map_or(false, |entry| entry.is_dir()) {
+                trace!("{:?} is already recorded", parent_id);
+                break;
+            }
+            let metadata = try!(fs::metadata(parent));
+            trace!("Recording directory {:?} in the manifest", parent_id);
+            try!(manifest.insert(ManifestEntry::dir(parent_id, mode_bits(&metadata))));
+            id = parent_id.parent();
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+
+    fn record_manifest(&self, id: &Path, meta: &FileMeta, location: Option<PackLocation>) -> io::Result<()> {
+        if location.is_some() && self.manifest.is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("Index of {:?} was packed, but there's no manifest to find it by", id)));
+        }
+        if let Some(ref manifest) = self.manifest {
+            trace!("Recording {:?} in the manifest", id);
+            try!(manifest.borrow_mut().insert(ManifestEntry {
+                id: id_bytes(id),
+                index: id_bytes(id),
+                size: meta.size,
+                mtime: meta.mtime,
+                mtime_nsec: meta.mtime_nsec,
+                content_hash: meta.content_hash,
+                lines: meta.node_count as u64,
+                // a fresh index is in its own directory, or wherever the
+                // packed backend appended it
+                pack: location,
+                // and a checked copy records its hash once it's done
+                stage_hash: None,
+                dir_mode: None
+            }));
+        }
+        Ok(())
+    }
+
+    pub fn record_stage_hash(&self, id: &Path, hash: u64) -> io::Result<()> {
+        // the hash of a checked copy in the stage, for verify to read it
+        // back against later
+        if let Some(ref manifest) = self.manifest {
+            let mut manifest = manifest.borrow_mut();
+            if let Some(mut entry) = try!(manifest.get(id)) {
+                trace!("Recording stage hash {:016x} of {:?}", hash, id);
+                entry.stage_hash = Some(hash);
+                try!(manifest.insert(entry));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stage_hash(&self, id: &Path) -> io::Result<Option<u64>> {
+        match self.manifest {
+            Some(ref manifest) => Ok(try!(manifest.borrow_mut().get(id)).and_then(|entry| entry.stage_hash)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn pack_location(&self, id: &Path) -> io::Result<Option<PackLocation>> {
+        match self.manifest {
+            Some(ref manifest) => Ok(try!(manifest.borrow_mut().get(id)).and_then(|entry| entry.pack)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn is_indexed(&self, id: &Path) -> io::Result<bool> {
+        if try!(self.pack_location(id)).is_some() {
+            Ok(true)
+        } else {
+            Ok(fs::metadata(self.path.join(id).join("meta")).is_ok())
+        }
+    }
+
+    pub fn read_meta(&self, id: &Path) -> io::Result<FileMeta> {
+        // just the meta of a file's index, without opening its trees
+        let meta_data = match try!(self.pack_location(id)) {
+            Some(location) => {
+                trace!("Reading meta of {:?} from pack {}", id, location.pack);
+                try!(self.packs.read_span(location.pack, location.meta))
+            },
+            None => {
+                let mut meta_data = vec![];
+                try!(fs::File::open(self.path.join(id).join("meta")).and_then(|mut f| f.read_to_end(&mut meta_data)));
+                meta_data
+            }
+        };
+        decode_meta(id, &meta_data)
+    }
+
+    fn open_index(&self, id: &Path) -> io::Result<(FileMeta, FileIndex<SubBuffer<fs::File>>)> {
+        // the meta and line index of a file, out of a pack or its own
+        // directory. private like LineIndex, but the rest of the crate can use it.
+        // once the trees are open they're ours, even if the index is replaced
+        let _lock = try!(IndexLock::acquire(self.path.join(id), LockMode::Shared));
+        let mut meta = try!(self.read_meta(id));
+        if let Some(items) = meta.inline_index.take() {
+            trace!("Index of {:?} is inline", id);
+            return Ok((meta, FileIndex::Inline(items)));
+        }
+        let (content, places) = match try!(self.pack_location(id)) {
+            Some(location) => {
+                trace!("Reading index of {:?} from pack {}", id, location.pack);
+                (try!(self.packs.window(location.pack, location.content)),
+                 try!(self.packs.window(location.pack, location.places)))
+            },
+            None => {
+                let dir = self.path.join(id);
+                trace!("Reading index of {:?} from {:?}", id, &dir);
+                (try!(fs::File::open(dir.join("content")).and_then(SubBuffer::whole)),
+                 try!(fs::File::open(dir.join("places")).and_then(SubBuffer::whole)))
+            }
+        };
+
+        match unsafe {BufMap::from_buffers(content, places)} {
+            Err(e) => {
+                error!("Failed to open index of {}: {}", id.display(), e);
+                Err(e)
+            },
+            Ok(index) => Ok((meta, FileIndex::Tree(index)))
+        }
+    }
+
+    pub fn index_size(&self, id: &Path) -> io::Result<u64> {
+        // bytes taken by a file's line index
+        match try!(self.pack_location(id)) {
+            Some(location) => Ok(location.content.len + location.places.len),
+            None => {
+                // an inline index has no files besides its meta
+                let dir = self.path.join(id);
+                let mut size = 0;
+                for name in ["content", "places"].iter() {
+                    match fs::metadata(dir.join(name)) {
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                        Err(e) => {
+                            return Err(e);
+                        },
+                        Ok(data) => {
+                            size += data.len();
+                        }
+                    }
+                }
+                Ok(size)
+            }
+        }
+    }
+
+    pub fn repack(&self) -> io::Result<usize> {
+        // move every index into a new pack, taking loose ones out of their
+        // directories and leaving behind whatever old packs held that's no
+        // longer used
+        let manifest = match self.manifest {
+            Some(ref manifest) => manifest,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "Packing needs a repository with a manifest"));
+            }
+        };
+        let ids = try!(manifest.borrow_mut().ids());
+        let pack = try!(self.packs.list()).last().map_or(1, |last| last + 1);
+        if !self.plan.allow(Op::WriteFile(&self.packs.pack_path(pack))) {
+            return Ok(ids.len());
+        }
+
+        let mut writer = try!(self.packs.writer(pack));
+        let mut packed = vec![];
+        for id in ids.iter() {
+            let mut entry = match try!(manifest.borrow_mut().get(id)) {
+                Some(entry) => entry,
+                None => {
+                    continue;
+                }
+            };
+            let parts = match entry.pack {
+                Some(location) => {
+                    vec![try!(self.packs.read_span(location.pack, location.meta)),
+                         try!(self.packs.read_span(location.pack, location.content)),
+                         try!(self.packs.read_span(location.pack, location.places))]
+                },
+                None => {
+                    let _lock = try!(IndexLock::acquire(self.path.join(id), LockMode::Shared));
+                    let mut parts = vec![];
+                    for name in ["meta", "content", "places"].iter() {
+                        // an inline index packs empty trees
+                        let mut data = vec![];
+                        match fs::File::open(self.path.join(id).join(name)).and_then(|mut f| f.read_to_end(&mut data)) {
+                            Err(ref e) if e.kind() == io::ErrorKind::NotFound && *name != "meta" => {},
+                            Err(e) => {
+                                return Err(e);
+                            },
+                            Ok(_) => {}
+                        }
+                        parts.push(data);
+                    }
+                    parts
+                }
+            };
+            let loose = entry.pack.is_none();
+            debug!("Packing index of {:?}", id);
+            entry.pack = Some(try!(writer.add(&parts[0], &parts[1], &parts[2])));
+            packed.push((id.clone(), entry, loose));
+        }
+        try!(writer.finish());
+
+        // the pack is complete, so the manifest can point into it and the
+        // copies it replaces can go
+        debug!("Moving the manifest to pack {}", pack);
+        for &(_, ref entry, _) in packed.iter() {
+            try!(manifest.borrow_mut().insert(entry.clone()));
+        }
+        for &(ref id, _, loose) in packed.iter() {
+            if !loose {
+                continue;
+            }
+            let dir = self.path.join(id);
+            let lock = try!(IndexLock::acquire(&dir, LockMode::Exclusive));
+            for name in ["meta", "content", "places"].iter() {
+                match fs::remove_file(dir.join(name)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => {
+                        return Err(e);
+                    },
+                    Ok(()) => {}
+                }
+            }
+            drop(lock);
+            // only empty once nothing else is indexed under it
+            let _ = fs::remove_dir(&dir);
+        }
+        let removed = try!(self.packs.remove_except(pack));
+        debug!("Removed {} old packs", removed);
+        Ok(packed.len())
+    }
+
+    pub fn init(&mut self) -> Result<(), io::Error> {
+        info!("Creating logs");
+        if !self.plan.allow(Op::CreateDir(&self.path)) {
+            return Ok(());
+        }
+        match fs::create_dir_all(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                trace!("Directory already existed");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Directory created");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn diff_path(&self, path: &PathInfo) -> io::Result<bool> {
+        // whether the file differs from its index
+        if !path.metadata.is_file() {
+            // only diff files and then a change
+            error!("Path was not a file: {:?}", path);
+            return Ok(false);
+        } else {
+            info!("Diffing file: {:?}", path);
+        }
+        let _span = Span::start("diff", escape_id(&path.id));
+        count(Counter::FilesProcessed, 1);
+
+        debug!("Reading index for file {:?}", path);
+
+        if self.stat_cache {
+            if let Some(ref manifest) = self.manifest {
+                match try!(manifest.borrow_mut().get(&path.id)) {
+                    Some(ref entry) if entry.size == path.metadata.len() &&
+                        (entry.mtime, entry.mtime_nsec) == mtime(&path.metadata) => {
+                        debug!("Size and mtime match the manifest, skipping {:?}", &path.id);
+                        count(Counter::CacheHits, 1);
+                        return Ok(false);
+                    },
+                    _ => {
+                        trace!("Manifest has nothing current for {:?}", &path.id);
+                    }
+                }
+            }
+        }
+
+        if self.diff_source == DiffSource::Stage {
+            return self.diff_staged(path);
+        }
+        let (meta, mut index) = match self.open_index(&path.id) {
+            Err(e) => {
+                return self.diff_fallback(path, e);
+            },
+            Ok(opened) => opened
+        };
+
+        if self.stat_cache && meta.size == path.metadata.len() &&
+            (meta.mtime, meta.mtime_nsec) == mtime(&path.metadata) {
+            debug!("Size and mtime match the index, skipping {:?}", &path.id);
+            count(Counter::CacheHits, 1);
+            return Ok(false);
+        }
+        if self.stat_cache {
+            count(Counter::CacheMisses, 1);
+        }
+
+        let result = self.diff_with(path, meta, &mut index, true);
+        let damaged = match result {
+            Err(ref e) => e.kind() == io::ErrorKind::InvalidData,
+            Ok(_) => false
+        };
+        if !damaged {
+            return result;
+        }
+        // a lookup ran into something the tree shouldn't hold. if the index
+        // doesn't verify, the stage still has the file
+        match verify_index(self, &path.id) {
+            Ok(()) => result,
+            Err(reason) => self.diff_fallback(path, io::Error::new(io::ErrorKind::InvalidData, reason))
+        }
+    }
+
+    fn diff_fallback(&self, path: &PathInfo, error: io::Error) -> io::Result<bool> {
+        // diff against the stage when the index can't be read, or fail the
+        // way the index did if nothing was staged either
+        match fs::metadata(self.path.with_file_name("stage").join(&path.id)) {
+            Ok(ref data) if data.is_file() => {
+                warn!("Index of {:?} is unusable, diffing against the stage: {}", &path.id, error);
+                count(Counter::StageFallbacks, 1);
+                self.diff_staged(path)
+            },
+            _ => Err(error)
+        }
+    }
+
+    fn diff_staged(&self, path: &PathInfo) -> io::Result<bool> {
+        // whether the file differs from its staged copy, found the same way
+        // as against its index with one built in memory from the stage
+        debug!("Reading staged copy of {:?}", &path.id);
+        let stage = Stage::new(self.path.with_file_name("stage")).with_key(self.stage_key.clone());
+        let staged = try!(stage.read_path(&path.id));
+        let driver = self.drivers.driver_for(&path.id);
+        match driver {
+            DiffDriver::Skip => {
+                debug!("Not diffing {:?}", &path.id);
+                return Ok(false);
+            },
+            DiffDriver::Whole => {
+                debug!("Comparing {:?} with its staged copy", &path.id);
+                return Ok(try!(read_or_empty(&path.path)) != staged);
+            },
+            DiffDriver::Lines | DiffDriver::Blocks => {}
+        }
+
+        let line_endings = if driver.has_lines() {self.line_endings} else {LineEndings::Exact};
+        let mut reader = try!(UnitReader::new(driver, Cursor::new(staged), self.max_line_length));
+        let (places, node_count, content_hash) = {
+            let _timer = PhaseTimer::start(Phase::Hash);
+            try!(index_units(&mut reader, self.hasher, Some(line_endings), 1))
+        };
+        let mut index: LineIndex<_> = try!(BufMap::new(Cursor::new(vec![]), Cursor::new(vec![]), MIN_TREE_WIDTH));
+        try!(index.extend(places));
+        let meta = FileMeta {
+            node_count: node_count,
+            no_trailing_newline: reader.missing_newline(),
+            hasher: self.hasher,
+            size: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            content_hash: content_hash,
+            line_endings: Some(line_endings),
+            driver: Some(driver),
+            max_line_length: Some(self.max_line_length),
+            inline_index: None
+        };
+        // anchors are kept against the index, not a copy made for one diff
+        self.diff_with(path, meta, &mut FileIndex::Tree(index), false)
+    }
+
+    fn diff_with<T: Read + Write + Seek + fmt::Debug>(&self, path: &PathInfo, mut meta: FileMeta,
+                                                       index: &mut FileIndex<T>, anchored: bool)
+                                                       -> io::Result<bool> {
+        let driver = meta.driver.unwrap_or(DiffDriver::Lines);
+        if driver != self.drivers.driver_for(&path.id) {
+            // an index is only read by the driver that built it. adding the
+            // file again builds one with the driver the config has now
+            debug!("{:?} was indexed by the {} driver, the config now has {}", &path.id, driver,
+                   self.drivers.driver_for(&path.id));
+            return Ok(true);
+        }
+        match driver {
+            DiffDriver::Skip => {
+                debug!("Not diffing {:?}", &path.id);
+                return Ok(false);
+            },
+            DiffDriver::Whole => {
+                debug!("Comparing the hash of {:?}", &path.id);
+                let _timer = PhaseTimer::start(Phase::Hash);
+                return Ok(try!(hash_file(&path.path)) != meta.content_hash);
+            },
+            DiffDriver::Lines | DiffDriver::Blocks => {}
+        }
+
+        // where the last diff found the file still matched, if it did. the
+        // lines before the furthest anchor the file still matches needn't be
+        // looked up again
+        let anchors = Anchors::new(self.path.with_file_name("anchors")).with_plan(self.plan);
+        let index_hash = meta.content_hash;
+        let loaded = if anchored && driver == DiffDriver::Lines {
+            anchors.load(&path.id, index_hash)
+        } else {
+            vec![]
+        };
+        let start = if loaded.is_empty() {
+            None
+        } else {
+            let _timer = PhaseTimer::start(Phase::Hash);
+            try!(find_anchor(&mut BufReader::new(try!(path.get_buffer())), &loaded))
+        };
+        let mut found: Vec<Anchor> = loaded.iter().cloned().take_while(|anchor| start.map_or(false, |start| {
+            anchor.line <= start.line
+        })).collect();
+
+        debug!("Opening original file");
+        let mut orig = match path.get_buffer() {
+            Err(e) => {
+                error!("Failed to open file: {}", e);
+                return Err(e);
+            },
+            Ok(mut b) => {
+                trace!("Successfully opened file");
+                if let Some(anchor) = start {
+                    debug!("Starting from line {} of {:?}", anchor.line, &path.id);
+                    try!(b.seek(SeekFrom::Start(anchor.position)));
+                    count(Counter::LinesSkipped, anchor.line as u64);
+                }
+                // wrap in a unit reader so we can read lines or blocks, cut
+                // where the index cut them
+                try!(UnitReader::new(driver, BufReader::new(b),
+                                     meta.max_line_length.unwrap_or(self.max_line_length)))
+            }
+        };
+
+        debug!("Comparing lines");
+        // hashing and looking up each line, the bulk of a diff
+        let _timer = PhaseTimer::start(Phase::Hash);
+        let mut offset: isize = start.map_or(0, |anchor| anchor.offset);
+        let mut new_offset: isize = start.map_or(0, |anchor| anchor.new_offset);
+        let mut counter = start.map_or(0, |anchor| anchor.line);
+        // the bytes read so far and their hash, for the anchors this diff
+        // leaves behind
+        let mut position = start.map_or(0, |anchor| anchor.position);
+        let mut prefix = FnvHasher::with_state(start.map_or(FnvHasher::default().finish(),
+                                                            |anchor| anchor.prefix_hash));
+        let indexed_lines = meta.node_count;
+        let mut changed = false;
+        let mut line = Vec::new();
+        loop {
+            if anchored && !changed && driver == DiffDriver::Lines && counter > 0 && counter % ANCHOR_SPACING == 0 &&
+                !orig.missing_newline() && found.last().map_or(true, |last| last.line < counter) {
+                trace!("Anchoring at line {}", counter);
+                found.push(Anchor {
+                    line: counter,
+                    position: position,
+                    prefix_hash: prefix.finish(),
+                    offset: offset,
+                    new_offset: new_offset
+                });
+            }
+            trace!("Reading line");
+            match orig.read_unit(&mut line) {
+                Ok(false) => {
+                    trace!("Done with this file");
+                    break;
+                },
+                Ok(true) => {
+                    trace!("Got new line: {:?}", String::from_utf8_lossy(&line));
+                },
+                Err(e) => {
+                    error!("Failed to read line: {}", e);
+                    return Err(e);
+                }
+            }
+            if !changed {
+                prefix.write(&line);
+                position += line.len() as u64;
+                if !orig.missing_newline() {
+                    prefix.write(b"\n");
+                    position += 1;
+                }
+            }
+            // hash the way the index did, whatever the config says now
+            meta.line_endings.unwrap_or(LineEndings::Exact).normalize(&mut line);
+            debug!("Counter {}: {:?}", counter, String::from_utf8_lossy(&line));
+            trace!("Searching in index");
+            let places = match index.get(meta.hasher.hash_line(&line)) {
+                Err(e) => {
+                    error!("Failed to get places: {}", e);
+                    return Err(e);
+                },
+                Ok(places) => places.unwrap_or(vec![])
+            };
+            if places.len() > self.probe_limit {
+                // trying every place of a line this common makes the diff
+                // quadratic, so only the ones nearest its position are tried
+                debug!("{} places for this line, trying the {} nearest", places.len(), self.probe_limit);
+                count(Counter::ProbeLimitHits, 1);
+            }
+            let places = nearest_places(&places, counter as isize + offset, self.probe_limit);
+            if places.is_empty() {
+                info!("New node {}: {:?}", meta.node_count, String::from_utf8_lossy(&line));
+                changed = true;
+                if offset != meta.node_count as isize - counter as isize {
+                    info!("Counter {}: offset {}", (counter - 1),
+                          meta.node_count as isize - counter as isize - offset);
+                    new_offset += meta.node_count as isize - counter as isize - offset;
+                    offset = meta.node_count as isize - counter as isize;
+                }
+                meta.node_count += 1;
+            } else {
+                // iterate through the places we have for this line
+                let mut next = None;
+                let mut place = places[0];
+                let mut diff = new_offset + place.node as isize - counter as isize - offset;
+                debug!("Starting place: {:?}", place);
+                debug!("Starting difference: {}", diff);
+                for candidate in places.iter() {
+                    debug!("Considering place {:?}", candidate);
+                    if counter as isize + offset + candidate.offset == candidate.node as isize {
+                        // we've foun a match
+                        next = Some(*candidate);
+                        debug!("Found a match: {:?}", candidate);
+                        break;
+                    } else if (new_offset + candidate.node as isize -
+                               counter as isize - offset).abs() < diff.abs() {
+                        diff = new_offset + candidate.node as isize -
+                            counter as isize - offset;
+                        place = *candidate;
+                        debug!("offset {} new_offset {} place.offset {} place.node {}", offset, new_offset, place.offset, place.node);
+                        debug!("Found a better solution {}: {:?}", diff, place);
+                    }
+                }
+
+                trace!("Finalizing decision");
+                match next {
+                    Some(place) => {
+                        // our best path doesn't need an offset
+                        trace!("Found matching place");
+                        offset += place.offset;
+                    },
+                    None => {
+                        // new next element
+                        trace!("No matching place, creating new one");
+                        changed = true;
+                        debug!("Closest place: {:?}", place);
+                        info!("Counter {}: offset {}", (counter - 1),
+                              place.node as isize - counter as isize - offset);
+                        new_offset += place.node as isize - counter as isize - offset;
+                        offset = place.node as isize - counter as isize;
+                    }
+                }
+            }
+
+            trace!("Incrementing counter");
+            counter += 1;
+        }
+
+        if orig.missing_newline() != meta.no_trailing_newline {
+            info!("Counter {}: newline at end of file {}", counter,
+                  if orig.missing_newline() {"removed"} else {"added"});
+            changed = true;
+        }
+        if counter as isize + offset != indexed_lines as isize {
+            // the file ended before the last line the index has
+            info!("Counter {}: lines removed from the end", counter);
+            changed = true;
+        }
+        if found != loaded {
+            // only a faster start for the next diff, so failing to keep them
+            // doesn't fail this one
+            if let Err(e) = anchors.save(&path.id, index_hash, &found) {
+                warn!("Failed to save diff anchors for {:?}: {}", &path.id, e);
+            }
+        }
+
+        // TODO: actually change the tree to match, write out info
+        Ok(changed)
+    }
+
+    pub fn add_path(&mut self, path: &PathInfo) -> io::Result<()> {
+        let dest_path = self.path.join(&path.id);
+        try!(self.record_dirs(path));
+        if !path.metadata.is_file() {
+            // only create an index for a file
+            return Ok(());
+        }
+        if !self.plan.allow(Op::WriteIndex(&dest_path)) {
+            return Ok(());
+        }
+        if let Some(ref undo) = self.undo {
+            try!(undo.save("logs", &self.path, &path.id));
+        }
+        let _span = Span::start("index", escape_id(&path.id));
+        count(Counter::FilesProcessed, 1);
+
+        let tree_path = try!(self.store.prepare(&path.id));
+        // readers wait until the meta matches the new trees. a packed index
+        // has no directory to lock, and readers only find it once the
+        // manifest points at it
+        let _lock = try!(IndexLock::acquire(&dest_path, LockMode::Exclusive));
+
+        let driver = self.drivers.driver_for(&path.id);
+        debug!("Indexing {:?} with the {} driver", &path.id, driver);
+        let (places, counter, no_trailing_newline, content_hash) = match driver {
+            DiffDriver::Skip => (HashMap::new(), 0, false, 0),
+            DiffDriver::Whole => {
+                let _timer = PhaseTimer::start(Phase::Hash);
+                (HashMap::new(), 0, false, try!(hash_file(&path.path)))
+            },
+            DiffDriver::Lines | DiffDriver::Blocks => try!(self.collect_places(path, driver))
+        };
+        // whole and skipped files have no places, so no tree to keep them in
+        let inline_index = if places.is_empty() || path.metadata.len() <= self.inline_limit {
+            debug!("Keeping index of {:?} inline", &path.id);
+            let mut items: Vec<(u64, Vec<IndexPlace>)> = places.into_iter().collect();
+            items.sort_by(|a, b| a.0.cmp(&b.0));
+            Some(items)
+        } else {
+            try!(self.write_tree(&tree_path, places));
+            None
+        };
+
+        debug!("Saving meta info");
+        trace!("Creating meta object");
+        let meta_info = FileMeta {
+            node_count: counter,
+            no_trailing_newline: no_trailing_newline,
+            hasher: self.hasher,
+            size: path.metadata.len(),
+            mtime: mtime(&path.metadata).0,
+            mtime_nsec: mtime(&path.metadata).1,
+            content_hash: content_hash,
+            // blocks are kept byte for byte
+            line_endings: Some(if driver.has_lines() {self.line_endings} else {LineEndings::Exact}),
+            driver: Some(driver),
+            max_line_length: Some(self.max_line_length),
+            inline_index: inline_index
+        };
+        trace!("Creating json");
+        let data = match json::encode(&meta_info) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Failed to encode index meta for {}: {}", path.id.display(), e)));
+            },
+            Ok(d) => {
+                trace!("Data encoded successfully");
+                d
+            }
+        };
+        let location = try!(self.store.store(&path.id, data.as_ref(), meta_info.inline_index.is_none()));
+        self.record_manifest(&path.id, &meta_info, location)
+    }
+
+    fn collect_places(&mut self, path: &PathInfo, driver: DiffDriver)
+                      -> io::Result<(HashMap<u64, Vec<IndexPlace>>, usize, bool, u64)> {
+        // every place of each line or block of a file, with how many there
+        // are, whether the last line had no terminator and the content hash.
+        // a big file's lines are hashed on every thread the logs have, in
+        // batches read a large chunk at a time
+        let parallel = self.threads > 1 && path.metadata.len() >= PARALLEL_HASH_MIN_BYTES;
+        let threads = if parallel {self.threads} else {1};
+        trace!("Opening original file");
+        let mut orig = match path.get_buffer() {
+            Err(e) => {
+                error!("Failed to open file: {}", e);
+                return Err(e);
+            },
+            Ok(b) => {
+                trace!("Successfully opened file");
+                let buffer = if parallel {BufReader::with_capacity(HASH_READ_BUFFER, b)} else {BufReader::new(b)};
+                // wrap in a unit reader so we can read lines or blocks
+                try!(UnitReader::new(driver, buffer, self.max_line_length))
+            }
+        };
+
+        debug!("Collecting places of original lines");
+        let hash_timer = PhaseTimer::start(Phase::Hash);
+        let endings = if driver.has_lines() {Some(self.line_endings)} else {None};
+        let (places, counter, content_hash) = try!(index_units(&mut orig, self.hasher, endings, threads));
+        drop(hash_timer);
+        Ok((places, counter, orig.missing_newline(), content_hash))
+    }
+
+    fn write_tree(&self, dest_path: &Path, places: HashMap<u64, Vec<IndexPlace>>) -> io::Result<()> {
+        // a file's index as a tree in temp content and places files
+        debug!("Creating tree at {:?}", dest_path);
+
+        trace!("Creating destination buffers");
+        // build the index off to the side, it replaces the old one once it's
+        // complete. a temp file left by an interrupted run is reused and cleared
+        let dest = match fs::OpenOptions::new().read(true).write(true).create(true)
+            .open(try!(temp_path(dest_path.join("content")))) {
+            Err(e) => {
+                error!("Failed to create destination buffer: {}", e);
+                return Err(e);
+            },
+            Ok(b) => {
+                trace!("Successfully created destination buffer");
+                PosBuffer::new(b)
+            }
+        };
+        let places_dest = match fs::OpenOptions::new().read(true).write(true).create(true)
+            .open(try!(temp_path(dest_path.join("places")))) {
+            Err(e) => {
+                error!("Failed to create places buffer: {}", e);
+                return Err(e);
+            },
+            Ok(b) => {
+                trace!("Successfully created places buffer");
+                PosBuffer::new(b)
+            }
+        };
+
+        trace!("Creating index object");
+        // existing indexes keep the width in their header, this only
+        // decides it for new ones
+        let width = self.tree_width.unwrap_or(<LineIndex<fs::File>>::page_width());
+        let mut index: LineIndex<_> = match BufMap::new(dest, places_dest, width) {
+            Err(e) => {
+                error!("Failed to create index: {}", e);
+                return Err(e);
+            },
+            Ok(t) => {
+                trace!("Successfully created index");
+                t
+            }
+        };
+        try!(index.clear());
+        index.tree_mut().set_growth(self.tree_growth);
+
+        trace!("Inserting places into index");
+        let insert_timer = PhaseTimer::start(Phase::Insert);
+        match index.extend(places) {
+            Ok(count) => {
+                trace!("Inserted {} elements", count);
+            },
+            Err(e) => {
+                error!("Failed to insert elements: {}", e);
+                return Err(e);
+            }
+        }
+        trace!("Finished inserting lines");
+        drop(insert_timer);
+        // nothing more goes in, so room grown ahead of time would only
+        // end up in the store
+        try!(index.tree_mut().trim());
+
+        trace!("Flushing index buffers");
+        let (content_buf, places_buf) = index.into_buffers();
+        try!(content_buf.into_inner());
+        try!(places_buf.into_inner());
+        // the store puts them in place alongside the meta
+        Ok(())
+    }
+}
+
+fn nearest_places(places: &[IndexPlace], node: isize, limit: usize) -> &[IndexPlace] {
+    // at most limit places, the ones closest to a node. places are kept in
+    // node order, as that's the order lines are indexed in
+    if places.len() <= limit {
+        return places;
+    }
+    let at = match places.binary_search_by(|place| (place.node as isize).cmp(&node)) {
+        Ok(i) | Err(i) => i
+    };
+    let start = ::std::cmp::min(at.saturating_sub(limit / 2), places.len() - limit);
+    &places[start..start + limit]
+}
+
+fn decode_meta(id: &Path, data: &[u8]) -> io::Result<FileMeta> {
+    let text = match ::std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Meta of {} is not utf-8", id.display())));
+        }
+    };
+    match json::decode(text) {
+        Err(e) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Failed to decode meta of {}: {}", id.display(), e)))
+        },
+        Ok(meta) => Ok(meta)
+    }
+}
+
+fn save_manifest(undo: &Undo) -> io::Result<()> {
+    // the manifest changes along with the logs and isn't big, so undo keeps
+    // all of it
+    let manifest = repo_path("manifest");
+    match fs::metadata(&manifest) {
+        Ok(ref data) if data.is_dir() => undo.save("manifest", &manifest, Path::new("")),
+        _ => Ok(())
+    }
+}
+
+/// Create a repository in the current directory and stage everything in it.
+/// Returns the paths that would have been staged if not for the filter, and why.
+pub fn init(dedup: bool, backend: Backend, encrypt: Option<KeySource>, plan: Plan, errors: &WalkErrors,
+            filter: FileFilter) -> io::Result<Vec<(PathBuf, SkipReason)>> {
+    info!("Creating half2 directories");
+    let repo = Repo::new(".");
+
+    debug!("Creating {:?}", &repo.path);
+    // a pointer to a relocated repository counts as one too
+    if fs::metadata(&repo.path).is_ok() || fs::metadata(repo.root.join(".h2")).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  format!("A repository already exists at {}", repo.root.display())));
+    }
+    if dedup && encrypt.is_some() {
+        // the line store keeps lines in the clear
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "An encrypted repository can't share lines between files"));
+    }
+    // a missing or malformed key stops init before anything is created
+    let sealing = match encrypt {
+        Some(source) => Some((try!(source.load()), source)),
+        None => None
+    };
+    if plan.allow(Op::CreateDir(&repo.path)) {
+        match fs::create_dir_all(&repo.path) {
+            Err(e) => {
+                error!("Failed to create directory {:?}: {}", &repo.path, e);
+                return Err(e);
+            },
+            Ok(_) => {
+                trace!("Directory created");
+            }
+        }
+    }
+    if repo.is_relocated() && plan.allow(Op::WriteFile(&repo.root.join(".h2"))) {
+        try!(repo.write_pointer());
+    }
+
+    debug!("Writing repository header");
+    if plan.allow(Op::WriteFile(&repo.path.join("version"))) {
+        match repo.write_header() {
+            Ok(()) => {
+                trace!("Repository header written");
+            },
+            Err(e) => {
+                error!("Failed to write repository header: {}", e);
+                return Err(e);
+            }
+        }
+    }
+
+    let mut config = String::new();
+    if let Some((ref key, ref source)) = sealing {
+        debug!("Recording encryption in the config");
+        config.push_str(&encryption_config(key, source));
+    }
+    if backend != Backend::default() {
+        // every later command opens the logs with it
+        debug!("Recording the {} backend in the config", backend);
+        config.push_str(&format!("backend = {}\n", backend));
+    }
+    let config_path = repo.path.join("config");
+    if !config.is_empty() && plan.allow(Op::WriteFile(&config_path)) {
+        try!(atomic_write(&config_path, config.as_bytes()));
+    }
+
+    // there's nothing to lock if we didn't create anything
+    let _lock = if plan.is_dry_run() {
+        None
+    } else {
+        trace!("Locking repository");
+        Some(try!(repo.lock(LockMode::Exclusive, false)))
+    };
+
+    trace!("Creating checkout object");
+    let mut checkout = Checkout::default().with_plan(plan).with_errors(errors.clone()).with_filter(filter);
+    debug!("Initializing checkout");
+    match checkout.init() {
+        Ok(()) => {
+            trace!("Checkout creation successful");
+        },
+        Err(e) => {
+            error!("Checkout creation failed: {}", e);
+            return Err(e);
+        }
+    }
+    
+    trace!("Creating Stage object");
+    let mut stage = try!(open_stage()).with_plan(plan);
+    debug!("Initializing stage");
+    match stage.init() {
+        Ok(()) => {
+            trace!("Stage creation successful");
+        },
+        Err(e) => {
+            error!("Stage creation failed: {}", e);
+            return Err(e);
+        }
+    }
+
+    if dedup && plan.allow(Op::CreateDir(&repo.path.join("lines"))) {
+        // revisions keep their full copies as lists of lines in it
+        debug!("Creating shared line store");
+        match LineStore::open(repo.path.join("lines")) {
+            Ok(_) => {
+                trace!("Line store created");
+            },
+            Err(e) => {
+                error!("Failed to create line store: {}", e);
+                return Err(e);
+            }
+        }
+    }
+
+    if plan.allow(Op::CreateDir(&repo.path.join("manifest"))) {
+        debug!("Creating manifest");
+        try!(Manifest::open(repo.path.join("manifest")));
+    }
+
+    trace!("Creating Logs object");
+    let mut logs = try!(open_logs()).with_plan(plan);
+    debug!("Initializing logs");
+    match logs.init() {
+        Ok(()) => {
+            trace!("Logs creation successful");
+        },
+        Err(e) => {
+            error!("Logs creation failed: {}", e);
+            return Err(e);
+        }
+    }
+    
+    trace!("Creating Revisions object");
+    let mut revs = try!(open_revisions()).with_plan(plan);
+    debug!("Initializing revisions");
+    match revs.init() {
+        Ok(()) => {
+            trace!("Revisions creation successful");
+        },
+        Err(e) => {
+            error!("Revisions creation failed: {}", e);
+            return Err(e);
+        }
+    }
+    debug!("Starting on branch {}", DEFAULT_BRANCH);
+    try!(Refs::default().with_plan(plan).set_current_branch(DEFAULT_BRANCH));
+
+    info!("Walking current directory");
+    let ignore = try!(load_ignore(&checkout));
+    match stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore) {
+        Ok(()) => {
+            debug!("Walk successful");
+        },
+        Err(e) => {
+            error!("Walk failed: {}", e);
+            return Err(e);
+        }
+    }
+    let warnings = report_unsettled(&stage);
+
+    try!(record_op_warned(plan, "init", None, vec![".".to_string()], warnings));
+    Ok(ignore.skipped())
+}
+
+/// Normalize a path given on the command line into a checkout-relative id.
+pub fn path_id<T: AsRef<Path>>(path: T) -> io::Result<PathBuf> {
+    // normalize a path given on the command line into a checkout-relative id.
+    // absolute paths, drive letters and all, work if they're under the
+    // current directory
+    let cwd = try!(env::current_dir());
+    let relative = if path.as_ref().is_absolute() {
+        match path.as_ref().relative_from(&cwd) {
+            Some(relative) => relative,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Path {} is not inside the checkout",
+                                                  path.as_ref().display())));
+            }
+        }
+    } else {
+        path.as_ref()
+    };
+    let mut id = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            Component::CurDir => {},
+            Component::Normal(part) => {
+                id.push(part);
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Path {} is not inside the checkout",
+                                                  path.as_ref().display())));
+            }
+        }
+    }
+    Ok(id)
+}
+
+/// Ignore rules for a checkout: the built in list, .h2ignore, and .gitignore
+/// if the repository's config turns it on, along with the size, binary and
+/// nested repository filters from the checkout and the config.
+pub fn load_ignore(checkout: &Checkout) -> io::Result<IgnoreRules> {
+    let config = try!(Repo::new(&checkout.path).config());
+    let rules = try!(IgnoreRules::for_checkout(&checkout.path, DEFAULT_IGNORE.iter(), &config));
+    let filter = checkout.filter.or(try!(FileFilter::from_config(&config)));
+    Ok(rules.with_filter(filter))
+}
+
+/// Ignore rules that only look under the given paths, inside the sparse set
+/// if the config has one. No paths means the whole checkout.
+pub fn scoped_ignore(checkout: &Checkout, paths: &[PathBuf]) -> io::Result<IgnoreRules> {
+    let ignore = try!(load_ignore(checkout));
+    if paths.is_empty() {
+        return Ok(ignore);
+    }
+    let mut ids = vec![];
+    for path in paths.iter() {
+        ids.push(try!(path_id(path)));
+    }
+    Ok(ignore.with_scope(ids))
+}
+
+/// Stage the given paths and update their indexes. Entries under a directory
+/// that can't be read are left out and noted in `errors`, unless it's strict.
+/// Returns the paths that would have been staged if not for the filter, and why.
+pub fn add(paths: &[PathBuf], plan: Plan, errors: &WalkErrors, filter: FileFilter)
+           -> io::Result<Vec<(PathBuf, SkipReason)>> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let ids: Vec<String> = paths.iter().map(|path| escape_id(path)).collect();
+    try!(Hooks::default().with_plan(plan).run("pre-add", None, &ids));
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("add"));
+    try!(save_manifest(&undo));
+
+    let checkout = Checkout::default().with_plan(plan).with_errors(errors.clone()).with_filter(filter);
+    let mut stage = try!(open_stage()).with_plan(plan).with_undo(undo.clone());
+    let mut logs = try!(open_logs()).with_plan(plan).with_undo(undo);
+    let ignore = try!(load_ignore(&checkout));
+
+    for path in paths {
+        let id = try!(path_id(path));
+
+        trace!("Getting file metadata");
+        let metadata = match fs::metadata(checkout.path.join(&id)) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Could not get metadata for {}: {}", id.display(), e);
+                return Err(e);
+            }
+        };
+
+        if ignore.is_ignored(&id, metadata.is_dir()) {
+            info!("Skipping ignored path {:?}", &id);
+            continue;
+        }
+        if try!(ignore.skip(&id, &checkout.path.join(&id), &metadata)) {
+            continue;
+        }
+
+        let is_dir = metadata.is_dir();
+        let info = PathInfo::new(checkout.path.join(&id), id.clone(), metadata);
+
+        debug!("Adding path to stage");
+        try!(stage.add_path(&info));
+        debug!("Creating file index");
+        try!(logs.add_path(&info));
+
+        if is_dir {
+            debug!("Adding directory contents");
+            try!(stage_dir_all(&checkout, &mut logs, &mut stage, id, &ignore));
+        }
+    }
+    try!(stage.flush());
+    try!(record_copy_hashes(&mut stage, &logs));
+    let warnings = report_unsettled(&stage);
+
+    try!(record_op_warned(plan, "add", None, ids, warnings));
+    Ok(ignore.skipped())
+}
+
+/// Walk the changed files under the given paths, offering each hunk to
+/// `choose` and staging only the ones it accepts. Changed words in offered
+/// hunks are highlighted if `highlight` is set.
+pub fn add_interactive<F>(paths: &[PathBuf], mut choose: F, highlight: bool, plan: Plan, errors: &WalkErrors,
+                          filter: FileFilter) -> io::Result<usize>
+    where F: FnMut(&HunkOffer) -> io::Result<HunkChoice> {
+    trace!("Opening repository");
+    let repo = try!(Repo::open_for(".", plan));
+
+    let ids: Vec<String> = paths.iter().map(|path| escape_id(path)).collect();
+    try!(Hooks::default().with_plan(plan).run("pre-add", None, &ids));
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("add"));
+    try!(save_manifest(&undo));
+
+    let checkout = Checkout::default().with_plan(plan).with_errors(errors.clone()).with_filter(filter);
+    let mut stage = try!(open_stage()).with_plan(plan).with_undo(undo.clone());
+    let mut logs = try!(open_logs()).with_plan(plan).with_undo(undo);
+    let ignore = try!(load_ignore(&checkout));
+    let mut prefixes = vec![];
+    for path in paths {
+        prefixes.push(try!(path_id(path)));
+    }
+
+    let mut staged = 0;
+    let mut staged_ids = vec![];
+    for id in try!(checkout_files(&checkout, &ignore)) {
+        if !prefixes.iter().any(|prefix| id.starts_with(prefix)) {
+            continue;
+        }
+        let old = try!(stage.read_path(&id));
+        let new = try!(read_or_empty(checkout.path.join(&id)));
+        // hunks are found between normalized lines, applying them still
+        // copies each line with its own ending
+        let old_lines = logs.line_endings().normalize_lines(split_lines(&old));
+        let new_lines = logs.line_endings().normalize_lines(split_lines(&new));
+        let ops = diff(&old_lines, &new_lines);
+        let mut queue = hunks(&ops, 3);
+        if queue.is_empty() {
+            trace!("No changes to {:?}", &id);
+            continue;
+        }
+
+        queue.reverse();
+        let mut accepted = vec![];
+        let mut quit = false;
+        let mut unsplit = false;
+        while let Some(hunk) = queue.pop() {
+            let offer = HunkOffer {
+                id: &id,
+                lines: render_hunk(&hunk, &old_lines, &new_lines, highlight),
+                unsplit: unsplit
+            };
+            unsplit = false;
+            match try!(choose(&offer)) {
+                HunkChoice::Stage => {
+                    accepted.push(hunk);
+                },
+                HunkChoice::Skip => {
+                    trace!("Skipping hunk");
+                },
+                HunkChoice::Split => {
+                    let mut pieces = split_hunk(&hunk);
+                    unsplit = pieces.len() == 1;
+                    pieces.reverse();
+                    queue.extend(pieces);
+                },
+                HunkChoice::Quit => {
+                    quit = true;
+                    break;
+                }
+            }
+        }
+
+        if !accepted.is_empty() {
+            debug!("Staging {} hunks of {:?}", accepted.len(), &id);
+            let content = apply_hunks(&old, &new, &ops, &accepted);
+            let partial = if content == new {None} else {Some(&content[..])};
+            try!(stage_content(&repo, &checkout, &mut stage, &mut logs, &id, partial, plan));
+            staged += accepted.len();
+            staged_ids.push(escape_id(&id));
+        }
+        if quit {
+            debug!("Stopping early");
+            break;
+        }
+    }
+
+    let warnings = report_unsettled(&stage);
+    try!(record_op_warned(plan, "add", None, staged_ids, warnings));
+    Ok(staged)
+}
+
+/// A hunk `add_interactive` asks about, rendered as diff lines.
+pub struct HunkOffer<'a> {
+    pub id: &'a Path,
+    pub lines: Vec<Vec<u8>>,
+    /// Whether this hunk is back because splitting it left it whole.
+    pub unsplit: bool
+}
+
+/// What to do with an offered hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkChoice {
+    Stage,
+    Skip,
+    Split,
+    Quit
+}
+
+fn stage_content(repo: &Repo, checkout: &Checkout, stage: &mut Stage, logs: &mut Logs, id: &Path,
+                 partial: Option<&[u8]>, plan: Plan) -> io::Result<()> {
+    // stage a file as it is in the checkout, or with partial content. the
+    // stage and logs take files from disk, so that goes through a scratch
+    // file first
+    let source = match partial {
+        None => checkout.path.join(id),
+        Some(content) => {
+            let scratch = repo.path.join("partial");
+            if !plan.allow(Op::WriteFile(&scratch)) {
+                return Ok(());
+            }
+            try!(atomic_write(&scratch, content));
+            scratch
+        }
+    };
+
+    let info = PathInfo::new(source.clone(), id, try!(fs::metadata(&source)));
+    try!(stage.add_path(&info));
+    // queued copies have to finish before the scratch file is reused
+    try!(stage.flush());
+    try!(logs.add_path(&info));
+    try!(record_copy_hashes(stage, logs));
+    if partial.is_some() {
+        try!(fs::remove_file(&source));
+    }
+    Ok(())
+}
+
+/// Apply a unified diff to the checkout, and to the stage too if to_stage is set.
+pub fn apply(patch: &[u8], to_stage: bool, fuzz: usize, plan: Plan) -> io::Result<usize> {
+    trace!("Opening repository");
+    let repo = try!(Repo::open_for(".", plan));
+
+    let files = try!(parse_patch(patch));
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The patch doesn't change any files"));
+    }
+
+    let checkout = Checkout::default().with_plan(plan);
+    let stage = try!(open_stage()).with_plan(plan);
+    // every file is patched in memory first, so a conflict leaves the
+    // checkout and stage as they were
+    let mut results = vec![];
+    for file in files.iter() {
+        let id = match file.new_id.as_ref().or(file.old_id.as_ref()) {
+            Some(id) => id.clone(),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Patch names no file"));
+            }
+        };
+        let in_checkout = match file.old_id {
+            Some(ref old_id) => match fs::metadata(checkout.path.join(old_id)) {
+                Ok(ref data) if data.is_file() => try!(read_or_empty(checkout.path.join(old_id))),
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("{} is not a file in the checkout", escape_id(old_id))));
+                }
+            },
+            None if fs::metadata(checkout.path.join(&id)).is_ok() => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("{} already exists in the checkout", escape_id(&id))));
+            },
+            None => vec![]
+        };
+
+        let apply_to = |old: &[u8]| -> io::Result<Vec<u8>> {
+            let new = match apply_patch(old, file, fuzz) {
+                Ok(new) => new,
+                Err(e) => {
+                    return Err(io::Error::new(e.kind(), format!("{}: {}", escape_id(&id), e)));
+                }
+            };
+            if file.new_id.is_none() && !new.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{}: the patch deletes the file but leaves lines behind",
+                                                  escape_id(&id))));
+            }
+            Ok(new)
+        };
+        let patched = try!(apply_to(&in_checkout));
+        let staged = if to_stage {
+            let old = match file.old_id {
+                Some(ref old_id) => try!(stage.read_path(old_id)),
+                None => vec![]
+            };
+            Some(try!(apply_to(&old)))
+        } else {
+            None
+        };
+        debug!("Patched {:?}", &id);
+        results.push((file, patched, staged));
+    }
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("apply"));
+    try!(save_manifest(&undo));
+    let mut stage = stage.with_undo(undo.clone());
+    let mut logs = try!(open_logs()).with_plan(plan).with_undo(undo.clone());
+    let mut ids = vec![];
+
+    for &(file, ref patched, ref staged) in results.iter() {
+        // a rename or deletion takes the old path away first
+        if let Some(ref old_id) = file.old_id {
+            if file.new_id.as_ref() != Some(old_id) {
+                try!(undo.save("checkout", &checkout.path, old_id));
+                try!(remove_path(&checkout.path.join(old_id), plan));
+                if staged.is_some() {
+                    try!(undo.save("stage", &stage.path, old_id));
+                    try!(remove_path(&stage.path.join(old_id), plan));
+                    try!(undo.save("logs", &logs.path, old_id));
+                    try!(remove_path(&logs.path.join(old_id), plan));
+                    try!(logs.forget(old_id));
+                }
+                ids.push(escape_id(old_id));
+            }
+        }
+
+        let new_id = match file.new_id {
+            Some(ref new_id) => new_id,
+            None => {
+                continue;
+            }
+        };
+        let path = checkout.path.join(new_id);
+        try!(undo.save("checkout", &checkout.path, new_id));
+        if plan.allow(Op::WriteFile(&path)) {
+            debug!("Writing {:?}", &path);
+            try!(fs::create_dir_all(path.parent().unwrap()));
+            try!(atomic_write(&path, patched));
+        }
+        if let Some(ref staged) = *staged {
+            try!(stage_content(&repo, &checkout, &mut stage, &mut logs, new_id, Some(staged), plan));
+        }
+        ids.push(escape_id(new_id));
+    }
+
+    try!(record_op(plan, "apply", None, ids));
+    Ok(results.len())
+}
+
+/// Stop tracking the given paths, deleting the working copies too unless cached is set.
+pub fn remove(paths: &[PathBuf], cached: bool, plan: Plan) -> io::Result<()> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("rm"));
+    try!(save_manifest(&undo));
+
+    let checkout = Checkout::default();
+    let stage = Stage::default();
+    let logs = try!(open_logs()).with_plan(plan);
+
+    for path in paths {
+        let id = try!(path_id(path));
+        if id == Path::new("") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Refusing to remove the whole checkout"));
+        }
+
+        let staged = stage.path.join(&id);
+        if fs::metadata(&staged).is_err() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{} is not tracked", id.display())));
+        }
+
+        // the snapshot goes first, a log without one is only an orphan for gc
+        debug!("Removing {:?} from the stage", &id);
+        try!(undo.save("stage", &stage.path, &id));
+        try!(remove_path(&staged, plan));
+        debug!("Removing log of {:?}", &id);
+        try!(undo.save("logs", &logs.path, &id));
+        try!(remove_path(&logs.path.join(&id), plan));
+        try!(logs.forget(&id));
+        if !cached {
+            debug!("Removing working copy of {:?}", &id);
+            try!(undo.save("checkout", &checkout.path, &id));
+            try!(remove_path(&checkout.path.join(&id), plan));
+        }
+        info!("Removed {:?}", &id);
+    }
+
+    record_op(plan, "rm", None, paths.iter().map(|path| escape_id(path)).collect())
+}
+
+fn record_op(plan: Plan, op: &str, rev: Option<RevisionId>, paths: Vec<String>) -> io::Result<()> {
+    record_op_warned(plan, op, rev, paths, vec![])
+}
+
+fn record_op_warned(plan: Plan, op: &str, rev: Option<RevisionId>, paths: Vec<String>, warnings: Vec<String>)
+                    -> io::Result<()> {
+    // nothing happened in a dry run, so there's nothing to record
+    if plan.is_dry_run() {
+        return Ok(());
+    }
+    OpLog::default().append(&OpRecord::new(op, rev, paths).with_warnings(warnings))
+}
+
+fn record_copy_hashes(stage: &mut Stage, logs: &Logs) -> io::Result<()> {
+    // checked copies go in the manifest once their indexes are there, as
+    // indexing a file resets its entry
+    for (id, hash) in stage.take_copy_hashes() {
+        try!(logs.record_stage_hash(&id, hash));
+    }
+    Ok(())
+}
+
+fn report_unsettled(stage: &Stage) -> Vec<String> {
+    // files that kept changing while they were staged, warned about now and
+    // kept for the operation log
+    let mut warnings = vec![];
+    let mut stderr = io::stderr();
+    for id in stage.unsettled() {
+        let warning = format!("{} changed while it was staged, the staged copy may be inconsistent",
+                              escape_id(id));
+        let _ = writeln!(stderr, "warning: {}", warning);
+        warnings.push(warning);
+    }
+    warnings
+}
+
+fn remove_path(path: &Path, plan: Plan) -> io::Result<()> {
+    // remove a file or directory tree, missing ones are already gone
+    let metadata = match fs::symlink_metadata(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            trace!("{:?} does not exist", path);
+            return Ok(());
+        },
+        Err(e) => {
+            error!("Failed to get metadata for {}: {}", path.display(), e);
+            return Err(e);
+        },
+        Ok(data) => data
+    };
+    if !plan.allow(Op::Remove(path)) {
+        return Ok(());
+    }
+    let result = if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Err(e) => {
+            error!("Failed to remove {}: {}", path.display(), e);
+            Err(e)
+        },
+        Ok(()) => {
+            trace!("Removed {:?}", path);
+            Ok(())
+        }
+    }
+}
+
+/// Open the stage of the current repository, chunking big files and copying in
+/// parallel if its config asks for it.
+pub fn open_stage() -> io::Result<Stage> {
+    let repo = Repo::new(".");
+    configure_stage(&repo, &try!(repo.config()))
+}
+
+/// Open the revisions of the current repository, with the key its snapshots
+/// are sealed with if it's encrypted.
+pub fn open_revisions() -> io::Result<Revisions> {
+    let repo = Repo::new(".");
+    configure_revisions(&repo, &try!(repo.config()))
+}
+
+/// Open the logs of the current repository, with its manifest if it has one.
+pub fn open_logs() -> io::Result<Logs> {
+    let repo = Repo::new(".");
+    configure_logs(&repo, &try!(repo.config()))
+}
+
+fn open_undo(plan: Plan) -> io::Result<Undo> {
+    // the undo information of the current repository, sealed if it's encrypted
+    let key = try!(load_key(&try!(Repo::new(".").config())));
+    Ok(Undo::default().with_plan(plan).with_key(key))
+}
+
+fn open_stash(plan: Plan) -> io::Result<Stash> {
+    // the stash of the current repository, sealed if it's encrypted
+    let key = try!(load_key(&try!(Repo::new(".").config())));
+    Ok(Stash::default().with_plan(plan).with_key(key))
+}
+
+/// Commit the stage as a new revision, with a message saying why and the
+/// author from the environment or config. Returns the revision and any
+/// warnings, like a post-commit hook that failed after it was committed.
+pub fn commit(message: Option<String>, plan: Plan) -> io::Result<(RevisionId, Vec<String>)> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+    commit_stage(message, plan, false)
+}
+
+fn commit_stage(message: Option<String>, plan: Plan, auto: bool) -> io::Result<(RevisionId, Vec<String>)> {
+    // the stage as a new revision on the current branch, marked as an
+    // automatic snapshot if autosnap is committing it
+
+    let stage = try!(open_stage());
+    let info = CommitInfo::new(message, &try!(Repo::new(".").config()));
+    let mut revs = try!(open_revisions()).with_plan(plan);
+    let refs = Refs::default().with_plan(plan);
+    let branch = try!(refs.current_branch());
+    let merged = try!(refs.merge_head());
+    let hooks = Hooks::default().with_plan(plan);
+    let next = try!(revs.next_id());
+    try!(hooks.run("pre-commit", Some(next), &[]));
+
+    // undoing a commit only needs the revision id from the operation log
+    try!(Undo::default().with_plan(plan).begin("commit"));
+
+    let tree_hash = try!(try!(open_logs()).tree_hash());
+    let committed = if auto {
+        revs.commit_auto(&stage, tree_hash, &info)
+    } else {
+        revs.commit_merge(&stage, tree_hash, merged, &info)
+    };
+    match committed {
+        Ok(id) => {
+            debug!("Committed revision {}", id);
+            if let Some(ref branch) = branch {
+                try!(refs.set_branch(branch, id));
+            }
+            if merged.is_some() {
+                try!(refs.clear_merge_head());
+            }
+            let warnings: Vec<String> = hooks.run_post("post-commit", Some(id), &[]).into_iter().collect();
+            try!(record_op_warned(plan, "commit", Some(id), vec![], warnings.clone()));
+            Ok((id, warnings))
+        },
+        Err(e) => {
+            error!("Failed to commit: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Commit every change to tracked files as an automatic snapshot if the
+/// policy says one is due, returning the revision committed and its warnings
+/// as `commit` does. Untracked files are left alone, and nothing is committed
+/// while a merge is in progress.
+pub fn autosnap(policy: &AutosnapPolicy, plan: Plan, errors: &WalkErrors, filter: FileFilter)
+                -> io::Result<Option<(RevisionId, Vec<String>)>> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    if let Some(rev) = try!(Refs::default().merge_head()) {
+        debug!("A merge of revision {} is in progress, not snapshotting", rev);
+        return Ok(None);
+    }
+    let changes = try!(status(&[], errors, filter));
+    let modified: Vec<PathBuf> = changes.iter().filter(|status| status.change == FileChange::Modified)
+        .map(|status| status.id.clone()).collect();
+    let deleted: Vec<PathBuf> = changes.iter().filter(|status| status.change == FileChange::Deleted)
+        .map(|status| status.id.clone()).collect();
+    // what's staged but not committed is snapshotted along with the rest
+    let staged = match try!(stage_matches_head()) {
+        Some((_, matches)) => !matches,
+        None => false
+    };
+
+    let revs = try!(open_revisions());
+    let last = match try!(revs.head()) {
+        Some(head) => try!(revs.meta(head)).time,
+        None => None
+    };
+    let changed = modified.len() + deleted.len() + if staged {1} else {0};
+    if !policy.is_due(changed, last, now()) {
+        return Ok(None);
+    }
+
+    info!("Snapshotting {} modified and {} deleted files", modified.len(), deleted.len());
+    if !modified.is_empty() {
+        try!(add(&modified, plan, errors, filter));
+    }
+    if !deleted.is_empty() {
+        try!(remove(&deleted, true, plan));
+    }
+    commit_stage(None, plan, true).map(Some)
+}
+
+/// Name a revision, head if none is given, returning the revision tagged.
+pub fn tag(name: &str, rev: Option<&str>, plan: Plan) -> io::Result<RevisionId> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let revs = try!(open_revisions());
+    let refs = Refs::default().with_plan(plan);
+    let rev = try!(refs.resolve(&revs, rev.unwrap_or("HEAD")));
+    // make sure it's there before naming it
+    try!(revs.meta(rev));
+    try!(refs.set_tag(name, rev));
+    try!(record_op(plan, "tag", Some(rev), vec![name.to_string()]));
+    Ok(rev)
+}
+
+/// Start a branch at a revision, head if none is given, returning the
+/// revision it starts at. Switching to it is left to `switch`.
+pub fn branch(name: &str, rev: Option<&str>, plan: Plan) -> io::Result<RevisionId> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let revs = try!(open_revisions());
+    let refs = Refs::default().with_plan(plan);
+    if try!(refs.branch(name)).is_some() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  format!("A branch named {} already exists", name)));
+    }
+    let rev = try!(refs.resolve(&revs, rev.unwrap_or("HEAD")));
+    try!(revs.meta(rev));
+    try!(refs.set_branch(name, rev));
+    try!(record_op(plan, "branch", Some(rev), vec![name.to_string()]));
+    Ok(rev)
+}
+
+/// Every branch with the revision it's at, the one commits go to marked
+/// with `*` even before anything is committed to it.
+pub fn list_branches() -> io::Result<Vec<String>> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let refs = Refs::default();
+    let current = try!(refs.current_branch());
+    let mut branches: Vec<(String, Option<RevisionId>)> = try!(refs.branches()).into_iter()
+        .map(|(name, rev)| (name, Some(rev))).collect();
+    if let Some(ref current) = current {
+        if !branches.iter().any(|&(ref name, _)| name == current) {
+            branches.push((current.clone(), None));
+            branches.sort();
+        }
+    }
+    Ok(branches.into_iter().map(|(name, rev)| {
+        let mark = if current.as_ref() == Some(&name) {"*"} else {" "};
+        match rev {
+            Some(rev) => format!("{} {} {}", mark, name, rev),
+            None => format!("{} {} -", mark, name)
+        }
+    }).collect())
+}
+
+/// Move to another branch: the checkout and stage are set to the revision
+/// it's at and commits go to it from then on. Changes that aren't committed
+/// would be lost, so there can't be any, and files that aren't tracked are
+/// never overwritten. Returns the revision switched to.
+pub fn switch(name: &str, plan: Plan) -> io::Result<RevisionId> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let revs = try!(open_revisions()).with_plan(plan);
+    let refs = Refs::default().with_plan(plan);
+    let rev = match try!(refs.branch(name)) {
+        Some(rev) => rev,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No branch named {:?}", name)));
+        }
+    };
+    let head = match try!(revs.head()) {
+        Some(head) => head,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::Other, "Nothing has been committed to switch away from"));
+        }
+    };
+
+    try!(check_clean(&refs, head));
+
+    let checkout = Checkout::default().with_plan(plan);
+    let mut old_files = try!(stage_files(&Stage::default().path));
+    old_files.sort();
+    let new_files = try!(revs.files(rev));
+    try!(check_untracked(&checkout, new_files.iter().filter(|id| !old_files.contains(id))));
+    let mut old_dirs = try!(stage_dirs(&Stage::default().path));
+    old_dirs.sort();
+    let new_dirs = try!(revs.dirs(rev));
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("switch"));
+    try!(save_manifest(&undo));
+    try!(undo.save("refs", &repo_path("refs"), Path::new("HEAD")));
+    try!(undo.save("revs", &repo_path("revs"), Path::new("HEAD")));
+    let mut stage = try!(open_stage()).with_plan(plan).with_undo(undo.clone());
+    let mut logs = try!(open_logs()).with_plan(plan).with_undo(undo.clone());
+
+    // files the branch doesn't have go the way rm takes them
+    for id in old_files.iter().filter(|id| !new_files.contains(id)) {
+        try!(drop_tracked(id, &checkout, &stage, &logs, &undo, plan));
+    }
+
+    // the rest are written as the branch has them and staged again, unless
+    // they're the same on both
+    let mut written = vec![];
+    for id in new_files {
+        let data = try!(revs.read_path(rev, &id));
+        if old_files.contains(&id) && try!(revs.read_path(head, &id)) == data {
+            trace!("{:?} is the same on both", &id);
+            continue;
+        }
+        debug!("Writing {:?} as revision {} has it", &id, rev);
+        if let Some(info) = try!(write_checkout(&id, &data, &checkout, &undo, plan)) {
+            written.push(info);
+        }
+    }
+    // directories are made whether or not anything is in them, and staged
+    // along with the files
+    for id in new_dirs.iter().filter(|id| old_dirs.binary_search(id).is_err()) {
+        let path = checkout.path.join(id);
+        if !plan.allow(Op::CreateDir(&path)) {
+            continue;
+        }
+        debug!("Making directory {:?} as revision {} has it", id, rev);
+        try!(fs::create_dir_all(&path));
+        let metadata = try!(fs::metadata(&path));
+        written.push(PathInfo::new(path, id.clone(), metadata));
+    }
+    try!(stage_written(&written, &mut stage, &mut logs));
+    // ones the branch doesn't have go once they're empty, deepest first.
+    // anything left in them that isn't tracked keeps them in the checkout
+    for id in old_dirs.iter().rev().filter(|id| new_dirs.binary_search(id).is_err()) {
+        try!(drop_dir(id, &checkout, &stage, &logs, plan));
+    }
+
+    // head moves last, so a switch that fails part way can be run again
+    try!(revs.set_head(rev));
+    try!(refs.set_current_branch(name));
+    info!("Switched to branch {} at revision {}", name, rev);
+    let warnings = report_unsettled(&stage);
+    try!(record_op_warned(plan, "switch", Some(rev), vec![name.to_string()], warnings));
+    Ok(rev)
+}
+
+fn check_clean(refs: &Refs, head: RevisionId) -> io::Result<()> {
+    // switching and merging rewrite the stage and checkout, so anything
+    // that isn't committed would be lost
+    if let Some(rev) = try!(refs.merge_head()) {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("A merge of revision {} is in progress, commit or undo it first", rev)));
+    }
+    if let Some((_, false)) = try!(stage_matches_head()) {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("The stage has changes that aren't committed to revision {}", head)));
+    }
+    for change in try!(status(&[], &WalkErrors::default(), FileFilter::default())) {
+        match change.change {
+            FileChange::Modified | FileChange::Deleted => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("{} has changes that aren't staged", escape_id(&change.id))));
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_untracked<'a, I: Iterator<Item=&'a PathBuf>>(checkout: &Checkout, ids: I) -> io::Result<()> {
+    // files that would be written where there's one that isn't tracked
+    for id in ids {
+        if fs::symlink_metadata(checkout.path.join(id)).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} isn't tracked and would be overwritten", escape_id(id))));
+        }
+    }
+    Ok(())
+}
+
+fn drop_tracked(id: &Path, checkout: &Checkout, stage: &Stage, logs: &Logs, undo: &Undo, plan: Plan)
+                -> io::Result<()> {
+    // take a file out of the stage, its index and the checkout the way rm does
+    debug!("Removing {:?}", id);
+    try!(undo.save("stage", &stage.path, id));
+    try!(remove_path(&stage.path.join(id), plan));
+    try!(undo.save("logs", &logs.path, id));
+    try!(remove_path(&logs.path.join(id), plan));
+    try!(logs.forget(id));
+    try!(undo.save("checkout", &checkout.path, id));
+    remove_path(&checkout.path.join(id), plan)
+}
+
+fn drop_dir(id: &Path, checkout: &Checkout, stage: &Stage, logs: &Logs, plan: Plan) -> io::Result<()> {
+    // take an empty directory out of the stage, the manifest and the checkout
+    for path in [stage.path.join(id), checkout.path.join(id)].iter() {
+        if !plan.allow(Op::Remove(path)) {
+            continue;
+        }
+        match fs::remove_dir(path) {
+            Err(e) => {
+                debug!("Leaving directory {:?}: {}", path, e);
+            },
+            Ok(()) => {
+                trace!("Removed directory {:?}", path);
+            }
+        }
+    }
+    logs.forget(id)
+}
+
+fn write_checkout(id: &Path, data: &[u8], checkout: &Checkout, undo: &Undo, plan: Plan)
+                  -> io::Result<Option<PathInfo>> {
+    // a file written into the checkout, ready to stage. none in a dry run
+    let path = checkout.path.join(id);
+    if !plan.allow(Op::WriteFile(&path)) {
+        return Ok(None);
+    }
+    try!(undo.save("checkout", &checkout.path, id));
+    if let Some(parent) = path.parent() {
+        try!(fs::create_dir_all(parent));
+    }
+    try!(atomic_write(&path, data));
+    let metadata = try!(fs::metadata(&path));
+    Ok(Some(PathInfo::new(path, id.to_path_buf(), metadata)))
+}
+
+fn stage_written(written: &[PathInfo], stage: &mut Stage, logs: &mut Logs) -> io::Result<()> {
+    for info in written.iter() {
+        try!(stage.add_path(info));
+    }
+    try!(stage.flush());
+    debug!("Updating {} file indexes", written.len());
+    for info in written.iter() {
+        try!(logs.add_path(info));
+    }
+    record_copy_hashes(stage, logs)
+}
+
+/// What merging a revision did: nothing if it was already merged, otherwise
+/// the revision committed for it, or the files left with conflicts to be
+/// resolved and committed by hand.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeOutcome {
+    pub base: Option<RevisionId>,
+    pub committed: Option<RevisionId>,
+    pub conflicts: Vec<PathBuf>,
+    pub up_to_date: bool
+}
+
+fn read_at(revs: &Revisions, rev: Option<RevisionId>, files: &[PathBuf], id: &Path) -> io::Result<Option<Vec<u8>>> {
+    match rev {
+        Some(rev) if files.iter().any(|file| file.as_path() == id) => revs.read_path(rev, id).map(Some),
+        _ => Ok(None)
+    }
+}
+
+/// Merge a branch, or any revision, into head: every file either side
+/// changed since the revision they have in common is merged line by line,
+/// and the result is committed with both as parents. Files both changed in
+/// the same place are left in the checkout with conflict markers instead,
+/// and the merge is committed by the next commit once they're resolved.
+pub fn merge(name: &str, plan: Plan) -> io::Result<MergeOutcome> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let revs = try!(open_revisions());
+    let refs = Refs::default().with_plan(plan);
+    let head = match try!(revs.head()) {
+        Some(head) => head,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::Other, "Nothing has been committed to merge into"));
+        }
+    };
+    let theirs = try!(refs.resolve(&revs, name));
+    try!(revs.meta(theirs));
+    try!(check_clean(&refs, head));
+
+    let mut parents = HashMap::new();
+    for id in try!(revs.list()) {
+        parents.insert(id, try!(revs.meta(id)).parents());
+    }
+    let base = merge_base(&parents, head, theirs);
+    debug!("Merging revision {} into {} from {:?}", theirs, head, base);
+    if base == Some(theirs) {
+        info!("Revision {} is already merged", theirs);
+        return Ok(MergeOutcome {
+            base: base,
+            up_to_date: true,
+            ..MergeOutcome::default()
+        });
+    }
+
+    let base_files = match base {
+        Some(base) => try!(revs.files(base)),
+        None => vec![]
+    };
+    let ours_files = try!(revs.files(head));
+    let theirs_files = try!(revs.files(theirs));
+    let mut ids: Vec<PathBuf> = base_files.iter().chain(ours_files.iter()).chain(theirs_files.iter())
+        .cloned().collect();
+    ids.sort();
+    ids.dedup();
+
+    let logs = try!(open_logs());
+    let ours_label = try!(refs.current_branch()).unwrap_or("HEAD".to_string());
+    let mut writes = vec![];
+    let mut deletes = vec![];
+    let mut conflicts = vec![];
+    for id in ids {
+        let old_data = try!(read_at(&revs, base, &base_files, &id));
+        let ours_data = try!(read_at(&revs, Some(head), &ours_files, &id));
+        let theirs_data = try!(read_at(&revs, Some(theirs), &theirs_files, &id));
+        if ours_data == theirs_data || old_data == theirs_data {
+            continue;
+        }
+        if old_data == ours_data {
+            trace!("Taking {:?} from revision {}", &id, theirs);
+            match theirs_data {
+                Some(data) => writes.push((id, data, true)),
+                None => deletes.push(id)
+            }
+            continue;
+        }
+        // both sides changed it
+        match (ours_data, theirs_data) {
+            (Some(ours_data), Some(theirs_data)) if logs.driver_for(&id).has_lines() => {
+                let merged = merge3(&old_data.unwrap_or(vec![]), &ours_data, &theirs_data, (&ours_label[..], name));
+                debug!("Merged {:?} with {} conflicts", &id, merged.conflicts);
+                let clean = merged.conflicts == 0;
+                if !clean {
+                    conflicts.push(id.clone());
+                }
+                writes.push((id, merged.data, clean));
+            },
+            (None, Some(theirs_data)) => {
+                // deleted here and changed there, it's back for deciding
+                conflicts.push(id.clone());
+                writes.push((id, theirs_data, false));
+            },
+            _ => {
+                // ours stays for resolving by hand
+                conflicts.push(id);
+            }
+        }
+    }
+    let checkout = Checkout::default().with_plan(plan);
+    try!(check_untracked(&checkout, writes.iter().map(|&(ref id, _, _)| id)
+                         .filter(|id| !ours_files.contains(*id))));
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("merge"));
+    try!(save_manifest(&undo));
+    try!(undo.save("refs", &repo_path("refs"), Path::new("MERGE_HEAD")));
+    let mut stage = try!(open_stage()).with_plan(plan).with_undo(undo.clone());
+    let mut logs = logs.with_plan(plan).with_undo(undo.clone());
+    for id in deletes.iter() {
+        try!(drop_tracked(id, &checkout, &stage, &logs, &undo, plan));
+    }
+    // conflicts only go in the checkout, staging one is marking it resolved
+    let mut written = vec![];
+    for &(ref id, ref data, clean) in writes.iter() {
+        match try!(write_checkout(id, data, &checkout, &undo, plan)) {
+            Some(info) if clean => written.push(info),
+            _ => {}
+        }
+    }
+    try!(stage_written(&written, &mut stage, &mut logs));
+    try!(refs.set_merge_head(theirs));
+    let warnings = report_unsettled(&stage);
+    try!(record_op_warned(plan, "merge", Some(theirs), vec![name.to_string()], warnings));
+
+    let committed = if conflicts.is_empty() {
+        // a failed post-commit hook is left in the operation log
+        Some(try!(commit(Some(format!("Merge {} (revision {})", name, theirs)), plan)).0)
+    } else {
+        info!("Merge of revision {} has {} conflicts", theirs, conflicts.len());
+        None
+    };
+    Ok(MergeOutcome {
+        base: base,
+        committed: committed,
+        conflicts: conflicts,
+        up_to_date: false
+    })
+}
+
+/// Put every change to a tracked file aside as a patch against head, on top
+/// of the stash, and set the checkout and stage back to head. Files added
+/// since are taken out along with their changes, untracked ones are left
+/// alone. Returns the stash entry and how many files it changes.
+pub fn stash(plan: Plan) -> io::Result<(usize, usize)> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let revs = try!(open_revisions());
+    let refs = Refs::default();
+    let head = match try!(revs.head()) {
+        Some(head) => head,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::Other, "Nothing has been committed to stash changes against"));
+        }
+    };
+    if let Some(rev) = try!(refs.merge_head()) {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("A merge of revision {} is in progress, commit or undo it first", rev)));
+    }
+
+    let checkout = Checkout::default().with_plan(plan);
+    let stage = try!(open_stage()).with_plan(plan);
+    let head_files = try!(revs.files(head));
+    let mut ids = try!(stage_files(&stage.path));
+    ids.extend(head_files.iter().cloned());
+    ids.sort();
+    ids.dedup();
+
+    let mut patch = vec![];
+    let mut changed = vec![];
+    for id in ids {
+        let old = try!(read_at(&revs, Some(head), &head_files, &id));
+        let path = checkout.path.join(&id);
+        let new = match fs::metadata(&path) {
+            Ok(ref data) if data.is_file() => Some(try!(read_or_empty(&path))),
+            _ => None
+        };
+        let staged = match fs::metadata(stage.path.join(&id)) {
+            Ok(_) => Some(try!(stage.read_path(&id))),
+            Err(_) => None
+        };
+        if new != old {
+            debug!("Stashing changes to {:?}", &id);
+            patch.extend(write_patch(old.as_ref().map(|_| id.as_path()), new.as_ref().map(|_| id.as_path()),
+                                     old.as_ref().map_or(&[][..], |old| &old[..]),
+                                     new.as_ref().map_or(&[][..], |new| &new[..])));
+        }
+        if new != old || staged != old {
+            changed.push((id, old));
+        }
+    }
+    if patch.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "There are no changes to stash"));
+    }
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("stash"));
+    try!(save_manifest(&undo));
+    let stash = try!(open_stash(plan));
+    let entry = try!(stash.list()).last().map_or(1, |last| last + 1);
+    try!(undo.save("stash", &repo_path("stash"), Path::new(&format!("{}", entry))));
+    try!(stash.push(&patch));
+
+    // what head has goes back, what it doesn't goes away
+    let mut stage = stage.with_undo(undo.clone());
+    let mut logs = try!(open_logs()).with_plan(plan).with_undo(undo.clone());
+    let mut written = vec![];
+    for &(ref id, ref old) in changed.iter() {
+        match *old {
+            Some(ref data) => {
+                if let Some(info) = try!(write_checkout(id, data, &checkout, &undo, plan)) {
+                    written.push(info);
+                }
+            },
+            None => {
+                try!(drop_tracked(id, &checkout, &stage, &logs, &undo, plan));
+            }
+        }
+    }
+    try!(stage_written(&written, &mut stage, &mut logs));
+
+    info!("Stashed changes to {} files as entry {}", changed.len(), entry);
+    try!(record_op(plan, "stash", Some(head), changed.iter().map(|&(ref id, _)| escape_id(id)).collect()));
+    Ok((entry, changed.len()))
+}
+
+/// Apply the newest stash entry to the checkout and drop it, returning the
+/// entry and how many files it changed. A change that doesn't apply anymore
+/// leaves everything, the entry included, as it was. What was staged isn't
+/// staged again, files the entry added come back untracked.
+pub fn stash_pop(plan: Plan) -> io::Result<(usize, usize)> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let stash = try!(open_stash(plan));
+    let entry = match try!(stash.list()).pop() {
+        Some(entry) => entry,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Nothing is stashed"));
+        }
+    };
+    let patch = try!(stash.read(entry));
+    let count = try!(apply(&patch, false, 0, plan));
+    // apply started the undo information, undoing it brings the entry back too
+    try!(try!(open_undo(plan)).save("stash", &repo_path("stash"), Path::new(&format!("{}", entry))));
+    try!(stash.remove(entry));
+    info!("Applied stash entry {} to {} files", entry, count);
+    Ok((entry, count))
+}
+
+/// Every revision, newest first and each after the ones committed on top of
+/// it, with when it was committed, its revision hash, `[auto]` if autosnap
+/// committed it, HEAD and any tags naming it, and its author and message.
+/// Drawn as a graph of its history if asked for.
+pub fn log(graph: bool) -> io::Result<Vec<String>> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let revs = try!(open_revisions());
+    let mut names: HashMap<RevisionId, Vec<String>> = HashMap::new();
+    if let Some(head) = try!(revs.head()) {
+        names.entry(head).or_insert(vec![]).push("HEAD".to_string());
+    }
+    for (name, rev) in try!(Refs::default().tags()) {
+        names.entry(rev).or_insert(vec![]).push(format!("tag: {}", name));
+    }
+    let mut parents: HashMap<RevisionId, Vec<RevisionId>> = HashMap::new();
+    let mut metas = HashMap::new();
+    for id in try!(revs.list()) {
+        let meta = try!(revs.meta(id));
+        parents.insert(id, meta.parents());
+        metas.insert(id, meta);
+    }
+
+    let order = try!(topo_order(&parents));
+    debug!("Logging {} revisions", order.len());
+    let lines = if graph {
+        render_graph(&order, &parents)
+    } else {
+        order.iter().map(|&id| (String::new(), Some(id))).collect()
+    };
+    Ok(lines.into_iter().map(|(drawn, rev)| {
+        let id = match rev {
+            Some(id) => id,
+            None => {
+                return drawn;
+            }
+        };
+        let meta = &metas[&id];
+        let mut text = match meta.time {
+            Some(time) => format!("{} {}", id, time),
+            None => format!("{} -", id)
+        };
+        if let Some(ref hash) = meta.hash {
+            text.push_str(&format!(" {}", hash));
+        }
+        if meta.is_auto() {
+            text.push_str(" [auto]");
+        }
+        if let Some(names) = names.get(&id) {
+            text.push_str(&format!(" ({})", names.join(", ")));
+        }
+        if let Some(summary) = meta.summary() {
+            text.push_str(&format!(" - {}", summary));
+        }
+        if graph {format!("{} {}", drawn, text)} else {text}
+    }).collect())
+}
+
+/// Send another repository the revisions it's missing and move its head
+/// to match. Its history has to be the start of this one's.
+pub fn push(remote: &Transport, plan: Plan) -> io::Result<SyncStats> {
+    let local = try!(DirTransport::open("."));
+    let stats = try!(sync(&local, remote, plan));
+    try!(record_op(plan, "push", stats.revisions.last().cloned(), vec![remote.describe()]));
+    Ok(stats)
+}
+
+/// Fetch the revisions another repository has that this one doesn't and
+/// move head to match. The stage and checkout are left as they are.
+pub fn pull(remote: &Transport, plan: Plan) -> io::Result<SyncStats> {
+    let local = try!(DirTransport::open("."));
+    let stats = try!(sync(remote, &local, plan));
+    try!(record_op(plan, "pull", stats.revisions.last().cloned(), vec![remote.describe()]));
+    Ok(stats)
+}
+
+/// Replay the first-parent history of a git repository into a new
+/// repository in the current directory, one revision per commit, leaving the
+/// checkout and stage at the last one. Returns each revision with its commit.
+pub fn import_git(source: &Path, plan: Plan, errors: &WalkErrors, filter: FileFilter)
+                  -> io::Result<Vec<(RevisionId, String)>> {
+    let commits = try!(git_commits(source));
+    // the checkout becomes each commit in turn, so it can't hold anything else
+    if try!(fs::read_dir(".")).next().is_some() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  "Importing from git needs an empty directory"));
+    }
+    if plan.is_dry_run() {
+        return Ok(commits.into_iter().enumerate().map(|(i, hash)| (i as RevisionId + 1, hash)).collect());
+    }
+
+    try!(init(false, Backend::default(), None, plan, errors, filter));
+    let _lock = try!(Repo::new(".").lock(LockMode::Exclusive, false));
+    let mut imported = vec![];
+    let mut parent: Option<String> = None;
+    for hash in commits {
+        debug!("Importing commit {}", hash);
+        let mut written = vec![];
+        let mut blobs = vec![];
+        let mut deleted = vec![];
+        for change in try!(git_changes(source, parent.as_ref().map(|parent| &parent[..]), &hash)) {
+            match change {
+                GitChange::Write(id, blob) => {
+                    written.push(id);
+                    blobs.push(blob);
+                },
+                GitChange::Delete(id) => {
+                    deleted.push(id);
+                }
+            }
+        }
+
+        // deletions first, a file may have been replaced by a directory.
+        // files add left out were never staged, so they only leave the checkout
+        let stage = Stage::default();
+        let (tracked, untracked): (Vec<PathBuf>, Vec<PathBuf>) = deleted.into_iter()
+            .partition(|id| fs::metadata(stage.path.join(id)).is_ok());
+        for id in untracked.iter() {
+            try!(remove_path(id, plan));
+        }
+        if !tracked.is_empty() {
+            try!(remove(&tracked, false, plan));
+        }
+        for (id, data) in written.iter().zip(try!(git_blobs(source, &blobs))) {
+            if let Some(dir) = id.parent() {
+                try!(fs::create_dir_all(dir));
+            }
+            try!(atomic_write(id, &data));
+        }
+        if !written.is_empty() {
+            try!(add(&written, plan, errors, filter));
+        }
+        imported.push((try!(commit(Some(format!("Import git commit {}", hash)), plan)).0, hash.clone()));
+        parent = Some(hash);
+    }
+
+    try!(record_op(plan, "import-git", imported.last().map(|&(id, _)| id), vec![escape_id(source)]));
+    Ok(imported)
+}
+
+/// Write revisions as a git fast-import stream, returning how many were
+/// written. The range is `from..to`, the revisions after from up to to,
+/// with either end left out meaning the first revision or head. A single
+/// revision means everything up to it, and no range everything up to head.
+pub fn export_git<W: Write>(range: Option<&str>, options: &FastExportOptions, out: &mut W) -> io::Result<usize> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let revs = try!(open_revisions());
+    let refs = Refs::default();
+    let (from, to) = match range.map(|range| (range, range.find(".."))) {
+        Some((range, Some(split))) => (&range[..split], &range[split + 2..]),
+        Some((range, None)) => ("", range),
+        None => ("", "")
+    };
+    let after = if from.is_empty() {0} else {try!(refs.resolve(&revs, from))};
+    let upto = try!(refs.resolve(&revs, if to.is_empty() {"HEAD"} else {to}));
+    let ids: Vec<RevisionId> = try!(revs.list()).into_iter().filter(|&id| id > after && id <= upto).collect();
+
+    info!("Exporting {} revisions to git", ids.len());
+    try!(write_fast_import(out, &revs, &ids, &try!(refs.tags()), options));
+    Ok(ids.len())
+}
+
+/// Pack every file's index into a single pack file, returning how many were packed.
+pub fn pack(plan: Plan) -> io::Result<usize> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let logs = try!(open_logs()).with_plan(plan);
+    let packed = try!(logs.repack());
+    try!(record_op(plan, "pack", None, vec![]));
+    Ok(packed)
+}
+
+/// What repairing a file's index came to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairOutcome {
+    // the index checked out fine
+    Intact,
+    // rebuilt from the staged snapshot, nothing was lost
+    FromStage,
+    // the snapshot was gone too, so the checkout was staged again and
+    // whatever was staged before is lost
+    FromCheckout,
+    // there was nothing left to rebuild from, the file is no longer tracked
+    Dropped
+}
+
+/// A file whose index was checked, with what was wrong with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    pub id: PathBuf,
+    pub problem: Option<String>,
+    pub outcome: RepairOutcome
+}
+
+/// Verify the indexes of the given paths, or of every tracked file if none are
+/// given, and rebuild the broken ones from the stage, or from the checkout when
+/// the stage has lost the file as well.
+pub fn repair(paths: &[PathBuf], plan: Plan) -> io::Result<Vec<Repair>> {
+    trace!("Opening repository");
+    let repo = try!(Repo::open_for(".", plan));
+
+    let checkout = Checkout::default().with_plan(plan);
+    let mut stage = try!(open_stage()).with_plan(plan);
+    let mut logs = try!(open_logs()).with_plan(plan);
+    let mut ids = vec![];
+    if paths.is_empty() {
+        ids = try!(logs.indexed_ids());
+    }
+    for path in paths.iter() {
+        ids.push(try!(path_id(path)));
+    }
+
+    let mut repairs = vec![];
+    for id in ids {
+        let problem = if !try!(logs.is_indexed(&id)) {
+            "no index".to_string()
+        } else {
+            match verify_index(&logs, &id) {
+                Ok(()) => {
+                    trace!("Index of {:?} is intact", &id);
+                    repairs.push(Repair {
+                        id: id,
+                        problem: None,
+                        outcome: RepairOutcome::Intact
+                    });
+                    continue;
+                },
+                Err(problem) => problem
+            }
+        };
+        warn!("Index of {:?} is bad: {}", &id, problem);
+
+        try!(drop_index(&logs, &id, plan));
+        let outcome = match fs::metadata(stage.path.join(&id)) {
+            Ok(ref data) if data.is_file() => {
+                debug!("Rebuilding index of {:?} from the stage", &id);
+                let content = try!(stage.read_path(&id));
+                try!(stage_content(&repo, &checkout, &mut stage, &mut logs, &id, Some(&content), plan));
+                RepairOutcome::FromStage
+            },
+            _ => match fs::metadata(checkout.path.join(&id)) {
+                Ok(ref data) if data.is_file() => {
+                    debug!("Staging {:?} again from the checkout", &id);
+                    try!(stage_content(&repo, &checkout, &mut stage, &mut logs, &id, None, plan));
+                    RepairOutcome::FromCheckout
+                },
+                _ => {
+                    debug!("Nothing left of {:?}, no longer tracking it", &id);
+                    try!(logs.forget(&id));
+                    RepairOutcome::Dropped
+                }
+            }
+        };
+        repairs.push(Repair {
+            id: id,
+            problem: Some(problem),
+            outcome: outcome
+        });
+    }
+
+    let rebuilt: Vec<String> = repairs.iter().filter(|repair| repair.outcome != RepairOutcome::Intact)
+        .map(|repair| escape_id(&repair.id)).collect();
+    if !rebuilt.is_empty() {
+        let warnings = report_unsettled(&stage);
+        try!(record_op_warned(plan, "repair", None, rebuilt, warnings));
+    }
+    Ok(repairs)
+}
+
+fn drop_index(logs: &Logs, id: &Path, plan: Plan) -> io::Result<()> {
+    // whatever's left of an index before it's rebuilt. a packed one is
+    // replaced in the manifest and dropped with its pack on the next repack
+    let dir = logs.path.join(id);
+    for name in ["meta", "content", "places"].iter() {
+        try!(remove_path(&dir.join(name), plan));
+    }
+    if !plan.is_dry_run() {
+        let _ = fs::remove_dir(&dir);
+    }
+    Ok(())
+}
+
+/// How the staged copy, the index and the checkout of a file disagree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disagreement {
+    // staged with no index to diff against
+    NoIndex,
+    // indexed, but the index can't be read back
+    BrokenIndex,
+    // indexed with nothing staged
+    NotStaged,
+    // indexed and staged, but the index wasn't built from the staged copy
+    StaleIndex
+}
+
+/// What brings a file's staged copy and index back in line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    // index the staged copy again
+    RebuildIndex,
+    // stage the checkout again, it's what the index was built from
+    RefreshStage,
+    // stage and index the checkout, whatever was staged is lost
+    KeepWorkingCopy,
+    // nothing is left to rebuild from, so the file is no longer tracked
+    Forget
+}
+
+impl fmt::Display for Disagreement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Disagreement::NoIndex => write!(f, "unindexed"),
+            Disagreement::BrokenIndex => write!(f, "broken"),
+            Disagreement::NotStaged => write!(f, "unstaged"),
+            Disagreement::StaleIndex => write!(f, "stale")
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resolution::RebuildIndex => write!(f, "rebuild the index from the stage"),
+            Resolution::RefreshStage => write!(f, "stage the checkout again, it matches the index"),
+            Resolution::KeepWorkingCopy => write!(f, "keep the working copy, what was staged is lost"),
+            Resolution::Forget => write!(f, "stop tracking it, nothing is left to rebuild from")
+        }
+    }
+}
+
+/// What `doctor` does about the disagreements it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoctorAction {
+    // only report them, with what would resolve each
+    Report,
+    // apply the suggested resolution
+    Fix,
+    // stage the checkout wherever there is one, otherwise as suggested
+    KeepWorking
+}
+
+/// A file whose staged copy, index and checkout disagree, with what
+/// resolves it and whether that was done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub id: PathBuf,
+    pub problem: Disagreement,
+    pub detail: String,
+    pub resolution: Resolution,
+    pub applied: bool
+}
+
+fn copy_matches_index(logs: &Logs, id: &Path, path: &Path) -> io::Result<bool> {
+    // whether a copy of a file has what its index was built from
+    let metadata = try!(fs::metadata(path));
+    Ok(!try!(logs.diff_path(&PathInfo::new(path, id, metadata))))
+}
+
+fn staged_matches_index(logs: &Logs, stage: &Stage, id: &Path) -> io::Result<bool> {
+    // a staged copy kept as chunks or sealed can only be held up against
+    // the size
+    let path = stage.path.join(id);
+    let mut head = vec![];
+    try!(fs::File::open(&path).and_then(|f| f.take(CHUNK_MANIFEST_MAGIC.len() as u64).read_to_end(&mut head)));
+    if stage.is_sealed() || is_manifest(&head) {
+        let content = try!(stage.read_path(id));
+        return Ok(try!(logs.read_meta(id)).size == content.len() as u64);
+    }
+    copy_matches_index(logs, id, &path)
+}
+
+fn diagnose(logs: &Logs, stage: &Stage, checkout: &Checkout, id: &Path)
+            -> io::Result<Option<(Disagreement, String, Resolution)>> {
+    // what's wrong with a file and what would put it right, none if its
+    // staged copy and index agree. the checkout is only a way back to the
+    // index when it's what the index was built from
+    let is_file = |path: PathBuf| fs::metadata(path).map(|data| data.is_file()).unwrap_or(false);
+    let staged = is_file(stage.path.join(id));
+    let checked_out = is_file(checkout.path.join(id));
+    let fallback = if checked_out {Resolution::KeepWorkingCopy} else {Resolution::Forget};
+
+    if !try!(logs.is_indexed(id)) {
+        if !staged {
+            return Ok(None);
+        }
+        return Ok(Some((Disagreement::NoIndex, "staged without an index".to_string(), Resolution::RebuildIndex)));
+    }
+    if let Err(problem) = verify_index(logs, id) {
+        return Ok(Some((Disagreement::BrokenIndex, problem,
+                        if staged {Resolution::RebuildIndex} else {fallback})));
+    }
+    if staged && try!(staged_matches_index(logs, stage, id)) {
+        trace!("{:?} agrees with its index", id);
+        return Ok(None);
+    }
+    let checkout_matches = checked_out && try!(copy_matches_index(logs, id, &checkout.path.join(id)));
+    if !staged {
+        return Ok(Some((Disagreement::NotStaged, "indexed with nothing staged".to_string(),
+                        if checkout_matches {Resolution::RefreshStage} else {fallback})));
+    }
+    Ok(Some((Disagreement::StaleIndex, "the index doesn't match the staged copy".to_string(),
+             if checkout_matches {Resolution::RefreshStage} else {Resolution::RebuildIndex})))
+}
+
+/// Cross-check the staged copy, the index and the checkout of the given
+/// paths, or of everything staged or indexed if none are given, and report
+/// each file where they disagree with what would resolve it. The resolution
+/// is applied too unless the action only reports.
+pub fn doctor(paths: &[PathBuf], action: DoctorAction, plan: Plan) -> io::Result<(usize, Vec<Diagnosis>)> {
+    trace!("Opening repository");
+    let repo = try!(Repo::open_for(".", plan));
+
+    let checkout = Checkout::default().with_plan(plan);
+    let mut stage = try!(open_stage()).with_plan(plan);
+    // what's on disk is compared, never what the stat info says
+    let mut logs = try!(open_logs()).with_plan(plan).with_stat_cache(false);
+    let mut prefixes = vec![];
+    for path in paths.iter() {
+        prefixes.push(try!(path_id(path)));
+    }
+    let mut ids = try!(stage_files(&stage.path));
+    ids.retain(|id| !is_temp_path(id));
+    ids.extend(try!(logs.indexed_ids()));
+    ids.sort();
+    ids.dedup();
+    ids.retain(|id| prefixes.is_empty() || prefixes.iter().any(|prefix| id.starts_with(prefix)));
+
+    let mut found = vec![];
+    for id in ids.iter() {
+        let (problem, detail, suggested) = match try!(diagnose(&logs, &stage, &checkout, id)) {
+            Some(diagnosis) => diagnosis,
+            None => {
+                continue;
+            }
+        };
+        let checked_out = fs::metadata(checkout.path.join(id)).map(|data| data.is_file()).unwrap_or(false);
+        let resolution = match action {
+            DoctorAction::KeepWorking if checked_out => Resolution::KeepWorkingCopy,
+            _ => suggested
+        };
+        warn!("{:?} disagrees with its index: {}", id, detail);
+        let applied = action != DoctorAction::Report && !plan.is_dry_run();
+        if action != DoctorAction::Report {
+            debug!("Resolving {:?}: {:?}", id, resolution);
+            match resolution {
+                Resolution::RebuildIndex => {
+                    let content = try!(stage.read_path(id));
+                    try!(drop_index(&logs, id, plan));
+                    try!(stage_content(&repo, &checkout, &mut stage, &mut logs, id, Some(&content), plan));
+                },
+                Resolution::RefreshStage | Resolution::KeepWorkingCopy => {
+                    try!(drop_index(&logs, id, plan));
+                    try!(stage_content(&repo, &checkout, &mut stage, &mut logs, id, None, plan));
+                },
+                Resolution::Forget => {
+                    try!(drop_index(&logs, id, plan));
+                    try!(remove_path(&stage.path.join(id), plan));
+                    try!(logs.forget(id));
+                }
+            }
+        }
+        found.push(Diagnosis {
+            id: id.clone(),
+            problem: problem,
+            detail: detail,
+            resolution: resolution,
+            applied: applied
+        });
+    }
+
+    let resolved: Vec<String> = found.iter().filter(|diagnosis| diagnosis.applied)
+        .map(|diagnosis| escape_id(&diagnosis.id)).collect();
+    if !resolved.is_empty() {
+        let warnings = report_unsettled(&stage);
+        try!(record_op_warned(plan, "doctor", None, resolved, warnings));
+    }
+    Ok((ids.len(), found))
+}
+
+/// Remove the revisions a retention policy doesn't keep, then everything
+/// only they were using. Head and tagged revisions are always kept. Returns
+/// the revisions removed.
+pub fn prune(retention: &Retention, plan: Plan) -> io::Result<Vec<RevisionId>> {
+    trace!("Opening repository");
+    let repo = try!(Repo::open_for(".", plan));
+
+    let mut revs = try!(open_revisions()).with_plan(plan);
+    let refs = Refs::default();
+    let pinned: Vec<RevisionId> = try!(refs.tags()).into_iter().chain(try!(refs.branches()).into_iter())
+        .map(|(_, rev)| rev).collect();
+    let keep = try!(revs.retained(retention, &pinned));
+    let removed = try!(revs.prune(&keep));
+    if !removed.is_empty() && !plan.is_dry_run() {
+        // snapshots, logs and chunks only the removed revisions used
+        try!(collect_stage(&repo));
+    }
+    try!(record_op(plan, "prune", None, removed.iter().map(|id| id.to_string()).collect()));
+    Ok(removed)
+}
+
+/// Annotate every line of a checkout file with the revision that introduced it.
+pub fn blame_path(path: &str) -> io::Result<Vec<BlameLine>> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let checkout = Checkout::default();
+    let id = try!(path_id(path));
+    let path = checkout.path.join(&id);
+    let current = match fs::metadata(&path) {
+        Ok(ref data) if data.is_file() => try!(read_or_empty(&path)),
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{} is not a file in the checkout", id.display())));
+        }
+    };
+
+    debug!("Annotating {:?}", &id);
+    blame(&try!(open_revisions()), &id, &current)
+}
+
+/// The stored content of a file, given as `<rev>:<path>` or just `<path>` for
+/// the staged copy. The revision can be an id, a tag or `HEAD`.
+pub fn show(spec: &str) -> io::Result<Vec<u8>> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let revs = try!(open_revisions());
+    let (rev, path) = match spec.find(':') {
+        None => (None, spec),
+        Some(split) => {
+            let rev = try!(Refs::default().resolve(&revs, &spec[..split]));
+            (Some(rev), &spec[split + 1..])
+        }
+    };
+    let id = try!(path_id(path));
+
+    match rev {
+        Some(rev) => {
+            debug!("Reading {:?} at revision {}", &id, rev);
+            revs.read_path(rev, &id)
+        },
+        None => cat_stage(&id, false)
+    }
+}
+
+/// Restore a file to its content at a revision, head if none is given.
+pub fn revert(id: PathBuf, rev: Option<&str>, plan: Plan) -> io::Result<()> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let undo = try!(open_undo(plan));
+    try!(undo.begin("revert"));
+    try!(save_manifest(&undo));
+
+    let checkout = Checkout::default().with_plan(plan);
+    let mut stage = try!(open_stage()).with_plan(plan).with_undo(undo.clone());
+    let mut logs = try!(open_logs()).with_plan(plan).with_undo(undo.clone());
+    let revs = try!(open_revisions());
+
+    let rev = try!(Refs::default().resolve(&revs, rev.unwrap_or("HEAD")));
+
+    debug!("Reconstructing {:?} at revision {}", &id, rev);
+    let data = try!(revs.read_path(rev, &id));
+
+    let path = checkout.path.join(&id);
+    if !plan.allow(Op::WriteFile(&path)) {
+        // nothing was written, so report what restaging it would do
+        plan.allow(Op::CopyFile(&path, &stage.path.join(&id)));
+        plan.allow(Op::WriteIndex(&logs.path.join(&id)));
+        return Ok(());
+    }
+    try!(undo.save("checkout", &checkout.path, &id));
+    debug!("Writing reconstructed content to {:?}", &path);
+    match fs::File::create(&path).and_then(|mut f| f.write_all(&data)) {
+        Err(e) => {
+            error!("Failed to write {}: {}", path.display(), e);
+            return Err(e);
+        },
+        Ok(()) => {
+            trace!("Wrote reconstructed file");
+        }
+    }
+
+    trace!("Getting file metadata");
+    let metadata = try!(fs::metadata(&path));
+    let info = PathInfo::new(path, id, metadata);
+
+    debug!("Updating stage");
+    try!(stage.add_path(&info));
+    try!(stage.flush());
+    debug!("Updating file index");
+    try!(logs.add_path(&info));
+    try!(record_copy_hashes(&mut stage, &logs));
+
+    info!("Reverted {:?} to revision {}", &info.id, rev);
+    let warnings = report_unsettled(&stage);
+    record_op_warned(plan, "revert", Some(rev), vec![escape_id(&info.id)], warnings)
+}
+
+/// Reverse the last operation in the operation log, returning what was undone.
+pub fn undo(plan: Plan) -> io::Result<OpRecord> {
+    trace!("Opening repository");
+    try!(Repo::open_for(".", plan));
+
+    let oplog = OpLog::default();
+    let last = match try!(oplog.read()).pop() {
+        Some(record) => record,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "There is nothing to undo"));
+        }
+    };
+    let undo = try!(open_undo(plan));
+
+    match last.op.as_ref() {
+        "commit" => {
+            let rev = match last.rev {
+                Some(rev) => rev,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Commit record has no revision"));
+                }
+            };
+            debug!("Uncommitting revision {}", rev);
+            let mut revs = try!(open_revisions()).with_plan(plan);
+            let parent = try!(revs.meta(rev)).parent;
+            try!(revs.uncommit(rev));
+            // the branch it was committed to goes back with it
+            let refs = Refs::default().with_plan(plan);
+            if let Some(branch) = try!(refs.current_branch()) {
+                if try!(refs.branch(&branch)) == Some(rev) {
+                    match parent {
+                        Some(parent) => try!(refs.set_branch(&branch, parent)),
+                        None => try!(refs.remove_branch(&branch))
+                    }
+                }
+            }
+        },
+        "add" | "rm" | "revert" | "apply" | "switch" | "merge" | "stash" => {
+            match try!(undo.op()) {
+                Some(ref op) if *op == last.op => {
+                    trace!("Undo information matches the last operation");
+                },
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("Undo information for the last {} is missing", last.op)));
+                }
+            }
+            let checkout = Checkout::default();
+            debug!("Rolling back {}", last.op);
+            let count = try!(undo.rollback("stage", &Stage::default().path)) +
+                try!(undo.rollback("logs", &Logs::default().path)) +
+                try!(undo.rollback("manifest", &repo_path("manifest"))) +
+                try!(undo.rollback("checkout", &checkout.path)) +
+                try!(undo.rollback("refs", &repo_path("refs"))) +
+                try!(undo.rollback("revs", &repo_path("revs"))) +
+                try!(undo.rollback("stash", &repo_path("stash")));
+            debug!("Rolled back {} paths", count);
+        },
+        "undo" => {
+            return Err(io::Error::new(io::ErrorKind::Other, "The last operation was already undone"));
+        },
+        op => {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("{} can't be undone", op)));
+        }
+    }
+
+    try!(undo.clear());
+    try!(record_op(plan, "undo", last.rev, vec![last.op.clone()]));
+    Ok(last)
+}
+
+/// Stage and index everything under a directory.
+pub fn stage_dir_all<T: Into<PathBuf>>(checkout: &Checkout, logs: &mut Logs, stage: &mut Stage, path: T, ignore: &IgnoreRules)
+                                       -> Result<(), io::Error> {
+    info!("Copying directory tree");
+    try!(checkout.walk(&path.into(), ignore, |path, id, metadata| {
+        // a file that can't be read is skipped before anything is staged
+        if metadata.is_file() && try!(checkout.errors.attempt(&id, || fs::File::open(&path))).is_none() {
+            return Ok(());
+        }
+
+        trace!("Creating path info object");
+        let info = PathInfo::new(path, id, metadata);
+
+        debug!("Adding path to stage");
+        match stage.add_path(&info) {
+            Ok(()) => {
+                trace!("Add path succeeded");
+            },
+            Err(e) => {
+                error!("Add path failed: {}", e);
+                return Err(e);
+            }
+        }
+
+        debug!("Creating file index");
+        match logs.add_path(&info) {
+            Ok(()) => {
+                trace!("Index creation successful");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Index creation failed: {}", e);
+                Err(e)
+            }
+        }
+    }));
+
+    debug!("Finishing queued copies");
+    match stage.flush() {
+        Ok(count) => {
+            trace!("Flushed {} copies", count);
+        },
+        Err(e) => {
+            error!("Copying to stage failed: {}", e);
+            return Err(e);
+        }
+    }
+    try!(record_copy_hashes(stage, logs));
+
+    trace!("Init finished");
+    Ok(())
+}
+
+/// Report which lines changed in everything under a directory, using the indexes.
+pub fn diff_dir_all<T: Into<PathBuf>>(checkout: &Checkout, logs: &Logs, path: T, ignore: &IgnoreRules)
+                                      -> Result<Vec<PathBuf>, io::Error> {
+    // ids of the files that differ from their index, sorted. the walk
+    // comes first, then the files are diffed, on as many threads as the
+    // logs are set up for
+    let mut files = vec![];
+
+    info!("Diffing directory tree");
+    try!(checkout.walk(&path.into(), ignore, |path, id, metadata| {
+        if metadata.is_file() {
+            trace!("Creating path info object");
+            files.push(PathInfo::new(path, id, metadata));
+        }
+        Ok(())
+    }));
+
+    let mut changed = vec![];
+    for (id, result) in try!(diff_paths(logs, files)) {
+        match try!(checkout.errors.check(&id, result)) {
+            Some(true) => {
+                changed.push(id);
+            },
+            Some(false) => {
+                trace!("{:?} is unchanged", &id);
+            },
+            None => {
+                error!("Diffing {:?} failed", &id);
+            }
+        }
+    }
+    changed.sort();
+
+    trace!("Diff finished");
+    Ok(changed)
+}
+
+fn diff_paths(logs: &Logs, files: Vec<PathInfo>) -> io::Result<Vec<(PathBuf, io::Result<bool>)>> {
+    // the result of diffing each file, in the order given. the checkout's
+    // error list can't cross threads, so failures come back for the caller
+    // to note
+    if logs.threads <= 1 || files.len() <= 1 {
+        return Ok(files.into_iter().map(|info| {
+            let result = retry_transient(&info.id, || logs.diff_path(&info));
+            (info.id, result)
+        }).collect());
+    }
+
+    let count = files.len();
+    let threads = ::std::cmp::min(logs.threads, count);
+    debug!("Diffing {} files on {} threads", count, threads);
+    let queue = Arc::new(Mutex::new(files.into_iter().enumerate().collect::<Vec<_>>()));
+    let (sender, receiver) = mpsc::channel();
+    let mut workers = vec![];
+    for _ in 0..threads {
+        // opened here so a failure stops the diff before anything runs
+        let worker_logs = try!(logs.fork());
+        let (queue, sender) = (queue.clone(), sender.clone());
+        workers.push(thread::spawn(move || {
+            loop {
+                let job = queue.lock().unwrap().pop();
+                let (i, info) = match job {
+                    Some(job) => job,
+                    None => return
+                };
+                let result = retry_transient(&info.id, || worker_logs.diff_path(&info));
+                if sender.send((i, info.id, result)).is_err() {
+                    return;
+                }
+            }
+        }));
+    }
+    drop(sender);
+
+    let mut results: Vec<(usize, PathBuf, io::Result<bool>)> = receiver.iter().collect();
+    for worker in workers {
+        if worker.join().is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other, "A diff thread panicked"));
+        }
+    }
+    if results.len() != count {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("Only {} of {} files were diffed", results.len(), count)));
+    }
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results.into_iter().map(|(_, id, result)| (id, result)).collect())
+}
+
+fn index_units<R: BufRead>(orig: &mut UnitReader<R>, hasher: LineHasher, endings: Option<LineEndings>,
+                           threads: usize) -> io::Result<(HashMap<u64, Vec<IndexPlace>>, usize, u64)> {
+    // every place of each unit read, how many there were and the content
+    // hash over them, on as many threads as given
+    let mut counter = 0;
+    let mut content_hasher = FnvHasher::default();
+    let mut places: HashMap<u64, Vec<IndexPlace>> = HashMap::new();
+    {
+        let mut record = |line: &[u8], line_hash: u64| -> io::Result<()> {
+            content_hasher.write_u64(line_hash);
+            trace!("Recording place");
+            places.entry(line_hash).or_insert(vec![]).push(IndexPlace {
+                node: counter,
+                offset: 0
+            });
+            debug!("Counter {}: {:?}", counter, String::from_utf8_lossy(line));
+            trace!("Incrementing counter");
+            counter += 1;
+            Ok(())
+        };
+        if threads > 1 {
+            debug!("Hashing lines on {} threads", threads);
+            try!(hash_units(orig, hasher, endings, threads, record));
+        } else {
+            let mut line = Vec::new();
+            loop {
+                trace!("Reading line");
+                match orig.read_unit(&mut line) {
+                    Ok(false) => {
+                        trace!("Done with this file");
+                        break;
+                    },
+                    Ok(true) => {
+                        trace!("Got new line: {:?}", String::from_utf8_lossy(&line));
+                    },
+                    Err(e) => {
+                        error!("Failed to read line: {}", e);
+                        return Err(e);
+                    }
+                }
+                if let Some(endings) = endings {
+                    endings.normalize(&mut line);
+                }
+                try!(record(&line, hasher.hash_line(&line)));
+            }
+        }
+    }
+    Ok((places, counter, content_hasher.finish()))
+}
+
+pub fn read_or_empty<T: AsRef<Path>>(path: T) -> io::Result<Vec<u8>> {
+    // missing files read as empty so additions show up as a full insertion
+    let mut data = vec![];
+    match fs::File::open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            trace!("File does not exist");
+        },
+        Err(e) => {
+            error!("Failed to open file: {}", e);
+            return Err(e);
+        },
+        Ok(mut f) => {
+            try!(f.read_to_end(&mut data));
+        }
+    }
+    Ok(data)
+}
+
+/// How differences are printed, as unified diffs or as per-file line counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    Patch,
+    Stat
+}
+
+// writes each file's diff as it comes, or collects counts to write at the end
+struct DiffPrinter<'a> {
+    out: &'a mut (Write + 'a),
+    format: DiffFormat,
+    // changed words in reverse video, only ever for a terminal
+    highlight: bool,
+    line_endings: LineEndings,
+    // files another driver handles are only said to differ
+    drivers: DriverRules,
+    stats: Vec<(String, DiffStat)>,
+    // files that had any changes
+    changed: usize
+}
+
+impl<'a> DiffPrinter<'a> {
+    fn new(out: &'a mut (Write + 'a), format: DiffFormat, highlight: bool, line_endings: LineEndings,
+           drivers: DriverRules) -> DiffPrinter<'a> {
+        DiffPrinter {
+            out: out,
+            format: format,
+            highlight: highlight,
+            line_endings: line_endings,
+            drivers: drivers,
+            stats: vec![],
+            changed: 0
+        }
+    }
+
+    fn differ(&mut self, id: &Path) -> io::Result<()> {
+        // a file that's only said to have changed
+        self.changed += 1;
+        match self.format {
+            DiffFormat::Patch => {
+                writeln!(self.out, "Files a/{} and b/{} differ", escape_id(id), escape_id(id))
+            },
+            DiffFormat::Stat => {
+                self.stats.push((escape_id(id), DiffStat::default()));
+                Ok(())
+            }
+        }
+    }
+
+    fn file(&mut self, id: &Path, old: &[u8], new: &[u8]) -> io::Result<()> {
+        match self.drivers.driver_for(id) {
+            DiffDriver::Lines => {},
+            DiffDriver::Skip => {
+                trace!("Not diffing {:?}", id);
+                return Ok(());
+            },
+            DiffDriver::Whole | DiffDriver::Blocks => {
+                if old == new {
+                    trace!("No changes");
+                    return Ok(());
+                }
+                return self.differ(id);
+            }
+        }
+        let old = self.line_endings.normalize_lines(split_lines(old));
+        let new = self.line_endings.normalize_lines(split_lines(new));
+        let file_hunks = match diff_within(&old, &new, MAX_DIFF_EDITS) {
+            Some(ops) => hunks(&ops, 3),
+            None => {
+                // too different to be worth listing line by line
+                return self.differ(id);
+            }
+        };
+        if file_hunks.is_empty() {
+            trace!("No changes");
+            return Ok(());
+        }
+        self.changed += 1;
+
+        match self.format {
+            DiffFormat::Patch => {
+                try!(writeln!(self.out, "--- a/{}", escape_id(id)));
+                try!(writeln!(self.out, "+++ b/{}", escape_id(id)));
+                for hunk in file_hunks.iter() {
+                    for line in render_hunk(hunk, &old, &new, self.highlight) {
+                        try!(self.out.write_all(&line));
+                        try!(self.out.write_all(b"\n"));
+                    }
+                }
+            },
+            DiffFormat::Stat => {
+                self.stats.push((escape_id(id), hunks_stat(&file_hunks)));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<usize> {
+        if self.format == DiffFormat::Stat && !self.stats.is_empty() {
+            for line in render_stat(&self.stats, terminal_width()) {
+                try!(writeln!(self.out, "{}", line));
+            }
+        }
+        Ok(self.changed)
+    }
+}
+
+fn terminal_width() -> usize {
+    // shells export COLUMNS, anything else gets the usual 80
+    match env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()) {
+        Some(width) => width,
+        None => 80
+    }
+}
+
+fn walk_stage_diffs<T, F>(checkout: &Checkout, stage: &Stage, logs: &Logs, path: T, ignore: &IgnoreRules,
+                          visit: F) -> io::Result<()>
+    where T: Into<PathBuf>, F: FnMut(&Path, Option<Vec<u8>>, Option<Vec<u8>>) {
+    walk_stage_diffs_reusing(checkout, stage, logs, path, ignore, |_, _, _| false, visit)
+}
+
+fn walk_stage_diffs_reusing<T, R, F>(checkout: &Checkout, stage: &Stage, logs: &Logs, path: T,
+                                     ignore: &IgnoreRules, mut reuse: R, mut visit: F) -> io::Result<()>
+    where T: Into<PathBuf>, R: FnMut(&Path, &fs::Metadata, Option<&fs::Metadata>) -> bool,
+          F: FnMut(&Path, Option<Vec<u8>>, Option<Vec<u8>>) {
+    // hand every file under a directory to visit with its staged and current
+    // content, none where it isn't staged or no longer exists. tracked files
+    // that were deleted come last. a file reuse already knows about from its
+    // stat info and its staged copy's isn't read or visited
+    let path = path.into();
+    try!(checkout.walk(&path, ignore, |path, id, metadata| {
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        let staged_metadata = fs::metadata(stage.path.join(&id)).ok();
+        if reuse(&id, &metadata, staged_metadata.as_ref()) {
+            trace!("Already know about {:?}", &id);
+            return Ok(());
+        }
+        debug!("Diffing {:?}", &id);
+        let current = match try!(checkout.errors.attempt(&id, || read_or_empty(&path))) {
+            Some(current) => current,
+            None => {
+                return Ok(());
+            }
+        };
+        let staged = if staged_metadata.is_some() {
+            Some(try!(stage.read_path(&id)))
+        } else {
+            None
+        };
+        visit(&id, staged, Some(current));
+        Ok(())
+    }));
+
+    // the walk only sees what's there, the manifest knows what's missing
+    let prefix = try!(path_id(&path));
+    if let Some(ids) = try!(logs.tracked_ids()) {
+        for id in ids.iter().filter(|id| id.starts_with(&prefix) && ignore.includes(id, false)) {
+            match fs::symlink_metadata(checkout.path.join(id)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    debug!("{:?} was deleted", id);
+                    visit(id, Some(try!(stage.read_path(id))), None);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write diffs of the stage against everything under a directory to `out`,
+/// including tracked files that were deleted. Returns how many files differ.
+pub fn write_diff_dir_all<T: Into<PathBuf>, W: Write>(checkout: &Checkout, stage: &Stage, logs: &Logs, path: T,
+                                                      ignore: &IgnoreRules, format: DiffFormat, highlight: bool,
+                                                      out: &mut W) -> io::Result<usize> {
+    info!("Writing directory tree differences");
+    let mut printer = DiffPrinter::new(out, format, highlight, logs.line_endings(), logs.drivers().clone());
+    // the walk can't be stopped, so the first failure to write is kept
+    let mut failure = None;
+    try!(walk_stage_diffs(checkout, stage, logs, path, ignore, |id, staged, current| {
+        if failure.is_none() {
+            failure = printer.file(id, &staged.unwrap_or(vec![]), &current.unwrap_or(vec![])).err();
+        }
+    }));
+    match failure {
+        Some(e) => Err(e),
+        None => printer.finish()
+    }
+}
+
+/// How a file in the checkout differs from the stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    Untracked,
+    Modified,
+    Deleted,
+    // a directory that isn't staged, or a staged one that's gone
+    UntrackedDir,
+    DeletedDir,
+    // left out by the filter, whether it's staged or not
+    Skipped(SkipReason)
+}
+
+/// A file that differs from the stage, with how many lines changed and a
+/// hash of its content, the checkout's or the stage's if it was deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStatus {
+    pub id: PathBuf,
+    pub change: FileChange,
+    pub stat: DiffStat,
+    pub content_hash: u64
+}
+
+/// Every file in the checkout that differs from the stage, sorted by path,
+/// along with directories made or removed since they were staged. Files
+/// that look the same as at the last status, with nothing staged or
+/// configured since, are answered from the status cache.
+pub fn status(paths: &[PathBuf], errors: &WalkErrors, filter: FileFilter) -> io::Result<Vec<FileStatus>> {
+    trace!("Opening repository");
+    let repo = try!(Repo::open("."));
+
+    let checkout = Checkout::default().with_errors(errors.clone()).with_filter(filter);
+    let stage = try!(open_stage());
+    let logs = try!(open_logs());
+    let ignore = try!(scoped_ignore(&checkout, paths));
+    let key = status_key(try!(logs.tree_hash()), &try!(read_or_empty(repo.path.join("config"))));
+    let mut cache = StatusCache::load(repo.path.join("cache").join("status"), key);
+    let mut changes = vec![];
+    // files the cache answered for, and ones read this time with how they
+    // looked
+    let mut cached_changes = vec![];
+    let mut reused = vec![];
+    let mut stamps = vec![];
+    {
+        let cached = &cache;
+        try!(walk_stage_diffs_reusing(&checkout, &stage, &logs, ".", &ignore, |id, metadata, staged| {
+            let stamp = FileStamp::new(metadata, staged);
+            match cached.lookup(id, &stamp) {
+                Some(status) => {
+                    if let Some(status) = status {
+                        cached_changes.push(FileStatus {
+                            id: id.to_path_buf(),
+                            change: if status.untracked {FileChange::Untracked} else {FileChange::Modified},
+                            stat: DiffStat {
+                                insertions: status.insertions,
+                                deletions: status.deletions
+                            },
+                            content_hash: status.content_hash
+                        });
+                    }
+                    reused.push(id.to_path_buf());
+                    true
+                },
+                None => {
+                    stamps.push((id.to_path_buf(), stamp));
+                    false
+                }
+            }
+        }, |id, staged, current| {
+            let change = match (staged.is_some(), current.is_some()) {
+                (false, _) => FileChange::Untracked,
+                (true, true) => FileChange::Modified,
+                (true, false) => FileChange::Deleted
+            };
+            let content_hash = match (current.as_ref(), staged.as_ref()) {
+                (Some(data), _) | (None, Some(data)) => hash_bytes(data),
+                (None, None) => hash_bytes(&[])
+            };
+            // only lines have counts, other drivers just say whether it changed
+            let (stat, unchanged) = match logs.driver_for(id) {
+                DiffDriver::Lines => {
+                    let old = logs.line_endings().normalize_lines(split_lines(&staged.unwrap_or(vec![])));
+                    let new = logs.line_endings().normalize_lines(split_lines(&current.unwrap_or(vec![])));
+                    match diff_within(&old, &new, MAX_DIFF_EDITS) {
+                        Some(ops) => {
+                            let stat = hunks_stat(&hunks(&ops, 0));
+                            (stat, stat.changes() == 0)
+                        },
+                        // too different to count, like another driver
+                        None => (DiffStat::default(), false)
+                    }
+                },
+                DiffDriver::Whole | DiffDriver::Blocks => (DiffStat::default(), staged == current),
+                DiffDriver::Skip => (DiffStat::default(), true)
+            };
+            if change == FileChange::Modified && unchanged {
+                trace!("{:?} is unchanged", id);
+                return;
+            }
+            changes.push(FileStatus {
+                id: id.to_path_buf(),
+                change: change,
+                stat: stat,
+                content_hash: content_hash
+            });
+        }));
+    }
+
+    {
+        let found: HashMap<&Path, &FileStatus> = changes.iter().map(|status| (status.id.as_path(), status)).collect();
+        for (id, stamp) in stamps.iter().cloned() {
+            let status = found.get(id.as_path()).map(|status| CachedStatus {
+                untracked: status.change == FileChange::Untracked,
+                insertions: status.stat.insertions,
+                deletions: status.stat.deletions,
+                content_hash: status.content_hash
+            });
+            cache.record(&id, stamp, status);
+        }
+    }
+    if paths.is_empty() {
+        // the whole checkout was walked, anything not in it is gone
+        reused.extend(stamps.into_iter().map(|(id, _)| id));
+        cache.keep_only(&reused);
+    }
+    // it only makes the next status faster
+    if let Err(e) = cache.save() {
+        warn!("Failed to save the status cache: {}", e);
+    }
+    changes.extend(cached_changes);
+    for (id, change) in try!(dir_changes(&checkout, &logs, &ignore)) {
+        changes.push(FileStatus {
+            id: id,
+            change: change,
+            stat: DiffStat::default(),
+            content_hash: 0
+        });
+    }
+    for (id, reason) in ignore.skipped() {
+        changes.push(FileStatus {
+            id: id,
+            change: FileChange::Skipped(reason),
+            stat: DiffStat::default(),
+            content_hash: 0
+        });
+    }
+    changes.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(changes)
+}
+
+/// Whether the stage holds exactly what HEAD does, by comparing tree hashes
+/// rather than walking either. None if there's no HEAD or either side has no
+/// hash to compare.
+pub fn stage_matches_head() -> io::Result<Option<(RevisionId, bool)>> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let revs = try!(open_revisions());
+    let head = match try!(revs.head()) {
+        Some(head) => head,
+        None => {
+            debug!("No revisions to compare against");
+            return Ok(None);
+        }
+    };
+    let staged = match try!(try!(open_logs()).tree_hash()) {
+        Some(hash) => format_tree_hash(hash),
+        None => {
+            debug!("No manifest, so the stage has no tree hash");
+            return Ok(None);
+        }
+    };
+    match try!(revs.meta(head)).tree_hash {
+        Some(committed) => {
+            trace!("Stage hash {}, revision {} hash {}", staged, head, committed);
+            Ok(Some((head, staged == committed)))
+        },
+        None => {
+            debug!("Revision {} was committed without a tree hash", head);
+            Ok(None)
+        }
+    }
+}
+
+/// One line per changed file, or with line counts and a bar if verbose.
+pub fn render_status(changes: &[FileStatus], verbose: bool) -> Vec<String> {
+    let names: Vec<String> = changes.iter().map(|status| {
+        let mark = match status.change {
+            FileChange::Untracked => "?",
+            FileChange::Modified => "M",
+            FileChange::Deleted => "D",
+            FileChange::UntrackedDir => {
+                return format!("? {}/", escape_id(&status.id));
+            },
+            FileChange::DeletedDir => {
+                return format!("D {}/", escape_id(&status.id));
+            },
+            FileChange::Skipped(reason) => {
+                return format!("! {} ({})", escape_id(&status.id), reason);
+            }
+        };
+        format!("{} {}", mark, escape_id(&status.id))
+    }).collect();
+
+    if !verbose {
+        names
+    } else if !changes.is_empty() {
+        let stats: Vec<(String, DiffStat)> = names.into_iter().zip(changes.iter().map(|status| status.stat)).collect();
+        render_stat(&stats, terminal_width())
+    } else {
+        vec![]
+    }
+}
+
+/// Status lines in the porcelain format, which stays the same across
+/// versions for scripts to read. One line per path in path order: `M path`
+/// for a modified file, `A path` for one that isn't staged, `D path` for one
+/// gone from the checkout, `R old -> new` for a deleted file whose content
+/// turned up at a path that isn't staged, and `! path` for one the filter
+/// left out. Directories made or removed show as `A dir/` and `D dir/`.
+/// Paths are escaped the same way as everywhere else.
+pub fn porcelain_status(changes: &[FileStatus]) -> Vec<String> {
+    // a deleted file pairs with the first unstaged one with the same content.
+    // empty files all look alike, so they're never paired
+    let empty = hash_bytes(&[]);
+    let mut unstaged: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, status) in changes.iter().enumerate().rev() {
+        if status.change == FileChange::Untracked && status.content_hash != empty {
+            unstaged.entry(status.content_hash).or_insert(vec![]).push(i);
+        }
+    }
+    let mut renames = HashMap::new();
+    let mut moved = HashSet::new();
+    for (i, status) in changes.iter().enumerate() {
+        if status.change != FileChange::Deleted {
+            continue;
+        }
+        if let Some(j) = unstaged.get_mut(&status.content_hash).and_then(|found| found.pop()) {
+            trace!("{:?} was moved to {:?}", &status.id, &changes[j].id);
+            renames.insert(i, j);
+            moved.insert(j);
+        }
+    }
+
+    let mut lines = vec![];
+    for (i, status) in changes.iter().enumerate() {
+        let id = escape_id(&status.id);
+        match status.change {
+            FileChange::Modified => lines.push(format!("M {}", id)),
+            FileChange::Untracked if moved.contains(&i) => {},
+            FileChange::Untracked => lines.push(format!("A {}", id)),
+            FileChange::Deleted => match renames.get(&i) {
+                Some(&j) => lines.push(format!("R {} -> {}", id, escape_id(&changes[j].id))),
+                None => lines.push(format!("D {}", id))
+            },
+            FileChange::UntrackedDir => lines.push(format!("A {}/", id)),
+            FileChange::DeletedDir => lines.push(format!("D {}/", id)),
+            FileChange::Skipped(_) => lines.push(format!("! {}", id))
+        }
+    }
+    lines
+}
+
+fn checkout_files(checkout: &Checkout, ignore: &IgnoreRules) -> io::Result<Vec<PathBuf>> {
+    // ids of every file in the checkout that isn't ignored
+    checkout_paths(checkout, ignore).map(|(files, _)| files)
+}
+
+fn checkout_paths(checkout: &Checkout, ignore: &IgnoreRules) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    // ids of every file and directory in the checkout that isn't ignored
+    let mut files = vec![];
+    let mut dirs = vec![];
+    try!(checkout.walk(Path::new(""), ignore, |_, id, metadata| {
+        if metadata.is_dir() {
+            dirs.push(id);
+        } else if metadata.is_file() {
+            files.push(id);
+        }
+        Ok(())
+    }));
+    Ok((files, dirs))
+}
+
+fn dir_changes(checkout: &Checkout, logs: &Logs, ignore: &IgnoreRules) -> io::Result<Vec<(PathBuf, FileChange)>> {
+    // directories made since the last add, and recorded ones that are gone.
+    // without a manifest no directories are recorded to compare against
+    let tracked = match try!(logs.tracked_dirs()) {
+        Some(tracked) => tracked,
+        None => {
+            return Ok(vec![]);
+        }
+    };
+    // the walk for files has noted anything that can't be read already
+    let walk = Checkout::new(checkout.path.clone()).with_errors(WalkErrors::new(false));
+    let mut changes = vec![];
+    let (_, dirs) = try!(checkout_paths(&walk, ignore));
+    for id in dirs {
+        if ignore.includes(&id, false) && tracked.binary_search(&id).is_err() {
+            trace!("Directory {:?} isn't staged", &id);
+            changes.push((id, FileChange::UntrackedDir));
+        }
+    }
+    for id in tracked {
+        if !ignore.includes(&id, false) || ignore.matches(&id, true) {
+            continue;
+        }
+        match fs::symlink_metadata(checkout.path.join(&id)) {
+            Ok(ref data) if data.is_dir() => {},
+            _ => {
+                trace!("Directory {:?} is gone", &id);
+                changes.push((id, FileChange::DeletedDir));
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn read_rev_or_empty(revs: &Revisions, rev: RevisionId, id: &Path) -> io::Result<Vec<u8>> {
+    // files missing from a revision diff as empty
+    match revs.read_path(rev, id) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        result => result
+    }
+}
+
+/// Write the differences between two revisions to `out`, or between a revision
+/// and the checkout if only one is given, optionally limited to paths under
+/// `path`. Returns how many files differ.
+pub fn write_diff_revs<W: Write>(from: &str, to: Option<&str>, path: Option<&Path>, format: DiffFormat,
+                                 highlight: bool, errors: &WalkErrors, filter: FileFilter,
+                                 out: &mut W) -> io::Result<usize> {
+    trace!("Opening repository");
+    try!(Repo::open("."));
+
+    let revs = try!(open_revisions());
+    let refs = Refs::default();
+    let checkout = Checkout::default().with_errors(errors.clone()).with_filter(filter);
+    let from = try!(refs.resolve(&revs, from));
+    let to = match to {
+        Some(to) => Some(try!(refs.resolve(&revs, to))),
+        None => None
+    };
+    let prefix = match path {
+        Some(path) => Some(try!(path_id(path))),
+        None => None
+    };
+
+    let mut ids = try!(revs.files(from));
+    match to {
+        Some(to) => {
+            ids.extend(try!(revs.files(to)));
+        },
+        None => {
+            ids.extend(try!(checkout_files(&checkout, &try!(load_ignore(&checkout)))));
+        }
+    }
+    ids.sort();
+    ids.dedup();
+
+    info!("Writing differences from revision {}", from);
+    let config = try!(Repo::new(".").config());
+    let mut printer = DiffPrinter::new(out, format, highlight, try!(LineEndings::from_config(&config)),
+                                       try!(DriverRules::from_config(&config)));
+    for id in ids.iter() {
+        if let Some(ref prefix) = prefix {
+            if !id.starts_with(prefix) {
+                continue;
+            }
+        }
+        debug!("Diffing {:?}", id);
+        let old = try!(read_rev_or_empty(&revs, from, id));
+        let new = match to {
+            Some(to) => try!(read_rev_or_empty(&revs, to, id)),
+            None => try!(read_or_empty(checkout.path.join(id)))
+        };
+        try!(printer.file(id, &old, &new));
+    }
+    printer.finish()
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::{Arc, Mutex, mpsc};
+
+use std::mem;
+use std::thread;
+use std::io;
+
+use drivers::*;
+use hashers::*;
+use lines::*;
+
+// hashing the lines of a big file on several threads. lines are cut from
+// the file in order on the calling thread and handed out in batches, and
+// each batch comes back to the caller in the order it went out, so whatever
+// is built from the hashes sees the same sequence as hashing one line at a
+// time would give
+
+// files smaller than this are hashed one line at a time, starting threads
+// costs more than it saves on them
+pub const PARALLEL_HASH_MIN_BYTES: u64 = 4 << 20;
+// how much of a file is read at once when its lines are hashed in batches
+pub const HASH_READ_BUFFER: usize = 1 << 20;
+// lines in each batch handed to a thread
+pub const HASH_BATCH_UNITS: usize = 4096;
+
+type Batch = Vec<Vec<u8>>;
+
+pub fn hash_units<R, F>(reader: &mut UnitReader<R>, hasher: LineHasher, endings: Option<LineEndings>,
+                        threads: usize, mut each: F) -> io::Result<()>
+    where R: BufRead, F: FnMut(&[u8], u64) -> io::Result<()> {
+    // each unit of reader with its hash, in order. line endings are
+    // normalized first when given
+    let threads = if threads == 0 {1} else {threads};
+    let (job_sender, job_receiver) = mpsc::channel::<(usize, Batch)>();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    let (done_sender, done_receiver) = mpsc::channel();
+    let mut workers = vec![];
+    for _ in 0..threads {
+        let (jobs, done) = (job_receiver.clone(), done_sender.clone());
+        workers.push(thread::spawn(move || {
+            loop {
+                let job = jobs.lock().unwrap().recv();
+                let (i, mut batch) = match job {
+                    Ok(job) => job,
+                    Err(_) => return
+                };
+                let hashes: Vec<u64> = batch.iter_mut().map(|unit| {
+                    if let Some(endings) = endings {
+                        endings.normalize(unit);
+                    }
+                    hasher.hash_line(unit)
+                }).collect();
+                if done.send((i, batch, hashes)).is_err() {
+                    return;
+                }
+            }
+        }));
+    }
+    drop(done_sender);
+
+    // two batches a thread keeps them all busy without reading far ahead
+    let result = feed_batches(reader, &job_sender, &done_receiver, threads * 2, &mut each);
+    drop(job_sender);
+    for worker in workers {
+        if worker.join().is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other, "A line hashing thread panicked"));
+        }
+    }
+    result
+}
+
+fn read_batch<R: BufRead>(reader: &mut UnitReader<R>) -> io::Result<Batch> {
+    // up to a batch of units, fewer only at the end of the file
+    let mut batch = Vec::with_capacity(HASH_BATCH_UNITS);
+    let mut unit = vec![];
+    while batch.len() < HASH_BATCH_UNITS {
+        if !try!(reader.read_unit(&mut unit)) {
+            break;
+        }
+        batch.push(mem::replace(&mut unit, vec![]));
+    }
+    Ok(batch)
+}
+
+fn feed_batches<R, F>(reader: &mut UnitReader<R>, jobs: &mpsc::Sender<(usize, Batch)>,
+                      done: &mpsc::Receiver<(usize, Batch, Vec<u64>)>, in_flight: usize, each: &mut F)
+                      -> io::Result<()>
+    where R: BufRead, F: FnMut(&[u8], u64) -> io::Result<()> {
+    let mut sent = 0;
+    let mut next = 0;
+    let mut more = true;
+    // batches that came back ahead of one still being hashed
+    let mut waiting: HashMap<usize, (Batch, Vec<u64>)> = HashMap::new();
+    loop {
+        while more && sent - next < in_flight {
+            let batch = try!(read_batch(reader));
+            if batch.len() < HASH_BATCH_UNITS {
+                trace!("Read the last batch of {} units", batch.len());
+                more = false;
+            }
+            if batch.is_empty() {
+                break;
+            }
+            if jobs.send((sent, batch)).is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other, "Line hashing threads stopped early"));
+            }
+            sent += 1;
+        }
+        if next == sent {
+            debug!("Hashed {} batches", sent);
+            return Ok(());
+        }
+        while !waiting.contains_key(&next) {
+            match done.recv() {
+                Ok((i, batch, hashes)) => {
+                    waiting.insert(i, (batch, hashes));
+                },
+                Err(_) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Line hashing threads stopped early"));
+                }
+            }
+        }
+        let (batch, hashes) = waiting.remove(&next).unwrap();
+        for (unit, &hash) in batch.iter().zip(hashes.iter()) {
+            try!(each(unit, hash));
+        }
+        next += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use test::Bencher;
+
+    use drivers::*;
+    use hashers::*;
+    use lines::*;
+
+    fn sample(lines: usize) -> Vec<u8> {
+        let mut data = vec![];
+        for i in 0..lines {
+            data.extend(format!("line {} of the sample, {}\r\n", i, i * 7919 % 1000).into_bytes());
+        }
+        data
+    }
+
+    fn hash_serial(data: &[u8]) -> Vec<(Vec<u8>, u64)> {
+        let mut reader = UnitReader::new(DiffDriver::Lines, Cursor::new(data), DEFAULT_MAX_LINE_LENGTH).unwrap();
+        let mut found = vec![];
+        let mut line = vec![];
+        while reader.read_unit(&mut line).unwrap() {
+            LineEndings::Normalize.normalize(&mut line);
+            found.push((line.clone(), LineHasher::Fnv.hash_line(&line)));
+        }
+        found
+    }
+
+    fn hash_batched(data: &[u8], threads: usize) -> Vec<(Vec<u8>, u64)> {
+        let mut reader = UnitReader::new(DiffDriver::Lines, Cursor::new(data), DEFAULT_MAX_LINE_LENGTH).unwrap();
+        let mut found = vec![];
+        hash_units(&mut reader, LineHasher::Fnv, Some(LineEndings::Normalize), threads, |line, hash| {
+            found.push((line.to_vec(), hash));
+            Ok(())
+        }).unwrap();
+        found
+    }
+
+    #[test]
+    fn test_hash_units() {
+        // a few batches and a bit, so the last one is short and the threads
+        // can finish out of order
+        let data = sample(HASH_BATCH_UNITS * 5 + 17);
+        let expected = hash_serial(&data);
+        assert_eq!(expected.len(), HASH_BATCH_UNITS * 5 + 17);
+        assert!(!expected[0].0.ends_with(b"\r"));
+        assert_eq!(hash_batched(&data, 1), expected);
+        assert_eq!(hash_batched(&data, 4), expected);
+        assert!(hash_batched(b"", 4).is_empty());
+        // and a file cut off partway through a line
+        assert_eq!(hash_batched(&data[..HASH_BATCH_UNITS * 10], 3), hash_serial(&data[..HASH_BATCH_UNITS * 10]));
+    }
+
+    #[bench]
+    fn bench_hash_serial(b: &mut Bencher) {
+        let data = sample(200000);
+        b.iter(|| hash_serial(&data).len());
+    }
+
+    #[bench]
+    fn bench_hash_batched(b: &mut Bencher) {
+        let data = sample(200000);
+        b.iter(|| hash_batched(&data, 4).len());
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::BufRead;
+
+use std::cmp;
+use std::fmt;
+use std::io;
+
+use config::*;
+
+// how the ends of lines are treated when they're hashed and compared. lines
+// are read without their newline, normalizing drops a carriage return left
+// before it so a file saved with windows line endings matches itself saved
+// with unix ones. set with the line_endings config setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum LineEndings {
+    Exact,
+    Normalize
+}
+
+impl Default for LineEndings {
+    fn default() -> LineEndings {
+        LineEndings::Exact
+    }
+}
+
+impl fmt::Display for LineEndings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LineEndings::Exact => write!(f, "exact"),
+            LineEndings::Normalize => write!(f, "normalize")
+        }
+    }
+}
+
+impl LineEndings {
+    pub fn from_config(config: &Config) -> io::Result<LineEndings> {
+        match config.get("line_endings") {
+            None | Some("exact") => Ok(LineEndings::Exact),
+            Some("normalize") => Ok(LineEndings::Normalize),
+            Some(value) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Config value line_endings = {:?} is not exact or normalize", value)))
+            }
+        }
+    }
+
+    pub fn normalize(&self, line: &mut Vec<u8>) {
+        if *self == LineEndings::Normalize && line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+
+    pub fn normalize_lines(&self, mut lines: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        for line in lines.iter_mut() {
+            self.normalize(line);
+        }
+        lines
+    }
+}
+
+/// Lines longer than this many bytes are cut into blocks of at most this
+/// length, so one huge line never has to fit in memory at once.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 1 << 20;
+
+#[derive(Debug)]
+pub struct LineReader<R: BufRead> {
+    inner: R,
+    // whether the last line read had no terminator
+    missing_newline: bool,
+    // the most bytes a line read holds, anything past it comes back as the
+    // next line
+    max_length: usize
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(inner: R) -> LineReader<R> {
+        LineReader {
+            inner: inner,
+            missing_newline: false,
+            max_length: DEFAULT_MAX_LINE_LENGTH
+        }
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> LineReader<R> {
+        // a line has to hold at least a byte to make progress
+        self.max_length = if max_length == 0 {1} else {max_length};
+        self
+    }
+
+    pub fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<bool> {
+        // read the next line into the buffer without its terminator, so the
+        // last line hashes the same whether or not the file ends in a newline.
+        // a line over the max length is cut, each piece an opaque block with
+        // no terminator of its own
+        line.clear();
+        if line.capacity() > self.max_length {
+            // don't hold on to more than a line can use
+            line.shrink_to_fit();
+        }
+        loop {
+            let (used, end) = {
+                let available = try!(self.inner.fill_buf());
+                if available.is_empty() {
+                    if line.is_empty() {
+                        trace!("No more lines");
+                        return Ok(false);
+                    }
+                    trace!("Line has no terminator");
+                    self.missing_newline = true;
+                    return Ok(true);
+                }
+                let room = self.max_length - line.len();
+                // a terminator right at the max length still ends the line
+                let window = &available[..cmp::min(available.len(), room.saturating_add(1))];
+                match window.iter().position(|&c| c == b'\n') {
+                    Some(end) => {
+                        line.extend(window[..end].iter().cloned());
+                        (end + 1, Some(false))
+                    },
+                    None if room == 0 => {
+                        debug!("Cutting a line at {} bytes", line.len());
+                        (0, Some(true))
+                    },
+                    None => {
+                        let taken = cmp::min(window.len(), room);
+                        line.extend(window[..taken].iter().cloned());
+                        (taken, None)
+                    }
+                }
+            };
+            self.inner.consume(used);
+            if let Some(missing_newline) = end {
+                self.missing_newline = missing_newline;
+                return Ok(true);
+            }
+        }
+    }
+
+    pub fn missing_newline(&self) -> bool {
+        self.missing_newline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    use config::*;
+
+    fn read_all(data: &[u8]) -> (Vec<Vec<u8>>, bool) {
+        let mut reader = LineReader::new(Cursor::new(data));
+        let mut lines = vec![];
+        let mut line = vec![];
+        while reader.read_line(&mut line).unwrap() {
+            lines.push(line.clone());
+        }
+        (lines, reader.missing_newline())
+    }
+
+    #[test]
+    fn test_lines_empty() {
+        let (lines, missing) = read_all(b"");
+        assert!(lines.is_empty());
+        assert_eq!(missing, false);
+    }
+
+    #[test]
+    fn test_lines_one() {
+        let (lines, missing) = read_all(b"one\n");
+        assert_eq!(lines, vec![b"one".to_vec()]);
+        assert_eq!(missing, false);
+    }
+
+    #[test]
+    fn test_lines_no_newline() {
+        let (lines, missing) = read_all(b"one\ntwo");
+        assert_eq!(lines, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(missing, true);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let (lines, _) = read_all(b"one\r\ntwo\r\nthree\r");
+        assert_eq!(LineEndings::Exact.normalize_lines(lines.clone()), lines);
+        assert_eq!(LineEndings::Normalize.normalize_lines(lines),
+                   vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+
+        // only the carriage return at the end goes
+        let mut line = b"a\rb\r\r".to_vec();
+        LineEndings::Normalize.normalize(&mut line);
+        assert_eq!(line, b"a\rb\r".to_vec());
+
+        assert_eq!(LineEndings::from_config(&Config::default()).unwrap(), LineEndings::Exact);
+        let config = Config::parse("line_endings = normalize").unwrap();
+        assert_eq!(LineEndings::from_config(&config).unwrap(), LineEndings::Normalize);
+        assert!(LineEndings::from_config(&Config::parse("line_endings = crlf").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_lines_cut() {
+        fn read_cut(data: &[u8], max_length: usize) -> (Vec<Vec<u8>>, bool) {
+            // a tiny buffer so lines span several fills
+            let mut reader = LineReader::new(BufReader::with_capacity(2, Cursor::new(data)))
+                .with_max_length(max_length);
+            let mut lines = vec![];
+            let mut line = vec![];
+            while reader.read_line(&mut line).unwrap() {
+                assert!(line.len() <= max_length);
+                lines.push(line.clone());
+            }
+            (lines, reader.missing_newline())
+        }
+
+        let (lines, missing) = read_cut(b"abcdefgh\nab\nabcd\n", 4);
+        assert_eq!(lines, vec![b"abcd".to_vec(), b"efgh".to_vec(), b"ab".to_vec(), b"abcd".to_vec()]);
+        assert_eq!(missing, false);
+
+        let (lines, missing) = read_cut(b"one\nabcdefg", 3);
+        assert_eq!(lines, vec![b"one".to_vec(), b"abc".to_vec(), b"def".to_vec(), b"g".to_vec()]);
+        assert_eq!(missing, true);
+
+        // a buffer that grew elsewhere is brought back down to the cap
+        let mut reader = LineReader::new(Cursor::new(b"a\n".to_vec())).with_max_length(16);
+        let mut line = Vec::with_capacity(1 << 16);
+        assert!(reader.read_line(&mut line).unwrap());
+        assert_eq!(line, b"a".to_vec());
+        assert!(line.capacity() < 1 << 16);
+    }
+
+    #[test]
+    fn test_lines_blank() {
+        let (lines, missing) = read_all(b"\n\n");
+        assert_eq!(lines, vec![vec![], vec![]]);
+        assert_eq!(missing, false);
+    }
+}
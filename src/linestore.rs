@@ -0,0 +1,306 @@
+use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::hash::Hasher;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use tree::*;
+use hashers::*;
+use portable::*;
+use delta::*;
+
+// a stored file that starts with this lists runs of lines in the shared
+// store rather than holding the content itself
+pub const LINE_LIST_MAGIC: &'static [u8] = b"\0h2lines\n";
+
+// where a line's content lives in the blob
+#[derive(Debug, Clone, Copy)]
+pub struct LineRef {
+    hash: u64,
+    offset: u64,
+    len: u64
+}
+
+impl Eq for LineRef {}
+
+impl PartialEq for LineRef {
+    fn eq(&self, other: &LineRef) -> bool {
+        self.hash == other.hash
+    }
+}
+
+impl Ord for LineRef {
+    fn cmp(&self, other: &LineRef) -> Ordering {
+        self.hash.cmp(&other.hash)
+    }
+}
+
+impl PartialOrd for LineRef {
+    fn partial_cmp(&self, other: &LineRef) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Portable for LineRef {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_u64(out, self.hash));
+        try!(write_u64(out, self.offset));
+        write_u64(out, self.len)
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<LineRef> {
+        Ok(LineRef {
+            hash: try!(read_u64(input)),
+            offset: try!(read_u64(input)),
+            len: try!(read_u64(input))
+        })
+    }
+}
+
+// a run of lines that sit next to each other in the blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRun {
+    pub offset: u64,
+    pub len: u64
+}
+
+pub fn is_line_list(data: &[u8]) -> bool {
+    data.starts_with(LINE_LIST_MAGIC)
+}
+
+pub fn write_line_list(runs: &[LineRun]) -> Vec<u8> {
+    let mut data = LINE_LIST_MAGIC.to_vec();
+    for run in runs.iter() {
+        data.extend(format!("{:x} {}\n", run.offset, run.len).bytes());
+    }
+    data
+}
+
+pub fn parse_line_list(data: &[u8]) -> io::Result<Vec<LineRun>> {
+    let text = match ::std::str::from_utf8(&data[LINE_LIST_MAGIC.len()..]) {
+        Ok(text) => text,
+        Err(_) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Line list is not valid text"));
+        }
+    };
+    let mut runs = vec![];
+    for line in text.lines() {
+        let mut parts = line.split(' ');
+        let offset = parts.next().and_then(|offset| u64::from_str_radix(offset, 16).ok());
+        let len = parts.next().and_then(|len| len.parse().ok());
+        match (offset, len, parts.next()) {
+            (Some(offset), Some(len), None) => {
+                runs.push(LineRun {
+                    offset: offset,
+                    len: len
+                });
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Bad line list entry: {:?}", line)));
+            }
+        }
+    }
+    Ok(runs)
+}
+
+fn hash_line(line: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(line);
+    hasher.finish()
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LineStoreStats {
+    pub interned: usize,
+    pub shared: usize
+}
+
+// a store of line contents shared between every file in the checkout,
+// so identical lines are only kept once
+#[derive(Debug)]
+pub struct LineStore<T: Read + Write + Seek + fmt::Debug> {
+    index: BufTree<T, LineRef>,
+    blob: T,
+    stats: LineStoreStats
+}
+
+impl LineStore<fs::File> {
+    pub fn open<T: Into<PathBuf>>(path: T) -> io::Result<LineStore<fs::File>> {
+        let path = path.into();
+        debug!("Opening line store at {:?}", &path);
+        try!(fs::create_dir_all(&path));
+
+        let blob = try!(fs::OpenOptions::new().read(true).write(true).create(true).open(path.join("blob")));
+        let index_path = path.join("index");
+        let index = match fs::metadata(&index_path) {
+            Ok(ref data) if data.len() > 0 => {
+                trace!("Opening existing line index");
+                let buffer = try!(fs::OpenOptions::new().read(true).write(true).open(&index_path));
+                try!(unsafe {BufTree::from_buffer(buffer)})
+            },
+            _ => {
+                trace!("Creating line index");
+                let buffer = try!(fs::OpenOptions::new().read(true).write(true).create(true).open(&index_path));
+                try!(BufTree::new_multi(buffer, page_width::<LineRef>()))
+            }
+        };
+
+        Ok(LineStore::new(index, blob))
+    }
+
+    pub fn open_existing<T: Into<PathBuf>>(path: T) -> io::Result<Option<LineStore<fs::File>>> {
+        // only repositories made with --dedup have one
+        let path = path.into();
+        match fs::metadata(&path) {
+            Ok(ref data) if data.is_dir() => LineStore::open(path).map(Some),
+            _ => {
+                trace!("No line store at {:?}", &path);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<T: Read + Write + Seek + fmt::Debug> LineStore<T> {
+    pub fn new(index: BufTree<T, LineRef>, blob: T) -> LineStore<T> {
+        LineStore {
+            index: index,
+            blob: blob,
+            stats: LineStoreStats::default()
+        }
+    }
+
+    pub fn stats(&self) -> LineStoreStats {
+        self.stats
+    }
+
+    pub fn read(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        try!(self.blob.seek(SeekFrom::Start(offset)));
+        let mut data = vec![0; len as usize];
+        let mut read = 0;
+        while read < data.len() {
+            match try!(self.blob.read(&mut data[read..])) {
+                0 => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Line at {} is past the end of the blob", offset)));
+                },
+                n => {
+                    read += n;
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    pub fn intern(&mut self, hash: u64, line: &[u8]) -> io::Result<u64> {
+        // return the offset of this line in the blob, adding it if needed
+        let key = LineRef {
+            hash: hash,
+            offset: 0,
+            len: 0
+        };
+        // hashes can collide, so compare content too
+        for candidate in try!(self.index.get_all(&key)) {
+            if candidate.len == line.len() as u64 &&
+                try!(self.read(candidate.offset, candidate.len)) == line {
+                trace!("Line already in store at {}", candidate.offset);
+                self.stats.shared += 1;
+                return Ok(candidate.offset);
+            }
+        }
+
+        let offset = try!(self.blob.seek(SeekFrom::End(0)));
+        try!(self.blob.write_all(line));
+        try!(self.index.insert(LineRef {
+            hash: hash,
+            offset: offset,
+            len: line.len() as u64
+        }));
+        trace!("Added line to store at {}", offset);
+        self.stats.interned += 1;
+        Ok(offset)
+    }
+
+    pub fn store(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        // the line list a file is kept as once its lines are in the store.
+        // lines added together land next to each other, so a new file is
+        // usually a single run
+        let mut runs: Vec<LineRun> = vec![];
+        for line in raw_lines(data) {
+            let offset = try!(self.intern(hash_line(line), line));
+            let len = line.len() as u64;
+            if let Some(last) = runs.last_mut() {
+                if last.offset + last.len == offset {
+                    last.len += len;
+                    continue;
+                }
+            }
+            runs.push(LineRun {
+                offset: offset,
+                len: len
+            });
+        }
+        Ok(write_line_list(&runs))
+    }
+
+    pub fn expand(&mut self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        // the original content of a stored file, which may be a line list
+        if !is_line_list(&data) {
+            return Ok(data);
+        }
+        let mut content = vec![];
+        for run in try!(parse_line_list(&data)) {
+            content.extend(try!(self.read(run.offset, run.len)));
+        }
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tree::*;
+
+    #[test]
+    fn test_line_store_shares() {
+        let index = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        let mut store = LineStore::new(index, Cursor::new(vec![]));
+        let a = store.intern(1, b"license header").unwrap();
+        let b = store.intern(2, b"fn main() {").unwrap();
+        assert_eq!(store.intern(1, b"license header").unwrap(), a);
+        // a colliding hash with different content gets its own entry
+        let c = store.intern(1, b"something else").unwrap();
+        assert!(c != a && c != b);
+        assert_eq!(store.read(b, 11).unwrap(), b"fn main() {".to_vec());
+        assert_eq!(store.stats().interned, 3);
+        assert_eq!(store.stats().shared, 1);
+    }
+
+    #[test]
+    fn test_line_store_lists() {
+        let index = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        let mut store = LineStore::new(index, Cursor::new(vec![]));
+        let first = b"// license header\nfn one() {}\n".to_vec();
+        let second = b"// license header\nfn two() {}\nno newline".to_vec();
+        let first_list = store.store(&first).unwrap();
+        let second_list = store.store(&second).unwrap();
+        assert!(is_line_list(&first_list) && is_line_list(&second_list));
+
+        // the first file went in as one run, the second shares its header
+        assert_eq!(parse_line_list(&first_list).unwrap().len(), 1);
+        let runs = parse_line_list(&second_list).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0], LineRun {offset: 0, len: 18});
+        assert_eq!(store.stats().shared, 1);
+
+        assert_eq!(store.expand(first_list).unwrap(), first);
+        assert_eq!(store.expand(second_list).unwrap(), second);
+        assert_eq!(store.expand(b"plain\n".to_vec()).unwrap(), b"plain\n".to_vec());
+        assert!(parse_line_list(b"\0h2lines\nzz 1\n").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use std::fs;
+use std::io;
+
+use platform::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    // read-only commands, any number can run at once
+    Shared,
+    // mutating commands, which run alone
+    Exclusive
+}
+
+#[derive(Debug)]
+pub struct RepoLock {
+    file: fs::File,
+    mode: LockMode
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        trace!("Releasing {:?} repository lock", self.mode);
+        if self.mode == LockMode::Exclusive {
+            // don't leave a stale pid around for the next error message
+            let _ = self.file.set_len(0);
+        }
+        unlock_file(&self.file);
+    }
+}
+
+impl RepoLock {
+    pub fn acquire<T: AsRef<Path>>(path: T, mode: LockMode, wait: bool) -> io::Result<RepoLock> {
+        let path = path.as_ref();
+        debug!("Taking {:?} lock on {:?}", mode, path);
+        let mut file = match fs::OpenOptions::new().read(true).write(true).create(true).open(path) {
+            Err(e) => {
+                error!("Failed to open lock file: {}", e);
+                return Err(e);
+            },
+            Ok(f) => f
+        };
+
+        if let Err(e) = lock_file(&file, mode == LockMode::Exclusive, wait) {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                // whoever holds it exclusively left their pid behind
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                          match holder.trim() {
+                                              "" => format!("Repository is locked (use --wait to wait for it)"),
+                                              pid => format!("Repository is locked by pid {} (use --wait to wait for it)", pid)
+                                          }));
+            } else {
+                error!("Failed to lock repository: {}", e);
+                return Err(e);
+            }
+        }
+
+        if mode == LockMode::Exclusive {
+            trace!("Recording our pid in the lock file");
+            try!(file.set_len(0));
+            try!(file.seek(SeekFrom::Start(0)));
+            try!(file.write_all(format!("{}\n", process_id()).as_ref()));
+        }
+
+        Ok(RepoLock {
+            file: file,
+            mode: mode
+        })
+    }
+}
+
+// a lock on one file's index, so a reader never pairs the meta of one
+// version with the trees of another. it's held on the index directory,
+// which stays put while the files in it are replaced
+#[derive(Debug)]
+pub struct IndexLock {
+    dir: fs::File,
+    mode: LockMode
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        trace!("Releasing {:?} index lock", self.mode);
+        unlock_file(&self.dir);
+    }
+}
+
+impl IndexLock {
+    pub fn acquire<T: AsRef<Path>>(dir: T, mode: LockMode) -> io::Result<Option<IndexLock>> {
+        // waits for whoever holds it, which is never for long. none if
+        // there's no index there, or nothing to lock on this platform
+        let dir = dir.as_ref();
+        trace!("Taking {:?} lock on index {:?}", mode, dir);
+        let file = match open_dir(dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No index at {:?}", dir);
+                return Ok(None);
+            },
+            Err(e) => {
+                error!("Failed to open index directory: {}", e);
+                return Err(e);
+            },
+            Ok(None) => {
+                return Ok(None);
+            },
+            Ok(Some(file)) => file
+        };
+        try!(lock_file(&file, mode == LockMode::Exclusive, true));
+        Ok(Some(IndexLock {
+            dir: file,
+            mode: mode
+        }))
+    }
+}
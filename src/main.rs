@@ -1,1000 +1,1309 @@
-#![feature(core)]
-#![feature(hash)]
-#![feature(collections)]
-#![feature(dir_entry_ext)]
-#![feature(path_relative_from)]
-#![feature(associated_consts)]
-#![feature(test)]
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate test;
-extern crate rustc_serialize;
-
-// general TODO:
-// - create our own error type and use that everywhere
-// - unify error handling to be more descriptive (replace try!, unwrap)
-// - move fileops into a separate module so we can mock it out for testing
+extern crate half2;
 
 use std::path::PathBuf;
-use std::collections::HashSet;
-use std::iter::FromIterator;
-use std::cmp::Ordering;
-use std::hash::{hash, SipHasher};
-use std::io::{BufReader, BufRead, Read, Write};
+use std::ffi::OsString;
+use std::str::FromStr;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::error::Error;
+use std::time::Duration;
 
-use rustc_serialize::json;
+use log::LogLevelFilter;
 
-use std::fmt;
 use std::fs;
-use std::io;
-use std::mem;
 use std::env;
+use std::process;
+use std::thread;
+use std::io;
 
-use tree::*;
-
-mod tree;
-
-const INDEX_PLACES_SIZE: usize = 4;
-const FILE_TREE_WIDTH: usize = 6;
-const FILE_BLOCK_LENGTH: usize = 1;
-
-#[derive(Debug)]
-struct Stage {
-    path: PathBuf
-}
-
-#[derive(Debug)]
-struct Checkout {
-    pub path: PathBuf
-}
-
-struct PathInfo {
-    path: PathBuf,
-    pub id: PathBuf,
-    pub metadata: fs::Metadata
-}
-
-#[derive(Debug)]
-struct Logs {
-    path: PathBuf
-}
-
-#[derive(Debug, Clone, Copy)]
-struct IndexPlace {
-    node: usize,
-    offset: isize
-}
-
-// TODO: Improve this structure to include more caching
-struct IndexItem {
-    hash: u64,
-    order: usize,
-    count: usize,
-    places: [IndexPlace; INDEX_PLACES_SIZE]
-}
-
-#[derive(RustcDecodable, RustcEncodable)]
-struct FileMeta {
-    node_count: usize
-}
+use half2::*;
+use half2::repo::*;
+use half2::verify::*;
+use half2::revs::*;
+use half2::profile::*;
+use half2::gc::*;
+use half2::lock::*;
+use half2::archive::*;
+use half2::blame::*;
+use half2::plan::*;
+use half2::oplog::*;
+use half2::stats::*;
+use half2::refs::*;
+use half2::walk::*;
+use half2::ignore::*;
+use half2::instrument::*;
+use half2::platform::*;
+use half2::crypt::*;
+use half2::inspect::*;
+use half2::sync::*;
+use half2::gitexport::*;
+use half2::migrate::*;
+use half2::pathid::*;
+use half2::autosnap::*;
+use half2::storage::*;
+use half2::error::*;
+use half2::timing::*;
+
+// what the process exits with, so scripts can tell outcomes apart
+const EXIT_CHANGES: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_REPO: i32 = 3;
+
+// whether failures are followed by their causes
+static VERBOSE: AtomicBool = ATOMIC_BOOL_INIT;
 
-impl fmt::Debug for IndexItem {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        try!(write!(f, "IndexItem {{ hash: {:?}, order: {:?}, count: {:?}, places: [",
-                    self.hash, self.order, self.count));
-        if self.count > 0 {
-            try!(write!(f, "{:?}", self.places[0]));
-        }
-        if self.count > 1 {
-            for i in 1..self.count as usize {
-                try!(write!(f, ", {:?}", self.places[i]));
+fn main() {
+    // paths are taken as given, everything else has to be text
+    let mut raw_args: Vec<OsString> = env::args_os().collect();
+    let mut args: Vec<String> = raw_args.iter().map(|a| a.to_string_lossy().into_owned()).collect();
+
+    // start up logging, at the level the flags ask for if they do
+    let level = take_verbosity(&mut args, &mut raw_args);
+    let mut builder = env_logger::LogBuilder::new();
+    match level {
+        Some(level) => {
+            builder.filter(None, level);
+        },
+        None => {
+            // failures are reported once, by exit_with, so without any
+            // asking the library's own error logs stay quiet
+            match env::var("RUST_LOG") {
+                Ok(spec) => {
+                    builder.parse(&spec);
+                },
+                Err(_) => {
+                    builder.filter(None, LogLevelFilter::Off);
+                }
             }
         }
-        write!(f, "] }}")
-    }
-}
-
-impl Copy for IndexItem {}
-
-impl Clone for IndexItem {
-    fn clone(&self) -> IndexItem {
-        *self
-    }
-}
-
-impl Eq for IndexItem {}
-
-impl PartialEq for IndexItem {
-    fn eq(&self, other: &IndexItem) -> bool {
-        self.hash == other.hash && self.order == other.order
     }
-}
-
-impl Ord for IndexItem {
-    fn cmp(&self, other: &IndexItem) -> Ordering {
-        if self.hash < other.hash {
-            Ordering::Less
-        } else if self.hash > other.hash {
-            Ordering::Greater
-        } else if self.order < other.order {
-            Ordering::Less
-        } else if self.order > other.order {
-            Ordering::Greater
-        } else {
-            Ordering::Equal
+    match builder.init() {
+        Ok(()) => {
+            trace!("Logger initialization successful");
+        },
+        Err(e) => {
+            exit_with(&H2Error::repository("Failed to start up logging").with_cause(e));
         }
     }
-}
-
-impl PartialOrd for IndexItem {
-    fn partial_cmp(&self, other: &IndexItem) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl fmt::Debug for PathInfo {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PathInfo {{ path: {:?}, id: {:?}, metadata: {{...}} }}", self.path, self.id)
-    }
-}
 
-impl PathInfo {
-    pub fn new<T: Into<PathBuf>, V: Into<PathBuf>>(path: T, id: V, metadata: fs::Metadata) -> PathInfo {
-        PathInfo {
-            path: path.into(),
-            id: id.into(),
-            metadata: metadata
+    VERBOSE.store(args[1..].iter().any(|a| a == "--verbose") ||
+                  level.map_or(false, |level| level >= LogLevelFilter::Info), Ordering::Relaxed);
+    // the most a file can hold and still be staged. the flag and its value
+    // are taken out of the arguments so commands don't mistake the size for
+    // a path
+    let max_file_size = match args.iter().position(|a| a == "--max-file-size") {
+        Some(i) => {
+            let size = match args.get(i + 1).and_then(|size| parse_size(size)) {
+                Some(size) => size,
+                None => {
+                    usage_error("--max-file-size takes a size like 50M");
+                }
+            };
+            for _ in 0..2 {
+                args.remove(i);
+                raw_args.remove(i);
+            }
+            Some(size)
+        },
+        None => None
+    };
+    // a repository kept outside the checkout, which everything after finds
+    // through the environment
+    if let Some(i) = args.iter().position(|a| a == "--repo-dir") {
+        let dir = match raw_args.get(i + 1) {
+            Some(dir) => PathBuf::from(dir.clone()),
+            None => {
+                usage_error("--repo-dir takes a directory");
+            }
+        };
+        let dir = match env::current_dir() {
+            Ok(cwd) => cwd.join(dir),
+            Err(e) => {
+                fail("Finding the working directory failed", e);
+            }
+        };
+        env::set_var(REPO_DIR_VAR, &dir);
+        for _ in 0..2 {
+            args.remove(i);
+            raw_args.remove(i);
         }
     }
-
-    pub fn get_buffer(&self) -> Result<fs::File, io::Error> {
-        fs::File::open(&self.path)
+    // what walks leave out besides the ignore rules: big files, binary ones
+    // and nested repositories
+    let filter = FileFilter {
+        max_file_size: max_file_size,
+        exclude_binary: args[1..].iter().any(|a| a == "--exclude-binary"),
+        include_nested: args[1..].iter().any(|a| a == "--include-nested"),
+        // only from the config
+        reject_special: false
+    };
+    let wait = args[1..].iter().any(|a| a == "--wait");
+    let plan = Plan::new(args[1..].iter().any(|a| a == "--dry-run")).with_report(print_planned);
+    // unreadable entries are skipped and reported at the end, unless strict
+    let errors = WalkErrors::new(args[1..].iter().any(|a| a == "--strict"));
+
+    // counters are always kept, this decides whether they're written out
+    let started = monotonic_ns();
+    let timings = match args.iter().position(|a| a == "--timings") {
+        Some(i) => {
+            args.remove(i);
+            raw_args.remove(i);
+            true
+        },
+        None => false
+    };
+    if args[1..].iter().any(|a| a == "--profile=spans") {
+        set_profile_mode(ProfileMode::Spans);
+    } else if args[1..].iter().any(|a| a == "--profile") {
+        set_profile_mode(ProfileMode::Summary);
     }
 
-    pub fn copy<T: Into<PathBuf>>(&self, to: T) -> Result<(), io::Error> {
-        if self.metadata.is_dir() {
-            trace!("Copying as directory");
-            self.copy_dir(to)
-        } else if self.metadata.is_file() {
-            trace!("Copying as file");
-            self.copy_file(to)
-        } else {
-            error!("{} is neither a file nor a directory", self.path.display());
-            unimplemented!()
-        }
-    }
+    let format = if args[1..].iter().any(|a| a == "--stat") {DiffFormat::Stat} else {DiffFormat::Patch};
+    // changed words are highlighted on a terminal, anywhere else the output
+    // could be a patch someone applies
+    let highlight = if args[1..].iter().any(|a| a == "--no-color") {
+        false
+    } else {
+        args[1..].iter().any(|a| a == "--color") || stdout_is_terminal()
+    };
+    // diff and status set this, it only decides the exit code with --check
+    let mut found_changes = false;
 
-    fn copy_dir<T: Into<PathBuf>>(&self, to: T) -> Result<(), io::Error> {
-        let dest_path = to.into().join(&self.id);
-        debug!("Creating directory at {:?}", &dest_path);
-        match fs::create_dir_all(dest_path) {
-            Err(e) => {
-                error!("Failed to create directory: {}", e);
-                Err(e)
+    if args.len() > 1 && args[1] == "init" {
+        info!("Init in current directory");
+        // snapshots are sealed with a key from the environment, or from a
+        // key file the config remembers
+        let usage = "Usage: h2 init [--dedup] [--backend dir|packed] [--encrypt | --key-file <path>]";
+        let encrypt = match option_value::<String>(&args, "--key-file") {
+            Ok(Some(path)) => Some(KeySource::File(PathBuf::from(path))),
+            Ok(None) if args[2..].iter().any(|a| a == "--encrypt") => Some(KeySource::Env),
+            Ok(None) => None,
+            Err(()) => {
+                usage_error(usage);
+            }
+        };
+        // where indexes are kept, a directory each or appended to packs
+        let backend = match option_value::<String>(&args, "--backend") {
+            Ok(None) => Backend::default(),
+            Ok(Some(name)) => match Backend::from_name(&name) {
+                Some(backend) => backend,
+                None => {
+                    usage_error(usage);
+                }
             },
-            Ok(_) => {
-                trace!("Directory created successfully");
-                Ok(())
+            Err(()) => {
+                usage_error(usage);
             }
-        }
-    }
-
-    fn copy_file<T: Into<PathBuf>>(&self, to: T) -> Result<(), io::Error> {
-        let dest_path = to.into().join(&self.id);
-
-        debug!("Creating parent directory for path");
-        match fs::create_dir_all(dest_path.parent().unwrap()) {
-            Err(e) => {
-                error!("Failed to create parent directory: {}", e);
-                return Err(e);
+        };
+        match init(args[2..].iter().any(|a| a == "--dedup"), backend, encrypt, plan, &errors, filter) {
+            Ok(skipped) => {
+                trace!("Init successful");
+                print_skipped(&skipped);
             },
-            Ok(_) => {
-                trace!("Directory created");
+            Err(e) => {
+                fail("Init failed", e);
             }
         }
-
-        debug!("Copying {:?} to {:?}", &self.path, &dest_path);
-        match fs::copy(&self.path, &dest_path) {
+    } else if args.len() > 1 && args[1] == "commit" {
+        let message = option_value::<String>(&args, "-m")
+            .unwrap_or_else(|_| usage_error("Usage: h2 commit [-m <message>]"));
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Committing stage");
+        match commit(message, plan) {
+            Ok((id, _)) if plan.is_dry_run() => {
+                println!("Would commit revision {}", id);
+            },
+            Ok((id, warnings)) => {
+                println!("Committed revision {}", id);
+                print_warnings(&warnings);
+            },
             Err(e) => {
-                error!("Failed to copy {} to {}: {}", self.path.display(), dest_path.display(), e);
-                Err(e)
+                fail("Commit failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "autosnap" {
+        let usage = "Usage: h2 autosnap [--interval <time>] [--changes <count>] [--daemon]";
+        let interval = match args.iter().position(|a| a == "--interval") {
+            Some(i) => match args.get(i + 1).and_then(|text| parse_interval(text)) {
+                Some(interval) => interval,
+                None => {
+                    usage_error(usage);
+                }
             },
-            Ok(_) => {
-                trace!("Copy succeeded");
-                Ok(())
+            None => DEFAULT_AUTOSNAP_INTERVAL
+        };
+        let policy = AutosnapPolicy {
+            interval: interval,
+            max_changes: option_value(&args, "--changes").unwrap_or_else(|_| usage_error(usage))
+        };
+        let daemon = args[2..].iter().any(|a| a == "--daemon");
+        loop {
+            {
+                // a daemon waits for other commands rather than giving up
+                let _lock = lock_repo(LockMode::Exclusive, wait || daemon);
+                info!("Looking for changes to snapshot");
+                match autosnap(&policy, plan, &errors, filter) {
+                    Ok(Some((id, _))) if plan.is_dry_run() => {
+                        println!("Would snapshot revision {}", id);
+                    },
+                    Ok(Some((id, warnings))) => {
+                        println!("Snapshotted revision {}", id);
+                        print_warnings(&warnings);
+                    },
+                    Ok(None) => {
+                        if !daemon {
+                            println!("No snapshot is due");
+                        }
+                    },
+                    Err(e) => {
+                        fail("Autosnap failed", e);
+                    }
+                }
             }
+            if !daemon {
+                break;
+            }
+            thread::sleep(Duration::from_secs(AUTOSNAP_POLL_INTERVAL));
         }
-    }
-}
-
-impl Default for Stage {
-    fn default() -> Stage {
-        Stage::new("./.h2/stage")
-    }
-}
-
-impl Stage {
-    pub fn new<T: Into<PathBuf>>(path: T) -> Stage {
-        Stage {
-            path: path.into(),
+    } else if args.len() > 1 && args[1] == "revert" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        if args.len() < 3 {
+            usage_error("Usage: h2 revert <path> [--rev <rev>]");
         }
-    }
-
-    pub fn init(&mut self) -> Result<(), io::Error> {
-        info!("Creating Stage");
-        match fs::create_dir_all(&self.path) {
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                trace!("Directory already existed");
-                Ok(())
+        let rev = if args.len() > 4 && args[3] == "--rev" {
+            Some(args[4].as_ref())
+        } else {
+            None
+        };
+        info!("Reverting {}", args[2]);
+        match revert(PathBuf::from(&raw_args[2]), rev, plan) {
+            Ok(()) => {
+                trace!("Revert successful");
             },
             Err(e) => {
-                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
-                Err(e)
-            },
-            Ok(_) => {
-                trace!("Directory created");
-                Ok(())
+                fail("Revert failed", e);
             }
         }
-    }
-
-    pub fn add_path(&mut self, path: &PathInfo) -> Result<(), io::Error> {
-        // initial implementation. Overwrites anything.
-        info!("Adding path {:?}", path);
-        // copy the path to the stage
-        path.copy(&self.path)
-    }
-}
-
-impl Default for Checkout {
-    fn default() -> Checkout {
-        Checkout::new(".")
-    }
-}
-
-impl Checkout {
-    pub fn new<T: Into<PathBuf>>(path: T) -> Checkout {
-        Checkout {
-            path: path.into()
+    } else if args.len() > 1 && args[1] == "tag" {
+        if args.len() < 3 {
+            let _lock = lock_repo(LockMode::Shared, wait);
+            match Repo::open(".").and_then(|_| Refs::default().tags()) {
+                Ok(tags) => {
+                    for (name, rev) in tags {
+                        println!("{} {}", name, rev);
+                    }
+                },
+                Err(e) => {
+                    fail("Listing tags failed", e);
+                }
+            }
+        } else {
+            let _lock = lock_repo(LockMode::Exclusive, wait);
+            let rev = match args.get(3) {
+                Some(rev) if !rev.starts_with("--") => Some(rev.as_ref()),
+                _ => None
+            };
+            info!("Tagging {}", args[2]);
+            match tag(&args[2], rev, plan) {
+                Ok(rev) if plan.is_dry_run() => {
+                    println!("Would tag revision {} as {}", rev, args[2]);
+                },
+                Ok(rev) => {
+                    println!("Tagged revision {} as {}", rev, args[2]);
+                },
+                Err(e) => {
+                    fail("Tag failed", e);
+                }
+            }
         }
-    }
-
-    pub fn init(&mut self) -> Result<(), io::Error> {
-        info!("Creating checkout");
-        match fs::create_dir_all(&self.path) {
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                trace!("Directory already existed");
-                Ok(())
+    } else if args.len() > 1 && args[1] == "branch" {
+        if args.len() < 3 {
+            let _lock = lock_repo(LockMode::Shared, wait);
+            match list_branches() {
+                Ok(lines) => {
+                    for line in lines {
+                        println!("{}", line);
+                    }
+                },
+                Err(e) => {
+                    fail("Listing branches failed", e);
+                }
+            }
+        } else {
+            let _lock = lock_repo(LockMode::Exclusive, wait);
+            let rev = match args.get(3) {
+                Some(rev) if !rev.starts_with("--") => Some(rev.as_ref()),
+                _ => None
+            };
+            info!("Creating branch {}", args[2]);
+            match branch(&args[2], rev, plan) {
+                Ok(rev) if plan.is_dry_run() => {
+                    println!("Would start branch {} at revision {}", args[2], rev);
+                },
+                Ok(rev) => {
+                    println!("Started branch {} at revision {}", args[2], rev);
+                },
+                Err(e) => {
+                    fail("Branch failed", e);
+                }
+            }
+        }
+    } else if args.len() > 1 && args[1] == "switch" {
+        if args.len() < 3 {
+            usage_error("Usage: h2 switch <branch>");
+        }
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Switching to branch {}", args[2]);
+        match switch(&args[2], plan) {
+            Ok(rev) if plan.is_dry_run() => {
+                println!("Would switch to branch {} at revision {}", args[2], rev);
             },
-            Err(e) => {
-                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
-                Err(e)
+            Ok(rev) => {
+                println!("Switched to branch {} at revision {}", args[2], rev);
             },
-            Ok(_) => {
-                trace!("Directory created");
-                Ok(())
+            Err(e) => {
+                fail("Switch failed", e);
             }
         }
-    }
-}
-
-impl Default for Logs {
-    fn default() -> Logs {
-        Logs::new("./.h2/logs")
-    }
-}
-
-impl Logs {
-    pub fn new<T: Into<PathBuf>>(path: T) -> Logs {
-        Logs {
-            path: path.into()
+    } else if args.len() > 1 && args[1] == "merge" {
+        if args.len() < 3 {
+            usage_error("Usage: h2 merge <branch>");
         }
-    }
-
-    pub fn init(&mut self) -> Result<(), io::Error> {
-        info!("Creating logs");
-        match fs::create_dir_all(&self.path) {
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                trace!("Directory already existed");
-                Ok(())
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Merging {}", args[2]);
+        match merge(&args[2], plan) {
+            Ok(ref outcome) if outcome.up_to_date => {
+                println!("Already up to date with {}", args[2]);
+            },
+            Ok(outcome) => {
+                for id in outcome.conflicts.iter() {
+                    println!("conflict {}", escape_id(id));
+                }
+                match outcome.committed {
+                    _ if !outcome.conflicts.is_empty() => {
+                        println!("Merged {} with {} conflicts, resolve them, add them and commit",
+                                 args[2], outcome.conflicts.len());
+                    },
+                    Some(rev) if !plan.is_dry_run() => {
+                        println!("Merged {} as revision {}", args[2], rev);
+                    },
+                    _ => {
+                        println!("Would merge {}", args[2]);
+                    }
+                }
             },
             Err(e) => {
-                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
-                Err(e)
+                fail("Merge failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "stash" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        if args.get(2).map_or(false, |a| a == "pop") {
+            info!("Applying the newest stash entry");
+            match stash_pop(plan) {
+                Ok((entry, count)) if plan.is_dry_run() => {
+                    println!("Would apply stash entry {} to {} files", entry, count);
+                },
+                Ok((entry, count)) => {
+                    println!("Applied stash entry {} to {} files", entry, count);
+                },
+                Err(e) => {
+                    fail("Stash pop failed", e);
+                }
+            }
+        } else {
+            info!("Stashing changes");
+            match stash(plan) {
+                Ok((entry, count)) if plan.is_dry_run() => {
+                    println!("Would stash changes to {} files as entry {}", count, entry);
+                },
+                Ok((entry, count)) => {
+                    println!("Stashed changes to {} files as entry {}", count, entry);
+                },
+                Err(e) => {
+                    fail("Stash failed", e);
+                }
+            }
+        }
+    } else if args.len() > 1 && args[1] == "log" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        info!("Listing revisions");
+        match log(args[2..].iter().any(|a| a == "--graph")) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
             },
-            Ok(_) => {
-                trace!("Directory created");
-                Ok(())
+            Err(e) => {
+                fail("Log failed", e);
             }
         }
-    }
-
-    pub fn diff_path(&self, path: &PathInfo) -> io::Result<()> {
-        let dest_path = self.path.join(&path.id);
-        if !path.metadata.is_file() {
-            // only diff files and then a change
-            error!("Path was not a file: {:?}", path);
-            return Ok(());
+    } else if args.len() > 1 && args[1] == "add" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        let interactive = args[2..].iter().any(|a| a == "-i" || a == "--interactive");
+        let mut paths: Vec<PathBuf> = raw_args[2..].iter().zip(args[2..].iter())
+            .filter(|&(_, a)| !a.starts_with("--") && a != "-i").map(|(raw, _)| PathBuf::from(raw)).collect();
+        if interactive {
+            if paths.is_empty() {
+                paths.push(PathBuf::from("."));
+            }
+            info!("Adding hunks to stage");
+            let stdin = io::stdin();
+            let mut input = stdin.lock();
+            let mut shown: Option<PathBuf> = None;
+            let choose = |offer: &HunkOffer| {
+                // the file header comes before its first hunk
+                if shown.as_ref().map_or(true, |id| id != offer.id) {
+                    println!("--- a/{}", escape_id(offer.id));
+                    println!("+++ b/{}", escape_id(offer.id));
+                    shown = Some(offer.id.to_path_buf());
+                }
+                if offer.unsplit {
+                    println!("This hunk can't be split any further");
+                }
+                for line in offer.lines.iter() {
+                    let mut stdout = io::stdout();
+                    try!(stdout.write_all(line).and_then(|_| stdout.write_all(b"\n")));
+                }
+                prompt_hunk(&mut input)
+            };
+            match add_interactive(&paths, choose, highlight, plan, &errors, filter) {
+                Ok(staged) => {
+                    println!("Staged {} hunks", staged);
+                },
+                Err(e) => {
+                    fail("Add failed", e);
+                }
+            }
         } else {
-            info!("Diffing file: {:?}", path);
+            if paths.is_empty() {
+                usage_error("Usage: h2 add [-i] <path>...");
+            }
+            info!("Adding paths to stage");
+            match add(&paths, plan, &errors, filter) {
+                Ok(skipped) => {
+                    trace!("Add successful");
+                    print_skipped(&skipped);
+                },
+                Err(e) => {
+                    fail("Add failed", e);
+                }
+            }
         }
-
-        debug!("Reading tree at {:?} for file {:?}", &dest_path, path);
-
-        trace!("Opening meta info file");
-        let mut meta_buf = match fs::OpenOptions::new().read(true).write(false).open(dest_path.join("meta")) {
+    } else if args.len() > 1 && args[1] == "rm" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        let paths: Vec<PathBuf> = raw_args[2..].iter().zip(args[2..].iter())
+            .filter(|&(_, a)| !a.starts_with("--")).map(|(raw, _)| PathBuf::from(raw)).collect();
+        if paths.is_empty() {
+            usage_error("Usage: h2 rm [--cached] <path>...");
+        }
+        info!("Removing paths from tracking");
+        match remove(&paths, args[2..].iter().any(|a| a == "--cached"), plan) {
+            Ok(()) => {
+                trace!("Remove successful");
+            },
             Err(e) => {
-                error!("Failed to open meta file: {}", e);
-                return Err(e);
+                fail("Remove failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "repair" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        let paths: Vec<PathBuf> = raw_args[2..].iter().zip(args[2..].iter())
+            .filter(|&(_, a)| !a.starts_with("--")).map(|(raw, _)| PathBuf::from(raw)).collect();
+        if paths.is_empty() == !args[2..].iter().any(|a| a == "--all") {
+            usage_error("Usage: h2 repair (--all | <path>...)");
+        }
+        info!("Repairing indexes");
+        match repair(&paths, plan) {
+            Ok(repairs) => {
+                print_repairs(&repairs);
             },
-            Ok(b) => {
-                trace!("Successfully opened meta file");
-                b
+            Err(e) => {
+                fail("Repair failed", e);
             }
+        }
+    } else if args.len() > 1 && args[1] == "doctor" {
+        let action = if args[2..].iter().any(|a| a == "--keep-working") {
+            DoctorAction::KeepWorking
+        } else if args[2..].iter().any(|a| a == "--fix") {
+            DoctorAction::Fix
+        } else {
+            DoctorAction::Report
         };
-
-        let mut meta_str = String::new();
-        trace!("Reading metadata file");
-        match meta_buf.read_to_string(&mut meta_str) {
-            Err(e) => {
-                error!("Failed to read meta info: {}", e);
-                return Err(e);
+        let _lock = lock_repo(if action == DoctorAction::Report {LockMode::Shared} else {LockMode::Exclusive}, wait);
+        let paths: Vec<PathBuf> = raw_args[2..].iter().zip(args[2..].iter())
+            .filter(|&(_, a)| !a.starts_with("--")).map(|(raw, _)| PathBuf::from(raw)).collect();
+        info!("Cross-checking the stage, indexes and checkout");
+        match doctor(&paths, action, plan) {
+            Ok((checked, diagnoses)) => {
+                print_diagnoses(checked, &diagnoses);
+                let unapplied = diagnoses.iter().filter(|diagnosis| !diagnosis.applied).count();
+                if unapplied > 0 {
+                    exit_with(&H2Error::repository(format!("{} disagreements left unresolved", unapplied)));
+                }
             },
-            Ok(s) => {
-                trace!("Successfully read meta file");
-                s
+            Err(e) => {
+                fail("Doctor failed", e);
             }
-        };
-
-        trace!("Decoding object");
-        let mut meta: FileMeta = match json::decode(meta_str.as_ref()) {
+        }
+    } else if args.len() > 1 && args[1] == "profile" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        info!("Profiling repository");
+        match Repository::open(".")
+            .and_then(|repository| profile_checkout(repository.checkout(), repository.revisions())) {
+            Ok(profile) => {
+                print_profile(&profile);
+            },
             Err(e) => {
-                panic!("Failed to decode meta object: {}", e);
+                fail("Profile failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "stats" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        info!("Collecting repository statistics");
+        match Repository::open(".").and_then(|repository| collect_stats(repository.repo())) {
+            Ok(stats) => {
+                print_stats(&stats);
             },
-            Ok(obj) => {
-                trace!("Successfully decoded meta object");
-                obj
+            Err(e) => {
+                fail("Stats failed", e);
             }
-        };
-
-        trace!("Opening tree file");
-        let tree_buf = match fs::File::open(dest_path.join("content")) {
+        }
+    } else if args.len() > 1 && args[1] == "diff" &&
+        !scope_paths(&args, &raw_args).iter().all(|path| fs::metadata(path).is_ok()) {
+        // arguments that all name paths in the checkout scope a diff against
+        // the stage, anything else is a revision
+        let _lock = lock_repo(LockMode::Shared, wait);
+        // revisions are text, the path is taken as given
+        let specs: Vec<usize> = (2..args.len()).filter(|&i| !args[i].starts_with("--")).collect();
+        let from: &str = &args[specs[0]];
+        let to: Option<&str> = specs.get(1).map(|&i| &args[i][..]);
+        let path = specs.get(2).map(|&i| PathBuf::from(&raw_args[i]));
+        info!("Printing differences from revision {}", from);
+        let stdout = io::stdout();
+        match write_diff_revs(from, to, path.as_ref().map(|path| path.as_path()), format, highlight,
+                              &errors, filter, &mut stdout.lock()) {
+            Ok(changed) => {
+                debug!("Diff successful, {} files differ", changed);
+                found_changes = changed > 0;
+            },
             Err(e) => {
-                error!("Failed to open content buffer: {}", e);
-                return Err(e);
+                fail("Diff failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "diff" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        trace!("Opening repository");
+        match Repo::open(".") {
+            Ok(_) => {
+                trace!("Repository opened successfully");
             },
-            Ok(b) => {
-                trace!("Opened tree file");
-                b
+            Err(e) => {
+                fail("Failed to open repository", e);
             }
-        };
-
-        trace!("Creating tree object");
+        }
 
-        let mut tree: BufTree<_, IndexItem> = match unsafe {BufTree::from_buffer(tree_buf)} {
+        info!("Printing differences against the stage");
+        let checkout = Checkout::default().with_errors(errors.clone()).with_filter(filter);
+        let logs = match open_logs() {
+            Ok(logs) => logs,
             Err(e) => {
-                error!("Failed to create tree object: {}", e);
-                return Err(e);
-            },
-            Ok(t) => {
-                trace!("Tree object created successfully");
-                t
+                fail("Failed to open logs", e);
             }
         };
-
-        debug!("Opening original file");
-        let mut orig = match path.get_buffer() {
+        let stage = match open_stage() {
+            Ok(stage) => stage,
             Err(e) => {
-                error!("Failed to open file: {}", e);
-                return Err(e);
-            },
-            Ok(b) => {
-                trace!("Successfully opened file");
-                // wrap in a buffreader so we can read_line
-                BufReader::new(b)
+                fail("Failed to open stage", e);
             }
         };
-
-        debug!("Comparing lines");
-        let mut offset: isize = 0;
-        let mut new_offset: isize = 0;
-        let mut counter = 0;
-        let mut line = Vec::new();
-        loop {
-            unsafe {line.set_len(0)};
-            trace!("Reading line");
-            match orig.read_until(b'\n', &mut line) {
-                Ok(0) => {
-                    trace!("Done with this file");
-                    break;
+        let paths = scope_paths(&args, &raw_args);
+        let stdout = io::stdout();
+        match scoped_ignore(&checkout, &paths).and_then(|ignore| {
+            write_diff_dir_all(&checkout, &stage, &logs, PathBuf::from("."), &ignore, format, highlight,
+                               &mut stdout.lock())
+        }) {
+            Ok(changed) => {
+                debug!("Diff successful, {} files differ", changed);
+                found_changes = changed > 0;
+            },
+            Err(e) => {
+                fail("Diff failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "status" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        if args[2..].iter().any(|a| a == "--head") {
+            info!("Comparing the stage with HEAD");
+            match stage_matches_head() {
+                Ok(Some((head, true))) => {
+                    println!("Stage matches revision {}", head);
                 },
-                Ok(_) => {
-                    trace!("Got new line: {:?}", String::from_utf8_lossy(&line));
+                Ok(Some((head, false))) => {
+                    println!("Stage differs from revision {}", head);
+                    found_changes = true;
+                },
+                Ok(None) => {
+                    println!("No tree hash to compare with");
                 },
                 Err(e) => {
-                    error!("Failed to read line: {}", e);
-                    return Err(e);
+                    fail("Status failed", e);
                 }
             }
-            trace!("Creating initial item");
-            debug!("Counter {}: {:?}", counter, String::from_utf8_lossy(&line));
-            let mut item = IndexItem {
-                hash: hash::<_, SipHasher>(&line),
-                order: 0,
-                count: 0,
-                places: unsafe {mem::zeroed()}
-            };
-            trace!("Searching in tree");
-            match tree.get(&item) {
-                Err(e) => {
-                    error!("Failed to get item: {}", e);
-                    return Err(e);
-                },
-                Ok(None) => {
-                    info!("New node {}: {:?}", meta.node_count, String::from_utf8_lossy(&line));
-                    if offset != meta.node_count as isize - counter as isize {
-                        info!("Counter {}: offset {}", (counter - 1),
-                              meta.node_count as isize - counter as isize - offset);
-                        new_offset += meta.node_count as isize - counter as isize - offset;
-                        offset = meta.node_count as isize - counter as isize;
-                    }
-                    meta.node_count += 1;
-                },
-                Ok(Some(tree_item)) => {
-                    trace!("Found existing item: {:?}", &tree_item);
-                    // iterate through the places we have
-                    let mut next = None;
-                    let mut place = tree_item.places[0];
-                    let mut diff = new_offset + tree_item.places[0].node as isize - counter as isize - offset;
-                    debug!("Starting place: {:?}", place);
-                    debug!("Starting difference: {}", diff);
-                    for i in 0..tree_item.count {
-                        debug!("Considering place {:?}", tree_item.places[i]);
-                        if counter as isize + offset + tree_item.places[i].offset == tree_item.places[i].node as isize {
-                            // we've foun a match
-                            next = Some(tree_item.places[i]);
-                            debug!("Found a match: {:?}", &tree_item.places[i]);
-                            break;
-                        } else if (new_offset + tree_item.places[i].node as isize -
-                                   counter as isize - offset).abs() < diff.abs() {
-                            diff = new_offset + tree_item.places[i].node as isize -
-                                counter as isize - offset;
-                            place = tree_item.places[i];
-                            debug!("offset {} new_offset {} place.offset {} place.node {}", offset, new_offset, place.offset, place.node);
-                            debug!("Found a better solution {}: {:?}", diff, place);
+        } else {
+            info!("Comparing the checkout with the stage");
+            match status(&scope_paths(&args, &raw_args), &errors, filter) {
+                Ok(changes) => {
+                    if args[2..].iter().any(|a| a == "--porcelain") {
+                        for line in porcelain_status(&changes) {
+                            println!("{}", line);
                         }
-                    }
-
-                    // iterate through the next ones if they exist
-                    if next.is_none() {
-                        trace!("Checking for sub-items");
-                    }
-                    while next.is_none() {
-                        item.order += 1;
-                        match tree.get(&item) {
-                            Err(e) => {
-                                error!("Failed to get item: {}", e);
-                                return Err(e);
-                            },
-                            Ok(None) => {
-                                trace!("Iterated through all sub-items");
-                                break;
-                            },
-                            Ok(Some(other_item)) => {
-                                trace!("Found other sub-item: {:?}", &other_item);
-                                for i in 0..other_item.count {
-                                    debug!("Considering place {:?}", other_item.places[i]);
-                                    if counter as isize + offset + other_item.places[i].offset == other_item.places[i].node as isize {
-                                        // we've foun a match
-                                        next = Some(other_item.places[i]);
-                                        debug!("Found a match: {:?}", &other_item.places[i]);
-                                        break;
-                                    } else if (new_offset + other_item.places[i].node as isize -
-                                               counter as isize - offset).abs() < diff.abs() {
-                                        diff = new_offset + other_item.places[i].node as isize -
-                                            counter as isize - offset;
-                                        place = tree_item.places[i];
-                                        debug!("offset {} new_offset {} place.offset {} place.node {}", offset, new_offset, place.offset, place.node);
-                                        debug!("Found a better solution {}: {:?}", diff, place);
-                                    }
-                                }
-                            }
+                    } else {
+                        for line in render_status(&changes, args[2..].iter().any(|a| a == "-v" || a == "--verbose")) {
+                            println!("{}", line);
                         }
                     }
-
-                    trace!("Finalizing decision");
-                    match next {
-                        Some(place) => {
-                            // our best path doesn't need an offset
-                            trace!("Found matching place");
-                            offset += place.offset;
-                        },
-                        None => {
-                            // new next element
-                            trace!("No matching place, creating new one");
-                            debug!("Closest place: {:?}", place);
-                            info!("Counter {}: offset {}", (counter - 1),
-                                  place.node as isize - counter as isize - offset);
-                            new_offset += place.node as isize - counter as isize - offset;
-                            offset = place.node as isize - counter as isize;
-                        }
+                    found_changes = !changes.is_empty();
+                },
+                Err(e) => {
+                    fail("Status failed", e);
+                }
+            }
+        }
+    } else if args.len() > 1 && args[1] == "gc" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Collecting garbage");
+        match Repository::open(".").and_then(|repository| collect_stage(repository.repo())) {
+            Ok(stats) => {
+                debug!("Garbage collection successful: {:?}", stats);
+                for id in stats.snapshots.iter() {
+                    println!("removed snapshot {}", escape_id(id));
+                    if stats.logs.contains(id) {
+                        println!("removed log {}", escape_id(id));
                     }
                 }
+                println!("{} snapshots kept, {} snapshots, {} logs and {} chunks removed",
+                         stats.kept, stats.snapshots.len(), stats.logs.len(), stats.chunks);
+            },
+            Err(e) => {
+                fail("Garbage collection failed", e);
             }
-
-            trace!("Incrementing counter");
-            counter += 1;
         }
-
-        // TODO: actually change the tree to match, write out info
-        Ok(())
-    }
-
-    pub fn add_path(&mut self, path: &PathInfo) -> io::Result<()> {
-        let dest_path = self.path.join(&path.id);
-        if !path.metadata.is_file() {
-            // only create an index for a file
-            return Ok(());
+    } else if args.len() > 1 && args[1] == "prune" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        let usage = "Usage: h2 prune [--keep-last <count>] [--keep-days <days>] [--keep-auto <count>] \
+                     [--keep-auto-hours <hours>]";
+        let retention = Retention {
+            keep_last: option_value(&args, "--keep-last").unwrap_or_else(|_| usage_error(usage)),
+            keep_days: option_value(&args, "--keep-days").unwrap_or_else(|_| usage_error(usage)),
+            keep_auto: option_value(&args, "--keep-auto").unwrap_or_else(|_| usage_error(usage)),
+            keep_auto_hours: option_value(&args, "--keep-auto-hours").unwrap_or_else(|_| usage_error(usage))
+        };
+        if retention.keep_last.is_none() && retention.keep_days.is_none() && retention.keep_auto.is_none() &&
+            retention.keep_auto_hours.is_none() {
+            usage_error(usage);
         }
-
-        debug!("Creating log directory");
-        match fs::create_dir_all(&dest_path) {
-            Err(e) => {
-                error!("Failed to create parent directory: {}", e);
-                return Err(e);
+        info!("Pruning revisions");
+        match prune(&retention, plan) {
+            Ok(removed) if plan.is_dry_run() => {
+                println!("Would remove {} revisions", removed.len());
             },
-            Ok(_) => {
-                trace!("Parent directory created");
+            Ok(removed) => {
+                println!("Removed {} revisions", removed.len());
+            },
+            Err(e) => {
+                fail("Prune failed", e);
             }
         }
-
-        debug!("Creating tree at {:?} from {:?}", &dest_path, path);
-
-        trace!("Creating meta file");
-        let mut meta = match fs::File::create(dest_path.join("meta")) {
-            Err(e) => {
-                error!("Failed to create meta buffer: {}", e);
-                return Err(e);
+    } else if args.len() > 1 && args[1] == "pack" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Packing indexes");
+        match pack(plan) {
+            Ok(packed) if plan.is_dry_run() => {
+                println!("Would pack {} indexes", packed);
+            },
+            Ok(packed) => {
+                println!("Packed {} indexes", packed);
             },
-            Ok(b) => {
-                trace!("Successfully created meta buffer");
-                b
+            Err(e) => {
+                fail("Pack failed", e);
+            }
+        }
+    } else if args.len() > 1 && (args[1] == "push" || args[1] == "pull") {
+        let pushing = args[1] == "push";
+        let _lock = lock_repo(if pushing {LockMode::Shared} else {LockMode::Exclusive}, wait);
+        let spec = match args[2..].iter().find(|a| !a.starts_with("--")) {
+            Some(spec) => spec.clone(),
+            None => {
+                usage_error(if pushing {"Usage: h2 push <dest>"} else {"Usage: h2 pull <src>"});
             }
         };
-
-        trace!("Creating destination buffer");
-        let dest = match fs::OpenOptions::new().read(true).write(true).create(true).open(dest_path.join("content")) {
+        // whichever side receives revisions is the one that changes
+        let remote = match open_remote(&spec, if pushing {LockMode::Exclusive} else {LockMode::Shared}, wait) {
+            Ok(remote) => remote,
             Err(e) => {
-                error!("Failed to create destination buffer: {}", e);
-                return Err(e);
-            },
-            Ok(b) => {
-                trace!("Successfully created destination buffer");
-                b
+                fail(&format!("Failed to open {}", spec), e);
             }
         };
-
-        trace!("Creating tree object");
-        let mut tree: BufTree<_, IndexItem> = match BufTree::new(dest, FILE_TREE_WIDTH) {
+        info!("{} {}", if pushing {"Pushing to"} else {"Pulling from"}, spec);
+        let (result, verb, failed) = if pushing {
+            (push(&*remote, plan), "Pushed", "Push failed")
+        } else {
+            (pull(&*remote, plan), "Pulled", "Pull failed")
+        };
+        match result {
+            Ok(ref stats) if stats.revisions.is_empty() => {
+                println!("Already up to date");
+            },
+            Ok(ref stats) if plan.is_dry_run() => {
+                println!("Would send {} revisions and {} chunks", stats.revisions.len(), stats.chunks);
+            },
+            Ok(stats) => {
+                println!("{} {} revisions and {} chunks, head is now revision {}",
+                         verb, stats.revisions.len(), stats.chunks, stats.revisions[stats.revisions.len() - 1]);
+            },
             Err(e) => {
-                error!("Failed to create tree: {}", e);
-                return Err(e);
+                fail(failed, e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "blame" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        if args.len() < 3 {
+            usage_error("Usage: h2 blame <path>");
+        }
+        info!("Annotating {}", args[2]);
+        match blame_path(&args[2]) {
+            Ok(lines) => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                for line in render_blame(&lines) {
+                    let _ = stdout.write_all(&line).and_then(|_| stdout.write_all(b"\n"));
+                }
             },
-            Ok(t) => {
-                trace!("Successfully created tree");
-                t
+            Err(e) => {
+                fail("Blame failed", e);
             }
-        };
-
-        trace!("Opening original file");
-        let mut orig = match path.get_buffer() {
+        }
+    } else if args.len() > 1 && args[1] == "show" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        if args.len() < 3 {
+            usage_error("Usage: h2 show [<rev>:]<path>");
+        }
+        info!("Showing {}", args[2]);
+        match show(&args[2]).and_then(|data| io::stdout().write_all(&data)) {
+            Ok(()) => {
+                trace!("Show successful");
+            },
             Err(e) => {
-                error!("Failed to open file: {}", e);
-                return Err(e);
+                fail("Show failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "cat-stage" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        let paths = scope_paths(&args, &raw_args);
+        if paths.len() != 1 {
+            usage_error("Usage: h2 cat-stage [--raw] <path>");
+        }
+        let raw = args[2..].iter().any(|a| a == "--raw");
+        match cat_stage(&paths[0], raw).and_then(|data| io::stdout().write_all(&data)) {
+            Ok(()) => {
+                trace!("Cat successful");
             },
-            Ok(b) => {
-                trace!("Successfully opened file");
-                // wrap in a buffreader so we can read_line
-                BufReader::new(b)
+            Err(e) => {
+                fail("Reading the stage failed", e);
             }
-        };
-
-        debug!("Inserting original lines into tree");
-        let mut line = Vec::new();
-        let mut counter = 0;
-        let mut item;
-        loop {
-            unsafe {line.set_len(0)};
-            trace!("Reading line");
-            match orig.read_until(b'\n', &mut line) {
-                Ok(0) => {
-                    trace!("Done with this file");
-                    break;
-                },
-                Ok(_) => {
-                    trace!("Got new line: {:?}", String::from_utf8_lossy(&line));
-                },
-                Err(e) => {
-                    error!("Failed to read line: {}", e);
-                    return Err(e);
-                }
+        }
+    } else if args.len() > 1 && args[1] == "cat-meta" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        let paths = scope_paths(&args, &raw_args);
+        if paths.len() != 1 {
+            usage_error("Usage: h2 cat-meta <path>");
+        }
+        match index_meta(&paths[0]) {
+            Ok(meta) => {
+                println!("{}", render_meta(&meta));
+            },
+            Err(e) => {
+                fail("Reading the index meta failed", e);
             }
-            trace!("Creating initial item");
-            item = IndexItem {
-                hash: hash::<_, SipHasher>(&line),
-                order: 0,
-                count: 0,
-                // create zeroed memory so it compresses better
-                places: unsafe {mem::zeroed()}
-            };
-            trace!("Merging with tree");
-            loop {
-                match tree.get(&item) {
-                    Err(e) => {
-                        error!("Failed to get tree item: {}", e);
-                        return Err(e);
-                    },
-                    Ok(None) => {
-                        trace!("Creating new tree item");
-                        break;
-                    },
-                    Ok(Some(tree_item)) => {
-                        if tree_item.count >= INDEX_PLACES_SIZE {
-                            trace!("Found full item, incrementing");
-                            item.order += 1;
-                        } else {
-                            trace!("Found item with space, merging");
-                            item = tree_item;
-                            break;
-                        }
-                    }
+        }
+    } else if args.len() > 1 && args[1] == "dump-index" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        let paths = scope_paths(&args, &raw_args);
+        if paths.len() != 1 {
+            usage_error("Usage: h2 dump-index <path>");
+        }
+        match dump_index(&paths[0]) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
                 }
+            },
+            Err(e) => {
+                fail("Dumping the index failed", e);
             }
-            trace!("Inserting element");
-            item.places[item.count] = IndexPlace {
-                node: counter,
-                offset: 0
-            };
-            item.count += 1;
-            debug!("Counter {}: {:?}", counter, String::from_utf8_lossy(&line));
-            trace!("Inserting item into tree");
-            match tree.insert(item) {
-                Ok(_) => {
-                    trace!("Inserted element successfully");
-                },
-                Err(e) => {
-                    error!("Failed to insert element: {}", e);
-                    return Err(e);
+        }
+    } else if args.len() > 1 && args[1] == "oplog" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        info!("Reading operation log");
+        match Repo::open(".").and_then(|_| OpLog::default().read()) {
+            Ok(records) => {
+                for line in render_oplog(&records) {
+                    println!("{}", line);
                 }
+            },
+            Err(e) => {
+                fail("Reading operation log failed", e);
             }
-            trace!("Incrementing counter");
-            counter += 1;
         }
-        trace!("Finished inserting lines");
-
-        debug!("Saving meta info");
-        trace!("Creating meta object");
-        let meta_info = FileMeta {
-            node_count: counter
-        };
-        trace!("Creating json");
-        let data = match json::encode(&meta_info) {
+    } else if args.len() > 1 && args[1] == "undo" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Undoing the last operation");
+        match undo(plan) {
+            Ok(ref record) if plan.is_dry_run() => {
+                println!("Would undo {}", record.op);
+            },
+            Ok(record) => {
+                println!("Undid {}", record.op);
+            },
             Err(e) => {
-                panic!("Failed to encode to json: {}", e)
+                fail("Undo failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "export" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        if args.len() < 3 {
+            usage_error("Usage: h2 export <file>");
+        }
+        info!("Exporting repository to {}", args[2]);
+        match Repository::open(".").and_then(|repository| export_repo(repository.repo(), &args[2])) {
+            Ok(count) => {
+                println!("Exported {} entries to {}", count, args[2]);
             },
-            Ok(d) => {
-                trace!("Data encoded successfully");
-                d
+            Err(e) => {
+                fail("Export failed", e);
             }
+        }
+    } else if args.len() > 1 && args[1] == "apply" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        let fuzz = match args[2..].iter().position(|a| a == "--fuzz") {
+            Some(i) => match args.get(i + 3).and_then(|fuzz| fuzz.parse().ok()) {
+                Some(fuzz) => fuzz,
+                None => {
+                    usage_error("Usage: h2 apply [--stage] [--fuzz <lines>] [<patch>]");
+                }
+            },
+            None => 0
+        };
+        // the patch is the first argument that isn't a flag or its value
+        let source = raw_args[2..].iter().zip(args[2..].iter()).enumerate()
+            .filter(|&(i, (_, a))| !a.starts_with("--") && !(i > 0 && args[i + 1] == "--fuzz"))
+            .map(|(_, (raw, _))| PathBuf::from(raw)).next();
+        let mut patch = vec![];
+        let read = match source {
+            Some(ref path) if path.to_str() != Some("-") => fs::File::open(path).and_then(|mut f| f.read_to_end(&mut patch)),
+            _ => io::stdin().read_to_end(&mut patch)
         };
-        trace!("Writing to file");
-        match meta.write_all(data.as_ref()) {
+        if let Err(e) = read {
+            fail("Failed to read the patch", e);
+        }
+        info!("Applying patch");
+        match apply(&patch, args[2..].iter().any(|a| a == "--stage"), fuzz, plan) {
+            Ok(count) if plan.is_dry_run() => {
+                println!("Would patch {} files", count);
+            },
+            Ok(count) => {
+                println!("Patched {} files", count);
+            },
             Err(e) => {
-                error!("Failed to write meta info to file: {}", e);
-                return Err(e);
+                fail("Apply failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "import" {
+        if args.len() < 3 {
+            usage_error("Usage: h2 import <file>");
+        }
+        info!("Importing repository from {}", args[2]);
+        match import_repo(&args[2], &Repo::new(".")) {
+            Ok(count) => {
+                println!("Imported {} entries from {}", count, args[2]);
             },
-            Ok(()) => {
-                trace!("Meta info written to file successfully");
+            Err(e) => {
+                fail("Import failed", e);
             }
         }
-        Ok(())
-    }
-}
-
-fn main() {
-    // start up logging
-    match env_logger::init() {
-        Ok(()) => {
-            trace!("Logger initialization successful");
-        },
-        Err(e) => {
-            panic!("Failed to start up logging: {}", e);
+    } else if args.len() > 1 && args[1] == "import-git" {
+        if args.len() < 3 {
+            usage_error("Usage: h2 import-git <path>");
         }
-    }
-
-    trace!("Getting command-line arguments");
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() > 1 && args[1] == "init" {
-        info!("Init in current directory");
-        match init() {
-            Ok(()) => {
-                trace!("Init successful");
+        info!("Importing git history from {}", args[2]);
+        match import_git(&PathBuf::from(&raw_args[2]), plan, &errors, filter) {
+            Ok(imported) => {
+                for &(rev, ref hash) in imported.iter() {
+                    println!("{} {}", rev, hash);
+                }
+                println!("{} {} commits", if plan.is_dry_run() {"Would import"} else {"Imported"}, imported.len());
             },
             Err(e) => {
-                panic!("Init failed: {}", e);
+                fail("Import failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "export-git" {
+        let _lock = lock_repo(LockMode::Shared, wait);
+        let usage = "Usage: h2 export-git [<from>..<to>] [--branch <name>] [--committer <ident>]";
+        let mut options = FastExportOptions::default();
+        if let Some(branch) = option_value(&args, "--branch").unwrap_or_else(|_| usage_error(usage)) {
+            options.branch = branch;
+        }
+        if let Some(committer) = option_value(&args, "--committer").unwrap_or_else(|_| usage_error(usage)) {
+            options.committer = committer;
+        }
+        // the range is the first argument that isn't a flag or its value
+        let range = args[2..].iter().enumerate()
+            .filter(|&(i, a)| !a.starts_with("--") && !(i > 0 && (args[i + 1] == "--branch" || args[i + 1] == "--committer")))
+            .map(|(_, a)| &a[..]).next();
+        info!("Exporting revisions as a git fast-import stream");
+        let stdout = io::stdout();
+        match export_git(range, &options, &mut stdout.lock()) {
+            Ok(count) => {
+                debug!("Exported {} revisions", count);
+            },
+            Err(e) => {
+                fail("Export failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "migrate" {
+        let _lock = lock_repo(LockMode::Exclusive, wait);
+        info!("Migrating repository in current directory");
+        match migrate(&Repo::new("."), plan) {
+            Ok(ref steps) if steps.is_empty() => {
+                println!("Repository is already at format version {}", FORMAT_VERSION);
+            },
+            Ok(steps) => {
+                for step in steps.iter() {
+                    println!("{} {} to {}: {}", if plan.is_dry_run() {"Would migrate"} else {"Migrated"},
+                             step.from, step.from + 1, step.summary);
+                }
+            },
+            Err(e) => {
+                fail("Migrate failed", e);
+            }
+        }
+    } else if args.len() > 1 && args[1] == "verify" {
+        info!("Verifying repository in current directory");
+        let action = if args[2..].iter().any(|a| a == "--adopt") {
+            OrphanAction::Adopt
+        } else if args[2..].iter().any(|a| a == "--prune") {
+            OrphanAction::Prune
+        } else {
+            OrphanAction::Report
+        };
+        let _lock = lock_repo(if action == OrphanAction::Report {LockMode::Shared} else {LockMode::Exclusive}, wait);
+        let repo = Repo::new(".");
+        match verify_repo(&repo).and_then(|checked| Ok((checked, try!(verify_stage(&repo, action))))) {
+            Ok((checked, staged)) => {
+                print_repo_report(&checked);
+                print_stage_report(&staged);
+                if !checked.is_ok() || !staged.is_ok() {
+                    exit_with(&H2Error::repository("Repository is corrupt"));
+                }
+                trace!("Verify successful");
+            },
+            Err(e) => {
+                fail("Verify failed", e);
             }
         }
     } else {
-        let checkout = Checkout::default();
+        let _lock = lock_repo(LockMode::Shared, wait);
+        trace!("Opening repository");
+        match Repo::open(".") {
+            Ok(_) => {
+                trace!("Repository opened successfully");
+            },
+            Err(e) => {
+                fail("Failed to open repository", e);
+            }
+        }
+
+        let checkout = Checkout::default().with_errors(errors.clone()).with_filter(filter);
         //let stage = Stage::default();
-        let logs = Logs::default();
+        let logs = match open_logs() {
+            Ok(logs) => logs.with_stat_cache(!args[1..].iter().any(|a| a == "--no-cache")),
+            Err(e) => {
+                fail("Failed to open logs", e);
+            }
+        };
 
         info!("Walking current directory");
-        match diff_dir_all(&checkout, &logs, PathBuf::from("."), vec![".h2", ".git", "target", "perf.data", "src"]) {
-            Ok(()) => {
-                debug!("Walk successful");
+        match load_ignore(&checkout).and_then(|ignore| diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore)) {
+            Ok(changed) => {
+                debug!("Walk successful, {} files differ", changed.len());
+                for id in changed.iter() {
+                    println!("{}", escape_id(id));
+                }
+                found_changes = !changed.is_empty();
             },
             Err(e) => {
-                panic!("Walk failed: {}", e);
+                fail("Walk failed", e);
             }
         }
     }
-}
 
-fn init() -> Result<(), io::Error> {
-    info!("Creating half2 directories");
-
-    debug!("Creating ./.h2");
-    match fs::create_dir("./.h2") {
-        Err(e) => {
-            error!("Failed to create directory \".h2\": {}", e);
-            return Err(e);
-        },
-        Ok(_) => {
-            trace!("Directory created");
-        }
+    print_summary(monotonic_ns() - started);
+    if timings {
+        print_timings(monotonic_ns() - started);
     }
 
-    trace!("Creating checkout object");
-    let mut checkout = Checkout::default();
-    debug!("Initializing checkout");
-    match checkout.init() {
-        Ok(()) => {
-            trace!("Checkout creation successful");
-        },
-        Err(e) => {
-            error!("Checkout creation failed: {}", e);
-            return Err(e);
-        }
+    // the command got through, but not everything it walked could be read
+    let unreadable = errors.report();
+    if unreadable > 0 {
+        exit_with(&H2Error::repository(format!("{} {} could not be read", unreadable,
+                                                 if unreadable == 1 {"entry"} else {"entries"})));
     }
-    
-    trace!("Creating Stage object");
-    let mut stage = Stage::default();
-    debug!("Initializing stage");
-    match stage.init() {
-        Ok(()) => {
-            trace!("Stage creation successful");
-        },
-        Err(e) => {
-            error!("Stage creation failed: {}", e);
-            return Err(e);
-        }
+    if found_changes && args[1..].iter().any(|a| a == "--check") {
+        process::exit(EXIT_CHANGES);
     }
+}
 
-    trace!("Creating Logs object");
-    let mut logs = Logs::default();
-    debug!("Initializing logs");
-    match logs.init() {
-        Ok(()) => {
-            trace!("Logs creation successful");
-        },
-        Err(e) => {
-            error!("Logs creation failed: {}", e);
-            return Err(e);
+fn take_verbosity(args: &mut Vec<String>, raw_args: &mut Vec<OsString>) -> Option<LogLevelFilter> {
+    // -q, -v, -vv and -vvv before the command, taken out so it doesn't see
+    // them. after it they're the command's own, like status -v. without
+    // any RUST_LOG decides, as it always has
+    let mut quiet = false;
+    let mut count = 0;
+    let mut i = 1;
+    while i < args.len() && args[i].starts_with("-") {
+        if args[i] == "-q" {
+            quiet = true;
+        } else if args[i].len() > 1 && args[i].starts_with("-") && args[i][1..].chars().all(|c| c == 'v') {
+            count += args[i].len() - 1;
+        } else {
+            i += 1;
+            continue;
         }
+        args.remove(i);
+        raw_args.remove(i);
     }
-    
-    info!("Walking current directory");
-    match stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), vec![".h2", ".git", "target", "perf.data", "src"]) {
-        Ok(()) => {
-            debug!("Walk successful");
-        },
-        Err(e) => {
-            error!("Walk failed: {}", e);
-            return Err(e);
-        }
+    match count {
+        _ if quiet => Some(LogLevelFilter::Off),
+        0 => None,
+        1 => Some(LogLevelFilter::Info),
+        2 => Some(LogLevelFilter::Debug),
+        _ => Some(LogLevelFilter::Trace)
     }
+}
 
-    Ok(())
+fn scope_paths(args: &[String], raw_args: &[OsString]) -> Vec<PathBuf> {
+    // the paths a command was limited to, everything after the command name
+    // that isn't a flag
+    raw_args[2..].iter().zip(args[2..].iter())
+        .filter(|&(_, a)| !a.starts_with("-")).map(|(raw, _)| PathBuf::from(raw)).collect()
 }
 
-fn stage_dir_all<T: Into<PathBuf>, V: IntoIterator>(checkout: &Checkout, logs: &mut Logs, stage: &mut Stage, path: T, ignore: V)
-                                                    -> Result<(), io::Error> where V::Item: Into<PathBuf> {
-    let mut to_visit = vec![checkout.path.join(path.into())];
-    let to_ignore: HashSet<PathBuf> = HashSet::from_iter(ignore.into_iter().map(|x| {x.into()}));
-
-    info!("Copying directory tree");
-    while !to_visit.is_empty() {
-        trace!("Popping directory from queue");
-        let dir = to_visit.pop().unwrap();
-        debug!("Reading directory {:?}", dir);
-        for item in match fs::read_dir(dir) {
-            Ok(iter) => {
-                trace!("Got directory iterator");
-                iter
-            },
-            Err(e) => {
-                error!("Failed to read directory: {}", e);
-                return Err(e);
-            }
-        } {
-            let entry = match item {
-                Ok(item) => {
-                    trace!("No new error");
-                    item
-                },
-                Err(e) => {
-                    error!("Error reading directory: {}", e);
-                    return Err(e);
-                }
-            };
+fn option_value<T: FromStr>(args: &[String], name: &str) -> Result<Option<T>, ()> {
+    // the value after a flag, an error if the flag is there without a usable value
+    match args.iter().position(|a| a == name) {
+        Some(i) => args.get(i + 1).and_then(|value| value.parse().ok()).map(Some).ok_or(()),
+        None => Ok(None)
+    }
+}
 
-            trace!("Getting path relative to checkout directory");
-            let id = match entry.path().relative_from(&checkout.path) {
-                Some(id) => {
-                    trace!("Got path relative_from successfully");
-                    PathBuf::from(id)
-                },
-                None => {
-                    panic!("Failed to get path relative to checkout path");
-                }
-            };
+fn print_planned(op: &Op) {
+    // what a dry run would have done
+    println!("would {}", op);
+}
 
-            trace!("Entry path: {:?}", entry.path());
-            trace!("Entry id: {:?}", &id);
-            if to_ignore.contains(&id) {
-                // ignore our own directory
-                trace!("Path was in ignore set");
-                continue;
-            }
+fn print_warnings(warnings: &[String]) {
+    // things that went wrong after the command was done, so it still succeeded
+    for warning in warnings.iter() {
+        let _ = writeln!(io::stderr(), "warning: {}", warning);
+    }
+}
 
-            trace!("Getting file metadata");
-            let metadata = match entry.metadata() {
-                Ok(data) => {
-                    trace!("Got metadata");
-                    data
-                },
-                Err(e) => {
-                    error!("Could not get file metadata: {}", e);
-                    return Err(e);
-                }
-            };
+fn print_skipped(skipped: &[(PathBuf, SkipReason)]) {
+    // paths that would have been staged if not for the filter
+    for &(ref id, ref reason) in skipped.iter() {
+        println!("skipped {} ({})", escape_id(id), reason);
+    }
+}
 
-            if metadata.is_dir() {
-                trace!("Adding path to visit queue");
-                to_visit.push(entry.path());
-            } else {
-                trace!("Not adding path to visit queue");
+fn print_repairs(repairs: &[Repair]) {
+    // what was found and done for each file, and what was lost
+    for repair in repairs.iter() {
+        let id = escape_id(&repair.id);
+        let problem = repair.problem.clone().unwrap_or(String::new());
+        match repair.outcome {
+            RepairOutcome::Intact => println!("ok      {}", id),
+            RepairOutcome::FromStage => println!("rebuilt {}: {}", id, problem),
+            RepairOutcome::FromCheckout => {
+                println!("rebuilt {}: {} (from the checkout, what was staged is lost)", id, problem)
+            },
+            RepairOutcome::Dropped => {
+                println!("dropped {}: {} (nothing staged or checked out to rebuild from)", id, problem)
             }
-            
-            trace!("Creating path info object");
-            let info = PathInfo::new(entry.path(), id, metadata);
+        }
+    }
+    let broken = repairs.iter().filter(|repair| repair.outcome != RepairOutcome::Intact).count();
+    println!("{} checked, {} repaired", repairs.len(), broken);
+}
 
-            debug!("Adding path to stage");
-            match stage.add_path(&info) {
-                Ok(()) => {
-                    trace!("Add path succeeded");
-                },
-                Err(e) => {
-                    error!("Add path failed: {}", e);
-                    return Err(e);
-                }
-            }
+fn print_diagnoses(checked: usize, diagnoses: &[Diagnosis]) {
+    // each disagreement with its resolution, then how many files were
+    // checked and how many still disagree
+    for diagnosis in diagnoses.iter() {
+        println!("{:<9} {}: {}; {}{}", diagnosis.problem.to_string(), escape_id(&diagnosis.id), diagnosis.detail,
+                 diagnosis.resolution, if diagnosis.applied {" (done)"} else {""});
+    }
+    let left = diagnoses.iter().filter(|diagnosis| !diagnosis.applied).count();
+    println!("{} checked, {} disagree, {} resolved", checked, diagnoses.len(), diagnoses.len() - left);
+}
 
-            debug!("Creating file index");
-            match logs.add_path(&info) {
-                Ok(()) => {
-                    trace!("Index creation successful");
-                },
-                Err(e) => {
-                    error!("Index creation failed: {}", e);
-                    return Err(e);
-                }
-            }
-        }
+fn print_profile(profile: &Profile) {
+    println!("files:         {}", profile.files);
+    println!("total size:    {} bytes", profile.total_size);
+    println!("largest file:  {} bytes", profile.largest);
+    println!("average lines: {}", profile.average_lines());
+    println!("size distribution:");
+    let labels = ["<= 1K", "<= 16K", "<= 256K", "<= 4M", "> 4M"];
+    for i in 0..labels.len() {
+        println!("  {:>8}: {}", labels[i], profile.buckets[i]);
     }
+    println!("revisions:     {}", profile.revisions);
+    println!("churn:         {} files changed in the last revision", profile.churn);
+    println!("");
+    println!("# recommended settings for .h2/config");
+    println!("tree_width = {}", profile.tree_width());
+    println!("cache_size = {}", profile.cache_size());
+    println!("threads = {}", profile.threads());
+    println!("chunking = {}", if profile.chunking() {"on"} else {"off"});
+}
 
-    trace!("Init finished");
-    Ok(())
+fn print_stats(stats: &RepoStats) {
+    println!("tracked files:  {} ({} inline)", stats.files, stats.inline);
+    println!("stage size:     {} bytes", stats.stage_bytes);
+    println!("index size:     {} bytes", stats.index_bytes);
+    println!("lines indexed:  {}", stats.lines);
+    println!("tree nodes:     {} ({} free)", stats.nodes, stats.free_nodes);
+    println!("average depth:  {:.2}", stats.average_depth());
+    println!("fill factor:    {:.2}", stats.fill_factor());
 }
 
-fn diff_dir_all<T: Into<PathBuf>, V: IntoIterator>(checkout: &Checkout, logs: &Logs, path: T, ignore: V)
-                                                   -> Result<(), io::Error> where V::Item: Into<PathBuf> {
-    let mut to_visit = vec![checkout.path.join(path.into())];
-    let to_ignore: HashSet<PathBuf> = HashSet::from_iter(ignore.into_iter().map(|x| {x.into()}));
-
-    info!("Diffing directory tree");
-    while !to_visit.is_empty() {
-        trace!("Popping directory from queue");
-        let dir = to_visit.pop().unwrap();
-        debug!("Reading directory {:?}", dir);
-        for item in match fs::read_dir(dir) {
-            Ok(iter) => {
-                trace!("Got directory iterator");
-                iter
-            },
-            Err(e) => {
-                error!("Failed to read directory: {}", e);
-                return Err(e);
-            }
-        } {
-            let entry = match item {
-                Ok(item) => {
-                    trace!("No new error");
-                    item
-                },
-                Err(e) => {
-                    error!("Error reading directory: {}", e);
-                    return Err(e);
-                }
-            };
+fn print_check(check: &Check) {
+    match check.problem {
+        None => println!("ok   {}", check.subject),
+        Some(ref problem) => println!("FAIL {}: {}", check.subject, problem)
+    }
+}
 
-            trace!("Getting path relative to checkout directory");
-            let id = match entry.path().relative_from(&checkout.path) {
-                Some(id) => {
-                    trace!("Got path relative_from successfully");
-                    PathBuf::from(id)
-                },
-                None => {
-                    panic!("Failed to get path relative to checkout path");
-                }
-            };
+fn print_repo_report(report: &RepoReport) {
+    // the header first, the files are only checked if it's usable
+    print_check(&report.header);
+    if report.header.problem.is_some() {
+        return;
+    }
+    for check in report.files.iter() {
+        print_check(check);
+    }
+    println!("{} files checked, {} failed", report.files.len(), report.failed());
+}
+
+fn print_stage_report(report: &StageReport) {
+    for orphan in report.orphans.iter() {
+        let id = escape_id(&orphan.id);
+        println!("orphan  {}{}", id, if orphan.unfinished {" (unfinished write)"} else {""});
+        match orphan.outcome {
+            OrphanOutcome::Left => {},
+            OrphanOutcome::Adopted => println!("adopted {}", id),
+            OrphanOutcome::Pruned => println!("pruned  {}", id),
+            OrphanOutcome::NotAdopted(ref e) => println!("FAIL    {}: could not adopt: {}", id, e),
+            OrphanOutcome::NotPruned(ref e) => println!("FAIL    {}: could not prune: {}", id, e)
+        }
+    }
+    for id in report.missing.iter() {
+        println!("missing {}", escape_id(id));
+    }
+    let resolved = report.orphans.iter().filter(|orphan| orphan.is_resolved()).count();
+    println!("{} orphaned ({} resolved), {} missing from stage", report.orphans.len(), resolved,
+             report.missing.len());
+}
 
-            trace!("Entry path: {:?}", entry.path());
-            trace!("Entry id: {:?}", &id);
-            if to_ignore.contains(&id) {
-                // ignore our own directory
-                trace!("Path was in ignore set");
-                continue;
+fn prompt_hunk<R: io::BufRead>(input: &mut R) -> io::Result<HunkChoice> {
+    loop {
+        print!("Stage this hunk [y,n,s,q,?]? ");
+        try!(io::stdout().flush());
+        let mut answer = String::new();
+        if try!(input.read_line(&mut answer)) == 0 {
+            // nothing more to read, treat it like a quit
+            println!("");
+            return Ok(HunkChoice::Quit);
+        }
+        match answer.trim() {
+            "y" => return Ok(HunkChoice::Stage),
+            "n" => return Ok(HunkChoice::Skip),
+            "s" => return Ok(HunkChoice::Split),
+            "q" => return Ok(HunkChoice::Quit),
+            _ => {
+                println!("y - stage this hunk");
+                println!("n - do not stage this hunk");
+                println!("s - split this hunk into smaller ones");
+                println!("q - quit, staging only what was accepted so far");
             }
+        }
+    }
+}
 
-            trace!("Getting file metadata");
-            let metadata = match entry.metadata() {
-                Ok(data) => {
-                    trace!("Got metadata");
-                    data
-                },
-                Err(e) => {
-                    error!("Could not get file metadata: {}", e);
-                    return Err(e);
-                }
-            };
+fn usage_error(message: &str) -> ! {
+    exit_with(&H2Error::usage(message));
+}
 
-            if metadata.is_dir() {
-                trace!("Adding path to visit queue");
-                to_visit.push(entry.path());
-            } else {
-                trace!("Not adding path to visit queue");
-            }
-            
-            trace!("Creating path info object");
-            let info = PathInfo::new(entry.path(), id, metadata);
+fn fail(what: &str, e: io::Error) -> ! {
+    exit_with(&H2Error::repository(what).with_cause(e));
+}
 
-            debug!("Creating file index");
-            match logs.diff_path(&info) {
-                Ok(()) => {
-                    trace!("Index creation successful");
-                },
-                Err(e) => {
-                    error!("Index creation failed: {}", e);
-                    return Err(e);
-                }
-            }
+fn exit_with(e: &H2Error) -> ! {
+    // the one place an error decides the exit code. one line for scripts
+    // and people, the kind and causes with --verbose
+    let code = match e.kind() {
+        ErrorKind::Usage => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            EXIT_USAGE
+        },
+        ErrorKind::Repository => {
+            let _ = writeln!(io::stderr(), "h2: {}", e);
+            EXIT_REPO
+        }
+    };
+    if VERBOSE.load(Ordering::Relaxed) {
+        let _ = writeln!(io::stderr(), "  kind: {:?}", e.kind());
+        let mut cause = e.cause();
+        while let Some(inner) = cause {
+            let _ = writeln!(io::stderr(), "  caused by: {}", inner);
+            cause = inner.cause();
         }
     }
+    process::exit(code);
+}
+
+fn lock_repo(mode: LockMode, wait: bool) -> Option<RepoLock> {
+    // without a repository there's nothing to lock, opening it will say so
+    let repo = match Repo::discover(".") {
+        Ok(repo) => repo,
+        Err(_) => {
+            trace!("No repository to lock");
+            return None;
+        }
+    };
 
-    trace!("Init finished");
-    Ok(())
+    match repo.lock(mode, wait) {
+        Ok(lock) => {
+            trace!("Repository locked");
+            Some(lock)
+        },
+        Err(e) => {
+            fail("Failed to lock repository", e);
+        }
+    }
 }
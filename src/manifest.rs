@@ -0,0 +1,507 @@
+use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, Write};
+use std::hash::Hasher;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use map::*;
+use hashers::*;
+use portable::*;
+use pathid::*;
+use pack::*;
+use fileops::*;
+
+// the running tree hash, kept next to the manifest's own buffers
+pub const TREE_HASH_FILE: &'static str = "tree_hash";
+
+// what the repository knows about a tracked path without opening its log
+// directory: where its index lives, and the stat info and content hash it
+// was indexed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    // the path id, as bytes
+    pub id: Vec<u8>,
+    // the log directory holding the index, relative to the logs, when it
+    // isn't packed
+    pub index: Vec<u8>,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub content_hash: u64,
+    pub lines: u64,
+    // where the index is packed, none if it's still in its own directory
+    pub pack: Option<PackLocation>,
+    // hash of the staged copy, when it was read back and checked as it was
+    // made
+    pub stage_hash: Option<u64>,
+    // a directory with these permission bits, none for a file
+    pub dir_mode: Option<u32>
+}
+
+impl Portable for ManifestEntry {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_bytes(out, &self.id));
+        try!(write_bytes(out, &self.index));
+        try!(write_u64(out, self.size));
+        try!(write_u64(out, self.mtime as u64));
+        try!(write_u64(out, self.mtime_nsec as u64));
+        try!(write_u64(out, self.content_hash));
+        try!(write_u64(out, self.lines));
+        // a bit for each optional part that follows
+        let parts = (if self.pack.is_some() {1} else {0}) | (if self.stage_hash.is_some() {2} else {0}) |
+            (if self.dir_mode.is_some() {4} else {0});
+        try!(write_u64(out, parts));
+        if let Some(ref location) = self.pack {
+            try!(location.write_portable(out));
+        }
+        if let Some(hash) = self.stage_hash {
+            try!(write_u64(out, hash));
+        }
+        if let Some(mode) = self.dir_mode {
+            try!(write_u64(out, mode as u64));
+        }
+        Ok(())
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<ManifestEntry> {
+        let mut entry = ManifestEntry {
+            id: try!(read_bytes(input)),
+            index: try!(read_bytes(input)),
+            size: try!(read_u64(input)),
+            mtime: try!(read_u64(input)) as i64,
+            mtime_nsec: try!(read_u64(input)) as i64,
+            content_hash: try!(read_u64(input)),
+            lines: try!(read_u64(input)),
+            pack: None,
+            stage_hash: None,
+            dir_mode: None
+        };
+        let parts = try!(read_u64(input));
+        if parts & 1 != 0 {
+            entry.pack = Some(try!(PackLocation::read_portable(input)));
+        }
+        if parts & 2 != 0 {
+            entry.stage_hash = Some(try!(read_u64(input)));
+        }
+        if parts & 4 != 0 {
+            entry.dir_mode = Some(try!(read_u64(input)) as u32);
+        }
+        Ok(entry)
+    }
+}
+
+impl ManifestEntry {
+    pub fn dir(id: &Path, mode: u32) -> ManifestEntry {
+        ManifestEntry {
+            id: id_bytes(id),
+            index: vec![],
+            size: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            content_hash: 0,
+            lines: 0,
+            pack: None,
+            stage_hash: None,
+            dir_mode: Some(mode)
+        }
+    }
+
+    pub fn path_id(&self) -> PathBuf {
+        id_from_bytes(&self.id)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.dir_mode.is_some()
+    }
+}
+
+pub fn entry_hash(entry: &ManifestEntry) -> u64 {
+    // one path's part of the tree hash. the size goes in along with the
+    // content hash, which doesn't see whether the last line has a newline.
+    // directories are left out, so trees hash the same as they did before
+    // they were recorded
+    if entry.is_dir() {
+        return 0;
+    }
+    let mut hasher = FnvHasher::default();
+    hasher.write(&entry.id);
+    hasher.write_u64(entry.size);
+    hasher.write_u64(entry.content_hash);
+    hasher.finish()
+}
+
+pub fn format_tree_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn id_hash(id: &Path) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&id_bytes(id));
+    hasher.finish()
+}
+
+// every tracked path in one map, keyed by the hash of its id. ids that hash
+// the same share a bucket. replaced values are left behind in the data file.
+// the tree hash is the wrapping sum of every entry's hash, so it doesn't
+// depend on order and follows inserts and removes without a walk. an opened
+// manifest keeps it in a file, removed before the first change and written
+// again when the manifest is dropped, so a crash can't leave it stale
+#[derive(Debug)]
+pub struct Manifest<T: Read + Write + Seek + fmt::Debug> {
+    map: BufMap<T, u64, Vec<ManifestEntry>>,
+    // none until it's read or summed
+    tree_hash: Option<u64>,
+    hash_path: Option<PathBuf>,
+    changed: bool
+}
+
+impl Manifest<fs::File> {
+    pub fn open<T: Into<PathBuf>>(path: T) -> io::Result<Manifest<fs::File>> {
+        let path = path.into();
+        debug!("Opening manifest at {:?}", &path);
+        try!(fs::create_dir_all(&path));
+
+        let data = try!(fs::OpenOptions::new().read(true).write(true).create(true).open(path.join("data")));
+        let index_path = path.join("index");
+        let map = match fs::metadata(&index_path) {
+            Ok(ref meta) if meta.len() > 0 => {
+                trace!("Opening existing manifest index");
+                let index = try!(fs::OpenOptions::new().read(true).write(true).open(&index_path));
+                try!(unsafe {BufMap::from_buffers(index, data)})
+            },
+            _ => {
+                trace!("Creating manifest index");
+                let index = try!(fs::OpenOptions::new().read(true).write(true).create(true).open(&index_path));
+                try!(BufMap::new(index, data, BufMap::<fs::File, u64, Vec<ManifestEntry>>::page_width()))
+            }
+        };
+
+        let mut manifest = Manifest::new(map);
+        let hash_path = path.join(TREE_HASH_FILE);
+        let mut text = String::new();
+        match fs::File::open(&hash_path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => match u64::from_str_radix(text.trim(), 16) {
+                Ok(hash) => {
+                    manifest.tree_hash = Some(hash);
+                },
+                Err(_) => {
+                    warn!("Ignoring unreadable tree hash {:?}", text.trim());
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No saved tree hash");
+            },
+            Err(e) => {
+                error!("Failed to read {}: {}", hash_path.display(), e);
+                return Err(e);
+            }
+        }
+        manifest.hash_path = Some(hash_path);
+        Ok(manifest)
+    }
+
+    pub fn import<T: Into<PathBuf>, R: Read>(path: T, input: &mut R) -> io::Result<Manifest<fs::File>> {
+        // a manifest rebuilt from an export, over whatever was at path. the
+        // tree hash is saved on its own
+        let path = path.into();
+        try!(fs::create_dir_all(&path));
+        let index = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                         .open(path.join("index")));
+        let data = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                        .open(path.join("data")));
+        Ok(Manifest::new(try!(BufMap::import(index, data, input))))
+    }
+
+    pub fn reopen(&self) -> io::Result<Manifest<fs::File>> {
+        // another handle on the same manifest, for a thread of its own
+        match self.hash_path.as_ref().and_then(|path| path.parent()) {
+            Some(dir) => Manifest::open(dir),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Manifest was not opened from a directory"))
+        }
+    }
+
+    pub fn open_existing<T: Into<PathBuf>>(path: T) -> io::Result<Option<Manifest<fs::File>>> {
+        // repositories made before the manifest don't have one
+        let path = path.into();
+        match fs::metadata(&path) {
+            Ok(ref data) if data.is_dir() => Manifest::open(path).map(Some),
+            _ => {
+                trace!("No manifest at {:?}", &path);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<T: Read + Write + Seek + fmt::Debug> Manifest<T> {
+    pub fn new(map: BufMap<T, u64, Vec<ManifestEntry>>) -> Manifest<T> {
+        Manifest {
+            map: map,
+            tree_hash: None,
+            hash_path: None,
+            changed: false
+        }
+    }
+
+    fn changing(&mut self) -> io::Result<()> {
+        // the saved hash goes before anything changes, it's only written
+        // back once the changes are done
+        if self.changed {
+            return Ok(());
+        }
+        self.changed = true;
+        if let Some(ref path) = self.hash_path {
+            match fs::remove_file(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => {
+                    error!("Failed to remove {}: {}", path.display(), e);
+                    return Err(e);
+                },
+                Ok(()) => {
+                    trace!("Removed saved tree hash");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn tree_hash(&mut self) -> io::Result<u64> {
+        // the hash of every tracked path and its content, summed over the
+        // whole manifest the first time
+        if let Some(hash) = self.tree_hash {
+            return Ok(hash);
+        }
+        debug!("Summing the tree hash");
+        let mut hash = 0u64;
+        try!(self.map.verify_each(|_, bucket| {
+            for entry in bucket.iter() {
+                hash = hash.wrapping_add(entry_hash(entry));
+            }
+        }));
+        self.tree_hash = Some(hash);
+        Ok(hash)
+    }
+
+    pub fn export<W: Write>(&mut self, out: &mut W) -> io::Result<usize> {
+        // every bucket entry by entry, see BufMap::export
+        self.map.export(out)
+    }
+
+    pub fn get(&mut self, id: &Path) -> io::Result<Option<ManifestEntry>> {
+        let bytes = id_bytes(id);
+        let bucket = try!(self.map.get(id_hash(id))).unwrap_or(vec![]);
+        Ok(bucket.into_iter().find(|entry| entry.id == bytes))
+    }
+
+    pub fn insert(&mut self, entry: ManifestEntry) -> io::Result<()> {
+        try!(self.changing());
+        let key = id_hash(&entry.path_id());
+        let mut bucket = try!(self.map.get(key)).unwrap_or(vec![]);
+        if let Some(mut hash) = self.tree_hash {
+            for old in bucket.iter().filter(|other| other.id == entry.id) {
+                hash = hash.wrapping_sub(entry_hash(old));
+            }
+            self.tree_hash = Some(hash.wrapping_add(entry_hash(&entry)));
+        }
+        bucket.retain(|other| other.id != entry.id);
+        bucket.push(entry);
+        try!(self.map.insert(key, bucket));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &Path) -> io::Result<bool> {
+        // true if the path was in the manifest
+        let key = id_hash(id);
+        let bytes = id_bytes(id);
+        let mut bucket = match try!(self.map.get(key)) {
+            Some(bucket) => bucket,
+            None => {
+                return Ok(false);
+            }
+        };
+        try!(self.changing());
+        if let Some(mut hash) = self.tree_hash {
+            for old in bucket.iter().filter(|entry| entry.id == bytes) {
+                hash = hash.wrapping_sub(entry_hash(old));
+            }
+            self.tree_hash = Some(hash);
+        }
+        let len = bucket.len();
+        bucket.retain(|entry| entry.id != bytes);
+        if bucket.len() == len {
+            Ok(false)
+        } else if bucket.is_empty() {
+            try!(self.map.remove(key));
+            Ok(true)
+        } else {
+            try!(self.map.insert(key, bucket));
+            Ok(true)
+        }
+    }
+
+    fn entry_ids(&mut self, dirs: bool) -> io::Result<Vec<PathBuf>> {
+        let mut ids = vec![];
+        try!(self.map.verify_each(|_, bucket| {
+            for entry in bucket.iter().filter(|entry| entry.is_dir() == dirs) {
+                ids.push(entry.path_id());
+            }
+        }));
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn ids(&mut self) -> io::Result<Vec<PathBuf>> {
+        // every file in the manifest, sorted
+        self.entry_ids(false)
+    }
+
+    pub fn dir_ids(&mut self) -> io::Result<Vec<PathBuf>> {
+        // every directory in the manifest, sorted
+        self.entry_ids(true)
+    }
+
+    pub fn remove_under(&mut self, prefix: &Path) -> io::Result<usize> {
+        // drop a path, or everything under a directory, the directory too
+        let mut removed = 0;
+        let mut ids = try!(self.ids());
+        ids.extend(try!(self.dir_ids()));
+        for id in ids {
+            if id.starts_with(prefix) && try!(self.remove(&id)) {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl<T: Read + Write + Seek + fmt::Debug> Drop for Manifest<T> {
+    fn drop(&mut self) {
+        // save the hash for next time if this changed it. a failure only
+        // means summing it again
+        if !self.changed {
+            return;
+        }
+        let path = match self.hash_path {
+            Some(ref path) => path.clone(),
+            None => {
+                return;
+            }
+        };
+        match self.tree_hash() {
+            Ok(hash) => {
+                if let Err(e) = atomic_write(&path, format!("{}\n", format_tree_hash(hash)).as_ref()) {
+                    warn!("Failed to save the tree hash: {}", e);
+                }
+            },
+            Err(e) => {
+                warn!("Failed to sum the tree hash: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::io::Cursor;
+
+    use map::*;
+    use pack::*;
+
+    fn entry(id: &str, size: u64) -> ManifestEntry {
+        ManifestEntry {
+            id: id.as_bytes().to_vec(),
+            index: id.as_bytes().to_vec(),
+            size: size,
+            mtime: 1,
+            mtime_nsec: 2,
+            content_hash: 3,
+            lines: 4,
+            pack: None,
+            stage_hash: None,
+            dir_mode: None
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let map = BufMap::new(Cursor::new(vec![]), Cursor::new(vec![]), 6).unwrap();
+        let mut manifest = Manifest::new(map);
+        manifest.insert(entry("a/b", 1)).unwrap();
+        manifest.insert(entry("a/c", 2)).unwrap();
+        manifest.insert(entry("d", 3)).unwrap();
+        manifest.insert(entry("a/b", 4)).unwrap();
+        assert_eq!(manifest.get(Path::new("a/b")).unwrap(), Some(entry("a/b", 4)));
+        assert_eq!(manifest.get(Path::new("a")).unwrap(), None);
+        assert_eq!(manifest.ids().unwrap(),
+                   vec![PathBuf::from("a/b"), PathBuf::from("a/c"), PathBuf::from("d")]);
+        assert_eq!(manifest.remove_under(Path::new("a")).unwrap(), 2);
+        assert!(!manifest.remove(Path::new("a/b")).unwrap());
+        assert_eq!(manifest.ids().unwrap(), vec![PathBuf::from("d")]);
+
+        // the running hash matches one summed from scratch
+        let hash = manifest.tree_hash().unwrap();
+        manifest.insert(entry("f", 6)).unwrap();
+        manifest.insert(entry("f", 7)).unwrap();
+        assert!(manifest.tree_hash().unwrap() != hash);
+        manifest.remove(Path::new("f")).unwrap();
+        assert_eq!(manifest.tree_hash().unwrap(), hash);
+        manifest.tree_hash = None;
+        assert_eq!(manifest.tree_hash().unwrap(), hash);
+        assert_eq!(hash, entry_hash(&entry("d", 3)));
+
+        let span = PackSpan {offset: 8, len: 5};
+        let mut packed = entry("e", 5);
+        packed.pack = Some(PackLocation {pack: 2, meta: span, content: span, places: span});
+        manifest.insert(packed.clone()).unwrap();
+        assert_eq!(manifest.get(Path::new("e")).unwrap(), Some(packed.clone()));
+        packed.stage_hash = Some(9);
+        manifest.insert(packed.clone()).unwrap();
+        assert_eq!(manifest.get(Path::new("e")).unwrap(), Some(packed));
+
+        // directories are kept apart from files and out of the tree hash
+        let hash = manifest.tree_hash().unwrap();
+        manifest.insert(ManifestEntry::dir(Path::new("g"), 0o755)).unwrap();
+        manifest.insert(ManifestEntry::dir(Path::new("g/h"), 0o700)).unwrap();
+        manifest.insert(entry("g/h/i", 8)).unwrap();
+        assert_eq!(manifest.get(Path::new("g/h")).unwrap(), Some(ManifestEntry::dir(Path::new("g/h"), 0o700)));
+        assert_eq!(manifest.dir_ids().unwrap(), vec![PathBuf::from("g"), PathBuf::from("g/h")]);
+        assert_eq!(manifest.ids().unwrap(),
+                   vec![PathBuf::from("d"), PathBuf::from("e"), PathBuf::from("g/h/i")]);
+        assert_eq!(manifest.tree_hash().unwrap(), hash.wrapping_add(entry_hash(&entry("g/h/i", 8))));
+        assert_eq!(manifest.remove_under(Path::new("g")).unwrap(), 3);
+        assert!(manifest.dir_ids().unwrap().is_empty());
+        assert_eq!(manifest.tree_hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn test_manifest_removals() {
+        // enough entries for removals to reach inner nodes of the map, taken
+        // out in an order that isn't the one they went in
+        let map = BufMap::new(Cursor::new(vec![]), Cursor::new(vec![]), 6).unwrap();
+        let mut manifest = Manifest::new(map);
+        let ids: Vec<String> = (0..300).map(|i| format!("dir{}/file{}", i % 7, i)).collect();
+        for (i, id) in ids.iter().enumerate() {
+            manifest.insert(entry(id, i as u64)).unwrap();
+        }
+        for i in 0..300 {
+            if i % 3 != 1 {
+                let id = &ids[i * 7 % 300];
+                assert!(manifest.remove(Path::new(id)).unwrap(), "{}", id);
+                assert!(!manifest.remove(Path::new(id)).unwrap());
+            }
+        }
+        for i in 0..300 {
+            let id = &ids[i * 7 % 300];
+            let expected = if i % 3 == 1 {Some(entry(id, (i * 7 % 300) as u64))} else {None};
+            assert_eq!(manifest.get(Path::new(id)).unwrap(), expected);
+        }
+        assert_eq!(manifest.ids().unwrap().len(), 100);
+        let hash = manifest.tree_hash().unwrap();
+        manifest.tree_hash = None;
+        assert_eq!(manifest.tree_hash().unwrap(), hash);
+    }
+}
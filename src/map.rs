@@ -0,0 +1,323 @@
+use std::marker::PhantomData;
+use std::cmp::Ordering;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use std::io;
+use std::fmt;
+
+use tree::*;
+use portable::*;
+
+// values up to this many bytes are kept in the tree entry itself, which
+// covers a line that appears once without a read of the data buffer
+pub const MAP_INLINE_SIZE: usize = 24;
+
+// where a key's value lives, only the key takes part in ordering so lookups
+// don't need a fake value
+#[derive(Debug, Clone, Copy)]
+pub struct MapEntry<K: BufItem> {
+    key: K,
+    len: u64,
+    // offset in the data buffer when the value didn't fit inline
+    offset: u64,
+    inline: [u8; MAP_INLINE_SIZE]
+}
+
+impl<K: BufItem> Eq for MapEntry<K> {}
+
+impl<K: BufItem> PartialEq for MapEntry<K> {
+    fn eq(&self, other: &MapEntry<K>) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: BufItem> Ord for MapEntry<K> {
+    fn cmp(&self, other: &MapEntry<K>) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<K: BufItem> PartialOrd for MapEntry<K> {
+    fn partial_cmp(&self, other: &MapEntry<K>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: BufItem> MapEntry<K> {
+    fn search(key: K) -> MapEntry<K> {
+        MapEntry {
+            key: key,
+            len: 0,
+            offset: 0,
+            inline: [0; MAP_INLINE_SIZE]
+        }
+    }
+}
+
+// a map on top of BufTree, keys and small values are kept in the tree and
+// bigger values overflow into a separate data buffer. space from replaced or
+// removed values isn't reused, rebuild the map to reclaim it
+#[derive(Debug)]
+pub struct BufMap<T: Read + Write + Seek + fmt::Debug, K: BufItem, V: Portable> {
+    tree: BufTree<T, MapEntry<K>>,
+    data: T,
+    phantom: PhantomData<V>
+}
+
+impl<T: Read + Write + Seek + fmt::Debug, K: BufItem, V: Portable> BufMap<T, K, V> {
+    pub fn new(index: T, data: T, size: usize) -> io::Result<BufMap<T, K, V>> {
+        Ok(BufMap {
+            tree: try!(BufTree::new(index, size)),
+            data: data,
+            phantom: PhantomData
+        })
+    }
+
+    pub fn page_width() -> usize {
+        // the node width that fills a page with this map's entries
+        page_width::<MapEntry<K>>()
+    }
+
+    pub unsafe fn from_buffers(index: T, data: T) -> io::Result<BufMap<T, K, V>> {
+        // unsafe for the same reason BufTree::from_buffer is
+        Ok(BufMap {
+            tree: try!(BufTree::from_buffer(index)),
+            data: data,
+            phantom: PhantomData
+        })
+    }
+
+    pub unsafe fn open_checked(index: T, data: T) -> io::Result<BufMap<T, K, V>> {
+        // for maps that could be damaged, see BufTree::open_checked. values
+        // are already read against the length of the data
+        Ok(BufMap {
+            tree: try!(BufTree::open_checked(index)),
+            data: data,
+            phantom: PhantomData
+        })
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> where T: Truncate {
+        try!(self.tree.clear());
+        self.data.truncate(0)
+    }
+
+    pub fn into_buffers(self) -> (T, T) {
+        // the index and data buffers, in that order
+        (self.tree.into_inner(), self.data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn tree_mut(&mut self) -> &mut BufTree<T, MapEntry<K>> {
+        // for looking at the shape of the map
+        &mut self.tree
+    }
+
+    fn read_value(&mut self, entry: &MapEntry<K>) -> io::Result<V> {
+        let buf = if entry.len <= MAP_INLINE_SIZE as u64 {
+            entry.inline[..entry.len as usize].to_vec()
+        } else {
+            try!(self.data.seek(SeekFrom::Start(entry.offset)));
+            let mut buf = vec![];
+            try!(Read::by_ref(&mut self.data).take(entry.len).read_to_end(&mut buf));
+            if buf.len() as u64 != entry.len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Value of {:?} at {} is past the end of the data",
+                                                  entry.key, entry.offset)));
+            }
+            buf
+        };
+        let mut input = Cursor::new(buf);
+        let value = try!(V::read_portable(&mut input));
+        if input.position() != entry.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Value of {:?} is {} bytes, expected {}",
+                                              entry.key, input.position(), entry.len)));
+        }
+        Ok(value)
+    }
+
+    fn write_value(&mut self, key: K, value: &V) -> io::Result<MapEntry<K>> {
+        let mut buf = vec![];
+        try!(value.write_portable(&mut buf));
+        let mut entry = MapEntry::search(key);
+        entry.len = buf.len() as u64;
+        if buf.len() <= MAP_INLINE_SIZE {
+            trace!("Storing {} byte value inline", buf.len());
+            for i in 0..buf.len() {
+                entry.inline[i] = buf[i];
+            }
+        } else {
+            trace!("Storing {} byte value in overflow", buf.len());
+            entry.offset = try!(self.data.seek(SeekFrom::End(0)));
+            try!(self.data.write_all(&buf));
+        }
+        Ok(entry)
+    }
+
+    pub fn contains_key(&mut self, key: K) -> io::Result<bool> {
+        self.tree.contains(MapEntry::search(key))
+    }
+
+    pub fn get(&mut self, key: K) -> io::Result<Option<V>> {
+        match try!(self.tree.get(MapEntry::search(key))) {
+            None => Ok(None),
+            Some(entry) => Ok(Some(try!(self.read_value(&entry))))
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<V>> {
+        // returns the value that was replaced, if any
+        let entry = try!(self.write_value(key, &value));
+        match try!(self.tree.insert(entry)) {
+            None => Ok(None),
+            Some(old) => Ok(Some(try!(self.read_value(&old))))
+        }
+    }
+
+    pub fn extend<I: IntoIterator<Item=(K, V)>>(&mut self, items: I) -> io::Result<usize> {
+        // insert many keys through BufTree::extend, returning how many were
+        // new. replaced values aren't read back
+        let mut entries = vec![];
+        for (key, value) in items {
+            entries.push(try!(self.write_value(key, &value)));
+        }
+        self.tree.extend(entries)
+    }
+
+    pub fn remove(&mut self, key: K) -> io::Result<Option<V>> {
+        match try!(self.tree.remove(MapEntry::search(key))) {
+            None => Ok(None),
+            Some(old) => Ok(Some(try!(self.read_value(&old))))
+        }
+    }
+
+    pub fn dump_nodes(&mut self) -> io::Result<Vec<NodeDump<(K, V)>>> {
+        // the tree's nodes with each key's value read back in place of its entry
+        let mut nodes = vec![];
+        for node in try!(self.tree.dump_nodes()) {
+            let mut items = vec![];
+            for entry in node.items.iter() {
+                items.push((entry.key, try!(self.read_value(entry))));
+            }
+            nodes.push(NodeDump {
+                idx: node.idx,
+                depth: node.depth,
+                leaf: node.leaf,
+                items: items,
+                next: node.next
+            });
+        }
+        Ok(nodes)
+    }
+
+    pub fn verify_each<F: FnMut(&K, &V)>(&mut self, mut each: F) -> io::Result<usize> {
+        // check the tree, then that every value can be read back
+        let mut entries = vec![];
+        try!(self.tree.verify_each(|entry| entries.push(*entry)));
+        for entry in entries.iter() {
+            let value = try!(self.read_value(entry));
+            each(&entry.key, &value);
+        }
+        Ok(entries.len())
+    }
+
+    pub fn export<W: Write>(&mut self, out: &mut W) -> io::Result<usize> where K: Portable {
+        // like BufTree::export, with each value following its key
+        let mut entries = vec![];
+        try!(self.tree.verify_each(|entry| entries.push(*entry)));
+        try!(write_u64(out, self.tree.size() as u64));
+        try!(write_u64(out, entries.len() as u64));
+        for entry in entries.iter() {
+            let value = try!(self.read_value(entry));
+            try!(entry.key.write_portable(out));
+            try!(value.write_portable(out));
+        }
+        Ok(entries.len())
+    }
+
+    pub fn import<R: Read>(index: T, data: T, input: &mut R) -> io::Result<BufMap<T, K, V>> where K: Portable {
+        let size = try!(read_u64(input)) as usize;
+        let count = try!(read_u64(input));
+        if size < MIN_TREE_WIDTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Exported map node size ({}) is too small", size)));
+        }
+        let mut map = try!(BufMap::new(index, data, size));
+        for _ in 0..count {
+            let key = try!(K::read_portable(input));
+            let value = try!(V::read_portable(input));
+            try!(map.insert(key, value));
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_map_basic() {
+        let mut map: BufMap<_, u64, Vec<u64>> = BufMap::new(Cursor::new(vec![]), Cursor::new(vec![]), 6).unwrap();
+        for i in 0..50 {
+            assert_eq!(map.insert(i, (0..i).collect()).unwrap(), None);
+        }
+        assert_eq!(map.get(7).unwrap(), Some(vec![0, 1, 2, 3, 4, 5, 6]));
+        assert_eq!(map.insert(7, vec![]).unwrap(), Some(vec![0, 1, 2, 3, 4, 5, 6]));
+        assert_eq!(map.get(7).unwrap(), Some(vec![]));
+        assert_eq!(map.remove(3).unwrap(), Some(vec![0, 1, 2]));
+        assert!(!map.contains_key(3).unwrap());
+        assert_eq!(map.get(100).unwrap(), None);
+        assert_eq!(map.len(), 49);
+
+        let mut values = 0;
+        assert_eq!(map.verify_each(|k, v| if *k != 7 {values += v.len()}).unwrap(), 49);
+        // every value but 3 and 7, which had 3 and 7 entries
+        assert_eq!(values, 49 * 50 / 2 - 3 - 7);
+
+        let nodes = map.dump_nodes().unwrap();
+        let mut found = 0;
+        for node in nodes.iter() {
+            for &(key, ref value) in node.items.iter() {
+                assert_eq!(value.len() as u64, if key == 7 {0} else {key});
+                found += 1;
+            }
+        }
+        assert_eq!(found, 49);
+    }
+
+    #[test]
+    fn test_map_extend() {
+        let mut map: BufMap<_, u64, Vec<u64>> = BufMap::new(Cursor::new(vec![]), Cursor::new(vec![]), 6).unwrap();
+        assert_eq!(map.extend((0..50).map(|i| (i, (0..i).collect()))).unwrap(), 50);
+        assert_eq!(map.extend(vec![(7, vec![]), (50, vec![1])]).unwrap(), 1);
+        assert_eq!(map.len(), 51);
+        assert_eq!(map.get(7).unwrap(), Some(vec![]));
+        assert_eq!(map.get(49).unwrap(), Some((0..49).collect()));
+        assert_eq!(map.verify_each(|_, _| {}).unwrap(), 51);
+    }
+
+    #[test]
+    fn test_map_inline() {
+        let mut index = Cursor::new(vec![]);
+        let mut data = Cursor::new(vec![]);
+        {
+            let mut map: BufMap<_, u64, Vec<u64>> = BufMap::new(&mut index, &mut data, 6).unwrap();
+            // a count and two items fit inline, three items don't
+            map.insert(1, vec![1, 2]).unwrap();
+            map.insert(2, vec![1, 2, 3]).unwrap();
+            assert_eq!(map.get(1).unwrap(), Some(vec![1, 2]));
+            assert_eq!(map.get(2).unwrap(), Some(vec![1, 2, 3]));
+        }
+        assert_eq!(data.into_inner().len(), 32);
+    }
+}
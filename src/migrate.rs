@@ -0,0 +1,466 @@
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+
+use std::fs;
+use std::io;
+
+use repo::*;
+use tree::*;
+use map::*;
+use pack::*;
+use plan::*;
+use undo::*;
+use fileops::*;
+use manifest::*;
+use linestore::*;
+use verify::*;
+use platform::*;
+use revs::*;
+use stash::*;
+use crypt::*;
+
+// upgrading a repository written by an older version, one format version at
+// a time. a step only bumps the header once everything it rewrites is done,
+// and notes what it finished in the progress file so running it again after
+// an interruption doesn't rewrite anything twice
+
+const PROGRESS_FILE: &'static str = "migration";
+
+pub struct Migration {
+    // the version this step upgrades from, to the one after it
+    pub from: u32,
+    pub summary: &'static str,
+    run: fn(&Repo, &mut Progress) -> io::Result<()>
+}
+
+fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            from: 11,
+            summary: "record item counts in tree headers",
+            run: upgrade_tree_headers
+        },
+        Migration {
+            from: 12,
+            summary: "allow inline indexes for small files",
+            run: no_rewrite
+        },
+        Migration {
+            from: 13,
+            summary: "allow stage copy hashes in the manifest",
+            run: no_rewrite
+        },
+        Migration {
+            from: 14,
+            summary: "record diff drivers in index metas",
+            run: no_rewrite
+        },
+        Migration {
+            from: 15,
+            summary: "record merge parents in revision metas",
+            run: no_rewrite
+        },
+        Migration {
+            from: 16,
+            summary: "record line length caps in index metas",
+            run: no_rewrite
+        },
+        Migration {
+            from: 17,
+            summary: "mark automatic snapshots in revision metas",
+            run: no_rewrite
+        },
+        Migration {
+            from: 18,
+            summary: "record directories in the manifest",
+            run: record_stage_dirs
+        },
+        Migration {
+            from: 19,
+            summary: "record authors, messages and hashes in revision metas",
+            run: no_rewrite
+        },
+        Migration {
+            from: 20,
+            summary: "bind sealed files to their place and seal the stage and stash",
+            run: seal_in_place
+        },
+        Migration {
+            from: 21,
+            summary: "replace the key check with an hmac of the key",
+            run: rewrite_key_check
+        }
+    ]
+}
+
+pub fn pending_migrations(version: u32) -> io::Result<Vec<Migration>> {
+    // every step from version to the current one, in order
+    if version > FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("Repository format version {} is newer than supported version {}",
+                                          version, FORMAT_VERSION)));
+    }
+    let mut steps = migrations();
+    let mut pending = vec![];
+    for from in version..FORMAT_VERSION {
+        match steps.iter().position(|step| step.from == from) {
+            Some(i) => {
+                pending.push(steps.swap_remove(i));
+            },
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("Repository format version {} can't be migrated, \
+                                                   re-create it with `h2 init`", from)));
+            }
+        }
+    }
+    Ok(pending)
+}
+
+pub fn migrate(repo: &Repo, plan: Plan) -> io::Result<Vec<Migration>> {
+    // run every pending step, returning the steps that were run, or would be
+    let version = try!(repo.read_header());
+    let pending = try!(pending_migrations(version));
+    if plan.is_dry_run() {
+        return Ok(pending);
+    }
+    for step in pending.iter() {
+        info!("Migrating from format version {}: {}", step.from, step.summary);
+        let mut progress = try!(Progress::load(repo.path.join(PROGRESS_FILE), step.from));
+        try!((step.run)(repo, &mut progress));
+        try!(repo.write_version(step.from + 1));
+        try!(progress.finish());
+    }
+    if !pending.is_empty() {
+        // what undo would put back is in the old format
+        try!(Undo::new(repo.path.join("undo")).clear());
+    }
+    Ok(pending)
+}
+
+pub fn migrate_imported(repo: &Repo, current: &[&Path]) -> io::Result<Vec<Migration>> {
+    // migrate a repository imported from an archive of an older version.
+    // the trees in current were rebuilt from their entries in the layout 11
+    // to 12 upgrades to, so that step counts them as done already. they're
+    // named by their directory, the same as the step names them
+    let version = try!(repo.read_header());
+    try!(pending_migrations(version));
+    if version == 11 {
+        let mut progress = try!(Progress::load(repo.path.join(PROGRESS_FILE), version));
+        for path in current.iter() {
+            let name = match path.parent() {
+                Some(dir) => format!("{}", dir.display()),
+                None => {
+                    continue;
+                }
+            };
+            if !progress.is_done(&name) {
+                trace!("{} was imported in the current layout", name);
+                try!(progress.mark_done(&name));
+            }
+        }
+    }
+    migrate(repo, Plan::default())
+}
+
+// what a step has already finished, one name per line after the version it
+// started from
+struct Progress {
+    path: PathBuf,
+    done: Vec<String>
+}
+
+impl Progress {
+    fn load(path: PathBuf, from: u32) -> io::Result<Progress> {
+        let mut text = String::new();
+        match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No migration in progress");
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(_) => {}
+        }
+        let mut lines = text.lines();
+        let done = match lines.next() {
+            Some(version) if version.trim() == format!("{}", from) => {
+                debug!("Resuming migration from format version {}", from);
+                lines.map(|line| line.to_string()).collect()
+            },
+            _ => {
+                try!(atomic_write(&path, format!("{}\n", from).as_bytes()));
+                vec![]
+            }
+        };
+        Ok(Progress {
+            path: path,
+            done: done
+        })
+    }
+
+    fn is_done(&self, name: &str) -> bool {
+        self.done.iter().any(|done| done == name)
+    }
+
+    fn mark_done(&mut self, name: &str) -> io::Result<()> {
+        let mut file = try!(fs::OpenOptions::new().append(true).open(&self.path));
+        try!(file.write_all(format!("{}\n", name).as_bytes()));
+        try!(file.sync_all());
+        self.done.push(name.to_string());
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+fn upgrade_tree_file<V: BufItem>(path: &Path, name: &str, progress: &mut Progress) -> io::Result<()> {
+    // rewrite one tree file through a temporary copy
+    if progress.is_done(name) {
+        trace!("{} was already upgraded", name);
+        return Ok(());
+    }
+    match fs::metadata(path) {
+        Ok(ref data) if data.len() > 0 => {
+            debug!("Upgrading tree {:?}", path);
+            let old = try!(fs::File::open(path));
+            let temp = try!(temp_path(path));
+            let new = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temp));
+            let upgraded: BufTree<fs::File, V> = match unsafe {BufTree::upgrade_v11(old, new)} {
+                Err(e) => {
+                    let _ = fs::remove_file(&temp);
+                    return Err(io::Error::new(e.kind(), format!("Failed to upgrade {}: {}", path.display(), e)));
+                },
+                Ok(tree) => tree
+            };
+            try!(upgraded.into_inner().sync_all());
+            try!(fs::rename(&temp, path));
+        },
+        _ => {
+            trace!("No tree at {:?}", path);
+        }
+    }
+    progress.mark_done(name)
+}
+
+fn upgrade_tree_bytes<V: BufItem>(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let upgraded: BufTree<io::Cursor<Vec<u8>>, V> =
+        try!(unsafe {BufTree::upgrade_v11(io::Cursor::new(data), io::Cursor::new(vec![]))});
+    Ok(upgraded.into_inner().into_inner())
+}
+
+fn upgrade_tree_headers(repo: &Repo, progress: &mut Progress) -> io::Result<()> {
+    // 11 to 12: tree headers grew an item count, which moves every node, so
+    // each tree is rebuilt. the manifest goes first since it says where
+    // packed indexes are
+    try!(upgrade_tree_file::<MapEntry<u64>>(&repo.path.join("manifest").join("index"), "manifest", progress));
+    try!(upgrade_tree_file::<LineRef>(&repo.path.join("lines").join("index"), "lines", progress));
+
+    let logs_path = repo.path.join("logs");
+    for id in try!(log_ids(&logs_path)) {
+        let name = format!("logs/{}", id.display());
+        try!(upgrade_tree_file::<MapEntry<u64>>(&logs_path.join(&id).join("content"), &name, progress));
+    }
+
+    if fs::metadata(repo.path.join("manifest")).is_err() {
+        return Ok(());
+    }
+    let packs = Packs::new(repo.path.join("packs"));
+    let old_packs = try!(packs.list());
+    if old_packs.is_empty() || progress.is_done("packs") {
+        return Ok(());
+    }
+
+    // packed indexes all move to one new pack. a pack named in the progress
+    // file was written by an earlier try, so what's in it is already upgraded
+    let mut manifest = try!(Manifest::open(repo.path.join("manifest")));
+    let pack = old_packs.last().map_or(1, |last| last + 1);
+    try!(progress.mark_done(&format!("pack {}", pack)));
+    let mut writer = try!(packs.writer(pack));
+    let mut moved = vec![];
+    for id in try!(manifest.ids()) {
+        let mut entry = match try!(manifest.get(&id)) {
+            Some(entry) => entry,
+            None => {
+                continue;
+            }
+        };
+        let location = match entry.pack {
+            Some(location) => location,
+            None => {
+                continue;
+            }
+        };
+        debug!("Upgrading packed index of {:?}", &id);
+        let content = try!(packs.read_span(location.pack, location.content));
+        let content = if progress.is_done(&format!("pack {}", location.pack)) {
+            content
+        } else {
+            try!(upgrade_tree_bytes::<MapEntry<u64>>(content))
+        };
+        entry.pack = Some(try!(writer.add(&try!(packs.read_span(location.pack, location.meta)), &content,
+                                          &try!(packs.read_span(location.pack, location.places)))));
+        moved.push(entry);
+    }
+    try!(writer.finish());
+    for entry in moved {
+        try!(manifest.insert(entry));
+    }
+    try!(packs.remove_except(pack));
+    progress.mark_done("packs")
+}
+
+fn record_stage_dirs(repo: &Repo, progress: &mut Progress) -> io::Result<()> {
+    // 18 to 19: directories in the stage go in the manifest, with the mode
+    // the checkout has them at, or the stage's once they're gone from it.
+    // inserting one twice is the same as once, the progress only saves
+    // walking the stage again
+    if fs::metadata(repo.path.join("manifest")).is_err() || progress.is_done("dirs") {
+        return Ok(());
+    }
+    let mut manifest = try!(Manifest::open(repo.path.join("manifest")));
+    let stage_path = repo.path.join("stage");
+    for id in try!(stage_dirs(&stage_path)) {
+        let metadata = match fs::metadata(repo.root.join(&id)) {
+            Ok(data) if data.is_dir() => data,
+            _ => try!(fs::metadata(stage_path.join(&id)))
+        };
+        debug!("Recording directory {:?}", &id);
+        try!(manifest.insert(ManifestEntry::dir(&id, mode_bits(&metadata))));
+    }
+    progress.mark_done("dirs")
+}
+
+fn seal_in_place(repo: &Repo, progress: &mut Progress) -> io::Result<()> {
+    // 20 to 21: in an encrypted repository, every revision file is sealed
+    // again bound to its revision and path id, and the stage and stash,
+    // which were kept in the clear, are sealed the same way. a file that
+    // already opens bound to its place was done before an interruption
+    let key = match try!(load_key(&try!(repo.config()))) {
+        Some(key) => key,
+        None => {
+            trace!("Not encrypted, nothing to seal");
+            return Ok(());
+        }
+    };
+
+    let revs_path = repo.path.join("revs");
+    let revs = Revisions::new(revs_path.clone());
+    let rev_ids = if fs::metadata(&revs_path).is_ok() {try!(revs.list())} else {vec![]};
+    for rev in rev_ids {
+        let name = format!("revs/{}", rev);
+        if progress.is_done(&name) {
+            continue;
+        }
+        let tree = PathBuf::from(format!("{}", rev)).join("tree");
+        for id in try!(revs.files(rev)) {
+            try!(seal_file(&key, &revs_path.join(&tree).join(&id), &seal_place("revs", &tree.join(&id))));
+        }
+        try!(progress.mark_done(&name));
+    }
+
+    if !progress.is_done("stage") {
+        let stage_path = repo.path.join("stage");
+        for id in try!(stage_files(&stage_path)) {
+            try!(seal_file(&key, &stage_path.join(&id), &seal_place("stage", &id)));
+        }
+        try!(progress.mark_done("stage"));
+    }
+
+    if !progress.is_done("stash") {
+        let stash_path = repo.path.join("stash");
+        for entry in try!(Stash::new(stash_path.clone()).list()) {
+            let name = format!("{}", entry);
+            try!(seal_file(&key, &stash_path.join(&name), &seal_place("stash", Path::new(&name))));
+        }
+        try!(progress.mark_done("stash"));
+    }
+    Ok(())
+}
+
+fn rewrite_key_check(repo: &Repo, _: &mut Progress) -> io::Result<()> {
+    // 21 to 22: the key check was a block of keystream, it becomes an hmac
+    // of a fixed text. the old check still loads the key, so only the
+    // key_check line of the config is rewritten
+    let config = try!(repo.config());
+    let key = match try!(load_key(&config)) {
+        Some(key) => key,
+        None => {
+            trace!("Not encrypted, no key check to rewrite");
+            return Ok(());
+        }
+    };
+    let check = key.check_value();
+    if config.get("key_check") == Some(&check[..]) {
+        trace!("Key check was already rewritten");
+        return Ok(());
+    }
+
+    let config_path = repo.path.join("config");
+    let mut data = String::new();
+    try!(fs::File::open(&config_path).and_then(|mut f| f.read_to_string(&mut data)));
+    let mut rewritten = String::new();
+    for line in data.lines() {
+        match line.find('=') {
+            Some(split) if line[..split].trim() == "key_check" => {
+                rewritten.push_str(&format!("key_check = {}\n", check));
+            },
+            _ => {
+                rewritten.push_str(line);
+                rewritten.push('\n');
+            }
+        }
+    }
+    debug!("Rewriting the key check");
+    atomic_write(&config_path, rewritten.as_bytes())
+}
+
+fn seal_file(key: &StoreKey, path: &Path, place: &[u8]) -> io::Result<()> {
+    // one file sealed bound to its place, opened first if it was sealed
+    // without one
+    let mut data = vec![];
+    try!(fs::File::open(path).and_then(|mut f| f.read_to_end(&mut data)));
+    let plain = if !is_sealed(&data) {
+        data
+    } else if key.open(place, &data).is_ok() {
+        trace!("{:?} is already bound", path);
+        return Ok(());
+    } else {
+        match key.open(&[], &data) {
+            Ok(plain) => plain,
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Failed to open {}: {}", path.display(), e)));
+            }
+        }
+    };
+    debug!("Sealing {:?}", path);
+    atomic_write(path, &try!(key.seal(place, &plain)))
+}
+
+fn no_rewrite(_: &Repo, _: &mut Progress) -> io::Result<()> {
+    // 12 to 13 and on up to 18 only add things newer versions may write: an
+    // inline index in a meta, a stage hash in a manifest entry, the driver of
+    // an index, the merged parent of a revision, the line length cap of an
+    // index and the mark on an automatic snapshot. what's there already reads
+    // the same, the bump keeps older versions away
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repo::FORMAT_VERSION;
+
+    #[test]
+    fn test_pending_migrations() {
+        assert!(pending_migrations(FORMAT_VERSION).unwrap().is_empty());
+        let pending = pending_migrations(FORMAT_VERSION - 1).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].from, FORMAT_VERSION - 1);
+        assert!(pending_migrations(FORMAT_VERSION + 1).is_err());
+        assert!(pending_migrations(2).is_err());
+    }
+}
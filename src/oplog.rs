@@ -0,0 +1,131 @@
+use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Write};
+
+use rustc_serialize::json;
+
+use std::fs;
+use std::io;
+
+use revs::*;
+use repo::*;
+
+// one line of the journal, what a command did and what it touched
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable)]
+pub struct OpRecord {
+    // seconds since the epoch
+    pub time: i64,
+    pub op: String,
+    pub rev: Option<RevisionId>,
+    // escaped with escape_id so any path survives the json
+    pub paths: Vec<String>,
+    // what went wrong without stopping the command, like a file changing
+    // while it was staged. none in records from before warnings were kept
+    pub warnings: Option<Vec<String>>
+}
+
+// append-only journal of the commands that changed the repository, one json
+// record per line so a torn last write only loses that record
+#[derive(Debug)]
+pub struct OpLog {
+    path: PathBuf
+}
+
+impl Default for OpLog {
+    fn default() -> OpLog {
+        OpLog::new(repo_path("oplog"))
+    }
+}
+
+impl OpRecord {
+    pub fn new(op: &str, rev: Option<RevisionId>, paths: Vec<String>) -> OpRecord {
+        OpRecord {
+            time: now(),
+            op: op.to_string(),
+            rev: rev,
+            paths: paths,
+            warnings: None
+        }
+    }
+
+    pub fn with_warnings(mut self, warnings: Vec<String>) -> OpRecord {
+        self.warnings = if warnings.is_empty() {None} else {Some(warnings)};
+        self
+    }
+}
+
+impl OpLog {
+    pub fn new<T: Into<PathBuf>>(path: T) -> OpLog {
+        OpLog {
+            path: path.into()
+        }
+    }
+
+    pub fn append(&self, record: &OpRecord) -> io::Result<()> {
+        debug!("Recording {} in the operation log", record.op);
+        let data = match json::encode(record) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to encode to json: {}", e)));
+            },
+            Ok(d) => d
+        };
+        let file = fs::OpenOptions::new().append(true).create(true).open(&self.path);
+        match file.and_then(|mut f| f.write_all(format!("{}\n", data).as_ref())) {
+            Err(e) => {
+                error!("Failed to append to {}: {}", self.path.display(), e);
+                Err(e)
+            },
+            Ok(()) => {
+                trace!("Operation recorded");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Vec<OpRecord>> {
+        // every record, oldest first
+        let file = match fs::File::open(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No operation log");
+                return Ok(vec![]);
+            },
+            Err(e) => {
+                error!("Failed to open {}: {}", self.path.display(), e);
+                return Err(e);
+            },
+            Ok(f) => f
+        };
+
+        let mut records = vec![];
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = try!(line);
+            match json::decode(&line) {
+                Ok(record) => {
+                    records.push(record);
+                },
+                Err(e) => {
+                    // only the end of the log can be cut short by a crash
+                    warn!("Skipping unreadable operation log line {}: {}", number + 1, e);
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+pub fn render_oplog(records: &[OpRecord]) -> Vec<String> {
+    // a line per operation, with its warnings under it
+    let mut out = vec![];
+    for record in records.iter() {
+        let rev = match record.rev {
+            Some(rev) => format!("{}", rev),
+            None => "-".to_string()
+        };
+        out.push(format!("{:>10} {:<8} {:>7} {}", record.time, record.op, rev, record.paths.join(" ")));
+        if let Some(ref warnings) = record.warnings {
+            for warning in warnings.iter() {
+                out.push(format!("{:>10} warning: {}", "", warning));
+            }
+        }
+    }
+    out
+}
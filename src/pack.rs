@@ -0,0 +1,284 @@
+use std::path::PathBuf;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+use std::fs;
+use std::io;
+
+use fileops::*;
+use portable::*;
+use subbuf::*;
+
+// a pack file starts with this and then holds the meta, content and places
+// of many indexes back to back. packs are numbered, a repack writes a new
+// one and only removes the old ones once nothing refers to them
+pub const PACK_MAGIC: &'static [u8] = b"\0h2pack\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackSpan {
+    pub offset: u64,
+    pub len: u64
+}
+
+// where one file's index lives in a pack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackLocation {
+    pub pack: u64,
+    pub meta: PackSpan,
+    pub content: PackSpan,
+    pub places: PackSpan
+}
+
+impl Portable for PackSpan {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_u64(out, self.offset));
+        write_u64(out, self.len)
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<PackSpan> {
+        Ok(PackSpan {
+            offset: try!(read_u64(input)),
+            len: try!(read_u64(input))
+        })
+    }
+}
+
+impl Portable for PackLocation {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_u64(out, self.pack));
+        try!(self.meta.write_portable(out));
+        try!(self.content.write_portable(out));
+        self.places.write_portable(out)
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<PackLocation> {
+        Ok(PackLocation {
+            pack: try!(read_u64(input)),
+            meta: try!(PackSpan::read_portable(input)),
+            content: try!(PackSpan::read_portable(input)),
+            places: try!(PackSpan::read_portable(input))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Packs {
+    path: PathBuf
+}
+
+// a pack being written, it only shows up under its real name once finished.
+// one appending to a pack already has its real name
+#[derive(Debug)]
+pub struct PackWriter {
+    path: PathBuf,
+    pack: u64,
+    out: BufWriter<fs::File>,
+    offset: u64,
+    appending: bool
+}
+
+impl Packs {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Packs {
+        Packs {
+            path: path.into()
+        }
+    }
+
+    pub fn pack_path(&self, pack: u64) -> PathBuf {
+        self.path.join(format!("{}", pack))
+    }
+
+    pub fn list(&self) -> io::Result<Vec<u64>> {
+        // every finished pack, oldest first
+        let entries = match fs::read_dir(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No packs");
+                return Ok(vec![]);
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(entries) => entries
+        };
+        let mut packs = vec![];
+        for item in entries {
+            let entry = try!(item);
+            match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(pack) => {
+                    packs.push(pack);
+                },
+                None => {
+                    trace!("Skipping {:?}", entry.path());
+                }
+            }
+        }
+        packs.sort();
+        Ok(packs)
+    }
+
+    pub fn window(&self, pack: u64, span: PackSpan) -> io::Result<SubBuffer<fs::File>> {
+        let path = self.pack_path(pack);
+        let file = match fs::File::open(&path) {
+            Err(e) => {
+                error!("Failed to open pack {}: {}", pack, e);
+                return Err(e);
+            },
+            Ok(f) => f
+        };
+        let size = try!(file.metadata()).len();
+        if span.offset + span.len > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Span {}+{} is past the end of pack {}",
+                                              span.offset, span.len, pack)));
+        }
+        Ok(SubBuffer::new(file, span.offset, span.len))
+    }
+
+    pub fn read_span(&self, pack: u64, span: PackSpan) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        try!(try!(self.window(pack, span)).read_to_end(&mut data));
+        Ok(data)
+    }
+
+    pub fn writer(&self, pack: u64) -> io::Result<PackWriter> {
+        try!(fs::create_dir_all(&self.path));
+        let path = self.pack_path(pack);
+        debug!("Writing pack {}", pack);
+        let mut out = BufWriter::new(try!(fs::File::create(try!(temp_path(&path)))));
+        try!(out.write_all(PACK_MAGIC));
+        Ok(PackWriter {
+            path: path,
+            pack: pack,
+            out: out,
+            offset: PACK_MAGIC.len() as u64,
+            appending: false
+        })
+    }
+
+    pub fn appender(&self, limit: u64) -> io::Result<PackWriter> {
+        // a writer adding to the newest pack, or to a new one once that's
+        // limit bytes or more. nothing points at what it adds until the
+        // manifest does, so an append cut short only leaves unused bytes
+        try!(fs::create_dir_all(&self.path));
+        let pack = match try!(self.list()).last() {
+            Some(&last) if try!(fs::metadata(self.pack_path(last))).len() < limit => last,
+            Some(&last) => last + 1,
+            None => 1
+        };
+        let path = self.pack_path(pack);
+        debug!("Appending to pack {}", pack);
+        let file = try!(fs::OpenOptions::new().append(true).create(true).open(&path));
+        let mut offset = try!(file.metadata()).len();
+        let mut out = BufWriter::new(file);
+        if offset == 0 {
+            try!(out.write_all(PACK_MAGIC));
+            offset = PACK_MAGIC.len() as u64;
+        }
+        Ok(PackWriter {
+            path: path,
+            pack: pack,
+            out: out,
+            offset: offset,
+            appending: true
+        })
+    }
+
+    pub fn remove_except(&self, keep: u64) -> io::Result<usize> {
+        // drop every pack but one, once nothing points into them
+        let mut removed = 0;
+        for pack in try!(self.list()) {
+            if pack != keep {
+                debug!("Removing pack {}", pack);
+                try!(fs::remove_file(self.pack_path(pack)));
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl PackWriter {
+    fn append(&mut self, data: &[u8]) -> io::Result<PackSpan> {
+        let span = PackSpan {
+            offset: self.offset,
+            len: data.len() as u64
+        };
+        try!(self.out.write_all(data));
+        self.offset += span.len;
+        Ok(span)
+    }
+
+    pub fn add(&mut self, meta: &[u8], content: &[u8], places: &[u8]) -> io::Result<PackLocation> {
+        Ok(PackLocation {
+            pack: self.pack,
+            meta: try!(self.append(meta)),
+            content: try!(self.append(content)),
+            places: try!(self.append(places))
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        // what's been added so far, readable but not yet synced
+        self.out.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        try!(self.out.flush());
+        match self.out.get_mut().seek(SeekFrom::End(0)) {
+            Ok(size) if size == self.offset => {
+                trace!("Pack {} is {} bytes", self.pack, size);
+            },
+            Ok(size) => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("Pack {} is {} bytes, expected {}", self.pack, size, self.offset)));
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        try!(self.out.get_ref().sync_all());
+        if self.appending {
+            return Ok(());
+        }
+        commit_temp(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::env;
+
+    #[test]
+    fn test_pack_round_trip() {
+        let path = env::temp_dir().join("h2-test-packs");
+        let _ = fs::remove_dir_all(&path);
+        let packs = Packs::new(path.clone());
+        let mut writer = packs.writer(1).unwrap();
+        let first = writer.add(b"meta one", b"content one", b"").unwrap();
+        let second = writer.add(b"meta two", b"content two", b"places two").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(packs.list().unwrap(), vec![1]);
+        assert_eq!(packs.read_span(1, first.content).unwrap(), b"content one".to_vec());
+        assert_eq!(packs.read_span(1, first.places).unwrap(), b"".to_vec());
+        assert_eq!(packs.read_span(1, second.meta).unwrap(), b"meta two".to_vec());
+        assert!(packs.read_span(1, PackSpan {offset: 1000, len: 1}).is_err());
+
+        packs.writer(2).unwrap().finish().unwrap();
+        assert_eq!(packs.remove_except(2).unwrap(), 1);
+        assert_eq!(packs.list().unwrap(), vec![2]);
+
+        // appending picks up where the newest pack ends, until it's too big
+        let mut appender = packs.appender(1 << 20).unwrap();
+        let third = appender.add(b"meta three", b"", b"").unwrap();
+        appender.finish().unwrap();
+        assert_eq!(third.pack, 2);
+        assert_eq!(packs.read_span(2, third.meta).unwrap(), b"meta three".to_vec());
+        let mut appender = packs.appender(1).unwrap();
+        assert_eq!(appender.add(b"meta four", b"", b"").unwrap().pack, 3);
+        appender.finish().unwrap();
+        assert_eq!(packs.list().unwrap(), vec![2, 3]);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
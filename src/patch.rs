@@ -0,0 +1,491 @@
+use std::path::{Path, PathBuf};
+
+use std::cmp;
+use std::io;
+
+use diff::*;
+use pathid::*;
+
+// one hunk read back out of a unified diff. the ops index into the old and
+// new lines the hunk carries rather than into whole files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchHunk {
+    pub hunk: Hunk,
+    pub old: Vec<Vec<u8>>,
+    pub new: Vec<Vec<u8>>,
+    pub old_missing_newline: bool,
+    pub new_missing_newline: bool
+}
+
+// the changes to one file. a missing old id creates the file, a missing new
+// id deletes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatch {
+    pub old_id: Option<PathBuf>,
+    pub new_id: Option<PathBuf>,
+    pub hunks: Vec<PatchHunk>
+}
+
+fn bad_patch(line: usize, problem: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Bad patch at line {}: {}", line + 1, problem))
+}
+
+fn patch_path(field: &[u8], line: usize) -> io::Result<Option<PathBuf>> {
+    // the path after --- or +++, without the a/ or b/ and any timestamp
+    let text = String::from_utf8_lossy(field);
+    let text = match text.find('\t') {
+        Some(end) => &text[..end],
+        None => &text[..]
+    };
+    if text == "/dev/null" {
+        return Ok(None);
+    }
+    let text = if text.starts_with("a/") || text.starts_with("b/") {
+        &text[2..]
+    } else {
+        text
+    };
+    let bytes = match unescape_bytes(text) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return Err(bad_patch(line, "the file name can't be read"));
+        }
+    };
+    // refuse anything that would land outside the checkout or in the
+    // repository, like archive import does. checked before the name becomes
+    // an id, which drops empty components and with them a leading slash
+    if bytes.starts_with(b"/") || bytes.split(|&b| b == b'/').any(|c| c == b"..") {
+        return Err(bad_patch(line, "the file is outside the checkout"));
+    }
+    if bytes.split(|&b| b == b'/').any(|c| c == b".h2") {
+        return Err(bad_patch(line, "the file is inside a repository"));
+    }
+    Ok(Some(id_from_bytes(&bytes)))
+}
+
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    // start,len with the length optional. a start is 1-based unless the
+    // range is empty, then it's the line the range comes after
+    let mut parts = text.splitn(2, ',');
+    let start = match parts.next().and_then(|start| start.parse().ok()) {
+        Some(start) => start,
+        None => {
+            return None;
+        }
+    };
+    let len = match parts.next() {
+        Some(len) => match len.parse() {
+            Ok(len) => len,
+            Err(_) => {
+                return None;
+            }
+        },
+        None => 1
+    };
+    if len > 0 && start > 0 {
+        Some((start - 1, len))
+    } else {
+        Some((start, len))
+    }
+}
+
+fn parse_hunk(lines: &[Vec<u8>], at: usize) -> io::Result<(PatchHunk, usize)> {
+    // read the hunk whose header is at the given line, returning it and the
+    // line after it
+    let header = String::from_utf8_lossy(&lines[at]).into_owned();
+    let ranges: Vec<&str> = header[3..].split(' ').take(2).collect();
+    let (old_range, new_range) = match (ranges.get(0), ranges.get(1)) {
+        (Some(old), Some(new)) if old.starts_with('-') && new.starts_with('+') => {
+            match (parse_range(&old[1..]), parse_range(&new[1..])) {
+                (Some(old), Some(new)) => (old, new),
+                _ => {
+                    return Err(bad_patch(at, "the hunk header can't be read"));
+                }
+            }
+        },
+        _ => {
+            return Err(bad_patch(at, "the hunk header can't be read"));
+        }
+    };
+
+    let mut hunk = PatchHunk {
+        hunk: Hunk {
+            old_start: old_range.0,
+            old_len: old_range.1,
+            new_start: new_range.0,
+            new_len: new_range.1,
+            ops: vec![]
+        },
+        old: vec![],
+        new: vec![],
+        old_missing_newline: false,
+        new_missing_newline: false
+    };
+    let mut i = at + 1;
+    while hunk.old.len() < old_range.1 || hunk.new.len() < new_range.1 {
+        if i >= lines.len() {
+            return Err(bad_patch(i, "the hunk is cut short"));
+        }
+        let line = &lines[i];
+        // some tools drop the space from empty context lines
+        let (kind, content) = if line.is_empty() {
+            (b' ', vec![])
+        } else {
+            (line[0], line[1..].to_vec())
+        };
+        match kind {
+            b' ' => {
+                hunk.hunk.ops.push(DiffOp::Equal(hunk.old.len(), hunk.new.len()));
+                hunk.old.push(content.clone());
+                hunk.new.push(content);
+            },
+            b'-' => {
+                hunk.hunk.ops.push(DiffOp::Delete(hunk.old.len()));
+                hunk.old.push(content);
+            },
+            b'+' => {
+                hunk.hunk.ops.push(DiffOp::Insert(hunk.new.len()));
+                hunk.new.push(content);
+            },
+            b'\\' => {
+                trace!("Skipping a no newline marker in the middle of a hunk");
+            },
+            _ => {
+                return Err(bad_patch(i, "expected a context, removed or added line"));
+            }
+        }
+        i += 1;
+        if hunk.old.len() > old_range.1 || hunk.new.len() > new_range.1 {
+            return Err(bad_patch(i - 1, "the hunk is longer than its header says"));
+        }
+        // a marker applies to whichever side the line before it was on
+        while i < lines.len() && lines[i].starts_with(b"\\") {
+            match hunk.hunk.ops.last() {
+                Some(&DiffOp::Equal(_, _)) => {
+                    hunk.old_missing_newline = true;
+                    hunk.new_missing_newline = true;
+                },
+                Some(&DiffOp::Delete(_)) => {
+                    hunk.old_missing_newline = true;
+                },
+                Some(&DiffOp::Insert(_)) => {
+                    hunk.new_missing_newline = true;
+                },
+                None => {}
+            }
+            i += 1;
+        }
+    }
+    Ok((hunk, i))
+}
+
+pub fn parse_patch(data: &[u8]) -> io::Result<Vec<FilePatch>> {
+    // read every file out of a unified diff, skipping anything that isn't
+    // one, like a message before it or git's extended headers
+    let lines = split_lines(data);
+    let mut patches = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        if !(lines[i].starts_with(b"--- ") && i + 1 < lines.len() && lines[i + 1].starts_with(b"+++ ")) {
+            i += 1;
+            continue;
+        }
+        let old_id = try!(patch_path(&lines[i][4..], i));
+        let new_id = try!(patch_path(&lines[i + 1][4..], i + 1));
+        if old_id.is_none() && new_id.is_none() {
+            return Err(bad_patch(i, "the patch names no file"));
+        }
+        i += 2;
+
+        let mut hunks = vec![];
+        while i < lines.len() && lines[i].starts_with(b"@@ ") {
+            let (hunk, next) = try!(parse_hunk(&lines, i));
+            hunks.push(hunk);
+            i = next;
+        }
+        trace!("Read {} hunks for {:?}", hunks.len(), new_id.as_ref().or(old_id.as_ref()));
+        patches.push(FilePatch {
+            old_id: old_id,
+            new_id: new_id,
+            hunks: hunks
+        });
+    }
+    Ok(patches)
+}
+
+fn line_keys(lines: &[Vec<u8>], missing: bool) -> Vec<(&[u8], bool)> {
+    // a last line that gained or lost its terminator has to count as changed
+    lines.iter().enumerate().map(|(i, line)| (&line[..], missing && i + 1 == lines.len())).collect()
+}
+
+fn push_patch_line(out: &mut Vec<u8>, kind: u8, line: &[u8], missing: bool) {
+    out.push(kind);
+    out.extend(line.iter().cloned());
+    out.push(b'\n');
+    if missing {
+        out.extend(b"\\ No newline at end of file\n".iter().cloned());
+    }
+}
+
+pub fn write_patch(old_id: Option<&Path>, new_id: Option<&Path>, old: &[u8], new: &[u8]) -> Vec<u8> {
+    // a unified diff of one file that parse_patch reads back exactly, down
+    // to a missing final newline. empty if the file didn't change
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let old_missing = !old.is_empty() && old[old.len() - 1] != b'\n';
+    let new_missing = !new.is_empty() && new[new.len() - 1] != b'\n';
+    let ops = diff(&line_keys(&old_lines, old_missing), &line_keys(&new_lines, new_missing));
+    let file_hunks = hunks(&ops, 3);
+    if file_hunks.is_empty() && old_id.is_some() && new_id.is_some() {
+        return vec![];
+    }
+
+    let name = |id: Option<&Path>, side: &str| match id {
+        Some(id) => format!("{}{}", side, escape_id(id)),
+        None => "/dev/null".to_string()
+    };
+    let mut out = format!("--- {}\n+++ {}\n", name(old_id, "a/"), name(new_id, "b/")).into_bytes();
+    for hunk in file_hunks.iter() {
+        let old_start = if hunk.old_len == 0 {hunk.old_start} else {hunk.old_start + 1};
+        let new_start = if hunk.new_len == 0 {hunk.new_start} else {hunk.new_start + 1};
+        out.extend(format!("@@ -{},{} +{},{} @@\n", old_start, hunk.old_len, new_start, hunk.new_len).into_bytes());
+        for op in hunk.ops.iter() {
+            match *op {
+                DiffOp::Equal(o, _) => {
+                    push_patch_line(&mut out, b' ', &old_lines[o], old_missing && o + 1 == old_lines.len());
+                },
+                DiffOp::Delete(o) => {
+                    push_patch_line(&mut out, b'-', &old_lines[o], old_missing && o + 1 == old_lines.len());
+                },
+                DiffOp::Insert(n) => {
+                    push_patch_line(&mut out, b'+', &new_lines[n], new_missing && n + 1 == new_lines.len());
+                }
+            }
+        }
+    }
+    out
+}
+
+fn find_lines(lines: &[Vec<u8>], from: usize, expected: usize, want: &[&Vec<u8>]) -> Option<usize> {
+    // the place closest to expected, at or after from, where want matches
+    if want.len() > lines.len() || from > lines.len() - want.len() {
+        return None;
+    }
+    let last = lines.len() - want.len();
+    let expected = cmp::max(from, cmp::min(expected, last));
+    let matches = |at: usize| (0..want.len()).all(|i| lines[at + i] == *want[i]);
+    for distance in 0..(cmp::max(expected - from, last - expected) + 1) {
+        if expected + distance <= last && matches(expected + distance) {
+            return Some(expected + distance);
+        }
+        if distance > 0 && distance <= expected - from && matches(expected - distance) {
+            return Some(expected - distance);
+        }
+    }
+    None
+}
+
+fn place_hunk(lines: &[Vec<u8>], from: usize, expected: usize, hunk: &PatchHunk, fuzz: usize)
+              -> Option<(usize, usize, usize)> {
+    // where the hunk applies and how much context was left off each end to
+    // get it there. fuzz is how many context lines may be ignored
+    let ops = &hunk.hunk.ops;
+    let leading = ops.iter().take_while(|op| match **op {DiffOp::Equal(_, _) => true, _ => false}).count();
+    let trailing = ops.iter().rev().take_while(|op| match **op {DiffOp::Equal(_, _) => true, _ => false}).count();
+    for level in 0..(fuzz + 1) {
+        let lead = cmp::min(level, leading);
+        let trail = cmp::min(level, cmp::min(trailing, ops.len() - lead));
+        let want: Vec<&Vec<u8>> = ops[lead..ops.len() - trail].iter().filter_map(|op| match *op {
+            DiffOp::Equal(o, _) | DiffOp::Delete(o) => Some(&hunk.old[o]),
+            DiffOp::Insert(_) => None
+        }).collect();
+        if let Some(at) = find_lines(lines, from, expected + lead, &want) {
+            if level > 0 {
+                debug!("Hunk applied with fuzz {}", level);
+            }
+            return Some((at, lead, trail));
+        }
+        if lead == leading && trail == trailing {
+            break;
+        }
+    }
+    None
+}
+
+pub fn apply_patch(old: &[u8], patch: &FilePatch, fuzz: usize) -> io::Result<Vec<u8>> {
+    // apply every hunk to the old content, failing if any of them can't find
+    // its lines
+    let lines = split_lines(old);
+    let old_missing = !old.is_empty() && old[old.len() - 1] != b'\n';
+    // each line of the result, and whether it had a terminator where it came from
+    let mut out: Vec<(&[u8], bool)> = vec![];
+    let mut pos = 0;
+    // how far the hunks so far landed from where their headers put them
+    let mut drift = 0isize;
+    let mut failed = vec![];
+
+    for (n, hunk) in patch.hunks.iter().enumerate() {
+        let expected = cmp::max(hunk.hunk.old_start as isize + drift, 0) as usize;
+        let (at, lead, trail) = match place_hunk(&lines, pos, expected, hunk, fuzz) {
+            Some(place) => place,
+            None => {
+                debug!("Hunk {} does not apply", n + 1);
+                failed.push(format!("{}", n + 1));
+                continue;
+            }
+        };
+        drift = at as isize - lead as isize - hunk.hunk.old_start as isize;
+        for i in pos..at {
+            out.push((&lines[i][..], old_missing && i + 1 == lines.len()));
+        }
+        let mut i = at;
+        for op in hunk.hunk.ops[lead..hunk.hunk.ops.len() - trail].iter() {
+            match *op {
+                DiffOp::Equal(_, _) => {
+                    out.push((&lines[i][..], old_missing && i + 1 == lines.len()));
+                    i += 1;
+                },
+                DiffOp::Delete(_) => {
+                    i += 1;
+                },
+                DiffOp::Insert(n) => {
+                    out.push((&hunk.new[n][..], hunk.new_missing_newline && n + 1 == hunk.new.len()));
+                }
+            }
+        }
+        pos = i;
+    }
+    if !failed.is_empty() {
+        let mut list = String::new();
+        for (i, n) in failed.iter().enumerate() {
+            if i > 0 {
+                list.push_str(", ");
+            }
+            list.push_str(n);
+        }
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("hunk {} of {} does not apply", list, patch.hunks.len())));
+    }
+    for i in pos..lines.len() {
+        out.push((&lines[i][..], old_missing && i + 1 == lines.len()));
+    }
+
+    let mut data = vec![];
+    let mut missing = false;
+    for &(line, line_missing) in out.iter() {
+        data.extend(line.iter().cloned());
+        data.push(b'\n');
+        missing = line_missing;
+    }
+    if missing {
+        data.pop();
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    const PATCH: &'static [u8] = b"a message before the patch
+--- a/dir/file
++++ b/dir/file
+@@ -1,4 +1,4 @@
+ one
+-two
++TWO
+ three
+ four
+@@ -8,3 +8,4 @@
+ eight
+ nine
+ ten
++eleven
+\\ No newline at end of file
+";
+
+    #[test]
+    fn test_parse_patch() {
+        let files = parse_patch(PATCH).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].old_id, Some(PathBuf::from("dir/file")));
+        assert_eq!(files[0].hunks.len(), 2);
+        let hunk = &files[0].hunks[1];
+        assert_eq!((hunk.hunk.old_start, hunk.hunk.old_len, hunk.hunk.new_len), (7, 3, 4));
+        assert_eq!(hunk.new[3], b"eleven".to_vec());
+        assert!(hunk.new_missing_newline && !hunk.old_missing_newline);
+
+        assert!(parse_patch(b"--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n a\n").is_err());
+        let created = parse_patch(b"--- /dev/null\n+++ b/new\n@@ -0,0 +1 @@\n+hello\n").unwrap();
+        assert_eq!(created[0].old_id, None);
+        assert_eq!(apply_patch(b"", &created[0], 0).unwrap(), b"hello\n".to_vec());
+    }
+
+    #[test]
+    fn test_hostile_patch() {
+        // names that would write or delete outside the checkout, or inside
+        // the repository, whichever side of the patch they're on
+        let names: [&[u8]; 6] = [b"../../x", b"b/../x", b"/etc/foo", b"a/dir/../../x", b".h2/config",
+                                 b"b/sub/.h2/revs"];
+        for name in names.iter() {
+            let mut created = b"--- /dev/null\n+++ ".to_vec();
+            created.extend(name.iter().cloned());
+            created.extend(b"\n@@ -0,0 +1 @@\n+hello\n".iter().cloned());
+            assert!(parse_patch(&created).is_err(), "{}", String::from_utf8_lossy(name));
+
+            let mut deleted = b"--- ".to_vec();
+            deleted.extend(name.iter().cloned());
+            deleted.extend(b"\n+++ /dev/null\n@@ -1 +0,0 @@\n-hello\n".iter().cloned());
+            assert!(parse_patch(&deleted).is_err(), "{}", String::from_utf8_lossy(name));
+        }
+        assert!(parse_patch(b"--- a/dir/..x\n+++ b/dir/..x\n@@ -1 +1 @@\n-a\n+b\n").is_ok());
+    }
+
+    fn round_trip(old: &[u8], new: &[u8]) {
+        let id = Path::new("a file");
+        let patch = write_patch(Some(id), Some(id), old, new);
+        let files = parse_patch(&patch).unwrap();
+        assert_eq!(files[0].new_id, Some(PathBuf::from("a file")));
+        assert_eq!(apply_patch(old, &files[0], 0).unwrap(), new.to_vec());
+    }
+
+    #[test]
+    fn test_write_patch() {
+        round_trip(b"one\ntwo\nthree\n", b"one\nTWO\nthree\nfour\n");
+        round_trip(b"one\ntwo\n", b"one\ntwo");
+        round_trip(b"one\ntwo", b"one\ntwo\n");
+        round_trip(b"one\r\n", b"zero\r\none\r\n");
+        round_trip(b"", b"new\n");
+        // escape sequences in a file are its content like anything else
+        round_trip(b"plain\n", b"\x1b[7mplain\x1b[27m\n");
+        assert!(write_patch(Some(Path::new("a")), Some(Path::new("a")), b"same\n", b"same\n").is_empty());
+
+        let created = write_patch(None, Some(Path::new("new")), b"", b"hello");
+        assert_eq!(created, b"--- /dev/null\n+++ b/new\n@@ -0,0 +1,1 @@\n+hello\n\\ No newline at end of file\n".to_vec());
+        let deleted = parse_patch(&write_patch(Some(Path::new("old")), None, b"bye\n", b"")).unwrap();
+        assert_eq!(deleted[0].new_id, None);
+        assert_eq!(apply_patch(b"bye\n", &deleted[0], 0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let patch = &parse_patch(PATCH).unwrap()[0];
+        let old = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        assert_eq!(apply_patch(old, patch, 0).unwrap(),
+                   b"one\nTWO\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\neleven".to_vec());
+
+        // lines added above move the hunks, they still apply
+        let moved = b"zero\none\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        assert!(apply_patch(moved, patch, 0).unwrap().starts_with(b"zero\none\nTWO\n"));
+
+        // a changed context line needs fuzz
+        let fuzzy = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\nEIGHT\nnine\nten\n";
+        assert!(apply_patch(fuzzy, patch, 0).is_err());
+        assert!(apply_patch(fuzzy, patch, 1).unwrap().ends_with(b"EIGHT\nnine\nten\neleven"));
+
+        // a removed line that isn't there is a conflict
+        let conflict = b"one\n2\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        assert!(apply_patch(conflict, patch, 2).is_err());
+    }
+}
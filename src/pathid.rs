@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+
+use std::io;
+use std::str;
+
+use platform::*;
+
+// path ids are arbitrary bytes on unix, these keep them intact wherever they
+// get stored and make them safe to print. stored ids always separate
+// components with `/`, whatever the platform uses
+
+pub fn id_bytes(id: &Path) -> Vec<u8> {
+    let mut bytes = vec![];
+    for component in id.iter() {
+        if !bytes.is_empty() {
+            bytes.push(b'/');
+        }
+        bytes.extend(os_bytes(component).iter().cloned());
+    }
+    bytes
+}
+
+pub fn id_from_bytes(bytes: &[u8]) -> PathBuf {
+    let mut id = PathBuf::new();
+    for component in bytes.split(|&c| c == b'/').filter(|c| !c.is_empty()) {
+        id.push(os_from_bytes(component));
+    }
+    id
+}
+
+fn char_len(bytes: &[u8]) -> Option<usize> {
+    // length of the utf-8 character at the start of bytes, if it's valid
+    let len = match bytes[0] {
+        0x00...0x7f => 1,
+        0xc0...0xdf => 2,
+        0xe0...0xef => 3,
+        0xf0...0xf7 => 4,
+        _ => return None
+    };
+    if len <= bytes.len() && str::from_utf8(&bytes[..len]).is_ok() {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+pub fn escape_id(id: &Path) -> String {
+    // valid utf-8 is kept as is apart from control characters and
+    // backslashes, anything else becomes \xNN so it can be read back
+    let mut out = String::new();
+    let all = id_bytes(id);
+    let mut bytes = &all[..];
+    while !bytes.is_empty() {
+        match char_len(bytes) {
+            Some(1) if bytes[0] == b'\\' => {
+                out.push_str("\\\\");
+            },
+            Some(1) if bytes[0] < 0x20 || bytes[0] == 0x7f => {
+                out.push_str(&format!("\\x{:02x}", bytes[0]));
+            },
+            Some(len) => {
+                out.push_str(str::from_utf8(&bytes[..len]).unwrap());
+                bytes = &bytes[len..];
+                continue;
+            },
+            None => {
+                out.push_str(&format!("\\x{:02x}", bytes[0]));
+            }
+        }
+        bytes = &bytes[1..];
+    }
+    out
+}
+
+pub fn unescape_id(text: &str) -> io::Result<PathBuf> {
+    Ok(id_from_bytes(&try!(unescape_bytes(text))))
+}
+
+pub fn unescape_bytes(text: &str) -> io::Result<Vec<u8>> {
+    // the name escape_id wrote, before it's split into components
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("Bad escaped path: {:?}", text));
+    let input = text.as_bytes();
+    let mut bytes = vec![];
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'\\' {
+            bytes.push(input[i]);
+            i += 1;
+        } else if i + 1 < input.len() && input[i + 1] == b'\\' {
+            bytes.push(b'\\');
+            i += 2;
+        } else if i + 3 < input.len() && input[i + 1] == b'x' {
+            let hex = try!(str::from_utf8(&input[i + 2..i + 4]).map_err(|_| bad()));
+            bytes.push(try!(u8::from_str_radix(hex, 16).map_err(|_| bad())));
+            i += 4;
+        } else {
+            return Err(bad());
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_escape_round_trip() {
+        let names: Vec<&[u8]> = vec![b"plain/file.rs", b"caf\xc3\xa9", b"bad\xffbyte", b"back\\slash", b"tab\tname"];
+        for name in names {
+            let id = id_from_bytes(name);
+            let escaped = escape_id(&id);
+            assert!(!escaped.contains('\t'));
+            assert_eq!(unescape_id(&escaped).unwrap(), id);
+        }
+        assert_eq!(escape_id(Path::new("caf\u{e9}")), "caf\u{e9}");
+        assert_eq!(escape_id(&id_from_bytes(b"a\xff")), "a\\xff");
+        assert!(unescape_id("trailing\\").is_err());
+    }
+}
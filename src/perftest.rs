@@ -10,25 +10,385 @@
 extern crate log;
 extern crate env_logger;
 extern crate test;
+extern crate rustc_serialize;
+extern crate half2;
+
+use std::io::{Read, Seek, Write};
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
 
 use std::fmt;
 use std::fs;
-use std::io;
-use std::mem;
 use std::env;
+use std::io;
+use std::process;
+
+use rustc_serialize::json;
+
+use half2::tree::*;
+use half2::posbuf::*;
+use half2::platform::*;
+use half2::instrument::*;
+
+// times tree inserts, lookups and removes over a set of scenarios, every
+// combination of the sizes, key orders, buffers and growth policies asked
+// for. results go to stdout as a table, and with --report as json for
+// comparing between runs
+
+const EXIT_USAGE: i32 = 2;
+
+const USAGE: &'static str = "Usage: perftest [--items N[,N...]] [--keys sequential|random|both] \
+                             [--buffer memory|file|both] [--width N] [--growth exact|double|N[,...]] \
+                             [--seed N] [--report PATH|-]";
 
-use tree::*;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyOrder {
+    // 1 to the item count, in order
+    Sequential,
+    // the same keys shuffled, the same way for the same seed
+    Random
+}
+
+impl fmt::Display for KeyOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyOrder::Sequential => write!(f, "sequential"),
+            KeyOrder::Random => write!(f, "random")
+        }
+    }
+}
 
-mod tree;
+impl KeyOrder {
+    fn from_names(name: &str) -> Option<Vec<KeyOrder>> {
+        match name {
+            "sequential" => Some(vec![KeyOrder::Sequential]),
+            "random" => Some(vec![KeyOrder::Random]),
+            "both" => Some(vec![KeyOrder::Sequential, KeyOrder::Random]),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BufferKind {
+    // a vector in memory, what the tree itself costs
+    Memory,
+    // a temporary file behind a PosBuffer, the way indexes are written
+    File
+}
+
+impl fmt::Display for BufferKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BufferKind::Memory => write!(f, "memory"),
+            BufferKind::File => write!(f, "file")
+        }
+    }
+}
+
+impl BufferKind {
+    fn from_names(name: &str) -> Option<Vec<BufferKind>> {
+        match name {
+            "memory" => Some(vec![BufferKind::Memory]),
+            "file" => Some(vec![BufferKind::File]),
+            "both" => Some(vec![BufferKind::Memory, BufferKind::File]),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Settings {
+    items: Vec<usize>,
+    keys: Vec<KeyOrder>,
+    buffers: Vec<BufferKind>,
+    width: usize,
+    growths: Vec<TreeGrowth>,
+    seed: u64,
+    // none for just the table, "-" for json on stdout instead of it
+    report: Option<String>
+}
+
+#[derive(Debug, Default, RustcEncodable)]
+struct PhaseReport {
+    ns: u64,
+    tree_reads: u64,
+    tree_writes: u64
+}
+
+#[derive(Debug, RustcEncodable)]
+struct ScenarioReport {
+    items: usize,
+    keys: String,
+    buffer: String,
+    width: usize,
+    growth: String,
+    // nodes once every item is in
+    nodes: usize,
+    // for a file, how long it was grown to by the inserts and how long it
+    // is once the room left over is trimmed
+    file_bytes: Option<u64>,
+    trimmed_bytes: Option<u64>,
+    insert: PhaseReport,
+    lookup: PhaseReport,
+    remove: PhaseReport
+}
+
+#[derive(Debug, RustcEncodable)]
+struct Report {
+    seed: u64,
+    scenarios: Vec<ScenarioReport>
+}
+
+fn usage_error(message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}\n{}", message, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn option_value<T: FromStr>(args: &[String], name: &str) -> Option<T> {
+    // the value after a flag, exiting if the flag is there without a usable
+    // value
+    match args.iter().position(|a| a == name) {
+        Some(i) => match args.get(i + 1).and_then(|value| value.parse().ok()) {
+            Some(value) => Some(value),
+            None => usage_error(&format!("{} is missing a value or has a bad one", name))
+        },
+        None => None
+    }
+}
+
+fn parse_settings(args: &[String]) -> Settings {
+    let items = match option_value::<String>(args, "--items") {
+        None => vec![200000],
+        Some(list) => list.split(',').map(|count| match count.parse() {
+            Ok(count) if count > 0 => count,
+            _ => usage_error(&format!("--items takes positive counts, not {:?}", count))
+        }).collect()
+    };
+    let keys = match option_value::<String>(args, "--keys") {
+        None => vec![KeyOrder::Sequential, KeyOrder::Random],
+        Some(name) => KeyOrder::from_names(&name).unwrap_or_else(|| {
+            usage_error(&format!("--keys takes sequential, random or both, not {:?}", name))
+        })
+    };
+    let buffers = match option_value::<String>(args, "--buffer") {
+        None => vec![BufferKind::Memory, BufferKind::File],
+        Some(name) => BufferKind::from_names(&name).unwrap_or_else(|| {
+            usage_error(&format!("--buffer takes memory, file or both, not {:?}", name))
+        })
+    };
+    let width = match option_value::<usize>(args, "--width") {
+        None => page_width::<u64>(),
+        Some(width) if width >= MIN_TREE_WIDTH => width,
+        Some(width) => usage_error(&format!("--width must be at least {}, not {}", MIN_TREE_WIDTH, width))
+    };
+    let growths = match option_value::<String>(args, "--growth") {
+        // every policy side by side, which is what the file sizes are for
+        None => vec![TreeGrowth::Exact, TreeGrowth::Double, TreeGrowth::Chunk(256)],
+        Some(list) => list.split(',').map(|name| match TreeGrowth::from_name(name) {
+            Some(growth) => growth,
+            None => usage_error(&format!("--growth takes exact, double or numbers of nodes, not {:?}", name))
+        }).collect()
+    };
+    Settings {
+        items: items,
+        keys: keys,
+        buffers: buffers,
+        width: width,
+        growths: growths,
+        seed: option_value(args, "--seed").unwrap_or(1),
+        report: option_value(args, "--report")
+    }
+}
+
+fn make_keys(items: usize, order: KeyOrder, seed: u64) -> Vec<u64> {
+    let mut keys: Vec<u64> = (1..items as u64 + 1).collect();
+    if order == KeyOrder::Random {
+        // xorshift64*, so a seed gives the same order on every run
+        let mut state = if seed == 0 {1} else {seed};
+        for i in (1..keys.len()).rev() {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let j = (state.wrapping_mul(2685821657736338717) % (i as u64 + 1)) as usize;
+            keys.swap(i, j);
+        }
+    }
+    keys
+}
+
+fn time_phase<F: FnMut() -> io::Result<()>>(mut phase: F) -> io::Result<PhaseReport> {
+    reset_counters();
+    let started = monotonic_ns();
+    try!(phase());
+    Ok(PhaseReport {
+        ns: monotonic_ns() - started,
+        tree_reads: counter_value(Counter::TreeReads),
+        tree_writes: counter_value(Counter::TreeWrites)
+    })
+}
+
+// what a scenario's tree measured
+struct Phases {
+    nodes: usize,
+    file_bytes: Option<u64>,
+    trimmed_bytes: Option<u64>,
+    insert: PhaseReport,
+    lookup: PhaseReport,
+    remove: PhaseReport
+}
+
+fn file_len(file: Option<&Path>) -> io::Result<Option<u64>> {
+    match file {
+        Some(path) => Ok(Some(try!(fs::metadata(path)).len())),
+        None => Ok(None)
+    }
+}
+
+fn run_phases<T>(mut tree: BufTree<T, u64>, keys: &[u64], growth: TreeGrowth, file: Option<&Path>)
+                 -> io::Result<Phases>
+    where T: Read + Write + Seek + fmt::Debug + Truncate + Preallocate {
+    tree.set_growth(growth);
+    let insert = try!(time_phase(|| {
+        for &key in keys.iter() {
+            try!(tree.insert(key));
+        }
+        Ok(())
+    }));
+    try!(tree.flush());
+    let file_bytes = try!(file_len(file));
+    try!(tree.trim());
+    let trimmed_bytes = try!(file_len(file));
+    // checked outside the timings, a fast wrong tree is no use
+    if try!(tree.verify()) != keys.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Tree is missing items after inserting"));
+    }
+    let nodes = try!(tree.node_count());
+
+    let lookup = try!(time_phase(|| {
+        for &key in keys.iter() {
+            if !try!(tree.contains(key)) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Lookup missed key {}", key)));
+            }
+        }
+        Ok(())
+    }));
+    let remove = try!(time_phase(|| {
+        for &key in keys.iter() {
+            if try!(tree.remove(key)).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Remove missed key {}", key)));
+            }
+        }
+        Ok(())
+    }));
+    if !tree.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Tree still has items after removing them all"));
+    }
+    Ok(Phases {
+        nodes: nodes,
+        file_bytes: file_bytes,
+        trimmed_bytes: trimmed_bytes,
+        insert: insert,
+        lookup: lookup,
+        remove: remove
+    })
+}
+
+fn run_scenario(settings: &Settings, items: usize, order: KeyOrder, buffer: BufferKind, growth: TreeGrowth)
+                -> io::Result<ScenarioReport> {
+    let keys = make_keys(items, order, settings.seed);
+    let phases = match buffer {
+        BufferKind::Memory => {
+            let tree = try!(BufTree::new(io::Cursor::new(vec![]), settings.width));
+            try!(run_phases(tree, &keys, growth, None))
+        },
+        BufferKind::File => {
+            let path: PathBuf = env::temp_dir().join(format!("h2-perftest-{}-{}-{}", items, order, growth));
+            let file = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path));
+            let tree = try!(BufTree::new(PosBuffer::new(file), settings.width));
+            let result = run_phases(tree, &keys, growth, Some(&path));
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to remove {:?}: {}", path, e);
+            }
+            try!(result)
+        }
+    };
+    Ok(ScenarioReport {
+        items: items,
+        keys: format!("{}", order),
+        buffer: format!("{}", buffer),
+        width: settings.width,
+        growth: format!("{}", growth),
+        nodes: phases.nodes,
+        file_bytes: phases.file_bytes,
+        trimmed_bytes: phases.trimmed_bytes,
+        insert: phases.insert,
+        lookup: phases.lookup,
+        remove: phases.remove
+    })
+}
+
+fn print_scenario(scenario: &ScenarioReport) {
+    for &(name, phase) in [("insert", &scenario.insert), ("lookup", &scenario.lookup),
+                           ("remove", &scenario.remove)].iter() {
+        let secs = phase.ns as f64 / 1e9;
+        println!("{:>8} {:>10} {:>6} {:>6} {}: {:>10.0} ops/s {:>8} ms {:>10} reads {:>10} writes",
+                 scenario.items, scenario.keys, scenario.buffer, scenario.growth, name,
+                 scenario.items as f64 / secs, phase.ns / 1000000, phase.tree_reads, phase.tree_writes);
+    }
+    if let (Some(grown), Some(trimmed)) = (scenario.file_bytes, scenario.trimmed_bytes) {
+        println!("{:>8} {:>10} {:>6} {:>6} file: {} bytes grown, {} after trim",
+                 scenario.items, scenario.keys, scenario.buffer, scenario.growth, grown, trimmed);
+    }
+}
 
 fn main() {
-    let mut tree: BufTree<_, usize> = BufTree::default();
-    
-    for i in 0..200000 {
-        tree.insert(i + 1).unwrap();
+    env_logger::init().unwrap();
+    let args: Vec<String> = env::args().collect();
+    if args[1..].iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let settings = parse_settings(&args);
+    let table = settings.report.as_ref().map_or(true, |path| path != "-");
+
+    let mut report = Report {
+        seed: settings.seed,
+        scenarios: vec![]
+    };
+    for &items in settings.items.iter() {
+        for &order in settings.keys.iter() {
+            for &buffer in settings.buffers.iter() {
+                for &growth in settings.growths.iter() {
+                    let scenario = match run_scenario(&settings, items, order, buffer, growth) {
+                        Ok(scenario) => scenario,
+                        Err(e) => {
+                            let _ = writeln!(io::stderr(), "perftest: {} {} keys in {} growing {}: {}",
+                                             items, order, buffer, growth, e);
+                            process::exit(1);
+                        }
+                    };
+                    if table {
+                        print_scenario(&scenario);
+                    }
+                    report.scenarios.push(scenario);
+                }
+            }
+        }
     }
 
-    for i in (200000..0).step_by(-1) {
-        assert!(tree.contains(&i).unwrap());
+    if let Some(ref path) = settings.report {
+        let data = match json::encode(&report) {
+            Err(e) => {
+                panic!("Failed to encode to json: {}", e)
+            },
+            Ok(d) => d
+        };
+        if path == "-" {
+            println!("{}", data);
+        } else if let Err(e) = fs::File::create(path).and_then(|mut f| f.write_all(data.as_bytes())) {
+            let _ = writeln!(io::stderr(), "perftest: failed to write {}: {}", path, e);
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use std::fmt;
+
+use pathid::*;
+
+// a change to the filesystem a command is about to make
+#[derive(Debug, Clone, Copy)]
+pub enum Op<'a> {
+    CreateDir(&'a Path),
+    CopyFile(&'a Path, &'a Path),
+    CopyDir(&'a Path, &'a Path),
+    WriteFile(&'a Path),
+    // a file or a whole directory tree
+    Remove(&'a Path),
+    // build or rebuild the line index in a log directory
+    WriteIndex(&'a Path),
+    // hooks can do anything, so a dry run doesn't run them either
+    RunHook(&'a Path)
+}
+
+impl<'a> fmt::Display for Op<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::CreateDir(path) => write!(f, "create directory {}", escape_id(path)),
+            Op::CopyFile(from, to) => write!(f, "copy {} to {}", escape_id(from), escape_id(to)),
+            Op::CopyDir(from, to) => write!(f, "copy directory {} to {}", escape_id(from), escape_id(to)),
+            Op::WriteFile(path) => write!(f, "write {}", escape_id(path)),
+            Op::Remove(path) => write!(f, "remove {}", escape_id(path)),
+            Op::WriteIndex(path) => write!(f, "write index {}", escape_id(path)),
+            Op::RunHook(path) => write!(f, "run hook {}", escape_id(path))
+        }
+    }
+}
+
+// decides whether mutating operations actually happen. every change to the
+// repository or checkout asks first, so a dry run can report them instead
+#[derive(Clone, Copy, Default)]
+pub struct Plan {
+    dry_run: bool,
+    // told about each operation a dry run skips
+    report: Option<fn(&Op)>
+}
+
+impl Plan {
+    pub fn new(dry_run: bool) -> Plan {
+        Plan {
+            dry_run: dry_run,
+            report: None
+        }
+    }
+
+    pub fn with_report(mut self, report: fn(&Op)) -> Plan {
+        self.report = Some(report);
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn allow(&self, op: Op) -> bool {
+        // true if the caller should go ahead with the operation
+        if self.dry_run {
+            debug!("Would {}", op);
+            if let Some(report) = self.report {
+                report(&op);
+            }
+            false
+        } else {
+            trace!("Going to {}", op);
+            true
+        }
+    }
+}
+
+impl fmt::Debug for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Plan {{ dry_run: {} }}", self.dry_run)
+    }
+}
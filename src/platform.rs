@@ -0,0 +1,325 @@
+// the parts of the filesystem that differ between unix and windows. the
+// rest of the crate only goes through these, so a port is a matter of
+// filling in another imp module
+pub use self::imp::*;
+
+use std::fmt;
+
+// what a path that's neither a file nor a directory is. none of them can be
+// staged, so walks leave them out or stop at them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFile {
+    Symlink,
+    Socket,
+    Fifo,
+    BlockDevice,
+    CharDevice,
+    Other
+}
+
+impl fmt::Display for SpecialFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpecialFile::Symlink => write!(f, "symlink"),
+            SpecialFile::Socket => write!(f, "socket"),
+            SpecialFile::Fifo => write!(f, "fifo"),
+            SpecialFile::BlockDevice => write!(f, "block device"),
+            SpecialFile::CharDevice => write!(f, "character device"),
+            SpecialFile::Other => write!(f, "special file")
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::borrow::Cow;
+
+    use super::SpecialFile;
+
+    use std::fs;
+    use std::io;
+
+    #[repr(C)]
+    struct Timespec {
+        sec: i64,
+        nsec: i64
+    }
+
+    #[cfg(target_os = "macos")]
+    const CLOCK_MONOTONIC: i32 = 6;
+    #[cfg(not(target_os = "macos"))]
+    const CLOCK_MONOTONIC: i32 = 1;
+
+    // FICLONE from linux/fs.h, and the errors that mean a clone can't be
+    // made here rather than that something went wrong: EXDEV, EINVAL,
+    // ENOTTY and EOPNOTSUPP
+    #[cfg(target_os = "linux")]
+    const FICLONE: u64 = 0x40049409;
+    #[cfg(target_os = "linux")]
+    const CLONE_UNSUPPORTED: [i32; 4] = [18, 22, 25, 95];
+
+    // flock(2) operations, the same on every unix we care about
+    const LOCK_SH: i32 = 1;
+    const LOCK_EX: i32 = 2;
+    const LOCK_NB: i32 = 4;
+    const LOCK_UN: i32 = 8;
+
+    extern {
+        fn flock(fd: i32, operation: i32) -> i32;
+        fn getpid() -> i32;
+        fn clock_gettime(clock: i32, time: *mut Timespec) -> i32;
+        fn isatty(fd: i32) -> i32;
+    }
+
+    #[cfg(target_os = "linux")]
+    extern {
+        fn ioctl(fd: i32, request: u64, ...) -> i32;
+    }
+
+    pub fn os_bytes(name: &OsStr) -> Cow<[u8]> {
+        // names are already bytes
+        Cow::Borrowed(name.as_bytes())
+    }
+
+    pub fn os_from_bytes(bytes: &[u8]) -> OsString {
+        OsString::from_vec(bytes.to_vec())
+    }
+
+    pub fn mtime(metadata: &fs::Metadata) -> (i64, i64) {
+        (metadata.mtime(), metadata.mtime_nsec())
+    }
+
+    pub fn process_id() -> u32 {
+        unsafe {getpid() as u32}
+    }
+
+    pub fn stdout_is_terminal() -> bool {
+        unsafe {isatty(1) == 1}
+    }
+
+    pub fn is_executable(metadata: &fs::Metadata) -> bool {
+        // any of the execute bits
+        metadata.is_file() && metadata.mode() & 0o111 != 0
+    }
+
+    pub fn mode_bits(metadata: &fs::Metadata) -> u32 {
+        metadata.mode() & 0o7777
+    }
+
+    pub fn special_file(metadata: &fs::Metadata) -> Option<SpecialFile> {
+        // by the file type bits of its mode, none for a file or directory
+        if metadata.is_file() || metadata.is_dir() {
+            return None;
+        }
+        Some(match metadata.mode() & 0o170000 {
+            0o120000 => SpecialFile::Symlink,
+            0o140000 => SpecialFile::Socket,
+            0o010000 => SpecialFile::Fifo,
+            0o060000 => SpecialFile::BlockDevice,
+            0o020000 => SpecialFile::CharDevice,
+            _ => SpecialFile::Other
+        })
+    }
+
+    pub fn monotonic_ns() -> u64 {
+        // only good for measuring intervals
+        let mut time = Timespec {sec: 0, nsec: 0};
+        unsafe {clock_gettime(CLOCK_MONOTONIC, &mut time)};
+        time.sec as u64 * 1000000000 + time.nsec as u64
+    }
+
+    pub fn lock_file(file: &fs::File, exclusive: bool, wait: bool) -> io::Result<()> {
+        // a WouldBlock error if it's held elsewhere and we aren't waiting
+        let mut operation = if exclusive {LOCK_EX} else {LOCK_SH};
+        if !wait {
+            operation |= LOCK_NB;
+        }
+        if unsafe {flock(file.as_raw_fd(), operation)} != 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn unlock_file(file: &fs::File) {
+        unsafe {flock(file.as_raw_fd(), LOCK_UN)};
+    }
+
+    pub fn open_dir(path: &Path) -> io::Result<Option<fs::File>> {
+        // a handle on a directory, only good for locking
+        fs::File::open(path).map(Some)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn clone_file(from: &Path, to: &Path) -> io::Result<bool> {
+        // a copy-on-write clone of from at to, false if the filesystem
+        // can't make one, which leaves nothing behind
+        let input = try!(fs::File::open(from));
+        let output = try!(fs::File::create(to));
+        if unsafe {ioctl(output.as_raw_fd(), FICLONE, input.as_raw_fd())} == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        drop(output);
+        let _ = fs::remove_file(to);
+        match e.raw_os_error() {
+            Some(code) if CLONE_UNSUPPORTED.contains(&code) => Ok(false),
+            _ => Err(e)
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn clone_file(_: &Path, _: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::{OsStr, OsString};
+    use std::os::windows::fs::MetadataExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::Path;
+    use std::borrow::Cow;
+
+    use super::SpecialFile;
+
+    use std::fs;
+    use std::io;
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    // 100ns intervals between 1601 and the unix epoch
+    const EPOCH_OFFSET: u64 = 116444736000000000;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut u8
+    }
+
+    extern "system" {
+        fn LockFileEx(file: *mut u8, flags: u32, reserved: u32, low: u32, high: u32,
+                      overlapped: *mut Overlapped) -> i32;
+        fn UnlockFileEx(file: *mut u8, reserved: u32, low: u32, high: u32,
+                        overlapped: *mut Overlapped) -> i32;
+        fn GetCurrentProcessId() -> u32;
+        fn GetStdHandle(which: u32) -> *mut u8;
+        fn GetConsoleMode(console: *mut u8, mode: *mut u32) -> i32;
+        fn QueryPerformanceCounter(count: *mut i64) -> i32;
+        fn QueryPerformanceFrequency(frequency: *mut i64) -> i32;
+    }
+
+    fn whole_file() -> Overlapped {
+        Overlapped {
+            internal: 0,
+            internal_high: 0,
+            offset: 0,
+            offset_high: 0,
+            event: 0 as *mut u8
+        }
+    }
+
+    pub fn os_bytes(name: &OsStr) -> Cow<[u8]> {
+        // names are utf-16, unpaired surrogates can't be represented and
+        // come out as replacement characters
+        match name.to_string_lossy() {
+            Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+            Cow::Owned(text) => Cow::Owned(text.into_bytes())
+        }
+    }
+
+    pub fn os_from_bytes(bytes: &[u8]) -> OsString {
+        OsString::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    pub fn mtime(metadata: &fs::Metadata) -> (i64, i64) {
+        let since = metadata.last_write_time().saturating_sub(EPOCH_OFFSET);
+        ((since / 10000000) as i64, (since % 10000000 * 100) as i64)
+    }
+
+    pub fn process_id() -> u32 {
+        unsafe {GetCurrentProcessId()}
+    }
+
+    pub fn stdout_is_terminal() -> bool {
+        // only a console has a mode, STD_OUTPUT_HANDLE is -11
+        let mut mode = 0;
+        unsafe {GetConsoleMode(GetStdHandle(-11i32 as u32), &mut mode) != 0}
+    }
+
+    pub fn is_executable(metadata: &fs::Metadata) -> bool {
+        // there's no execute bit, whether it runs is up to the extension
+        metadata.is_file()
+    }
+
+    pub fn mode_bits(metadata: &fs::Metadata) -> u32 {
+        // only whether it's read only
+        if metadata.permissions().readonly() {0o555} else {0o777}
+    }
+
+    pub fn special_file(metadata: &fs::Metadata) -> Option<SpecialFile> {
+        // links are the only kind there's a way to tell apart
+        if metadata.is_file() || metadata.is_dir() {
+            None
+        } else if metadata.file_type().is_symlink() {
+            Some(SpecialFile::Symlink)
+        } else {
+            Some(SpecialFile::Other)
+        }
+    }
+
+    pub fn monotonic_ns() -> u64 {
+        let (mut count, mut frequency) = (0, 1);
+        unsafe {
+            QueryPerformanceCounter(&mut count);
+            QueryPerformanceFrequency(&mut frequency);
+        }
+        (count as u64 / frequency as u64) * 1000000000 +
+            (count as u64 % frequency as u64) * 1000000000 / frequency as u64
+    }
+
+    pub fn lock_file(file: &fs::File, exclusive: bool, wait: bool) -> io::Result<()> {
+        let mut flags = if exclusive {LOCKFILE_EXCLUSIVE_LOCK} else {0};
+        if !wait {
+            flags |= LOCKFILE_FAIL_IMMEDIATELY;
+        }
+        let mut overlapped = whole_file();
+        if unsafe {LockFileEx(file.as_raw_handle() as *mut u8, flags, 0, !0, !0, &mut overlapped)} == 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION) {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, e))
+            } else {
+                Err(e)
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn unlock_file(file: &fs::File) {
+        let mut overlapped = whole_file();
+        unsafe {UnlockFileEx(file.as_raw_handle() as *mut u8, 0, !0, !0, &mut overlapped)};
+    }
+
+    pub fn open_dir(path: &Path) -> io::Result<Option<fs::File>> {
+        // directories can't be locked, but a file that's open can't be
+        // replaced either, so a reader still never sees half an index
+        try!(fs::metadata(path));
+        Ok(None)
+    }
+
+    pub fn clone_file(_: &Path, _: &Path) -> io::Result<bool> {
+        // block cloning needs ReFS, copies are left to the caller
+        Ok(false)
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::{Read, Write};
+
+use std::io;
+
+// items that can be written out independently of the machine's endianness
+// and pointer size, everything is stored as little-endian 64 bit values
+pub trait Portable: Sized {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()>;
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<Self>;
+}
+
+pub fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 8];
+    for i in 0..8 {
+        buf[i] = (value >> (i * 8)) as u8;
+    }
+    out.write_all(&buf)
+}
+
+pub fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    let mut read = 0;
+    while read < buf.len() {
+        match try!(input.read(&mut buf[read..])) {
+            0 => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("Portable data ended after {} of 8 bytes", read)));
+            },
+            n => {
+                read += n;
+            }
+        }
+    }
+    let mut value = 0;
+    for i in 0..8 {
+        value |= (buf[i] as u64) << (i * 8);
+    }
+    Ok(value)
+}
+
+pub fn write_bytes<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    try!(write_u64(out, data.len() as u64));
+    out.write_all(data)
+}
+
+pub fn read_bytes<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let len = try!(read_u64(input));
+    let mut data = vec![];
+    try!(input.take(len).read_to_end(&mut data));
+    if data.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  format!("Portable data ended after {} of {} bytes", data.len(), len)));
+    }
+    Ok(data)
+}
+
+impl Portable for u64 {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_u64(out, *self)
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<u64> {
+        read_u64(input)
+    }
+}
+
+impl<T: Portable> Portable for Vec<T> {
+    fn write_portable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(write_u64(out, self.len() as u64));
+        for item in self.iter() {
+            try!(item.write_portable(out));
+        }
+        Ok(())
+    }
+
+    fn read_portable<R: Read>(input: &mut R) -> io::Result<Vec<T>> {
+        let len = try!(read_u64(input));
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(try!(T::read_portable(input)));
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_portable_layout() {
+        let mut out = vec![];
+        write_u64(&mut out, 0x0102030405060708).unwrap();
+        // little-endian no matter what we're running on
+        assert_eq!(out, vec![8, 7, 6, 5, 4, 3, 2, 1]);
+        write_bytes(&mut out, b"abc").unwrap();
+
+        let mut input = Cursor::new(out);
+        assert_eq!(read_u64(&mut input).unwrap(), 0x0102030405060708);
+        assert_eq!(read_bytes(&mut input).unwrap(), b"abc".to_vec());
+        assert!(read_u64(&mut input).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use std::cmp;
+use std::io;
+
+use tree::{Preallocate, Truncate};
+
+// how much is read ahead, and how much written data is held back, at once
+pub const POS_BUFFER_SIZE: usize = 8192;
+
+// a read buffer and a write buffer over one backend, which also remembers
+// where the backend's cursor is. seeking only moves our own position, the
+// backend is sought when an access doesn't start where its cursor already
+// is. reads fill a block at a time and writes that follow on from each
+// other go out together. held back writes go out before any read, on
+// flush, and when dropped, where an error can't be reported, so flush or
+// use into_inner when it matters
+#[derive(Debug)]
+pub struct PosBuffer<T: Read + Write + Seek> {
+    // only none once into_inner has taken it
+    inner: Option<T>,
+    // where the next read or write happens
+    pos: u64,
+    // where the backend's cursor is, none when that isn't known
+    inner_pos: Option<u64>,
+    read_buf: Vec<u8>,
+    read_start: u64,
+    read_len: usize,
+    write_buf: Vec<u8>,
+    write_start: u64
+}
+
+impl<T: Read + Write + Seek> PosBuffer<T> {
+    pub fn new(inner: T) -> PosBuffer<T> {
+        PosBuffer {
+            inner: Some(inner),
+            pos: 0,
+            inner_pos: None,
+            read_buf: vec![0; POS_BUFFER_SIZE],
+            read_start: 0,
+            read_len: 0,
+            write_buf: Vec::with_capacity(POS_BUFFER_SIZE),
+            write_start: 0
+        }
+    }
+
+    pub fn into_inner(mut self) -> io::Result<T> {
+        // the backend, once everything held back has been written to it
+        try!(self.flush_writes());
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn seek_inner(&mut self, pos: u64) -> io::Result<()> {
+        if self.inner_pos == Some(pos) {
+            trace!("Backend already at {}", pos);
+            return Ok(());
+        }
+        self.inner_pos = None;
+        try!(self.inner.as_mut().unwrap().seek(SeekFrom::Start(pos)));
+        self.inner_pos = Some(pos);
+        Ok(())
+    }
+
+    fn flush_writes(&mut self) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let start = self.write_start;
+        try!(self.seek_inner(start));
+        self.inner_pos = None;
+        match self.inner.as_mut().unwrap().write_all(&self.write_buf) {
+            Err(e) => {
+                error!("Failed to write {} bytes at {}: {}", self.write_buf.len(), start, e);
+                return Err(e);
+            },
+            Ok(()) => {
+                trace!("Wrote {} bytes at {}", self.write_buf.len(), start);
+            }
+        }
+        self.inner_pos = Some(start + self.write_buf.len() as u64);
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    fn read_direct(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.pos;
+        try!(self.seek_inner(pos));
+        self.inner_pos = None;
+        let read = try!(self.inner.as_mut().unwrap().read(buf));
+        self.inner_pos = Some(pos + read as u64);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Write + Seek> Drop for PosBuffer<T> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush_writes();
+        }
+    }
+}
+
+impl<T: Read + Write + Seek> Read for PosBuffer<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // anything written has to land before it can be read back
+        try!(self.flush_writes());
+        let cached = self.pos >= self.read_start && self.pos < self.read_start + self.read_len as u64;
+        if !cached {
+            if buf.len() >= POS_BUFFER_SIZE {
+                // nothing to gain from going through the buffer
+                return self.read_direct(buf);
+            }
+            let pos = self.pos;
+            try!(self.seek_inner(pos));
+            self.inner_pos = None;
+            self.read_len = 0;
+            let read = try!(self.inner.as_mut().unwrap().read(&mut self.read_buf));
+            self.inner_pos = Some(pos + read as u64);
+            self.read_start = pos;
+            self.read_len = read;
+            if read == 0 {
+                return Ok(0);
+            }
+        }
+        let offset = (self.pos - self.read_start) as usize;
+        let count = cmp::min(buf.len(), self.read_len - offset);
+        for (dest, src) in buf.iter_mut().zip(self.read_buf[offset..offset + count].iter()) {
+            *dest = *src;
+        }
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<T: Read + Write + Seek> Write for PosBuffer<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // whatever was read ahead may be about to change
+        self.read_len = 0;
+        let follows = self.pos == self.write_start + self.write_buf.len() as u64;
+        if !self.write_buf.is_empty() && (!follows || self.write_buf.len() + buf.len() > POS_BUFFER_SIZE) {
+            try!(self.flush_writes());
+        }
+        if buf.len() >= POS_BUFFER_SIZE {
+            // too big to hold back, send it as it is
+            let pos = self.pos;
+            try!(self.seek_inner(pos));
+            self.inner_pos = None;
+            let written = try!(self.inner.as_mut().unwrap().write(buf));
+            self.inner_pos = Some(pos + written as u64);
+            self.pos += written as u64;
+            return Ok(written);
+        }
+        if self.write_buf.is_empty() {
+            self.write_start = self.pos;
+        }
+        self.write_buf.extend(buf.iter().cloned());
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.flush_writes());
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<T: Read + Write + Seek> Seek for PosBuffer<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+            SeekFrom::End(offset) => {
+                // only the backend knows where its end is, and held back
+                // writes might move it
+                try!(self.flush_writes());
+                self.inner_pos = None;
+                let end = try!(self.inner.as_mut().unwrap().seek(SeekFrom::End(0)));
+                self.inner_pos = Some(end);
+                end as i64 + offset
+            }
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of a buffer"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl<T: Read + Write + Seek + Truncate> Truncate for PosBuffer<T> {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        try!(self.flush_writes());
+        self.read_len = 0;
+        self.inner.as_mut().unwrap().truncate(len)
+    }
+}
+
+impl<T: Read + Write + Seek + Preallocate> Preallocate for PosBuffer<T> {
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        try!(self.flush_writes());
+        self.inner.as_mut().unwrap().preallocate(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use std::io;
+
+    use tree::*;
+
+    // counts what actually reaches the backend
+    #[derive(Debug)]
+    struct Counted {
+        inner: Cursor<Vec<u8>>,
+        seeks: usize,
+        reads: usize,
+        writes: usize
+    }
+
+    impl Counted {
+        fn new() -> Counted {
+            Counted {
+                inner: Cursor::new(vec![]),
+                seeks: 0,
+                reads: 0,
+                writes: 0
+            }
+        }
+    }
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Counted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Counted {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.seeks += 1;
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_pos_buffer() {
+        let mut buffer = PosBuffer::new(Counted::new());
+        buffer.write_all(b"abc").unwrap();
+        buffer.write_all(b"def").unwrap();
+        // seeking back to where we are doesn't reach the backend
+        assert_eq!(buffer.seek(SeekFrom::Start(6)).unwrap(), 6);
+        buffer.write_all(b"ghi").unwrap();
+        assert_eq!(buffer.seek(SeekFrom::End(0)).unwrap(), 9);
+
+        buffer.seek(SeekFrom::Start(2)).unwrap();
+        let mut data = [0u8; 3];
+        buffer.read(&mut data).unwrap();
+        assert_eq!(&data, b"cde");
+        buffer.read(&mut data).unwrap();
+        assert_eq!(&data, b"fgh");
+
+        // a write over read ahead data is what's read back
+        buffer.seek(SeekFrom::Start(4)).unwrap();
+        buffer.write_all(b"X").unwrap();
+        buffer.seek(SeekFrom::Start(3)).unwrap();
+        buffer.read(&mut data).unwrap();
+        assert_eq!(&data, b"dXf");
+
+        let counted = buffer.into_inner().unwrap();
+        assert_eq!(&counted.inner.get_ref()[..], b"abcdXfghi");
+        assert_eq!(counted.writes, 2);
+        assert_eq!(counted.reads, 2);
+        assert_eq!(counted.seeks, 5);
+    }
+
+    #[test]
+    fn test_tree_over_pos_buffer() {
+        let mut plain: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 6).unwrap();
+        let mut buffered: BufTree<_, u64> = BufTree::new(PosBuffer::new(Counted::new()), 6).unwrap();
+        for i in 0..200u64 {
+            plain.insert((i * 7) % 200).unwrap();
+            buffered.insert((i * 7) % 200).unwrap();
+        }
+        for i in 0..50u64 {
+            plain.remove(i * 3).unwrap();
+            buffered.remove(i * 3).unwrap();
+        }
+        assert_eq!(buffered.verify().unwrap(), plain.verify().unwrap());
+        assert_eq!(buffered.get(42).unwrap(), None);
+        assert_eq!(buffered.get(43).unwrap(), Some(43));
+
+        // the same nodes land in the same places. the bytes themselves can
+        // differ, since struct padding goes down as whatever was in memory
+        let counted = buffered.into_inner().into_inner().unwrap();
+        let written = counted.inner.into_inner();
+        let plain_nodes = plain.dump_nodes().unwrap();
+        let expected = plain.into_inner().into_inner();
+        assert_eq!(written.len(), expected.len());
+        let mut reopened: BufTree<_, u64> = unsafe {BufTree::from_buffer(Cursor::new(written))}.unwrap();
+        assert_eq!(reopened.dump_nodes().unwrap(), plain_nodes);
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufRead};
+
+use std::fs;
+use std::io;
+use std::mem;
+
+use revs::*;
+use map::*;
+use chunks::*;
+
+use {Checkout, load_ignore};
+
+// upper bounds of the file size buckets we report
+const SIZE_BUCKETS: [u64; 5] = [1 << 10, 1 << 14, 1 << 18, 1 << 22, ::std::u64::MAX];
+// aim for tree nodes about the size of a page
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub files: usize,
+    pub total_size: u64,
+    pub largest: u64,
+    pub total_lines: usize,
+    pub buckets: [usize; 5],
+    pub revisions: usize,
+    pub churn: usize
+}
+
+impl Profile {
+    pub fn average_lines(&self) -> usize {
+        if self.files == 0 {
+            0
+        } else {
+            self.total_lines / self.files
+        }
+    }
+
+    pub fn tree_width(&self) -> usize {
+        // fit a node header, items and next pointers into a page
+        let item = mem::size_of::<MapEntry<u64>>() + ::std::u64::BYTES;
+        (PAGE_SIZE - 2 * ::std::u64::BYTES) / item
+    }
+
+    pub fn cache_size(&self) -> usize {
+        // enough nodes to hold the index of an average file, within reason
+        let per_node = self.tree_width();
+        let nodes = self.average_lines() / per_node + 1;
+        if nodes < 16 {
+            16
+        } else if nodes > 4096 {
+            4096
+        } else {
+            nodes
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        // one thread per couple hundred files, up to eight
+        let threads = self.files / 200 + 1;
+        if threads > 8 {8} else {threads}
+    }
+
+    pub fn chunking(&self) -> bool {
+        self.largest > DEFAULT_CHUNK_THRESHOLD
+    }
+}
+
+pub fn profile_checkout(checkout: &Checkout, revs: &Revisions) -> io::Result<Profile> {
+    let mut profile = Profile::default();
+    let ignore = try!(load_ignore(checkout));
+    info!("Profiling checkout");
+    try!(checkout.walk(Path::new(""), &ignore, |path, _, metadata| {
+        if !metadata.is_file() {
+            return Ok(());
+        }
+
+        let size = metadata.len();
+        profile.files += 1;
+        profile.total_size += size;
+        if size > profile.largest {
+            profile.largest = size;
+        }
+        for i in 0..SIZE_BUCKETS.len() {
+            if size <= SIZE_BUCKETS[i] {
+                profile.buckets[i] += 1;
+                break;
+            }
+        }
+        profile.total_lines += try!(count_lines(path));
+        Ok(())
+    }));
+
+    debug!("Measuring churn");
+    if let Some(head) = try!(revs.head()) {
+        profile.revisions = head as usize;
+        let meta = try!(revs.meta(head));
+        if let Some(parent) = meta.parent {
+            profile.churn = try!(count_changed(&revs, parent, head));
+        }
+    }
+
+    Ok(profile)
+}
+
+fn count_lines<T: AsRef<Path>>(path: T) -> io::Result<usize> {
+    // count terminators a buffer at a time, so a huge line is never held
+    let mut reader = BufReader::new(try!(fs::File::open(path)));
+    let mut count = 0;
+    let mut last = None;
+    loop {
+        let used = {
+            let available = try!(reader.fill_buf());
+            if available.is_empty() {
+                // a last line without a terminator counts too
+                if last.is_some() && last != Some(b'\n') {
+                    count += 1;
+                }
+                return Ok(count);
+            }
+            count += available.iter().filter(|&&c| c == b'\n').count();
+            last = available.last().cloned();
+            available.len()
+        };
+        reader.consume(used);
+    }
+}
+
+fn count_changed(revs: &Revisions, old: RevisionId, new: RevisionId) -> io::Result<usize> {
+    // count the files in new that differ from, or are missing in, old. files
+    // may be stored as deltas, so the content has to be compared
+    let tree_path = revs.rev_path(new).join("tree");
+    let mut changed = 0;
+    let mut to_visit = vec![tree_path.clone()];
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            let metadata = try!(entry.metadata());
+            if metadata.is_dir() {
+                to_visit.push(entry.path());
+                continue;
+            }
+            let id = match entry.path().relative_from(&tree_path) {
+                Some(id) => PathBuf::from(id),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{} is outside of the revision", entry.path().display())));
+                }
+            };
+            match (revs.read_path(old, &id), revs.read_path(new, &id)) {
+                (Ok(ref before), Ok(ref after)) if before == after => {},
+                _ => {
+                    changed += 1;
+                }
+            }
+        }
+    }
+    Ok(changed)
+}
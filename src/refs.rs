@@ -0,0 +1,363 @@
+use std::path::{Path, PathBuf};
+use std::io::Read;
+
+use std::fs;
+use std::io;
+
+use fileops::*;
+use revs::*;
+use plan::*;
+use repo::*;
+
+// names for revisions. a tag is a file under `tags` holding the id of the
+// revision it names. a branch is the same under `heads`, but commits move it
+// along: `HEAD` holds `ref: heads/<name>` for the branch they go to. without
+// a `HEAD` commits only move the head revision, like before branches
+#[derive(Debug)]
+pub struct Refs {
+    path: PathBuf,
+    plan: Plan
+}
+
+pub const DEFAULT_BRANCH: &'static str = "main";
+
+const HEAD_PREFIX: &'static str = "ref: heads/";
+
+impl Default for Refs {
+    fn default() -> Refs {
+        Refs::new(repo_path("refs"))
+    }
+}
+
+pub fn validate_ref_name(name: &str) -> io::Result<()> {
+    // names become file names and share the command line with revision ids,
+    // so they're kept to something that can't be mistaken for either
+    let problem = if name.is_empty() {
+        Some("it is empty")
+    } else if name == "HEAD" {
+        Some("HEAD is reserved")
+    } else if name.parse::<RevisionId>().is_ok() {
+        Some("it could be a revision id")
+    } else if name.starts_with('.') || name.starts_with('-') {
+        Some("it starts with '.' or '-'")
+    } else if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        Some("only letters, digits, '-', '_' and '.' are allowed")
+    } else {
+        None
+    };
+
+    match problem {
+        Some(problem) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("Invalid name {:?}: {}", name, problem)))
+        },
+        None => Ok(())
+    }
+}
+
+impl Refs {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Refs {
+        Refs {
+            path: path.into(),
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Refs {
+        self.plan = plan;
+        self
+    }
+
+    fn tags_path(&self) -> PathBuf {
+        self.path.join("tags")
+    }
+
+    fn heads_path(&self) -> PathBuf {
+        self.path.join("heads")
+    }
+
+    fn write_ref(&self, dir: &Path, name: &str, rev: RevisionId) -> io::Result<()> {
+        try!(validate_ref_name(name));
+        if self.plan.allow(Op::CreateDir(dir)) {
+            try!(fs::create_dir_all(dir));
+        }
+
+        let ref_path = dir.join(name);
+        if self.plan.allow(Op::WriteFile(&ref_path)) {
+            try!(atomic_write(&ref_path, format!("{}\n", rev).as_ref()));
+        }
+        Ok(())
+    }
+
+    fn read_ref(&self, dir: &Path, kind: &str, name: &str) -> io::Result<Option<RevisionId>> {
+        if validate_ref_name(name).is_err() {
+            return Ok(None);
+        }
+
+        let mut rev_str = String::new();
+        match fs::File::open(dir.join(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No {} named {}", kind, name);
+                return Ok(None);
+            },
+            Err(e) => {
+                error!("Failed to open {} {}: {}", kind, name, e);
+                return Err(e);
+            },
+            Ok(mut f) => {
+                try!(f.read_to_string(&mut rev_str));
+            }
+        }
+
+        match rev_str.trim().parse() {
+            Err(_) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("The {} {} is corrupt: {:?}", kind, name, rev_str.trim())))
+            },
+            Ok(rev) => Ok(Some(rev))
+        }
+    }
+
+    fn list_refs(&self, dir: &Path, kind: &str) -> io::Result<Vec<(String, RevisionId)>> {
+        // every ref in dir, sorted by name
+        let entries = match fs::read_dir(dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No {}s", kind);
+                return Ok(vec![]);
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(entries) => entries
+        };
+
+        let mut refs = vec![];
+        for item in entries {
+            let entry = try!(item);
+            if is_temp_path(entry.path()) {
+                continue;
+            }
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    warn!("Skipping {} with a bad name {:?}", kind, name);
+                    continue;
+                }
+            };
+            if let Some(rev) = try!(self.read_ref(dir, kind, &name)) {
+                refs.push((name, rev));
+            }
+        }
+        refs.sort();
+        Ok(refs)
+    }
+
+    pub fn set_tag(&self, name: &str, rev: RevisionId) -> io::Result<()> {
+        debug!("Tagging revision {} as {}", rev, name);
+        self.write_ref(&self.tags_path(), name, rev)
+    }
+
+    pub fn tag(&self, name: &str) -> io::Result<Option<RevisionId>> {
+        self.read_ref(&self.tags_path(), "tag", name)
+    }
+
+    pub fn tags(&self) -> io::Result<Vec<(String, RevisionId)>> {
+        self.list_refs(&self.tags_path(), "tag")
+    }
+
+    pub fn set_branch(&self, name: &str, rev: RevisionId) -> io::Result<()> {
+        debug!("Moving branch {} to revision {}", name, rev);
+        self.write_ref(&self.heads_path(), name, rev)
+    }
+
+    pub fn remove_branch(&self, name: &str) -> io::Result<()> {
+        // a branch whose only revision was uncommitted, gone until it's
+        // committed to again
+        let branch_path = self.heads_path().join(name);
+        if !self.plan.allow(Op::Remove(&branch_path)) {
+            return Ok(());
+        }
+        match fs::remove_file(&branch_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result
+        }
+    }
+
+    pub fn branch(&self, name: &str) -> io::Result<Option<RevisionId>> {
+        self.read_ref(&self.heads_path(), "branch", name)
+    }
+
+    pub fn branches(&self) -> io::Result<Vec<(String, RevisionId)>> {
+        self.list_refs(&self.heads_path(), "branch")
+    }
+
+    pub fn merge_head(&self) -> io::Result<Option<RevisionId>> {
+        // the revision a merge with conflicts brings in, until it's committed
+        self.read_ref(&self.path, "merge head", "MERGE_HEAD")
+    }
+
+    pub fn set_merge_head(&self, rev: RevisionId) -> io::Result<()> {
+        debug!("Merging revision {}", rev);
+        self.write_ref(&self.path, "MERGE_HEAD", rev)
+    }
+
+    pub fn clear_merge_head(&self) -> io::Result<()> {
+        let merge_path = self.path.join("MERGE_HEAD");
+        if !self.plan.allow(Op::Remove(&merge_path)) {
+            return Ok(());
+        }
+        match fs::remove_file(&merge_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result
+        }
+    }
+
+    pub fn current_branch(&self) -> io::Result<Option<String>> {
+        // the branch commits go to, which may not have a revision yet
+        let mut head = String::new();
+        match fs::File::open(self.path.join("HEAD")).and_then(|mut f| f.read_to_string(&mut head)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("Not on a branch");
+                return Ok(None);
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(_) => {}
+        }
+        let head = head.trim();
+        if head.starts_with(HEAD_PREFIX) && validate_ref_name(&head[HEAD_PREFIX.len()..]).is_ok() {
+            Ok(Some(head[HEAD_PREFIX.len()..].to_string()))
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Branch pointer is corrupt: {:?}", head)))
+        }
+    }
+
+    pub fn set_current_branch(&self, name: &str) -> io::Result<()> {
+        try!(validate_ref_name(name));
+        if self.plan.allow(Op::CreateDir(&self.path)) {
+            try!(fs::create_dir_all(&self.path));
+        }
+        let head_path = self.path.join("HEAD");
+        if self.plan.allow(Op::WriteFile(&head_path)) {
+            debug!("Switching to branch {}", name);
+            try!(atomic_write(&head_path, format!("{}{}\n", HEAD_PREFIX, name).as_ref()));
+        }
+        Ok(())
+    }
+
+    pub fn resolve(&self, revs: &Revisions, name: &str) -> io::Result<RevisionId> {
+        // a revision id, HEAD, a tag, a branch, or a revision or tree hash
+        if name == "HEAD" {
+            return match try!(revs.head()) {
+                Some(rev) => Ok(rev),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "No revisions have been committed"))
+            };
+        }
+        if name.len() >= 7 && name.chars().all(|c| c.is_digit(16)) {
+            // a revision's own hash names it alone, short of a collision
+            match try!(revs.find_revision_hash(&name.to_lowercase())).pop() {
+                Some(rev) => {
+                    trace!("Revision hash {} names revision {}", name, rev);
+                    return Ok(rev);
+                },
+                None => {
+                    trace!("No revision has hash {}", name);
+                }
+            }
+            // identical states share a hash, so the newest one wins
+            match try!(revs.find_tree_hash(&name.to_lowercase())).pop() {
+                Some(rev) => {
+                    trace!("Tree hash {} names revision {}", name, rev);
+                    return Ok(rev);
+                },
+                None => {
+                    trace!("No revision has tree hash {}", name);
+                }
+            }
+        }
+        if let Ok(rev) = name.parse() {
+            return Ok(rev);
+        }
+        if let Some(rev) = try!(self.tag(name)) {
+            trace!("Tag {} names revision {}", name, rev);
+            return Ok(rev);
+        }
+        match try!(self.branch(name)) {
+            Some(rev) => {
+                trace!("Branch {} is at revision {}", name, rev);
+                Ok(rev)
+            },
+            None => {
+                Err(io::Error::new(io::ErrorKind::NotFound,
+                                   format!("Unknown revision {:?}", name)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::env;
+
+    use revs::*;
+
+    #[test]
+    fn test_ref_names() {
+        assert!(validate_ref_name("v1.0").is_ok());
+        assert!(validate_ref_name("release_2-rc").is_ok());
+        assert!(validate_ref_name("").is_err());
+        assert!(validate_ref_name("HEAD").is_err());
+        assert!(validate_ref_name("12").is_err());
+        assert!(validate_ref_name(".hidden").is_err());
+        assert!(validate_ref_name("a/b").is_err());
+        assert!(validate_ref_name("a:b").is_err());
+    }
+
+    #[test]
+    fn test_tags() {
+        let path = env::temp_dir().join("h2-test-refs");
+        let _ = fs::remove_dir_all(&path);
+        let refs = Refs::new(&path);
+        let revs = Revisions::new(path.join("revs"));
+        assert!(refs.tags().unwrap().is_empty());
+
+        refs.set_tag("first", 1).unwrap();
+        refs.set_tag("second", 2).unwrap();
+        refs.set_tag("first", 3).unwrap();
+        assert_eq!(refs.tags().unwrap(), vec![("first".to_string(), 3), ("second".to_string(), 2)]);
+        assert_eq!(refs.resolve(&revs, "second").unwrap(), 2);
+        assert_eq!(refs.resolve(&revs, "7").unwrap(), 7);
+        assert!(refs.resolve(&revs, "third").is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_branches() {
+        let path = env::temp_dir().join("h2-test-branches");
+        let _ = fs::remove_dir_all(&path);
+        let refs = Refs::new(&path);
+        let revs = Revisions::new(path.join("revs"));
+        assert_eq!(refs.current_branch().unwrap(), None);
+
+        refs.set_current_branch(DEFAULT_BRANCH).unwrap();
+        assert_eq!(refs.current_branch().unwrap(), Some("main".to_string()));
+        assert_eq!(refs.branch("main").unwrap(), None);
+        refs.set_branch("main", 2).unwrap();
+        refs.set_branch("topic", 3).unwrap();
+        refs.set_tag("topic", 1).unwrap();
+        assert_eq!(refs.branches().unwrap(), vec![("main".to_string(), 2), ("topic".to_string(), 3)]);
+        assert_eq!(refs.resolve(&revs, "main").unwrap(), 2);
+        // a tag and a branch of the same name resolve to the tag
+        assert_eq!(refs.resolve(&revs, "topic").unwrap(), 1);
+
+        refs.remove_branch("topic").unwrap();
+        refs.remove_branch("topic").unwrap();
+        assert_eq!(refs.branches().unwrap(), vec![("main".to_string(), 2)]);
+        assert!(refs.set_current_branch("a/b").is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
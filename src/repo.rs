@@ -0,0 +1,394 @@
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+
+use std::fs;
+use std::io;
+use std::env;
+
+use map::*;
+use lock::*;
+use plan::*;
+use config::*;
+use fileops::*;
+
+use LineIndex;
+
+// version of the on-disk layout, bumped whenever trees or meta files change shape
+// 2: tree headers record multi mode, index items lost their order field
+// 3: lines are hashed without terminators, meta records a missing final newline
+// 4: meta records the line hasher
+// 5: meta records stat info and a content hash
+// 6: the line index maps each line hash to its places, kept in a separate places file
+// 7: short place lists are kept inline in the index, only longer ones go in places
+// 8: big files may be staged as lists of chunks kept in a shared chunk store
+// 9: revisions may store files as line deltas against the previous revision
+// 10: a manifest maps every tracked path to its index and stat info
+// 11: indexes may be packed together, the manifest records where
+// 12: tree headers record how many items they hold
+// 13: small files may keep their line index in their meta
+// 14: manifest entries may record a hash of the staged copy
+// 15: index metas record the diff driver that built them
+// 16: revision metas record the revision a merge brought in
+// 17: index metas record the length lines were cut at
+// 18: revision metas mark automatic snapshots
+// 19: the manifest records staged directories, empty ones included
+// 20: revision metas record an author, a message and a revision hash
+// 21: sealed files are bound to where they're kept, and an encrypted
+//     repository seals its stage, stash and undo information too
+// 22: the key check is an hmac of a fixed text under the key
+pub const FORMAT_VERSION: u32 = 22;
+
+// repositories from before there was a version file, they're read as the
+// first layout
+pub const BASELINE_VERSION: u32 = 1;
+
+/// Environment variable naming a directory to keep the repository in
+/// instead of the checkout's .h2.
+pub const REPO_DIR_VAR: &'static str = "H2_DIR";
+
+// a .h2 file rather than a directory says where the repository was put
+const POINTER_PREFIX: &'static str = "h2dir: ";
+
+#[derive(Debug)]
+pub struct Repo {
+    // the checkout directory
+    pub root: PathBuf,
+    // the .h2 directory inside of it, or wherever it was relocated to
+    pub path: PathBuf
+}
+
+fn repo_dir(root: &Path) -> PathBuf {
+    // the environment first, then a pointer file left by init, then the
+    // usual .h2. relative paths are taken from the checkout
+    if let Some(dir) = env::var_os(REPO_DIR_VAR) {
+        if dir.to_str() != Some("") {
+            return root.join(dir);
+        }
+    }
+    let dot = root.join(".h2");
+    let mut text = String::new();
+    match fs::File::open(&dot).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) if text.starts_with(POINTER_PREFIX) => {
+            let target = text[POINTER_PREFIX.len()..].lines().next().unwrap_or("");
+            trace!("Repository of {:?} is kept at {:?}", root, target);
+            root.join(target)
+        },
+        _ => dot
+    }
+}
+
+/// A part of the repository of the checkout in the working directory,
+/// wherever the repository is kept.
+pub fn repo_path(name: &str) -> PathBuf {
+    Repo::new(".").path.join(name)
+}
+
+fn probe_error(what: &str, remedy: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{} (try `h2 {}`)", what, remedy))
+}
+
+fn broken_repo(what: String) -> io::Error {
+    // nothing h2 runs can put these right
+    io::Error::new(io::ErrorKind::Other, what)
+}
+
+impl Repo {
+    pub fn new<T: Into<PathBuf>>(root: T) -> Repo {
+        let root = root.into();
+        Repo {
+            path: repo_dir(&root),
+            root: root
+        }
+    }
+
+    pub fn is_relocated(&self) -> bool {
+        self.path != self.root.join(".h2")
+    }
+
+    pub fn write_pointer(&self) -> io::Result<()> {
+        // a .h2 file in the checkout, so a relocated repository is found
+        // again without the environment
+        let path = if self.path.is_absolute() {self.path.clone()} else {try!(env::current_dir()).join(&self.path)};
+        let target = match path.to_str() {
+            Some(target) => target,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Repository path {} is not utf-8", path.display())));
+            }
+        };
+        debug!("Pointing {:?} at {:?}", &self.root, target);
+        atomic_write(&self.root.join(".h2"), format!("{}{}\n", POINTER_PREFIX, target).as_bytes())
+    }
+
+    pub fn open<T: Into<PathBuf>>(root: T) -> io::Result<Repo> {
+        let repo = Repo::new(root);
+        info!("Opening repository at {:?}", &repo.path);
+        // check everything up front instead of failing halfway through a walk
+        match repo.probe() {
+            Ok(()) => {
+                trace!("Repository probe successful");
+                Ok(repo)
+            },
+            Err(e) => {
+                debug!("Repository probe failed: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn open_for<T: Into<PathBuf>>(root: T, plan: Plan) -> io::Result<Repo> {
+        // open for a command that writes, which needs the repository to
+        // take writes too unless it's only planning them
+        let repo = try!(Repo::open(root));
+        try!(repo.probe_trees());
+        if !plan.is_dry_run() {
+            try!(repo.check_writable());
+        }
+        Ok(repo)
+    }
+
+    pub fn discover<T: Into<PathBuf>>(start: T) -> io::Result<Repo> {
+        // the nearest directory at or above start with a repository in it
+        let start = start.into();
+        let start = if start.is_absolute() {start} else {try!(env::current_dir()).join(start)};
+        let mut current = Some(start.as_path());
+        while let Some(dir) = current {
+            // a directory, or a file pointing at one somewhere else
+            match fs::metadata(dir.join(".h2")) {
+                Ok(_) => {
+                    debug!("Found repository at {:?}", dir);
+                    return Ok(Repo::new(dir));
+                },
+                _ => {
+                    trace!("No repository at {:?}", dir);
+                }
+            }
+            current = dir.parent();
+        }
+        if env::var_os(REPO_DIR_VAR).is_some() {
+            // the environment names the repository, the checkout is where we are
+            return Ok(Repo::new(start));
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound,
+                           format!("No repository found at or above {} (try `h2 init`)", start.display())))
+    }
+
+    pub fn config(&self) -> io::Result<Config> {
+        Config::load(self.path.join("config"))
+    }
+
+    pub fn check_layout(&self) -> io::Result<()> {
+        // the directories init creates, which everything after assumes are there
+        for name in ["stage", "logs", "revs"].iter() {
+            match fs::metadata(self.path.join(name)) {
+                Ok(ref data) if data.is_dir() => {
+                    trace!("Found {} directory", name);
+                },
+                _ => {
+                    return Err(broken_repo(format!("Repository is missing its {} directory", name)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn lock(&self, mode: LockMode, wait: bool) -> io::Result<RepoLock> {
+        RepoLock::acquire(self.path.join("lock"), mode, wait)
+    }
+
+    pub fn write_header(&self) -> io::Result<()> {
+        self.write_version(FORMAT_VERSION)
+    }
+
+    pub fn write_version(&self, version: u32) -> io::Result<()> {
+        debug!("Writing format version {}", version);
+        let mut header = match fs::File::create(self.path.join("version")) {
+            Err(e) => {
+                error!("Failed to create version file: {}", e);
+                return Err(e);
+            },
+            Ok(f) => {
+                trace!("Created version file");
+                f
+            }
+        };
+
+        header.write_all(format!("{}\n", version).as_ref())
+    }
+
+    pub fn read_header(&self) -> io::Result<u32> {
+        let mut header = match fs::File::open(self.path.join("version")) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No version file, taking the baseline format");
+                return Ok(BASELINE_VERSION);
+            },
+            Err(e) => {
+                error!("Failed to open version file: {}", e);
+                return Err(broken_repo(format!("Repository header is not readable: {}", e)));
+            },
+            Ok(f) => {
+                trace!("Opened version file");
+                f
+            }
+        };
+
+        let mut data = String::new();
+        match header.read_to_string(&mut data) {
+            Err(e) => {
+                error!("Failed to read version file: {}", e);
+                return Err(broken_repo(format!("Repository header is not readable: {}", e)));
+            },
+            Ok(_) => {
+                trace!("Read version file");
+            }
+        }
+
+        match data.trim().parse() {
+            Err(e) => {
+                error!("Failed to parse version: {}", e);
+                Err(broken_repo(format!("Repository header is corrupt: {:?}", data.trim())))
+            },
+            Ok(version) => {
+                trace!("Got format version {}", version);
+                Ok(version)
+            }
+        }
+    }
+
+    pub fn probe(&self) -> io::Result<()> {
+        debug!("Checking repository directory");
+        match fs::metadata(&self.path) {
+            Ok(ref data) if data.is_dir() => {
+                trace!("Repository directory exists");
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("No repository found at {} (try `h2 init`)",
+                                                  self.root.display())));
+            }
+        }
+
+        debug!("Checking format version");
+        let version = try!(self.read_header());
+        if version > FORMAT_VERSION {
+            return Err(broken_repo(format!("Repository format version {} is newer than supported version {}",
+                                           version, FORMAT_VERSION)));
+        } else if version < FORMAT_VERSION {
+            return Err(probe_error(&format!("Repository format version {} is older than supported version {}",
+                                            version, FORMAT_VERSION), "migrate"));
+        }
+
+        Ok(())
+    }
+
+    pub fn probe_trees(&self) -> io::Result<()> {
+        // a broken index only stops commands that write. ones that read can
+        // still fall back to the stage for that file
+        debug!("Checking a sample tree");
+        match try!(self.sample_tree()) {
+            None => {
+                trace!("No trees to sample");
+                Ok(())
+            },
+            Some(path) => {
+                self.probe_tree(path)
+            }
+        }
+    }
+
+    pub fn check_writable(&self) -> io::Result<()> {
+        debug!("Checking that the repository is writable");
+        let probe_path = self.path.join("probe");
+        match fs::File::create(&probe_path) {
+            Err(e) => {
+                return Err(broken_repo(format!("Repository directory {} is not writable: {}",
+                                               self.path.display(), e)));
+            },
+            Ok(_) => {
+                trace!("Created probe file");
+            }
+        }
+        match fs::remove_file(&probe_path) {
+            Err(e) => {
+                Err(broken_repo(format!("Failed to remove probe file {}: {}", probe_path.display(), e)))
+            },
+            Ok(()) => {
+                trace!("Removed probe file");
+                Ok(())
+            }
+        }
+    }
+
+    fn sample_tree(&self) -> io::Result<Option<PathBuf>> {
+        // find the first content tree in the logs directory
+        let mut to_visit = vec![self.path.join("logs")];
+
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            trace!("Looking for a tree in {:?}", &dir);
+            for item in match fs::read_dir(&dir) {
+                Ok(iter) => iter,
+                Err(e) => {
+                    return Err(broken_repo(format!("Failed to read log directory {}: {}", dir.display(), e)));
+                }
+            } {
+                let entry = match item {
+                    Ok(item) => item,
+                    Err(e) => {
+                        return Err(broken_repo(format!("Failed to read log directory {}: {}",
+                                                       dir.display(), e)));
+                    }
+                };
+
+                if entry.file_name().to_str() == Some("content") {
+                    return Ok(Some(entry.path()));
+                }
+
+                match entry.metadata() {
+                    Ok(ref data) if data.is_dir() => {
+                        to_visit.push(entry.path());
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn probe_tree(&self, path: PathBuf) -> io::Result<()> {
+        debug!("Probing tree at {:?}", &path);
+        let buffer = match fs::File::open(&path) {
+            Err(e) => {
+                return Err(probe_error(&format!("Failed to open tree {}: {}", path.display(), e), "verify"));
+            },
+            Ok(b) => b
+        };
+
+        let places_path = path.with_file_name("places");
+        let places = match fs::File::open(&places_path) {
+            Err(e) => {
+                return Err(probe_error(&format!("Failed to open places {}: {}", places_path.display(), e), "verify"));
+            },
+            Ok(b) => b
+        };
+
+        let mut index: LineIndex<_> = match unsafe {BufMap::from_buffers(buffer, places)} {
+            Err(e) => {
+                return Err(probe_error(&format!("Failed to read tree {}: {}", path.display(), e), "verify"));
+            },
+            Ok(t) => t
+        };
+
+        // a lookup reads the header and the root node
+        match index.contains_key(0) {
+            Err(e) => {
+                Err(probe_error(&format!("Tree {} is unreadable: {}", path.display(), e), "verify"))
+            },
+            Ok(_) => {
+                trace!("Tree probe successful");
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::path::{Path, PathBuf};
+
+use std::fs;
+use std::io;
+
+use repo::*;
+use revs::*;
+use config::*;
+use crypt::*;
+use lines::*;
+use chunks::*;
+use fileops::*;
+use manifest::*;
+use lock::*;
+use drivers::*;
+use refs::*;
+use storage::*;
+use tree::TreeGrowth;
+
+use {Checkout, DiffSource, Logs, Stage, DEFAULT_INLINE_LIMIT, DEFAULT_PROBE_LIMIT};
+
+// a repository with its parts opened and set up from its config, found from
+// anywhere inside its checkout. every path comes from where the repository
+// was found rather than the working directory
+#[derive(Debug)]
+pub struct Repository {
+    repo: Repo,
+    config: Config,
+    checkout: Checkout,
+    stage: Stage,
+    logs: Logs,
+    revs: Revisions
+}
+
+fn parse_number<T: ::std::str::FromStr>(config: &Config, key: &str, default: T) -> io::Result<T> {
+    match config.get(key) {
+        None => Ok(default),
+        Some(value) => match value.parse() {
+            Ok(number) => Ok(number),
+            Err(_) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Config value {} = {:?} is not a number", key, value)))
+            }
+        }
+    }
+}
+
+/// The stage of a repository, chunking big files, copying in parallel,
+/// cloning or linking files and checking copies if its config asks for it,
+/// and sealing every copy if it's encrypted.
+pub fn configure_stage(repo: &Repo, config: &Config) -> io::Result<Stage> {
+    let threads = try!(parse_number(config, "threads", 1));
+    let retries = try!(parse_number(config, "copy_retries", DEFAULT_COPY_RETRIES));
+    let stage = Stage::new(repo.path.join("stage")).with_threads(threads).with_copy_retries(retries)
+        .with_copy_strategy(try!(CopyStrategy::from_config(config)))
+        .with_checked_copies(try!(config.get_bool("check_copies", false)))
+        .with_key(try!(load_key(config)));
+    if !try!(config.get_bool("chunking", false)) {
+        trace!("Chunking is off");
+        return Ok(stage);
+    }
+    if try!(config.get_bool("encryption", false)) {
+        // chunks are shared by the stage and every revision, and kept in the clear
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Chunking can't be used in an encrypted repository"));
+    }
+    let threshold = match config.get("chunk_threshold") {
+        None => DEFAULT_CHUNK_THRESHOLD,
+        Some(value) => match value.parse() {
+            Ok(threshold) => threshold,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value chunk_threshold = {:?} is not a size in bytes",
+                                                  value)));
+            }
+        }
+    };
+    debug!("Chunking files over {} bytes", threshold);
+    Ok(stage.with_chunking(Some(threshold)))
+}
+
+/// The revisions of a repository, with the key its snapshots are sealed with
+/// if it's encrypted.
+pub fn configure_revisions(repo: &Repo, config: &Config) -> io::Result<Revisions> {
+    Ok(Revisions::new(repo.path.join("revs")).with_key(try!(load_key(config))))
+}
+
+/// The logs of a repository, with its manifest if it has one.
+pub fn configure_logs(repo: &Repo, config: &Config) -> io::Result<Logs> {
+    let width = match config.get("tree_width") {
+        None | Some("auto") => None,
+        Some(value) => match value.parse() {
+            Ok(width) => Some(width),
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value tree_width = {:?} is not a number or auto", value)));
+            }
+        }
+    };
+    let growth = match config.get("tree_growth") {
+        None => TreeGrowth::default(),
+        Some(value) => match TreeGrowth::from_name(value) {
+            Some(growth) => growth,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value tree_growth = {:?} is not exact, double or a number \
+                                                   of nodes", value)));
+            }
+        }
+    };
+    let probe_limit = match config.get("probe_limit") {
+        None => DEFAULT_PROBE_LIMIT,
+        Some(value) => match value.parse() {
+            Ok(limit) if limit > 0 => limit,
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value probe_limit = {:?} is not a positive number", value)));
+            }
+        }
+    };
+    let max_line_length = match config.get("max_line_length") {
+        None => DEFAULT_MAX_LINE_LENGTH,
+        Some(value) => match value.parse() {
+            Ok(length) if length > 0 => length,
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Config value max_line_length = {:?} is not a positive number",
+                                                  value)));
+            }
+        }
+    };
+    let mut logs = Logs::new(repo.path.join("logs")).with_tree_width(width)
+        .with_tree_growth(growth)
+        .with_line_endings(try!(LineEndings::from_config(config)))
+        .with_probe_limit(probe_limit)
+        .with_max_line_length(max_line_length)
+        .with_inline_limit(try!(parse_number(config, "inline_limit", DEFAULT_INLINE_LIMIT)))
+        .with_drivers(try!(DriverRules::from_config(config)))
+        .with_backend(try!(Backend::from_config(config)))
+        .with_diff_source(try!(DiffSource::from_config(config)))
+        .with_stage_key(try!(load_key(config)))
+        .with_threads(try!(parse_number(config, "threads", 1)));
+    if let Some(manifest) = try!(Manifest::open_existing(repo.path.join("manifest"))) {
+        logs = logs.with_manifest(manifest);
+    }
+    Ok(logs)
+}
+
+impl Repository {
+    pub fn open<T: Into<PathBuf>>(start: T) -> io::Result<Repository> {
+        // the nearest repository at or above start, checked and configured
+        let repo = try!(Repo::discover(start));
+        info!("Opening repository at {:?}", &repo.path);
+        try!(repo.probe());
+        try!(repo.check_layout());
+        Repository::configure(repo)
+    }
+
+    pub fn init<T: Into<PathBuf>>(root: T, encrypt: Option<KeySource>) -> io::Result<Repository> {
+        // an empty repository in root, nothing is staged
+        let repo = Repo::new(root);
+        info!("Creating repository at {:?}", &repo.path);
+        if fs::metadata(&repo.path).is_ok() || fs::metadata(repo.root.join(".h2")).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("A repository already exists at {}", repo.root.display())));
+        }
+        // a missing or malformed key stops init before anything is created
+        let sealing = match encrypt {
+            Some(source) => Some((try!(source.load()), source)),
+            None => None
+        };
+        try!(fs::create_dir_all(&repo.path));
+        if repo.is_relocated() {
+            try!(repo.write_pointer());
+        }
+        try!(repo.write_header());
+        if let Some((ref key, ref source)) = sealing {
+            debug!("Recording encryption in the config");
+            try!(atomic_write(&repo.path.join("config"), encryption_config(key, source).as_bytes()));
+        }
+
+        let _lock = try!(repo.lock(LockMode::Exclusive, false));
+        try!(Manifest::open(repo.path.join("manifest")));
+        try!(Refs::new(repo.path.join("refs")).set_current_branch(DEFAULT_BRANCH));
+        let mut repository = try!(Repository::configure(repo));
+        try!(repository.checkout.init());
+        try!(repository.stage.init());
+        try!(repository.logs.init());
+        try!(repository.revs.init());
+        Ok(repository)
+    }
+
+    fn configure(repo: Repo) -> io::Result<Repository> {
+        let config = try!(repo.config());
+        Ok(Repository {
+            checkout: Checkout::new(repo.root.clone()),
+            stage: try!(configure_stage(&repo, &config)),
+            logs: try!(configure_logs(&repo, &config)),
+            revs: try!(configure_revisions(&repo, &config)),
+            config: config,
+            repo: repo
+        })
+    }
+
+    pub fn repo(&self) -> &Repo {
+        &self.repo
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.repo.root
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn lock(&self, mode: LockMode, wait: bool) -> io::Result<RepoLock> {
+        self.repo.lock(mode, wait)
+    }
+
+    pub fn checkout(&self) -> &Checkout {
+        &self.checkout
+    }
+
+    pub fn stage(&self) -> &Stage {
+        &self.stage
+    }
+
+    pub fn stage_mut(&mut self) -> &mut Stage {
+        &mut self.stage
+    }
+
+    pub fn logs(&self) -> &Logs {
+        &self.logs
+    }
+
+    pub fn logs_mut(&mut self) -> &mut Logs {
+        &mut self.logs
+    }
+
+    pub fn revisions(&self) -> &Revisions {
+        &self.revs
+    }
+
+    pub fn revisions_mut(&mut self) -> &mut Revisions {
+        &mut self.revs
+    }
+
+    pub fn parts_mut(&mut self) -> (&Checkout, &mut Logs, &mut Stage) {
+        // staging needs all three at once
+        (&self.checkout, &mut self.logs, &mut self.stage)
+    }
+}
@@ -0,0 +1,1210 @@
+use std::path::{Path, PathBuf, Component};
+use std::collections::HashSet;
+use std::cell::RefCell;
+use std::io::Read;
+use std::hash::Hasher;
+
+use rustc_serialize::json;
+
+use std::env;
+use std::fs;
+use std::io;
+
+use fileops::*;
+use config::*;
+use hashers::*;
+use plan::*;
+use chunks::*;
+use delta::*;
+use verify::*;
+use crypt::*;
+use manifest::*;
+use linestore::*;
+
+use {PathInfo, Stage};
+use repo::*;
+
+pub type RevisionId = u64;
+
+// who a commit is by, over the config's author_name and author_email
+pub const AUTHOR_NAME_VAR: &'static str = "H2_AUTHOR_NAME";
+pub const AUTHOR_EMAIL_VAR: &'static str = "H2_AUTHOR_EMAIL";
+
+extern {
+    fn time(t: *mut i64) -> i64;
+}
+
+pub fn now() -> i64 {
+    // seconds since the epoch
+    unsafe {time(0 as *mut i64)}
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct RevisionMeta {
+    pub id: RevisionId,
+    pub parent: Option<RevisionId>,
+    // seconds since the epoch, missing for revisions made before it was recorded
+    pub time: Option<i64>,
+    // the manifest's tree hash when it was committed, the same for the same
+    // paths and content. missing for revisions from before it was kept
+    pub tree_hash: Option<String>,
+    // the other revision a merge brought in, the parent being the one it
+    // was committed on top of
+    pub merged: Option<RevisionId>,
+    // true for snapshots `h2 autosnap` committed, missing for the rest
+    pub auto: Option<bool>,
+    // who committed it, as "Name <email>", and why
+    pub author: Option<String>,
+    pub message: Option<String>,
+    // a hash of its parents' hashes, tree hash, author and message, the same
+    // wherever and whenever the same commit is made. missing for revisions
+    // from before it was kept
+    pub hash: Option<String>
+}
+
+// what a commit records besides the stage
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub author: Option<String>,
+    pub message: Option<String>
+}
+
+impl CommitInfo {
+    pub fn new(message: Option<String>, config: &Config) -> CommitInfo {
+        // an empty message is the same as none
+        CommitInfo {
+            author: author_from(config),
+            message: message.and_then(|message| {
+                let message = message.trim().to_string();
+                if message.is_empty() {None} else {Some(message)}
+            })
+        }
+    }
+}
+
+pub fn author_from(config: &Config) -> Option<String> {
+    // "Name <email>" from the environment or config, with whichever half
+    // there is if only one is set
+    let lookup = |var: &str, key: &str| env::var(var).ok().or_else(|| config.get(key).map(|value| value.to_string()))
+        .and_then(|value| if value.trim().is_empty() {None} else {Some(value.trim().to_string())});
+    match (lookup(AUTHOR_NAME_VAR, "author_name"), lookup(AUTHOR_EMAIL_VAR, "author_email")) {
+        (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+        (Some(name), None) => Some(name),
+        (None, Some(email)) => Some(format!("<{}>", email)),
+        (None, None) => None
+    }
+}
+
+pub fn revision_hash(parent: Option<&str>, merged: Option<&str>, tree_hash: Option<&str>, auto: bool,
+                     info: &CommitInfo) -> String {
+    // each field is marked present or not and length prefixed, so no two
+    // different commits can run together into the same bytes. when it was
+    // committed is left out, so the same commit made again hashes the same
+    let mut hasher = FnvHasher::default();
+    let fields = [parent, merged, tree_hash, info.author.as_ref().map(|text| &text[..]),
+                  info.message.as_ref().map(|text| &text[..])];
+    for field in fields.iter() {
+        match *field {
+            Some(text) => {
+                hasher.write_u8(1);
+                hasher.write_u64(text.len() as u64);
+                hasher.write(text.as_bytes());
+            },
+            None => {
+                hasher.write_u8(0);
+            }
+        }
+    }
+    hasher.write_u8(if auto {1} else {0});
+    format_tree_hash(hasher.finish())
+}
+
+impl RevisionMeta {
+    pub fn parents(&self) -> Vec<RevisionId> {
+        self.parent.into_iter().chain(self.merged.into_iter()).collect()
+    }
+
+    pub fn is_auto(&self) -> bool {
+        self.auto == Some(true)
+    }
+
+    pub fn hash_key(&self) -> String {
+        // what the hash of a revision on top of this one builds on, its
+        // number if it's from before hashes were kept
+        self.hash.clone().unwrap_or_else(|| format!("{}", self.id))
+    }
+
+    pub fn summary(&self) -> Option<String> {
+        // the author and the first line of the message, as log shows them
+        let subject = self.message.as_ref().and_then(|message| message.lines().next());
+        match (self.author.as_ref(), subject) {
+            (Some(author), Some(subject)) => Some(format!("{}: {}", author, subject)),
+            (Some(author), None) => Some(author.clone()),
+            (None, Some(subject)) => Some(subject.to_string()),
+            (None, None) => None
+        }
+    }
+}
+
+// which revisions prune keeps besides head and tagged ones. a revision is
+// kept if either limit keeps it, and with neither set everything is kept.
+// automatic snapshots also have to be kept by one of the auto limits if
+// either is set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    // keep this many of the newest revisions
+    pub keep_last: Option<usize>,
+    // keep revisions committed within this many days
+    pub keep_days: Option<u64>,
+    // keep this many of the newest automatic snapshots
+    pub keep_auto: Option<usize>,
+    // keep automatic snapshots committed within this many hours
+    pub keep_auto_hours: Option<u64>
+}
+
+#[derive(Debug)]
+pub struct Revisions {
+    path: PathBuf,
+    // shared with the stage, revisions copy its chunk lists as they are
+    chunks: ChunkStore,
+    // where the shared line store is, if the repository has one. it's only
+    // opened once a line list is read or written
+    lines_path: PathBuf,
+    lines: RefCell<Option<LineStore<fs::File>>>,
+    // seals every stored file, if the repository is encrypted
+    key: Option<StoreKey>,
+    plan: Plan
+}
+
+impl Default for Revisions {
+    fn default() -> Revisions {
+        Revisions::new(repo_path("revs"))
+    }
+}
+
+impl Revisions {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Revisions {
+        let path = path.into();
+        Revisions {
+            chunks: ChunkStore::new(path.with_file_name("chunks")),
+            lines_path: path.with_file_name("lines"),
+            lines: RefCell::new(None),
+            path: path,
+            key: None,
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Revisions {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_key(mut self, key: Option<StoreKey>) -> Revisions {
+        self.key = key;
+        self
+    }
+
+    pub fn chunks(&self) -> &ChunkStore {
+        &self.chunks
+    }
+
+    pub fn key_check(&self) -> Option<String> {
+        // the check value of the key files are sealed with, none in the clear
+        self.key.as_ref().map(|key| key.check_value())
+    }
+
+    pub fn init(&mut self) -> io::Result<()> {
+        info!("Creating revisions");
+        if !self.plan.allow(Op::CreateDir(&self.path)) {
+            return Ok(());
+        }
+        match fs::create_dir_all(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                trace!("Directory already existed");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to create directory \"{}\": {}", self.path.display(), e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Directory created");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn rev_path(&self, id: RevisionId) -> PathBuf {
+        self.path.join(format!("{}", id))
+    }
+
+    pub fn head(&self) -> io::Result<Option<RevisionId>> {
+        trace!("Reading head revision");
+        let mut head_str = String::new();
+        match fs::File::open(self.path.join("HEAD")) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("No head revision");
+                return Ok(None);
+            },
+            Err(e) => {
+                error!("Failed to open head file: {}", e);
+                return Err(e);
+            },
+            Ok(mut f) => {
+                try!(f.read_to_string(&mut head_str));
+            }
+        }
+
+        match head_str.trim().parse() {
+            Err(e) => {
+                error!("Failed to parse head revision: {}", e);
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Head revision is corrupt: {:?}", head_str.trim())))
+            },
+            Ok(id) => {
+                trace!("Head revision is {}", id);
+                Ok(Some(id))
+            }
+        }
+    }
+
+    pub fn list(&self) -> io::Result<Vec<RevisionId>> {
+        // every revision in the store, oldest first
+        let mut ids = vec![];
+        for item in try!(fs::read_dir(&self.path)) {
+            let entry = try!(item);
+            match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(id) => {
+                    ids.push(id);
+                },
+                None => {
+                    trace!("Skipping {:?}", entry.path());
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn next_id(&self) -> io::Result<RevisionId> {
+        // ids go up across every branch, so one committed on top of an older
+        // revision can't take the id of a newer one
+        Ok(try!(self.list()).last().map_or(1, |last| last + 1))
+    }
+
+    pub fn find_tree_hash(&self, prefix: &str) -> io::Result<Vec<RevisionId>> {
+        // every revision whose tree hash starts with this, oldest first
+        let mut found = vec![];
+        for id in try!(self.list()) {
+            match try!(self.meta(id)).tree_hash {
+                Some(ref hash) if hash.starts_with(prefix) => {
+                    found.push(id);
+                },
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn find_revision_hash(&self, prefix: &str) -> io::Result<Vec<RevisionId>> {
+        // every revision whose own hash starts with this, oldest first
+        let mut found = vec![];
+        for id in try!(self.list()) {
+            match try!(self.meta(id)).hash {
+                Some(ref hash) if hash.starts_with(prefix) => {
+                    found.push(id);
+                },
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn meta(&self, id: RevisionId) -> io::Result<RevisionMeta> {
+        let mut meta_str = String::new();
+        match fs::File::open(self.rev_path(id).join("meta")) {
+            Err(e) => {
+                error!("Failed to open revision {} meta: {}", id, e);
+                return Err(e);
+            },
+            Ok(mut f) => {
+                try!(f.read_to_string(&mut meta_str));
+            }
+        }
+
+        match json::decode(meta_str.as_ref()) {
+            Err(e) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Failed to decode revision {} meta: {}", id, e)))
+            },
+            Ok(meta) => Ok(meta)
+        }
+    }
+
+    fn write_meta(&self, meta: &RevisionMeta) -> io::Result<()> {
+        let data = match json::encode(meta) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to encode to json: {}", e)));
+            },
+            Ok(d) => d
+        };
+        let meta_path = self.rev_path(meta.id).join("meta");
+        if self.plan.allow(Op::WriteFile(&meta_path)) {
+            try!(atomic_write(&meta_path, data.as_ref()));
+        }
+        Ok(())
+    }
+
+    pub fn commit(&mut self, stage: &Stage, tree_hash: Option<u64>) -> io::Result<RevisionId> {
+        self.commit_merge(stage, tree_hash, None, &CommitInfo::default())
+    }
+
+    pub fn commit_merge(&mut self, stage: &Stage, tree_hash: Option<u64>, merged: Option<RevisionId>,
+                        info: &CommitInfo) -> io::Result<RevisionId> {
+        self.commit_with(stage, tree_hash, merged, false, info)
+    }
+
+    pub fn commit_auto(&mut self, stage: &Stage, tree_hash: Option<u64>, info: &CommitInfo)
+                       -> io::Result<RevisionId> {
+        self.commit_with(stage, tree_hash, None, true, info)
+    }
+
+    fn commit_with(&mut self, stage: &Stage, tree_hash: Option<u64>, merged: Option<RevisionId>, auto: bool,
+                   info: &CommitInfo) -> io::Result<RevisionId> {
+        // a revision on top of head, and of merged as well if there is one
+        let parent = try!(self.head());
+        let id = try!(self.next_id());
+        let parent_key = match parent {
+            Some(parent) => Some(try!(self.meta(parent)).hash_key()),
+            None => None
+        };
+        let merged_key = match merged {
+            Some(merged) => Some(try!(self.meta(merged)).hash_key()),
+            None => None
+        };
+        let tree_hash = tree_hash.map(format_tree_hash);
+        let hash = revision_hash(parent_key.as_ref().map(|key| &key[..]), merged_key.as_ref().map(|key| &key[..]),
+                                 tree_hash.as_ref().map(|hash| &hash[..]), auto, info);
+        let rev_path = self.rev_path(id);
+        info!("Committing revision {}", id);
+
+        let tree_path = rev_path.join("tree");
+        if self.plan.allow(Op::CopyDir(&stage.path, &tree_path)) {
+            debug!("Copying stage to {:?}", &rev_path);
+            try!(self.store_tree(stage, &tree_path));
+            if let Some(parent) = parent {
+                try!(self.store_deltas(parent, &tree_path));
+            }
+            try!(self.store_lines(&tree_path));
+        }
+
+        debug!("Saving revision meta info");
+        try!(self.write_meta(&RevisionMeta {
+            id: id,
+            parent: parent,
+            time: Some(now()),
+            tree_hash: tree_hash,
+            merged: merged,
+            auto: if auto {Some(true)} else {None},
+            author: info.author.clone(),
+            message: info.message.clone(),
+            hash: Some(hash)
+        }));
+
+        // only move head once the revision is complete
+        try!(self.set_head(id));
+
+        Ok(id)
+    }
+
+    pub fn set_head(&self, id: RevisionId) -> io::Result<()> {
+        debug!("Updating head revision to {}", id);
+        let head_path = self.path.join("HEAD");
+        if self.plan.allow(Op::WriteFile(&head_path)) {
+            try!(atomic_write(&head_path, format!("{}\n", id).as_ref()));
+        }
+        Ok(())
+    }
+
+    pub fn uncommit(&mut self, id: RevisionId) -> io::Result<()> {
+        // drop the head revision, only if nothing was committed on top of it
+        match try!(self.head()) {
+            Some(head) if head == id => {
+                trace!("Revision {} is head", id);
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("Revision {} is no longer head", id)));
+            }
+        }
+        let meta = try!(self.meta(id));
+
+        // move head back first, a crash after that leaves an unused revision
+        // directory instead of a head pointing at nothing
+        let head_path = self.path.join("HEAD");
+        match meta.parent {
+            Some(parent) => {
+                debug!("Moving head back to {}", parent);
+                if self.plan.allow(Op::WriteFile(&head_path)) {
+                    try!(atomic_write(&head_path, format!("{}\n", parent).as_ref()));
+                }
+            },
+            None => {
+                debug!("Removing head, no revisions are left");
+                if self.plan.allow(Op::Remove(&head_path)) {
+                    try!(fs::remove_file(&head_path));
+                }
+            }
+        }
+
+        let rev_path = self.rev_path(id);
+        if self.plan.allow(Op::Remove(&rev_path)) {
+            debug!("Removing revision {}", id);
+            try!(fs::remove_dir_all(&rev_path));
+        }
+        Ok(())
+    }
+
+    pub fn export(&self, id: RevisionId, bases: &[RevisionId]) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+        // every stored file of a revision as it's copied to another store,
+        // relative to the revision directory and with the meta last. sealed
+        // files stay sealed, a delta against a revision the other store
+        // won't have is stored whole
+        let rev_path = self.rev_path(id);
+        let mut files = vec![];
+        for path in try!(self.files(id)) {
+            let stored_path = rev_path.join("tree").join(&path);
+            let mut data = vec![];
+            try!(fs::File::open(&stored_path).and_then(|mut f| f.read_to_end(&mut data)));
+            let opened = try!(self.unseal(data.clone(), &stored_path));
+            if is_line_list(&opened) {
+                // the other store has lines of its own, if any
+                debug!("Exporting {:?} at revision {} whole, it's in the line store", &path, id);
+                data = try!(self.read_path(id, &path));
+            } else if is_delta(&opened) {
+                let (base_rev, _) = try!(delta_header(&opened));
+                if !bases.contains(&base_rev) {
+                    debug!("Exporting {:?} at revision {} whole, its base {} isn't there", &path, id, base_rev);
+                    let whole = try!(self.read_path(id, &path));
+                    data = match self.key {
+                        Some(ref key) => try!(key.seal(&try!(self.place(&stored_path)), &whole)),
+                        None => whole
+                    };
+                }
+            }
+            files.push((Path::new("tree").join(&path), data));
+        }
+
+        let mut meta = vec![];
+        try!(fs::File::open(rev_path.join("meta")).and_then(|mut f| f.read_to_end(&mut meta)));
+        files.push((PathBuf::from("meta"), meta));
+        Ok(files)
+    }
+
+    pub fn import(&self, id: RevisionId, files: &[(PathBuf, Vec<u8>)]) -> io::Result<()> {
+        // write a revision exported from another store. the meta goes last,
+        // so a revision cut short reads as never finished and can be
+        // imported again
+        let rev_path = self.rev_path(id);
+        if fs::metadata(rev_path.join("meta")).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("Revision {} already exists", id)));
+        }
+        let tree_path = rev_path.join("tree");
+        if !self.plan.allow(Op::CreateDir(&tree_path)) {
+            return Ok(());
+        }
+        try!(fs::create_dir_all(&tree_path));
+
+        let (meta, tree): (Vec<_>, Vec<_>) = files.iter().partition(|&&(ref path, _)| path == Path::new("meta"));
+        for &&(ref path, ref data) in tree.iter().chain(meta.iter()) {
+            if path.components().any(|part| match part {Component::Normal(_) => false, _ => true}) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Revision {} has a bad path {:?}", id, path)));
+            }
+            let dest = rev_path.join(path);
+            if let Some(parent) = dest.parent() {
+                try!(fs::create_dir_all(parent));
+            }
+            trace!("Importing {:?}", &dest);
+            try!(atomic_write(&dest, data));
+        }
+        Ok(())
+    }
+
+    pub fn chunk_refs(&self, id: RevisionId) -> io::Result<HashSet<u64>> {
+        // every chunk the files of a revision list, looking inside sealed ones
+        let tree_path = self.rev_path(id).join("tree");
+        let mut hashes = HashSet::new();
+        for path in try!(self.files(id)) {
+            let data = try!(self.read_stored(&tree_path.join(&path)));
+            if is_manifest(&data) {
+                for chunk in try!(parse_manifest(&data)) {
+                    hashes.insert(chunk.hash);
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    pub fn retained(&self, retention: &Retention, pinned: &[RevisionId]) -> io::Result<Vec<RevisionId>> {
+        // the revisions a retention policy keeps, oldest first. head and
+        // anything pinned are always kept
+        let ids = try!(self.list());
+        let head = try!(self.head());
+        let cutoff = retention.keep_days.map(|days| now() - days as i64 * 24 * 60 * 60);
+        let auto_cutoff = retention.keep_auto_hours.map(|hours| now() - hours as i64 * 60 * 60);
+        let mut metas = vec![];
+        for &id in ids.iter() {
+            match self.meta(id) {
+                Ok(meta) => {
+                    metas.push(Some(meta));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    trace!("Revision {} has no meta, it was never finished", id);
+                    metas.push(None);
+                },
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        let is_auto = |i: usize| metas[i].as_ref().map_or(false, |meta| meta.is_auto());
+        let time = |i: usize| metas[i].as_ref().and_then(|meta| meta.time);
+        // automatic snapshots seen so far, going from the newest
+        let mut autos_after = 0;
+        let mut keep = vec![];
+        for (i, &id) in ids.iter().enumerate().rev() {
+            let newest = match retention.keep_last {
+                Some(count) => ids.len() - i <= count,
+                None => false
+            };
+            let recent = match cutoff {
+                Some(cutoff) => time(i).map_or(false, |time| time >= cutoff),
+                None => false
+            };
+            let unlimited = retention.keep_last.is_none() && retention.keep_days.is_none();
+            let mut kept = unlimited || newest || recent;
+            if is_auto(i) {
+                autos_after += 1;
+                let auto_newest = retention.keep_auto.map_or(false, |count| autos_after <= count);
+                let auto_recent = match auto_cutoff {
+                    Some(cutoff) => time(i).map_or(false, |time| time >= cutoff),
+                    None => false
+                };
+                let auto_unlimited = retention.keep_auto.is_none() && retention.keep_auto_hours.is_none();
+                kept = kept && (auto_unlimited || auto_newest || auto_recent);
+            }
+            if kept || head == Some(id) || pinned.contains(&id) {
+                keep.push(id);
+            }
+        }
+        keep.reverse();
+        Ok(keep)
+    }
+
+    pub fn prune(&mut self, keep: &[RevisionId]) -> io::Result<Vec<RevisionId>> {
+        // remove every revision that isn't kept, returning the ids removed.
+        // deltas against a removed revision are stored whole again and
+        // parents skip over removed revisions, all before anything is
+        // removed, so stopping partway leaves every revision readable
+        let head = try!(self.head());
+        let removed: Vec<RevisionId> = try!(self.list()).into_iter()
+            .filter(|id| !keep.contains(id) && head != Some(*id)).collect();
+        if removed.is_empty() {
+            debug!("Nothing to prune");
+            return Ok(removed);
+        }
+        info!("Pruning {} revisions", removed.len());
+
+        // oldest first, so a delta's base has been fixed before the delta is
+        for &id in try!(self.list()).iter().filter(|id| !removed.contains(id)) {
+            try!(self.rebase_deltas(id, &removed));
+            let mut meta = match self.meta(id) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    trace!("Revision {} has no meta to update", id);
+                    continue;
+                },
+                Err(e) => {
+                    return Err(e);
+                },
+                Ok(meta) => meta
+            };
+            let parent = try!(self.kept_ancestor(meta.parent, &removed));
+            let merged = try!(self.kept_ancestor(meta.merged, &removed));
+            if parent != meta.parent || merged != meta.merged {
+                debug!("Revision {} now follows {:?}, merging {:?}", id, parent, merged);
+                meta.parent = parent;
+                meta.merged = merged;
+                try!(self.write_meta(&meta));
+            }
+        }
+
+        for &id in removed.iter() {
+            let rev_path = self.rev_path(id);
+            if self.plan.allow(Op::Remove(&rev_path)) {
+                debug!("Removing revision {}", id);
+                try!(fs::remove_dir_all(&rev_path));
+            }
+        }
+        Ok(removed)
+    }
+
+    fn kept_ancestor(&self, mut rev: Option<RevisionId>, removed: &[RevisionId]) -> io::Result<Option<RevisionId>> {
+        // the first revision down the parent line from rev that prune keeps
+        while let Some(skipped) = rev {
+            if !removed.contains(&skipped) {
+                break;
+            }
+            rev = match self.meta(skipped) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e);
+                },
+                Ok(removed_meta) => removed_meta.parent
+            };
+        }
+        Ok(rev)
+    }
+
+    fn rebase_deltas(&self, id: RevisionId, removed: &[RevisionId]) -> io::Result<()> {
+        // store whole every file in a revision that's a delta against a
+        // removed revision
+        let tree_path = self.rev_path(id).join("tree");
+        let mut to_visit = vec![tree_path.clone()];
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            let entries = match fs::read_dir(dir) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    trace!("Revision {} has no tree", id);
+                    continue;
+                },
+                Err(e) => {
+                    return Err(e);
+                },
+                Ok(entries) => entries
+            };
+            for item in entries {
+                let entry = try!(item);
+                if try!(entry.metadata()).is_dir() {
+                    to_visit.push(entry.path());
+                    continue;
+                }
+                let data = try!(self.read_stored(&entry.path()));
+                if !is_delta(&data) {
+                    continue;
+                }
+                let (base_rev, _) = try!(delta_header(&data));
+                if !removed.contains(&base_rev) {
+                    continue;
+                }
+                let path = match entry.path().relative_from(&tree_path) {
+                    Some(path) => PathBuf::from(path),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("{} is outside of the revision", entry.path().display())));
+                    }
+                };
+                if self.plan.allow(Op::WriteFile(&entry.path())) {
+                    debug!("Storing {:?} at revision {} whole, its base {} is being pruned", &path, id, base_rev);
+                    try!(self.write_stored(&entry.path(), &try!(self.read_path(id, &path))));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn place(&self, path: &Path) -> io::Result<Vec<u8>> {
+        // a stored file is sealed to where it's kept under the revisions,
+        // which names both its revision and its path id
+        match path.relative_from(&self.path) {
+            Some(place) => Ok(seal_place("revs", place)),
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   format!("{} is not stored with the revisions", path.display())))
+            }
+        }
+    }
+
+    fn unseal(&self, data: Vec<u8>, path: &Path) -> io::Result<Vec<u8>> {
+        // what was stored, once the key has been taken off
+        if self.key.is_none() {
+            return open_stored(None, &[], data, path);
+        }
+        open_stored(self.key.as_ref(), &try!(self.place(path)), data, path)
+    }
+
+    fn read_stored(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        try!(fs::File::open(path).and_then(|mut f| f.read_to_end(&mut data)));
+        self.unseal(data, path)
+    }
+
+    fn write_stored(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if self.key.is_none() {
+            return atomic_write(path, data);
+        }
+        write_stored(self.key.as_ref(), &try!(self.place(path)), path, data)
+    }
+
+    fn store_tree(&self, stage: &Stage, to: &Path) -> io::Result<()> {
+        // copy the stage in as a revision tree, sealing each file on the way
+        // so no cleartext copy is ever written to the revision
+        let from = &stage.path;
+        if self.key.is_none() {
+            return copy_dir_all(from, to);
+        }
+        try!(fs::create_dir_all(to));
+        let mut to_visit = vec![from.to_path_buf()];
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            for item in try!(fs::read_dir(dir)) {
+                let entry = try!(item);
+                if is_temp_path(entry.path()) {
+                    trace!("Skipping unfinished file {:?}", entry.path());
+                    continue;
+                }
+                let id = match entry.path().relative_from(from) {
+                    Some(id) => PathBuf::from(id),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("{} is outside of the stage", entry.path().display())));
+                    }
+                };
+                let dest = to.join(&id);
+                if try!(entry.metadata()).is_dir() {
+                    try!(fs::create_dir_all(&dest));
+                    to_visit.push(entry.path());
+                    continue;
+                }
+                // the stage's own seal comes off, the revision's goes on
+                let data = try!(stage.read_stored(&id));
+                trace!("Sealing {:?}", &dest);
+                try!(self.write_stored(&dest, &data));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn files(&self, id: RevisionId) -> io::Result<Vec<PathBuf>> {
+        // ids of every file in a revision, sorted
+        let tree_path = self.rev_path(id).join("tree");
+        match fs::metadata(&tree_path) {
+            Ok(ref data) if data.is_dir() => {
+                trace!("Revision exists");
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("No such revision: {}", id)));
+            }
+        }
+        let mut files = try!(stage_files(&tree_path));
+        files.sort();
+        Ok(files)
+    }
+
+    pub fn dirs(&self, id: RevisionId) -> io::Result<Vec<PathBuf>> {
+        // ids of every directory in a revision, sorted
+        let mut dirs = try!(stage_dirs(&self.rev_path(id).join("tree")));
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    pub fn read_path<T: AsRef<Path>>(&self, id: RevisionId, path: T) -> io::Result<Vec<u8>> {
+        // reconstruct the content of a file as it was at the given revision
+        let path = path.as_ref();
+        let file_path = self.rev_path(id).join("tree").join(path);
+        debug!("Reading {:?} at revision {}", path, id);
+
+        let mut file = match fs::File::open(&file_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("{} does not exist at revision {}", path.display(), id)));
+            },
+            Err(e) => {
+                error!("Failed to open {:?}: {}", &file_path, e);
+                return Err(e);
+            },
+            Ok(f) => f
+        };
+
+        let mut data = vec![];
+        match file.read_to_end(&mut data) {
+            Err(e) => {
+                error!("Failed to read {:?}: {}", &file_path, e);
+                return Err(e);
+            },
+            Ok(_) => {
+                trace!("Read {} bytes", data.len());
+            }
+        }
+
+        let data = try!(self.unseal(data, &file_path));
+        if is_delta(&data) {
+            let (base_rev, depth) = try!(delta_header(&data));
+            trace!("Stored as a delta against revision {}, depth {}", base_rev, depth);
+            let base = try!(self.read_path(base_rev, path));
+            apply_delta(&base, &data)
+        } else if is_line_list(&data) {
+            self.expand_lines(data)
+        } else {
+            self.chunks.expand(data)
+        }
+    }
+
+    fn open_lines(&self) -> io::Result<bool> {
+        // whether there's a line store, opening it the first time
+        if self.lines.borrow().is_some() {
+            return Ok(true);
+        }
+        let store = try!(LineStore::open_existing(&self.lines_path));
+        let found = store.is_some();
+        *self.lines.borrow_mut() = store;
+        Ok(found)
+    }
+
+    fn expand_lines(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        if !try!(self.open_lines()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("A stored file lists lines, but there's no line store at {}",
+                                              self.lines_path.display())));
+        }
+        self.lines.borrow_mut().as_mut().unwrap().expand(data)
+    }
+
+    fn store_lines(&self, tree_path: &Path) -> io::Result<()> {
+        // replace the full copies in a fresh revision with lists of their
+        // lines in the shared store, where the repository has one. sealed
+        // revisions never do, the store itself isn't sealed
+        if self.key.is_some() || !try!(self.open_lines()) {
+            return Ok(());
+        }
+        debug!("Moving the lines of {:?} to the line store", tree_path);
+        let mut to_visit = vec![tree_path.to_path_buf()];
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            for item in try!(fs::read_dir(dir)) {
+                let entry = try!(item);
+                if try!(entry.metadata()).is_dir() {
+                    to_visit.push(entry.path());
+                    continue;
+                }
+                let data = try!(self.read_stored(&entry.path()));
+                if is_delta(&data) || is_manifest(&data) || is_line_list(&data) {
+                    continue;
+                }
+                let list = try!(self.lines.borrow_mut().as_mut().unwrap().store(&data));
+                if list.len() < data.len() {
+                    trace!("Storing {:?} as a line list of {} bytes", entry.path(), list.len());
+                    try!(self.write_stored(&entry.path(), &list));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn delta_depth(&self, id: RevisionId, path: &Path) -> io::Result<Option<u64>> {
+        // how many deltas deep the stored file is, none if it can't be a base
+        let data = match self.read_stored(&self.rev_path(id).join("tree").join(path)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(data) => data
+        };
+        if is_delta(&data) {
+            delta_header(&data).map(|(_, depth)| Some(depth))
+        } else if is_manifest(&data) {
+            Ok(None)
+        } else {
+            Ok(Some(0))
+        }
+    }
+
+    fn store_deltas(&self, parent: RevisionId, tree_path: &Path) -> io::Result<()> {
+        // replace files in a fresh copy of the stage with deltas against the
+        // parent revision wherever that's smaller. every so often a full copy
+        // is kept so reading a file never goes through too many deltas
+        debug!("Storing changes against revision {}", parent);
+        let mut to_visit = vec![tree_path.to_path_buf()];
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            for item in try!(fs::read_dir(dir)) {
+                let entry = try!(item);
+                let metadata = try!(entry.metadata());
+                if metadata.is_dir() {
+                    to_visit.push(entry.path());
+                    continue;
+                }
+                if metadata.len() > DELTA_SIZE_LIMIT {
+                    trace!("Keeping {:?} whole, it's too big to diff", entry.path());
+                    continue;
+                }
+                let id = match entry.path().relative_from(tree_path) {
+                    Some(id) => PathBuf::from(id),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("{} is outside of the revision", entry.path().display())));
+                    }
+                };
+                let depth = match try!(self.delta_depth(parent, &id)) {
+                    Some(depth) if depth < MAX_DELTA_DEPTH => depth + 1,
+                    _ => {
+                        trace!("Keeping a full copy of {:?}", &id);
+                        continue;
+                    }
+                };
+
+                let data = try!(self.read_stored(&entry.path()));
+                if is_manifest(&data) {
+                    continue;
+                }
+                let base = try!(self.read_path(parent, &id));
+                let delta = make_delta(&base, &data, parent, depth);
+                if delta.len() < data.len() {
+                    trace!("Storing {:?} as a delta of {} bytes", &id, delta.len());
+                    try!(self.write_stored(&entry.path(), &delta));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn restore<T: Into<PathBuf>>(&self, id: RevisionId, to: T) -> io::Result<()> {
+        let tree_path = self.rev_path(id).join("tree");
+        info!("Restoring revision {}", id);
+        match fs::metadata(&tree_path) {
+            Ok(ref data) if data.is_dir() => {
+                trace!("Revision exists");
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("No such revision: {}", id)));
+            }
+        }
+        let to = to.into();
+        try!(copy_dir_all(&tree_path, &to));
+        self.expand_stored(id, &tree_path, &to)
+    }
+
+    fn expand_stored(&self, id: RevisionId, tree_path: &Path, to: &Path) -> io::Result<()> {
+        // chunk lists, line lists, deltas and sealed files were copied as is,
+        // replace them with content
+        let mut to_visit = vec![tree_path.to_path_buf()];
+        while !to_visit.is_empty() {
+            let dir = to_visit.pop().unwrap();
+            for item in try!(fs::read_dir(dir)) {
+                let entry = try!(item);
+                if try!(entry.metadata()).is_dir() {
+                    to_visit.push(entry.path());
+                    continue;
+                }
+                let path = match entry.path().relative_from(tree_path) {
+                    Some(path) => PathBuf::from(path),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("{} is outside of the revision", entry.path().display())));
+                    }
+                };
+                let mut data = vec![];
+                try!(fs::File::open(entry.path()).and_then(|mut f| f.read_to_end(&mut data)));
+                if is_sealed(&data) || is_manifest(&data) || is_delta(&data) || is_line_list(&data) {
+                    debug!("Reassembling {:?}", &path);
+                    try!(atomic_write(to.join(&path), &try!(self.read_path(id, &path))));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn copy_dir_all<T: AsRef<Path>, V: Into<PathBuf>>(from: T, to: V) -> io::Result<()> {
+    let from = from.as_ref();
+    let to = to.into();
+    debug!("Copying {:?} to {:?}", from, &to);
+
+    match fs::create_dir_all(&to) {
+        Err(e) => {
+            error!("Failed to create directory: {}", e);
+            return Err(e);
+        },
+        Ok(_) => {
+            trace!("Directory created");
+        }
+    }
+
+    let mut to_visit = vec![from.to_path_buf()];
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        trace!("Reading directory {:?}", &dir);
+        for item in try!(fs::read_dir(dir)) {
+            let entry = try!(item);
+            if is_temp_path(entry.path()) {
+                trace!("Skipping unfinished file {:?}", entry.path());
+                continue;
+            }
+            let id = match entry.path().relative_from(from) {
+                Some(id) => PathBuf::from(id),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{} is outside of the copy source", entry.path().display())));
+                }
+            };
+            let metadata = try!(entry.metadata());
+            if metadata.is_dir() {
+                to_visit.push(entry.path());
+            }
+            try!(PathInfo::new(entry.path(), id, metadata).copy(&to));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use std::fs;
+    use std::env;
+    use std::io;
+
+    use delta::*;
+    use fileops::*;
+    use crypt::*;
+
+    use Stage;
+
+    #[test]
+    fn test_prune() {
+        let path = env::temp_dir().join("h2-test-prune");
+        let _ = fs::remove_dir_all(&path);
+        let mut revs = Revisions::new(path.join("revs"));
+        let versions: Vec<&[u8]> = vec![&b"one\ntwo\n"[..], &b"one\ntwo\nthree\n"[..],
+                                         &b"zero\none\ntwo\nthree\n"[..]];
+        for (i, data) in versions.iter().enumerate() {
+            let id = i as RevisionId + 1;
+            fs::create_dir_all(revs.rev_path(id).join("tree")).unwrap();
+            let stored = if id == 1 {
+                data.to_vec()
+            } else {
+                make_delta(versions[i - 1], data, id - 1, id - 1)
+            };
+            atomic_write(revs.rev_path(id).join("tree").join("a"), &stored).unwrap();
+            let parent = if id == 1 {None} else {Some(id - 1)};
+            revs.write_meta(&RevisionMeta {id: id, parent: parent, time: Some(0), tree_hash: None, merged: None,
+                                          auto: None, author: None, message: None, hash: None}).unwrap();
+        }
+        atomic_write(path.join("revs").join("HEAD"), b"3\n").unwrap();
+
+        // head is kept even when nothing else is
+        let retention = Retention {keep_last: Some(0), keep_days: Some(1), ..Retention::default()};
+        assert_eq!(revs.retained(&retention, &[1]).unwrap(), vec![1, 3]);
+        assert_eq!(revs.retained(&Retention::default(), &[]).unwrap(), vec![1, 2, 3]);
+
+        assert_eq!(revs.prune(&[1]).unwrap(), vec![2]);
+        assert_eq!(revs.list().unwrap(), vec![1, 3]);
+        assert_eq!(revs.read_path(3, "a").unwrap(), versions[2].to_vec());
+        assert_eq!(revs.meta(3).unwrap().parent, Some(1));
+
+        assert_eq!(revs.prune(&[]).unwrap(), vec![1]);
+        assert_eq!(revs.read_path(3, "a").unwrap(), versions[2].to_vec());
+        assert_eq!(revs.meta(3).unwrap().parent, None);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_revision_hash() {
+        let info = CommitInfo {author: Some("A <a@example.com>".to_string()), message: Some("first".to_string())};
+        let hash = revision_hash(None, None, Some("0000000000000abc"), false, &info);
+        assert_eq!(hash, revision_hash(None, None, Some("0000000000000abc"), false, &info.clone()));
+        assert_eq!(hash.len(), 16);
+        // every field counts, and where one ends
+        assert!(hash != revision_hash(Some("1"), None, Some("0000000000000abc"), false, &info));
+        assert!(hash != revision_hash(None, None, Some("0000000000000abc"), true, &info));
+        assert!(hash != revision_hash(None, None, Some("0000000000000abc"), false, &CommitInfo::default()));
+        let moved = CommitInfo {author: Some("A <a@example.com>first".to_string()), message: None};
+        assert!(hash != revision_hash(None, None, Some("0000000000000abc"), false, &moved));
+    }
+
+    #[test]
+    fn test_retained_auto() {
+        let path = env::temp_dir().join("h2-test-retained-auto");
+        let _ = fs::remove_dir_all(&path);
+        let revs = Revisions::new(path.join("revs"));
+        // 2, 3, 5 and 6 are automatic, 3 is from long ago
+        for id in 1..7 {
+            fs::create_dir_all(revs.rev_path(id)).unwrap();
+            let auto = [2, 3, 5, 6].contains(&id);
+            revs.write_meta(&RevisionMeta {
+                id: id,
+                parent: if id == 1 {None} else {Some(id - 1)},
+                time: Some(if id == 3 {0} else {now()}),
+                tree_hash: None,
+                merged: None,
+                auto: if auto {Some(true)} else {None},
+                author: None,
+                message: None,
+                hash: None
+            }).unwrap();
+        }
+        atomic_write(path.join("revs").join("HEAD"), b"6\n").unwrap();
+
+        // the auto limits only ever drop automatic snapshots
+        let retention = Retention {keep_auto: Some(1), ..Retention::default()};
+        assert_eq!(revs.retained(&retention, &[]).unwrap(), vec![1, 4, 6]);
+        let retention = Retention {keep_auto_hours: Some(1), ..Retention::default()};
+        assert_eq!(revs.retained(&retention, &[3]).unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(revs.retained(&retention, &[]).unwrap(), vec![1, 2, 4, 5, 6]);
+
+        // and only take away from what the other limits keep
+        let retention = Retention {keep_last: Some(3), keep_auto: Some(3), ..Retention::default()};
+        assert_eq!(revs.retained(&retention, &[]).unwrap(), vec![4, 5, 6]);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sealed_revisions() {
+        let path = env::temp_dir().join("h2-test-sealed-revs");
+        let _ = fs::remove_dir_all(&path);
+        let stage = Stage::new(path.join("stage"));
+        fs::create_dir_all(path.join("stage").join("dir")).unwrap();
+        let key = StoreKey::from_hex(&vec!["ab"; 32].concat()).unwrap();
+        let mut revs = Revisions::new(path.join("revs")).with_key(Some(key));
+        revs.init().unwrap();
+
+        let first = b"secret line\nanother line\nand one more\n";
+        let second = b"secret line\nanother line\nand one more\nplus a last one\n";
+        atomic_write(path.join("stage").join("dir").join("a"), first).unwrap();
+        assert_eq!(revs.commit(&stage, Some(0xabc)).unwrap(), 1);
+        atomic_write(path.join("stage").join("dir").join("a"), second).unwrap();
+        assert_eq!(revs.commit(&stage, None).unwrap(), 2);
+        assert_eq!(revs.meta(1).unwrap().tree_hash, Some("0000000000000abc".to_string()));
+        assert_eq!(revs.find_tree_hash("00000000").unwrap(), vec![1]);
+
+        // every stored file is sealed, whether whole or a delta
+        for id in 1..3 {
+            let mut stored = vec![];
+            fs::File::open(revs.rev_path(id).join("tree").join("dir").join("a"))
+                .and_then(|mut f| f.read_to_end(&mut stored)).unwrap();
+            assert!(is_sealed(&stored));
+            assert!(!stored.windows(6).any(|w| w == b"secret"));
+        }
+        assert_eq!(revs.read_path(1, "dir/a").unwrap(), first.to_vec());
+        assert_eq!(revs.read_path(2, "dir/a").unwrap(), second.to_vec());
+
+        revs.restore(2, path.join("restored")).unwrap();
+        let mut restored = vec![];
+        fs::File::open(path.join("restored").join("dir").join("a"))
+            .and_then(|mut f| f.read_to_end(&mut restored)).unwrap();
+        assert_eq!(restored, second.to_vec());
+
+        let keyless = Revisions::new(path.join("revs"));
+        assert_eq!(keyless.read_path(2, "dir/a").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+use std::io::Read;
+
+use std::fs;
+use std::io;
+
+use fileops::*;
+use plan::*;
+use repo::*;
+use crypt::*;
+
+// changes put aside by `h2 stash`, each a patch against the revision they
+// were made on top of. entries are numbered from 1 in the order they were
+// pushed and pop takes the newest. an encrypted repository's are sealed
+#[derive(Debug)]
+pub struct Stash {
+    path: PathBuf,
+    key: Option<StoreKey>,
+    plan: Plan
+}
+
+impl Default for Stash {
+    fn default() -> Stash {
+        Stash::new(repo_path("stash"))
+    }
+}
+
+impl Stash {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Stash {
+        Stash {
+            path: path.into(),
+            key: None,
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Stash {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_key(mut self, key: Option<StoreKey>) -> Stash {
+        self.key = key;
+        self
+    }
+
+    fn place(id: usize) -> Vec<u8> {
+        seal_place("stash", Path::new(&format!("{}", id)))
+    }
+
+    pub fn list(&self) -> io::Result<Vec<usize>> {
+        // every entry, oldest first
+        let entries = match fs::read_dir(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("Nothing is stashed");
+                return Ok(vec![]);
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(entries) => entries
+        };
+
+        let mut ids = vec![];
+        for item in entries {
+            let entry = try!(item);
+            if is_temp_path(entry.path()) {
+                continue;
+            }
+            match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(id) => {
+                    ids.push(id);
+                },
+                None => {
+                    trace!("Skipping {:?}", entry.path());
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn push(&self, patch: &[u8]) -> io::Result<usize> {
+        // keep a patch as the newest entry, returning its number
+        let id = try!(self.list()).last().map_or(1, |last| last + 1);
+        if self.plan.allow(Op::CreateDir(&self.path)) {
+            try!(fs::create_dir_all(&self.path));
+        }
+        let entry_path = self.path.join(format!("{}", id));
+        if self.plan.allow(Op::WriteFile(&entry_path)) {
+            debug!("Stashing {} bytes of changes as entry {}", patch.len(), id);
+            try!(write_stored(self.key.as_ref(), &Stash::place(id), &entry_path, patch));
+        }
+        Ok(id)
+    }
+
+    pub fn read(&self, id: usize) -> io::Result<Vec<u8>> {
+        let entry_path = self.path.join(format!("{}", id));
+        let mut patch = vec![];
+        match fs::File::open(&entry_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("No stash entry {}", id)));
+            },
+            Err(e) => {
+                error!("Failed to open stash entry {}: {}", id, e);
+                return Err(e);
+            },
+            Ok(mut f) => {
+                try!(f.read_to_end(&mut patch));
+            }
+        }
+        open_stored(self.key.as_ref(), &Stash::place(id), patch, &entry_path)
+    }
+
+    pub fn remove(&self, id: usize) -> io::Result<()> {
+        let entry_path = self.path.join(format!("{}", id));
+        if self.plan.allow(Op::Remove(&entry_path)) {
+            debug!("Dropping stash entry {}", id);
+            try!(fs::remove_file(&entry_path));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::fs;
+    use std::env;
+
+    use crypt::*;
+
+    #[test]
+    fn test_stash() {
+        let path = env::temp_dir().join("h2-test-stash");
+        let _ = fs::remove_dir_all(&path);
+        let stash = Stash::new(&path);
+        assert!(stash.list().unwrap().is_empty());
+
+        assert_eq!(stash.push(b"first").unwrap(), 1);
+        assert_eq!(stash.push(b"second").unwrap(), 2);
+        assert_eq!(stash.list().unwrap(), vec![1, 2]);
+        assert_eq!(stash.read(2).unwrap(), b"second".to_vec());
+
+        // a number is only reused once everything after it is gone
+        stash.remove(1).unwrap();
+        assert_eq!(stash.push(b"third").unwrap(), 3);
+        stash.remove(3).unwrap();
+        stash.remove(2).unwrap();
+        assert!(stash.read(2).is_err());
+        assert_eq!(stash.push(b"fourth").unwrap(), 1);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sealed_stash() {
+        let path = env::temp_dir().join("h2-test-sealed-stash");
+        let _ = fs::remove_dir_all(&path);
+        let key = StoreKey::from_hex(&vec!["ab"; 32].concat()).unwrap();
+        let stash = Stash::new(&path).with_key(Some(key));
+        assert_eq!(stash.push(b"+secret line\n").unwrap(), 1);
+        assert_eq!(stash.push(b"+another\n").unwrap(), 2);
+
+        let mut stored = vec![];
+        fs::File::open(path.join("1")).and_then(|mut f| f.read_to_end(&mut stored)).unwrap();
+        assert!(is_sealed(&stored));
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+        assert_eq!(stash.read(1).unwrap(), b"+secret line\n".to_vec());
+
+        // an entry can't be passed off as another one
+        fs::rename(path.join("1"), path.join("2")).unwrap();
+        assert!(stash.read(2).is_err());
+        assert!(Stash::new(&path).read(2).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::Path;
+
+use std::fs;
+use std::io;
+
+use repo::*;
+use manifest::*;
+
+use {FileIndex, Logs};
+
+#[derive(Debug, Default)]
+pub struct RepoStats {
+    pub files: usize,
+    // files whose index is kept in their meta, with no tree to measure
+    pub inline: usize,
+    pub stage_bytes: u64,
+    // line indexes, both the trees and their overflow
+    pub index_bytes: u64,
+    pub lines: usize,
+    pub nodes: usize,
+    pub free_nodes: usize,
+    // summed over every index, for the averages
+    pub total_depth: usize,
+    pub items: usize,
+    pub slots: usize
+}
+
+impl RepoStats {
+    pub fn average_depth(&self) -> f64 {
+        let trees = self.files - self.inline;
+        if trees == 0 {
+            0.0
+        } else {
+            self.total_depth as f64 / trees as f64
+        }
+    }
+
+    pub fn fill_factor(&self) -> f64 {
+        // how full tree nodes are on average
+        if self.slots == 0 {
+            0.0
+        } else {
+            self.items as f64 / self.slots as f64
+        }
+    }
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    let mut to_visit = vec![path.to_path_buf()];
+    while let Some(dir) = to_visit.pop() {
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            let metadata = try!(entry.metadata());
+            if metadata.is_dir() {
+                to_visit.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+pub fn collect_stats(repo: &Repo) -> io::Result<RepoStats> {
+    info!("Collecting repository statistics");
+    let mut logs = Logs::new(repo.path.join("logs"));
+    if let Some(manifest) = try!(Manifest::open_existing(repo.path.join("manifest"))) {
+        logs = logs.with_manifest(manifest);
+    }
+    let mut stats = RepoStats::default();
+    stats.stage_bytes = try!(dir_size(&repo.path.join("stage")));
+
+    for tracked in try!(logs.tracked_paths()) {
+        let (id, meta) = try!(tracked);
+        debug!("Measuring index of {:?}", &id);
+        let (_, index) = try!(logs.open_index(&id));
+        stats.index_bytes += try!(logs.index_size(&id));
+        stats.files += 1;
+        stats.lines += meta.node_count;
+        let mut index = match index {
+            FileIndex::Tree(index) => index,
+            FileIndex::Inline(_) => {
+                stats.inline += 1;
+                continue;
+            }
+        };
+        let tree = index.tree_mut();
+        let shape = try!(tree.stats());
+
+        stats.nodes += shape.nodes;
+        stats.free_nodes += shape.free_nodes;
+        stats.total_depth += shape.depth;
+        stats.items += try!(tree.verify());
+        stats.slots += shape.nodes * shape.width;
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::hash::Hasher;
+use std::io::Read;
+
+use std::fs;
+use std::io;
+
+use rustc_serialize::json;
+
+use fileops::*;
+use hashers::*;
+use pathid::*;
+use platform::*;
+use revs::now;
+
+// what the last status made of each file it read, so the next one only reads
+// files that moved since. the whole cache is for one tree hash and config, a
+// file's entry for the size and mtime it and its staged copy had
+
+// a file changed less than this many seconds before its status was found can
+// change again without its mtime moving, so it isn't kept
+pub const STATUS_CACHE_SETTLE_SECS: i64 = 1;
+
+// how a file and its staged copy looked when its status was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    // none when nothing was staged
+    pub staged: Option<(u64, i64, i64)>
+}
+
+impl FileStamp {
+    pub fn new(metadata: &fs::Metadata, staged: Option<&fs::Metadata>) -> FileStamp {
+        let (secs, nsecs) = mtime(metadata);
+        FileStamp {
+            size: metadata.len(),
+            mtime: secs,
+            mtime_nsec: nsecs,
+            staged: staged.map(|staged| {
+                let (secs, nsecs) = mtime(staged);
+                (staged.len(), secs, nsecs)
+            })
+        }
+    }
+
+    fn settled(&self, at: i64) -> bool {
+        self.mtime + STATUS_CACHE_SETTLE_SECS < at &&
+            self.staged.map_or(true, |staged| staged.1 + STATUS_CACHE_SETTLE_SECS < at)
+    }
+}
+
+// a file status shows, new or modified with its line counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct CachedStatus {
+    pub untracked: bool,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub content_hash: u64
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct CachedFile {
+    // escaped with escape_id so any path survives the json
+    id: String,
+    stamp: FileStamp,
+    // none for a file status found unchanged
+    status: Option<CachedStatus>
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+struct CacheList {
+    key: u64,
+    files: Vec<CachedFile>
+}
+
+pub fn status_key(tree_hash: Option<u64>, config: &[u8]) -> u64 {
+    // what every entry depends on besides its own file: what's staged, and
+    // the config that says how lines are compared
+    let mut hasher = FnvHasher::default();
+    match tree_hash {
+        Some(hash) => {
+            hasher.write(&[1]);
+            hasher.write_u64(hash);
+        },
+        None => {
+            hasher.write(&[0]);
+        }
+    }
+    hasher.write(config);
+    hasher.finish()
+}
+
+// like anchors, a cache that's missing, unreadable or for another key is
+// the same as an empty one
+#[derive(Debug)]
+pub struct StatusCache {
+    path: PathBuf,
+    key: u64,
+    files: HashMap<String, (FileStamp, Option<CachedStatus>)>
+}
+
+impl StatusCache {
+    pub fn load<T: Into<PathBuf>>(path: T, key: u64) -> StatusCache {
+        let mut cache = StatusCache {
+            path: path.into(),
+            key: key,
+            files: HashMap::new()
+        };
+        let mut data = String::new();
+        if let Err(e) = fs::File::open(&cache.path).and_then(|mut f| f.read_to_string(&mut data)) {
+            trace!("No status cache: {}", e);
+            return cache;
+        }
+        match json::decode::<CacheList>(&data) {
+            Ok(ref list) if list.key == key => {
+                debug!("{} files in the status cache", list.files.len());
+                for file in list.files.iter() {
+                    cache.files.insert(file.id.clone(), (file.stamp, file.status));
+                }
+            },
+            Ok(_) => {
+                debug!("Status cache is for another tree or config");
+            },
+            Err(e) => {
+                debug!("Status cache is unreadable: {}", e);
+            }
+        }
+        cache
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn lookup(&self, id: &Path, stamp: &FileStamp) -> Option<Option<CachedStatus>> {
+        // what status found for id last time if it still looks the same,
+        // some of none if it was unchanged. none if it has to be read
+        match self.files.get(&escape_id(id)) {
+            Some(&(ref cached, status)) if cached == stamp => Some(status),
+            _ => None
+        }
+    }
+
+    pub fn record(&mut self, id: &Path, stamp: FileStamp, status: Option<CachedStatus>) {
+        let key = escape_id(id);
+        if stamp.settled(now()) {
+            self.files.insert(key, (stamp, status));
+        } else {
+            trace!("{:?} changed too recently to cache", id);
+            self.files.remove(&key);
+        }
+    }
+
+    pub fn keep_only(&mut self, ids: &[PathBuf]) {
+        // after a walk of the whole checkout, whatever it didn't see is gone
+        let seen: HashSet<String> = ids.iter().map(|id| escape_id(id)).collect();
+        self.files.retain(|id, _| seen.contains(id));
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut files: Vec<CachedFile> = self.files.iter().map(|(id, &(stamp, status))| CachedFile {
+            id: id.clone(),
+            stamp: stamp,
+            status: status
+        }).collect();
+        files.sort_by(|a, b| a.id.cmp(&b.id));
+        let data = match json::encode(&CacheList {key: self.key, files: files}) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to encode to json: {}", e)));
+            },
+            Ok(d) => d
+        };
+        if let Some(parent) = self.path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        debug!("Saving {} files to the status cache", self.files.len());
+        atomic_write(&self.path, data.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::fs;
+    use std::env;
+
+    fn stamp(mtime: i64) -> FileStamp {
+        FileStamp {
+            size: 4,
+            mtime: mtime,
+            mtime_nsec: 0,
+            staged: Some((4, 100, 0))
+        }
+    }
+
+    #[test]
+    fn test_status_cache() {
+        let path = env::temp_dir().join("h2-test-status-cache").join("status");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        let key = status_key(Some(7), b"line_endings = normalize\n");
+        let modified = CachedStatus {
+            untracked: false,
+            insertions: 1,
+            deletions: 2,
+            content_hash: 3
+        };
+
+        let mut cache = StatusCache::load(&path, key);
+        assert!(cache.is_empty());
+        cache.record(Path::new("a.txt"), stamp(100), Some(modified));
+        cache.record(Path::new("b.txt"), stamp(100), None);
+        // one that could still change within its mtime isn't kept
+        cache.record(Path::new("c.txt"), stamp(::revs::now()), None);
+        cache.save().unwrap();
+
+        let mut cache = StatusCache::load(&path, key);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lookup(Path::new("a.txt"), &stamp(100)), Some(Some(modified)));
+        assert_eq!(cache.lookup(Path::new("b.txt"), &stamp(100)), Some(None));
+        assert_eq!(cache.lookup(Path::new("b.txt"), &stamp(101)), None);
+        assert_eq!(cache.lookup(Path::new("c.txt"), &stamp(100)), None);
+        cache.keep_only(&[PathBuf::from("b.txt")]);
+        assert_eq!(cache.len(), 1);
+
+        // nothing carries over to another tree or config
+        assert!(StatusCache::load(&path, status_key(None, b"line_endings = normalize\n")).is_empty());
+        assert!(StatusCache::load(&path, status_key(Some(7), b"")).is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use std::path::{Path, PathBuf};
+use std::io::Read;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use config::*;
+use fileops::*;
+use pack::*;
+
+// where new indexes are kept. either way the manifest says where each one
+// is, so reading never needs to know which backend wrote it and a
+// repository can hold indexes from both. only indexes have a backend, the
+// stage keeps a copy per path since revisions are made by copying it
+
+// a packed backend starts a new pack once the active one is this big
+pub const PACK_SIZE_LIMIT: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    // a directory of meta, content and places per file under logs
+    Dir,
+    // appended to the newest pack, one file for many indexes
+    Packed
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend::Dir
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Backend::Dir => write!(f, "dir"),
+            Backend::Packed => write!(f, "packed")
+        }
+    }
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "dir" => Some(Backend::Dir),
+            "packed" => Some(Backend::Packed),
+            _ => None
+        }
+    }
+
+    pub fn from_config(config: &Config) -> io::Result<Backend> {
+        match config.get("backend") {
+            None => Ok(Backend::default()),
+            Some(value) => Backend::from_name(value).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Config value backend = {:?} is not dir or packed", value))
+            })
+        }
+    }
+}
+
+// one backend's way of keeping the index it's handed. an index is built in
+// the directory prepare gives, meta and all written by store once it's
+// complete
+pub trait IndexStore: fmt::Debug {
+    fn backend(&self) -> Backend;
+    // the directory the trees of id are built in, as temp files
+    fn prepare(&mut self, id: &Path) -> io::Result<PathBuf>;
+    // keep the meta of id, with the trees built for it if it has them. the
+    // pack it went to, if any, is for the manifest to point at
+    fn store(&mut self, id: &Path, meta: &[u8], has_trees: bool) -> io::Result<Option<PackLocation>>;
+    // take away the index of id, returning whether there was one. a packed
+    // index is left for the next repack once the manifest forgets it
+    fn remove(&mut self, id: &Path) -> io::Result<bool>;
+}
+
+pub fn open_store(backend: Backend, logs_path: &Path) -> Box<IndexStore + Send> {
+    match backend {
+        Backend::Dir => Box::new(DirStore::new(logs_path)),
+        Backend::Packed => Box::new(PackedStore::new(logs_path))
+    }
+}
+
+fn remove_loose(logs_path: &Path, id: &Path) -> io::Result<bool> {
+    // the files of an index kept in its own directory, and the directory
+    // unless it holds the indexes of files under it
+    let dir = logs_path.join(id);
+    let mut removed = false;
+    for name in ["meta", "content", "places"].iter() {
+        match fs::remove_file(dir.join(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(()) => {
+                trace!("Removed loose {} of {:?}", name, id);
+                removed = true;
+            }
+        }
+    }
+    match fs::remove_dir(&dir) {
+        Ok(()) => {
+            trace!("Removed loose index directory of {:?}", id);
+        },
+        Err(e) => {
+            // gone already, or it holds the indexes of files under it
+            trace!("Leaving {:?}: {}", &dir, e);
+        }
+    }
+    Ok(removed)
+}
+
+// each index in a directory of its own under logs
+#[derive(Debug)]
+pub struct DirStore {
+    path: PathBuf
+}
+
+impl DirStore {
+    pub fn new<T: Into<PathBuf>>(path: T) -> DirStore {
+        DirStore {
+            path: path.into()
+        }
+    }
+}
+
+impl IndexStore for DirStore {
+    fn backend(&self) -> Backend {
+        Backend::Dir
+    }
+
+    fn prepare(&mut self, id: &Path) -> io::Result<PathBuf> {
+        let dest_path = self.path.join(id);
+        debug!("Creating log directory");
+        match fs::create_dir_all(&dest_path) {
+            Err(e) => {
+                error!("Failed to create parent directory: {}", e);
+                Err(e)
+            },
+            Ok(_) => {
+                trace!("Parent directory created");
+                Ok(dest_path)
+            }
+        }
+    }
+
+    fn store(&mut self, id: &Path, meta: &[u8], has_trees: bool) -> io::Result<Option<PackLocation>> {
+        let dest_path = self.path.join(id);
+        if has_trees {
+            trace!("Replacing index");
+            try!(commit_temp(dest_path.join("places")));
+            try!(commit_temp(dest_path.join("content")));
+        }
+        trace!("Writing to file");
+        match atomic_write(dest_path.join("meta"), meta) {
+            Err(e) => {
+                error!("Failed to write meta info to file: {}", e);
+                return Err(e);
+            },
+            Ok(()) => {
+                trace!("Meta info written to file successfully");
+            }
+        }
+        if !has_trees {
+            // a tree from when the file was bigger isn't read any more
+            for name in ["content", "places"].iter() {
+                match fs::remove_file(dest_path.join(name)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => {
+                        return Err(e);
+                    },
+                    Ok(()) => {
+                        trace!("Removed old {}", name);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&mut self, id: &Path) -> io::Result<bool> {
+        remove_loose(&self.path, id)
+    }
+}
+
+// every index appended to the newest pack. trees are built in a scratch
+// directory next to logs and copied in, and the writer stays open for the
+// rest of the command. nothing points at what's appended until the manifest
+// does, so a command cut short only leaves unused bytes for the next repack
+#[derive(Debug)]
+pub struct PackedStore {
+    logs: PathBuf,
+    scratch: PathBuf,
+    packs: Packs,
+    writer: Option<PackWriter>
+}
+
+impl PackedStore {
+    pub fn new<T: Into<PathBuf>>(logs_path: T) -> PackedStore {
+        let logs = logs_path.into();
+        PackedStore {
+            scratch: logs.with_file_name("building"),
+            packs: Packs::new(logs.with_file_name("packs")),
+            logs: logs,
+            writer: None
+        }
+    }
+
+    fn take_tree(&self, name: &str) -> io::Result<Vec<u8>> {
+        let path = try!(temp_path(self.scratch.join(name)));
+        let mut data = vec![];
+        try!(fs::File::open(&path).and_then(|mut f| f.read_to_end(&mut data)));
+        try!(fs::remove_file(&path));
+        Ok(data)
+    }
+
+}
+
+impl IndexStore for PackedStore {
+    fn backend(&self) -> Backend {
+        Backend::Packed
+    }
+
+    fn prepare(&mut self, _: &Path) -> io::Result<PathBuf> {
+        try!(fs::create_dir_all(&self.scratch));
+        Ok(self.scratch.clone())
+    }
+
+    fn store(&mut self, id: &Path, meta: &[u8], has_trees: bool) -> io::Result<Option<PackLocation>> {
+        let (content, places) = if has_trees {
+            (try!(self.take_tree("content")), try!(self.take_tree("places")))
+        } else {
+            (vec![], vec![])
+        };
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => try!(self.packs.appender(PACK_SIZE_LIMIT))
+        };
+        let added = writer.add(meta, &content, &places).and_then(|location| writer.flush().map(|_| location));
+        self.writer = Some(writer);
+        let location = try!(added);
+        debug!("Appended index of {:?} to pack {}", id, location.pack);
+        // an index written before the repository was packed is no longer
+        // the one the manifest points at
+        try!(remove_loose(&self.logs, id));
+        Ok(Some(location))
+    }
+
+    fn remove(&mut self, id: &Path) -> io::Result<bool> {
+        // what's in a pack stays until a repack, nothing points at it once
+        // the manifest forgets it. a loose index from before packing goes
+        remove_loose(&self.logs, id)
+    }
+}
+
+impl Drop for PackedStore {
+    fn drop(&mut self) {
+        // every index was flushed as it was added, this makes them durable
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.finish() {
+                warn!("Failed to sync the active pack: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::fs;
+    use std::env;
+
+    use pack::*;
+
+    #[test]
+    fn test_packed_store() {
+        let root = env::temp_dir().join("h2-test-storage");
+        let _ = fs::remove_dir_all(&root);
+        let logs = root.join("logs");
+        fs::create_dir_all(logs.join("a.txt")).unwrap();
+        fs::File::create(logs.join("a.txt").join("meta")).unwrap();
+
+        let (first, second) = {
+            let mut store = PackedStore::new(&logs);
+            let first = store.store(Path::new("a.txt"), b"meta a", false).unwrap().unwrap();
+            let second = store.store(Path::new("b.txt"), b"meta b", false).unwrap().unwrap();
+            (first, second)
+        };
+        // the loose index it replaced is gone
+        assert!(fs::metadata(logs.join("a.txt")).is_err());
+        assert!(!PackedStore::new(&logs).remove(Path::new("a.txt")).unwrap());
+
+        // and a later command appends to the same pack
+        let third = PackedStore::new(&logs).store(Path::new("c.txt"), b"meta c", false).unwrap().unwrap();
+        let packs = Packs::new(root.join("packs"));
+        assert_eq!(packs.list().unwrap(), vec![1]);
+        assert_eq!(first.pack, third.pack);
+        assert_eq!(packs.read_span(1, first.meta).unwrap(), b"meta a".to_vec());
+        assert_eq!(packs.read_span(1, second.meta).unwrap(), b"meta b".to_vec());
+        assert_eq!(packs.read_span(1, third.meta).unwrap(), b"meta c".to_vec());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use std::cmp;
+use std::fs;
+use std::io;
+
+// a window onto part of a bigger buffer, so a tree or map can live at some
+// offset inside a shared file. positions are relative to the start of the
+// window and nothing outside it can be read or written. the inner position
+// is set before every access, so handles sharing a file don't get in each
+// other's way
+#[derive(Debug)]
+pub struct SubBuffer<T: Read + Write + Seek> {
+    inner: T,
+    base: u64,
+    len: u64,
+    pos: u64
+}
+
+impl SubBuffer<fs::File> {
+    pub fn whole(file: fs::File) -> io::Result<SubBuffer<fs::File>> {
+        // a window over an entire file, as it is now
+        let len = try!(file.metadata()).len();
+        Ok(SubBuffer::new(file, 0, len))
+    }
+}
+
+impl<T: Read + Write + Seek> SubBuffer<T> {
+    pub fn new(inner: T, base: u64, len: u64) -> SubBuffer<T> {
+        SubBuffer {
+            inner: inner,
+            base: base,
+            len: len,
+            pos: 0
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read + Write + Seek> Read for SubBuffer<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let max = cmp::min(buf.len() as u64, self.len - self.pos) as usize;
+        try!(self.inner.seek(SeekFrom::Start(self.base + self.pos)));
+        let read = try!(self.inner.read(&mut buf[..max]));
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Write + Seek> Write for SubBuffer<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.len {
+            return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                      format!("Write at {} is past the end of a {} byte window",
+                                              self.pos, self.len)));
+        }
+        let max = cmp::min(buf.len() as u64, self.len - self.pos) as usize;
+        try!(self.inner.seek(SeekFrom::Start(self.base + self.pos)));
+        let written = try!(self.inner.write(&buf[..max]));
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Read + Write + Seek> Seek for SubBuffer<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of a window"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use tree::*;
+
+    #[test]
+    fn test_sub_buffer() {
+        let mut window = SubBuffer::new(Cursor::new(vec![0u8; 16]), 4, 8);
+        window.write_all(b"abcd").unwrap();
+        assert_eq!(window.seek(SeekFrom::End(-2)).unwrap(), 6);
+        window.write_all(b"xy").unwrap();
+        assert!(window.write_all(b"z").is_err());
+
+        window.seek(SeekFrom::Start(0)).unwrap();
+        let mut data = vec![];
+        window.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcd\0\0xy".to_vec());
+        assert_eq!(&window.into_inner().into_inner()[..], b"\0\0\0\0abcd\0\0xy\0\0\0\0");
+    }
+
+    #[test]
+    fn test_tree_in_window() {
+        // build a tree in memory, then read it back out of the middle of a bigger buffer
+        let mut tree: BufTree<_, u64> = BufTree::default();
+        for i in 0..100 {
+            tree.insert(i).unwrap();
+        }
+        let mut data = vec![];
+        tree.export(&mut data).unwrap();
+        let built: BufTree<_, u64> = BufTree::import(Cursor::new(vec![]), &mut Cursor::new(data)).unwrap();
+        let bytes = built.into_inner().into_inner();
+
+        let mut shared = vec![7u8; 10];
+        shared.extend(bytes.iter().cloned());
+        shared.extend(vec![7u8; 10]);
+        let window = SubBuffer::new(Cursor::new(shared), 10, bytes.len() as u64);
+        let mut packed: BufTree<_, u64> = unsafe {BufTree::from_buffer(window)}.unwrap();
+        assert_eq!(packed.verify().unwrap(), 100);
+        assert_eq!(packed.get(42).unwrap(), Some(42));
+    }
+}
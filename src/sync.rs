@@ -0,0 +1,359 @@
+use std::path::PathBuf;
+use std::collections::HashSet;
+
+use std::io;
+
+use repo::*;
+use revs::*;
+use lock::*;
+use plan::*;
+use config::*;
+use crypt::*;
+
+// copying revisions between repositories. history is linear, so two
+// repositories agree as long as the newest revision the receiving side has
+// is the same on both, told by its parent and tree hash. only the revisions
+// after it and the chunks they list that the receiving side lacks are sent
+
+// one end of a sync. revisions go across as their stored files, so sealed
+// files are copied without ever being opened
+pub trait Transport {
+    // where it is, for messages
+    fn describe(&self) -> String;
+    fn head(&self) -> io::Result<Option<RevisionId>>;
+    fn revisions(&self) -> io::Result<Vec<RevisionId>>;
+    fn meta(&self, id: RevisionId) -> io::Result<RevisionMeta>;
+    // the check value of the key its files are sealed with, if any
+    fn key_check(&self) -> Option<String>;
+    fn chunks(&self) -> io::Result<HashSet<u64>>;
+    fn chunk_refs(&self, id: RevisionId) -> io::Result<HashSet<u64>>;
+    // a revision's stored files, with deltas against anything not in bases
+    // stored whole
+    fn read_revision(&self, id: RevisionId, bases: &[RevisionId]) -> io::Result<Vec<(PathBuf, Vec<u8>)>>;
+    fn write_revision(&self, id: RevisionId, files: &[(PathBuf, Vec<u8>)]) -> io::Result<()>;
+    fn read_chunk(&self, hash: u64) -> io::Result<Vec<u8>>;
+    fn write_chunk(&self, hash: u64, data: &[u8]) -> io::Result<()>;
+    fn set_head(&self, id: RevisionId) -> io::Result<()>;
+}
+
+// a repository on a local path
+#[derive(Debug)]
+pub struct DirTransport {
+    root: PathBuf,
+    revs: Revisions,
+    // held for as long as it's open, if it isn't the repository the command
+    // already locked
+    _lock: Option<RepoLock>
+}
+
+impl DirTransport {
+    pub fn open<T: Into<PathBuf>>(root: T) -> io::Result<DirTransport> {
+        let repo = try!(Repo::open(root));
+        // sealed files are copied as they are, but deltas stored whole have
+        // to be opened and sealed again
+        let key = try!(load_key(&try!(Config::load(repo.path.join("config")))));
+        Ok(DirTransport {
+            revs: Revisions::new(repo.path.join("revs")).with_key(key),
+            root: repo.root,
+            _lock: None
+        })
+    }
+
+    pub fn open_locked<T: Into<PathBuf>>(root: T, mode: LockMode, wait: bool) -> io::Result<DirTransport> {
+        let mut transport = try!(DirTransport::open(root));
+        transport._lock = Some(try!(Repo::new(transport.root.clone()).lock(mode, wait)));
+        Ok(transport)
+    }
+}
+
+impl Transport for DirTransport {
+    fn describe(&self) -> String {
+        format!("{}", self.root.display())
+    }
+
+    fn head(&self) -> io::Result<Option<RevisionId>> {
+        self.revs.head()
+    }
+
+    fn revisions(&self) -> io::Result<Vec<RevisionId>> {
+        self.revs.list()
+    }
+
+    fn meta(&self, id: RevisionId) -> io::Result<RevisionMeta> {
+        self.revs.meta(id)
+    }
+
+    fn key_check(&self) -> Option<String> {
+        self.revs.key_check()
+    }
+
+    fn chunks(&self) -> io::Result<HashSet<u64>> {
+        Ok(try!(self.revs.chunks().list()).into_iter().collect())
+    }
+
+    fn chunk_refs(&self, id: RevisionId) -> io::Result<HashSet<u64>> {
+        self.revs.chunk_refs(id)
+    }
+
+    fn read_revision(&self, id: RevisionId, bases: &[RevisionId]) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+        self.revs.export(id, bases)
+    }
+
+    fn write_revision(&self, id: RevisionId, files: &[(PathBuf, Vec<u8>)]) -> io::Result<()> {
+        self.revs.import(id, files)
+    }
+
+    fn read_chunk(&self, hash: u64) -> io::Result<Vec<u8>> {
+        self.revs.chunks().read_hash(hash)
+    }
+
+    fn write_chunk(&self, hash: u64, data: &[u8]) -> io::Result<()> {
+        let chunk = try!(self.revs.chunks().write_chunk(data));
+        if chunk.hash != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Chunk {:016x} arrived corrupt", hash)));
+        }
+        Ok(())
+    }
+
+    fn set_head(&self, id: RevisionId) -> io::Result<()> {
+        self.revs.set_head(id)
+    }
+}
+
+fn is_ssh_spec(spec: &str) -> bool {
+    // ssh://host/path or host:path, but not a drive letter
+    spec.starts_with("ssh://") || match spec.find(':') {
+        Some(i) => i > 1 && !spec[..i].contains('/') && !spec[..i].contains('\\'),
+        None => false
+    }
+}
+
+pub fn open_remote(spec: &str, mode: LockMode, wait: bool) -> io::Result<Box<Transport>> {
+    // the other end of a push or pull, only a local path for now
+    if is_ssh_spec(spec) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("SSH remotes are not supported yet: {}", spec)));
+    }
+    debug!("Opening repository at {} as a remote", spec);
+    Ok(Box::new(try!(DirTransport::open_locked(spec, mode, wait))))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    // the revisions sent, oldest first
+    pub revisions: Vec<RevisionId>,
+    pub chunks: usize
+}
+
+fn same_revision(a: &RevisionMeta, b: &RevisionMeta) -> bool {
+    // by revision hash when both have one, then by tree hash, then by when
+    // it was committed
+    if let (Some(a_hash), Some(b_hash)) = (a.hash.as_ref(), b.hash.as_ref()) {
+        return a.parent == b.parent && a_hash == b_hash;
+    }
+    a.parent == b.parent && a.merged == b.merged && a.auto == b.auto && match (a.tree_hash.as_ref(), b.tree_hash.as_ref()) {
+        (Some(a_hash), Some(b_hash)) => a_hash == b_hash,
+        _ => a.time == b.time
+    }
+}
+
+pub fn missing_revisions(from: &Transport, to: &Transport) -> io::Result<Vec<RevisionId>> {
+    // the revisions to send, oldest first. fails unless the receiving side's
+    // history is the start of the sending side's
+    let from_head = match try!(from.head()) {
+        Some(head) => head,
+        None => {
+            debug!("{} has no revisions", from.describe());
+            return Ok(vec![]);
+        }
+    };
+    let to_head = try!(to.head());
+    if let Some(to_head) = to_head {
+        if to_head > from_head {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("{} has revisions up to {} that {} doesn't, sync the other way first",
+                                              to.describe(), to_head, from.describe())));
+        }
+        let theirs = try!(to.meta(to_head));
+        let ours = match from.meta(to_head) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("Revision {} was pruned from {}, so the histories can't be compared",
+                                                  to_head, from.describe())));
+            },
+            Err(e) => {
+                return Err(e);
+            },
+            Ok(meta) => meta
+        };
+        if !same_revision(&ours, &theirs) {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("{} and {} have diverged at revision {}",
+                                              from.describe(), to.describe(), to_head)));
+        }
+        trace!("Both sides agree up to revision {}", to_head);
+    }
+
+    let after = to_head.unwrap_or(0);
+    Ok(try!(from.revisions()).into_iter().filter(|&id| id > after && id <= from_head).collect())
+}
+
+pub fn sync(from: &Transport, to: &Transport, plan: Plan) -> io::Result<SyncStats> {
+    // send what the other side is missing, chunks first and head last so it
+    // never refers to anything that hasn't arrived
+    info!("Syncing {} into {}", from.describe(), to.describe());
+    if from.key_check() != to.key_check() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("{} and {} are not sealed with the same key",
+                                          from.describe(), to.describe())));
+    }
+
+    let revisions = try!(missing_revisions(from, to));
+    let have = try!(to.chunks());
+    let mut needed = HashSet::new();
+    for &id in revisions.iter() {
+        for hash in try!(from.chunk_refs(id)) {
+            if !have.contains(&hash) {
+                needed.insert(hash);
+            }
+        }
+    }
+    let stats = SyncStats {
+        revisions: revisions.clone(),
+        chunks: needed.len()
+    };
+    if revisions.is_empty() || plan.is_dry_run() {
+        debug!("Sending nothing, {} revisions are missing", revisions.len());
+        return Ok(stats);
+    }
+
+    debug!("Sending {} chunks", needed.len());
+    for &hash in needed.iter() {
+        try!(to.write_chunk(hash, &try!(from.read_chunk(hash))));
+    }
+    let mut bases = try!(to.revisions());
+    for &id in revisions.iter() {
+        debug!("Sending revision {}", id);
+        try!(to.write_revision(id, &try!(from.read_revision(id, &bases))));
+        bases.push(id);
+    }
+    try!(to.set_head(revisions[revisions.len() - 1]));
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::is_ssh_spec;
+    use std::path::PathBuf;
+    use std::collections::HashSet;
+    use std::io;
+
+    use revs::*;
+
+    // just enough of a repository to negotiate against
+    struct MetaOnly {
+        metas: Vec<RevisionMeta>
+    }
+
+    fn rev(id: RevisionId, hash: &str) -> RevisionMeta {
+        RevisionMeta {
+            id: id,
+            parent: if id == 1 {None} else {Some(id - 1)},
+            time: Some(0),
+            tree_hash: Some(hash.to_string()),
+            merged: None,
+            auto: None,
+            author: None,
+            message: None,
+            hash: None
+        }
+    }
+
+    fn only_metas() -> io::Error {
+        // negotiating never reads or writes content, so nothing else is kept
+        io::Error::new(io::ErrorKind::Other, "Only revision metas are kept")
+    }
+
+    impl Transport for MetaOnly {
+        fn describe(&self) -> String {
+            "test".to_string()
+        }
+
+        fn head(&self) -> io::Result<Option<RevisionId>> {
+            Ok(self.metas.last().map(|meta| meta.id))
+        }
+
+        fn revisions(&self) -> io::Result<Vec<RevisionId>> {
+            Ok(self.metas.iter().map(|meta| meta.id).collect())
+        }
+
+        fn meta(&self, id: RevisionId) -> io::Result<RevisionMeta> {
+            match self.metas.iter().find(|meta| meta.id == id) {
+                Some(meta) => Ok(rev(meta.id, meta.tree_hash.as_ref().unwrap())),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such revision"))
+            }
+        }
+
+        fn key_check(&self) -> Option<String> {
+            None
+        }
+
+        fn chunks(&self) -> io::Result<HashSet<u64>> {
+            Ok(HashSet::new())
+        }
+
+        fn chunk_refs(&self, _: RevisionId) -> io::Result<HashSet<u64>> {
+            Ok(HashSet::new())
+        }
+
+        fn read_revision(&self, _: RevisionId, _: &[RevisionId]) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+            Err(only_metas())
+        }
+
+        fn write_revision(&self, _: RevisionId, _: &[(PathBuf, Vec<u8>)]) -> io::Result<()> {
+            Err(only_metas())
+        }
+
+        fn read_chunk(&self, _: u64) -> io::Result<Vec<u8>> {
+            Err(only_metas())
+        }
+
+        fn write_chunk(&self, _: u64, _: &[u8]) -> io::Result<()> {
+            Err(only_metas())
+        }
+
+        fn set_head(&self, _: RevisionId) -> io::Result<()> {
+            Err(only_metas())
+        }
+    }
+
+    #[test]
+    fn test_missing_revisions() {
+        let ours = MetaOnly {metas: vec![rev(1, "a"), rev(2, "b"), rev(3, "c")]};
+        let empty = MetaOnly {metas: vec![]};
+        let behind = MetaOnly {metas: vec![rev(1, "a")]};
+        let diverged = MetaOnly {metas: vec![rev(1, "a"), rev(2, "x")]};
+
+        assert_eq!(missing_revisions(&ours, &empty).unwrap(), vec![1, 2, 3]);
+        assert_eq!(missing_revisions(&ours, &behind).unwrap(), vec![2, 3]);
+        assert_eq!(missing_revisions(&ours, &ours).unwrap(), vec![]);
+        assert_eq!(missing_revisions(&empty, &ours).unwrap(), vec![]);
+        assert!(missing_revisions(&behind, &ours).is_err());
+        assert!(missing_revisions(&ours, &diverged).is_err());
+        // only the receiving side's head has to be there to compare
+        let pruned = MetaOnly {metas: vec![rev(2, "b"), rev(3, "c")]};
+        assert_eq!(missing_revisions(&pruned, &behind).unwrap_err().kind(), io::ErrorKind::Other);
+        assert_eq!(missing_revisions(&ours, &pruned).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_ssh_specs() {
+        assert!(is_ssh_spec("ssh://host/repo"));
+        assert!(is_ssh_spec("host:repo"));
+        assert!(!is_ssh_spec("../repo"));
+        assert!(!is_ssh_spec("/tmp/a:b"));
+        assert!(!is_ssh_spec("C:\\repo"));
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::Path;
+use std::io::Write;
+
+use std::fs;
+use std::io;
+
+// the shape of a made up checkout, for benchmarking the whole pipeline on
+// something bigger than a unit test
+#[derive(Debug, Clone)]
+pub struct SynthSpec {
+    pub dirs: usize,
+    pub files_per_dir: usize,
+    pub lines_per_file: usize,
+    pub line_len: usize,
+    pub seed: u64
+}
+
+impl Default for SynthSpec {
+    fn default() -> SynthSpec {
+        SynthSpec {
+            dirs: 10,
+            files_per_dir: 20,
+            lines_per_file: 200,
+            line_len: 40,
+            seed: 1
+        }
+    }
+}
+
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+fn write_lines(path: &Path, rng: &mut Lcg, spec: &SynthSpec) -> io::Result<()> {
+    // lines come from a small vocabulary so some repeat, like real source
+    let mut data = vec![];
+    for _ in 0..spec.lines_per_file {
+        let target = spec.line_len / 2 + rng.next() as usize % (spec.line_len / 2 + 1);
+        let mut line = String::new();
+        while line.len() < target {
+            line.push_str(&format!("w{} ", rng.next() % 64));
+        }
+        data.extend(line.trim_right().bytes());
+        data.push(b'\n');
+    }
+    fs::File::create(path).and_then(|mut f| f.write_all(&data))
+}
+
+pub fn generate_checkout<T: AsRef<Path>>(root: T, spec: &SynthSpec) -> io::Result<usize> {
+    // write the files a spec describes under root, returning how many
+    let root = root.as_ref();
+    let mut rng = Lcg(spec.seed);
+    let mut count = 0;
+    for dir in 0..spec.dirs {
+        let dir_path = root.join(format!("dir{}", dir));
+        try!(fs::create_dir_all(&dir_path));
+        for file in 0..spec.files_per_dir {
+            try!(write_lines(&dir_path.join(format!("file{}.txt", file)), &mut rng, spec));
+            count += 1;
+        }
+    }
+    debug!("Generated {} files under {:?}", count, root);
+    Ok(count)
+}
+
+pub fn mutate_checkout<T: AsRef<Path>>(root: T, spec: &SynthSpec, every: usize) -> io::Result<usize> {
+    // rewrite every nth file with different content, returning how many changed
+    let root = root.as_ref();
+    let mut rng = Lcg(spec.seed.wrapping_add(1));
+    let mut count = 0;
+    for dir in 0..spec.dirs {
+        for file in 0..spec.files_per_dir {
+            if (dir * spec.files_per_dir + file) % every == 0 {
+                try!(write_lines(&root.join(format!("dir{}", dir)).join(format!("file{}.txt", file)), &mut rng, spec));
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::fs;
+    use std::env;
+    use test::Bencher;
+
+    use ignore::*;
+    use platform::*;
+    use timing::*;
+    use {Checkout, Logs, Stage, stage_dir_all, diff_dir_all};
+
+    fn checkout_dir(name: &str, spec: &SynthSpec) -> PathBuf {
+        let root = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        generate_checkout(&root, spec).unwrap();
+        root
+    }
+
+    fn stage_all(root: &Path) {
+        let repo = root.join(".h2");
+        let _ = fs::remove_dir_all(&repo);
+        let mut stage = Stage::new(repo.join("stage"));
+        let mut logs = Logs::new(repo.join("logs"));
+        stage.init().unwrap();
+        logs.init().unwrap();
+        stage_dir_all(&Checkout::new(root), &mut logs, &mut stage, PathBuf::from("."),
+                      &IgnoreRules::new(vec![".h2"])).unwrap();
+    }
+
+    #[test]
+    fn test_generate_checkout() {
+        let spec = SynthSpec {dirs: 2, files_per_dir: 3, ..SynthSpec::default()};
+        let root = env::temp_dir().join("h2-test-synth");
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(generate_checkout(&root, &spec).unwrap(), 6);
+        assert_eq!(mutate_checkout(&root, &spec, 2).unwrap(), 3);
+        assert!(fs::metadata(root.join("dir1/file2.txt")).unwrap().len() > 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[bench]
+    fn bench_stage_pipeline(b: &mut Bencher) {
+        let root = checkout_dir("h2-bench-stage", &SynthSpec::default());
+        reset_phases();
+        let started = monotonic_ns();
+        b.iter(|| stage_all(&root));
+        print_phases(monotonic_ns() - started);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[bench]
+    fn bench_diff_pipeline(b: &mut Bencher) {
+        let spec = SynthSpec::default();
+        let root = checkout_dir("h2-bench-diff", &spec);
+        stage_all(&root);
+        mutate_checkout(&root, &spec, 10).unwrap();
+
+        let checkout = Checkout::new(root.clone());
+        let logs = Logs::new(root.join(".h2/logs")).with_stat_cache(false);
+        let ignore = IgnoreRules::new(vec![".h2"]);
+        reset_phases();
+        let started = monotonic_ns();
+        b.iter(|| diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap());
+        print_phases(monotonic_ns() - started);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::io::Write;
+
+use std::io;
+
+use platform::*;
+
+// stages of the snapshot and diff pipeline that are timed separately.
+// walking the checkout is whatever is left over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    // reading and hashing lines
+    Hash,
+    // building line indexes
+    Insert,
+    // copying files into the stage
+    Copy
+}
+
+pub const PHASES: [Phase; 3] = [Phase::Hash, Phase::Insert, Phase::Copy];
+
+// nanoseconds spent in each phase, across every thread
+static HASH_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+static INSERT_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+static COPY_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn total(phase: Phase) -> &'static AtomicUsize {
+    match phase {
+        Phase::Hash => &HASH_NS,
+        Phase::Insert => &INSERT_NS,
+        Phase::Copy => &COPY_NS
+    }
+}
+
+// adds the time from start until it's dropped to a phase
+pub struct PhaseTimer {
+    phase: Phase,
+    started: u64
+}
+
+impl PhaseTimer {
+    pub fn start(phase: Phase) -> PhaseTimer {
+        PhaseTimer {
+            phase: phase,
+            started: monotonic_ns()
+        }
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let spent = monotonic_ns() - self.started;
+        total(self.phase).fetch_add(spent as usize, Ordering::Relaxed);
+    }
+}
+
+pub fn phase_ns(phase: Phase) -> u64 {
+    total(phase).load(Ordering::Relaxed) as u64
+}
+
+pub fn reset_phases() {
+    for phase in PHASES.iter() {
+        total(*phase).store(0, Ordering::Relaxed);
+    }
+}
+
+pub fn print_phases(elapsed_ns: u64) {
+    let _ = write_phases(&mut io::stdout(), elapsed_ns);
+}
+
+pub fn print_timings(elapsed_ns: u64) {
+    // the phases and the whole command on stderr, kept out of its output
+    let mut stderr = io::stderr();
+    let _ = write_phases(&mut stderr, elapsed_ns);
+    let _ = writeln!(stderr, "{:>8}: {} ms", "total", elapsed_ns / 1000000);
+}
+
+fn write_phases<W: Write>(out: &mut W, elapsed_ns: u64) -> io::Result<()> {
+    // per phase milliseconds, with the rest counted as walking
+    let mut rest = elapsed_ns;
+    for phase in PHASES.iter() {
+        let spent = phase_ns(*phase);
+        rest = rest.saturating_sub(spent);
+        try!(writeln!(out, "{:>8}: {} ms", format!("{:?}", phase).to_lowercase(), spent / 1000000));
+    }
+    writeln!(out, "{:>8}: {} ms", "walk", rest / 1000000)
+}
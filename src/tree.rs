@@ -1,20 +1,205 @@
 use std::borrow::Borrow;
 use std::marker::PhantomData;
+use std::collections::{HashMap, HashSet};
 
+use std::cmp;
+use std::fs;
 use std::io;
 use std::mem;
+use std::ptr;
 use std::slice;
 use std::fmt;
+use std::vec;
+
+use portable::*;
+use instrument::*;
+
+// smallest node size that keeps every node non-empty through splits and
+// removes: a split of a full node leaves at least one item on each side,
+// and a node a remove descends into keeps one after giving one up
+pub const MIN_TREE_WIDTH: usize = 4;
+
+// what a node aims to fill when its width is picked for it. a disk backed
+// tree reads a node per level, so wide nodes mean shallow trees and fewer
+// seeks
+pub const PAGE_SIZE: usize = 4096;
+
+// the biggest a node slot can be. reading a node allocates its whole slot,
+// so this is also the most a damaged header can make one read allocate
+pub const MAX_NODE_BYTES: usize = 1 << 24;
+
+// nodes a bulk insert holds in memory before writing the changed ones back,
+// a few megabytes of page sized nodes
+pub const EXTEND_CACHE_NODES: usize = 1024;
+
+// the most a doubling tree file grows by at once
+pub const GROWTH_MAX_BYTES: u64 = 1 << 26;
 
 pub trait BufItem: Copy + Ord + fmt::Debug {}
 
 // anything that implements copy can simply be addressed directly as a buffer
 impl<T: Copy + Ord + fmt::Debug> BufItem for T {}
 
+// backends that can shrink, which clearing a tree needs to drop old nodes
+pub trait Truncate {
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Truncate for fs::File {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+impl Truncate for io::Cursor<Vec<u8>> {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+// backends that can be made longer ahead of what's written to them, which
+// growing a tree more than a node at a time needs
+pub trait Preallocate {
+    fn preallocate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Preallocate for fs::File {
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        if try!(self.metadata()).len() < len {
+            try!(self.set_len(len));
+        }
+        Ok(())
+    }
+}
+
+impl Preallocate for io::Cursor<Vec<u8>> {
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        let data = self.get_mut();
+        if (data.len() as u64) < len {
+            let more = len as usize - data.len();
+            data.extend(::std::iter::repeat(0).take(more));
+        }
+        Ok(())
+    }
+}
+
+fn preallocate_buffer<T: Preallocate>(buffer: &mut T, len: u64) -> io::Result<()> {
+    buffer.preallocate(len)
+}
+
+// how a tree's buffer is made longer, for the buffers that can be
+struct Grow<T>(fn(&mut T, u64) -> io::Result<()>);
+
+impl<T> fmt::Debug for Grow<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Grow")
+    }
+}
+
+// how a tree's buffer grows once the nodes it has room for are used up. a
+// file that grows a node at a time ends up in pieces all over the disk,
+// one that grows in extents stays in a few
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeGrowth {
+    // a node at a time, as each is written
+    Exact,
+    // room for as many nodes again as the tree has, up to GROWTH_MAX_BYTES
+    Double,
+    // room for this many nodes at a time
+    Chunk(u32)
+}
+
+impl Default for TreeGrowth {
+    fn default() -> TreeGrowth {
+        TreeGrowth::Exact
+    }
+}
+
+impl fmt::Display for TreeGrowth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TreeGrowth::Exact => write!(f, "exact"),
+            TreeGrowth::Double => write!(f, "double"),
+            TreeGrowth::Chunk(nodes) => write!(f, "{}", nodes)
+        }
+    }
+}
+
+impl TreeGrowth {
+    pub fn from_name(name: &str) -> Option<TreeGrowth> {
+        match name {
+            "exact" => Some(TreeGrowth::Exact),
+            "double" => Some(TreeGrowth::Double),
+            _ => match name.parse() {
+                Ok(nodes) if nodes > 0 => Some(TreeGrowth::Chunk(nodes)),
+                _ => None
+            }
+        }
+    }
+
+    fn extent(&self, nodes: u64, node_bytes: u64) -> u64 {
+        // nodes to make room for when a tree of nodes runs out
+        let wanted = match *self {
+            TreeGrowth::Exact => 1,
+            TreeGrowth::Double => cmp::min(nodes, GROWTH_MAX_BYTES / node_bytes),
+            TreeGrowth::Chunk(extent) => extent as u64
+        };
+        cmp::max(wanted, 1)
+    }
+}
+
+fn check_tag(raw: &[u8], offset: usize, what: &str) -> io::Result<()> {
+    // an Option read from a buffer has to hold a tag the compiler could have
+    // written, its first byte. anything else isn't a value at all
+    match raw[offset] {
+        0 | 1 => Ok(()),
+        tag => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} has a bad tag ({})", what, tag)))
+    }
+}
+
+fn field_offset<S, F>(base: &S, field: &F) -> usize {
+    field as *const F as usize - base as *const S as usize
+}
+
+fn read_full<R: io::Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    // read until the buffer is full or the reader runs dry
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+pub fn node_bytes<V: BufItem>(width: usize) -> usize {
+    // a node is its head, its items and one more child than items
+    mem::size_of::<BufNodeHead>() + mem::size_of::<V>() * width + ::std::u64::BYTES as usize * (width + 1)
+}
+
+pub fn page_width<V: BufItem>() -> usize {
+    // the widest node that still fits in a page
+    let fixed = mem::size_of::<BufNodeHead>() + ::std::u64::BYTES as usize;
+    let per_item = mem::size_of::<V>() + ::std::u64::BYTES as usize;
+    cmp::max(MIN_TREE_WIDTH, PAGE_SIZE.saturating_sub(fixed) / per_item)
+}
+
 #[derive(Debug)]
 pub struct BufTree<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> {
     head: BufTreeHead,
     buffer: T,
+    // nodes held through a bulk insert, none the rest of the time
+    cache: Option<NodeCache<V>>,
+    // whether node reads are bounded by the buffer's real length, see
+    // open_checked
+    checked: bool,
+    growth: TreeGrowth,
+    // none for buffers that only grow as they're written
+    grow: Option<Grow<T>>,
     phantom: PhantomData<V>
 }
 
@@ -28,13 +213,30 @@ struct BufNodeHead {
     leaf: u8
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BufNode<T: BufItem> {
     head: BufNodeHead,
     items: Vec<T>,
     next: Vec<u64>
 }
 
+// every node read or written since the cache was started, and which of
+// them have to be written back
+#[derive(Debug)]
+struct NodeCache<T: BufItem> {
+    nodes: HashMap<u64, BufNode<T>>,
+    dirty: HashSet<u64>
+}
+
+impl<T: BufItem> NodeCache<T> {
+    fn new() -> NodeCache<T> {
+        NodeCache {
+            nodes: HashMap::new(),
+            dirty: HashSet::new()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct BufGone {
     // index of this node
@@ -52,7 +254,100 @@ struct BufTreeHead {
     // index of the root node
     root: Option<u64>,
     // index of the last deleted node
-    gone: Option<u64>
+    gone: Option<u64>,
+    // whether equal items can coexist in the tree
+    multi: u8,
+    // nodes' worth of room past last that the buffer was grown by ahead of
+    // time. it sits in what was multi's padding, so a tree from before it
+    // may hold anything here. it's only ever a hint: a node written past
+    // the buffer's end grows it anyway
+    reserved: u32,
+    // number of items in the tree
+    len: u64
+}
+
+// the header as written up to format version 11, before it held the item count
+#[derive(Debug, Clone, Copy)]
+struct BufTreeHeadV11 {
+    size: usize,
+    last: u64,
+    root: Option<u64>,
+    gone: Option<u64>,
+    multi: u8
+}
+
+// items from a point in a tree onwards, see BufTree::iter_from
+pub struct ItemsFrom<'a, T: io::Read + io::Write + io::Seek + fmt::Debug + 'a, V: BufItem> {
+    tree: &'a mut BufTree<T, V>,
+    // the last item returned, or where to start before the first
+    from: Option<V>,
+    started: bool
+}
+
+impl<'a, T: io::Read + io::Write + io::Seek + fmt::Debug + 'a, V: BufItem> Iterator for ItemsFrom<'a, T, V> {
+    type Item = io::Result<V>;
+
+    fn next(&mut self) -> Option<io::Result<V>> {
+        let from = match self.from {
+            Some(from) => from,
+            None => {
+                return None;
+            }
+        };
+        let found = if self.started {
+            self.tree.get_next(from)
+        } else {
+            self.started = true;
+            self.tree.get_ceiling(from)
+        };
+        match found {
+            Err(e) => {
+                self.from = None;
+                Some(Err(e))
+            },
+            Ok(item) => {
+                self.from = item;
+                item.map(Ok)
+            }
+        }
+    }
+}
+
+// one live node as BufTree::dump_nodes finds it, for looking at the shape
+// of a tree from outside
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeDump<V> {
+    pub idx: u64,
+    // levels below the root
+    pub depth: usize,
+    pub leaf: bool,
+    pub items: Vec<V>,
+    // indexes of the children, empty for a leaf
+    pub next: Vec<u64>
+}
+
+// the shape of a tree in numbers, from BufTree::stats
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TreeStats {
+    pub width: usize,
+    // levels from the root to the leaves
+    pub depth: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    pub items: usize,
+    // deleted nodes waiting to be reused
+    pub free_nodes: usize
+}
+
+impl TreeStats {
+    pub fn fill_factor(&self) -> f64 {
+        // how full the live nodes are, zero for an empty tree
+        if self.nodes == 0 {
+            0.0
+        } else {
+            self.items as f64 / (self.nodes * self.width) as f64
+        }
+    }
 }
 
 impl<V: BufItem> Default for BufTree<io::Cursor<Vec<u8>>, V> {
@@ -69,14 +364,52 @@ impl<V: BufItem> Default for BufTree<io::Cursor<Vec<u8>>, V> {
 
 impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V> {
     pub fn new(buffer: T, size: usize) -> io::Result<BufTree<T, V>> {
+        Self::create(buffer, size, false)
+    }
+
+    pub fn new_multi(buffer: T, size: usize) -> io::Result<BufTree<T, V>> {
+        // a tree where inserting an equal item adds it instead of replacing
+        Self::create(buffer, size, true)
+    }
+
+    fn check_layout(size: usize) -> io::Result<()> {
+        if mem::size_of::<V>() == 0 {
+            // items are addressed by their size, so there'd be nothing to store
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Tree items can't be zero-sized"));
+        }
+        if size < MIN_TREE_WIDTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Tree node size ({}) is below the minimum of {}",
+                                              size, MIN_TREE_WIDTH)));
+        }
+        // worked out without node_bytes, which a big enough size overflows
+        let fixed = mem::size_of::<BufNodeHead>() + ::std::u64::BYTES as usize;
+        let per_item = mem::size_of::<V>() + ::std::u64::BYTES as usize;
+        if size > (MAX_NODE_BYTES - fixed) / per_item {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Tree node size ({}) is above the maximum of {}",
+                                              size, (MAX_NODE_BYTES - fixed) / per_item)));
+        }
+        Ok(())
+    }
+
+    fn create(buffer: T, size: usize, multi: bool) -> io::Result<BufTree<T, V>> {
+        try!(Self::check_layout(size));
         let mut tree = BufTree {
             head: BufTreeHead {
                 size: size,
                 last: mem::size_of::<BufTreeHead>() as u64,
                 root: None,
-                gone: None
+                gone: None,
+                multi: multi as u8,
+                reserved: 0,
+                len: 0
             },
             buffer: buffer,
+            cache: None,
+            checked: false,
+            growth: TreeGrowth::default(),
+            grow: None,
             phantom: PhantomData
         };
         // write meta info since it's a new tree
@@ -87,14 +420,101 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
 
     pub unsafe fn from_buffer(mut buffer: T) -> io::Result<BufTree<T, V>> {
         // unsafe because there's no guarentee that this buffer is correctly formed
+        let head = try!(Self::read_meta(&mut buffer));
+        match Self::check_layout(head.size) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad tree header: {}", e)));
+            },
+            Ok(()) => {}
+        }
         Ok(BufTree {
-            head: try!(Self::read_meta(&mut buffer)),
+            head: head,
             buffer: buffer,
+            cache: None,
+            checked: false,
+            growth: TreeGrowth::default(),
+            grow: None,
             phantom: PhantomData
         })
     }
 
+    pub unsafe fn open_checked(buffer: T) -> io::Result<BufTree<T, V>> {
+        // from_buffer for trees that could be damaged or made to break us.
+        // nodes are read against the buffer's real length, and the whole
+        // tree is walked once so nothing after can loop or run off the end.
+        // unsafe only because items are copied in byte for byte, so any
+        // bytes have to make a valid V
+        let mut tree = try!(Self::from_buffer(buffer));
+        tree.checked = true;
+        try!(tree.verify());
+        Ok(tree)
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> where T: Truncate {
+        // empty the tree, keeping its node size and mode
+        self.head.last = mem::size_of::<BufTreeHead>() as u64;
+        self.head.root = None;
+        self.head.gone = None;
+        self.head.reserved = 0;
+        self.head.len = 0;
+        try!(self.buffer.truncate(self.head.last));
+        self.write_meta()
+    }
+
+    pub fn set_growth(&mut self, growth: TreeGrowth) where T: Preallocate {
+        // grow the buffer by more than a node at a time from now on
+        self.growth = growth;
+        self.grow = Some(Grow(preallocate_buffer::<T>));
+    }
+
+    pub fn growth(&self) -> TreeGrowth {
+        self.growth
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        // push anything the buffer holds back out to its backend
+        self.buffer.flush()
+    }
+
+    pub fn trim(&mut self) -> io::Result<()> where T: Truncate {
+        // give back the room grown ahead of time, for a tree that's done
+        // growing
+        if self.head.reserved == 0 {
+            return Ok(());
+        }
+        debug!("Trimming {} reserved nodes", self.head.reserved);
+        self.head.reserved = 0;
+        try!(self.buffer.truncate(self.head.last));
+        self.write_meta()
+    }
+
+    pub fn into_inner(self) -> T {
+        // the buffer, for moving a finished tree somewhere else
+        self.buffer
+    }
+
+    pub fn is_multi(&self) -> bool {
+        self.head.multi != 0
+    }
+
+    pub fn size(&self) -> usize {
+        self.head.size
+    }
+
+    pub fn len(&self) -> usize {
+        // kept in the header, so this doesn't touch any nodes
+        self.head.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.len == 0
+    }
+
     fn write_meta(&mut self) -> io::Result<()> {
+        if self.cache.is_some() {
+            // a bulk insert writes the header once it's done
+            return Ok(());
+        }
         // seek to the start of the file
         try!(self.buffer.seek(io::SeekFrom::Start(0)));
         // create the slice we care about
@@ -108,46 +528,43 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
         // unsafe because data could be garbage
         // seek to the start of the file
         try!(buffer.seek(io::SeekFrom::Start(0)));
-        // create our buffer
-        let mut head: BufTreeHead = mem::uninitialized();
-        let head_buf = slice::from_raw_parts_mut(&mut head as *mut _ as *mut _,
-                                                 mem::size_of::<BufTreeHead>());
-        // read into it
-        try!(buffer.read(head_buf));
-        // forget our buffer
-        mem::forget(head_buf);
-        // return it
+        // read the whole header, a short one is as good as none
+        let mut raw = vec![0u8; mem::size_of::<BufTreeHead>()];
+        if try!(read_full(buffer, &mut raw)) < raw.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Tree header is cut short"));
+        }
+        let mut head: BufTreeHead = mem::zeroed();
+        try!(check_tag(&raw, field_offset(&head, &head.root), "Tree root index"));
+        try!(check_tag(&raw, field_offset(&head, &head.gone), "Tree deleted node index"));
+        ptr::copy_nonoverlapping(raw.as_ptr(), &mut head as *mut _ as *mut u8, raw.len());
         Ok(head)
     }
 
     fn write_node(&mut self, node: &BufNode<V>) -> io::Result<()> {
-        // write a node
+        if let Some(ref mut cache) = self.cache {
+            cache.nodes.insert(node.head.idx, node.clone());
+            cache.dirty.insert(node.head.idx);
+            return Ok(());
+        }
+        // lay the node out first so it goes down in one write
+        let buffer = Self::encode_node(node);
+        count(Counter::TreeWrites, 1);
         try!(self.buffer.seek(io::SeekFrom::Start(node.head.idx)));
-        // create the slice we care about
-        let head_buf = unsafe {
-            slice::from_raw_parts(&node.head as *const _ as *const _,
-                                  mem::size_of::<BufNodeHead>())
-        };
-        // write that
-        try!(self.buffer.write_all(head_buf));
-        // create a buffer for the items
-        let items_buf = unsafe {slice::from_raw_parts(node.items.as_ptr() as *const _,
-                                                      node.items.len() * mem::size_of::<V>())};
-        // write that
-        try!(self.buffer.write_all(items_buf.as_ref()));
-        mem::forget(items_buf);
-        
-        if node.next.len() > 0 {
-            // create the right kind of slice
-            let next_buf = unsafe {slice::from_raw_parts(node.next.as_ptr() as *const _,
-                                                         node.next.len() * ::std::u64::BYTES)};
-            // write that
-            try!(self.buffer.write_all(next_buf));
-            mem::forget(next_buf);
-            Ok(())
-        } else {
-            Ok(())
+        self.buffer.write_all(&buffer)
+    }
+
+    fn encode_node(node: &BufNode<V>) -> Vec<u8> {
+        // the head, then the items, then the children straight after the items
+        let mut buffer = Vec::with_capacity(node_bytes::<V>(node.items.len()));
+        unsafe {
+            buffer.extend(slice::from_raw_parts(&node.head as *const _ as *const u8,
+                                                mem::size_of::<BufNodeHead>()).iter().cloned());
+            buffer.extend(slice::from_raw_parts(node.items.as_ptr() as *const u8,
+                                                node.items.len() * mem::size_of::<V>()).iter().cloned());
+            buffer.extend(slice::from_raw_parts(node.next.as_ptr() as *const u8,
+                                                node.next.len() * ::std::u64::BYTES as usize).iter().cloned());
         }
+        buffer
     }
 
     pub unsafe fn items_at_idx(&mut self, idx: u64) -> io::Result<Vec<V>> {
@@ -159,20 +576,46 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
     }
 
     unsafe fn read_node(&mut self, idx: u64) -> io::Result<BufNode<V>> {
+        if let Some(ref cache) = self.cache {
+            if let Some(node) = cache.nodes.get(&idx) {
+                return Ok(node.clone());
+            }
+        }
+        let node = try!(self.read_node_uncached(idx));
+        if let Some(ref mut cache) = self.cache {
+            cache.nodes.insert(idx, node.clone());
+        }
+        Ok(node)
+    }
+
+    unsafe fn read_node_uncached(&mut self, idx: u64) -> io::Result<BufNode<V>> {
         // unsafe because the data could be garbage
-        // seek to the given position
+        // the whole node slot comes in with one read, then gets picked apart.
+        // a node doesn't always fill its slot, so the read can come up short
+        // at the end of the buffer
+        let mut slot = node_bytes::<V>(self.head.size);
+        if self.checked {
+            // only what's really there is read, whatever the header says
+            let end = try!(self.buffer.seek(io::SeekFrom::End(0)));
+            if idx < mem::size_of::<BufTreeHead>() as u64 || idx >= end {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Node index ({}) is outside of the buffer ({} bytes)", idx, end)));
+            }
+            slot = cmp::min(slot as u64, end - idx) as usize;
+        }
+        let mut buffer = vec![0u8; slot];
         try!(self.buffer.seek(io::SeekFrom::Start(idx)));
-        // read the node
-        // create a header object
+        let filled = try!(read_full(&mut self.buffer, &mut buffer));
+        count(Counter::TreeReads, 1);
+
+        let head_size = mem::size_of::<BufNodeHead>();
+        if filled < head_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Node at {} is past the end of the tree", idx)));
+        }
         let mut head: BufNodeHead = mem::uninitialized();
-        // create a slice that points to it
-        let head_buf = slice::from_raw_parts_mut(&mut head as *mut _ as *mut _,
-                                                 mem::size_of::<BufNodeHead>());
-        // read into that slice
-        try!(self.buffer.read(head_buf));
-        // forget that slice
-        mem::forget(head_buf);
-        
+        ptr::copy_nonoverlapping(buffer.as_ptr(), &mut head as *mut _ as *mut u8, head_size);
+
         // check head idx
         if head.idx != idx {
             return Err(io::Error::new(io::ErrorKind::InvalidData,
@@ -187,38 +630,23 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
                                               head.len, self.head.size)));
         }
 
-        let vec_len = {
-            if head.leaf == 0 {
-                // no further reads
-                head.len
-            } else {
-                // will read next list, read full node size to
-                // position cursor correctly
-                self.head.size
-            }
-        } * mem::size_of::<V>();
-        let mut items_buf = Vec::with_capacity(vec_len);
-        items_buf.set_len(vec_len);
-        try!(self.buffer.read(items_buf.as_mut()));
-        let items = Vec::from_raw_parts(items_buf.as_mut_ptr() as *mut _,
-                                        head.len,
-                                        items_buf.capacity() / mem::size_of::<V>());
-        mem::forget(items_buf);
-        let mut next;
-        if head.leaf == 0 {
-            // create our buffer
-            next = Vec::with_capacity((head.len + 1));
-            next.set_len(head.len + 1);
-            // create a slice that points to it
-            let next_buf = slice::from_raw_parts_mut(next.as_ptr() as *mut _,
-                                                     (head.len + 1) * ::std::u64::BYTES);
-            // read into the slice
-            try!(self.buffer.read(next_buf));
-            // forget the slice
-            mem::forget(next_buf);
-        } else {
-            next = vec![];
+        // children are only stored for inner nodes
+        let items_size = head.len * mem::size_of::<V>();
+        let next_len = if head.leaf == 0 {head.len + 1} else {0};
+        let end = head_size + items_size + next_len * ::std::u64::BYTES as usize;
+        if filled < end {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Node at {} is cut short ({} of {} bytes)", idx, filled, end)));
         }
+
+        // room to grow to a full node, unless the tree might not be ours
+        let mut items: Vec<V> = Vec::with_capacity(if self.checked {head.len} else {self.head.size});
+        items.set_len(head.len);
+        ptr::copy_nonoverlapping(buffer[head_size..].as_ptr(), items.as_mut_ptr() as *mut u8, items_size);
+        let mut next: Vec<u64> = Vec::with_capacity(next_len);
+        next.set_len(next_len);
+        ptr::copy_nonoverlapping(buffer[head_size + items_size..].as_ptr(), next.as_mut_ptr() as *mut u8,
+                                 next_len * ::std::u64::BYTES as usize);
         Ok(BufNode {
             head: head,
             items: items,
@@ -230,22 +658,24 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
         // unsafe because the data could be garbage
         // seek to the given position
         try!(self.buffer.seek(io::SeekFrom::Start(idx)));
-        // create a buffer
-        let mut gone_buf = Vec::with_capacity(mem::size_of::<BufGone>());
-        // read into it
-        try!(self.buffer.read(gone_buf.as_mut()));
-        // transmute into our desired type
-        let gone_ptr = gone_buf.as_ptr() as *const BufGone;
-        // return it
-        Ok(*gone_ptr.as_ref().unwrap())
+        // read into a buffer, a deleted node always fills its part of one
+        let mut raw = vec![0u8; mem::size_of::<BufGone>()];
+        if try!(read_full(&mut self.buffer, &mut raw)) < raw.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Deleted node at {} is cut short", idx)));
+        }
+        let mut gone: BufGone = mem::zeroed();
+        try!(check_tag(&raw, field_offset(&gone, &gone.next), "Deleted node next index"));
+        ptr::copy_nonoverlapping(raw.as_ptr(), &mut gone as *mut _ as *mut u8, raw.len());
+        Ok(gone)
     }
 
     fn delete_node(&mut self, idx: u64) -> io::Result<()> {
-        if idx == self.head.last - (mem::size_of::<BufNodeHead>() as u64
-                                    + mem::size_of::<V>() as u64 *
-                                    (self.head.size * 2 + 1) as u64) {
-            // instead of writing a gone, just decrement last
+        if idx == self.head.last - node_bytes::<V>(self.head.size) as u64 {
+            // instead of writing a gone, just decrement last. the buffer
+            // still has the room
             self.head.last = idx;
+            self.head.reserved = self.head.reserved.saturating_add(1);
         } else {
             // seek to the given index
             try!(self.buffer.seek(io::SeekFrom::Start(idx)));
@@ -271,10 +701,13 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
         // counters as necessary
         match self.head.gone {
             None => {
+                if self.head.reserved > 0 {
+                    self.head.reserved -= 1;
+                } else {
+                    try!(self.reserve());
+                }
                 let idx = self.head.last;
-                self.head.last += mem::size_of::<BufNodeHead>() as u64 +
-                    mem::size_of::<V>() as u64 * (self.head.size) as u64 +
-                    ::std::u64::BYTES as u64 * (self.head.size + 1) as u64;
+                self.head.last += node_bytes::<V>(self.head.size) as u64;
                 Ok(idx)
             },
             Some(idx) => {
@@ -285,6 +718,337 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
         }
     }
 
+    fn reserve(&mut self) -> io::Result<()> {
+        // grow the buffer past last by an extent of nodes, the first of
+        // which is about to be used
+        let grow = match self.grow {
+            Some(ref grow) => grow.0,
+            None => {
+                return Ok(());
+            }
+        };
+        let bytes = node_bytes::<V>(self.head.size) as u64;
+        let nodes = (self.head.last - mem::size_of::<BufTreeHead>() as u64) / bytes;
+        let extent = cmp::min(self.growth.extent(nodes, bytes), ::std::u32::MAX as u64);
+        // even a single node is grown to its whole slot. nodes are written
+        // only as long as their items, so otherwise the buffer could end
+        // short of last
+        trace!("Growing tree by {} nodes", extent);
+        try!(grow(&mut self.buffer, self.head.last + extent * bytes));
+        self.head.reserved = (extent - 1) as u32;
+        Ok(())
+    }
+
+    pub fn node_count(&mut self) -> io::Result<usize> {
+        // live nodes reachable from the root
+        let mut count = 0;
+        let mut to_visit: Vec<u64> = self.head.root.into_iter().collect();
+        while let Some(idx) = to_visit.pop() {
+            let node = try!(unsafe {self.read_node(idx)});
+            count += 1;
+            to_visit.extend(node.next.iter().cloned());
+        }
+        Ok(count)
+    }
+
+    pub fn depth(&mut self) -> io::Result<usize> {
+        // levels from the root to the leaves, every leaf is at the same depth
+        let mut depth = 0;
+        let mut current = self.head.root;
+        while let Some(idx) = current {
+            let node = try!(unsafe {self.read_node(idx)});
+            depth += 1;
+            current = node.next.first().cloned();
+        }
+        Ok(depth)
+    }
+
+    pub fn free_nodes(&mut self) -> io::Result<usize> {
+        // deleted nodes waiting to be reused
+        self.free_list().map(|list| list.len())
+    }
+
+    fn free_list(&mut self) -> io::Result<Vec<u64>> {
+        // the deleted nodes in the order they'll be reused
+        let mut list = vec![];
+        let mut gone = self.head.gone;
+        while let Some(idx) = gone {
+            list.push(idx);
+            gone = try!(unsafe {self.read_gone(idx)}).next;
+        }
+        Ok(list)
+    }
+
+    pub fn dump_nodes(&mut self) -> io::Result<Vec<NodeDump<V>>> {
+        // every live node, each before its children and children left to
+        // right, so the items of each level come out in order
+        let mut nodes = vec![];
+        let mut to_visit: Vec<(u64, usize)> = self.head.root.into_iter().map(|idx| (idx, 0)).collect();
+        while let Some((idx, depth)) = to_visit.pop() {
+            let node = try!(unsafe {self.read_node(idx)});
+            to_visit.extend(node.next.iter().rev().map(|&next| (next, depth + 1)));
+            nodes.push(NodeDump {
+                idx: idx,
+                depth: depth,
+                leaf: node.head.leaf != 0,
+                items: node.items,
+                next: node.next
+            });
+        }
+        Ok(nodes)
+    }
+
+    pub fn stats(&mut self) -> io::Result<TreeStats> {
+        let nodes = try!(self.dump_nodes());
+        Ok(TreeStats {
+            width: self.head.size,
+            depth: nodes.iter().map(|node| node.depth + 1).max().unwrap_or(0),
+            nodes: nodes.len(),
+            leaves: nodes.iter().filter(|node| node.leaf).count(),
+            items: nodes.iter().fold(0, |sum, node| sum + node.items.len()),
+            free_nodes: try!(self.free_nodes())
+        })
+    }
+
+    pub fn dump<W: io::Write>(&mut self, out: &mut W) -> io::Result<()> {
+        // the whole tree for reading while chasing a balance bug: a summary,
+        // then every node indented by depth with its first and last item,
+        // then the free list. a tree that's damaged is dumped as far as it
+        // can be read
+        let stats = try!(self.stats());
+        try!(write!(out, "tree: width {}, {} items, depth {}, {} nodes ({} leaves), {} free, {:.2} full\n",
+                    stats.width, self.head.len, stats.depth, stats.nodes, stats.leaves, stats.free_nodes,
+                    stats.fill_factor()));
+        for node in try!(self.dump_nodes()) {
+            let indent: String = (0..node.depth).map(|_| "  ").collect();
+            let range = match (node.items.first(), node.items.last()) {
+                (Some(first), Some(last)) => format!("{:?}..{:?}", first, last),
+                _ => "empty".to_string()
+            };
+            if node.leaf {
+                try!(write!(out, "{}node {} leaf, {} items, {}\n", indent, node.idx, node.items.len(), range));
+            } else {
+                let next: Vec<String> = node.next.iter().map(|idx| idx.to_string()).collect();
+                try!(write!(out, "{}node {} -> {}, {} items, {}\n", indent, node.idx, next.join(" "),
+                            node.items.len(), range));
+            }
+        }
+        let free: Vec<String> = try!(self.free_list()).iter().map(|idx| idx.to_string()).collect();
+        if !free.is_empty() {
+            try!(write!(out, "free: {}\n", free.join(" ")));
+        }
+        Ok(())
+    }
+
+    pub fn verify(&mut self) -> io::Result<usize> {
+        self.verify_each(|_| {})
+    }
+
+    pub fn verify_each<F: FnMut(&V)>(&mut self, each: F) -> io::Result<usize> {
+        // walk the whole tree checking every invariant we rely on, returning
+        // the number of items found
+        let count = try!(self.walk_checked(each));
+        if count as u64 != self.head.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Tree header says it holds {} items, but {} were found",
+                                              self.head.len, count)));
+        }
+        Ok(count)
+    }
+
+    fn walk_checked<F: FnMut(&V)>(&mut self, mut each: F) -> io::Result<usize> {
+        // every check but the header's item count, which trees from before
+        // it was kept don't have
+        let head_size = mem::size_of::<BufTreeHead>() as u64;
+        if self.head.size < MIN_TREE_WIDTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Tree node size ({}) is too small", self.head.size)));
+        }
+        if self.head.last < head_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Tree last index ({}) is inside the header", self.head.last)));
+        }
+
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        let mut leaf_depth = None;
+
+        if let Some(root_idx) = self.head.root {
+            // nodes to visit, along with the bounds their items must fall between
+            let mut to_visit: Vec<(u64, Option<V>, Option<V>, usize)> = vec![(root_idx, None, None, 0)];
+            while let Some((idx, lower, upper, depth)) = to_visit.pop() {
+                if idx < head_size || idx >= self.head.last {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Node index ({}) is outside of the tree", idx)));
+                }
+                if !seen.insert(idx) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Node ({}) is referenced more than once", idx)));
+                }
+
+                // read_node checks the node idx and length for us
+                let node = try!(unsafe {self.read_node(idx)});
+
+                if node.head.len != node.items.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Node ({}) length ({}) does not match its items ({})",
+                                                      idx, node.head.len, node.items.len())));
+                }
+                if node.items.is_empty() && depth > 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Non-root node ({}) is empty", idx)));
+                }
+
+                // items must be increasing and within the parent's bounds,
+                // strictly so unless equal items are allowed
+                let multi = self.is_multi();
+                let out_of_order = |a: &V, b: &V| if multi {a > b} else {a >= b};
+                for i in 0..node.items.len() {
+                    let item = &node.items[i];
+                    if (i > 0 && out_of_order(&node.items[i - 1], item)) ||
+                        lower.map_or(false, |l| out_of_order(&l, item)) ||
+                        upper.map_or(false, |u| out_of_order(item, &u)) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Node ({}) item {:?} is out of order", idx, item)));
+                    }
+                    each(item);
+                }
+                count += node.items.len();
+
+                if node.head.leaf != 0 {
+                    // every leaf has to be at the same depth
+                    match leaf_depth {
+                        None => {
+                            leaf_depth = Some(depth);
+                        },
+                        Some(d) if d != depth => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("Leaf ({}) is at depth {}, expected {}",
+                                                              idx, depth, d)));
+                        },
+                        _ => {}
+                    }
+                } else {
+                    if node.next.len() != node.items.len() + 1 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Node ({}) has {} next pointers for {} items",
+                                                          idx, node.next.len(), node.items.len())));
+                    }
+                    for i in 0..node.next.len() {
+                        let lo = if i == 0 {lower} else {Some(node.items[i - 1])};
+                        let hi = if i == node.items.len() {upper} else {Some(node.items[i])};
+                        to_visit.push((node.next[i], lo, hi, depth + 1));
+                    }
+                }
+            }
+        }
+
+        // walk the free list, making sure it doesn't overlap live nodes or loop
+        let mut gone_seen = HashSet::new();
+        let mut gone = self.head.gone;
+        while let Some(idx) = gone {
+            if idx < head_size || idx >= self.head.last {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Deleted node index ({}) is outside of the tree", idx)));
+            }
+            if seen.contains(&idx) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Deleted node ({}) is still referenced by the tree", idx)));
+            }
+            if !gone_seen.insert(idx) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Deleted node list loops at ({})", idx)));
+            }
+            let item = try!(unsafe {self.read_gone(idx)});
+            if item.idx != idx {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Deleted node header idx ({}) did not match given idx ({})",
+                                                  item.idx, idx)));
+            }
+            gone = item.next;
+        }
+
+        Ok(count)
+    }
+
+    pub fn export<W: io::Write>(&mut self, out: &mut W) -> io::Result<usize> where V: Portable {
+        // write the items of the tree in a machine-independent form, our
+        // buffer layout depends on endianness and pointer size so it can't be
+        // copied between machines as-is
+        let mut items = vec![];
+        try!(self.verify_each(|item| items.push(*item)));
+        try!(write_u64(out, self.head.size as u64));
+        try!(write_u64(out, self.head.multi as u64));
+        try!(write_u64(out, items.len() as u64));
+        for item in items.iter() {
+            try!(item.write_portable(out));
+        }
+        Ok(items.len())
+    }
+
+    pub unsafe fn upgrade_v11<U>(mut buffer: U, into: T) -> io::Result<BufTree<T, V>>
+        where U: io::Read + io::Write + io::Seek + fmt::Debug {
+        // rebuild a tree written before headers held the item count.
+        // nodes are addressed by their offset in the buffer, which the
+        // bigger header moves, so the items are copied into a new tree.
+        // unsafe for the same reason from_buffer is
+        try!(buffer.seek(io::SeekFrom::Start(0)));
+        let mut raw = vec![0u8; mem::size_of::<BufTreeHeadV11>()];
+        if try!(read_full(&mut buffer, &mut raw)) < raw.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Tree header is cut short"));
+        }
+        let mut old: BufTreeHeadV11 = mem::zeroed();
+        try!(check_tag(&raw, field_offset(&old, &old.root), "Tree root index"));
+        try!(check_tag(&raw, field_offset(&old, &old.gone), "Tree deleted node index"));
+        ptr::copy_nonoverlapping(raw.as_ptr(), &mut old as *mut _ as *mut u8, raw.len());
+        match Self::check_layout(old.size) {
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad tree header: {}", e)));
+            },
+            Ok(()) => {}
+        }
+        let mut tree: BufTree<U, V> = BufTree {
+            head: BufTreeHead {
+                size: old.size,
+                last: old.last,
+                root: old.root,
+                gone: old.gone,
+                multi: old.multi,
+                reserved: 0,
+                len: 0
+            },
+            buffer: buffer,
+            cache: None,
+            checked: false,
+            growth: TreeGrowth::default(),
+            grow: None,
+            phantom: PhantomData
+        };
+        let mut items = vec![];
+        try!(tree.walk_checked(|item| items.push(*item)));
+
+        let mut upgraded = try!(Self::create(into, old.size, old.multi != 0));
+        try!(upgraded.extend(items));
+        Ok(upgraded)
+    }
+
+    pub fn import<R: io::Read>(buffer: T, input: &mut R) -> io::Result<BufTree<T, V>> where V: Portable {
+        // rebuild a tree from exported items in a fresh buffer
+        let size = try!(read_u64(input)) as usize;
+        let multi = try!(read_u64(input)) != 0;
+        let count = try!(read_u64(input));
+        if size < MIN_TREE_WIDTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Exported tree node size ({}) is too small", size)));
+        }
+        let mut tree = try!(Self::create(buffer, size, multi));
+        for _ in 0..count {
+            let item = try!(V::read_portable(input));
+            try!(tree.insert(item));
+        }
+        Ok(tree)
+    }
+
     pub fn contains<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<bool> {
         match self.get(as_item) {
             Err(e) => Err(e),
@@ -333,234 +1097,345 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
                 }
             };
 
-            current = try!(unsafe {self.read_node(current.next[next_index])});
+            current = try!(unsafe {self.read_node(current.next[next_index])});
+        }
+    }
+
+    pub fn get_all<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<vec::IntoIter<V>> {
+        // find every item equal to the given one, mostly useful for multi trees
+        let mut found = vec![];
+        if let Some(root_idx) = self.head.root {
+            try!(self.get_all_from(root_idx, as_item.borrow(), &mut found));
+        }
+        Ok(found.into_iter())
+    }
+
+    fn get_all_from(&mut self, idx: u64, item: &V, found: &mut Vec<V>) -> io::Result<()> {
+        let node = try!(unsafe {self.read_node(idx)});
+
+        // the range of items equal to ours
+        let mut lower = 0;
+        while lower < node.items.len() && node.items[lower] < *item {
+            lower += 1;
+        }
+        let mut upper = lower;
+        while upper < node.items.len() && node.items[upper] == *item {
+            upper += 1;
+        }
+
+        // equal items can also be in any of the children around that range
+        for i in lower..upper + 1 {
+            if node.head.leaf == 0 {
+                try!(self.get_all_from(node.next[i], item, found));
+            }
+            if i < upper {
+                found.push(node.items[i]);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_ceiling<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
+        // the smallest item at or above the given one
+        self.get_bound(as_item.borrow(), true, true)
+    }
+
+    pub fn get_floor<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
+        // the largest item at or below the given one
+        self.get_bound(as_item.borrow(), false, true)
+    }
+
+    pub fn get_next<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
+        // the smallest item strictly above the given one
+        self.get_bound(as_item.borrow(), true, false)
+    }
+
+    pub fn get_prev<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
+        // the largest item strictly below the given one
+        self.get_bound(as_item.borrow(), false, false)
+    }
+
+    pub fn iter_from<K: Borrow<V>>(&mut self, as_item: K) -> ItemsFrom<T, V> {
+        // every distinct item at or above the given one, in order. each step
+        // is a descent of its own, so the tree can be changed between them
+        ItemsFrom {
+            tree: self,
+            from: Some(*as_item.borrow()),
+            started: false
         }
     }
 
-    pub fn remove<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
-        // check for a root node
-        let root_idx = match self.head.root {
+    fn get_bound(&mut self, item: &V, above: bool, inclusive: bool) -> io::Result<Option<V>> {
+        // one descent, keeping the closest item on the wanted side of the
+        // given one. the child between two items holds everything between
+        // them, so the closest item is either in a node on the way down or
+        // the last one kept
+        let mut idx = match self.head.root {
             None => {
                 return Ok(None);
             },
             Some(idx) => idx
         };
+        let mut best = None;
+        loop {
+            let node = try!(unsafe {self.read_node(idx)});
+            // how many items in this node come before the wanted side
+            let split = node.items.iter().take_while(|other| {
+                if above == inclusive {*other < item} else {*other <= item}
+            }).count();
+            if above && split < node.items.len() {
+                best = Some(node.items[split]);
+            } else if !above && split > 0 {
+                best = Some(node.items[split - 1]);
+            }
+            if node.head.leaf != 0 {
+                return Ok(best);
+            }
+            idx = node.next[split];
+        }
+    }
 
-        // read the root node
-        let mut current = try!(unsafe {self.read_node(root_idx)});
-        // ensure there's at least one item in the root node
-        if current.items.is_empty() {
-            return Ok(None);
+    pub fn remove<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
+        match self.remove_item(as_item) {
+            Ok(Some(item)) => {
+                self.head.len -= 1;
+                try!(self.write_meta());
+                Ok(Some(item))
+            },
+            result => result
         }
+    }
 
-        let item = as_item.borrow();
-        let mut item_node = None;
-        let mut item_current = false;
-        let mut item_index = None;
-        let mut item_push = false;
+    fn remove_item<K: Borrow<V>>(&mut self, as_item: K) -> io::Result<Option<V>> {
+        // one pass down from the root. every node stepped into is first
+        // given more than the fewest items a node may hold, by borrowing
+        // from a sibling or merging with one, so taking an item out of it
+        // never leaves it short
+        let root_idx = match self.head.root {
+            None => {
+                return Ok(None);
+            },
+            Some(idx) => idx
+        };
 
-        // loop until we find the item or we hit a leaf
-        while current.head.leaf == 0 {
-            let next_index = {
-                if item_node.is_some() {
-                    current.head.len
-                } else {
-                    match current.items.binary_search(item) {
-                        Ok(idx) => {
-                            item_current = true;
-                            item_index = Some(idx);
-                            idx
-                        },
-                        Err(idx) => {
-                            idx
-                        }
+        let item = as_item.borrow();
+        let mut current = try!(unsafe {self.read_node(root_idx)});
+        loop {
+            let found = current.items.binary_search(item);
+            if current.head.leaf != 0 {
+                return match found {
+                    Ok(idx) => {
+                        let node_item = current.items.remove(idx);
+                        current.head.len -= 1;
+                        try!(self.write_node(&current));
+                        Ok(Some(node_item))
+                    },
+                    Err(_) => {
+                        // item not in tree
+                        Ok(None)
                     }
+                };
+            }
+
+            let idx = match found {
+                Ok(idx) => idx,
+                Err(idx) => {
+                    current = try!(self.fill_child(current, idx));
+                    continue;
                 }
             };
-            let next_idx = current.next[next_index];
-            // this means the next after root node is read twice, oh well.
-            let mut next = try!(unsafe {self.read_node(next_idx)});
-
-            // ensure that the next node can support a deletion
-            if next.head.len >= self.head.size / 2 {
-                // it does, nothing to do here
-                if item_current {
-                    if item_push {
-                        item_push = false;
-                    } else {
-                        item_current = false;
-                    }
-                    item_node = Some(current);
-                }
-                current = next;
-            } else {
-                // favorize left siblings
-                let sibling_index = {
-                    if next_index > 0 {
-                        next_index - 1
-                    } else {
-                        1
-                    }
-                };
-                let mut sibling = try!(unsafe {self.read_node(current.next[sibling_index])});
-
-                // can the sibling support a deletion?
-                if sibling.head.len >= self.head.size / 2 {
-                    // pull one from the sibling
-                    if sibling_index < next_index {
-                        // sibling is to the left
-                        let left_item = sibling.items.pop().unwrap();
-                        sibling.head.len -= 1;
-                        current.items.push(left_item);
-                        current.items.swap(sibling_index, current.head.len);
-                        let sep_item = current.items.pop().unwrap();
-                        next.items.insert(0, sep_item);
-                        next.head.len += 1;
-                        // move the next value if the sibling isn't a leaf
-                        if sibling.head.leaf == 0 {
-                            let left_next = sibling.next.pop().unwrap();
-                            next.next.insert(0, left_next);
-                        }
-                    } else {
-                        // sibling is to the right
-                        let right_item = sibling.items.remove(0);
-                        sibling.head.len -= 1;
-                        current.items.push(right_item);
-                        current.items.swap(next_index, current.head.len);
-                        let sep_item = current.items.pop().unwrap();
-                        next.items.push(sep_item);
-                        if item_current {
-                            item_push = true;
-                            item_index = Some(next.head.len);
-                        }
-                        next.head.len += 1;
-                        // move the next value if the sibling isn't a leaf
-                        if sibling.head.leaf == 0 {
-                            let right_next = sibling.next.remove(0);
-                            next.next.push(right_next);
-                        }
-                    }
+            // the item separates two children. it's swapped for the nearest
+            // item of whichever child can spare one, otherwise the children
+            // are merged around it and the search goes on in the merged node
+            let left = try!(unsafe {self.read_node(current.next[idx])});
+            if left.head.len >= self.spare_len() {
+                let replacement = try!(self.remove_edge(left, true));
+                let node_item = mem::replace(&mut current.items[idx], replacement);
+                try!(self.write_node(&current));
+                return Ok(Some(node_item));
+            }
+            let right = try!(unsafe {self.read_node(current.next[idx + 1])});
+            if right.head.len >= self.spare_len() {
+                let replacement = try!(self.remove_edge(right, false));
+                let node_item = mem::replace(&mut current.items[idx], replacement);
+                try!(self.write_node(&current));
+                return Ok(Some(node_item));
+            }
+            current = try!(self.merge_children(current, idx, left, right));
+        }
+    }
 
-                    // save everything
-                    try!(self.write_node(&sibling));
-                    try!(self.write_node(&next));
-                    try!(self.write_node(&current));
+    fn spare_len(&self) -> usize {
+        // a node this full can lose an item, and two nodes short of it merge
+        // around their separator into no more than a full node
+        (self.head.size + 1) / 2
+    }
 
-                    // update current
-                    if item_current {
-                        if item_push {
-                            item_push = false;
-                        } else {
-                            item_current = false;
-                        }
-                        item_node = Some(current);
-                    }
-                    current = next;
+    fn remove_edge(&mut self, node: BufNode<V>, last: bool) -> io::Result<V> {
+        // take the last or first item under a node that can spare one
+        let mut current = node;
+        loop {
+            if current.head.leaf != 0 {
+                let edge = if last {
+                    current.items.pop().unwrap()
                 } else {
-                    // merge the two nodes
-                    if sibling_index < next_index {
-                        // sibling is to the left
-                        let sep_item = current.items.remove(sibling_index);
-                        current.next.remove(next_index);
-                        current.head.len -= 1;
-                        sibling.items.push(sep_item);
-                        sibling.items.extend(next.items);
-                        sibling.next.extend(next.next);
-                        sibling.head.len = sibling.items.len();
-
-                        // write everything
-                        // check to see if we've emptied the root node
-                        if current.head.len == 0 {
-                            // this only happens if current is the root node
-                            self.head.root = Some(sibling.head.idx);
-                            try!(self.write_meta());
-                            try!(self.delete_node(current.head.idx));
-                        } else {
-                            try!(self.write_node(&current));
-                        }
+                    current.items.remove(0)
+                };
+                current.head.len -= 1;
+                try!(self.write_node(&current));
+                return Ok(edge);
+            }
+            let idx = if last {current.head.len} else {0};
+            current = try!(self.fill_child(current, idx));
+        }
+    }
 
-                        // write the rest of the nodes
-                        try!(self.delete_node(next.head.idx));
-                        try!(self.write_node(&sibling));
-                        if item_current {
-                            if item_push {
-                                item_push = false;
-                            } else {
-                                item_current = false;
-                            }
-                            item_node = Some(current);
-                        }
-                        current = sibling;
-                    } else {
-                        // sibling is to the right
-                        let sep_item = current.items.remove(next_index);
-                        current.next.remove(sibling_index);
-                        current.head.len -= 1;
-                        next.items.push(sep_item);
-                        if item_current {
-                            item_push = true;
-                            item_index = Some(next.head.len);
-                        }
-                        next.items.extend(sibling.items);
-                        next.next.extend(sibling.next);
-                        next.head.len = next.items.len();
-
-                        // write everything
-                        // check to see if we've emptied the root node
-                        if current.head.len == 0 {
-                            // this only happens if current is the root node
-                            self.head.root = Some(next.head.idx);
-                            try!(self.write_meta());
-                            try!(self.delete_node(current.head.idx));
-                        } else {
-                            try!(self.write_node(&current));
-                        }
+    fn fill_child(&mut self, mut parent: BufNode<V>, idx: usize) -> io::Result<BufNode<V>> {
+        // the child at idx, with an item to spare. it borrows one through
+        // the parent from a sibling that has one to spare, or is merged
+        // with a sibling
+        let mut child = try!(unsafe {self.read_node(parent.next[idx])});
+        if child.head.len >= self.spare_len() {
+            return Ok(child);
+        }
 
-                        try!(self.delete_node(sibling.head.idx));
-                        try!(self.write_node(&next));
-                        if item_current {
-                            if item_push {
-                                item_push = false;
-                            } else {
-                                item_current = false;
-                            }
-                            item_node = Some(current);
-                        }
-                        current = next;
-                    }
+        let left = if idx > 0 {
+            let mut left = try!(unsafe {self.read_node(parent.next[idx - 1])});
+            if left.head.len >= self.spare_len() {
+                // left's last item goes up, the separator comes down
+                let up = left.items.pop().unwrap();
+                left.head.len -= 1;
+                let sep = mem::replace(&mut parent.items[idx - 1], up);
+                child.items.insert(0, sep);
+                child.head.len += 1;
+                if left.head.leaf == 0 {
+                    let left_next = left.next.pop().unwrap();
+                    child.next.insert(0, left_next);
+                }
+                try!(self.write_node(&left));
+                try!(self.write_node(&child));
+                try!(self.write_node(&parent));
+                return Ok(child);
+            }
+            Some(left)
+        } else {
+            None
+        };
+
+        if idx < parent.head.len {
+            let mut right = try!(unsafe {self.read_node(parent.next[idx + 1])});
+            if right.head.len >= self.spare_len() {
+                // right's first item goes up, the separator comes down
+                let up = right.items.remove(0);
+                right.head.len -= 1;
+                let sep = mem::replace(&mut parent.items[idx], up);
+                child.items.push(sep);
+                child.head.len += 1;
+                if right.head.leaf == 0 {
+                    let right_next = right.next.remove(0);
+                    child.next.push(right_next);
                 }
+                try!(self.write_node(&right));
+                try!(self.write_node(&child));
+                try!(self.write_node(&parent));
+                return Ok(child);
             }
+            return self.merge_children(parent, idx, child, right);
         }
 
-        // at this point, current is a leaf node, supporting at least one deletion
-        match item_node {
+        match left {
+            Some(left) => self.merge_children(parent, idx - 1, left, child),
             None => {
-                // look for the item in the leaf
-                match current.items.binary_search(item) {
-                    Ok(idx) => {
-                        let node_item = current.items.remove(idx);
-                        current.head.len -= 1;
-                        try!(self.write_node(&current));
-                        Ok(Some(node_item))
-                    },
-                    Err(_) => {
-                        // item not in tree
-                        Ok(None)
-                    }
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("Node ({}) has a child but no items", parent.head.idx)))
+            }
+        }
+    }
+
+    fn merge_children(&mut self, mut parent: BufNode<V>, idx: usize, mut left: BufNode<V>, right: BufNode<V>)
+                      -> io::Result<BufNode<V>> {
+        // the children either side of the separator at idx become one node
+        // holding both and the separator, which is returned
+        let sep = parent.items.remove(idx);
+        parent.next.remove(idx + 1);
+        parent.head.len -= 1;
+        left.items.push(sep);
+        left.items.extend(right.items);
+        left.next.extend(right.next);
+        left.head.len = left.items.len();
+
+        try!(self.delete_node(right.head.idx));
+        if parent.head.len == 0 {
+            // only the root can empty, the merged node takes its place
+            self.head.root = Some(left.head.idx);
+            try!(self.write_meta());
+            try!(self.delete_node(parent.head.idx));
+        } else {
+            try!(self.write_node(&parent));
+        }
+        try!(self.write_node(&left));
+        Ok(left)
+    }
+
+    pub fn extend<I: IntoIterator>(&mut self, items: I) -> io::Result<usize> where I::Item: Into<V> {
+        // insert every item, holding the nodes the inserts pass through in
+        // memory and writing each changed one back once instead of writing
+        // the path down to a leaf for every item. returns how many items
+        // were new rather than replacing an equal one. after an error the
+        // tree is as it was when nodes were last written back
+        let mut written = self.head;
+        let mut added = 0;
+        self.cache = Some(NodeCache::new());
+        for item in items {
+            match unsafe {self.insert_item(item)} {
+                Ok(Ok(_)) => {
+                    self.head.len += 1;
+                    added += 1;
+                },
+                Ok(Err(_)) => {
+                    trace!("Replaced an equal item");
+                },
+                Err(e) => {
+                    self.cache = None;
+                    self.head = written;
+                    return Err(e);
+                }
+            }
+            if self.cache.as_ref().map_or(false, |cache| cache.nodes.len() >= EXTEND_CACHE_NODES) {
+                if let Err(e) = self.write_back() {
+                    self.head = written;
+                    return Err(e);
                 }
+                written = self.head;
+                self.cache = Some(NodeCache::new());
+            }
+        }
+        match self.write_back() {
+            Err(e) => {
+                self.head = written;
+                Err(e)
             },
-            Some(mut node) => {
-                // swap the relevant item out
-                let sep = current.items.pop().unwrap();
-                current.head.len -= 1;
-                node.items.push(sep);
-                node.items.swap(item_index.unwrap(), node.head.len);
-                let node_item = node.items.pop().unwrap();
-                try!(self.write_node(&node));
-                try!(self.write_node(&current));
-                Ok(Some(node_item))
+            Ok(()) => Ok(added)
+        }
+    }
+
+    fn write_back(&mut self) -> io::Result<()> {
+        // stop caching, writing every changed node in the order they're laid
+        // out and then the header
+        let cache = match self.cache.take() {
+            Some(cache) => cache,
+            None => {
+                return Ok(());
             }
+        };
+        let mut dirty: Vec<u64> = cache.dirty.into_iter().collect();
+        dirty.sort();
+        trace!("Writing back {} of {} held nodes", dirty.len(), cache.nodes.len());
+        for idx in dirty {
+            try!(self.write_node(&cache.nodes[&idx]));
         }
+        self.write_meta()
     }
 
     pub fn insert<K: Into<V>>(&mut self, to_item: K) -> io::Result<Option<V>> {
@@ -573,6 +1448,18 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
 
     pub unsafe fn insert_idx<K: Into<V>>(&mut self, to_item: K) -> io::Result<Result<u64, V>> {
         // there are certain cases where we care to know where the item was written
+        match self.insert_item(to_item) {
+            Ok(Ok(idx)) => {
+                // a replaced item leaves the count where it was
+                self.head.len += 1;
+                try!(self.write_meta());
+                Ok(Ok(idx))
+            },
+            result => result
+        }
+    }
+
+    unsafe fn insert_item<K: Into<V>>(&mut self, to_item: K) -> io::Result<Result<u64, V>> {
         let mut item = to_item.into();
 
         // check for a root node
@@ -626,7 +1513,7 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
 
             // update our separator value
             sep = current.items.pop().unwrap();
-            let finished = item == sep;
+            let finished = item == sep && !self.is_multi();
             let to_return;
             // update current's len
             current.head.len = current.items.len();
@@ -672,6 +1559,10 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
         while current.head.leaf == 0 {
             // figure out which next node we need to get
             let next_index = match current.items.binary_search(&item) {
+                Ok(idx) if self.is_multi() => {
+                    // keep equal items to the right
+                    idx + 1
+                },
                 Ok(idx) => {
                     current.items.push(item);
                     current.items.swap(idx, current.head.len);
@@ -717,7 +1608,7 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
                 let routing = {
                     if item < sep {
                         0
-                    } else if item > sep {
+                    } else if item > sep || self.is_multi() {
                         1
                     } else {
                         2
@@ -760,6 +1651,13 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
 
         // at this point current is a leaf node with space to insert our item
         match current.items.binary_search(&item) {
+            Ok(idx) if self.is_multi() => {
+                // add the item next to its equal
+                current.items.insert(idx + 1, item);
+                current.head.len += 1;
+                try!(self.write_node(&current));
+                Ok(Ok(current.head.idx))
+            },
             Ok(idx) => {
                 // item was found in the list, swap them
                 current.items.push(item);
@@ -782,19 +1680,28 @@ impl<T: io::Read + io::Write + io::Seek + fmt::Debug, V: BufItem> BufTree<T, V>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::{BufTreeHead, BufTreeHeadV11};
+    use std::slice;
+    use std::io::Cursor;
+    use std::mem;
+    use std::fs;
+    use std::env;
     use test::Bencher;
 
     #[test]
     fn test_tree_basic() {
         let mut tree: BufTree<_, u64> = BufTree::default();
+        assert!(tree.is_empty());
         assert_eq!(tree.contains(35).unwrap(), false);
         assert_eq!(tree.insert(35).unwrap(), None);
         assert_eq!(tree.insert(35).unwrap(), Some(35));
+        assert_eq!(tree.len(), 1);
         assert_eq!(tree.contains(35).unwrap(), true);
         assert_eq!(tree.get(35).unwrap(), Some(35));
         assert_eq!(tree.remove(35).unwrap(), Some(35));
         assert_eq!(tree.remove(35).unwrap(), None);
         assert_eq!(tree.contains(35).unwrap(), false);
+        assert!(tree.is_empty());
     }
 
     #[test]
@@ -816,6 +1723,392 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tree_verify() {
+        let mut tree: BufTree<_, u64> = BufTree::default();
+        assert_eq!(tree.verify().unwrap(), 0);
+        for i in 0..100 {
+            assert_eq!(tree.insert(i).unwrap(), None);
+        }
+        assert_eq!(tree.verify().unwrap(), 100);
+        for i in 0..50 {
+            assert_eq!(tree.remove(i * 2).unwrap(), Some(i * 2));
+            // removals that reach inner nodes leave nothing for verify to find
+            assert_eq!(tree.verify().unwrap(), 99 - i as usize);
+        }
+        let mut sum = 0;
+        assert_eq!(tree.verify_each(|i| sum += *i).unwrap(), 50);
+        // the sum of the first 50 odd numbers
+        assert_eq!(sum, 2500);
+
+        // the count survives reopening, and a header that disagrees is caught
+        let mut tree: BufTree<_, u64> = unsafe {BufTree::from_buffer(tree.into_inner())}.unwrap();
+        assert_eq!(tree.len(), 50);
+        tree.head.len = 49;
+        assert!(tree.verify().is_err());
+    }
+
+    #[test]
+    fn test_tree_open_checked() {
+        let mut tree: BufTree<_, u64> = BufTree::default();
+        for i in 0..100 {
+            tree.insert(i).unwrap();
+        }
+        let data = tree.into_inner().into_inner();
+        let mut checked: BufTree<_, u64> = unsafe {BufTree::open_checked(Cursor::new(data.clone()))}.unwrap();
+        assert_eq!(checked.len(), 100);
+        assert_eq!(checked.get(42).unwrap(), Some(42));
+
+        // a header cut short, one with a tag no Option has and one claiming
+        // nodes too wide to allocate
+        assert!(unsafe {BufTree::<_, u64>::from_buffer(Cursor::new(data[..4].to_vec()))}.is_err());
+        let head: BufTreeHead = unsafe {mem::zeroed()};
+        let mut bad_tag = data.clone();
+        bad_tag[field_offset(&head, &head.root)] = 7;
+        assert!(unsafe {BufTree::<_, u64>::from_buffer(Cursor::new(bad_tag))}.is_err());
+        let mut wide = BufTree::<_, u64>::default();
+        wide.head.size = 1 << 40;
+        wide.write_meta().unwrap();
+        assert!(unsafe {BufTree::<_, u64>::from_buffer(wide.into_inner())}.is_err());
+
+        // a root past the end opens unchecked, but not checked
+        let mut far: BufTree<_, u64> = unsafe {BufTree::from_buffer(Cursor::new(data.clone()))}.unwrap();
+        far.head.root = Some(data.len() as u64 + 1000);
+        far.head.last = data.len() as u64 + 2000;
+        far.write_meta().unwrap();
+        let far_data = far.into_inner().into_inner();
+        assert!(unsafe {BufTree::<_, u64>::from_buffer(Cursor::new(far_data.clone()))}.is_ok());
+        assert!(unsafe {BufTree::<_, u64>::open_checked(Cursor::new(far_data))}.is_err());
+    }
+
+    #[test]
+    fn test_tree_small_widths() {
+        for size in MIN_TREE_WIDTH..MIN_TREE_WIDTH + 4 {
+            let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), size).unwrap();
+            // a scattered order so splits and merges happen all over
+            for i in 0..200 {
+                assert_eq!(tree.insert(i * 37 % 200).unwrap(), None);
+            }
+            assert_eq!(tree.verify().unwrap(), 200);
+            for i in 0..100 {
+                assert_eq!(tree.remove(i * 74 % 200).unwrap(), Some(i * 74 % 200));
+                tree.verify().unwrap();
+            }
+            assert_eq!(tree.verify().unwrap(), 100);
+            // and all the way down, so the root collapses at every width to
+            // a single empty leaf that takes inserts again
+            for i in 0..100 {
+                assert_eq!(tree.remove(i * 2 + 1).unwrap(), Some(i * 2 + 1));
+                assert_eq!(tree.verify().unwrap(), 99 - i as usize);
+            }
+            assert_eq!(tree.depth().unwrap(), 1);
+            for i in 0..20u64 {
+                assert_eq!(tree.insert(i).unwrap(), None);
+            }
+            assert_eq!(tree.verify().unwrap(), 20);
+        }
+    }
+
+    #[test]
+    fn test_tree_bad_layout() {
+        for size in 0..MIN_TREE_WIDTH {
+            assert!(BufTree::<_, u64>::new(Cursor::new(vec![]), size).is_err());
+        }
+        assert!(BufTree::<_, ()>::new(Cursor::new(vec![]), 6).is_err());
+    }
+
+    #[test]
+    fn test_tree_shape() {
+        let mut tree: BufTree<_, u64> = BufTree::default();
+        assert_eq!(tree.node_count().unwrap(), 0);
+        assert_eq!(tree.depth().unwrap(), 0);
+        for i in 0..100 {
+            tree.insert(i).unwrap();
+        }
+        assert!(tree.node_count().unwrap() >= 100 / 6);
+        assert!(tree.depth().unwrap() >= 2);
+        assert_eq!(tree.free_nodes().unwrap(), 0);
+        for i in 0..100 {
+            tree.remove(i).unwrap();
+        }
+        // the root stays behind as an empty leaf
+        assert_eq!(tree.node_count().unwrap(), 1);
+        assert_eq!(tree.depth().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_tree_clear() {
+        let mut tree: BufTree<_, u64> = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        for i in 0..100 {
+            tree.insert(i).unwrap();
+        }
+        tree.clear().unwrap();
+        assert!(tree.is_empty());
+        assert_eq!(tree.node_count().unwrap(), 0);
+        assert_eq!(tree.buffer.get_ref().len(), mem::size_of::<BufTreeHead>());
+        assert!(tree.is_multi());
+        for i in 0..10 {
+            tree.insert(i).unwrap();
+        }
+        assert_eq!(tree.verify().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_tree_growth() {
+        let head_bytes = mem::size_of::<BufTreeHead>();
+        let bytes = node_bytes::<u64>(6);
+        for &growth in [TreeGrowth::Exact, TreeGrowth::Double, TreeGrowth::Chunk(16)].iter() {
+            let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 6).unwrap();
+            tree.set_growth(growth);
+            for i in 0..500 {
+                tree.insert(i * 37 % 500).unwrap();
+            }
+            let used = tree.head.last as usize;
+            let held = tree.buffer.get_ref().len();
+            assert_eq!(held, used + tree.head.reserved as usize * bytes);
+            if growth == TreeGrowth::Chunk(16) {
+                assert_eq!((held - head_bytes) / bytes % 16, 0);
+            }
+
+            // the reserved room survives a reopen, and is given back by trim
+            let mut tree: BufTree<_, u64> = unsafe {BufTree::from_buffer(tree.into_inner())}.unwrap();
+            assert_eq!(tree.verify().unwrap(), 500);
+            tree.trim().unwrap();
+            assert_eq!(tree.buffer.get_ref().len(), used);
+            assert_eq!(tree.verify().unwrap(), 500);
+            for i in 500..600 {
+                tree.insert(i).unwrap();
+            }
+            assert_eq!(tree.verify().unwrap(), 600);
+        }
+        assert_eq!(TreeGrowth::from_name("double"), Some(TreeGrowth::Double));
+        assert_eq!(TreeGrowth::from_name("64"), Some(TreeGrowth::Chunk(64)));
+        assert_eq!(TreeGrowth::from_name("0"), None);
+        assert_eq!(format!("{}", TreeGrowth::Chunk(64)), "64");
+    }
+
+    #[test]
+    fn test_tree_multi() {
+        let mut tree: BufTree<_, u64> = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        for i in 0..20 {
+            for _ in 0..(i % 5) {
+                assert_eq!(tree.insert(i).unwrap(), None);
+            }
+        }
+        assert_eq!(tree.len(), 40);
+        assert_eq!(tree.verify().unwrap(), 40);
+        for i in 0..20 {
+            assert_eq!(tree.get_all(i).unwrap().count(), (i % 5) as usize);
+            assert!(tree.get_all(i).unwrap().all(|item| item == i));
+        }
+        for i in 0..20 {
+            for j in 0..(i % 5) {
+                assert_eq!(tree.remove(i).unwrap(), Some(i));
+                assert_eq!(tree.get_all(i).unwrap().count(), (i % 5 - j - 1) as usize);
+            }
+            assert_eq!(tree.remove(i).unwrap(), None);
+        }
+        assert_eq!(tree.verify().unwrap(), 0);
+
+        // one copy of each item a round, so runs of equal items that span
+        // inner nodes are taken apart from the middle
+        for round in 0..6 {
+            for i in 0..50u64 {
+                assert_eq!(tree.insert(i * 7 % 50).unwrap(), None);
+            }
+            assert_eq!(tree.verify().unwrap(), (round + 1) * 50);
+        }
+        for round in 0..6 {
+            for i in 0..50 {
+                assert_eq!(tree.remove(i * 11 % 50).unwrap(), Some(i * 11 % 50));
+            }
+            assert_eq!(tree.verify().unwrap(), (5 - round) * 50);
+            assert!((0..50u64).all(|i| tree.get_all(i).unwrap().count() == 5 - round));
+        }
+    }
+
+    #[test]
+    fn test_tree_extend() {
+        // the same tree as inserting one at a time, just written less often
+        let items: Vec<u64> = (0..8000u64).map(|i| i.wrapping_mul(2654435761) % 7919).collect();
+        let mut one: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 4).unwrap();
+        let mut added = 0;
+        for item in items.iter() {
+            if one.insert(*item).unwrap().is_none() {
+                added += 1;
+            }
+        }
+        let mut bulk: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 4).unwrap();
+        assert_eq!(bulk.extend(items.iter().cloned()).unwrap(), added);
+        assert_eq!(bulk.len(), one.len());
+        assert_eq!(bulk.node_count().unwrap(), one.node_count().unwrap());
+        assert!(bulk.node_count().unwrap() > EXTEND_CACHE_NODES);
+
+        // and everything reached the buffer
+        let mut bulk: BufTree<_, u64> = unsafe {BufTree::from_buffer(bulk.into_inner())}.unwrap();
+        assert_eq!(bulk.verify().unwrap(), added);
+        assert!(items.iter().all(|item| bulk.contains(*item).unwrap()));
+        assert_eq!(bulk.extend(vec![7919u64, 7919, 8000]).unwrap(), 2);
+
+        let mut multi: BufTree<_, u64> = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        assert_eq!(multi.extend(vec![3u64, 1, 3, 2, 3]).unwrap(), 5);
+        assert_eq!(multi.get_all(3).unwrap().count(), 3);
+        assert_eq!(multi.verify().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_tree_bounds() {
+        let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 4).unwrap();
+        assert_eq!(tree.get_ceiling(5).unwrap(), None);
+        assert_eq!(tree.iter_from(0).count(), 0);
+        for i in 0..100u64 {
+            tree.insert((i * 37) % 100 * 10).unwrap();
+        }
+        assert_eq!(tree.get_ceiling(0).unwrap(), Some(0));
+        assert_eq!(tree.get_ceiling(1).unwrap(), Some(10));
+        assert_eq!(tree.get_ceiling(990).unwrap(), Some(990));
+        assert_eq!(tree.get_ceiling(991).unwrap(), None);
+        assert_eq!(tree.get_next(990).unwrap(), None);
+        assert_eq!(tree.get_next(500).unwrap(), Some(510));
+        assert_eq!(tree.get_floor(505).unwrap(), Some(500));
+        assert_eq!(tree.get_floor(500).unwrap(), Some(500));
+        assert_eq!(tree.get_prev(500).unwrap(), Some(490));
+        assert_eq!(tree.get_prev(0).unwrap(), None);
+        for i in 0..1000u64 {
+            let rounded_up = (i + 9) / 10 * 10;
+            assert_eq!(tree.get_ceiling(i).unwrap(), if rounded_up < 1000 {Some(rounded_up)} else {None});
+            assert_eq!(tree.get_floor(i).unwrap(), Some(i / 10 * 10));
+        }
+
+        let items: Vec<u64> = tree.iter_from(955).map(|item| item.unwrap()).collect();
+        assert_eq!(items, vec![960, 970, 980, 990]);
+
+        let mut multi: BufTree<_, u64> = BufTree::new_multi(Cursor::new(vec![]), 4).unwrap();
+        for i in 0..60u64 {
+            multi.insert(i % 6 * 2).unwrap();
+        }
+        assert_eq!(multi.get_ceiling(3).unwrap(), Some(4));
+        assert_eq!(multi.get_floor(3).unwrap(), Some(2));
+        let items: Vec<u64> = multi.iter_from(5).map(|item| item.unwrap()).collect();
+        assert_eq!(items, vec![6, 8, 10]);
+    }
+
+    #[test]
+    fn test_tree_dump() {
+        let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 4).unwrap();
+        assert!(tree.dump_nodes().unwrap().is_empty());
+        for i in 0..40u64 {
+            tree.insert(i).unwrap();
+        }
+        let nodes = tree.dump_nodes().unwrap();
+        assert_eq!(nodes.len(), tree.node_count().unwrap());
+        assert_eq!(nodes[0].depth, 0);
+        assert!(!nodes[0].leaf);
+        assert_eq!(nodes[1].idx, nodes[0].next[0]);
+
+        // the leaves in order hold the items in order
+        let mut items = vec![];
+        for node in nodes.iter().filter(|node| node.leaf) {
+            assert_eq!(node.depth, tree.depth().unwrap() - 1);
+            assert!(node.next.is_empty());
+            items.extend(node.items.iter().cloned());
+        }
+        let leaf_count = items.len();
+        let inner_count: usize = nodes.iter().filter(|node| !node.leaf).fold(0, |sum, node| sum + node.items.len());
+        assert_eq!(leaf_count + inner_count, 40);
+        assert!(items.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_tree_stats() {
+        let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), 4).unwrap();
+        assert_eq!(tree.stats().unwrap(), TreeStats {width: 4, ..TreeStats::default()});
+        assert_eq!(tree.stats().unwrap().fill_factor(), 0.0);
+        for i in 0..40u64 {
+            tree.insert(i).unwrap();
+        }
+        for i in 0..30u64 {
+            tree.remove(i).unwrap();
+        }
+        let stats = tree.stats().unwrap();
+        assert_eq!(stats.items, 10);
+        assert_eq!(stats.depth, tree.depth().unwrap());
+        assert_eq!(stats.nodes, tree.node_count().unwrap());
+        assert!(stats.leaves > 0 && stats.leaves <= stats.nodes);
+        assert!(stats.free_nodes > 0);
+        assert!(stats.fill_factor() > 0.0 && stats.fill_factor() <= 1.0);
+
+        let mut out = vec![];
+        tree.dump(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("tree: width 4, 10 items, "));
+        assert_eq!(lines.iter().filter(|line| line.trim_left().starts_with("node ")).count(), stats.nodes);
+        assert!(lines.iter().any(|line| line.contains(" leaf, ") && line.ends_with("..39")));
+        assert_eq!(lines.last().unwrap().split_whitespace().count(), stats.free_nodes + 1);
+    }
+
+    #[test]
+    fn test_tree_export() {
+        let mut tree: BufTree<_, u64> = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        for i in 0..50 {
+            assert_eq!(tree.insert(i % 10).unwrap(), None);
+        }
+        let mut out = vec![];
+        assert_eq!(tree.export(&mut out).unwrap(), 50);
+        let mut copy: BufTree<_, u64> = BufTree::import(Cursor::new(vec![]), &mut Cursor::new(out)).unwrap();
+        assert!(copy.is_multi());
+        assert_eq!(copy.verify().unwrap(), 50);
+        assert_eq!(copy.get_all(3).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn test_tree_upgrade_v11() {
+        let mut tree: BufTree<_, u64> = BufTree::new_multi(Cursor::new(vec![]), 6).unwrap();
+        for i in 0..50 {
+            assert_eq!(tree.insert(i % 10).unwrap(), None);
+        }
+        // nodes after the new header are still valid under the old one, so
+        // writing the old header over the front makes a tree from before
+        let old = BufTreeHeadV11 {
+            size: tree.head.size,
+            last: tree.head.last,
+            root: tree.head.root,
+            gone: tree.head.gone,
+            multi: tree.head.multi
+        };
+        let mut data = tree.into_inner().into_inner();
+        let old_buf = unsafe {slice::from_raw_parts(&old as *const _ as *const u8, mem::size_of::<BufTreeHeadV11>())};
+        for (i, &byte) in old_buf.iter().enumerate() {
+            data[i] = byte;
+        }
+
+        let mut upgraded: BufTree<_, u64> = unsafe {BufTree::upgrade_v11(Cursor::new(data), Cursor::new(vec![]))}.unwrap();
+        assert!(upgraded.is_multi());
+        assert_eq!(upgraded.len(), 50);
+        assert_eq!(upgraded.verify().unwrap(), 50);
+        assert_eq!(upgraded.get_all(3).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn test_page_width() {
+        let width = page_width::<u64>();
+        assert!(node_bytes::<u64>(width) <= PAGE_SIZE);
+        assert!(node_bytes::<u64>(width + 1) > PAGE_SIZE);
+        assert_eq!(page_width::<[u64; 32]>(), 15);
+        assert_eq!(page_width::<([u64; 32], [u64; 32], [u64; 32], [u64; 32])>(), MIN_TREE_WIDTH);
+
+        let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), width).unwrap();
+        for i in 0..1000 {
+            tree.insert(i).unwrap();
+        }
+        assert_eq!(tree.verify().unwrap(), 1000);
+        for i in 0..1000 {
+            assert_eq!(tree.remove(i).unwrap(), Some(i));
+        }
+        assert_eq!(tree.verify().unwrap(), 0);
+    }
+
     fn bench_contains(b: &mut Bencher, number: u64) {
         // create the tree
         let mut tree: BufTree<_, u64> = BufTree::default();
@@ -839,4 +2132,26 @@ mod tests {
     fn bench_contains_100(b: &mut Bencher) {
         bench_contains(b, 100)
     }
+
+    fn bench_file_contains(b: &mut Bencher, name: &str, width: usize) {
+        // a tree on disk, where every node a lookup visits is a seek and a read
+        let path = env::temp_dir().join(name);
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut tree: BufTree<_, u64> = BufTree::new(file, width).unwrap();
+        for i in 0..10000 {
+            assert_eq!(tree.insert(i).unwrap(), None);
+        }
+        b.iter(|| tree.contains(5000));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[bench]
+    fn bench_file_contains_narrow(b: &mut Bencher) {
+        bench_file_contains(b, "h2-bench-tree-narrow", 6)
+    }
+
+    #[bench]
+    fn bench_file_contains_paged(b: &mut Bencher) {
+        bench_file_contains(b, "h2-bench-tree-paged", page_width::<u64>())
+    }
 }
@@ -0,0 +1,213 @@
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+
+use std::fs;
+use std::io;
+
+use fileops::*;
+use revs::*;
+use plan::*;
+use repo::*;
+use crypt::*;
+
+// what the last add, rm, revert or apply replaced, so it can be put back. only the
+// most recent operation is kept, each one starts by throwing the old one out.
+// under it, `<area>/<id>` is the old copy of a path and `<area>-new/<id>`
+// marks a path that didn't exist before. an encrypted repository's old
+// copies are sealed, whatever area they came from
+#[derive(Debug, Clone)]
+pub struct Undo {
+    path: PathBuf,
+    key: Option<StoreKey>,
+    plan: Plan
+}
+
+impl Default for Undo {
+    fn default() -> Undo {
+        Undo::new(repo_path("undo"))
+    }
+}
+
+fn remove_any(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+        Ok(ref data) if data.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path)
+    }
+}
+
+fn walk_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    // ids of every file under root, none if it doesn't exist
+    let mut ids = vec![];
+    let mut to_visit = vec![root.to_path_buf()];
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        let entries = match fs::read_dir(&dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+            Ok(entries) => entries
+        };
+        for item in entries {
+            let entry = try!(item);
+            if try!(entry.metadata()).is_dir() {
+                to_visit.push(entry.path());
+            } else if !is_temp_path(entry.path()) {
+                match entry.path().relative_from(root) {
+                    Some(id) => ids.push(PathBuf::from(id)),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("{} is outside of the undo area", entry.path().display())));
+                    }
+                }
+            }
+        }
+    }
+    Ok(ids)
+}
+
+impl Undo {
+    pub fn new<T: Into<PathBuf>>(path: T) -> Undo {
+        Undo {
+            path: path.into(),
+            key: None,
+            plan: Plan::default()
+        }
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Undo {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_key(mut self, key: Option<StoreKey>) -> Undo {
+        self.key = key;
+        self
+    }
+
+    fn place(area: &str, id: &Path) -> Vec<u8> {
+        seal_place(&format!("undo/{}", area), id)
+    }
+
+    fn keep(&self, area: &str, from: &Path, id: &Path) -> io::Result<()> {
+        // one old copy, sealed on the way in if there's a key
+        let backup = self.path.join(area).join(id);
+        try!(fs::create_dir_all(backup.parent().unwrap()));
+        if self.key.is_none() {
+            return atomic_copy(from, &backup);
+        }
+        let mut data = vec![];
+        try!(fs::File::open(from).and_then(|mut f| f.read_to_end(&mut data)));
+        write_stored(self.key.as_ref(), &Undo::place(area, id), &backup, &data)
+    }
+
+    fn put_back(&self, area: &str, id: &Path, to: &Path) -> io::Result<()> {
+        // one old copy where it came from, opened if it was sealed
+        let from = self.path.join(area).join(id);
+        if self.key.is_none() {
+            return atomic_copy(&from, to);
+        }
+        let mut data = vec![];
+        try!(fs::File::open(&from).and_then(|mut f| f.read_to_end(&mut data)));
+        let data = try!(open_stored(self.key.as_ref(), &Undo::place(area, id), data, &from));
+        atomic_write(to, &data)
+    }
+
+    pub fn begin(&self, op: &str) -> io::Result<()> {
+        // forget the previous operation and start saving for this one
+        if self.plan.is_dry_run() {
+            return Ok(());
+        }
+        debug!("Starting undo information for {}", op);
+        try!(remove_any(&self.path));
+        try!(fs::create_dir_all(&self.path));
+        atomic_write(self.path.join("op"), op.as_ref())
+    }
+
+    pub fn op(&self) -> io::Result<Option<String>> {
+        // the operation the saved information is for
+        let mut op = String::new();
+        match fs::File::open(self.path.join("op")).and_then(|mut f| f.read_to_string(&mut op)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+            Ok(_) => Ok(Some(op))
+        }
+    }
+
+    pub fn save(&self, area: &str, root: &Path, id: &Path) -> io::Result<()> {
+        // keep what's at root/id before it's changed, the first save wins
+        if self.plan.is_dry_run() {
+            return Ok(());
+        }
+        let backup = self.path.join(area).join(id);
+        let marker = self.path.join(format!("{}-new", area)).join(id);
+        if fs::symlink_metadata(&backup).is_ok() || fs::symlink_metadata(&marker).is_ok() {
+            trace!("{:?} was already saved", id);
+            return Ok(());
+        }
+
+        let path = root.join(id);
+        match fs::symlink_metadata(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("Marking {:?} as new", &path);
+                try!(fs::create_dir_all(marker.parent().unwrap()));
+                try!(fs::File::create(&marker).and_then(|mut f| f.flush()));
+            },
+            Err(e) => {
+                error!("Failed to get metadata for {}: {}", path.display(), e);
+                return Err(e);
+            },
+            Ok(ref data) if data.is_dir() && self.key.is_some() => {
+                trace!("Sealing directory {:?}", &path);
+                try!(fs::create_dir_all(&backup));
+                for file in try!(walk_files(&path)) {
+                    try!(self.keep(area, &path.join(&file), &id.join(&file)));
+                }
+            },
+            Ok(ref data) if data.is_dir() => {
+                trace!("Saving directory {:?}", &path);
+                try!(copy_dir_all(&path, &backup));
+            },
+            Ok(_) => {
+                trace!("Saving {:?}", &path);
+                try!(self.keep(area, &path, id));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rollback(&self, area: &str, root: &Path) -> io::Result<usize> {
+        // put back everything saved for an area, returning how many paths changed
+        let saved = self.path.join(area);
+        let created = self.path.join(format!("{}-new", area));
+        let mut count = 0;
+
+        for id in try!(walk_files(&created)) {
+            let path = root.join(&id);
+            if self.plan.allow(Op::Remove(&path)) {
+                debug!("Removing {:?}", &path);
+                try!(remove_any(&path));
+            }
+            count += 1;
+        }
+
+        for id in try!(walk_files(&saved)) {
+            let (from, to) = (saved.join(&id), root.join(&id));
+            if self.plan.allow(Op::CopyFile(&from, &to)) {
+                debug!("Restoring {:?}", &to);
+                try!(fs::create_dir_all(to.parent().unwrap()));
+                try!(self.put_back(area, &id, &to));
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        if !self.plan.allow(Op::Remove(&self.path)) {
+            return Ok(());
+        }
+        remove_any(&self.path)
+    }
+}
@@ -0,0 +1,432 @@
+use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, Write};
+
+use rustc_serialize::json;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use map::*;
+use repo::*;
+use fileops::*;
+use lock::*;
+use pathid::*;
+use manifest::*;
+use crypt::*;
+
+use {FileIndex, FileMeta, LineIndex, Logs, PathInfo, Stage};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    // what was checked, a file id with where it was found
+    pub subject: String,
+    // why it failed, none if it held up
+    pub problem: Option<String>
+}
+
+impl Check {
+    fn passed(subject: String) -> Check {
+        Check {subject: subject, problem: None}
+    }
+
+    fn failed(subject: String, problem: String) -> Check {
+        Check {subject: subject, problem: Some(problem)}
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RepoReport {
+    pub header: Check,
+    // every index and staged copy, left empty if the header is unusable
+    pub files: Vec<Check>
+}
+
+impl RepoReport {
+    pub fn is_ok(&self) -> bool {
+        self.header.problem.is_none() && self.files.iter().all(|check| check.problem.is_none())
+    }
+
+    pub fn failed(&self) -> usize {
+        self.files.iter().filter(|check| check.problem.is_some()).count()
+    }
+}
+
+pub fn verify_repo(repo: &Repo) -> io::Result<RepoReport> {
+    info!("Verifying repository at {:?}", &repo.path);
+
+    debug!("Checking format version");
+    let header = match repo.read_header() {
+        Ok(version) if version == FORMAT_VERSION => {
+            Check::passed(format!("header (version {})", version))
+        },
+        Ok(version) => {
+            Check::failed("header".to_string(), format!("unsupported version {}", version))
+        },
+        Err(e) => {
+            Check::failed("header".to_string(), e.to_string())
+        }
+    };
+    if header.problem.is_some() {
+        return Ok(RepoReport {header: header, files: vec![]});
+    }
+
+    let logs_path = repo.path.join("logs");
+    let mut to_visit = vec![logs_path.clone()];
+    let mut files = vec![];
+
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        debug!("Reading directory {:?}", &dir);
+        let mut is_log = false;
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            if entry.file_name().to_str() == Some("meta") {
+                is_log = true;
+            }
+            if try!(entry.metadata()).is_dir() {
+                trace!("Adding path to visit queue");
+                to_visit.push(entry.path());
+            }
+        }
+
+        if !is_log {
+            trace!("Not a log directory");
+            continue;
+        }
+
+        let id = match dir.relative_from(&logs_path) {
+            Some(id) => PathBuf::from(id),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("{} is outside of the logs", dir.display())));
+            }
+        };
+
+        files.push(match verify_log(&dir) {
+            Ok(()) => Check::passed(escape_id(&id)),
+            Err(e) => Check::failed(escape_id(&id), e)
+        });
+    }
+
+    // packed indexes have no directory to find, the manifest lists them
+    let mut logs = Logs::new(logs_path.clone());
+    if let Some(manifest) = try!(Manifest::open_existing(repo.path.join("manifest"))) {
+        logs = logs.with_manifest(manifest);
+    }
+    for id in try!(logs.tracked_ids()).unwrap_or(vec![]) {
+        if try!(logs.pack_location(&id)).is_none() {
+            continue;
+        }
+        let subject = format!("{} (packed)", escape_id(&id));
+        files.push(match verify_index(&logs, &id) {
+            Ok(()) => Check::passed(subject),
+            Err(e) => Check::failed(subject, e)
+        });
+    }
+
+    // staged copies that were checked as they were made are read back again
+    let stage = Stage::new(repo.path.join("stage")).with_key(try!(load_key(&try!(repo.config()))));
+    for id in try!(logs.tracked_ids()).unwrap_or(vec![]) {
+        let hash = match try!(logs.stage_hash(&id)) {
+            Some(hash) => hash,
+            None => {
+                continue;
+            }
+        };
+        let subject = format!("{} (staged)", escape_id(&id));
+        files.push(match stage.read_path(&id) {
+            Ok(ref data) if hash_bytes(data) == hash => Check::passed(subject),
+            Ok(_) => Check::failed(subject, "contents don't match the hash they were staged with".to_string()),
+            Err(e) => Check::failed(subject, e.to_string())
+        });
+    }
+
+    Ok(RepoReport {header: header, files: files})
+}
+
+pub fn verify_log(path: &Path) -> Result<(), String> {
+    debug!("Verifying log at {:?}", path);
+    let _lock = match IndexLock::acquire(path, LockMode::Shared) {
+        Err(e) => {
+            return Err(format!("Failed to lock index: {}", e));
+        },
+        Ok(lock) => lock
+    };
+
+    trace!("Reading meta file");
+    let mut meta_str = String::new();
+    match fs::File::open(path.join("meta")).and_then(|mut f| f.read_to_string(&mut meta_str)) {
+        Err(e) => {
+            return Err(format!("Failed to read meta file: {}", e));
+        },
+        Ok(_) => {
+            trace!("Read meta file");
+        }
+    }
+
+    let mut meta: FileMeta = match json::decode(meta_str.as_ref()) {
+        Err(e) => {
+            return Err(format!("Failed to decode meta file: {}", e));
+        },
+        Ok(obj) => obj
+    };
+    if let Some(items) = meta.inline_index.take() {
+        trace!("Index is inline");
+        return check_index(&meta, &mut FileIndex::Inline::<fs::File>(items));
+    }
+
+    trace!("Opening tree file");
+    let buffer = match fs::File::open(path.join("content")) {
+        Err(e) => {
+            return Err(format!("Failed to open content tree: {}", e));
+        },
+        Ok(b) => b
+    };
+
+    let places_buffer = match fs::File::open(path.join("places")) {
+        Err(e) => {
+            return Err(format!("Failed to open places: {}", e));
+        },
+        Ok(b) => b
+    };
+
+    // the tree is walked as it's opened, so a damaged one can't make it loop
+    // or allocate more than the file holds
+    let index: LineIndex<_> = match unsafe {BufMap::open_checked(buffer, places_buffer)} {
+        Err(e) => {
+            return Err(format!("Index is corrupt: {}", e));
+        },
+        Ok(t) => t
+    };
+
+    check_index(&meta, &mut FileIndex::Tree(index))
+}
+
+pub fn verify_index(logs: &Logs, id: &Path) -> Result<(), String> {
+    // the index of one file, whether it's packed or has its own directory
+    match logs.open_index(id) {
+        Err(e) => Err(format!("Failed to open index: {}", e)),
+        Ok((meta, mut index)) => check_index(&meta, &mut index)
+    }
+}
+
+fn check_index<T: Read + Write + Seek + fmt::Debug>(meta: &FileMeta, index: &mut FileIndex<T>) -> Result<(), String> {
+    // every line of the file is recorded as exactly one place, so there
+    // can't be more line hashes than lines. the header count makes that
+    // cheap to see before walking the whole index
+    if index.len() > meta.node_count || (index.is_empty() != (meta.node_count == 0)) {
+        return Err(format!("Meta node count ({}) does not fit an index of {} line hashes",
+                           meta.node_count, index.len()));
+    }
+
+    let mut places = 0;
+    let mut empty_hash = None;
+    match index.verify_each(|hash, line_places| {
+        if line_places.is_empty() {
+            empty_hash = Some(*hash);
+        }
+        places += line_places.len();
+    }) {
+        Err(e) => {
+            return Err(format!("Index is corrupt: {}", e));
+        },
+        Ok(count) => {
+            trace!("Index has {} line hashes", count);
+        }
+    }
+
+    if let Some(hash) = empty_hash {
+        return Err(format!("Line hash {} has no places", hash));
+    }
+
+    if places != meta.node_count {
+        return Err(format!("Meta node count ({}) does not match indexed lines ({})",
+                           meta.node_count, places));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanAction {
+    // only report orphaned stage files
+    Report,
+    // create an index for orphaned stage files
+    Adopt,
+    // delete orphaned stage files
+    Prune
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrphanOutcome {
+    // reported and left as it is
+    Left,
+    Adopted,
+    Pruned,
+    // the action was tried and failed, with why
+    NotAdopted(String),
+    NotPruned(String)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    pub id: PathBuf,
+    // a temporary file a write never finished with
+    pub unfinished: bool,
+    pub outcome: OrphanOutcome
+}
+
+impl Orphan {
+    pub fn is_resolved(&self) -> bool {
+        self.outcome == OrphanOutcome::Adopted || self.outcome == OrphanOutcome::Pruned
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StageReport {
+    // stage files with no index
+    pub orphans: Vec<Orphan>,
+    // indexed files with nothing staged
+    pub missing: Vec<PathBuf>
+}
+
+impl StageReport {
+    pub fn is_ok(&self) -> bool {
+        self.orphans.iter().all(|orphan| orphan.is_resolved()) && self.missing.is_empty()
+    }
+}
+
+pub fn stage_files(stage_path: &Path) -> io::Result<Vec<PathBuf>> {
+    // ids of every file in the stage
+    let mut files = vec![];
+    let mut to_visit = vec![stage_path.to_path_buf()];
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        debug!("Reading directory {:?}", &dir);
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            if try!(entry.metadata()).is_dir() {
+                to_visit.push(entry.path());
+                continue;
+            }
+            match entry.path().relative_from(stage_path) {
+                Some(id) => {
+                    files.push(PathBuf::from(id));
+                },
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{} is outside of the stage", entry.path().display())));
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+pub fn stage_dirs(stage_path: &Path) -> io::Result<Vec<PathBuf>> {
+    // ids of every directory in the stage, empty ones included
+    let mut dirs = vec![];
+    let mut to_visit = vec![stage_path.to_path_buf()];
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        debug!("Reading directory {:?}", &dir);
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            if !try!(entry.metadata()).is_dir() {
+                continue;
+            }
+            match entry.path().relative_from(stage_path) {
+                Some(id) => {
+                    dirs.push(PathBuf::from(id));
+                },
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{} is outside of the stage", entry.path().display())));
+                }
+            }
+            to_visit.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+pub fn log_ids(logs_path: &Path) -> io::Result<Vec<PathBuf>> {
+    // ids of every file with an index
+    let mut ids = vec![];
+    let mut to_visit = vec![logs_path.to_path_buf()];
+    while !to_visit.is_empty() {
+        let dir = to_visit.pop().unwrap();
+        debug!("Reading directory {:?}", &dir);
+        for item in try!(fs::read_dir(&dir)) {
+            let entry = try!(item);
+            if entry.file_name().to_str() == Some("meta") {
+                match dir.relative_from(logs_path) {
+                    Some(id) => {
+                        ids.push(PathBuf::from(id));
+                    },
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("{} is outside of the logs", dir.display())));
+                    }
+                }
+            } else if try!(entry.metadata()).is_dir() {
+                to_visit.push(entry.path());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+pub fn verify_stage(repo: &Repo, action: OrphanAction) -> io::Result<StageReport> {
+    info!("Cross-checking stage against logs");
+    let stage_path = repo.path.join("stage");
+    let mut logs = Logs::new(repo.path.join("logs"));
+    if let Some(manifest) = try!(Manifest::open_existing(repo.path.join("manifest"))) {
+        logs = logs.with_manifest(manifest);
+    }
+
+    let mut report = StageReport::default();
+    for id in try!(stage_files(&stage_path)) {
+        let staged = stage_path.join(&id);
+        let unfinished = is_temp_path(&id);
+        if !unfinished && try!(logs.is_indexed(&id)) {
+            trace!("{:?} is tracked", &id);
+            continue;
+        }
+
+        let outcome = match action {
+            OrphanAction::Report => OrphanOutcome::Left,
+            OrphanAction::Adopt if !unfinished => {
+                debug!("Adopting {:?}", &id);
+                let metadata = try!(fs::metadata(&staged));
+                match logs.add_path(&PathInfo::new(staged, id.clone(), metadata)) {
+                    Ok(()) => OrphanOutcome::Adopted,
+                    Err(e) => OrphanOutcome::NotAdopted(e.to_string())
+                }
+            },
+            _ => {
+                // unfinished writes are never worth adopting
+                debug!("Pruning {:?}", &id);
+                match fs::remove_file(&staged) {
+                    Ok(()) => OrphanOutcome::Pruned,
+                    Err(e) => OrphanOutcome::NotPruned(e.to_string())
+                }
+            }
+        };
+        report.orphans.push(Orphan {id: id, unfinished: unfinished, outcome: outcome});
+    }
+
+    for id in try!(logs.indexed_ids()) {
+        match fs::metadata(stage_path.join(&id)) {
+            Ok(ref data) if data.is_file() => {
+                trace!("{:?} is staged", &id);
+            },
+            _ => {
+                report.missing.push(id);
+            }
+        }
+    }
+
+    Ok(report)
+}
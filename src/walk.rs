@@ -0,0 +1,231 @@
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io::Write;
+
+use std::fs;
+use std::io;
+
+use pathid::*;
+use ignore::*;
+
+// how many times an entry is tried when the error looks like it might go away
+const TRANSIENT_ATTEMPTS: usize = 3;
+
+// what a walk does when one entry can't be read. a strict walk stops at the
+// first failure, otherwise the failure is noted and the walk carries on so
+// one unreadable file doesn't hide everything else. clones share the same
+// list, so a checkout and whoever reports at the end see the same errors
+#[derive(Debug, Clone)]
+pub struct WalkErrors {
+    strict: bool,
+    errors: Rc<RefCell<Vec<(PathBuf, io::Error)>>>
+}
+
+impl Default for WalkErrors {
+    fn default() -> WalkErrors {
+        WalkErrors::new(true)
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => false
+    }
+}
+
+pub fn retry_transient<T, F: FnMut() -> io::Result<T>>(path: &Path, mut f: F) -> io::Result<T> {
+    // run something, trying again while its error looks like it might go
+    // away. for threads, which can't share a walk's error list
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match f() {
+            Err(ref e) if is_transient(e) && attempts < TRANSIENT_ATTEMPTS => {
+                debug!("Retrying {:?} after {}", path, e);
+            },
+            result => {
+                return result;
+            }
+        }
+    }
+}
+
+impl WalkErrors {
+    pub fn new(strict: bool) -> WalkErrors {
+        WalkErrors {
+            strict: strict,
+            errors: Rc::new(RefCell::new(vec![]))
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn attempt<T, F: FnMut() -> io::Result<T>>(&self, path: &Path, f: F) -> io::Result<Option<T>> {
+        // run something against one entry. none means it failed and the
+        // walk should skip the entry
+        self.check(path, retry_transient(path, f))
+    }
+
+    pub fn check<T>(&self, path: &Path, result: io::Result<T>) -> io::Result<Option<T>> {
+        // like attempt, for something that can't be tried again
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                if self.strict {
+                    return Err(e);
+                }
+                warn!("Skipping {:?}: {}", path, e);
+                self.errors.borrow_mut().push((path.to_path_buf(), e));
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn report(&self) -> usize {
+        // print every skipped entry to stderr, returning how many there were
+        let errors = self.errors.borrow();
+        let mut stderr = io::stderr();
+        for &(ref path, ref e) in errors.iter() {
+            let _ = writeln!(stderr, "error: {}: {}", escape_id(path), e);
+        }
+        errors.len()
+    }
+}
+
+pub fn walk_dir<F>(root: &Path, start: &Path, ignore: &IgnoreRules, errors: &WalkErrors, mut visit: F)
+                   -> io::Result<()>
+    where F: FnMut(PathBuf, PathBuf, fs::Metadata) -> io::Result<()> {
+    // hand every entry under start to visit with its id relative to root and
+    // its metadata, directories before what's in them. what the ignore rules
+    // match or the filter leaves out isn't visited, and entries that can't be
+    // read go to errors
+    let mut to_visit = vec![start.to_path_buf()];
+    while let Some(dir) = to_visit.pop() {
+        debug!("Reading directory {:?}", dir);
+        let entries = match try!(errors.attempt(&dir, || fs::read_dir(&dir))) {
+            Some(entries) => entries,
+            None => {
+                continue;
+            }
+        };
+        for item in entries {
+            let entry = match try!(errors.check(&dir, item)) {
+                Some(entry) => entry,
+                None => {
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let id = match path.relative_from(root) {
+                Some(id) => PathBuf::from(id),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("Walked to {:?}, outside of {:?}", path, root)));
+                }
+            };
+            let metadata = match try!(errors.attempt(&id, || entry.metadata())) {
+                Some(metadata) => metadata,
+                None => {
+                    continue;
+                }
+            };
+
+            if ignore.matches(&id, metadata.is_dir()) {
+                // our own directory, and anything the ignore files list
+                trace!("{:?} is ignored", &id);
+                continue;
+            }
+            // the filter notes what it leaves out, so status can list it
+            if try!(errors.attempt(&id, || ignore.skip(&id, &path, &metadata))).unwrap_or(true) {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                to_visit.push(path.clone());
+            }
+            try!(visit(path, id, metadata));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    use ignore::*;
+
+    #[test]
+    fn test_walk_errors() {
+        let strict = WalkErrors::default();
+        assert!(strict.attempt(Path::new("a"), || Err::<(), _>(io::Error::new(io::ErrorKind::PermissionDenied, "no"))).is_err());
+
+        let errors = WalkErrors::new(false);
+        let shared = errors.clone();
+        assert_eq!(errors.attempt(Path::new("a"), || Ok(1)).unwrap(), Some(1));
+        assert_eq!(errors.attempt(Path::new("b"), || Err::<(), _>(io::Error::new(io::ErrorKind::PermissionDenied, "no")))
+                   .unwrap(), None);
+        assert_eq!(shared.len(), 1);
+
+        // interruptions are tried again before they count
+        let mut tries = 0;
+        let result = errors.attempt(Path::new("c"), || {
+            tries += 1;
+            if tries < 2 {Err(io::Error::new(io::ErrorKind::Interrupted, "again"))} else {Ok(tries)}
+        });
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(shared.len(), 1);
+    }
+
+    #[test]
+    fn test_walk_dir() {
+        let root = env::temp_dir().join("h2-test-walk-dir");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        fs::create_dir_all(root.join(".h2")).unwrap();
+        for name in ["top", "a/one", "a/b/two", ".h2/inside"].iter() {
+            fs::File::create(root.join(name)).unwrap();
+        }
+
+        let ignore = IgnoreRules::new(vec![".h2"]);
+        let mut seen = vec![];
+        walk_dir(&root, &root, &ignore, &WalkErrors::default(), |path, id, metadata| {
+            assert_eq!(path, root.join(&id));
+            seen.push((id, metadata.is_dir()));
+            Ok(())
+        }).unwrap();
+        seen.sort();
+        assert_eq!(seen, vec![(PathBuf::from("a"), true), (PathBuf::from("a/b"), true),
+                              (PathBuf::from("a/b/two"), false), (PathBuf::from("a/one"), false),
+                              (PathBuf::from("top"), false)]);
+
+        // starting further down still gives ids from the root, and an error
+        // from visit stops the walk
+        let mut seen = vec![];
+        walk_dir(&root, &root.join("a/b"), &ignore, &WalkErrors::default(), |_, id, _| {
+            seen.push(id);
+            Ok(())
+        }).unwrap();
+        assert_eq!(seen, vec![PathBuf::from("a/b/two")]);
+        assert!(walk_dir(&root, &root, &ignore, &WalkErrors::default(), |_, _, _| {
+            Err(io::Error::new(io::ErrorKind::Other, "stop"))
+        }).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,968 @@
+// the command line flows end to end, run against the built binary in a
+// fresh checkout each
+#![feature(path_relative_from)]
+
+extern crate half2;
+
+mod support;
+
+use std::path::PathBuf;
+
+use support::*;
+
+#[test]
+fn test_init_stages_everything() {
+    let repo = TempRepo::new("init");
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("dir/b.txt", "three\n");
+    repo.h2(&["init"]);
+
+    assert!(repo.exists(".h2/version"));
+    assert!(repo.exists(".h2/manifest"));
+    assert!(repo.exists(".h2/revs"));
+    assert_eq!(files_under(repo.path(".h2/stage")), vec![PathBuf::from("a.txt"), PathBuf::from("dir/b.txt")]);
+    assert_eq!(repo.read(".h2/stage/dir/b.txt"), "three\n");
+    assert!(repo.exists(".h2/logs/a.txt/meta"));
+    assert_eq!(repo.h2(&["status"]), "");
+
+    // a second init leaves the first alone
+    assert!(repo.h2_fails(&["init"]).contains("already exists"));
+}
+
+#[test]
+fn test_add_status_diff() {
+    let repo = TempRepo::new("add");
+    repo.write("a.txt", "one\ntwo\n");
+    repo.h2(&["init"]);
+
+    repo.write("a.txt", "one\ntwo\nthree\n");
+    repo.write("new.txt", "fresh\n");
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt", "? new.txt"]);
+
+    let diff = repo.h2(&["diff"]);
+    assert!(diff.contains("--- a/a.txt\n+++ b/a.txt\n"));
+    assert!(diff.contains("+three\n"));
+    assert!(!diff.contains("-one\n"));
+
+    repo.h2(&["add", "a.txt", "new.txt"]);
+    assert_eq!(repo.read(".h2/stage/a.txt"), "one\ntwo\nthree\n");
+    assert_eq!(repo.h2(&["status"]), "");
+    assert_eq!(repo.h2(&["diff"]), "");
+
+    // changed words are only highlighted when asked, output to a pipe is a
+    // plain patch
+    repo.write("a.txt", "one\nto\nthree\n");
+    assert!(repo.h2(&["diff"]).ends_with("@@ -1,3 +1,3 @@\n one\n-two\n+to\n three\n"));
+    assert!(repo.h2(&["diff", "--color"]).contains("\n-\x1b[7mtwo\x1b[27m\n+\x1b[7mto\x1b[27m\n"));
+}
+
+#[test]
+fn test_packed_backend() {
+    let repo = TempRepo::new("packed");
+    let big: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("big.txt", &big);
+    repo.h2(&["init", "--backend", "packed"]);
+
+    // every index went to a pack, none to a directory of its own
+    assert!(repo.read(".h2/config").contains("backend = packed\n"));
+    assert!(repo.exists(".h2/packs/1"));
+    assert!(!repo.exists(".h2/logs/a.txt/meta"));
+    assert!(!repo.exists(".h2/logs/big.txt/meta"));
+    assert_eq!(repo.h2(&["status"]), "");
+
+    repo.write("a.txt", "one\ntwo\nthree\n");
+    repo.write("big.txt", &format!("{}more\n", big));
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt", "M big.txt"]);
+    assert!(repo.h2(&["diff"]).contains("+more\n"));
+
+    // a later command appends to the same pack
+    repo.h2(&["add", "a.txt", "big.txt"]);
+    assert_eq!(files_under(repo.path(".h2/packs")), vec![PathBuf::from("1")]);
+    assert_eq!(repo.h2(&["status"]), "");
+    repo.h2(&["verify"]);
+
+    // gc takes a packed index away along with its snapshot
+    ::std::fs::remove_file(repo.path("a.txt")).unwrap();
+    let collected = repo.h2(&["gc"]);
+    assert!(collected.contains("removed snapshot a.txt\n"));
+    assert!(collected.contains("removed log a.txt\n"));
+    assert_eq!(repo.h2(&["status"]), "");
+
+    assert!(repo.h2_fails(&["init", "--backend", "sqlite"]).contains("Usage"));
+}
+
+#[test]
+fn test_stage_fallback() {
+    let repo = TempRepo::new("stage-fallback");
+    let big: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+    repo.write("big.txt", &big);
+    repo.h2(&["init"]);
+
+    // an index whose tree is gone still diffs, against the staged copy
+    repo.write(".h2/logs/big.txt/content", "");
+    repo.write("big.txt", &format!("{}more\n", big));
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M big.txt"]);
+    repo.write("big.txt", &big);
+    assert_eq!(repo.h2(&["status"]), "");
+    // writing is still refused until the index is put right
+    assert!(repo.h2_fails(&["add", "big.txt"]).contains("h2 verify"));
+
+    // and the stage can be asked for outright, here it's been changed to
+    // match the checkout where the index doesn't
+    repo.write(".h2/config", "diff_source = stage\n");
+    repo.write(".h2/stage/big.txt", "staged\n");
+    repo.write("big.txt", "staged\n");
+    assert_eq!(repo.h2(&["status"]), "");
+    repo.write(".h2/config", "diff_source = nowhere\n");
+    assert!(repo.h2_fails(&["status"]).contains("diff_source"));
+}
+
+#[test]
+fn test_status_porcelain() {
+    let repo = TempRepo::new("porcelain");
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("b.txt", "moved\n");
+    repo.write("c.txt", "gone\n");
+    repo.h2(&["init"]);
+
+    repo.write("a.txt", "one\n");
+    ::std::fs::remove_file(repo.path("b.txt")).unwrap();
+    repo.write("d.txt", "moved\n");
+    ::std::fs::remove_file(repo.path("c.txt")).unwrap();
+    repo.write("new.txt", "fresh\n");
+    assert_eq!(lines(&repo.h2(&["status", "--porcelain"])),
+               vec!["M a.txt", "R b.txt -> d.txt", "D c.txt", "A new.txt"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_status_cache() {
+    let repo = TempRepo::new("status-cache");
+    let settle = |paths: &[&str]| {
+        // files only go in the cache once they're too old to change unseen
+        for path in paths {
+            assert!(::std::process::Command::new("touch").arg("-d").arg("@946684800").arg(repo.path(path))
+                    .status().unwrap().success());
+        }
+    };
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("b.txt", "three\n");
+    repo.h2(&["init"]);
+    repo.write("a.txt", "one\ntoo\n");
+    settle(&["a.txt", "b.txt", ".h2/stage/a.txt", ".h2/stage/b.txt"]);
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt"]);
+    assert!(repo.read(".h2/cache/status").contains("\"a.txt\""));
+
+    // a file with the same size and mtime isn't read again
+    repo.write("a.txt", "one\ntwo\n");
+    settle(&["a.txt"]);
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt"]);
+
+    // staging anything starts over
+    repo.write("c.txt", "four\n");
+    repo.h2(&["add", "c.txt"]);
+    assert_eq!(repo.h2(&["status"]), "");
+}
+
+#[test]
+fn test_directories() {
+    let repo = TempRepo::new("directories");
+    repo.write("a.txt", "one\n");
+    ::std::fs::create_dir(repo.path("empty")).unwrap();
+    repo.h2(&["init"]);
+    assert_eq!(repo.h2(&["status"]), "");
+
+    ::std::fs::remove_dir(repo.path("empty")).unwrap();
+    ::std::fs::create_dir(repo.path("new")).unwrap();
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["D empty/", "? new/"]);
+    assert_eq!(lines(&repo.h2(&["status", "--porcelain"])), vec!["D empty/", "A new/"]);
+    repo.h2(&["add", "new"]);
+    repo.h2(&["rm", "empty"]);
+    assert_eq!(repo.h2(&["status"]), "");
+
+    // a branch without the directory takes it away, and it comes back empty
+    repo.h2(&["commit"]);
+    repo.h2(&["branch", "topic"]);
+    ::std::fs::create_dir(repo.path("new/inner")).unwrap();
+    repo.h2(&["add", "new/inner"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["switch", "topic"]);
+    assert!(!repo.exists("new/inner"));
+    assert_eq!(repo.h2(&["status"]), "");
+    repo.h2(&["switch", "main"]);
+    assert!(::std::fs::metadata(repo.path("new/inner")).unwrap().is_dir());
+    assert_eq!(repo.h2(&["status"]), "");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_special_files() {
+    let repo = TempRepo::new("special");
+    repo.write("a.txt", "one\n");
+    assert!(::std::process::Command::new("mkfifo").arg(repo.path("pipe")).status().unwrap().success());
+    assert!(repo.h2(&["init"]).contains("skipped pipe (unsupported fifo)\n"));
+    assert!(!repo.exists(".h2/stage/pipe"));
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["! pipe (unsupported fifo)"]);
+
+    // or an error, for whoever needs every path tracked
+    repo.write(".h2/config", "special_files = error\n");
+    assert!(repo.h2_fails(&["status"]).contains("is a fifo, which can't be tracked"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_failed_post_hook() {
+    let repo = TempRepo::new("post-hook");
+    repo.write("a.txt", "one\n");
+    repo.h2(&["init"]);
+    repo.write(".h2/hooks/post-commit", "#!/bin/sh\nexit 1\n");
+    assert!(::std::process::Command::new("chmod").arg("+x").arg(repo.path(".h2/hooks/post-commit"))
+            .status().unwrap().success());
+
+    // the commit stands, the failure is a warning beside the output
+    let output = repo.run(&["commit"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Committed revision 1\n");
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("warning: The post-commit hook failed"));
+    assert!(repo.h2(&["oplog"]).contains("warning: The post-commit hook failed"));
+}
+
+#[test]
+fn test_scoped_and_sparse() {
+    let repo = TempRepo::new("sparse");
+    repo.write("lib/a.txt", "one\n");
+    repo.write("docs/b.txt", "two\n");
+    repo.h2(&["init"]);
+
+    repo.write("lib/a.txt", "one\nmore\n");
+    repo.write("docs/b.txt", "two\nmore\n");
+    assert_eq!(lines(&repo.h2(&["status", "lib/"])), vec!["M lib/a.txt"]);
+    let diff = repo.h2(&["diff", "docs"]);
+    assert!(diff.contains("+++ b/docs/b.txt\n"));
+    assert!(!diff.contains("lib/a.txt"));
+
+    // with a sparse set the rest of the checkout is left alone, even gone
+    repo.write(".h2/config", "sparse = lib\n");
+    repo.h2(&["add", "docs/b.txt"]);
+    assert_eq!(repo.read(".h2/stage/docs/b.txt"), "two\n");
+    ::std::fs::remove_file(repo.path("docs/b.txt")).unwrap();
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M lib/a.txt"]);
+    assert_eq!(repo.h2(&["status", "docs"]), "");
+}
+
+#[test]
+fn test_line_endings() {
+    let repo = TempRepo::new("line-endings");
+    repo.write("a.txt", "one\ntwo\n");
+    repo.h2(&["init"]);
+
+    repo.write("a.txt", "one\r\ntwo\r\n");
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt"]);
+    repo.write(".h2/config", "line_endings = normalize\n");
+    assert_eq!(repo.h2(&["status"]), "");
+    assert_eq!(repo.h2(&["diff"]), "");
+
+    // indexes record how they were built, new ones normalize
+    repo.h2(&["add", "a.txt"]);
+    assert!(repo.read(".h2/logs/a.txt/meta").contains("\"line_endings\":\"Normalize\""));
+    repo.write("a.txt", "one\r\ntwo\r\nthree\r\n");
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt"]);
+}
+
+#[test]
+fn test_commit_and_show() {
+    let repo = TempRepo::new("commit");
+    repo.write("a.txt", "first\n");
+    repo.h2(&["init"]);
+    assert_eq!(repo.h2(&["commit"]), "Committed revision 1\n");
+
+    repo.write("a.txt", "second\n");
+    repo.h2(&["add", "a.txt"]);
+    assert_eq!(repo.h2(&["commit"]), "Committed revision 2\n");
+
+    assert_eq!(repo.read(".h2/revs/HEAD"), "2\n");
+    assert!(repo.exists(".h2/revs/1/meta"));
+    assert!(repo.exists(".h2/revs/2/tree/a.txt"));
+    assert_eq!(repo.h2(&["show", "1:a.txt"]), "first\n");
+    assert_eq!(repo.h2(&["show", "HEAD:a.txt"]), "second\n");
+}
+
+#[test]
+fn test_dedup_line_store() {
+    let repo = TempRepo::new("dedup");
+    let header: String = (0..50).map(|i| format!("// license line {}\n", i)).collect();
+    repo.write("a.rs", &format!("{}fn a() {{}}\n", header));
+    repo.write("b.rs", &format!("{}fn b() {{}}\n", header));
+    repo.h2(&["init", "--dedup"]);
+    repo.h2(&["commit"]);
+
+    // the revision keeps lists of lines, and the header is only stored once
+    assert!(repo.read(".h2/revs/1/tree/a.rs").starts_with("\0h2lines\n"));
+    assert!(repo.read(".h2/revs/1/tree/b.rs").starts_with("\0h2lines\n"));
+    let blob = repo.read(".h2/lines/blob");
+    assert_eq!(blob.matches("license line 7\n").count(), 1);
+    assert_eq!(repo.h2(&["show", "1:a.rs"]), format!("{}fn a() {{}}\n", header));
+    assert_eq!(repo.h2(&["show", "1:b.rs"]), format!("{}fn b() {{}}\n", header));
+
+    // a copy without a line store gets the files whole
+    let copy = TempRepo::new("dedup-copy");
+    copy.h2(&["init"]);
+    copy.h2(&["pull", repo.root.to_str().unwrap()]);
+    assert_eq!(copy.read(".h2/revs/1/tree/b.rs"), format!("{}fn b() {{}}\n", header));
+    repo.h2(&["verify"]);
+
+    let sealed = TempRepo::new("dedup-sealed");
+    assert!(sealed.h2_fails(&["init", "--dedup", "--encrypt"]).contains("can't share lines"));
+}
+
+#[test]
+fn test_commit_message() {
+    let config = "author_name = Ada\nauthor_email = ada@example.com\n";
+    let message = "first draft\n\nwith the details";
+    let repo = TempRepo::new("commit-message");
+    repo.write("a.txt", "first\n");
+    repo.h2(&["init"]);
+    repo.write(".h2/config", config);
+    repo.h2(&["commit", "-m", message]);
+
+    let log = repo.h2(&["log"]);
+    assert!(log.starts_with("1 "));
+    assert!(log.ends_with(" (HEAD) - Ada <ada@example.com>: first draft\n"));
+    let hash = log.split(' ').nth(2).unwrap().to_string();
+    assert_eq!(hash.len(), 16);
+    assert_eq!(repo.h2(&["show", &format!("{}:a.txt", &hash[..8])]), "first\n");
+
+    // when it was committed isn't part of the hash, so the same commit
+    // made again elsewhere hashes the same
+    let again = TempRepo::new("commit-message-again");
+    again.write("a.txt", "first\n");
+    again.h2(&["init"]);
+    again.write(".h2/config", config);
+    again.h2(&["commit", "-m", message]);
+    assert_eq!(again.h2(&["log"]).split(' ').nth(2), Some(&hash[..]));
+
+    assert!(repo.h2_fails(&["commit", "-m"]).contains("Usage"));
+}
+
+#[test]
+fn test_log_graph() {
+    let repo = TempRepo::new("log");
+    repo.write("a.txt", "first\n");
+    repo.h2(&["init"]);
+    assert_eq!(repo.h2(&["log", "--graph"]), "");
+    repo.h2(&["commit"]);
+    repo.write("a.txt", "second\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["tag", "v1", "1"]);
+
+    let log = repo.h2(&["log", "--graph"]);
+    let log = lines(&log);
+    assert_eq!(log.len(), 2);
+    assert!(log[0].starts_with("* 2 ") && log[0].ends_with(" (HEAD)"));
+    assert!(log[1].starts_with("* 1 ") && log[1].ends_with(" (tag: v1)"));
+    assert!(repo.h2(&["log"]).starts_with("2 "));
+}
+
+#[test]
+fn test_branches() {
+    let repo = TempRepo::new("branches");
+    repo.write("a.txt", "first\n");
+    repo.h2(&["init"]);
+    assert_eq!(repo.h2(&["branch"]), "* main -\n");
+    repo.h2(&["commit"]);
+    repo.h2(&["branch", "topic"]);
+
+    repo.write("a.txt", "second\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    assert_eq!(repo.h2(&["branch"]), "* main 2\n  topic 1\n");
+
+    // commits on topic don't reuse the id main took
+    assert_eq!(repo.h2(&["switch", "topic"]), "Switched to branch topic at revision 1\n");
+    assert_eq!(repo.read("a.txt"), "first\n");
+    repo.write("b.txt", "topic\n");
+    repo.h2(&["add", "b.txt"]);
+    repo.h2(&["commit"]);
+    assert_eq!(repo.h2(&["branch"]), "  main 2\n* topic 3\n");
+    assert_eq!(repo.h2(&["show", "topic:b.txt"]), "topic\n");
+
+    repo.h2(&["switch", "main"]);
+    assert_eq!(repo.read("a.txt"), "second\n");
+    assert!(!repo.exists("b.txt"));
+    assert_eq!(repo.h2(&["status"]), "");
+
+    // nothing that isn't committed is ever lost
+    repo.write("a.txt", "changed\n");
+    repo.h2_fails(&["switch", "topic"]);
+    repo.write("a.txt", "second\n");
+    repo.write("b.txt", "untracked\n");
+    repo.h2_fails(&["switch", "topic"]);
+    assert_eq!(repo.read("b.txt"), "untracked\n");
+    repo.h2_fails(&["switch", "missing"]);
+}
+
+#[test]
+fn test_merge() {
+    let repo = TempRepo::new("merge");
+    repo.write("a.txt", "one\ntwo\nthree\n");
+    repo.write("b.txt", "bee\n");
+    repo.h2(&["init"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["branch", "topic"]);
+
+    repo.write("a.txt", "ONE\ntwo\nthree\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["switch", "topic"]);
+    repo.write("a.txt", "one\ntwo\nTHREE\n");
+    repo.write("c.txt", "new\n");
+    repo.h2(&["add", "a.txt", "c.txt"]);
+    repo.h2(&["rm", "b.txt"]);
+    repo.h2(&["commit"]);
+
+    repo.h2(&["switch", "main"]);
+    assert_eq!(repo.h2(&["merge", "topic"]), "Merged topic as revision 4\n");
+    assert_eq!(repo.read("a.txt"), "ONE\ntwo\nTHREE\n");
+    assert_eq!(repo.read("c.txt"), "new\n");
+    assert!(!repo.exists("b.txt"));
+    assert_eq!(repo.h2(&["status"]), "");
+    assert!(repo.read(".h2/revs/4/meta").contains("\"merged\":3"));
+    assert_eq!(repo.h2(&["merge", "topic"]), "Already up to date with topic\n");
+
+    // both changing the same line leaves it to be resolved
+    repo.write("a.txt", "ONE\ntwo\nmain\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["switch", "topic"]);
+    repo.write("a.txt", "one\ntwo\ntopic\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["switch", "main"]);
+    assert_eq!(repo.h2(&["merge", "topic"]),
+               "conflict a.txt\nMerged topic with 1 conflicts, resolve them, add them and commit\n");
+    assert_eq!(repo.read("a.txt"), "ONE\ntwo\n<<<<<<< main\nmain\n=======\ntopic\n>>>>>>> topic\n");
+    repo.h2_fails(&["switch", "topic"]);
+    repo.write("a.txt", "ONE\ntwo\nboth\n");
+    repo.h2(&["add", "a.txt"]);
+    assert_eq!(repo.h2(&["commit"]), "Committed revision 7\n");
+    assert!(repo.read(".h2/revs/7/meta").contains("\"merged\":6"));
+    assert!(!repo.exists(".h2/refs/MERGE_HEAD"));
+}
+
+#[test]
+fn test_stash() {
+    let repo = TempRepo::new("stash");
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("b.txt", "bee\n");
+    repo.h2(&["init"]);
+    repo.h2(&["commit"]);
+    repo.h2_fails(&["stash"]);
+
+    repo.write("a.txt", "one\nTWO\nthree");
+    repo.write("c.txt", "new\n");
+    repo.h2(&["add", "c.txt"]);
+    ::std::fs::remove_file(repo.path("b.txt")).unwrap();
+    repo.write("untracked.txt", "left alone\n");
+    assert_eq!(repo.h2(&["stash"]), "Stashed changes to 3 files as entry 1\n");
+    assert_eq!(repo.read("a.txt"), "one\ntwo\n");
+    assert_eq!(repo.read("b.txt"), "bee\n");
+    assert!(!repo.exists("c.txt"));
+    assert_eq!(repo.read("untracked.txt"), "left alone\n");
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["? untracked.txt"]);
+    assert_eq!(repo.h2(&["status", "--head"]), "Stage matches revision 1\n");
+
+    // the entry goes back on top of whatever was committed since
+    repo.write("b.txt", "bee\nsee\n");
+    repo.h2(&["add", "b.txt"]);
+    repo.h2_fails(&["stash", "pop"]);
+    repo.write("b.txt", "bee\n");
+    repo.h2(&["add", "b.txt"]);
+    repo.write("a.txt", "zero\none\ntwo\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    assert_eq!(repo.h2(&["stash", "pop"]), "Applied stash entry 1 to 3 files\n");
+    assert_eq!(repo.read("a.txt"), "zero\none\nTWO\nthree");
+    assert!(!repo.exists("b.txt"));
+    assert_eq!(repo.read("c.txt"), "new\n");
+    assert!(!repo.exists(".h2/stash/1"));
+    repo.h2_fails(&["stash", "pop"]);
+}
+
+#[test]
+fn test_autosnap() {
+    let repo = TempRepo::new("autosnap");
+    repo.write("a.txt", "one\n");
+    repo.write("b.txt", "bee\n");
+    repo.h2(&["init"]);
+    repo.h2(&["commit"]);
+    assert_eq!(repo.h2(&["autosnap", "--interval", "0s"]), "No snapshot is due\n");
+
+    // too soon after the last revision, unless enough files changed
+    repo.write("a.txt", "one\ntwo\n");
+    assert_eq!(repo.h2(&["autosnap", "--interval", "1h"]), "No snapshot is due\n");
+    assert_eq!(repo.h2(&["autosnap", "--interval", "1h", "--changes", "1"]), "Snapshotted revision 2\n");
+
+    // deletions are snapshotted too, untracked files aren't
+    ::std::fs::remove_file(repo.path("b.txt")).unwrap();
+    repo.write("new.txt", "untracked\n");
+    assert_eq!(repo.h2(&["autosnap", "--interval", "0s"]), "Snapshotted revision 3\n");
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["? new.txt"]);
+    assert_eq!(repo.h2(&["show", "3:a.txt"]), "one\ntwo\n");
+    repo.h2_fails(&["show", "3:b.txt"]);
+    repo.h2_fails(&["autosnap", "--interval", "soon"]);
+
+    let marked: Vec<bool> = lines(&repo.h2(&["log"])).iter().map(|line| line.contains("[auto]")).collect();
+    assert_eq!(marked, vec![true, true, false]);
+
+    // automatic snapshots past their own limit go, head and commits by hand stay
+    assert_eq!(repo.h2(&["prune", "--keep-auto", "0"]), "Removed 1 revisions\n");
+    assert_eq!(lines(&repo.h2(&["log"])).len(), 2);
+}
+
+#[test]
+fn test_tree_hash() {
+    let repo = TempRepo::new("tree-hash");
+    repo.write("a.txt", "first\n");
+    repo.h2(&["init"]);
+    repo.h2(&["commit"]);
+    assert_eq!(repo.h2(&["status", "--head"]), "Stage matches revision 1\n");
+
+    repo.write("a.txt", "second\n");
+    repo.h2(&["add", "a.txt"]);
+    assert_eq!(repo.h2(&["status", "--head"]), "Stage differs from revision 1\n");
+    assert_eq!(repo.run(&["status", "--head", "--check"]).status.code(), Some(1));
+    repo.h2(&["commit"]);
+
+    // the same content again hashes the same, and the hash names a revision
+    repo.write("a.txt", "first\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    let first = repo.read(".h2/revs/1/meta");
+    let third = repo.read(".h2/revs/3/meta");
+    let hash = &first[first.find("\"tree_hash\":\"").unwrap() + 13..][..16];
+    assert!(third.contains(hash));
+    assert_eq!(repo.h2(&["show", &format!("{}:a.txt", &hash[..8])]), "first\n");
+}
+
+#[test]
+fn test_push_pull() {
+    let origin = TempRepo::new("sync-origin");
+    origin.write("a.txt", "first\n");
+    origin.h2(&["init"]);
+    origin.h2(&["commit"]);
+    origin.write("a.txt", "first\nsecond\n");
+    origin.h2(&["add", "a.txt"]);
+    origin.h2(&["commit"]);
+    let origin_path = origin.root.to_str().unwrap();
+
+    let copy = TempRepo::new("sync-copy");
+    copy.h2(&["init"]);
+    assert_eq!(copy.h2(&["pull", origin_path]), "Pulled 2 revisions and 0 chunks, head is now revision 2\n");
+    assert_eq!(copy.h2(&["show", "HEAD:a.txt"]), "first\nsecond\n");
+    assert_eq!(copy.h2(&["show", "1:a.txt"]), "first\n");
+    assert_eq!(copy.h2(&["pull", origin_path]), "Already up to date\n");
+
+    // only what's new goes across, either way
+    copy.write("b.txt", "copied\n");
+    copy.h2(&["add", "b.txt"]);
+    copy.h2(&["commit"]);
+    assert_eq!(copy.h2(&["push", "--dry-run", origin_path]), "Would send 1 revisions and 0 chunks\n");
+    assert_eq!(origin.read(".h2/revs/HEAD"), "2\n");
+    assert_eq!(copy.h2(&["push", origin_path]), "Pushed 1 revisions and 0 chunks, head is now revision 3\n");
+    assert_eq!(origin.h2(&["show", "HEAD:b.txt"]), "copied\n");
+
+    // once both commit on their own neither can take the other's
+    origin.h2(&["commit"]);
+    copy.h2(&["commit"]);
+    assert!(copy.h2_fails(&["push", origin_path]).contains("diverged at revision 4"));
+    assert!(copy.h2_fails(&["push", "host:repo"]).contains("not supported"));
+}
+
+#[test]
+fn test_export_import() {
+    let repo = TempRepo::new("archive");
+    let big: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("big.txt", &big);
+    repo.h2(&["init", "--backend", "packed", "--dedup"]);
+    repo.h2(&["commit"]);
+    let archive = repo.path("repo.h2a");
+    let archive = archive.to_str().unwrap();
+    assert!(repo.h2(&["export", archive]).starts_with("Exported "));
+
+    // the packs and manifest come back rebuilt, with every index found
+    let copy = TempRepo::new("archive-copy");
+    assert!(copy.h2(&["import", archive]).starts_with("Imported "));
+    assert_eq!(files_under(copy.path(".h2/packs")), vec![PathBuf::from("1")]);
+    copy.write("a.txt", "one\ntwo\n");
+    copy.write("big.txt", &format!("{}more\n", big));
+    assert_eq!(lines(&copy.h2(&["status"])), vec!["M big.txt"]);
+    assert!(copy.h2(&["diff"]).contains("+more\n"));
+    assert_eq!(copy.h2(&["show", "1:a.txt"]), "one\ntwo\n");
+    copy.h2(&["verify"]);
+
+    // one from an older version is migrated as it's imported
+    let old = TempRepo::new("archive-old");
+    old.write("a.txt", "one\n");
+    old.h2(&["init"]);
+    old.write(".h2/version", "19\n");
+    let old_archive = old.path("old.h2a");
+    half2::archive::export_repo(&half2::repo::Repo::new(old.root.clone()), &old_archive).unwrap();
+    let migrated = TempRepo::new("archive-migrated");
+    migrated.h2(&["import", old_archive.to_str().unwrap()]);
+    assert_eq!(migrated.read(".h2/version"), "22\n");
+    migrated.write("a.txt", "one\n");
+    assert_eq!(migrated.h2(&["status"]), "");
+}
+
+#[test]
+fn test_import_git() {
+    let source = TempRepo::new("git-source");
+    let git = |args: &[&str]| {
+        ::std::process::Command::new("git").args(args).current_dir(&source.root)
+            .env("GIT_AUTHOR_NAME", "t").env("GIT_AUTHOR_EMAIL", "t@t")
+            .env("GIT_COMMITTER_NAME", "t").env("GIT_COMMITTER_EMAIL", "t@t").output()
+    };
+    match git(&["init", "-q"]) {
+        Ok(output) => assert!(output.status.success()),
+        // nothing to import from without git
+        Err(_) => return
+    }
+    let git = |args: &[&str]| assert!(git(args).unwrap().status.success());
+    source.write("a.txt", "one\n");
+    source.write("old.txt", "going\n");
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "first"]);
+    source.write("a.txt", "one\ntwo\n");
+    ::std::fs::remove_file(source.path("old.txt")).unwrap();
+    source.write("dir/b.txt", "new\n");
+    git(&["add", "-A", "."]);
+    git(&["commit", "-q", "-m", "second"]);
+
+    let repo = TempRepo::new("git-import");
+    let output = repo.h2(&["import-git", source.root.to_str().unwrap()]);
+    assert_eq!(lines(&output).len(), 3);
+    assert!(output.ends_with("Imported 2 commits\n"));
+    assert_eq!(repo.h2(&["show", "1:old.txt"]), "going\n");
+    assert_eq!(repo.h2(&["show", "HEAD:a.txt"]), "one\ntwo\n");
+    assert!(repo.h2_fails(&["show", "HEAD:old.txt"]).contains("does not exist"));
+    assert_eq!(repo.read("dir/b.txt"), "new\n");
+    assert!(!repo.exists("old.txt"));
+    assert_eq!(repo.h2(&["status"]), "");
+    assert!(repo.h2_fails(&["import-git", source.root.to_str().unwrap()]).contains("empty directory"));
+}
+
+#[test]
+fn test_export_git() {
+    let repo = TempRepo::new("git-export");
+    repo.write("a.txt", "one\n");
+    repo.write("old.txt", "going\n");
+    repo.h2(&["init"]);
+    repo.h2(&["commit"]);
+    repo.h2(&["tag", "first"]);
+    repo.write("a.txt", "one\ntwo\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["rm", "old.txt"]);
+    repo.h2(&["commit"]);
+
+    let stream = repo.h2(&["export-git", "--committer", "T <t@t>"]);
+    assert!(stream.starts_with("commit refs/heads/master\nmark :1\ncommitter T <t@t> "));
+    assert!(stream.contains("deleteall\nM 100644 inline a.txt\ndata 4\none\n\n"));
+    assert!(stream.contains("from :1\nD old.txt\nM 100644 inline a.txt\ndata 8\none\ntwo\n\n"));
+    assert!(stream.ends_with("reset refs/tags/first\nfrom :1\n\n"));
+
+    // a range picks up after where an earlier export stopped
+    let later = repo.h2(&["export-git", "first..HEAD", "--branch", "journal"]);
+    assert!(later.starts_with("commit refs/heads/journal\nmark :2\n"));
+    assert!(!later.contains("from :"));
+    assert!(!later.contains("refs/tags/first"));
+}
+
+#[test]
+fn test_inspect_commands() {
+    let repo = TempRepo::new("inspect");
+    repo.write("a.txt", "one\ntwo\none\n");
+    repo.h2(&["init"]);
+    repo.write("a.txt", "changed\n");
+
+    assert_eq!(repo.h2(&["cat-stage", "a.txt"]), "one\ntwo\none\n");
+    assert_eq!(repo.h2(&["cat-stage", "--raw", "a.txt"]), "one\ntwo\none\n");
+    assert!(repo.h2_fails(&["cat-stage", "b.txt"]).contains("not staged"));
+
+    let meta = repo.h2(&["cat-meta", "a.txt"]);
+    assert!(meta.contains("\"node_count\": 3"));
+    let dump = repo.h2(&["dump-index", "a.txt"]);
+    let dump = lines(&dump);
+    assert!(dump[0].starts_with("index of a.txt: 3 lines, fnv hasher"));
+    // two distinct lines, one of them in two places
+    assert_eq!(dump.iter().filter(|line| line.ends_with(" 0:+0 2:+0")).count(), 1);
+    assert_eq!(dump.iter().filter(|line| line.ends_with(" 1:+0")).count(), 1);
+}
+
+#[test]
+fn test_dry_run_writes_nothing() {
+    let repo = TempRepo::new("dry-run");
+    repo.write("a.txt", "one\n");
+    repo.h2(&["init", "--dry-run"]);
+    assert!(!repo.exists(".h2"));
+}
+
+#[test]
+fn test_exit_codes() {
+    let repo = TempRepo::new("exit-codes");
+    assert_eq!(repo.run(&["status"]).status.code(), Some(3));
+    let message = repo.h2_fails(&["status"]);
+    assert!(message.starts_with("h2: Status failed: "));
+    assert_eq!(lines(&message).len(), 1);
+    assert!(lines(&repo.h2_fails(&["status", "--verbose"])).len() > 1);
+
+    repo.write("a.txt", "one\n");
+    repo.h2(&["init"]);
+    assert_eq!(repo.run(&["add"]).status.code(), Some(2));
+    assert_eq!(repo.run(&["status", "--check"]).status.code(), Some(0));
+    assert_eq!(repo.run(&["diff", "--check"]).status.code(), Some(0));
+
+    repo.write("a.txt", "two\n");
+    assert_eq!(repo.run(&["status"]).status.code(), Some(0));
+    assert_eq!(repo.run(&["status", "--check"]).status.code(), Some(1));
+    assert_eq!(repo.run(&["diff", "--check"]).status.code(), Some(1));
+}
+
+#[test]
+fn test_verbosity_flags() {
+    let repo = TempRepo::new("verbosity-flags");
+    repo.write("a.txt", "one\n");
+    repo.h2(&["init"]);
+    repo.write("a.txt", "two\n");
+
+    // the flags go before the command and leave its output alone
+    let output = repo.run(&["-v", "status"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "M a.txt\n");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Comparing the checkout with the stage"));
+    let output = repo.run(&["-vv", "status"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "M a.txt\n");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("DEBUG"));
+    let output = repo.run(&["-q", "status"]);
+    assert!(output.stderr.is_empty());
+    // after the command they're its own
+    let output = repo.run(&["status", "-v"]);
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with(" M a.txt | "));
+    assert!(output.stderr.is_empty());
+    // and the quiet failure is still reported
+    assert_eq!(lines(&repo.h2_fails(&["-q", "show", "12"])).len(), 1);
+
+    let output = repo.run(&["--timings", "status"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "M a.txt\n");
+    let timings = String::from_utf8(output.stderr).unwrap();
+    assert!(lines(&timings).iter().any(|line| line.trim().starts_with("hash: ")));
+    assert!(lines(&timings).iter().any(|line| line.trim().starts_with("total: ")));
+}
+
+#[test]
+fn test_repo_dir() {
+    let repo = TempRepo::new("repo-dir");
+    let store = TempRepo::new("repo-dir-store");
+    repo.write("a.txt", "one\n");
+    let dir = store.path("h2");
+    repo.h2(&["init", "--repo-dir", dir.to_str().unwrap()]);
+
+    // the checkout only gets a pointer, which later commands follow
+    assert_eq!(repo.read(".h2"), format!("h2dir: {}\n", dir.display()));
+    assert_eq!(store.read("h2/stage/a.txt"), "one\n");
+    repo.write("a.txt", "two\n");
+    assert_eq!(lines(&repo.h2(&["status"])), vec!["M a.txt"]);
+    repo.h2(&["add", "a.txt"]);
+    assert_eq!(store.read("h2/stage/a.txt"), "two\n");
+    assert!(repo.h2_fails(&["init"]).contains("already exists"));
+}
+
+#[test]
+fn test_checked_copies() {
+    let repo = TempRepo::new("checked-copies");
+    repo.write("a.txt", "one\n");
+    repo.h2(&["init"]);
+    repo.write(".h2/config", "check_copies = on\n");
+    repo.write("a.txt", "two\n");
+    repo.h2(&["add", "a.txt"]);
+    assert!(repo.h2(&["verify"]).contains("ok   a.txt (staged)\n"));
+
+    // a staged copy that changes afterwards no longer matches its hash
+    repo.write(".h2/stage/a.txt", "tampered\n");
+    let output = repo.run(&["verify"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("FAIL a.txt (staged)"));
+}
+
+#[test]
+fn test_doctor() {
+    let repo = TempRepo::new("doctor");
+    repo.write("a.txt", "one\ntwo\n");
+    repo.write("b.txt", "three\n");
+    repo.write("c.txt", "four\n");
+    repo.h2(&["init"]);
+    assert_eq!(repo.h2(&["doctor"]), "3 checked, 0 disagree, 0 resolved\n");
+
+    // a staged copy edited by hand and one that's gone both come back from
+    // the checkout, which still matches their indexes
+    repo.write(".h2/stage/a.txt", "edited\n");
+    ::std::fs::remove_file(repo.path(".h2/stage/b.txt")).unwrap();
+    let output = repo.run(&["doctor"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(lines(&String::from_utf8_lossy(&output.stdout)),
+               vec!["stale     a.txt: the index doesn't match the staged copy; \
+                     stage the checkout again, it matches the index",
+                    "unstaged  b.txt: indexed with nothing staged; stage the checkout again, it matches the index",
+                    "3 checked, 2 disagree, 0 resolved"]);
+    assert!(lines(&repo.h2(&["doctor", "--fix"])).contains(&"3 checked, 2 disagree, 2 resolved"));
+    assert_eq!(repo.read(".h2/stage/a.txt"), "one\ntwo\n");
+    assert_eq!(repo.read(".h2/stage/b.txt"), "three\n");
+    assert_eq!(repo.h2(&["doctor"]), "3 checked, 0 disagree, 0 resolved\n");
+
+    // when the checkout has moved on too, the staged copy is indexed again
+    // unless the working copy is asked for
+    repo.write(".h2/stage/c.txt", "staged\n");
+    repo.write("c.txt", "working\n");
+    assert_eq!(lines(&String::from_utf8_lossy(&repo.run(&["doctor", "c.txt"]).stdout))[0],
+               "stale     c.txt: the index doesn't match the staged copy; rebuild the index from the stage");
+    repo.h2(&["doctor", "--keep-working", "c.txt"]);
+    assert_eq!(repo.read(".h2/stage/c.txt"), "working\n");
+    assert_eq!(repo.h2(&["status"]), "");
+}
+
+#[test]
+fn test_migrate() {
+    let repo = TempRepo::new("migrate");
+    repo.h2(&["init"]);
+    assert_eq!(repo.h2(&["migrate"]), "Repository is already at format version 22\n");
+
+    // an empty repository's trees are only headers, written as version 11 would have
+    repo.write(".h2/version", "11\n");
+    assert!(repo.h2_fails(&["status"]).contains("h2 migrate"));
+    assert_eq!(repo.h2(&["migrate", "--dry-run"]),
+               "Would migrate 11 to 12: record item counts in tree headers\n\
+                Would migrate 12 to 13: allow inline indexes for small files\n\
+                Would migrate 13 to 14: allow stage copy hashes in the manifest\n\
+                Would migrate 14 to 15: record diff drivers in index metas\n\
+                Would migrate 15 to 16: record merge parents in revision metas\n\
+                Would migrate 16 to 17: record line length caps in index metas\n\
+                Would migrate 17 to 18: mark automatic snapshots in revision metas\n\
+                Would migrate 18 to 19: record directories in the manifest\n\
+                Would migrate 19 to 20: record authors, messages and hashes in revision metas\n\
+                Would migrate 20 to 21: bind sealed files to their place and seal the stage and stash\n\
+                Would migrate 21 to 22: replace the key check with an hmac of the key\n");
+    assert_eq!(repo.read(".h2/version"), "11\n");
+    assert_eq!(repo.h2(&["migrate"]),
+               "Migrated 11 to 12: record item counts in tree headers\n\
+                Migrated 12 to 13: allow inline indexes for small files\n\
+                Migrated 13 to 14: allow stage copy hashes in the manifest\n\
+                Migrated 14 to 15: record diff drivers in index metas\n\
+                Migrated 15 to 16: record merge parents in revision metas\n\
+                Migrated 16 to 17: record line length caps in index metas\n\
+                Migrated 17 to 18: mark automatic snapshots in revision metas\n\
+                Migrated 18 to 19: record directories in the manifest\n\
+                Migrated 19 to 20: record authors, messages and hashes in revision metas\n\
+                Migrated 20 to 21: bind sealed files to their place and seal the stage and stash\n\
+                Migrated 21 to 22: replace the key check with an hmac of the key\n");
+    assert_eq!(repo.read(".h2/version"), "22\n");
+    assert!(!repo.exists(".h2/migration"));
+    assert_eq!(repo.h2(&["status"]), "");
+
+    // from before there was a version file at all
+    ::std::fs::remove_file(repo.path(".h2/version")).unwrap();
+    assert!(repo.h2_fails(&["status"]).contains("format version 1 is older"));
+}
+
+#[test]
+fn test_apply_hostile_patch() {
+    let repo = TempRepo::new("apply-hostile");
+    repo.write("a.txt", "one\n");
+    repo.h2(&["init"]);
+
+    // a file created next to the checkout, and the repository's own version
+    // deleted
+    let outside = "../h2-it-apply-hostile-outside.txt";
+    repo.write("out.patch", &format!("--- /dev/null\n+++ {}\n@@ -0,0 +1 @@\n+owned\n", outside));
+    assert!(repo.h2_fails(&["apply", "out.patch"]).contains("outside the checkout"));
+    assert!(!repo.exists(outside));
+    repo.write("in.patch", "--- a/.h2/version\n+++ /dev/null\n@@ -1 +0,0 @@\n-22\n");
+    assert!(repo.h2_fails(&["apply", "in.patch"]).contains("inside a repository"));
+    assert_eq!(repo.read(".h2/version"), "22\n");
+}
+
+#[test]
+fn test_blame_follows_edits() {
+    let repo = TempRepo::new("blame");
+    repo.write("a.txt", "a\nb\nc\n");
+    repo.h2(&["init"]);
+    repo.h2(&["commit"]);
+    // c moves to the top, which the diff sees as deleted and inserted again
+    repo.write("a.txt", "c\na\nb\nx\n");
+    repo.h2(&["add", "a.txt"]);
+    repo.h2(&["commit"]);
+    repo.write("a.txt", "c\na\nb\nx\na\n");
+
+    let revs: Vec<String> = lines(&repo.h2(&["blame", "a.txt"])).iter()
+        .map(|line| line.split_whitespace().next().unwrap().to_string()).collect();
+    assert_eq!(revs, vec!["2", "1", "1", "2", "working"]);
+}
+
+#[test]
+fn test_encryption_seals_stage_stash_and_undo() {
+    use std::io::Read;
+
+    let keys = TempRepo::new("sealed-keys");
+    keys.write("key", &vec!["ab"; 32].concat());
+    let repo = TempRepo::new("sealed");
+    repo.write("a.txt", "secret one\n");
+    repo.h2(&["init", "--key-file", keys.path("key").to_str().unwrap()]);
+    repo.h2(&["commit"]);
+    let in_clear = |path: &str| {
+        let mut data = vec![];
+        ::std::fs::File::open(repo.path(path)).and_then(|mut f| f.read_to_end(&mut data)).unwrap();
+        data.windows(6).any(|w| w == b"secret")
+    };
+    assert!(!in_clear(".h2/stage/a.txt"));
+
+    // the old staged copy undo keeps is sealed too, and still put back
+    repo.write("a.txt", "secret two\n");
+    repo.h2(&["add", "a.txt"]);
+    assert!(!in_clear(".h2/stage/a.txt"));
+    assert!(!in_clear(".h2/undo/stage/a.txt"));
+    repo.h2(&["undo"]);
+    assert_eq!(repo.h2(&["status", "--head"]), "Stage matches revision 1\n");
+
+    repo.write("a.txt", "secret three\n");
+    assert_eq!(repo.h2(&["stash"]), "Stashed changes to 1 files as entry 1\n");
+    assert!(!in_clear(".h2/stash/1"));
+    assert_eq!(repo.read("a.txt"), "secret one\n");
+    repo.h2(&["stash", "pop"]);
+    assert_eq!(repo.read("a.txt"), "secret three\n");
+
+    // a staged copy moved to another path doesn't open there
+    ::std::fs::copy(repo.path(".h2/stage/a.txt"), repo.path(".h2/stage/b.txt")).unwrap();
+    repo.write("b.txt", "secret one\n");
+    assert!(repo.h2_fails(&["diff"]).contains("Failed to open"));
+}
+
+#[test]
+fn test_migrate_key_check() {
+    let keys = TempRepo::new("key-check-keys");
+    keys.write("key", &vec!["ab"; 32].concat());
+    let repo = TempRepo::new("key-check");
+    repo.write("a.txt", "secret\n");
+    repo.h2(&["init", "--key-file", keys.path("key").to_str().unwrap()]);
+    repo.h2(&["commit"]);
+    let hmac_check = "key_check = eb53a4ffd0b34f07a74bce72e2c06928b836be67f68ab5d8202e73cfe20da6f4";
+    assert!(repo.read(".h2/config").contains(hmac_check));
+
+    // version 21 kept a block of keystream as the check, it still opens
+    // the repository for the migration and is replaced by the hmac
+    let config = repo.read(".h2/config").replace(hmac_check, "key_check = c5e0908b39c067c4f43092b64c486ec4");
+    repo.write(".h2/config", &config);
+    repo.write(".h2/version", "21\n");
+    assert_eq!(repo.h2(&["migrate"]), "Migrated 21 to 22: replace the key check with an hmac of the key\n");
+    assert!(repo.read(".h2/config").contains(hmac_check));
+    assert_eq!(repo.h2(&["status", "--head"]), "Stage matches revision 1\n");
+}
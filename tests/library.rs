@@ -0,0 +1,392 @@
+// the library used directly, with every path given explicitly rather than
+// taken from the working directory
+#![feature(path_relative_from)]
+
+extern crate half2;
+
+mod support;
+
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+
+use half2::api::*;
+use half2::synth::*;
+use half2::revs::*;
+use half2::ignore::*;
+use half2::instrument::*;
+use half2::lock::*;
+use half2::platform::*;
+use half2::drivers::*;
+use half2::verify::*;
+use half2::{Checkout, Logs, PathInfo, Stage, Repository, stage_dir_all, diff_dir_all};
+
+use support::*;
+
+fn small_spec() -> SynthSpec {
+    SynthSpec {
+        dirs: 3,
+        files_per_dir: 4,
+        lines_per_file: 50,
+        line_len: 30,
+        seed: 7
+    }
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let source = TempRepo::new("api-source");
+    let store = TempRepo::new("api-store");
+    let restored = TempRepo::new("api-restored");
+    let spec = small_spec();
+    assert_eq!(source.synthesize(&spec), 12);
+
+    let options = SnapshotOptions::default();
+    let repo_dir = store.path("repo");
+    assert_eq!(snapshot(&source.root, &repo_dir, &options).unwrap(), 1);
+    assert_eq!(mutate_checkout(&source.root, &spec, 5).unwrap(), 3);
+    assert_eq!(snapshot(&source.root, &repo_dir, &options).unwrap(), 2);
+    assert_eq!(Revisions::new(repo_dir.join("revs")).list().unwrap(), vec![1, 2]);
+
+    // the newest snapshot comes back exactly as the source is now
+    restore(&repo_dir, 2, restored.path("2")).unwrap();
+    let files = files_under(&source.root);
+    assert_eq!(files_under(restored.path("2")), files);
+    for file in files.iter() {
+        assert_eq!(restored.read(PathBuf::from("2").join(file)), source.read(file));
+    }
+
+    // and the first as it was before the mutation
+    restore(&repo_dir, 1, restored.path("1")).unwrap();
+    let changed = files.iter().filter(|file| restored.read(PathBuf::from("1").join(file)) != source.read(file)).count();
+    assert_eq!(changed, 3);
+}
+
+#[test]
+fn test_stage_and_index() {
+    let checkout_dir = TempRepo::new("library-checkout");
+    checkout_dir.write("a.txt", "one\ntwo\n");
+    checkout_dir.write("sub/b.txt", "three\n");
+    checkout_dir.write("skip/c.txt", "ignored\n");
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs"));
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2"), PathBuf::from("skip")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    assert_eq!(files_under(&h2.join("stage")), vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")]);
+    assert_eq!(stage.read_path("sub/b.txt").unwrap(), b"three\n".to_vec());
+    assert!(logs.is_indexed(&PathBuf::from("a.txt")).unwrap());
+    assert!(!logs.is_indexed(&PathBuf::from("skip/c.txt")).unwrap());
+
+    let tracked: Vec<(PathBuf, usize)> = logs.tracked_paths().unwrap()
+        .map(|tracked| tracked.map(|(id, meta)| (id, meta.node_count)).unwrap()).collect();
+    assert_eq!(tracked, vec![(PathBuf::from("a.txt"), 2), (PathBuf::from("sub/b.txt"), 1)]);
+}
+
+#[test]
+fn test_probe_limit() {
+    let checkout_dir = TempRepo::new("library-probe-limit");
+    let blank_lines: String = (0..20).map(|i| if i % 2 == 0 {"\n".to_string()} else {format!("{}\n", i)}).collect();
+    checkout_dir.write("a.txt", &blank_lines);
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs")).with_probe_limit(3);
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    // ten blank lines are more than the limit, so each one is matched by position
+    checkout_dir.write("a.txt", &format!("start\n{}", blank_lines));
+    let before = counter_value(Counter::ProbeLimitHits);
+    diff_dir_all(&checkout, &logs.with_stat_cache(false), PathBuf::from("."), &ignore).unwrap();
+    assert!(counter_value(Counter::ProbeLimitHits) >= before + 10);
+}
+
+#[test]
+fn test_inline_index() {
+    let checkout_dir = TempRepo::new("library-inline-index");
+    let big: String = (0..20).map(|i| format!("line {}\n", i)).collect();
+    checkout_dir.write("small.txt", "one\ntwo\none\n");
+    checkout_dir.write("big.txt", &big);
+    checkout_dir.write("same.txt", "same\n");
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs")).with_inline_limit(64);
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    // only the file over the limit gets a tree of its own
+    assert!(!checkout_dir.exists(".h2/logs/small.txt/content"));
+    assert!(checkout_dir.exists(".h2/logs/big.txt/content"));
+
+    // and changes are found in either
+    checkout_dir.write("small.txt", "one\ntwo\nthree\n");
+    checkout_dir.write("big.txt", &format!("{}extra\n", big));
+    let changed = diff_dir_all(&checkout, &logs.with_stat_cache(false), PathBuf::from("."), &ignore).unwrap();
+    assert_eq!(changed, vec![PathBuf::from("big.txt"), PathBuf::from("small.txt")]);
+}
+
+#[test]
+fn test_max_line_length() {
+    let checkout_dir = TempRepo::new("library-max-line-length");
+    let long: String = (0..100).map(|i| format!("{}", i % 10)).collect();
+    checkout_dir.write("a.txt", &format!("short\n{}\nend\n", long));
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs")).with_max_line_length(16);
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    // the long line is indexed as seven blocks, and the file staged whole
+    let tracked: Vec<usize> = logs.tracked_paths().unwrap().map(|tracked| tracked.unwrap().1.node_count).collect();
+    assert_eq!(tracked, vec![9]);
+    assert_eq!(stage.read_path("a.txt").unwrap(), checkout_dir.read("a.txt").into_bytes());
+
+    // the index keeps its cap, whatever the logs are given later
+    let logs = logs.with_max_line_length(1024).with_stat_cache(false);
+    assert!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap().is_empty());
+    checkout_dir.write("a.txt", &format!("short\n{}x\nend\n", long));
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(), vec![PathBuf::from("a.txt")]);
+}
+
+#[test]
+fn test_edge_files() {
+    // an empty file, a lone line with and without its newline, and files
+    // about to gain or lose their last newline
+    let checkout_dir = TempRepo::new("library-edge-files");
+    checkout_dir.write("empty.txt", "");
+    checkout_dir.write("one.txt", "one\n");
+    checkout_dir.write("bare.txt", "one");
+    checkout_dir.write("gains.txt", "one\ntwo");
+    checkout_dir.write("loses.txt", "one\ntwo\n");
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs"));
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    let tracked: Vec<(PathBuf, usize, bool)> = logs.tracked_paths().unwrap().map(|tracked| {
+        let (id, meta) = tracked.unwrap();
+        (id, meta.node_count, meta.no_trailing_newline)
+    }).collect();
+    assert_eq!(tracked, vec![(PathBuf::from("bare.txt"), 1, true), (PathBuf::from("empty.txt"), 0, false),
+                             (PathBuf::from("gains.txt"), 2, true), (PathBuf::from("loses.txt"), 2, false),
+                             (PathBuf::from("one.txt"), 1, false)]);
+    assert_eq!(stage.read_path("bare.txt").unwrap(), b"one".to_vec());
+    let logs = logs.with_stat_cache(false);
+    assert!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap().is_empty());
+
+    // only the last newline changes
+    checkout_dir.write("gains.txt", "one\ntwo\n");
+    checkout_dir.write("loses.txt", "one\ntwo");
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(),
+               vec![PathBuf::from("gains.txt"), PathBuf::from("loses.txt")]);
+
+    // and one file at a time, a lone line gaining its newline, one losing
+    // it, and an empty file gaining a line
+    let diff_one = |id: &str| {
+        let path = checkout_dir.path(id);
+        logs.diff_path(&PathInfo::new(path.clone(), id, fs::metadata(&path).unwrap())).unwrap()
+    };
+    assert!(!diff_one("bare.txt") && !diff_one("one.txt") && !diff_one("empty.txt"));
+    checkout_dir.write("bare.txt", "one\n");
+    checkout_dir.write("one.txt", "one");
+    checkout_dir.write("empty.txt", "\n");
+    assert!(diff_one("bare.txt") && diff_one("one.txt") && diff_one("empty.txt"));
+    checkout_dir.write("empty.txt", "");
+    assert!(!diff_one("empty.txt"));
+}
+
+#[test]
+fn test_diff_anchors() {
+    let checkout_dir = TempRepo::new("library-diff-anchors");
+    let text: String = (0..3000).map(|i| format!("line {}\n", i)).collect();
+    checkout_dir.write("a.txt", &text);
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs")).with_stat_cache(false);
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    // the first diff reads it all and leaves anchors behind
+    assert!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap().is_empty());
+    assert!(fs::metadata(h2.join("anchors").join("a.txt")).unwrap().is_file());
+
+    // a change at the end is found from the last anchor
+    checkout_dir.write("a.txt", &format!("{}more\n", text));
+    let before = counter_value(Counter::LinesSkipped);
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(), vec![PathBuf::from("a.txt")]);
+    assert!(counter_value(Counter::LinesSkipped) >= before + 2048);
+
+    // and one at the start passes every anchor
+    checkout_dir.write("a.txt", &format!("first\n{}", text));
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(), vec![PathBuf::from("a.txt")]);
+    checkout_dir.write("a.txt", &text);
+    assert!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap().is_empty());
+}
+
+#[test]
+fn test_diff_drivers() {
+    let checkout_dir = TempRepo::new("library-diff-drivers");
+    checkout_dir.write("notes.txt", "one\n");
+    checkout_dir.write("image.bin", "\0one\n");
+    checkout_dir.write("data/table.txt", "one\ntwo\n");
+    checkout_dir.write("run.log", "one\n");
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut rules = DriverRules::default();
+    rules.add_rule("*.bin", DiffDriver::Whole);
+    rules.add_rule("data/**", DiffDriver::Blocks);
+    rules.add_rule("*.log", DiffDriver::Skip);
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs")).with_drivers(rules.clone()).with_stat_cache(false);
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+
+    // each index says which driver built it
+    assert_eq!(logs.read_meta(Path::new("notes.txt")).unwrap().driver, Some(DiffDriver::Lines));
+    assert_eq!(logs.read_meta(Path::new("image.bin")).unwrap().driver, Some(DiffDriver::Whole));
+    assert_eq!(logs.read_meta(Path::new("data/table.txt")).unwrap().driver, Some(DiffDriver::Blocks));
+    assert_eq!(logs.read_meta(Path::new("run.log")).unwrap().driver, Some(DiffDriver::Skip));
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(), Vec::<PathBuf>::new());
+
+    // skipped files never differ
+    for id in ["notes.txt", "image.bin", "data/table.txt", "run.log"].iter() {
+        checkout_dir.write(id, "two\n");
+    }
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(),
+               vec![PathBuf::from("data/table.txt"), PathBuf::from("image.bin"), PathBuf::from("notes.txt")]);
+
+    // an index built by another driver than the config has now needs rebuilding
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+    rules.add_rule("notes.txt", DiffDriver::Whole);
+    let logs = Logs::new(h2.join("logs")).with_drivers(rules).with_stat_cache(false);
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(),
+               vec![PathBuf::from("notes.txt")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_index_lock() {
+    let dir = TempRepo::new("library-index-lock");
+    dir.write("index/meta", "");
+    {
+        let _first = IndexLock::acquire(dir.path("index"), LockMode::Shared).unwrap().unwrap();
+        let _second = IndexLock::acquire(dir.path("index"), LockMode::Shared).unwrap().unwrap();
+        // readers share it, a writer has to wait for both
+        let writer = fs::File::open(dir.path("index")).unwrap();
+        assert_eq!(lock_file(&writer, true, false).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+    assert!(IndexLock::acquire(dir.path("index"), LockMode::Exclusive).unwrap().is_some());
+    assert!(IndexLock::acquire(dir.path("missing"), LockMode::Shared).unwrap().is_none());
+}
+
+#[test]
+fn test_repository_open() {
+    let checkout_dir = TempRepo::new("library-repository");
+    checkout_dir.write("a.txt", "one\n");
+    checkout_dir.write("sub/deeper/b.txt", "two\n");
+    {
+        let mut repository = Repository::init(&checkout_dir.root, None).unwrap();
+        let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+        let (checkout, logs, stage) = repository.parts_mut();
+        stage_dir_all(checkout, logs, stage, PathBuf::from("."), &ignore).unwrap();
+    }
+    assert!(Repository::init(&checkout_dir.root, None).is_err());
+
+    // found from anywhere in the checkout, with every part rooted there
+    let repository = Repository::open(checkout_dir.path("sub/deeper")).unwrap();
+    assert_eq!(repository.root(), checkout_dir.root.as_path());
+    assert_eq!(repository.stage().read_path("sub/deeper/b.txt").unwrap(), b"two\n".to_vec());
+    assert!(repository.logs().is_indexed(&PathBuf::from("a.txt")).unwrap());
+    assert_eq!(repository.revisions().list().unwrap(), vec![]);
+
+    // a repository missing part of its layout is refused
+    fs::remove_dir_all(checkout_dir.path(".h2/revs")).unwrap();
+    assert!(Repository::open(&checkout_dir.root).is_err());
+}
+
+#[test]
+fn test_verify_reports() {
+    let checkout_dir = TempRepo::new("library-verify");
+    checkout_dir.write("a.txt", "one\n");
+    {
+        let mut repository = Repository::init(&checkout_dir.root, None).unwrap();
+        let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+        let (checkout, logs, stage) = repository.parts_mut();
+        stage_dir_all(checkout, logs, stage, PathBuf::from("."), &ignore).unwrap();
+    }
+    let repository = Repository::open(&checkout_dir.root).unwrap();
+
+    // what was checked comes back to the caller rather than being printed
+    let report = verify_repo(repository.repo()).unwrap();
+    assert!(report.is_ok());
+    assert!(report.header.subject.starts_with("header"));
+    assert!(report.files.iter().any(|check| check.subject == "a.txt"));
+    assert!(verify_stage(repository.repo(), OrphanAction::Report).unwrap().is_ok());
+
+    checkout_dir.write(".h2/stage/orphan.txt", "left behind\n");
+    let report = verify_stage(repository.repo(), OrphanAction::Report).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.orphans, vec![Orphan {
+        id: PathBuf::from("orphan.txt"),
+        unfinished: false,
+        outcome: OrphanOutcome::Left
+    }]);
+    let report = verify_stage(repository.repo(), OrphanAction::Prune).unwrap();
+    assert_eq!(report.orphans[0].outcome, OrphanOutcome::Pruned);
+    assert!(report.is_ok());
+    assert!(!checkout_dir.exists(".h2/stage/orphan.txt"));
+}
+
+#[test]
+fn test_parallel_diff() {
+    let checkout_dir = TempRepo::new("library-parallel-diff");
+    let spec = small_spec();
+    checkout_dir.synthesize(&spec);
+
+    let h2 = checkout_dir.path(".h2");
+    let checkout = Checkout::new(checkout_dir.root.clone());
+    let mut stage = Stage::new(h2.join("stage"));
+    let mut logs = Logs::new(h2.join("logs")).with_stat_cache(false);
+    stage.init().unwrap();
+    logs.init().unwrap();
+    let ignore = IgnoreRules::new(vec![PathBuf::from(".h2")]);
+    stage_dir_all(&checkout, &mut logs, &mut stage, PathBuf::from("."), &ignore).unwrap();
+    assert!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap().is_empty());
+
+    // every fifth file is rewritten, and however many threads look the same ones come back in order
+    assert_eq!(mutate_checkout(&checkout_dir.root, &spec, 5).unwrap(), 3);
+    let sequential = diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap();
+    assert_eq!(sequential, vec![PathBuf::from("dir0/file0.txt"), PathBuf::from("dir1/file1.txt"),
+                                PathBuf::from("dir2/file2.txt")]);
+    let logs = logs.with_threads(4);
+    assert_eq!(diff_dir_all(&checkout, &logs, PathBuf::from("."), &ignore).unwrap(), sequential);
+}
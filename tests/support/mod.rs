@@ -0,0 +1,120 @@
+// helpers shared by the integration tests. every test gets its own
+// directory under the system temp dir, removed again when it's dropped, and
+// runs commands with that as the working directory so nothing leaks
+// between tests or into the source tree
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::io::{Read, Write};
+
+use std::env;
+use std::fs;
+use std::str;
+
+use half2::synth::*;
+
+pub struct TempRepo {
+    pub root: PathBuf
+}
+
+fn h2_binary() -> PathBuf {
+    // the binary is built next to the test binaries, or one level up from
+    // them when they're kept under deps
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir.join("half2")
+}
+
+impl TempRepo {
+    pub fn new(name: &str) -> TempRepo {
+        let root = env::temp_dir().join(format!("h2-it-{}", name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        TempRepo {
+            root: root
+        }
+    }
+
+    pub fn path<T: AsRef<Path>>(&self, path: T) -> PathBuf {
+        self.root.join(path)
+    }
+
+    pub fn write<T: AsRef<Path>>(&self, path: T, data: &str) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(&path).and_then(|mut f| f.write_all(data.as_bytes())).unwrap();
+    }
+
+    pub fn read<T: AsRef<Path>>(&self, path: T) -> String {
+        let mut data = String::new();
+        fs::File::open(self.path(path)).and_then(|mut f| f.read_to_string(&mut data)).unwrap();
+        data
+    }
+
+    pub fn exists<T: AsRef<Path>>(&self, path: T) -> bool {
+        fs::metadata(self.path(path)).is_ok()
+    }
+
+    pub fn synthesize(&self, spec: &SynthSpec) -> usize {
+        generate_checkout(&self.root, spec).unwrap()
+    }
+
+    pub fn run(&self, args: &[&str]) -> Output {
+        // the key, repository and author variables are cleared so the
+        // user's own can't change a test
+        Command::new(h2_binary()).args(args).current_dir(&self.root).env_remove("H2_KEY")
+            .env_remove("H2_DIR").env_remove("H2_AUTHOR_NAME").env_remove("H2_AUTHOR_EMAIL").output().unwrap()
+    }
+
+    pub fn h2(&self, args: &[&str]) -> String {
+        // stdout of a command that has to succeed
+        let output = self.run(args);
+        if !output.status.success() {
+            panic!("h2 {} failed: {}", args.join(" "), str::from_utf8(&output.stderr).unwrap_or("?"));
+        }
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    pub fn h2_fails(&self, args: &[&str]) -> String {
+        // stderr of a command that has to fail
+        let output = self.run(args);
+        if output.status.success() {
+            panic!("h2 {} succeeded", args.join(" "));
+        }
+        String::from_utf8(output.stderr).unwrap()
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+pub fn lines(output: &str) -> Vec<&str> {
+    output.lines().collect()
+}
+
+pub fn files_under<T: AsRef<Path>>(dir: T) -> Vec<PathBuf> {
+    // every file under a directory, relative to it and sorted
+    let dir = dir.as_ref();
+    let mut files = vec![];
+    let mut to_visit = vec![dir.to_path_buf()];
+    while !to_visit.is_empty() {
+        let next = to_visit.pop().unwrap();
+        for item in fs::read_dir(&next).unwrap() {
+            let entry = item.unwrap();
+            if entry.metadata().unwrap().is_dir() {
+                to_visit.push(entry.path());
+            } else {
+                files.push(PathBuf::from(entry.path().relative_from(dir).unwrap()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
@@ -0,0 +1,313 @@
+// random runs of inserts, gets and removes on BufTree, checked against a
+// BTreeSet after every step, on trees kept in memory and in files. the tree
+// is checked all over after each step too: order, how full each node is and
+// how many children it points to. a sequence that fails is cut down to the
+// fewest steps that still fail and written out, to be copied into
+// tests/corpus/buftree where every later run replays it first. trees opened
+// from random or damaged bytes have to fail cleanly or read back whole
+
+extern crate half2;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::io::{Cursor, Read, Seek, Write};
+
+use std::env;
+use std::fmt;
+use std::fs;
+
+use half2::tree::*;
+
+// random sequences tried on each backend, and how long each is
+const SEEDS: u64 = 24;
+const STEPS: usize = 400;
+// random buffers and damaged trees opened per run
+const FUZZ_CASES: u64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Insert(u64),
+    Get(u64),
+    Remove(u64)
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Insert(item) => write!(f, "insert {}", item),
+            Step::Get(item) => write!(f, "get {}", item),
+            Step::Remove(item) => write!(f, "remove {}", item)
+        }
+    }
+}
+
+impl Step {
+    fn parse(line: &str) -> Option<Step> {
+        let mut words = line.split_whitespace();
+        let (op, item) = match (words.next(), words.next().and_then(|item| item.parse().ok())) {
+            (Some(op), Some(item)) => (op, item),
+            _ => {
+                return None;
+            }
+        };
+        match op {
+            "insert" => Some(Step::Insert(item)),
+            "get" => Some(Step::Get(item)),
+            "remove" => Some(Step::Remove(item)),
+            _ => None
+        }
+    }
+}
+
+// a node width and the steps to run on a tree that wide
+#[derive(Debug, Clone)]
+struct Case {
+    width: usize,
+    steps: Vec<Step>
+}
+
+impl Case {
+    fn parse(text: &str) -> Case {
+        let mut lines = text.lines().map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let width = lines.next().and_then(|line| {
+            if line.starts_with("width ") {line[6..].trim().parse().ok()} else {None}
+        }).expect("A corpus case starts with its width");
+        let steps = lines.map(|line| Step::parse(line).expect(&format!("Bad corpus step {:?}", line))).collect();
+        Case {
+            width: width,
+            steps: steps
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("width {}\n", self.width);
+        for step in self.steps.iter() {
+            text.push_str(&format!("{}\n", step));
+        }
+        text
+    }
+}
+
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+fn random_case(seed: u64) -> Case {
+    // a few items over and over make for collisions and for trees that fill
+    // and empty, many make for deep ones. inserts and removes take turns
+    // leading so nodes split and merge all through a run
+    let mut rng = Lcg(seed);
+    let width = MIN_TREE_WIDTH + rng.below(6) as usize;
+    let space = [8, 64, 512][rng.below(3) as usize];
+    let mut steps = vec![];
+    for i in 0..STEPS {
+        let growing = (i / 50) % 2 == 0;
+        let item = rng.below(space);
+        let roll = rng.below(10);
+        steps.push(if roll < 2 {
+            Step::Get(item)
+        } else if (roll < 7) == growing {
+            Step::Insert(item)
+        } else {
+            Step::Remove(item)
+        });
+    }
+    Case {
+        width: width,
+        steps: steps
+    }
+}
+
+fn check_shape<T: Read + Write + Seek + fmt::Debug>(tree: &mut BufTree<T, u64>, oracle: &BTreeSet<u64>)
+                                                    -> Result<(), String> {
+    let mut found = vec![];
+    let count = try!(tree.verify_each(|&item| found.push(item)).map_err(|e| format!("verify failed: {}", e)));
+    found.sort();
+    let expected: Vec<u64> = oracle.iter().cloned().collect();
+    if count != oracle.len() || tree.len() != oracle.len() || found != expected {
+        return Err(format!("tree holds {:?} ({} by its header), expected {:?}", found, tree.len(), expected));
+    }
+
+    let width = tree.size();
+    let nodes = try!(tree.dump_nodes().map_err(|e| format!("dump failed: {}", e)));
+    let depth = nodes.iter().map(|node| node.depth + 1).max().unwrap_or(0);
+    for node in nodes.iter() {
+        if node.items.len() > width {
+            return Err(format!("node {} holds {} items in a width of {}", node.idx, node.items.len(), width));
+        }
+        if node.depth > 0 && node.items.is_empty() {
+            return Err(format!("node {} is empty and not the root", node.idx));
+        }
+        if node.leaf && (!node.next.is_empty() || node.depth + 1 != depth) {
+            return Err(format!("leaf {} at depth {} has {} children, leaves are at depth {}",
+                               node.idx, node.depth, node.next.len(), depth - 1));
+        }
+        if !node.leaf && node.next.len() != node.items.len() + 1 {
+            return Err(format!("node {} has {} children for {} items", node.idx, node.next.len(),
+                               node.items.len()));
+        }
+    }
+    // every inner node has two children or more, so the leaves double at
+    // each level and hold an item each
+    if depth > 1 && (1 << (depth - 1)) > oracle.len() {
+        return Err(format!("tree is {} deep for {} items", depth, oracle.len()));
+    }
+    Ok(())
+}
+
+fn run_case<T: Read + Write + Seek + fmt::Debug>(buffer: T, case: &Case) -> Result<(), String> {
+    // the first step that goes wrong and how
+    let mut tree: BufTree<T, u64> = try!(BufTree::new(buffer, case.width).map_err(|e| format!("{}", e)));
+    let mut oracle = BTreeSet::new();
+    for (i, &step) in case.steps.iter().enumerate() {
+        let (got, expected) = match step {
+            Step::Insert(item) => (tree.insert(item), if oracle.insert(item) {None} else {Some(item)}),
+            Step::Get(item) => (tree.get(item), if oracle.contains(&item) {Some(item)} else {None}),
+            Step::Remove(item) => (tree.remove(item), if oracle.remove(&item) {Some(item)} else {None})
+        };
+        match got {
+            Ok(got) if got == expected => {},
+            Ok(got) => {
+                return Err(format!("step {} ({}) returned {:?}, expected {:?}", i, step, got, expected));
+            },
+            Err(e) => {
+                return Err(format!("step {} ({}) failed: {}", i, step, e));
+            }
+        }
+        try!(check_shape(&mut tree, &oracle).map_err(|e| format!("after step {} ({}): {}", i, step, e)));
+    }
+
+    // and it reads back in order from the start
+    let items: Result<Vec<u64>, _> = tree.iter_from(0).collect();
+    let expected: Vec<u64> = oracle.iter().cloned().collect();
+    match items {
+        Ok(ref items) if *items == expected => Ok(()),
+        other => Err(format!("iterating gave {:?}, expected {:?}", other, expected))
+    }
+}
+
+fn file_buffer(name: &str) -> fs::File {
+    let path = env::temp_dir().join(format!("h2-tree-props-{}", name));
+    fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap()
+}
+
+fn run_both(case: &Case, name: &str) -> Result<(), String> {
+    try!(run_case(Cursor::new(vec![]), case).map_err(|e| format!("in memory: {}", e)));
+    run_case(file_buffer(name), case).map_err(|e| format!("in a file: {}", e))
+}
+
+fn shrink(case: &Case, name: &str) -> Case {
+    // drop runs of steps while it still fails, halving the runs down to
+    // single steps
+    let mut case = case.clone();
+    let mut chunk = case.steps.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < case.steps.len() {
+            let end = if start + chunk < case.steps.len() {start + chunk} else {case.steps.len()};
+            let smaller = Case {
+                width: case.width,
+                steps: case.steps[..start].iter().chain(case.steps[end..].iter()).cloned().collect()
+            };
+            if run_both(&smaller, name).is_err() {
+                case = smaller;
+            } else {
+                start += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+    case
+}
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("corpus").join("buftree")
+}
+
+#[test]
+fn test_tree_corpus() {
+    // every sequence that broke the tree once
+    let mut paths: Vec<PathBuf> = fs::read_dir(corpus_dir()).unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    for path in paths {
+        let mut text = String::new();
+        fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut text)).unwrap();
+        if let Err(e) = run_both(&Case::parse(&text), "corpus") {
+            panic!("{} fails again {}", path.display(), e);
+        }
+    }
+}
+
+#[test]
+fn test_tree_random() {
+    for seed in 0..SEEDS {
+        let case = random_case(seed);
+        let name = format!("random-{}", seed);
+        if let Err(e) = run_both(&case, &name) {
+            let small = shrink(&case, &name);
+            let saved = env::temp_dir().join(format!("h2-buftree-seed-{}", seed));
+            fs::File::create(&saved).and_then(|mut f| f.write_all(small.to_text().as_bytes())).unwrap();
+            panic!("seed {} fails {}\ncut down to {} steps in {}, copy it into {} to keep it",
+                   seed, e, small.steps.len(), saved.display(), corpus_dir().display());
+        }
+    }
+}
+
+fn read_checked(data: Vec<u8>) {
+    // whatever the bytes, a checked open either fails or gives a tree every
+    // read goes through on
+    let mut tree: BufTree<_, u64> = match unsafe {BufTree::open_checked(Cursor::new(data))} {
+        Ok(tree) => tree,
+        Err(_) => {
+            return;
+        }
+    };
+    for item in 0..64 {
+        let _ = tree.get(item);
+        let _ = tree.get_all(item);
+    }
+    let _: Vec<_> = tree.iter_from(0).collect();
+}
+
+#[test]
+fn test_tree_fuzz() {
+    let mut rng = Lcg(0x5eed);
+    for _ in 0..FUZZ_CASES {
+        let len = rng.below(512) as usize;
+        read_checked((0..len).map(|_| rng.next() as u8).collect());
+    }
+
+    // a real tree with deleted nodes, with a few bytes changed or its end
+    // cut off
+    let mut tree: BufTree<_, u64> = BufTree::new(Cursor::new(vec![]), MIN_TREE_WIDTH).unwrap();
+    for i in 0..64 {
+        tree.insert(i * 3).unwrap();
+    }
+    for i in 0..16 {
+        tree.remove(i * 9).unwrap();
+    }
+    let data = tree.into_inner().into_inner();
+    for _ in 0..FUZZ_CASES {
+        let mut damaged = data.clone();
+        for _ in 0..1 + rng.below(4) {
+            let at = rng.below(damaged.len() as u64) as usize;
+            damaged[at] = rng.next() as u8;
+        }
+        if rng.below(4) == 0 {
+            let cut = rng.below(damaged.len() as u64) as usize;
+            damaged.truncate(cut);
+        }
+        read_checked(damaged);
+    }
+}